@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeSet,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use fs2::FileExt;
+
+use crate::MagResult;
+
+pub const ACTIVITY_DIR: &str = "activity";
+
+/// A running `magpkg` process as advertised under `<store>/activity/<pid>`.
+///
+/// The file is line oriented and rewritten in place whenever the process
+/// starts a new step or takes or releases a store lock:
+///
+/// ```text
+/// pid      <pid>
+/// started  <unix-seconds>
+/// command  <argv joined by spaces>
+/// step     <what the process is doing>
+/// holding  <lock label>        (repeatable)
+/// ```
+///
+/// The owner keeps an exclusive lock on the file for its whole lifetime, so
+/// a file whose lock can be taken belongs to a process that has exited.
+pub struct Activity {
+    path: PathBuf,
+    file: File,
+    pid: u32,
+    started: u64,
+    command: String,
+    state: Mutex<ActivityState>,
+}
+
+#[derive(Default)]
+struct ActivityState {
+    step: String,
+    holding: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ActivityInfo {
+    pub pid: u32,
+    pub started: u64,
+    pub command: String,
+    pub step: String,
+    pub holding: Vec<String>,
+}
+
+impl Activity {
+    pub fn register(base_root: &Path) -> MagResult<Self> {
+        let dir = base_root.join(ACTIVITY_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let pid = std::process::id();
+        let path = dir.join(pid.to_string());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.lock_exclusive()?;
+
+        let activity = Activity {
+            path,
+            file,
+            pid,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            command: env::args()
+                .map(|arg| arg.replace(['\n', '\t'], " "))
+                .collect::<Vec<_>>()
+                .join(" "),
+            state: Mutex::new(ActivityState {
+                step: "starting".into(),
+                holding: BTreeSet::new(),
+            }),
+        };
+        activity.write();
+        Ok(activity)
+    }
+
+    pub fn set_step(&self, step: impl Into<String>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.step = step.into();
+        }
+        self.write();
+    }
+
+    pub fn hold(&self, label: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.holding.insert(label.to_string());
+        }
+        self.write();
+    }
+
+    pub fn release(&self, label: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.holding.remove(label);
+        }
+        self.write();
+    }
+
+    /// Best effort: a stale or partially written activity file only affects
+    /// what other processes report while waiting.
+    fn write(&self) {
+        let Ok(state) = self.state.lock() else {
+            return;
+        };
+        let mut body = format!(
+            "pid\t{}\nstarted\t{}\ncommand\t{}\nstep\t{}\n",
+            self.pid, self.started, self.command, state.step
+        );
+        for label in &state.holding {
+            body.push_str(&format!("holding\t{label}\n"));
+        }
+
+        let mut file = &self.file;
+        let _ = file
+            .set_len(0)
+            .and_then(|()| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(body.as_bytes()));
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// List the live processes using the store, removing files left behind by
+/// processes that have exited.
+pub fn list_activities(base_root: &Path) -> MagResult<Vec<ActivityInfo>> {
+    let dir = base_root.join(ACTIVITY_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let own_pid = std::process::id();
+    let mut activities = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        match file.try_lock_shared() {
+            Ok(()) => {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => continue,
+        }
+        if let Some(info) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| parse_activity(&contents))
+        {
+            if info.pid != own_pid {
+                activities.push(info);
+            }
+        }
+    }
+    activities.sort_by_key(|info| info.started);
+    Ok(activities)
+}
+
+/// Find the live process that currently holds `label`, if it advertises it.
+pub fn find_holder(base_root: &Path, label: &str) -> Option<ActivityInfo> {
+    list_activities(base_root)
+        .ok()?
+        .into_iter()
+        .find(|info| info.holding.iter().any(|held| held == label))
+}
+
+fn parse_activity(contents: &str) -> Option<ActivityInfo> {
+    let mut info = ActivityInfo {
+        pid: 0,
+        started: 0,
+        command: String::new(),
+        step: String::new(),
+        holding: Vec::new(),
+    };
+    for line in contents.lines() {
+        let Some((key, value)) = line.split_once('\t') else {
+            continue;
+        };
+        match key {
+            "pid" => info.pid = value.parse().ok()?,
+            "started" => info.started = value.parse().unwrap_or(0),
+            "command" => info.command = value.to_string(),
+            "step" => info.step = value.to_string(),
+            "holding" => info.holding.push(value.to_string()),
+            _ => {}
+        }
+    }
+    (info.pid != 0).then_some(info)
+}
@@ -0,0 +1,137 @@
+use std::{
+    fs::{self, File},
+    io::{self, Cursor, Read},
+    path::Path,
+};
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::{MagError, MagResult};
+
+/// Bytes needed to recognize every format: a tar header's `ustar` magic
+/// ends at offset 262.
+const SNIFF_LEN: usize = 512;
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// The format of a fetched source, as far as the `untar` builder cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    Zstd,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zip,
+    Tar,
+    /// Anything else, copied into the output unchanged.
+    Raw,
+}
+
+impl SourceFormat {
+    /// Identify a format from the first bytes of a file. Old tar files
+    /// without the `ustar` magic are only recognized by a `.tar` name.
+    pub fn detect(head: &[u8], filename: &str) -> Self {
+        let starts = |magic: &[u8]| head.starts_with(magic);
+        if starts(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            SourceFormat::Zstd
+        } else if starts(&[0x1F, 0x8B]) {
+            SourceFormat::Gzip
+        } else if starts(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            SourceFormat::Xz
+        } else if starts(b"BZh") {
+            SourceFormat::Bzip2
+        } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+            SourceFormat::Zip
+        } else if is_tar(head) || filename.ends_with(".tar") {
+            SourceFormat::Tar
+        } else {
+            SourceFormat::Raw
+        }
+    }
+
+    /// The name suffix a compressed file conventionally carries.
+    fn suffix(self) -> &'static str {
+        match self {
+            SourceFormat::Zstd => ".zst",
+            SourceFormat::Gzip => ".gz",
+            SourceFormat::Xz => ".xz",
+            SourceFormat::Bzip2 => ".bz2",
+            SourceFormat::Zip => ".zip",
+            SourceFormat::Tar => ".tar",
+            SourceFormat::Raw => "",
+        }
+    }
+}
+
+fn is_tar(head: &[u8]) -> bool {
+    head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC)
+}
+
+/// Unpack one fetched file into `dest` for the `untar` builder. Compressed
+/// tarballs and zip files are extracted; a compressed file that is not a
+/// tarball is decompressed to its name without the compression suffix, and
+/// any other file is moved into `dest` as is.
+pub fn unpack_source(path: &Path, dest: &Path) -> MagResult<()> {
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut file = File::open(path)?;
+    let head = read_head(&mut file)?;
+    let format = SourceFormat::detect(&head, &filename);
+    let rest = Cursor::new(head).chain(file);
+
+    let mut decoded: Box<dyn Read> = match format {
+        SourceFormat::Zip => return unpack_zip(path, dest),
+        SourceFormat::Raw => {
+            fs::rename(path, dest.join(&filename))?;
+            return Ok(());
+        }
+        SourceFormat::Tar => {
+            tar::Archive::new(rest).unpack(dest)?;
+            return Ok(());
+        }
+        SourceFormat::Zstd => Box::new(ZstdDecoder::new(rest)?),
+        SourceFormat::Gzip => Box::new(MultiGzDecoder::new(rest)),
+        SourceFormat::Xz => Box::new(XzDecoder::new_multi_decoder(rest)),
+        SourceFormat::Bzip2 => Box::new(MultiBzDecoder::new(rest)),
+    };
+
+    let inner = read_head(&mut decoded)?;
+    let is_tarball = is_tar(&inner)
+        || filename.contains(".tar")
+        || [".tgz", ".tbz2", ".txz", ".tzst"]
+            .iter()
+            .any(|suffix| filename.ends_with(suffix));
+    let mut stream = Cursor::new(inner).chain(decoded);
+    if is_tarball {
+        tar::Archive::new(stream).unpack(dest)?;
+    } else {
+        let name = filename
+            .strip_suffix(format.suffix())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&filename);
+        let mut out = File::create(dest.join(name))?;
+        io::copy(&mut stream, &mut out)?;
+    }
+    Ok(())
+}
+
+fn unpack_zip(path: &Path, dest: &Path) -> MagResult<()> {
+    let zip_error = |err: zip::result::ZipError| {
+        MagError::Generic(format!("failed to unpack {}: {err}", path.display()))
+    };
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    archive.extract(dest).map_err(zip_error)
+}
+
+/// Read up to [`SNIFF_LEN`] bytes, fewer only at the end of the stream.
+fn read_head(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    reader.take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
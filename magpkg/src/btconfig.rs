@@ -0,0 +1,442 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use librqbit::{AddTorrentOptions, SessionOptions, dht::PersistentDhtConfig};
+
+use crate::{MagError, MagResult};
+
+pub const TORRENT_CONFIG_FILE: &str = "torrent.conf";
+const DHT_STATE_FILE: &str = "dht.json";
+const DEFAULT_PIECE_LENGTH: u32 = 4 * 1024 * 1024;
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+static CLI_OVERRIDES: OnceLock<TorrentOverrides> = OnceLock::new();
+
+/// Peer discovery settings shared by the fetcher, the seeder, and torrent
+/// creation. Values come from `<store>/torrent.conf` and are then overridden
+/// by command-line flags:
+///
+/// ```text
+/// dht          on|off
+/// persist-dht  on|off
+/// trackerless  on|off
+/// tracker      <url>      (repeatable)
+/// piece-length <bytes>    (power of two, at least 16 KiB)
+/// listen-addr  <ip>[:<port>]      (seeder; `[::]` listens on IPv4 and IPv6)
+/// utp          on|off             (seeder; also accept uTP peers on the listen port)
+/// upnp         on|off             (seeder; forward the listen port on the router)
+/// announce-addr <ip>:<port>       (repeatable; public peer address for magnets)
+/// stall-timeout <seconds>         (give up after no progress for this long; 0 waits forever)
+/// verify-interval <hours>         (seeder; re-hash seeded payloads this often; 0 never does)
+/// ```
+///
+/// IPv6 addresses with a port are written in brackets: `[2001:db8::1]:6881`.
+#[derive(Debug, Clone)]
+pub struct TorrentConfig {
+    pub dht: bool,
+    pub persist_dht: bool,
+    pub trackerless: bool,
+    pub trackers: Vec<String>,
+    /// Piece length for torrents created by this store.
+    pub piece_length: u32,
+    /// Address the seeder accepts peers on.
+    pub listen_addr: Option<ListenAddr>,
+    /// Whether the seeder also accepts uTP connections on its listen port.
+    pub utp: bool,
+    /// Whether the seeder asks the router to forward its listen port.
+    pub upnp: bool,
+    /// Addresses peers can reach this machine's seeder on, listed as
+    /// `x.pe` peers in announced magnet links.
+    pub announce_addrs: Vec<SocketAddr>,
+    /// How long a download may go without receiving metadata or data before
+    /// it is abandoned, so the fetch falls through to HTTP sources. `None`
+    /// waits forever.
+    pub stall_timeout: Option<Duration>,
+    /// How often the seeder hashes the payloads it serves again to catch
+    /// bitrot. `None` only checks them before seeding starts.
+    pub verify_interval: Option<Duration>,
+    dht_state_path: PathBuf,
+}
+
+/// A listen address whose port may be left to the command line or default.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenAddr {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl ListenAddr {
+    /// Parse `<ip>`, `<ipv4>:<port>`, `[<ipv6>]`, or `[<ipv6>]:<port>`.
+    pub fn parse(value: &str) -> Option<Self> {
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Some(Self {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            });
+        }
+        let ip = value
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+            .unwrap_or(value)
+            .parse()
+            .ok()?;
+        Some(Self { ip, port: None })
+    }
+}
+
+/// Command-line overrides for [`TorrentConfig`]; `None` keeps the file value.
+#[derive(Debug, Default, Clone)]
+pub struct TorrentOverrides {
+    pub dht: Option<bool>,
+    pub persist_dht: Option<bool>,
+    pub trackerless: Option<bool>,
+    pub trackers: Vec<String>,
+    /// Stall timeout in seconds; 0 disables it.
+    pub stall_timeout: Option<u64>,
+}
+
+/// Record the overrides parsed from the command line. Later calls are ignored.
+pub fn set_cli_overrides(overrides: TorrentOverrides) {
+    let _ = CLI_OVERRIDES.set(overrides);
+}
+
+impl TorrentConfig {
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        let mut config = TorrentConfig {
+            dht: true,
+            persist_dht: true,
+            trackerless: false,
+            trackers: Vec::new(),
+            piece_length: DEFAULT_PIECE_LENGTH,
+            listen_addr: None,
+            utp: false,
+            upnp: false,
+            announce_addrs: Vec::new(),
+            stall_timeout: Some(DEFAULT_STALL_TIMEOUT),
+            verify_interval: None,
+            dht_state_path: base_root.join("torrent").join(DHT_STATE_FILE),
+        };
+
+        let path = base_root.join(TORRENT_CONFIG_FILE);
+        match fs::read_to_string(&path) {
+            Ok(contents) => config.parse_file(&path, &contents)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if let Some(overrides) = CLI_OVERRIDES.get() {
+            config.dht = overrides.dht.unwrap_or(config.dht);
+            config.persist_dht = overrides.persist_dht.unwrap_or(config.persist_dht);
+            config.trackerless = overrides.trackerless.unwrap_or(config.trackerless);
+            if let Some(secs) = overrides.stall_timeout {
+                config.stall_timeout = stall_timeout_from_secs(secs);
+            }
+            for tracker in &overrides.trackers {
+                if !config.trackers.contains(tracker) {
+                    config.trackers.push(tracker.clone());
+                }
+            }
+        }
+
+        if config.trackerless && !config.dht {
+            return Err(MagError::Generic(
+                "trackerless torrent operation requires DHT; enable dht or disable trackerless"
+                    .into(),
+            ));
+        }
+
+        Ok(config)
+    }
+
+    fn parse_file(&mut self, path: &Path, contents: &str) -> MagResult<()> {
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .map(|(key, value)| (key, value.trim()))
+                .unwrap_or((line, ""));
+            let invalid = || {
+                MagError::Generic(format!(
+                    "{}:{}: invalid value {value:?} for {key}",
+                    path.display(),
+                    index + 1
+                ))
+            };
+
+            match key {
+                "dht" => self.dht = parse_switch(value).ok_or_else(invalid)?,
+                "persist-dht" => self.persist_dht = parse_switch(value).ok_or_else(invalid)?,
+                "trackerless" => self.trackerless = parse_switch(value).ok_or_else(invalid)?,
+                "piece-length" => {
+                    self.piece_length = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|len| len.is_power_of_two() && *len >= MIN_PIECE_LENGTH)
+                        .ok_or_else(invalid)?;
+                }
+                "listen-addr" => {
+                    self.listen_addr = Some(ListenAddr::parse(value).ok_or_else(invalid)?);
+                }
+                "utp" => self.utp = parse_switch(value).ok_or_else(invalid)?,
+                "upnp" => self.upnp = parse_switch(value).ok_or_else(invalid)?,
+                "announce-addr" => {
+                    let addr: SocketAddr = value.parse().map_err(|_| invalid())?;
+                    if !self.announce_addrs.contains(&addr) {
+                        self.announce_addrs.push(addr);
+                    }
+                }
+                "stall-timeout" => {
+                    let secs = value.parse::<u64>().map_err(|_| invalid())?;
+                    self.stall_timeout = stall_timeout_from_secs(secs);
+                }
+                "verify-interval" => {
+                    let hours = value.parse::<u64>().map_err(|_| invalid())?;
+                    self.verify_interval = verify_interval_from_hours(hours);
+                }
+                "tracker" if !value.is_empty() => {
+                    if !self.trackers.iter().any(|tracker| tracker == value) {
+                        self.trackers.push(value.to_string());
+                    }
+                }
+                _ => {
+                    return Err(MagError::Generic(format!(
+                        "{}:{}: unknown setting {key:?}",
+                        path.display(),
+                        index + 1
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_to_session(&self, opts: &mut SessionOptions) -> MagResult<()> {
+        opts.disable_dht = !self.dht;
+        opts.disable_dht_persistence = !self.dht || !self.persist_dht;
+        if self.dht && self.persist_dht {
+            if let Some(parent) = self.dht_state_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            opts.dht_config = Some(PersistentDhtConfig {
+                config_filename: Some(self.dht_state_path.clone()),
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    pub fn apply_to_torrent(&self, opts: &mut AddTorrentOptions) {
+        if self.trackerless {
+            opts.disable_trackers = true;
+        } else if !self.trackers.is_empty() {
+            opts.trackers = Some(self.trackers.clone());
+        }
+    }
+
+    /// Trackers to announce in torrents created by this store.
+    pub fn announce_trackers(&self) -> &[String] {
+        if self.trackerless {
+            &[]
+        } else {
+            &self.trackers
+        }
+    }
+}
+
+/// Add `announce` and `announce-list` keys to a bencoded torrent created
+/// without them. Only the outer dictionary changes, so the info hash is
+/// unaffected.
+pub fn inject_trackers(torrent_bytes: &[u8], trackers: &[String]) -> Vec<u8> {
+    if trackers.is_empty() || torrent_bytes.first() != Some(&b'd') {
+        return torrent_bytes.to_vec();
+    }
+    if torrent_bytes[1..].starts_with(b"8:announce") {
+        return torrent_bytes.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(torrent_bytes.len() + trackers.len() * 64);
+    out.push(b'd');
+    out.extend_from_slice(b"8:announce");
+    push_bencoded_str(&mut out, &trackers[0]);
+    out.extend_from_slice(b"13:announce-listl");
+    for tracker in trackers {
+        out.push(b'l');
+        push_bencoded_str(&mut out, tracker);
+        out.push(b'e');
+    }
+    out.push(b'e');
+    out.extend_from_slice(&torrent_bytes[1..]);
+    out
+}
+
+/// Append a `url-list` key (BEP 19 webseeds) to a bencoded torrent. The key
+/// sorts after `info`, so it goes right before the dictionary's closing `e`.
+pub fn inject_webseeds(torrent_bytes: &[u8], webseeds: &[String]) -> Vec<u8> {
+    if webseeds.is_empty()
+        || torrent_bytes.last() != Some(&b'e')
+        || torrent_bytes
+            .windows(b"8:url-list".len())
+            .any(|window| window == b"8:url-list")
+    {
+        return torrent_bytes.to_vec();
+    }
+
+    let mut out = torrent_bytes[..torrent_bytes.len() - 1].to_vec();
+    out.extend_from_slice(b"8:url-listl");
+    for webseed in webseeds {
+        push_bencoded_str(&mut out, webseed);
+    }
+    out.extend_from_slice(b"ee");
+    out
+}
+
+/// The `url-list` webseeds (BEP 19) of a bencoded torrent, which may be one
+/// string or a list of them, and the `name` from its info dictionary.
+pub fn torrent_webseeds(torrent_bytes: &[u8]) -> (Vec<String>, Option<String>) {
+    let mut webseeds = Vec::new();
+    let mut name = None;
+    for (key, value) in dict_entries(torrent_bytes).unwrap_or_default() {
+        match key {
+            b"url-list" if value.first() == Some(&b'l') => {
+                webseeds.extend(list_items(value).into_iter().filter_map(bencoded_string));
+            }
+            b"url-list" => webseeds.extend(bencoded_string(value)),
+            b"info" => {
+                name = dict_entries(value)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|(key, _)| *key == b"name")
+                    .and_then(|(_, value)| bencoded_string(value));
+            }
+            _ => {}
+        }
+    }
+    (webseeds, name)
+}
+
+/// The keys and raw values of a bencoded dictionary.
+fn dict_entries(bytes: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+    let mut entries = Vec::new();
+    let mut pos = 1;
+    while *bytes.get(pos)? != b'e' {
+        let key_end = bencode_value_end(bytes, pos)?;
+        let value_end = bencode_value_end(bytes, key_end)?;
+        let key = &bytes[pos..key_end];
+        let colon = key.iter().position(|&b| b == b':')?;
+        entries.push((&key[colon + 1..], &bytes[key_end..value_end]));
+        pos = value_end;
+    }
+    Some(entries)
+}
+
+/// The raw values of a bencoded list; empty when it is malformed.
+fn list_items(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut items = Vec::new();
+    let mut pos = 1;
+    while bytes.get(pos).is_some_and(|&b| b != b'e') {
+        let Some(end) = bencode_value_end(bytes, pos) else {
+            return Vec::new();
+        };
+        items.push(&bytes[pos..end]);
+        pos = end;
+    }
+    items
+}
+
+/// A bencoded string value as UTF-8 text.
+fn bencoded_string(value: &[u8]) -> Option<String> {
+    let colon = value.iter().position(|&b| b == b':')?;
+    if !value[..colon].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    String::from_utf8(value[colon + 1..].to_vec()).ok()
+}
+
+/// Drop the `creation date` and `created by` keys from a bencoded torrent's
+/// outer dictionary. Neither is part of the info hash, but both make the
+/// `.torrent` bytes differ between machines and runs for the same payload.
+pub fn strip_volatile_keys(torrent_bytes: &[u8]) -> Vec<u8> {
+    const VOLATILE: [&[u8]; 2] = [b"creation date", b"created by"];
+    if torrent_bytes.first() != Some(&b'd') {
+        return torrent_bytes.to_vec();
+    }
+    let mut out = Vec::with_capacity(torrent_bytes.len());
+    out.push(b'd');
+    let mut pos = 1;
+    while torrent_bytes.get(pos) != Some(&b'e') {
+        let Some(key_end) = bencode_value_end(torrent_bytes, pos) else {
+            return torrent_bytes.to_vec();
+        };
+        let Some(value_end) = bencode_value_end(torrent_bytes, key_end) else {
+            return torrent_bytes.to_vec();
+        };
+        let key = &torrent_bytes[pos..key_end];
+        let volatile = key
+            .iter()
+            .position(|&b| b == b':')
+            .is_some_and(|colon| VOLATILE.contains(&&key[colon + 1..]));
+        if !volatile {
+            out.extend_from_slice(&torrent_bytes[pos..value_end]);
+        }
+        pos = value_end;
+    }
+    out.extend_from_slice(&torrent_bytes[pos..]);
+    out
+}
+
+/// Offset just past the bencoded value starting at `pos`.
+fn bencode_value_end(bytes: &[u8], pos: usize) -> Option<usize> {
+    match *bytes.get(pos)? {
+        b'i' => Some(pos + bytes[pos..].iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = pos + 1;
+            while *bytes.get(pos)? != b'e' {
+                pos = bencode_value_end(bytes, pos)?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = pos + bytes[pos..].iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&bytes[pos..colon]).ok()?.parse().ok()?;
+            let end = colon + 1 + len;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+fn push_bencoded_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(value.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn stall_timeout_from_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+pub fn verify_interval_from_hours(hours: u64) -> Option<Duration> {
+    (hours > 0).then(|| Duration::from_secs(hours.saturating_mul(3600)))
+}
@@ -1,31 +1,53 @@
 use std::{
+    collections::HashSet,
     fs::{self, File, OpenOptions},
     path::{Path, PathBuf},
-    sync::{Arc, mpsc as std_mpsc},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc as std_mpsc,
+    },
     thread,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use fs2::FileExt;
+use futures::StreamExt;
 use librqbit::api::TorrentIdOrHash;
 use librqbit::dht::Id20;
-use librqbit::{AddTorrent, AddTorrentOptions, ManagedTorrent, Session};
+use librqbit::{
+    AddTorrent, AddTorrentOptions, ManagedTorrent, Session, SessionOptions,
+    SessionPersistenceConfig,
+};
 use tokio::runtime::Builder as TokioRuntimeBuilder;
 use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio::task::JoinHandle;
-use tokio::time::{Duration as TokioDuration, interval};
+use tokio::time::{Duration as TokioDuration, interval, sleep, timeout};
 
-use crate::{MagError, MagResult};
+use crate::{MagError, MagResult, btconfig::TorrentConfig};
 
 pub const TORRENT_WORK_MARKER: &str = ".torrent-work-";
 pub const TORRENT_SESSION_PREFIX: &str = ".torrent-session-";
 pub const TORRENT_FETCHER_LOCK: &str = ".torrent-fetcher.lock";
+/// Session that outlives the process, so interrupted downloads resume. It
+/// carries the session prefix, so cleanup treats it like the others.
+pub const TORRENT_RESUME_SESSION: &str = ".torrent-session-resume";
+/// librqbit's persisted torrent list and fastresume bitfields, keyed by
+/// info hash, inside the resume session.
+pub const TORRENT_SESSION_STATE_DIR: &str = "state";
+/// Suffix of the download directories of the resume session, which are
+/// named by the sha256 of the file they fetch instead of a counter.
+const RESUME_WORK_SUFFIX: &str = "resume";
+/// How often a download's progress is compared against the stall timeout.
+const STALL_CHECK_INTERVAL: TokioDuration = TokioDuration::from_secs(5);
 
 pub struct TorrentFetcher {
     command_tx: UnboundedSender<Command>,
     worker: Option<thread::JoinHandle<()>>,
     session_root: PathBuf,
     work_root: PathBuf,
+    /// Whether this is the resume session, which is kept on exit.
+    persistent: bool,
     _lock_file: File,
 }
 
@@ -35,6 +57,9 @@ pub struct TorrentDownloadRequest {
     pub sha256: String,
     pub filename: String,
     pub dest: PathBuf,
+    /// Abandon the download once this flag is set, e.g. because another
+    /// source already delivered the file.
+    pub cancel: Option<Arc<AtomicBool>>,
 }
 
 pub struct TorrentDownload {
@@ -48,21 +73,47 @@ enum Command {
         request: TorrentDownloadRequest,
         reply: std_mpsc::Sender<Result<TorrentDownload, String>>,
     },
+    CountPeers {
+        info_hash: Id20,
+        wait: TokioDuration,
+        reply: std_mpsc::Sender<Result<usize, String>>,
+    },
     Shutdown,
 }
 
 impl TorrentFetcher {
-    pub fn new(work_root: PathBuf) -> MagResult<Self> {
+    /// Start a fetcher in the resume session, or in a throwaway session
+    /// when another process holds it or its saved state cannot be loaded.
+    pub fn new(work_root: PathBuf, config: TorrentConfig) -> MagResult<Self> {
         fs::create_dir_all(&work_root)?;
+        let resume_root = work_root.join(TORRENT_RESUME_SESSION);
+        fs::create_dir_all(&resume_root)?;
+        let lock_file = open_session_lock(&resume_root)?;
+        match lock_file.try_lock_exclusive() {
+            Ok(()) => match Self::start(&work_root, resume_root, lock_file, true, config.clone()) {
+                Ok(fetcher) => return Ok(fetcher),
+                Err(err) => eprintln!(
+                    "warning: could not restore interrupted torrent downloads ({err}); starting a fresh session"
+                ),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+
         let session_root = allocate_session_dir(&work_root)?;
         fs::create_dir_all(&session_root)?;
-        let lock_path = session_root.join(TORRENT_FETCHER_LOCK);
-        let lock_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&lock_path)?;
+        let lock_file = open_session_lock(&session_root)?;
         lock_file.lock_exclusive()?;
+        Self::start(&work_root, session_root, lock_file, false, config)
+    }
+
+    fn start(
+        work_root: &Path,
+        session_root: PathBuf,
+        lock_file: File,
+        persistent: bool,
+        config: TorrentConfig,
+    ) -> MagResult<Self> {
         let downloads_root = session_root.join("downloads");
         fs::create_dir_all(&downloads_root)?;
 
@@ -77,35 +128,38 @@ impl TorrentFetcher {
                 run_worker(
                     thread_session_root,
                     thread_downloads_root,
+                    persistent,
+                    config,
                     command_rx,
                     init_tx,
                 )
             })
             .map_err(|err| MagError::Generic(format!("failed to spawn torrent fetcher: {err}")))?;
 
-        match init_rx.recv() {
-            Ok(Ok(())) => Ok(Self {
-                command_tx,
-                worker: Some(worker),
-                session_root,
-                work_root,
-                _lock_file: lock_file,
-            }),
-            Ok(Err(err)) => {
-                let _ = command_tx.send(Command::Shutdown);
-                let _ = worker.join();
-                let _ = fs::remove_dir_all(&session_root);
-                Err(MagError::Generic(err))
-            }
-            Err(err) => {
-                let _ = command_tx.send(Command::Shutdown);
-                let _ = worker.join();
-                let _ = fs::remove_dir_all(&session_root);
-                Err(MagError::Generic(format!(
-                    "failed to initialise torrent fetcher: {err}"
-                )))
+        let init_error = match init_rx.recv() {
+            Ok(Ok(())) => {
+                return Ok(Self {
+                    command_tx,
+                    worker: Some(worker),
+                    session_root,
+                    work_root: work_root.to_path_buf(),
+                    persistent,
+                    _lock_file: lock_file,
+                });
             }
+            Ok(Err(err)) => err,
+            Err(err) => format!("failed to initialise torrent fetcher: {err}"),
+        };
+        let _ = command_tx.send(Command::Shutdown);
+        let _ = worker.join();
+        if persistent {
+            // The saved state is what failed to load; partial downloads
+            // stay and are verified again when they are next requested.
+            let _ = fs::remove_dir_all(session_root.join(TORRENT_SESSION_STATE_DIR));
+        } else {
+            let _ = fs::remove_dir_all(&session_root);
         }
+        Err(MagError::Generic(init_error))
     }
 
     pub fn download(&self, request: TorrentDownloadRequest) -> MagResult<TorrentDownload> {
@@ -126,6 +180,23 @@ impl TorrentFetcher {
             Err(msg) => Err(MagError::Generic(msg)),
         }
     }
+
+    /// Ask the DHT for peers of `info_hash` for up to `wait` and return how
+    /// many distinct peers it found. Nothing is downloaded.
+    pub fn count_peers(&self, info_hash: Id20, wait: TokioDuration) -> MagResult<usize> {
+        let (reply_tx, reply_rx) = std_mpsc::channel();
+        self.command_tx
+            .send(Command::CountPeers {
+                info_hash,
+                wait,
+                reply: reply_tx,
+            })
+            .map_err(|_| MagError::Generic("torrent fetcher thread is not running".into()))?;
+        reply_rx
+            .recv()
+            .map_err(|err| MagError::Generic(format!("torrent fetcher response error: {err}")))?
+            .map_err(MagError::Generic)
+    }
 }
 
 impl Drop for TorrentFetcher {
@@ -134,7 +205,9 @@ impl Drop for TorrentFetcher {
         if let Some(handle) = self.worker.take() {
             let _ = handle.join();
         }
-        let _ = fs::remove_dir_all(&self.session_root);
+        if !self.persistent {
+            let _ = fs::remove_dir_all(&self.session_root);
+        }
         let _ = fs::remove_file(self.work_root.join(TORRENT_FETCHER_LOCK));
     }
 }
@@ -142,6 +215,8 @@ impl Drop for TorrentFetcher {
 fn run_worker(
     session_root: PathBuf,
     downloads_root: PathBuf,
+    persistent: bool,
+    config: TorrentConfig,
     mut command_rx: mpsc::UnboundedReceiver<Command>,
     init_tx: std_mpsc::Sender<Result<(), String>>,
 ) {
@@ -158,7 +233,19 @@ fn run_worker(
     };
 
     runtime.block_on(async move {
-        let session = match Session::new(session_root.clone()).await {
+        let mut session_opts = SessionOptions::default();
+        if let Err(err) = config.apply_to_session(&mut session_opts) {
+            let _ = init_tx.send(Err(format!("failed to configure torrent session: {err}")));
+            return;
+        }
+        if persistent {
+            session_opts.persistence = Some(SessionPersistenceConfig::Json {
+                folder: Some(session_root.join(TORRENT_SESSION_STATE_DIR)),
+            });
+            session_opts.fastresume = true;
+        }
+
+        let session = match Session::new_with_opts(session_root.clone(), session_opts).await {
             Ok(session) => session,
             Err(err) => {
                 let _ = init_tx.send(Err(format!("failed to create torrent session: {err:#}")));
@@ -166,6 +253,15 @@ fn run_worker(
             }
         };
 
+        // Restored downloads wait until a fetch asks for them again.
+        let restored: Vec<Arc<ManagedTorrent>> =
+            session.with_torrents(|torrents| torrents.map(|(_, handle)| handle.clone()).collect());
+        for handle in restored {
+            if !handle.is_paused() {
+                let _ = session.pause(&handle).await;
+            }
+        }
+
         let _ = init_tx.send(Ok(()));
         let mut counter: u64 = 0;
 
@@ -173,12 +269,45 @@ fn run_worker(
             match command {
                 Command::Download { request, reply } => {
                     counter = counter.wrapping_add(1);
+                    let work_dir = if persistent {
+                        downloads_root.join(format!(
+                            "{}{TORRENT_WORK_MARKER}{RESUME_WORK_SUFFIX}",
+                            request.sha256
+                        ))
+                    } else {
+                        downloads_root.join(format!(
+                            "{}{TORRENT_WORK_MARKER}{counter:016x}",
+                            request.sha256
+                        ))
+                    };
                     let result =
-                        handle_download(session.clone(), &downloads_root, counter, request)
+                        handle_download(session.clone(), work_dir, !persistent, &config, request)
                             .await
                             .map_err(|err| err.to_string());
                     let _ = reply.send(result);
                 }
+                Command::CountPeers {
+                    info_hash,
+                    wait,
+                    reply,
+                } => {
+                    let Some(dht) = session.get_dht().cloned() else {
+                        let _ = reply.send(Err("the DHT is disabled in torrent.conf".into()));
+                        continue;
+                    };
+                    // Lookups run beside downloads instead of queueing behind them.
+                    tokio::spawn(async move {
+                        let mut peers = HashSet::new();
+                        let mut stream = dht.get_peers(info_hash, None);
+                        let _ = timeout(wait, async {
+                            while let Some(peer) = stream.next().await {
+                                peers.insert(peer);
+                            }
+                        })
+                        .await;
+                        let _ = reply.send(Ok(peers.len()));
+                    });
+                }
                 Command::Shutdown => break,
             }
         }
@@ -187,24 +316,56 @@ fn run_worker(
     });
 }
 
+/// Download into `work_dir`. A directory left by an interrupted run of the
+/// resume session is kept and continued; otherwise, with `fresh`, stale
+/// contents are removed first.
 async fn handle_download(
     session: Arc<Session>,
-    downloads_root: &Path,
-    counter: u64,
+    work_dir: PathBuf,
+    fresh: bool,
+    config: &TorrentConfig,
     request: TorrentDownloadRequest,
 ) -> MagResult<TorrentDownload> {
-    let work_dir = allocate_download_dir(downloads_root, &request.sha256, counter)?;
+    if fresh {
+        match fs::remove_dir_all(&work_dir) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
     fs::create_dir_all(&work_dir)?;
 
-    let handle =
-        add_torrent_to_session(&session, &work_dir, &request.url, &request.filename).await?;
+    // Resolving a magnet's metadata waits for peers too, so it counts
+    // against the stall timeout.
+    let add = add_torrent_to_session(&session, config, &work_dir, &request.url, &request.filename);
+    let added = match config.stall_timeout {
+        Some(limit) => tokio::select! {
+            result = timeout(limit, add) => result.unwrap_or_else(|_| {
+                Err(stalled_error(&request.filename, limit, "found no peers with its metadata"))
+            }),
+            _ = wait_for_cancel(request.cancel.clone()) => {
+                Err(MagError::Generic("torrent download cancelled".into()))
+            }
+        },
+        None => add.await,
+    };
+    let handle = added?;
 
     let progress = spawn_progress_logger(handle.clone(), request.filename.clone());
 
-    let download_result = handle
-        .wait_until_completed()
-        .await
-        .map_err(|err| MagError::Generic(format!("torrent download failed: {err:#}")));
+    let mut stalled = false;
+    let download_result = tokio::select! {
+        result = handle.wait_until_completed() => {
+            result.map_err(|err| MagError::Generic(format!("torrent download failed: {err:#}")))
+        }
+        _ = wait_for_cancel(request.cancel.clone()) => {
+            Err(MagError::Generic("torrent download cancelled".into()))
+        }
+        limit = wait_for_stall(handle.clone(), config.stall_timeout) => {
+            stalled = true;
+            Err(stalled_error(&request.filename, limit, "made no progress"))
+        }
+    };
 
     progress.abort();
     let _ = progress.await;
@@ -220,7 +381,16 @@ async fn handle_download(
             )
             .await
         }
+        // Keep what the resume session has so far; a later fetch picks it
+        // up if the peers come back.
+        Err(err) if stalled && !fresh => {
+            let _ = session.pause(&handle).await;
+            Err(err)
+        }
         Err(err) => {
+            let _ = session
+                .delete(TorrentIdOrHash::from(handle.id()), false)
+                .await;
             let _ = fs::remove_dir_all(&work_dir);
             Err(err)
         }
@@ -237,6 +407,7 @@ async fn handle_download(
 
 async fn add_torrent_to_session(
     session: &Arc<Session>,
+    config: &TorrentConfig,
     work_dir: &Path,
     url: &str,
     filename: &str,
@@ -244,17 +415,66 @@ async fn add_torrent_to_session(
     let mut opts = AddTorrentOptions::default();
     opts.output_folder = Some(work_dir.to_string_lossy().into_owned());
     opts.overwrite = true;
+    config.apply_to_torrent(&mut opts);
 
     let response = session
         .add_torrent(AddTorrent::from_url(url), Some(opts))
         .await
         .map_err(|err| MagError::Generic(format!("failed to add torrent {filename}: {err:#}")))?;
 
-    response.into_handle().ok_or_else(|| {
+    let handle = response.into_handle().ok_or_else(|| {
         MagError::Generic(format!(
             "torrent {filename} added without handle (list-only response)"
         ))
-    })
+    })?;
+    // A download restored from the resume session is already managed, but
+    // paused until now.
+    if handle.is_paused() {
+        session.unpause(&handle).await.map_err(|err| {
+            MagError::Generic(format!("failed to resume torrent {filename}: {err:#}"))
+        })?;
+    }
+    Ok(handle)
+}
+
+async fn wait_for_cancel(cancel: Option<Arc<AtomicBool>>) {
+    let Some(cancel) = cancel else {
+        return std::future::pending().await;
+    };
+    while !cancel.load(Ordering::SeqCst) {
+        sleep(TokioDuration::from_millis(250)).await;
+    }
+}
+
+/// Resolve once the download has gone `limit` without receiving any data,
+/// returning the limit. Never resolves without a limit.
+async fn wait_for_stall(
+    handle: Arc<ManagedTorrent>,
+    limit: Option<TokioDuration>,
+) -> TokioDuration {
+    let Some(limit) = limit else {
+        return std::future::pending().await;
+    };
+    let mut ticker = interval(STALL_CHECK_INTERVAL.min(limit));
+    let mut last_progress = handle.stats().progress_bytes;
+    let mut last_change = Instant::now();
+    loop {
+        ticker.tick().await;
+        let progress = handle.stats().progress_bytes;
+        if progress != last_progress {
+            last_progress = progress;
+            last_change = Instant::now();
+        } else if last_change.elapsed() >= limit {
+            return limit;
+        }
+    }
+}
+
+fn stalled_error(filename: &str, limit: TokioDuration, what: &str) -> MagError {
+    MagError::Generic(format!(
+        "torrent {filename} {what} in {}s; giving up on it",
+        limit.as_secs()
+    ))
 }
 
 fn spawn_progress_logger(handle: Arc<ManagedTorrent>, label: String) -> JoinHandle<()> {
@@ -268,13 +488,13 @@ fn spawn_progress_logger(handle: Arc<ManagedTorrent>, label: String) -> JoinHand
 
             if total > 0 {
                 let percent = (downloaded as f64 / total as f64 * 100.0).min(100.0);
-                println!(
+                eprintln!(
                     "torrent {label}: {} / {} ({percent:.1}%)",
                     format_bytes(downloaded as u64),
                     format_bytes(total as u64)
                 );
             } else {
-                println!(
+                eprintln!(
                     "torrent {label}: {} downloaded",
                     format_bytes(downloaded as u64)
                 );
@@ -330,7 +550,7 @@ async fn finalize_download(
         .delete(TorrentIdOrHash::from(handle.id()), false)
         .await
     {
-        println!(
+        eprintln!(
             "warning: failed to remove torrent {} from session: {err:#}",
             info_hash
         );
@@ -343,6 +563,14 @@ async fn finalize_download(
     })
 }
 
+fn open_session_lock(session_root: &Path) -> MagResult<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(session_root.join(TORRENT_FETCHER_LOCK))?)
+}
+
 fn allocate_session_dir(work_root: &Path) -> MagResult<PathBuf> {
     let mut rng_seed = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -363,18 +591,6 @@ fn allocate_session_dir(work_root: &Path) -> MagResult<PathBuf> {
     ))
 }
 
-fn allocate_download_dir(downloads_root: &Path, sha: &str, counter: u64) -> MagResult<PathBuf> {
-    let dir = downloads_root.join(format!("{sha}{TORRENT_WORK_MARKER}{counter:016x}"));
-    if dir.exists() {
-        match fs::remove_dir_all(&dir) {
-            Ok(()) => {}
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
-            Err(err) => return Err(err.into()),
-        }
-    }
-    Ok(dir)
-}
-
 fn format_bytes(bytes: u64) -> String {
     const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
     let mut value = bytes as f64;
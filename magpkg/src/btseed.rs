@@ -1,29 +1,56 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     fs::{self, File},
-    io::ErrorKind,
+    io::{ErrorKind, Read},
+    net::SocketAddr,
     path::{Path, PathBuf},
     str,
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use fs2::FileExt;
 use librqbit::dht::Id20;
 use librqbit::{
-    AddTorrent, AddTorrentOptions, AddTorrentResponse, ByteBufOwned, ManagedTorrent, ParsedTorrent,
-    Session, SessionOptions, torrent_from_bytes_ext,
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, ByteBufOwned, ListenerMode, ListenerOptions,
+    ManagedTorrent, ParsedTorrent, Session, SessionOptions, torrent_from_bytes_ext,
 };
+use ring::digest::{SHA1_FOR_LEGACY_USE_ONLY, digest};
 use tokio::runtime::Builder as TokioRuntimeBuilder;
 use tokio::signal;
+use tokio::task::{Id as TaskId, JoinSet};
 use tokio::time::{Duration as TokioDuration, interval};
 
-use crate::{MagError, MagResult};
+use crate::{
+    MagError, MagResult,
+    btconfig::TorrentConfig,
+    store::{format_bytes, touch_path},
+};
 
 pub const SEED_LOCK_FILE: &str = "seed.lock";
+/// Left in a torrent directory whose payload `seed --max-storage` removed.
+/// The payload comes back from the fetch cache the next time the source is
+/// fetched or its torrents are refreshed.
+pub const EVICTED_MARKER: &str = "evicted";
+/// How many payloads are hashed at once.
+const VERIFY_CONCURRENCY: usize = 2;
 
 pub struct TorrentSeeder {
     torrent_root: PathBuf,
     lock_path: PathBuf,
+    watch_roots: Vec<WatchRoot>,
+    config: TorrentConfig,
+    max_storage: Option<u64>,
+    /// Whether payloads are hashed against their torrent before seeding.
+    verify: bool,
+}
+
+/// A directory scanned for torrents to seed. The store's own `torrent/`
+/// tree is always the first root; extra roots may point at other stores
+/// (e.g. an NFS export) or at a directory of manually added `.torrent` files.
+struct WatchRoot {
+    path: PathBuf,
+    enabled: bool,
 }
 
 pub struct SeedLock {
@@ -39,17 +66,232 @@ pub struct TorrentSeedInfo {
 struct ActiveSeed {
     handle: Arc<ManagedTorrent>,
     display_name: String,
+    torrent_dir: PathBuf,
+    data_path: PathBuf,
+    torrent_bytes: Vec<u8>,
+    /// Bytes uploaded as of the last scan, to notice new requests.
+    uploaded: u64,
+}
+
+/// A torrent the seeder would serve, as reported by [`TorrentSeeder::seedable`].
+pub struct SeedableTorrent {
+    pub info_hash: String,
+    pub display_name: String,
+    pub torrent_dir: PathBuf,
 }
 
 struct SeedPlan {
     info_hash: String,
     display_name: String,
     torrent_dir: PathBuf,
+    data_path: PathBuf,
     torrent_bytes: Vec<u8>,
 }
 
+/// Size and modification time of a payload, to notice when it is replaced.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct PayloadStamp {
+    size: u64,
+    modified: SystemTime,
+}
+
+impl PayloadStamp {
+    fn read(path: &Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        Some(Self {
+            size: meta.len(),
+            modified: meta.modified().ok()?,
+        })
+    }
+}
+
+/// When [`PayloadChecks::ready`] hashes a payload.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Verify {
+    /// Unless it was verified and has not changed since.
+    IfChanged,
+    /// Never; only earlier failures keep it from being seeded.
+    Never,
+}
+
+/// A payload waiting for, or in, piece verification.
+struct PendingCheck {
+    plan: SeedPlan,
+    stamp: PayloadStamp,
+    /// Whether the payload is already seeded, so a failure stops it rather
+    /// than keeping it from starting.
+    seeded: bool,
+}
+
+/// Outcome of piece verification for each payload the seeder has seen, by
+/// info hash. A payload is checked again when it changes on disk, and
+/// seeded payloads also when `verify-interval` has passed since their last
+/// check. Payloads are hashed in the background, [`VERIFY_CONCURRENCY`] at
+/// a time, and each one is seeded as soon as its own check passes.
+#[derive(Default)]
+struct PayloadChecks {
+    verified: HashMap<String, (PayloadStamp, Instant)>,
+    failed: HashMap<String, PayloadStamp>,
+    /// Payloads waiting for a hashing slot, oldest first.
+    queued: VecDeque<PendingCheck>,
+    /// Info hashes that are queued or being hashed.
+    pending: HashSet<String>,
+    running: JoinSet<(PendingCheck, MagResult<Option<String>>)>,
+    /// Info hash checked by each running task, for tasks that die without
+    /// returning their check.
+    tasks: HashMap<TaskId, String>,
+}
+
+impl PayloadChecks {
+    /// The plan back when its payload may be seeded right away. A payload
+    /// that has to be hashed first is queued instead and comes back from
+    /// [`PayloadChecks::finished`]. A payload that failed verification
+    /// stays refused until it changes on disk.
+    fn ready(&mut self, plan: SeedPlan, mode: Verify) -> Option<SeedPlan> {
+        if self.pending.contains(&plan.info_hash) {
+            return None;
+        }
+        let stamp = PayloadStamp::read(&plan.data_path)?;
+        if self.failed.get(&plan.info_hash) == Some(&stamp) {
+            return None;
+        }
+        let unchanged = self
+            .verified
+            .get(&plan.info_hash)
+            .is_some_and(|(verified, _)| *verified == stamp);
+        match mode {
+            Verify::IfChanged if unchanged => Some(plan),
+            Verify::Never => {
+                if !unchanged {
+                    self.verified
+                        .insert(plan.info_hash.clone(), (stamp, Instant::now()));
+                }
+                Some(plan)
+            }
+            Verify::IfChanged => {
+                self.queue(plan, stamp, false);
+                None
+            }
+        }
+    }
+
+    /// Hash the payload of a seeded torrent again, leaving it seeded until
+    /// the check fails.
+    fn recheck(&mut self, plan: SeedPlan) {
+        if self.pending.contains(&plan.info_hash) {
+            return;
+        }
+        if let Some(stamp) = PayloadStamp::read(&plan.data_path) {
+            self.queue(plan, stamp, true);
+        }
+    }
+
+    fn queue(&mut self, plan: SeedPlan, stamp: PayloadStamp, seeded: bool) {
+        self.pending.insert(plan.info_hash.clone());
+        self.queued.push_back(PendingCheck {
+            plan,
+            stamp,
+            seeded,
+        });
+        self.start();
+    }
+
+    /// Start hashing queued payloads while there are free slots.
+    fn start(&mut self) {
+        while self.running.len() < VERIFY_CONCURRENCY {
+            let Some(check) = self.queued.pop_front() else {
+                break;
+            };
+            let path = check.plan.data_path.clone();
+            let bytes = check.plan.torrent_bytes.clone();
+            let info_hash = check.plan.info_hash.clone();
+            let task = self.running.spawn(async move {
+                let result = tokio::task::spawn_blocking(move || verify_payload(&bytes, &path))
+                    .await
+                    .unwrap_or_else(|err| {
+                        Err(MagError::Generic(format!("hashing task failed: {err}")))
+                    });
+                (check, result)
+            });
+            self.tasks.insert(task.id(), info_hash);
+        }
+    }
+
+    /// Whether a payload is being hashed.
+    fn busy(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    /// Wait for the next payload check to finish and record its outcome.
+    /// Returns the check and whether the payload passed, or `None` when the
+    /// task died. A failure is reported once per version of the payload.
+    async fn finished(&mut self) -> Option<(PendingCheck, bool)> {
+        let (check, result) = match self.running.join_next_with_id().await? {
+            Ok((id, outcome)) => {
+                self.tasks.remove(&id);
+                outcome
+            }
+            Err(err) => {
+                // The check is lost with the task. Forgetting it lets the
+                // next scan queue the payload again.
+                if let Some(info_hash) = self.tasks.remove(&err.id()) {
+                    self.pending.remove(&info_hash);
+                    println!("warning: seeder: checking {info_hash} failed: {err}");
+                }
+                self.start();
+                return None;
+            }
+        };
+        self.pending.remove(&check.plan.info_hash);
+        self.start();
+
+        let info_hash = check.plan.info_hash.clone();
+        let reason = match result {
+            Ok(None) => {
+                self.verified
+                    .insert(info_hash.clone(), (check.stamp, Instant::now()));
+                self.failed.remove(&info_hash);
+                return Some((check, true));
+            }
+            Ok(Some(reason)) => reason,
+            Err(err) => format!("failed to verify the payload: {err:#}"),
+        };
+        println!(
+            "seeder: not seeding {info_hash} ({}): {reason}; skipping {} until it changes",
+            check.plan.display_name,
+            check.plan.data_path.display()
+        );
+        self.verified.remove(&info_hash);
+        self.failed.insert(info_hash, check.stamp);
+        Some((check, false))
+    }
+
+    /// Info hashes of `active` seeds last verified more than `interval` ago.
+    fn due(&self, active: &HashMap<String, ActiveSeed>, interval: Duration) -> Vec<String> {
+        active
+            .keys()
+            .filter(|info_hash| {
+                self.verified
+                    .get(*info_hash)
+                    .is_none_or(|(_, checked)| checked.elapsed() >= interval)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// A payload copy under the store's `torrent/` tree that the storage cap
+/// may evict.
+struct StoredPayload {
+    info_hash: String,
+    torrent_dir: PathBuf,
+    data_path: PathBuf,
+    size: u64,
+    last_requested: SystemTime,
+}
+
 impl TorrentSeeder {
-    pub fn new(watch_dir: impl Into<PathBuf>) -> MagResult<Self> {
+    pub fn new(watch_dir: impl Into<PathBuf>, config: TorrentConfig) -> MagResult<Self> {
         let torrent_root = watch_dir.into();
         if torrent_root.as_os_str().is_empty() {
             return Err(MagError::Generic(
@@ -60,14 +302,83 @@ impl TorrentSeeder {
         fs::create_dir_all(&torrent_root)?;
 
         let lock_path = seed_lock_path(&torrent_root);
+        let watch_roots = vec![WatchRoot {
+            path: torrent_root.clone(),
+            enabled: true,
+        }];
 
         Ok(Self {
             torrent_root,
             lock_path,
+            watch_roots,
+            config,
+            max_storage: None,
+            verify: true,
         })
     }
 
-    pub fn run(&self, listen_port: Option<u16>) -> MagResult<()> {
+    /// Whether payloads are hashed against their torrent's piece hashes
+    /// before they are seeded (the default). `verify-interval` in
+    /// torrent.conf re-checks seeded payloads either way.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Cap the disk used by payload copies in the store's `torrent/` tree.
+    /// Payloads of other watch roots are never evicted.
+    pub fn set_max_storage(&mut self, max_storage: Option<u64>) {
+        self.max_storage = max_storage;
+    }
+
+    /// Enable or disable seeding from the store's own torrent directory.
+    pub fn set_store_root_enabled(&mut self, enabled: bool) {
+        self.watch_roots[0].enabled = enabled;
+    }
+
+    pub fn add_watch_root(&mut self, path: impl Into<PathBuf>) -> MagResult<()> {
+        let path = path.into();
+        if path.as_os_str().is_empty() {
+            return Err(MagError::Generic(
+                "torrent watch directory must not be empty".into(),
+            ));
+        }
+        if self.watch_roots.iter().any(|root| root.path == path) {
+            return Ok(());
+        }
+        self.watch_roots.push(WatchRoot {
+            path,
+            enabled: true,
+        });
+        Ok(())
+    }
+
+    /// Every torrent found in the enabled roots, merged the same way the
+    /// seeding loop merges them. Unreadable roots and torrents are skipped.
+    pub fn seedable(&self) -> Vec<SeedableTorrent> {
+        let mut planned = HashSet::new();
+        let mut seedable = Vec::new();
+        for root in self.watch_roots.iter().filter(|root| root.enabled) {
+            let Ok((plans, _warnings)) = scan_torrent_directory(root.path.clone()) else {
+                continue;
+            };
+            for plan in plans {
+                if planned.insert(plan.info_hash.clone()) {
+                    seedable.push(SeedableTorrent {
+                        info_hash: plan.info_hash,
+                        display_name: plan.display_name,
+                        torrent_dir: plan.torrent_dir,
+                    });
+                }
+            }
+        }
+        seedable.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        seedable
+    }
+
+    /// Seed until interrupted, accepting peers on `listen` when given. An
+    /// unspecified IPv6 address (`[::]`) accepts IPv4 peers too unless the
+    /// host sets `net.ipv6.bindv6only`.
+    pub fn run(&self, listen: Option<SocketAddr>) -> MagResult<()> {
         let lock = acquire_seed_lock(&self.lock_path)?;
         println!("seeder lock acquired at {}", self.lock_path.display());
 
@@ -77,51 +388,112 @@ impl TorrentSeeder {
             .build()
             .map_err(|err| MagError::Generic(format!("failed to build tokio runtime: {err}")))?;
 
-        let result = runtime.block_on(self.run_seed_loop(listen_port));
+        let result = runtime.block_on(self.run_seed_loop(listen));
+        // A payload still being hashed only delays the exit.
+        runtime.shutdown_background();
 
         drop(lock);
         result
     }
 
-    async fn run_seed_loop(&self, listen_port: Option<u16>) -> MagResult<()> {
+    async fn run_seed_loop(&self, listen: Option<SocketAddr>) -> MagResult<()> {
         let mut session_opts = SessionOptions::default();
 
-        if let Some(port) = listen_port {
-            if port == u16::MAX {
-                return Err(MagError::Generic(
-                    "listen port must be lower than 65535".into(),
-                ));
-            }
-            session_opts.listen_port_range = Some(port..(port + 1));
+        if let Some(listen_addr) = listen {
+            session_opts.listen = Some(ListenerOptions {
+                mode: if self.config.utp {
+                    ListenerMode::TcpAndUtp
+                } else {
+                    ListenerMode::TcpOnly
+                },
+                listen_addr,
+                enable_upnp_port_forwarding: self.config.upnp,
+                utp_opts: None,
+            });
         }
+        self.config.apply_to_session(&mut session_opts)?;
 
         let session = Session::new_with_opts(self.torrent_root.clone(), session_opts)
             .await
             .map_err(|err| {
-                MagError::Generic(format!("failed to start seeding session: {err:#}"))
+                let hint = match listen {
+                    Some(addr) if addr.is_ipv6() => {
+                        " (is IPv6 enabled? pass --listen-addr 0.0.0.0 to listen on IPv4 only)"
+                    }
+                    _ => "",
+                };
+                MagError::Generic(format!("failed to start seeding session: {err:#}{hint}"))
             })?;
 
-        if let Some(port) = session.tcp_listen_port() {
-            println!("seeder listening on TCP port {port}");
+        if let (Some(addr), Some(port)) = (listen, session.tcp_listen_port()) {
+            println!("seeder listening on {}", SocketAddr::new(addr.ip(), port));
         } else {
             println!("seeder running without TCP listener");
         }
+        for root in &self.watch_roots {
+            if root.enabled {
+                println!("seeder: watching {}", root.path.display());
+            }
+        }
         println!("torrent seeder started; press Ctrl+C to stop");
 
+        if let Some(max_storage) = self.max_storage {
+            println!(
+                "seeder: payload copies capped at {}",
+                format_bytes(max_storage)
+            );
+        }
+        if let Some(interval) = self.config.verify_interval {
+            println!(
+                "seeder: re-verifying seeded payloads every {} hour(s)",
+                interval.as_secs() / 3600
+            );
+        }
         let mut active: HashMap<String, ActiveSeed> = HashMap::new();
-        if let Err(err) = self.sync_seeding_iteration(&session, &mut active).await {
+        let mut checks = PayloadChecks::default();
+        if let Err(err) = self.enforce_max_storage(&session, &mut active).await {
+            println!("seeder: storage cap error: {err:#}");
+        }
+        if let Err(err) = self
+            .sync_seeding_iteration(&session, &mut active, &mut checks)
+            .await
+        {
             println!("initial seeding scan error: {err:#}");
         }
 
         let mut ticker = interval(TokioDuration::from_secs(15));
+        let interrupted = signal::ctrl_c();
+        tokio::pin!(interrupted);
         loop {
             tokio::select! {
-                _ = signal::ctrl_c() => {
+                _ = &mut interrupted => {
                     println!("interrupt received, shutting down seeder...");
                     break;
                 }
+                Some((check, passed)) = checks.finished(), if checks.busy() => {
+                    let info_hash = &check.plan.info_hash;
+                    if !check.seeded {
+                        if passed && !active.contains_key(info_hash) {
+                            self.start_seeding(&session, &mut active, check.plan).await;
+                        }
+                    } else if !passed {
+                        if let Some(seed) = active.remove(info_hash) {
+                            if let Err(err) = session.delete(seed.handle.id().into(), false).await {
+                                println!("warning: failed to stop torrent {info_hash}: {err:#}");
+                            }
+                        }
+                    }
+                }
                 _ = ticker.tick() => {
-                    if let Err(err) = self.sync_seeding_iteration(&session, &mut active).await {
+                    record_requests(&mut active);
+                    if let Err(err) = self.enforce_max_storage(&session, &mut active).await {
+                        println!("seeder: storage cap error: {err:#}");
+                    }
+                    self.reverify_seeds(&active, &mut checks);
+                    if let Err(err) = self
+                        .sync_seeding_iteration(&session, &mut active, &mut checks)
+                        .await
+                    {
                         println!("seeding loop error: {err:#}");
                     }
                 }
@@ -146,8 +518,27 @@ impl TorrentSeeder {
         &self,
         session: &Arc<Session>,
         active: &mut HashMap<String, ActiveSeed>,
+        checks: &mut PayloadChecks,
     ) -> MagResult<()> {
-        let (plans, warnings) = scan_torrent_directory(self.torrent_root.clone())?;
+        let mut plans = Vec::new();
+        let mut warnings = Vec::new();
+        let mut planned: HashSet<String> = HashSet::new();
+        for root in self.watch_roots.iter().filter(|root| root.enabled) {
+            let (root_plans, root_warnings) = match scan_torrent_directory(root.path.clone()) {
+                Ok(result) => result,
+                Err(err) => {
+                    warnings.push(format!("failed to scan {}: {err:#}", root.path.display()));
+                    continue;
+                }
+            };
+            warnings.extend(root_warnings);
+            for plan in root_plans {
+                // The first root that provides an info hash wins.
+                if planned.insert(plan.info_hash.clone()) {
+                    plans.push(plan);
+                }
+            }
+        }
 
         for warning in warnings {
             println!("seeder: {warning}");
@@ -174,61 +565,196 @@ impl TorrentSeeder {
             }
         }
 
+        let mode = if self.verify {
+            Verify::IfChanged
+        } else {
+            Verify::Never
+        };
         for plan in plans {
             if active.contains_key(&plan.info_hash) {
                 continue;
             }
+            if let Some(plan) = checks.ready(plan, mode) {
+                self.start_seeding(session, active, plan).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the torrent of `plan` to the session and record it in `active`.
+    async fn start_seeding(
+        &self,
+        session: &Arc<Session>,
+        active: &mut HashMap<String, ActiveSeed>,
+        plan: SeedPlan,
+    ) {
+        let SeedPlan {
+            info_hash,
+            display_name,
+            torrent_dir,
+            data_path,
+            torrent_bytes,
+        } = plan;
+        let mut opts = AddTorrentOptions::default();
+        opts.paused = false;
+        // Allow librqbit to adopt the existing on-disk payload instead of
+        // failing with EEXIST when the file is already present.
+        opts.overwrite = true;
+        opts.output_folder = Some(torrent_dir.to_string_lossy().into_owned());
+        self.config.apply_to_torrent(&mut opts);
+
+        match session
+            .add_torrent(AddTorrent::from_bytes(torrent_bytes.clone()), Some(opts))
+            .await
+        {
+            Ok(AddTorrentResponse::Added(_, handle))
+            | Ok(AddTorrentResponse::AlreadyManaged(_, handle)) => {
+                if let Err(err) = session.unpause(&handle).await {
+                    println!("warning: failed to unpause torrent {info_hash}: {err:#}");
+                    return;
+                }
+                println!("seeder: now seeding {info_hash} ({display_name})");
+                let uploaded = handle.stats().uploaded_bytes;
+                active.insert(
+                    info_hash,
+                    ActiveSeed {
+                        handle,
+                        display_name,
+                        torrent_dir,
+                        data_path,
+                        torrent_bytes,
+                        uploaded,
+                    },
+                );
+            }
+            Ok(AddTorrentResponse::ListOnly(_)) => {
+                println!(
+                    "warning: torrent {info_hash} ({display_name}) returned list-only response"
+                );
+            }
+            Err(err) => {
+                println!("warning: failed to add torrent {info_hash} ({display_name}): {err:#}");
+            }
+        }
+    }
 
-            let SeedPlan {
+    /// Queue the payloads of seeds whose last check is older than
+    /// `verify-interval` for hashing again. Those that no longer match
+    /// stop seeding when their check finishes.
+    fn reverify_seeds(&self, active: &HashMap<String, ActiveSeed>, checks: &mut PayloadChecks) {
+        let Some(interval) = self.config.verify_interval else {
+            return;
+        };
+        for info_hash in checks.due(active, interval) {
+            let Some(seed) = active.get(&info_hash) else {
+                continue;
+            };
+            checks.recheck(SeedPlan {
                 info_hash,
-                display_name,
-                torrent_dir,
-                torrent_bytes,
-            } = plan;
-
-            let mut opts = AddTorrentOptions::default();
-            opts.paused = false;
-            // Allow librqbit to adopt the existing on-disk payload instead of
-            // failing with EEXIST when the file is already present.
-            opts.overwrite = true;
-            opts.output_folder = Some(torrent_dir.to_string_lossy().into_owned());
-
-            match session
-                .add_torrent(AddTorrent::from_bytes(torrent_bytes), Some(opts))
-                .await
-            {
-                Ok(AddTorrentResponse::Added(_, handle))
-                | Ok(AddTorrentResponse::AlreadyManaged(_, handle)) => {
-                    if let Err(err) = session.unpause(&handle).await {
-                        println!("warning: failed to unpause torrent {info_hash}: {err:#}");
-                        continue;
-                    }
-                    println!("seeder: now seeding {info_hash} ({display_name})");
-                    active.insert(
-                        info_hash,
-                        ActiveSeed {
-                            handle,
-                            display_name,
-                        },
-                    );
-                }
-                Ok(AddTorrentResponse::ListOnly(_)) => {
-                    println!(
-                        "warning: torrent {info_hash} ({display_name}) returned list-only response"
-                    );
-                }
-                Err(err) => {
+                display_name: seed.display_name.clone(),
+                torrent_dir: seed.torrent_dir.clone(),
+                data_path: seed.data_path.clone(),
+                torrent_bytes: seed.torrent_bytes.clone(),
+            });
+        }
+    }
+
+    /// Evict the least recently requested payload copies until those in
+    /// the store's `torrent/` tree fit within `--max-storage`. Evicted
+    /// torrents leave the session and keep their metadata and an
+    /// [`EVICTED_MARKER`].
+    async fn enforce_max_storage(
+        &self,
+        session: &Arc<Session>,
+        active: &mut HashMap<String, ActiveSeed>,
+    ) -> MagResult<()> {
+        let Some(max_storage) = self.max_storage else {
+            return Ok(());
+        };
+        if !self.watch_roots[0].enabled {
+            return Ok(());
+        }
+
+        let mut payloads = stored_payloads(&self.torrent_root)?;
+        let mut total: u64 = payloads.iter().map(|payload| payload.size).sum();
+        if total <= max_storage {
+            return Ok(());
+        }
+        payloads.sort_by_key(|payload| payload.last_requested);
+
+        for payload in payloads {
+            if total <= max_storage {
+                break;
+            }
+            if let Some(active_seed) = active.remove(&payload.info_hash) {
+                let id = active_seed.handle.id();
+                if let Err(err) = session.delete(id.into(), false).await {
                     println!(
-                        "warning: failed to add torrent {info_hash} ({display_name}): {err:#}"
+                        "warning: failed to stop torrent {}: {err:#}",
+                        payload.info_hash
                     );
+                    active.insert(payload.info_hash, active_seed);
+                    continue;
                 }
             }
+            match fs::remove_file(&payload.data_path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            File::create(payload.torrent_dir.join(EVICTED_MARKER))?;
+            total = total.saturating_sub(payload.size);
+            println!(
+                "seeder: evicted {} ({}) to stay within {}",
+                payload.info_hash,
+                format_bytes(payload.size),
+                format_bytes(max_storage)
+            );
         }
-
         Ok(())
     }
 }
 
+/// Touch the directory of every torrent that uploaded data since the last
+/// scan, so its modification time records when peers last requested it.
+fn record_requests(active: &mut HashMap<String, ActiveSeed>) {
+    for active_seed in active.values_mut() {
+        let uploaded = active_seed.handle.stats().uploaded_bytes;
+        if uploaded > active_seed.uploaded {
+            active_seed.uploaded = uploaded;
+            let _ = touch_path(&active_seed.torrent_dir);
+        }
+    }
+}
+
+/// Payload copies in `<info-hash>/` directories of the store's torrent tree.
+fn stored_payloads(torrent_root: &Path) -> MagResult<Vec<StoredPayload>> {
+    let mut payloads = Vec::new();
+    for entry in fs::read_dir(torrent_root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let torrent_dir = entry.path();
+        let Ok(seed_info) = load_torrent_seed_info(&torrent_dir.join("resource.torrent")) else {
+            continue;
+        };
+        let data_path = torrent_dir.join(&seed_info.relative_path);
+        let Ok(meta) = fs::metadata(&data_path) else {
+            continue;
+        };
+        payloads.push(StoredPayload {
+            info_hash: seed_info.info_hash,
+            size: meta.len(),
+            last_requested: entry.metadata()?.modified()?,
+            torrent_dir,
+            data_path,
+        });
+    }
+    Ok(payloads)
+}
+
 pub fn seed_lock_path(torrent_root: &Path) -> PathBuf {
     torrent_root.join(SEED_LOCK_FILE)
 }
@@ -321,7 +847,14 @@ fn scan_torrent_directory(torrent_root: PathBuf) -> MagResult<(Vec<SeedPlan>, Ve
 
     for entry in fs::read_dir(&torrent_root)? {
         let entry = entry?;
-        if !entry.file_type()?.is_dir() {
+        let file_type = entry.file_type()?;
+        if file_type.is_file() {
+            if let Some(plan) = plan_loose_torrent(&torrent_root, &entry.path(), &mut warnings) {
+                plans.push(plan);
+            }
+            continue;
+        }
+        if !file_type.is_dir() {
             continue;
         }
 
@@ -344,6 +877,9 @@ fn scan_torrent_directory(torrent_root: PathBuf) -> MagResult<(Vec<SeedPlan>, Ve
 
         let data_path = dir_path.join(&seed_info.relative_path);
         if !data_path.exists() {
+            if dir_path.join(EVICTED_MARKER).exists() {
+                continue;
+            }
             warnings.push(format!(
                 "skipping torrent {}: payload missing at {}",
                 seed_info.info_hash,
@@ -357,6 +893,7 @@ fn scan_torrent_directory(torrent_root: PathBuf) -> MagResult<(Vec<SeedPlan>, Ve
             info_hash: seed_info.info_hash,
             display_name,
             torrent_dir: dir_path,
+            data_path,
             torrent_bytes: seed_info.bytes,
         });
     }
@@ -364,6 +901,103 @@ fn scan_torrent_directory(torrent_root: PathBuf) -> MagResult<(Vec<SeedPlan>, Ve
     Ok((plans, warnings))
 }
 
+/// Plan a manually added `<name>.torrent` whose payload sits next to it.
+fn plan_loose_torrent(
+    torrent_root: &Path,
+    torrent_path: &Path,
+    warnings: &mut Vec<String>,
+) -> Option<SeedPlan> {
+    if torrent_path.extension().and_then(|ext| ext.to_str()) != Some("torrent") {
+        return None;
+    }
+
+    let seed_info = match load_torrent_seed_info(torrent_path) {
+        Ok(info) => info,
+        Err(err) => {
+            warnings.push(format!(
+                "failed to read {}: {err:#}",
+                torrent_path.display()
+            ));
+            return None;
+        }
+    };
+
+    let data_path = torrent_root.join(&seed_info.relative_path);
+    if !data_path.exists() {
+        warnings.push(format!(
+            "skipping torrent {}: payload missing at {}",
+            seed_info.info_hash,
+            data_path.display()
+        ));
+        return None;
+    }
+
+    Some(SeedPlan {
+        info_hash: seed_info.info_hash,
+        display_name: seed_info.relative_path.display().to_string(),
+        torrent_dir: torrent_root.to_path_buf(),
+        data_path,
+        torrent_bytes: seed_info.bytes,
+    })
+}
+
+/// Hash the payload of a single-file torrent piece by piece and compare the
+/// SHA-1 digests with the torrent's piece table. Returns why the payload
+/// does not match, or `None` when every piece does. Torrents of several
+/// files are refused, since their pieces span files other than `data_path`.
+pub fn verify_payload(torrent_bytes: &[u8], data_path: &Path) -> MagResult<Option<String>> {
+    let parsed: ParsedTorrent<ByteBufOwned> = torrent_from_bytes_ext(torrent_bytes)
+        .map_err(|err| MagError::Generic(format!("failed to parse torrent metadata: {err:#}")))?;
+    let info = parsed.meta.info;
+    let expected_len = match &info.files {
+        Some(files) if files.len() == 1 => files[0].length,
+        Some(files) => {
+            return Err(MagError::Generic(format!(
+                "torrent lists {} files; only single-file torrents can be verified",
+                files.len()
+            )));
+        }
+        None => info.length.unwrap_or(0),
+    };
+    let piece_length = u64::from(info.piece_length);
+    let pieces: &[u8] = info.pieces.as_ref();
+    let piece_count = expected_len.div_ceil(piece_length.max(1));
+    if piece_length == 0 || pieces.len() as u64 != piece_count * 20 {
+        return Err(MagError::Generic(format!(
+            "torrent lists {} piece hash bytes for {piece_count} piece(s)",
+            pieces.len()
+        )));
+    }
+
+    let mut file = File::open(data_path)?;
+    let actual_len = file.metadata()?.len();
+    if actual_len != expected_len {
+        return Ok(Some(format!(
+            "payload is {} but the torrent expects {}",
+            format_bytes(actual_len),
+            format_bytes(expected_len)
+        )));
+    }
+
+    let mut buf = vec![0u8; piece_length as usize];
+    let mut bad = Vec::new();
+    for (index, expected) in pieces.chunks(20).enumerate() {
+        let offset = index as u64 * piece_length;
+        let len = piece_length.min(expected_len - offset) as usize;
+        file.read_exact(&mut buf[..len])?;
+        if digest(&SHA1_FOR_LEGACY_USE_ONLY, &buf[..len]).as_ref() != expected {
+            bad.push(index);
+        }
+    }
+    Ok(match bad.as_slice() {
+        [] => None,
+        [first, ..] => Some(format!(
+            "{} of {piece_count} piece(s) do not match, starting with piece {first}",
+            bad.len()
+        )),
+    })
+}
+
 fn info_hash_to_hex(id: Id20) -> String {
     hex::encode(id.0)
 }
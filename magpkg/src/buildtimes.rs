@@ -0,0 +1,143 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{MagResult, package::Package};
+
+pub const BUILD_TIMES_FILE: &str = "build-times";
+
+/// How long packages took to build the last time they were built from
+/// source, kept in `<store>/build-times` to estimate the remaining time of
+/// later builds.
+///
+/// ```text
+/// gcc        1843.2
+/// zlib       12.7
+/// ```
+///
+/// Entries are keyed by package name (the hash for unnamed packages), so an
+/// estimate survives version bumps that change the hash. Concurrent builds
+/// may overwrite each other's updates; the file only feeds estimates.
+pub struct BuildHistory {
+    path: PathBuf,
+    durations: BTreeMap<String, Duration>,
+}
+
+impl BuildHistory {
+    /// Load the history; a missing or unreadable file starts an empty one.
+    pub fn load(base_root: &Path) -> Self {
+        let path = base_root.join(BUILD_TIMES_FILE);
+        let durations = fs::read_to_string(&path)
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let (key, seconds) = line.split_once('\t')?;
+                        let seconds: f64 = seconds.trim().parse().ok()?;
+                        let duration = Duration::try_from_secs_f64(seconds).ok()?;
+                        Some((key.to_string(), duration))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { path, durations }
+    }
+
+    pub fn get(&self, package: &Package) -> Option<Duration> {
+        self.durations.get(history_key(package)).copied()
+    }
+
+    /// Record a completed build from source and rewrite the file.
+    pub fn record(&mut self, package: &Package, duration: Duration) -> MagResult<()> {
+        self.durations
+            .insert(history_key(package).to_string(), duration);
+
+        let tmp_path = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for (key, duration) in &self.durations {
+                writeln!(writer, "{key}\t{:.1}", duration.as_secs_f64())?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn history_key(package: &Package) -> &str {
+    package
+        .name
+        .as_deref()
+        .filter(|name| !name.contains(['\t', '\n']))
+        .unwrap_or(&package.hash)
+}
+
+/// Graph-level progress for a build: which packages still need work and
+/// how long they took last time.
+pub struct BuildProgress {
+    total: usize,
+    done: usize,
+    /// Historical duration of each package that still has to be built;
+    /// `None` for packages never built from source.
+    pending: BTreeMap<String, Option<Duration>>,
+}
+
+impl BuildProgress {
+    /// `to_build` lists the packages without a published artifact, by base
+    /// name, with their historical build time.
+    pub fn new(total: usize, to_build: Vec<(String, Option<Duration>)>) -> Self {
+        Self {
+            total,
+            done: 0,
+            pending: to_build.into_iter().collect(),
+        }
+    }
+
+    pub fn finish(&mut self, base: &str) {
+        self.done += 1;
+        self.pending.remove(base);
+    }
+
+    /// Whether `base` still has to be built, i.e. is worth a progress line.
+    pub fn is_pending(&self, base: &str) -> bool {
+        self.pending.contains_key(base)
+    }
+
+    /// "14/37 packages, ~22 min remaining (+3 never built)"
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{}/{} packages", self.done, self.total);
+        let known: Duration = self.pending.values().flatten().sum();
+        let unknown = self.pending.values().filter(|time| time.is_none()).count();
+        if known > Duration::ZERO {
+            summary.push_str(&format!(", ~{} remaining", format_estimate(known)));
+            if unknown > 0 {
+                summary.push_str(&format!(" (+{unknown} never built)"));
+            }
+        } else if unknown > 0 {
+            summary.push_str(&format!(", {unknown} never built"));
+        }
+        summary
+    }
+}
+
+fn format_estimate(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds < 60 {
+        format!("{}s", seconds.max(1))
+    } else if seconds < 3600 {
+        format!("{} min", seconds.div_ceil(60))
+    } else {
+        format!(
+            "{}h{:02}",
+            seconds / 3600,
+            (seconds % 3600).div_ceil(60).min(59)
+        )
+    }
+}
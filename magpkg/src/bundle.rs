@@ -0,0 +1,311 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use ring::signature::{ED25519, Ed25519KeyPair, UnparsedPublicKey};
+use sha2::{Digest, Sha256};
+use tar::{Builder, EntryType, Header};
+
+use crate::{MagError, MagResult, index::public_key_hex, store::DependencyRecord};
+
+pub const BUNDLE_METADATA: &str = "bundle";
+const BUNDLE_HEADER: &str = "# magpkg artifact bundle";
+const BUNDLE_VERSION: &str = "2";
+/// Versions this magpkg reads; version 1 records carry no dependency lines.
+const READABLE_BUNDLE_VERSIONS: [&str; 2] = ["1", "2"];
+
+/// Description of the single package artifact carried by a bundle file.
+///
+/// A bundle is an uncompressed tar archive holding this record as its first
+/// member, named `bundle`, followed by the artifact as `<base>.tar.zst`.
+/// The record is line oriented like release indexes; when present, the
+/// `signature` line covers every byte before it, including the artifact's
+/// sha256. The `hash`, `name`, `run`, `build`, and `fetch` lines repeat the
+/// exporting store's `.deps` record when it has one:
+///
+/// ```text
+/// # magpkg artifact bundle
+/// version    1
+/// base       zlib-5f0c...
+/// sha256     <sha256 of the artifact>
+/// size       <artifact size in bytes>
+/// exported   <unix seconds>
+/// origin     <hostname of the exporting machine>
+/// hash       <package hash>
+/// name       <package name>
+/// run        <base name of a runtime dependency>
+/// build      <base name of a build dependency>
+/// fetch      <sha256 of a fetched source>
+/// key        <hex Ed25519 public key>
+/// signature  <hex signature>
+/// ```
+#[derive(Debug, Clone)]
+pub struct BundleMetadata {
+    pub base: String,
+    pub sha256: String,
+    pub size: u64,
+    pub exported: u64,
+    pub origin: Option<String>,
+    pub deps: Option<DependencyRecord>,
+}
+
+/// Who vouches for a bundle that was read back.
+pub enum BundleSigner {
+    Unsigned,
+    Untrusted(String),
+    Trusted(String),
+}
+
+impl BundleMetadata {
+    fn to_bytes(&self, key: Option<&Ed25519KeyPair>) -> Vec<u8> {
+        let mut body = String::new();
+        writeln!(body, "{BUNDLE_HEADER}").unwrap();
+        writeln!(body, "version\t{BUNDLE_VERSION}").unwrap();
+        writeln!(body, "base\t{}", self.base).unwrap();
+        writeln!(body, "sha256\t{}", self.sha256).unwrap();
+        writeln!(body, "size\t{}", self.size).unwrap();
+        writeln!(body, "exported\t{}", self.exported).unwrap();
+        if let Some(origin) = &self.origin {
+            writeln!(body, "origin\t{origin}").unwrap();
+        }
+        if let Some(deps) = &self.deps {
+            body.push_str(&deps.to_text());
+        }
+        if let Some(key) = key {
+            writeln!(body, "key\t{}", public_key_hex(key)).unwrap();
+            let signature = key.sign(body.as_bytes());
+            writeln!(body, "signature\t{}", hex::encode(signature.as_ref())).unwrap();
+        }
+        body.into_bytes()
+    }
+
+    fn parse(
+        bytes: &[u8],
+        trusted_keys: &[Vec<u8>],
+        origin: &str,
+    ) -> MagResult<(Self, BundleSigner)> {
+        let text = std::str::from_utf8(bytes).map_err(|err| {
+            MagError::Generic(format!("bundle {origin} has a non-UTF-8 record: {err}"))
+        })?;
+        let (signed, signature) = match text.rfind("\nsignature\t") {
+            Some(pos) => {
+                let (signed, sig_line) = text.split_at(pos + 1);
+                let signature = sig_line
+                    .trim_end()
+                    .strip_prefix("signature\t")
+                    .and_then(|value| hex::decode(value).ok())
+                    .ok_or_else(|| {
+                        MagError::Generic(format!("bundle {origin} has a malformed signature"))
+                    })?;
+                (signed, Some(signature))
+            }
+            None => (text, None),
+        };
+
+        let mut base = None;
+        let mut sha256 = None;
+        let mut size = None;
+        let mut exported = 0;
+        let mut exporter = None;
+        let mut key = None;
+        let mut deps: Option<DependencyRecord> = None;
+        let mut version_seen = false;
+        for (line_no, line) in signed.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || {
+                MagError::Generic(format!(
+                    "bundle {origin}: record line {}: malformed entry",
+                    line_no + 1
+                ))
+            };
+            match line.split('\t').collect::<Vec<_>>().as_slice() {
+                ["version", version] if READABLE_BUNDLE_VERSIONS.contains(version) => {
+                    version_seen = true
+                }
+                ["base", value] => base = Some(value.to_string()),
+                ["sha256", value] => sha256 = Some(value.to_ascii_lowercase()),
+                ["size", value] => size = Some(value.parse::<u64>().map_err(|_| malformed())?),
+                ["exported", value] => exported = value.parse().map_err(|_| malformed())?,
+                ["origin", value] => exporter = Some(value.to_string()),
+                ["key", value] => key = Some(hex::decode(value).map_err(|_| malformed())?),
+                ["hash", value] => deps.get_or_insert_default().hash = value.to_string(),
+                ["name", value] => deps.get_or_insert_default().name = value.to_string(),
+                ["run", value] => deps.get_or_insert_default().run.push(value.to_string()),
+                ["build", value] => deps.get_or_insert_default().build.push(value.to_string()),
+                ["fetch", value] => deps.get_or_insert_default().fetches.push(value.to_string()),
+                _ => return Err(malformed()),
+            }
+        }
+
+        if !version_seen {
+            return Err(MagError::Generic(format!(
+                "bundle {origin} is missing a supported version header"
+            )));
+        }
+        let missing = |field: &str| MagError::Generic(format!("bundle {origin} names no {field}"));
+        if deps.as_ref().is_some_and(|deps| deps.hash.is_empty()) {
+            return Err(missing("package hash for its dependencies"));
+        }
+        let metadata = Self {
+            base: base.ok_or_else(|| missing("base"))?,
+            sha256: sha256.ok_or_else(|| missing("sha256"))?,
+            size: size.ok_or_else(|| missing("size"))?,
+            exported,
+            origin: exporter,
+            deps,
+        };
+        validate_bundle_base(&metadata.base, origin)?;
+
+        let signer = match (key, signature) {
+            (None, None) => BundleSigner::Unsigned,
+            (Some(key), Some(signature)) => {
+                UnparsedPublicKey::new(&ED25519, &key)
+                    .verify(signed.as_bytes(), &signature)
+                    .map_err(|_| {
+                        MagError::Generic(format!("bundle {origin} signature verification failed"))
+                    })?;
+                if trusted_keys.contains(&key) {
+                    BundleSigner::Trusted(hex::encode(&key))
+                } else {
+                    BundleSigner::Untrusted(hex::encode(&key))
+                }
+            }
+            _ => {
+                return Err(MagError::Generic(format!(
+                    "bundle {origin} has a key without a signature or a signature without a key"
+                )));
+            }
+        };
+        Ok((metadata, signer))
+    }
+}
+
+/// Base names come from the bundle and become store paths, so they must be
+/// a plain file name that names a package hash: `<name>-<hash>`, or
+/// `<name>-<hash>-<output>` for an extra output.
+fn validate_bundle_base(base: &str, origin: &str) -> MagResult<()> {
+    let valid = !base.starts_with('.')
+        && !base.contains(['/', '\0', '\n', '\t'])
+        && base
+            .split('-')
+            .skip(1)
+            .any(|part| part.len() == 64 && part.bytes().all(|byte| byte.is_ascii_hexdigit()));
+    if valid {
+        Ok(())
+    } else {
+        Err(MagError::Generic(format!(
+            "bundle {origin} names an invalid package {base:?}"
+        )))
+    }
+}
+
+/// Write a bundle of `artifact` described by `metadata` to `out`, signed
+/// with `key` when one is given.
+pub fn write_bundle<W: Write>(
+    out: W,
+    metadata: &BundleMetadata,
+    artifact: &Path,
+    key: Option<&Ed25519KeyPair>,
+) -> MagResult<W> {
+    let mut builder = Builder::new(out);
+    let record = metadata.to_bytes(key);
+
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(metadata.exported);
+    header.set_size(record.len() as u64);
+    builder.append_data(&mut header, BUNDLE_METADATA, record.as_slice())?;
+
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(metadata.exported);
+    header.set_size(metadata.size);
+    builder.append_data(
+        &mut header,
+        format!("{}.tar.zst", metadata.base),
+        File::open(artifact)?,
+    )?;
+    Ok(builder.into_inner()?)
+}
+
+/// Read the bundle at `path`, copying its artifact to the file returned by
+/// `artifact_dest` once the record has been parsed. The artifact's size and
+/// sha256 are checked against the record; the caller decides whether the
+/// signer is acceptable before publishing it.
+pub fn read_bundle(
+    path: &Path,
+    trusted_keys: &[Vec<u8>],
+    artifact_dest: impl FnOnce(&BundleMetadata, &BundleSigner) -> MagResult<PathBuf>,
+) -> MagResult<(BundleMetadata, BundleSigner, PathBuf)> {
+    let origin = path.display().to_string();
+    let malformed =
+        |detail: &str| MagError::Generic(format!("{origin} is not a magpkg bundle: {detail}"));
+
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut entries = archive.entries()?;
+
+    let mut record_entry = entries.next().ok_or_else(|| malformed("it is empty"))??;
+    if record_entry.path()?.as_ref() != Path::new(BUNDLE_METADATA) {
+        return Err(malformed("the first member is not the bundle record"));
+    }
+    let mut record = Vec::new();
+    record_entry
+        .by_ref()
+        .take(64 * 1024)
+        .read_to_end(&mut record)?;
+    let (metadata, signer) = BundleMetadata::parse(&record, trusted_keys, &origin)?;
+
+    let mut artifact_entry = entries
+        .next()
+        .ok_or_else(|| malformed("the artifact is missing"))??;
+    if artifact_entry.path()?.as_ref() != Path::new(&format!("{}.tar.zst", metadata.base)) {
+        return Err(malformed("the second member is not the recorded artifact"));
+    }
+
+    let dest = artifact_dest(&metadata, &signer)?;
+    let copied = (|| -> MagResult<(u64, String)> {
+        let mut file = File::create(&dest)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1 << 20];
+        let mut size = 0u64;
+        loop {
+            let read = artifact_entry.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            file.write_all(&buffer[..read])?;
+            size += read as u64;
+        }
+        file.sync_all()?;
+        Ok((size, format!("{:x}", hasher.finalize())))
+    })();
+    let verified = match copied {
+        Ok((size, sha256)) if size == metadata.size && sha256 == metadata.sha256 => Ok(()),
+        Ok((size, sha256)) => Err(MagError::Generic(format!(
+            "artifact in {origin} does not match its record: {size} bytes with sha256 {sha256}, expected {} bytes with sha256 {}",
+            metadata.size, metadata.sha256
+        ))),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = verified {
+        let _ = fs::remove_file(&dest);
+        return Err(err);
+    }
+    Ok((metadata, signer, dest))
+}
+
+/// This machine's hostname, recorded as the bundle's origin.
+pub fn local_hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && !name.contains(['\t', '\n']))
+}
@@ -0,0 +1,265 @@
+use std::{
+    env, fs,
+    io::ErrorKind,
+    net::{TcpListener, UdpSocket},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{MagError, MagResult, btconfig::TorrentConfig, sandbox::find_libfaketime};
+
+/// Last reported set of degraded capabilities, at the store root.
+const CAPABILITIES_FILE: &str = "capabilities";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityStatus {
+    Available,
+    Degraded,
+    Unavailable,
+}
+
+impl CapabilityStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CapabilityStatus::Available => "available",
+            CapabilityStatus::Degraded => "degraded",
+            CapabilityStatus::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// One host facility magpkg relies on, and what stops working without it.
+#[derive(Debug, Clone)]
+pub struct Capability {
+    pub name: &'static str,
+    pub status: CapabilityStatus,
+    pub detail: String,
+    pub affects: &'static str,
+}
+
+impl Capability {
+    fn new(
+        name: &'static str,
+        affects: &'static str,
+        status: CapabilityStatus,
+        detail: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+            affects,
+        }
+    }
+}
+
+/// Check the host for everything magpkg can do without. The probes only
+/// read files and bind sockets, so they are cheap enough to run on every
+/// invocation.
+pub fn probe_capabilities(torrent_config: &TorrentConfig) -> Vec<Capability> {
+    let bwrap = find_executable("bwrap");
+    vec![
+        probe_bwrap(bwrap.as_deref()),
+        probe_user_namespaces(bwrap.as_deref()),
+        probe_torrents(torrent_config),
+        probe_libfaketime(),
+    ]
+}
+
+fn probe_bwrap(bwrap: Option<&Path>) -> Capability {
+    const AFFECTS: &str = "builds, venvs, debug-build";
+    match bwrap {
+        Some(path) => Capability::new(
+            "bwrap",
+            AFFECTS,
+            CapabilityStatus::Available,
+            path.display().to_string(),
+        ),
+        None => Capability::new(
+            "bwrap",
+            AFFECTS,
+            CapabilityStatus::Unavailable,
+            "bubblewrap is not on PATH; install the bubblewrap package",
+        ),
+    }
+}
+
+fn probe_user_namespaces(bwrap: Option<&Path>) -> Capability {
+    const AFFECTS: &str = "builds, venvs, debug-build";
+    let available = |detail: &str| {
+        Capability::new(
+            "user-namespaces",
+            AFFECTS,
+            CapabilityStatus::Available,
+            detail,
+        )
+    };
+    // SAFETY: geteuid has no preconditions.
+    if unsafe { libc::geteuid() } == 0 {
+        return available("running as root");
+    }
+    if let Some(bwrap) = bwrap {
+        if fs::metadata(bwrap).is_ok_and(|meta| meta.permissions().mode() & 0o4000 != 0) {
+            return available("bwrap is setuid root");
+        }
+    }
+
+    let sysctl = |path: &str| {
+        fs::read_to_string(path)
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+    if sysctl("/proc/sys/user/max_user_namespaces").as_deref() == Some("0") {
+        return Capability::new(
+            "user-namespaces",
+            AFFECTS,
+            CapabilityStatus::Unavailable,
+            "user.max_user_namespaces is 0",
+        );
+    }
+    if sysctl("/proc/sys/kernel/unprivileged_userns_clone").as_deref() == Some("0") {
+        return Capability::new(
+            "user-namespaces",
+            AFFECTS,
+            CapabilityStatus::Unavailable,
+            "kernel.unprivileged_userns_clone is 0",
+        );
+    }
+    if sysctl("/proc/sys/kernel/apparmor_restrict_unprivileged_userns").as_deref() == Some("1") {
+        return Capability::new(
+            "user-namespaces",
+            AFFECTS,
+            CapabilityStatus::Degraded,
+            "AppArmor restricts unprivileged user namespaces; bwrap needs a profile that allows them",
+        );
+    }
+    available("unprivileged user namespaces are enabled")
+}
+
+fn probe_torrents(config: &TorrentConfig) -> Capability {
+    const AFFECTS: &str = "torrent fetches, seeding";
+    if let Err(err) = TcpListener::bind(("0.0.0.0", 0)) {
+        return Capability::new(
+            "torrents",
+            AFFECTS,
+            CapabilityStatus::Unavailable,
+            format!("cannot open a TCP socket for peers: {err}"),
+        );
+    }
+    if config.dht {
+        if let Err(err) = UdpSocket::bind(("0.0.0.0", 0)) {
+            return Capability::new(
+                "torrents",
+                AFFECTS,
+                CapabilityStatus::Degraded,
+                format!("cannot open a UDP socket for the DHT: {err}"),
+            );
+        }
+    } else if config.trackers.is_empty() {
+        return Capability::new(
+            "torrents",
+            AFFECTS,
+            CapabilityStatus::Degraded,
+            "the DHT is off and no trackers are configured; magnets find peers only through their trackers and webseeds",
+        );
+    }
+    Capability::new(
+        "torrents",
+        AFFECTS,
+        CapabilityStatus::Available,
+        if config.dht {
+            "DHT enabled"
+        } else {
+            "trackers only"
+        },
+    )
+}
+
+fn probe_libfaketime() -> Capability {
+    const AFFECTS: &str = "packages with sandbox.fakeTime";
+    match find_libfaketime() {
+        Ok(path) => Capability::new(
+            "libfaketime",
+            AFFECTS,
+            CapabilityStatus::Available,
+            path.display().to_string(),
+        ),
+        Err(err) => Capability::new(
+            "libfaketime",
+            AFFECTS,
+            CapabilityStatus::Degraded,
+            err.to_string(),
+        ),
+    }
+}
+
+/// Print the degraded capabilities to stderr when they differ from the
+/// last report recorded at the store root, so each change is reported
+/// once rather than on every invocation.
+pub fn report_degraded_once(base_root: &Path, capabilities: &[Capability]) {
+    let mut record = String::new();
+    for capability in capabilities {
+        if capability.status != CapabilityStatus::Available {
+            record.push_str(&format!(
+                "{}\t{}\t{}\n",
+                capability.name,
+                capability.status.as_str(),
+                capability.detail
+            ));
+        }
+    }
+
+    let path = base_root.join(CAPABILITIES_FILE);
+    let previous = match fs::read_to_string(&path) {
+        Ok(previous) => previous,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(_) => return,
+    };
+    if previous == record {
+        return;
+    }
+    if !record.is_empty() {
+        eprintln!("magpkg is running with reduced capabilities on this host:");
+        for capability in capabilities {
+            if capability.status != CapabilityStatus::Available {
+                eprintln!(
+                    "  {} {}: {} (affects {})",
+                    capability.name,
+                    capability.status.as_str(),
+                    capability.detail,
+                    capability.affects
+                );
+            }
+        }
+        eprintln!("  run `magpkg doctor` for details; this is shown again only if it changes");
+    }
+    let _ = fs::write(&path, record);
+}
+
+/// Fail early, with the reason, when the build and venv sandbox cannot
+/// start on this host.
+pub fn require_sandbox(what: &str) -> MagResult<()> {
+    let bwrap = find_executable("bwrap");
+    for capability in [
+        probe_bwrap(bwrap.as_deref()),
+        probe_user_namespaces(bwrap.as_deref()),
+    ] {
+        if capability.status == CapabilityStatus::Unavailable {
+            return Err(MagError::Generic(format!(
+                "{what} needs the bubblewrap sandbox, but {}: {} (see `magpkg doctor`)",
+                capability.name, capability.detail
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| {
+            fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+use fs2::FileExt;
+use reqwest::blocking::ClientBuilder;
+
+use crate::{
+    MagError, MagResult,
+    archive::{SourceFormat, unpack_source},
+    leases::unix_now,
+    offline::is_offline,
+    proxy::HttpClient,
+    store::sha256_file,
+};
+
+pub const CHANNELS_CONFIG_FILE: &str = "channels.conf";
+/// Taken exclusively by `magpkg channel` around reading, changing, and
+/// writing `channels.conf`.
+pub const CHANNELS_LOCK_FILE: &str = "channels.lock";
+/// Unpacked channel tarballs, one `<sha256>/` per pinned tarball.
+pub const CHANNELS_DIR: &str = "channels";
+/// Import prefix of channel files: `channel:<name>/<path>`.
+pub const CHANNEL_IMPORT_PREFIX: &str = "channel:";
+const USER_AGENT: &str = concat!("magpkg/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub name: String,
+    pub url: String,
+    /// sha256 of the tarball the channel is pinned to.
+    pub sha256: String,
+    /// When the pin last changed, in seconds since the epoch.
+    pub updated: u64,
+}
+
+/// Channels registered with `magpkg channel add`, in
+/// `<store>/channels.conf`, one tab-separated line each:
+///
+/// ```text
+/// <name>  <url>  <tarball sha256>  <pinned at, seconds since the epoch>
+/// ```
+///
+/// A channel is pinned to the tarball it last fetched, unpacked under
+/// `<store>/channels/<sha256>/`, and only moves when `magpkg channel
+/// update` fetches it again. Evaluations hold a shared lock on
+/// `<store>/channels/.<sha256>.lock` for each checkout they import from,
+/// and checkouts are only removed while that lock is free.
+pub struct Channels {
+    base_root: PathBuf,
+    channels: BTreeMap<String, Channel>,
+    /// The `channels.lock` of a set loaded for changes.
+    _lock: Option<File>,
+}
+
+impl Channels {
+    /// The registered channels, for reading.
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        Self::read(base_root, None)
+    }
+
+    /// The registered channels, locked against other `magpkg channel`
+    /// commands until dropped, so that changes are not lost to a
+    /// concurrent writer.
+    pub fn load_for_update(base_root: &Path) -> MagResult<Self> {
+        let lock = File::create(base_root.join(CHANNELS_LOCK_FILE))?;
+        lock.lock_exclusive()?;
+        Self::read(base_root, Some(lock))
+    }
+
+    fn read(base_root: &Path, lock: Option<File>) -> MagResult<Self> {
+        let mut channels = Self {
+            base_root: base_root.to_path_buf(),
+            channels: BTreeMap::new(),
+            _lock: lock,
+        };
+        let path = base_root.join(CHANNELS_CONFIG_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(channels),
+            Err(err) => return Err(err.into()),
+        };
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [name, url, sha256, updated] = fields.as_slice() else {
+                return Err(MagError::Generic(format!(
+                    "{}:{}: expected <name> <url> <sha256> <time>",
+                    path.display(),
+                    index + 1
+                )));
+            };
+            channels.channels.insert(
+                name.to_string(),
+                Channel {
+                    name: name.to_string(),
+                    url: url.to_string(),
+                    sha256: sha256.to_string(),
+                    updated: updated.parse().unwrap_or(0),
+                },
+            );
+        }
+        Ok(channels)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(name)
+    }
+
+    /// The unpacked tree of the tarball with `sha256`.
+    pub fn checkout_dir(&self, sha256: &str) -> PathBuf {
+        self.base_root.join(CHANNELS_DIR).join(sha256)
+    }
+
+    fn checkout_lock_path(&self, sha256: &str) -> PathBuf {
+        self.base_root
+            .join(CHANNELS_DIR)
+            .join(format!(".{sha256}.lock"))
+    }
+
+    /// The file `reference`, `<name>/<path>` without the `channel:` prefix,
+    /// names in its channel's pinned checkout, with the channel. A shared
+    /// lock on the checkout, which keeps it in place while held, is added
+    /// to `locks` by tarball sha256 unless it is there already.
+    pub fn resolve(
+        &self,
+        reference: &str,
+        locks: &mut HashMap<String, File>,
+    ) -> MagResult<(PathBuf, &Channel)> {
+        let (name, path) = reference.split_once('/').ok_or_else(|| {
+            MagError::Generic(format!(
+                "{CHANNEL_IMPORT_PREFIX}{reference} names no file; use {CHANNEL_IMPORT_PREFIX}<channel>/<path>"
+            ))
+        })?;
+        let channel = self.channels.get(name).ok_or_else(|| {
+            MagError::Generic(format!(
+                "unknown channel {name}; add it with `magpkg channel add {name} <url>`"
+            ))
+        })?;
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(MagError::Generic(format!(
+                "{CHANNEL_IMPORT_PREFIX}{reference} must be a path inside the channel"
+            )));
+        }
+        let checkout = self.checkout_dir(&channel.sha256);
+        if !locks.contains_key(&channel.sha256) && checkout.is_dir() {
+            let lock = File::create(self.checkout_lock_path(&channel.sha256))?;
+            FileExt::lock_shared(&lock)?;
+            locks.insert(channel.sha256.clone(), lock);
+        }
+        // The checkout may have been pruned before the lock was taken.
+        if !checkout.is_dir() {
+            return Err(MagError::Generic(format!(
+                "channel {name} is not in the store; run `magpkg channel update {name}`"
+            )));
+        }
+        Ok((checkout.join(relative), channel))
+    }
+
+    /// Register `name` for `url` and pin it to the tarball the URL serves now.
+    pub fn add(&mut self, name: &str, url: &str) -> MagResult<&Channel> {
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+        {
+            return Err(MagError::Generic(format!(
+                "invalid channel name {name:?}: use letters, digits, '-', '_', and '.'"
+            )));
+        }
+        if self.channels.contains_key(name) {
+            return Err(MagError::Generic(format!(
+                "channel {name} already exists; remove it first to change its URL"
+            )));
+        }
+        let sha256 = self.fetch(name, url)?;
+        self.channels.insert(
+            name.to_string(),
+            Channel {
+                name: name.to_string(),
+                url: url.to_string(),
+                sha256,
+                updated: unix_now(),
+            },
+        );
+        self.save()?;
+        Ok(&self.channels[name])
+    }
+
+    /// Fetch `name` again and repin it. Returns the previous pin when the
+    /// tarball changed.
+    pub fn update(&mut self, name: &str) -> MagResult<Option<String>> {
+        let Some(channel) = self.channels.get(name) else {
+            return Err(MagError::Generic(format!("unknown channel {name}")));
+        };
+        let url = channel.url.clone();
+        let sha256 = self.fetch(name, &url)?;
+        let channel = self.channels.get_mut(name).expect("channel checked above");
+        if channel.sha256 == sha256 {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut channel.sha256, sha256);
+        channel.updated = unix_now();
+        self.save()?;
+        self.prune()?;
+        Ok(Some(previous))
+    }
+
+    pub fn remove(&mut self, name: &str) -> MagResult<()> {
+        if self.channels.remove(name).is_none() {
+            return Err(MagError::Generic(format!("unknown channel {name}")));
+        }
+        self.save()?;
+        self.prune()
+    }
+
+    /// Download the tarball at `url`, unpack it into the store unless that
+    /// tarball is already there, and return its sha256.
+    fn fetch(&self, name: &str, url: &str) -> MagResult<String> {
+        if is_offline() {
+            return Err(MagError::Generic(format!(
+                "--offline was given, but channel {name} must be fetched from {url}"
+            )));
+        }
+        let root = self.base_root.join(CHANNELS_DIR);
+        fs::create_dir_all(&root)?;
+        let staging = root.join(format!(".{name}.{}.tmp", process::id()));
+        let _ = fs::remove_dir_all(&staging);
+        fs::create_dir_all(&staging)?;
+        let result = (|| {
+            // The `.tar` name lets unpacking recognize tarballs whose
+            // header predates the `ustar` magic.
+            let tarball = staging.join(format!("{name}.tar"));
+            download(url, &tarball)?;
+            let sha256 = sha256_file(&tarball)?;
+            let checkout = self.checkout_dir(&sha256);
+            if checkout.is_dir() {
+                return Ok(sha256);
+            }
+
+            let mut head = Vec::new();
+            File::open(&tarball)?.take(512).read_to_end(&mut head)?;
+            if matches!(
+                SourceFormat::detect(&head, "channel.tar"),
+                SourceFormat::Raw
+            ) {
+                return Err(MagError::Generic(format!(
+                    "channel {name}: {url} did not serve a tarball"
+                )));
+            }
+            let tree = staging.join("tree");
+            fs::create_dir_all(&tree)?;
+            unpack_source(&tarball, &tree)?;
+            fs::rename(single_top_level_dir(&tree)?, &checkout)?;
+            Ok(sha256)
+        })();
+        let _ = fs::remove_dir_all(&staging);
+        result
+    }
+
+    /// Remove checkouts no channel is pinned to any more, except those an
+    /// evaluation is still importing from.
+    fn prune(&self) -> MagResult<()> {
+        let root = self.base_root.join(CHANNELS_DIR);
+        let entries = match fs::read_dir(&root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || self.channels.values().any(|c| c.sha256 == name) {
+                continue;
+            }
+            let lock_path = self.checkout_lock_path(&name);
+            let lock = File::create(&lock_path)?;
+            match lock.try_lock_exclusive() {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err.into()),
+            }
+            if let Err(err) = fs::remove_dir_all(entry.path()) {
+                eprintln!(
+                    "warning: failed to remove old channel checkout {}: {err}",
+                    entry.path().display()
+                );
+                continue;
+            }
+            let _ = fs::remove_file(&lock_path);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> MagResult<()> {
+        let mut contents = String::new();
+        for channel in self.channels.values() {
+            writeln!(
+                contents,
+                "{}\t{}\t{}\t{}",
+                channel.name, channel.url, channel.sha256, channel.updated
+            )
+            .unwrap();
+        }
+        let path = self.base_root.join(CHANNELS_CONFIG_FILE);
+        let tmp = self
+            .base_root
+            .join(format!(".{CHANNELS_CONFIG_FILE}.{}.tmp", process::id()));
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// Download `url`, an http(s) URL, `file://` URL, or local path, to `dest`.
+fn download(url: &str, dest: &Path) -> MagResult<()> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        let client = HttpClient::from_env(|| ClientBuilder::new().user_agent(USER_AGENT))?;
+        let mut response = client.get(url).send()?;
+        if !response.status().is_success() {
+            return Err(MagError::Generic(format!(
+                "HTTP {} fetching {url}",
+                response.status()
+            )));
+        }
+        response.copy_to(&mut File::create(dest)?)?;
+        return Ok(());
+    }
+    let path = url.strip_prefix("file://").unwrap_or(url);
+    fs::copy(path, dest)
+        .map_err(|err| MagError::Generic(format!("failed to read channel {path}: {err}")))?;
+    Ok(())
+}
+
+/// `tree` itself, or the one directory it holds when the tarball wrapped
+/// everything in a top-level directory.
+fn single_top_level_dir(tree: &Path) -> MagResult<PathBuf> {
+    let entries: Vec<_> = fs::read_dir(tree)?.collect::<Result<_, _>>()?;
+    if let [entry] = entries.as_slice() {
+        if entry.file_type()?.is_dir() {
+            return Ok(entry.path());
+        }
+    }
+    Ok(tree.to_path_buf())
+}
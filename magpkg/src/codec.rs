@@ -0,0 +1,158 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use xz2::{read::XzDecoder, write::XzEncoder};
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::seekable::SeekableWriter;
+
+/// Default zstd level for artifacts.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+/// xz only has presets 0 to 9; higher levels are clamped.
+const MAX_XZ_LEVEL: u32 = 9;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+/// How a package artifact is compressed. Artifacts keep their `.tar.zst`
+/// name whatever the codec; readers tell codecs apart by their magic bytes,
+/// and the publish record names the codec for tools that inspect the store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ArtifactCodec {
+    /// zstd seekable format; `magpkg cat` reads single files cheaply.
+    #[default]
+    Zstd,
+    /// xz, for archival density at the cost of slower builds and reads.
+    Xz,
+    /// Plain tar, for fast local disks where compression only costs CPU.
+    None,
+}
+
+impl ArtifactCodec {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArtifactCodec::Zstd => "zstd",
+            ArtifactCodec::Xz => "xz",
+            ArtifactCodec::None => "none",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [ArtifactCodec::Zstd, ArtifactCodec::Xz, ArtifactCodec::None]
+            .into_iter()
+            .find(|codec| codec.as_str() == value)
+    }
+
+    /// Identify the codec of an artifact from its first bytes. Anything
+    /// that is neither zstd nor xz is read as a plain tar stream.
+    pub fn detect(file: &mut File) -> io::Result<Self> {
+        let mut magic = [0u8; 6];
+        let mut filled = 0;
+        while filled < magic.len() {
+            match file.read(&mut magic[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(if magic[..filled].starts_with(&ZSTD_MAGIC) {
+            ArtifactCodec::Zstd
+        } else if magic[..filled].starts_with(&XZ_MAGIC) {
+            ArtifactCodec::Xz
+        } else {
+            ArtifactCodec::None
+        })
+    }
+}
+
+/// Codec, level, and worker threads used to compress an artifact.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionSettings {
+    pub codec: ArtifactCodec,
+    pub level: i32,
+    pub threads: usize,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            codec: ArtifactCodec::Zstd,
+            level: DEFAULT_COMPRESSION_LEVEL,
+            threads: num_cpus::get(),
+        }
+    }
+}
+
+/// Writes an artifact's tar stream with the configured codec.
+pub enum ArtifactWriter {
+    Zstd(SeekableWriter<File>),
+    Xz(XzEncoder<File>),
+    None(File),
+}
+
+impl ArtifactWriter {
+    pub fn new(file: File, settings: CompressionSettings) -> Self {
+        match settings.codec {
+            ArtifactCodec::Zstd => ArtifactWriter::Zstd(SeekableWriter::new(file, settings)),
+            ArtifactCodec::Xz => ArtifactWriter::Xz(XzEncoder::new(
+                file,
+                settings.level.clamp(0, MAX_XZ_LEVEL as i32) as u32,
+            )),
+            ArtifactCodec::None => ArtifactWriter::None(file),
+        }
+    }
+
+    /// Finish the compressed stream and return the underlying file.
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            ArtifactWriter::Zstd(writer) => writer.finish(),
+            ArtifactWriter::Xz(encoder) => encoder.finish(),
+            ArtifactWriter::None(mut file) => {
+                file.flush()?;
+                Ok(file)
+            }
+        }
+    }
+}
+
+impl Write for ArtifactWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            ArtifactWriter::Zstd(writer) => writer.write(data),
+            ArtifactWriter::Xz(encoder) => encoder.write(data),
+            ArtifactWriter::None(file) => file.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArtifactWriter::Zstd(writer) => writer.flush(),
+            ArtifactWriter::Xz(encoder) => encoder.flush(),
+            ArtifactWriter::None(file) => file.flush(),
+        }
+    }
+}
+
+/// Open an artifact for reading its decompressed tar stream, whatever
+/// codec it was written with.
+pub fn open_artifact(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut file = File::open(path)?;
+    let codec = ArtifactCodec::detect(&mut file)?;
+    decode_artifact(codec, file)
+}
+
+/// Decompress an artifact stream written with `codec`.
+pub fn decode_artifact<'a, R: Read + 'a>(
+    codec: ArtifactCodec,
+    reader: R,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match codec {
+        ArtifactCodec::Zstd => Box::new(ZstdDecoder::new(reader)?),
+        ArtifactCodec::Xz => Box::new(XzDecoder::new(reader)),
+        ArtifactCodec::None => Box::new(reader),
+    })
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    process::{Child, Command, Stdio},
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    MagError, MagResult,
+    store::{PackageStore, artifact_is_published},
+};
+
+/// What the magpkg at the other end of a `magpkg copy` connection does.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CopyMode {
+    /// Read base names on stdin and print those that are not published.
+    Missing,
+    /// Read base names on stdin and write their artifacts to stdout.
+    Send,
+    /// Publish the artifacts of a stream read from stdin.
+    Receive,
+}
+
+impl CopyMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            CopyMode::Missing => "missing",
+            CopyMode::Send => "send",
+            CopyMode::Receive => "receive",
+        }
+    }
+}
+
+/// A store reached as `ssh://[user@]host[:port]`, by running `program
+/// __copy <mode>` there through `ssh`.
+pub struct SshRemote {
+    url: String,
+    destination: String,
+    port: Option<u16>,
+    program: String,
+}
+
+impl SshRemote {
+    pub fn parse(url: &str, program: &str) -> MagResult<Self> {
+        let invalid = || MagError::Generic(format!("expected ssh://[user@]host[:port], got {url}"));
+        let authority = url
+            .strip_prefix("ssh://")
+            .map(|rest| rest.trim_end_matches('/'))
+            .filter(|rest| !rest.is_empty() && !rest.contains('/'))
+            .ok_or_else(invalid)?;
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, rest)) => (Some(user), rest),
+            None => (None, authority),
+        };
+        let (host, port) = if let Some(bracketed) = host_port.strip_prefix('[') {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+            (host, rest.strip_prefix(':'))
+        } else {
+            match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            }
+        };
+        // ssh would read a leading '-' as an option, such as -oProxyCommand.
+        let unsafe_part = |part: &str| {
+            part.starts_with('-') || part.chars().any(|c| c.is_whitespace() || c.is_control())
+        };
+        if host.is_empty()
+            || unsafe_part(host)
+            || user.is_some_and(|user| user.is_empty() || unsafe_part(user))
+        {
+            return Err(invalid());
+        }
+        let port = port
+            .map(|port| port.parse::<u16>().map_err(|_| invalid()))
+            .transpose()?;
+        Ok(Self {
+            url: url.to_string(),
+            destination: match user {
+                Some(user) => format!("{user}@{host}"),
+                None => host.to_string(),
+            },
+            port,
+            program: program.to_string(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The bases among `bases` the remote store has not published.
+    pub fn missing(&self, bases: &[String]) -> MagResult<Vec<String>> {
+        let mut child = self.spawn(CopyMode::Missing, Stdio::piped())?;
+        let written = write_bases(child.stdin.take(), bases);
+        let mut output = String::new();
+        let read = child
+            .stdout
+            .take()
+            .map_or(Ok(0), |mut stdout| stdout.read_to_string(&mut output));
+        self.wait(child)?;
+        written?;
+        read?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    /// Stream the artifacts `bases` from `store` into the remote store.
+    pub fn push(&self, store: &PackageStore, bases: &[String]) -> MagResult<()> {
+        let mut child = self.spawn(CopyMode::Receive, Stdio::null())?;
+        let sent = match child.stdin.take() {
+            Some(stdin) => store.send_artifacts(bases, BufWriter::new(stdin)),
+            None => Ok(()),
+        };
+        // A remote failure explains a broken pipe better than the pipe does.
+        self.wait(child)?;
+        sent
+    }
+
+    /// Fetch the artifacts `bases` from the remote store into `store`,
+    /// refusing any other artifact the remote sends. Returns the bases that
+    /// were new.
+    pub fn pull(&self, store: &PackageStore, bases: &[String]) -> MagResult<Vec<String>> {
+        let mut child = self.spawn(CopyMode::Send, Stdio::piped())?;
+        write_bases(child.stdin.take(), bases)?;
+        let wanted: HashSet<String> = bases.iter().cloned().collect();
+        let received = match child.stdout.take() {
+            Some(stdout) => store.receive_artifacts(BufReader::new(stdout), Some(&wanted)),
+            None => Ok(Vec::new()),
+        };
+        if received.is_err() {
+            let _ = child.kill();
+        }
+        self.wait(child)?;
+        received
+    }
+
+    fn spawn(&self, mode: CopyMode, stdout: Stdio) -> MagResult<Child> {
+        let mut command = Command::new("ssh");
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        command
+            .arg("--")
+            .arg(&self.destination)
+            .arg(&self.program)
+            .arg("__copy")
+            .arg(mode.as_str())
+            .stdin(Stdio::piped())
+            .stdout(stdout);
+        command
+            .spawn()
+            .map_err(|err| MagError::Generic(format!("failed to run ssh: {err}")))
+    }
+
+    fn wait(&self, mut child: Child) -> MagResult<()> {
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(MagError::Generic(format!(
+                "magpkg on {} failed ({status})",
+                self.url
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `store` has published the artifact `base`.
+pub fn is_published(store: &PackageStore, base: &str) -> bool {
+    artifact_is_published(&store.package_root().join(format!("{base}.tar.zst")))
+}
+
+/// The remote end of a `magpkg copy` connection, run as `magpkg __copy`.
+pub fn serve(store: &PackageStore, mode: CopyMode) -> MagResult<()> {
+    match mode {
+        CopyMode::Missing => {
+            let mut stdout = io::stdout().lock();
+            for base in read_bases(io::stdin().lock())? {
+                if !is_published(store, &base) {
+                    writeln!(stdout, "{base}")?;
+                }
+            }
+            stdout.flush()?;
+        }
+        CopyMode::Send => {
+            let bases = read_bases(io::stdin().lock())?;
+            store.send_artifacts(&bases, BufWriter::new(io::stdout().lock()))?;
+        }
+        CopyMode::Receive => {
+            // The sender logged in over SSH and could run anything here, so
+            // whatever it pushes is accepted.
+            store.receive_artifacts(BufReader::new(io::stdin().lock()), None)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_bases(stdin: Option<impl Write>, bases: &[String]) -> MagResult<()> {
+    let Some(stdin) = stdin else {
+        return Ok(());
+    };
+    let mut stdin = BufWriter::new(stdin);
+    for base in bases {
+        writeln!(stdin, "{base}")?;
+    }
+    stdin.flush()?;
+    Ok(())
+}
+
+/// Base names, one per line, as sent by the other end of a connection.
+/// Names are checked to stay inside the store.
+fn read_bases(input: impl BufRead) -> MagResult<Vec<String>> {
+    let mut bases = Vec::new();
+    for line in input.lines() {
+        let base = line?;
+        if base.is_empty() {
+            continue;
+        }
+        if base.starts_with('.') || base.contains('/') {
+            return Err(MagError::Generic(format!("invalid package base {base:?}")));
+        }
+        bases.push(base);
+    }
+    Ok(bases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> MagResult<SshRemote> {
+        SshRemote::parse(url, "magpkg")
+    }
+
+    #[test]
+    fn parses_user_host_and_port() {
+        let remote = parse("ssh://alice@example.com:2222").unwrap();
+        assert_eq!(remote.destination, "alice@example.com");
+        assert_eq!(remote.port, Some(2222));
+
+        let remote = parse("ssh://[2001:db8::1]:22/").unwrap();
+        assert_eq!(remote.destination, "2001:db8::1");
+        assert_eq!(remote.port, Some(22));
+
+        let remote = parse("ssh://example.com").unwrap();
+        assert_eq!(remote.destination, "example.com");
+        assert_eq!(remote.port, None);
+    }
+
+    #[test]
+    fn rejects_option_like_parts() {
+        for url in [
+            "ssh://-oProxyCommand=touch${IFS}/tmp/x@host",
+            "ssh://user@-oProxyCommand=id",
+            "ssh://-host",
+            "ssh://[-oProxyCommand=id]:22",
+        ] {
+            assert!(parse(url).is_err(), "{url} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_whitespace_and_control_characters() {
+        for url in [
+            "ssh://user name@host",
+            "ssh://user@host\tname",
+            "ssh://user@ho\nst",
+            "ssh://@host",
+        ] {
+            assert!(parse(url).is_err(), "{url:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        for url in [
+            "example.com",
+            "ssh://",
+            "ssh://host/path",
+            "ssh://host:port",
+            "ssh://host:70000",
+        ] {
+            assert!(parse(url).is_err(), "{url} was accepted");
+        }
+    }
+}
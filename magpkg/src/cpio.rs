@@ -0,0 +1,154 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::Path,
+};
+
+use crate::{MagError, MagResult};
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const TRAILER: &str = "TRAILER!!!";
+
+/// Writes a root filesystem as a `newc` cpio archive, the format the Linux
+/// kernel unpacks as an initramfs. Entries are emitted in sorted order with
+/// uid/gid 0 and mtime 0, so the same tree always yields the same bytes.
+pub struct CpioWriter<W: Write> {
+    writer: W,
+    next_ino: u32,
+}
+
+impl<W: Write> CpioWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            next_ino: 1,
+        }
+    }
+
+    /// Append every entry below `root`; `root` itself is not archived.
+    pub fn append_tree(&mut self, root: &Path) -> MagResult<()> {
+        self.append_dir_contents(root, "")
+    }
+
+    /// Append a symlink that is not present in the tree, e.g. `init`.
+    pub fn append_symlink(&mut self, name: &str, target: &str) -> MagResult<()> {
+        let mode = libc::S_IFLNK | 0o777;
+        self.write_header(name, mode, target.len() as u32, 0)?;
+        self.writer.write_all(target.as_bytes())?;
+        self.pad(target.len())
+    }
+
+    /// Write the trailer and return the underlying writer.
+    pub fn finish(mut self) -> MagResult<W> {
+        self.write_header(TRAILER, 0, 0, 0)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn append_dir_contents(&mut self, dir: &Path, prefix: &str) -> MagResult<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let file_name = entry.file_name();
+            let name = std::str::from_utf8(file_name.as_bytes())
+                .map(|name| format!("{prefix}{name}"))
+                .map_err(|_| {
+                    MagError::Generic(format!(
+                        "cannot store non-UTF-8 path {} in a cpio archive",
+                        entry.path().display()
+                    ))
+                })?;
+            let path = entry.path();
+            let meta = fs::symlink_metadata(&path)?;
+            let file_type = meta.file_type();
+            // Permission and type bits only; ownership is normalized to root.
+            let mode = meta.mode() & (libc::S_IFMT | 0o7777);
+
+            if file_type.is_dir() {
+                self.write_header(&name, mode, 0, 0)?;
+                self.append_dir_contents(&path, &format!("{name}/"))?;
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path)?;
+                let target = target.as_os_str().as_bytes();
+                self.write_header(&name, mode, target.len() as u32, 0)?;
+                self.writer.write_all(target)?;
+                self.pad(target.len())?;
+            } else if file_type.is_file() {
+                let size = u32::try_from(meta.len()).map_err(|_| {
+                    MagError::Generic(format!(
+                        "{} is too large for a newc cpio archive",
+                        path.display()
+                    ))
+                })?;
+                self.write_header(&name, mode, size, 0)?;
+                let copied = io::copy(&mut File::open(&path)?, &mut self.writer)?;
+                if copied != u64::from(size) {
+                    return Err(MagError::Generic(format!(
+                        "{} changed size while being archived",
+                        path.display()
+                    )));
+                }
+                self.pad(size as usize)?;
+            } else if file_type.is_block_device()
+                || file_type.is_char_device()
+                || file_type.is_fifo()
+                || file_type.is_socket()
+            {
+                self.write_header(&name, mode, 0, meta.rdev())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self, name: &str, mode: u32, size: u32, rdev: u64) -> MagResult<()> {
+        let ino = if name == TRAILER {
+            0
+        } else {
+            let ino = self.next_ino;
+            self.next_ino += 1;
+            ino
+        };
+        let nlink = if mode & libc::S_IFMT == libc::S_IFDIR {
+            2
+        } else {
+            1
+        };
+        let rdev_major = libc::major(rdev);
+        let rdev_minor = libc::minor(rdev);
+        let name_size = name.len() + 1;
+
+        self.writer.write_all(NEWC_MAGIC)?;
+        for field in [
+            ino,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            0, // mtime
+            size,
+            0, // devmajor
+            0, // devminor
+            rdev_major,
+            rdev_minor,
+            name_size as u32,
+            0, // check
+        ] {
+            write!(self.writer, "{field:08x}")?;
+        }
+        self.writer.write_all(name.as_bytes())?;
+        self.writer.write_all(&[0])?;
+        // The 110-byte header plus the name is padded to a multiple of four.
+        self.pad(110 + name_size)
+    }
+
+    fn pad(&mut self, len: usize) -> MagResult<()> {
+        let padding = (4 - len % 4) % 4;
+        self.writer.write_all(&[0; 3][..padding])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    lockfile::Lockfile,
+    package::{BuildHooks, BuilderShell, Package, collect_closure},
+};
+
+/// One package of a graph being compared. Graphs read back from a lockfile
+/// only know names and hashes, so `details` is `None` for them.
+pub struct PackageSummary {
+    pub name: Option<String>,
+    pub hash: String,
+    details: Option<PackageDetails>,
+}
+
+struct PackageDetails {
+    build: String,
+    hooks: BuildHooks,
+    fetch: Vec<(String, String)>,
+    sandbox: String,
+    shell: Option<String>,
+    build_with: Vec<String>,
+    env: BTreeMap<String, String>,
+    outputs: Vec<String>,
+    run_deps: Vec<DepRef>,
+    build_deps: Vec<DepRef>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct DepRef {
+    name: Option<String>,
+    hash: String,
+}
+
+impl DepRef {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("pkg-{}", short_hash(&self.hash)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Rebuilt,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Rebuilt => "rebuilt",
+        }
+    }
+}
+
+pub struct PackageChange {
+    pub kind: ChangeKind,
+    pub name: Option<String>,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    pub reasons: Vec<String>,
+}
+
+impl PackageChange {
+    pub fn label(&self) -> String {
+        let hash = self.new_hash.as_ref().or(self.old_hash.as_ref());
+        match (&self.name, hash) {
+            (Some(name), _) => name.clone(),
+            (None, Some(hash)) => format!("pkg-{}", short_hash(hash)),
+            (None, None) => "pkg".into(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct GraphDiff {
+    pub changes: Vec<PackageChange>,
+    pub unchanged: usize,
+}
+
+/// Summarize every package in the closure of `roots`.
+pub fn summarize_graph(roots: &[Rc<Package>]) -> Vec<PackageSummary> {
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for pkg in roots {
+        collect_closure(pkg.clone(), &mut visited, &mut order);
+    }
+
+    let dep_refs = |deps: &[Rc<Package>]| -> Vec<DepRef> {
+        deps.iter()
+            .map(|dep| DepRef {
+                name: dep.name.clone(),
+                hash: dep.hash.clone(),
+            })
+            .collect()
+    };
+
+    order
+        .iter()
+        .map(|pkg| PackageSummary {
+            name: pkg.name.clone(),
+            hash: pkg.hash.clone(),
+            details: Some(PackageDetails {
+                build: pkg.build.clone(),
+                hooks: pkg.hooks.clone(),
+                fetch: pkg
+                    .fetch
+                    .iter()
+                    .map(|fetch| (fetch.filename.clone(), fetch.sha256.clone()))
+                    .collect(),
+                sandbox: format!("{:?}", pkg.sandbox),
+                shell: pkg.builder_shell.as_ref().map(|shell| match shell {
+                    BuilderShell::Path(path) => path.clone(),
+                    BuilderShell::Package(shell_pkg) => format!("package {}", shell_pkg.hash),
+                }),
+                build_with: pkg.build_with.clone(),
+                env: pkg.env.clone(),
+                outputs: pkg.outputs.clone(),
+                run_deps: dep_refs(&pkg.run_deps),
+                build_deps: dep_refs(&pkg.build_deps),
+            }),
+        })
+        .collect()
+}
+
+/// Summarize the packages recorded in a lockfile.
+pub fn summarize_lockfile(lock: &Lockfile) -> Vec<PackageSummary> {
+    lock.packages
+        .iter()
+        .map(|(hash, base)| PackageSummary {
+            name: base
+                .strip_suffix(&format!("-{hash}"))
+                .filter(|name| *name != "pkg")
+                .map(str::to_string),
+            hash: hash.clone(),
+            details: None,
+        })
+        .collect()
+}
+
+/// Compare two package graphs. Packages are matched by name when exactly one
+/// package carries that name on each side; everything else is matched by
+/// hash, so it is either unchanged or added/removed.
+pub fn diff_graphs(old: &[PackageSummary], new: &[PackageSummary]) -> GraphDiff {
+    let old_hashes: HashSet<&str> = old.iter().map(|pkg| pkg.hash.as_str()).collect();
+    let new_hashes: HashSet<&str> = new.iter().map(|pkg| pkg.hash.as_str()).collect();
+
+    let by_name = |side: &[PackageSummary]| {
+        let mut names: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, pkg) in side.iter().enumerate() {
+            if let Some(name) = &pkg.name {
+                names.entry(name.clone()).or_default().push(index);
+            }
+        }
+        names
+    };
+    let old_names = by_name(old);
+    let new_names = by_name(new);
+
+    let mut diff = GraphDiff {
+        unchanged: new
+            .iter()
+            .filter(|pkg| old_hashes.contains(pkg.hash.as_str()))
+            .count(),
+        ..Default::default()
+    };
+
+    let mut paired_old = HashSet::new();
+    let mut paired_new = HashSet::new();
+    for (name, new_indexes) in &new_names {
+        let Some(old_indexes) = old_names.get(name) else {
+            continue;
+        };
+        if let ([old_index], [new_index]) = (old_indexes.as_slice(), new_indexes.as_slice()) {
+            let (old_pkg, new_pkg) = (&old[*old_index], &new[*new_index]);
+            paired_old.insert(*old_index);
+            paired_new.insert(*new_index);
+            if old_pkg.hash != new_pkg.hash {
+                diff.changes.push(PackageChange {
+                    kind: ChangeKind::Rebuilt,
+                    name: Some(name.clone()),
+                    old_hash: Some(old_pkg.hash.clone()),
+                    new_hash: Some(new_pkg.hash.clone()),
+                    reasons: rebuild_reasons(old_pkg, new_pkg),
+                });
+            }
+        }
+    }
+
+    for (index, pkg) in new.iter().enumerate() {
+        if !paired_new.contains(&index) && !old_hashes.contains(pkg.hash.as_str()) {
+            diff.changes.push(PackageChange {
+                kind: ChangeKind::Added,
+                name: pkg.name.clone(),
+                old_hash: None,
+                new_hash: Some(pkg.hash.clone()),
+                reasons: Vec::new(),
+            });
+        }
+    }
+    for (index, pkg) in old.iter().enumerate() {
+        if !paired_old.contains(&index) && !new_hashes.contains(pkg.hash.as_str()) {
+            diff.changes.push(PackageChange {
+                kind: ChangeKind::Removed,
+                name: pkg.name.clone(),
+                old_hash: Some(pkg.hash.clone()),
+                new_hash: None,
+                reasons: Vec::new(),
+            });
+        }
+    }
+
+    diff.changes
+        .sort_by(|a, b| (a.kind as u8, a.label()).cmp(&(b.kind as u8, b.label())));
+    diff
+}
+
+fn rebuild_reasons(old: &PackageSummary, new: &PackageSummary) -> Vec<String> {
+    let (Some(old), Some(new)) = (&old.details, &new.details) else {
+        return vec!["hash changed (the lockfile records no details)".into()];
+    };
+
+    let mut reasons = Vec::new();
+    if old.build != new.build {
+        reasons.push("build script changed".into());
+    }
+    if old.hooks != new.hooks {
+        let phases: Vec<&str> = old
+            .hooks
+            .phases()
+            .into_iter()
+            .zip(new.hooks.phases())
+            .filter(|(old, new)| old != new)
+            .map(|((phase, _), _)| phase)
+            .collect();
+        reasons.push(format!("{} changed", phases.join(", ")));
+    }
+    if old.fetch != new.fetch {
+        let mut parts = Vec::new();
+        for (filename, sha) in &new.fetch {
+            if !old.fetch.iter().any(|(_, old_sha)| old_sha == sha) {
+                parts.push(format!("+{filename}"));
+            }
+        }
+        for (filename, sha) in &old.fetch {
+            if !new.fetch.iter().any(|(_, new_sha)| new_sha == sha) {
+                parts.push(format!("-{filename}"));
+            }
+        }
+        if parts.is_empty() {
+            reasons.push("fetch changed (renamed or reordered)".into());
+        } else {
+            reasons.push(format!("fetch changed: {}", parts.join(", ")));
+        }
+    }
+    if old.sandbox != new.sandbox {
+        reasons.push("sandbox options changed".into());
+    }
+    if old.shell != new.shell {
+        reasons.push("builder shell changed".into());
+    }
+    if old.build_with != new.build_with {
+        reasons.push("build interpreter changed".into());
+    }
+    if old.env != new.env {
+        let names: Vec<&str> = old
+            .env
+            .iter()
+            .chain(&new.env)
+            .filter(|(name, _)| old.env.get(*name) != new.env.get(*name))
+            .map(|(name, _)| name.as_str())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        reasons.push(format!("env changed: {}", names.join(", ")));
+    }
+    if old.outputs != new.outputs {
+        reasons.push(format!(
+            "outputs changed: {} -> {}",
+            old.outputs.join(", "),
+            new.outputs.join(", ")
+        ));
+    }
+    for (field, old_deps, new_deps) in [
+        ("runDeps", &old.run_deps, &new.run_deps),
+        ("buildDeps", &old.build_deps, &new.build_deps),
+    ] {
+        if old_deps != new_deps {
+            reasons.push(format!(
+                "{field}: {}",
+                describe_dep_change(old_deps, new_deps)
+            ));
+        }
+    }
+    if reasons.is_empty() {
+        reasons.push("hash changed".into());
+    }
+    reasons
+}
+
+fn describe_dep_change(old: &[DepRef], new: &[DepRef]) -> String {
+    let mut parts = Vec::new();
+    for dep in new {
+        if old.contains(dep) {
+            continue;
+        }
+        let renamed = dep.name.is_some() && old.iter().any(|old_dep| old_dep.name == dep.name);
+        if renamed {
+            parts.push(format!("{} rebuilt", dep.label()));
+        } else {
+            parts.push(format!("+{}", dep.label()));
+        }
+    }
+    for dep in old {
+        let still_named = dep.name.is_some() && new.iter().any(|new_dep| new_dep.name == dep.name);
+        if !new.contains(dep) && !still_named {
+            parts.push(format!("-{}", dep.label()));
+        }
+    }
+    if parts.is_empty() {
+        "reordered".into()
+    } else {
+        parts.join(", ")
+    }
+}
+
+pub fn short_hash(hash: &str) -> &str {
+    hash.get(..12).unwrap_or(hash)
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use jrsonnet_evaluator::Val;
+
+use crate::{
+    MagError, MagResult,
+    errors::format_jr_error,
+    package::{Package, PackageGraphBuilder},
+};
+
+/// Every `.jsonnet` file below `dir`, sorted by path. Hidden files and
+/// directories are skipped, and symlinked directories are not followed.
+pub fn find_manifests(dir: &Path) -> MagResult<Vec<PathBuf>> {
+    let mut manifests = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = fs::read_dir(&current).map_err(|err| {
+            MagError::Generic(format!("failed to read {}: {err}", current.display()))
+        })?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "jsonnet") {
+                manifests.push(path);
+            }
+        }
+    }
+    manifests.sort();
+    if manifests.is_empty() {
+        return Err(MagError::Generic(format!(
+            "no .jsonnet files found below {}",
+            dir.display()
+        )));
+    }
+    Ok(manifests)
+}
+
+/// One expression importing every manifest, so that a single evaluation
+/// parses each shared import once:
+///
+/// ```text
+/// [import "/src/pkgs/base/bash.jsonnet", import "/src/pkgs/base/coreutils.jsonnet"]
+/// ```
+pub fn manifests_expression(manifests: &[PathBuf]) -> MagResult<String> {
+    let mut imports = Vec::with_capacity(manifests.len());
+    for manifest in manifests {
+        let path = manifest.canonicalize().map_err(|err| {
+            MagError::Generic(format!("failed to resolve {}: {err}", manifest.display()))
+        })?;
+        // A JSON string is also a valid Jsonnet string literal.
+        let literal = serde_json::to_string(&path.to_string_lossy())
+            .map_err(|err| MagError::Generic(err.to_string()))?;
+        imports.push(format!("import {literal}"));
+    }
+    Ok(format!("[{}]", imports.join(", ")))
+}
+
+/// Add the packages of every manifest in `value`, the result of
+/// [`manifests_expression`], to one graph. Packages with identical hashes
+/// are merged. Manifests that evaluate to a function are helpers rather than
+/// packages and are skipped.
+pub fn packages_from_manifests(
+    value: Val,
+    manifests: &[PathBuf],
+    builder: &mut PackageGraphBuilder,
+) -> MagResult<Vec<Rc<Package>>> {
+    let mut packages = Vec::new();
+    for_each_manifest(value, manifests, |_, value| {
+        packages.extend(builder.packages_from_value(value)?);
+        Ok(())
+    })?;
+    Ok(packages)
+}
+
+/// Like [`packages_from_manifests`], pairing each package with an
+/// expression that imports its manifest by its path relative to `dir`, the
+/// directory the manifests were found in, after `import_base`:
+///
+/// ```text
+/// import "base/bash.jsonnet"
+/// (import "channel:magnet/base/coreutils.jsonnet")[1]
+/// ```
+pub fn manifest_snippets(
+    value: Val,
+    manifests: &[PathBuf],
+    dir: &Path,
+    import_base: &str,
+    builder: &mut PackageGraphBuilder,
+) -> MagResult<Vec<(Rc<Package>, String)>> {
+    let mut snippets = Vec::new();
+    for_each_manifest(value, manifests, |manifest, value| {
+        let relative = manifest.strip_prefix(dir).unwrap_or(manifest);
+        let path = format!("{import_base}{}", relative.to_string_lossy());
+        // A JSON string is also a valid Jsonnet string literal.
+        let literal =
+            serde_json::to_string(&path).map_err(|err| MagError::Generic(err.to_string()))?;
+        snippets.extend(expression_snippets(
+            &format!("import {literal}"),
+            value,
+            builder,
+        )?);
+        Ok(())
+    })?;
+    Ok(snippets)
+}
+
+/// The packages of `value`, the result of evaluating `expression`, each
+/// with an expression that evaluates to it: `expression` itself, or an
+/// index into it when it evaluates to an array.
+fn expression_snippets(
+    expression: &str,
+    value: Val,
+    builder: &mut PackageGraphBuilder,
+) -> MagResult<Vec<(Rc<Package>, String)>> {
+    let is_array = matches!(value, Val::Arr(_));
+    let packages = builder.packages_from_value(value)?;
+    Ok(packages
+        .into_iter()
+        .enumerate()
+        .map(|(index, package)| {
+            let snippet = if is_array {
+                format!("({expression})[{index}]")
+            } else {
+                expression.to_string()
+            };
+            (package, snippet)
+        })
+        .collect())
+}
+
+fn for_each_manifest(
+    value: Val,
+    manifests: &[PathBuf],
+    mut visit: impl FnMut(&Path, Val) -> MagResult<()>,
+) -> MagResult<()> {
+    let Val::Arr(arr) = value else {
+        return Err(MagError::Generic(
+            "manifest directory expression did not evaluate to an array".into(),
+        ));
+    };
+    for (manifest, item) in manifests.iter().zip(arr.iter()) {
+        let value = item.map_err(|err| {
+            let message = format_jr_error(&err);
+            MagError::Evaluation {
+                context: format!("failed to evaluate {}", manifest.display()),
+                message,
+                source: err,
+            }
+        })?;
+        if matches!(value, Val::Func(_)) {
+            eprintln!(
+                "note: skipping {}, which evaluates to a function",
+                manifest.display()
+            );
+            continue;
+        }
+        visit(manifest, value).inspect_err(|_| {
+            eprintln!("note: while reading packages from {}", manifest.display())
+        })?;
+    }
+    Ok(())
+}
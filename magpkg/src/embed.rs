@@ -0,0 +1,427 @@
+use std::{
+    any::Any,
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    env, fmt,
+    fs::File,
+    hash::Hasher,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use jrsonnet_evaluator::{
+    ImportResolver, State, Val,
+    error::{ErrorKind, Result as JrResult},
+    parser::{SourcePath, SourcePathT},
+    trace::PathResolver,
+};
+use jrsonnet_gcmodule::{Trace, Tracer};
+use jrsonnet_stdlib::ContextInitializer as StdlibContext;
+
+use crate::{
+    MagError, MagResult,
+    channels::{CHANNEL_IMPORT_PREFIX, CHANNELS_DIR, Channels},
+    errors::format_jr_error,
+    imports::{ImportLog, MagImportResolver},
+    localsrc::LOCAL_IMPORT_PREFIX,
+    natives::MagContext,
+    store::store_base_root,
+};
+
+/// Evaluates manifests with a caller-controlled view of the import space.
+///
+/// Virtual files are served from memory and shadow files on disk at the
+/// same path. Filesystem imports can be confined to a set of import roots
+/// or disabled outright, and remote `http(s)://` imports can be disabled.
+/// Disabling the filesystem also disables `magpkg.readFile` and
+/// `magpkg.envVar`, so an evaluator that only sees its virtual files reads
+/// nothing from the host:
+///
+/// ```text
+/// Evaluator::sandboxed()
+///     .virtual_file("/manifests/main.jsonnet", main)
+///     .virtual_file("/manifests/lib.libsonnet", lib)
+///     .evaluate("<request>", r#"import "/manifests/main.jsonnet""#)
+/// ```
+///
+/// Relative imports resolve against the importing file, or against `/` (the
+/// working directory for [`Evaluator::new`]) for the expression itself.
+/// Relative virtual file paths resolve the same way.
+///
+/// `channel:<name>/<path>` imports read `<path>` from the tarball channel
+/// `<name>` is pinned to. They count as filesystem imports, but are allowed
+/// regardless of the import roots.
+///
+/// The crate only builds the `magpkg` binary, so this is crate-internal
+/// until the evaluator moves into a library crate of its own.
+pub(crate) struct Evaluator {
+    base_dir: PathBuf,
+    files: HashMap<PathBuf, Rc<[u8]>>,
+    import_roots: Vec<PathBuf>,
+    filesystem: bool,
+    remote_imports: bool,
+}
+
+impl Evaluator {
+    /// An evaluator that only sees its virtual files, rooted at `/`.
+    pub fn sandboxed() -> Self {
+        Self {
+            base_dir: PathBuf::from("/"),
+            files: HashMap::new(),
+            import_roots: Vec::new(),
+            filesystem: false,
+            remote_imports: false,
+        }
+    }
+
+    /// An evaluator with the CLI's defaults: relative imports resolve
+    /// against the working directory, and any file or URL may be imported.
+    pub fn new() -> MagResult<Self> {
+        let mut evaluator = Self::sandboxed().filesystem(true).remote_imports(true);
+        evaluator.base_dir = env::current_dir()?;
+        Ok(evaluator)
+    }
+
+    /// Serve `contents` for imports of `path`.
+    pub fn virtual_file(mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        let path = normalize(&self.base_dir.join(path));
+        self.files.insert(path, contents.into().into());
+        self
+    }
+
+    /// Only allow filesystem imports of files beneath `dir` (repeatable).
+    /// Without any roots, every readable file may be imported.
+    pub fn import_root(mut self, dir: impl AsRef<Path>) -> MagResult<Self> {
+        let dir = dir.as_ref();
+        let root = dir
+            .canonicalize()
+            .map_err(|err| MagError::Generic(format!("import root {}: {err}", dir.display())))?;
+        self.import_roots.push(root);
+        Ok(self)
+    }
+
+    /// Allow or forbid importing files from disk.
+    pub fn filesystem(mut self, allowed: bool) -> Self {
+        self.filesystem = allowed;
+        self
+    }
+
+    /// Allow or forbid `http://` and `https://` imports.
+    pub fn remote_imports(mut self, allowed: bool) -> Self {
+        self.remote_imports = allowed;
+        self
+    }
+
+    /// Evaluate `expression`, reported as `name` in error locations, and
+    /// return a handle to the log of remote imports. Values are lazy, so the
+    /// log is only complete once the result has been fully forced.
+    pub fn evaluate(&self, name: &str, expression: &str) -> MagResult<(Val, ImportLog)> {
+        let inner = MagImportResolver::new(Vec::new())?;
+        let imports = inner.import_log();
+        let channel_root = store_base_root().ok().map(|base_root| {
+            let channel_root = base_root.join(CHANNELS_DIR);
+            channel_root.canonicalize().unwrap_or(channel_root)
+        });
+        let resolver = SandboxImportResolver {
+            inner,
+            channels: OnceCell::new(),
+            checkout_locks: RefCell::new(HashMap::new()),
+            channel_root,
+            base_dir: self.base_dir.clone(),
+            files: self.files.clone(),
+            import_roots: self.import_roots.clone(),
+            filesystem: self.filesystem,
+            remote_imports: self.remote_imports,
+        };
+
+        let mut builder = State::builder();
+        builder.import_resolver(resolver);
+        builder.context_initializer(MagContext::new(
+            StdlibContext::new(PathResolver::new_cwd_fallback()),
+            self.filesystem,
+        ));
+        let state = builder.build();
+
+        let value = state.evaluate_snippet(name, expression).map_err(|err| {
+            let message = format_jr_error(&err);
+            MagError::ExpressionEval {
+                message,
+                source: err,
+            }
+        })?;
+        Ok((value, imports))
+    }
+}
+
+struct SandboxImportResolver {
+    inner: MagImportResolver,
+    /// Loaded on the first `channel:` import.
+    channels: OnceCell<Channels>,
+    /// Shared locks on the channel checkouts imported from, held for as
+    /// long as the evaluation can still read them.
+    checkout_locks: RefCell<HashMap<String, File>>,
+    channel_root: Option<PathBuf>,
+    base_dir: PathBuf,
+    files: HashMap<PathBuf, Rc<[u8]>>,
+    import_roots: Vec<PathBuf>,
+    filesystem: bool,
+    remote_imports: bool,
+}
+
+impl SandboxImportResolver {
+    fn check_remote(&self, path: &str) -> JrResult<()> {
+        if self.remote_imports {
+            Ok(())
+        } else {
+            Err(ErrorKind::ImportIo(format!("remote imports are disabled: {path}")).into())
+        }
+    }
+
+    /// Resolve a `channel:<name>/<path>` import to the file in the channel's
+    /// pinned checkout, and log the pin like a remote import's digest.
+    fn resolve_channel(&self, reference: &str) -> JrResult<SourcePath> {
+        if !self.filesystem {
+            return Err(ErrorKind::ImportIo(format!(
+                "{CHANNEL_IMPORT_PREFIX}{reference} is not available; filesystem imports are disabled"
+            ))
+            .into());
+        }
+        let channels = match self.channels.get() {
+            Some(channels) => channels,
+            None => {
+                let loaded = store_base_root()
+                    .and_then(|base_root| Channels::load(&base_root))
+                    .map_err(|err| ErrorKind::ImportIo(err.to_string()))?;
+                self.channels.get_or_init(|| loaded)
+            }
+        };
+        let (path, channel) = channels
+            .resolve(reference, &mut self.checkout_locks.borrow_mut())
+            .map_err(|err| ErrorKind::ImportIo(err.to_string()))?;
+        self.inner.record(
+            &format!("{CHANNEL_IMPORT_PREFIX}{}", channel.name),
+            channel.sha256.clone(),
+        );
+        self.inner.resolve(&path)
+    }
+
+    /// Resolve a `local:<path>` import to a virtual file holding the
+    /// absolute path of the directory as a string literal.
+    fn resolve_local_source(&self, from: &SourcePath, path: &str) -> JrResult<SourcePath> {
+        let Some(dir) = self.importing_dir(from) else {
+            return Err(ErrorKind::ImportIo(format!(
+                "{LOCAL_IMPORT_PREFIX}{path} cannot be imported from a remote file"
+            ))
+            .into());
+        };
+        if !self.filesystem {
+            return Err(ErrorKind::ImportIo(format!(
+                "{LOCAL_IMPORT_PREFIX}{path} is not available; filesystem imports are disabled"
+            ))
+            .into());
+        }
+        let candidate = normalize(&dir.join(path));
+        self.check_import_roots(&candidate)?;
+        Ok(SourcePath::new(VirtualSource {
+            path: candidate,
+            local_source: true,
+        }))
+    }
+
+    /// The directory relative imports from `from` resolve against; `None`
+    /// for remote files.
+    fn importing_dir(&self, from: &SourcePath) -> Option<PathBuf> {
+        if let Some(source) = from.downcast_ref::<VirtualSource>() {
+            Some(source.path.parent().unwrap_or(Path::new("/")).to_path_buf())
+        } else if let Some(file) = from.path() {
+            Some(file.parent().unwrap_or(Path::new("/")).to_path_buf())
+        } else if from.is_default() {
+            Some(self.base_dir.clone())
+        } else {
+            None
+        }
+    }
+
+    fn check_import_roots(&self, path: &Path) -> JrResult<()> {
+        if !self.import_roots.is_empty()
+            && !self
+                .channel_root
+                .as_ref()
+                .is_some_and(|root| path.starts_with(root))
+            && !self.import_roots.iter().any(|root| path.starts_with(root))
+        {
+            return Err(ErrorKind::ImportIo(format!(
+                "{} is outside the allowed import roots",
+                path.display()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Resolve `candidate`, an absolute path with `.` and `..` removed, to a
+    /// virtual file or, when allowed, a file on disk.
+    fn resolve_path(
+        &self,
+        candidate: PathBuf,
+        disk: impl FnOnce() -> JrResult<SourcePath>,
+    ) -> JrResult<SourcePath> {
+        if self.files.contains_key(&candidate) {
+            return Ok(SourcePath::new(VirtualSource {
+                path: candidate,
+                local_source: false,
+            }));
+        }
+        if !self.filesystem {
+            return Err(ErrorKind::ImportIo(format!(
+                "{} is not available; filesystem imports are disabled",
+                candidate.display()
+            ))
+            .into());
+        }
+        let resolved = disk()?;
+        if let Some(path) = resolved.path() {
+            self.check_import_roots(path)?;
+        }
+        Ok(resolved)
+    }
+}
+
+impl Trace for SandboxImportResolver {
+    fn trace(&self, _tracer: &mut Tracer<'_>) {}
+
+    fn is_type_tracked() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+}
+
+impl ImportResolver for SandboxImportResolver {
+    fn resolve_from(&self, from: &SourcePath, path: &str) -> JrResult<SourcePath> {
+        if let Some(reference) = path.strip_prefix(CHANNEL_IMPORT_PREFIX) {
+            return self.resolve_channel(reference);
+        }
+        if let Some(relative) = path.strip_prefix(LOCAL_IMPORT_PREFIX) {
+            return self.resolve_local_source(from, relative);
+        }
+        if path.starts_with("http://") || path.starts_with("https://") {
+            self.check_remote(path)?;
+            return self.inner.resolve_from(from, path);
+        }
+
+        let Some(dir) = self.importing_dir(from) else {
+            // Relative import from a remote file.
+            self.check_remote(path)?;
+            return self.inner.resolve_from(from, path);
+        };
+
+        let candidate = normalize(&dir.join(path));
+        self.resolve_path(candidate.clone(), || self.inner.resolve(&candidate))
+    }
+
+    fn resolve(&self, path: &Path) -> JrResult<SourcePath> {
+        let candidate = normalize(&self.base_dir.join(path));
+        self.resolve_path(candidate.clone(), || self.inner.resolve(&candidate))
+    }
+
+    fn load_file_contents(&self, resolved: &SourcePath) -> JrResult<Vec<u8>> {
+        if let Some(source) = resolved.downcast_ref::<VirtualSource>() {
+            if source.local_source {
+                // A JSON string is also a valid Jsonnet string literal.
+                return serde_json::to_vec(&source.path.to_string_lossy())
+                    .map_err(|err| ErrorKind::ImportIo(err.to_string()).into());
+            }
+            if let Some(contents) = self.files.get(&source.path) {
+                return Ok(contents.to_vec());
+            }
+        }
+        self.inner.load_file_contents(resolved)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct VirtualSource {
+    path: PathBuf,
+    /// A `local:` import, whose contents are `path` itself.
+    local_source: bool,
+}
+
+impl fmt::Debug for VirtualSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VirtualSource({})", self.path.display())
+    }
+}
+
+impl fmt::Display for VirtualSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+impl Trace for VirtualSource {
+    fn trace(&self, _tracer: &mut Tracer<'_>) {}
+
+    fn is_type_tracked() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+}
+
+impl SourcePathT for VirtualSource {
+    fn is_default(&self) -> bool {
+        false
+    }
+
+    // Not a real file, so `std.thisFile`-style path lookups never touch
+    // the disk.
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn dyn_hash(&self, state: &mut dyn Hasher) {
+        state.write(self.path.as_os_str().as_encoded_bytes());
+    }
+
+    fn dyn_eq(&self, other: &dyn SourcePathT) -> bool {
+        other
+            .as_any()
+            .downcast_ref::<Self>()
+            .is_some_and(|o| o == self)
+    }
+
+    fn dyn_debug(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Remove `.` and `..` components without touching the filesystem, so
+/// virtual paths never escape `/`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::Normal(part) => out.push(part),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    out
+}
@@ -0,0 +1,97 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, Write},
+    os::{fd::FromRawFd, unix::process::ExitStatusExt},
+    process::{Child, ExitStatus},
+    time::Duration,
+};
+
+use crate::{MagError, MagResult};
+
+/// Destination of `magpkg venv --json-exit-report FD`: a file descriptor the
+/// caller opened (e.g. `3>report.json`) that receives one JSON object once
+/// the command inside the venv has exited.
+///
+/// ```text
+/// {"exit_code":1,"signal":null,"wall_time_secs":12.84,"max_rss_bytes":73400320,
+///  "rootfs_hash":"5f0c…","command":["make","check"]}
+/// ```
+///
+/// `max_rss_bytes` is the peak resident set size of the largest process the
+/// command ran (see [`wait_with_max_rss`]). Exactly one of `exit_code` and
+/// `signal` is set.
+pub struct ExitReportTarget {
+    file: File,
+}
+
+impl ExitReportTarget {
+    /// Take ownership of `fd`. It is marked close-on-exec so the sandboxed
+    /// command cannot write to it.
+    pub fn open(fd: i32) -> MagResult<Self> {
+        // SAFETY: fcntl on an arbitrary descriptor number only inspects or
+        // updates its flags; an invalid number yields EBADF.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if fd <= 2 || flags < 0 {
+            return Err(MagError::Generic(format!(
+                "--json-exit-report needs an open file descriptor above 2, got {fd}"
+            )));
+        }
+        // SAFETY: as above.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) };
+        // SAFETY: the descriptor is open and nothing else in this process
+        // owns it; the caller handed it over on the command line.
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Self { file })
+    }
+
+    pub fn write(
+        mut self,
+        status: ExitStatus,
+        max_rss: Option<u64>,
+        wall_time: Duration,
+        rootfs_hash: &str,
+        command: &[OsString],
+    ) -> MagResult<()> {
+        let report = serde_json::json!({
+            "exit_code": status.code(),
+            "signal": status.signal(),
+            "wall_time_secs": (wall_time.as_secs_f64() * 1000.0).round() / 1000.0,
+            "max_rss_bytes": max_rss,
+            "rootfs_hash": rootfs_hash,
+            "command": command
+                .iter()
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>(),
+        });
+        writeln!(self.file, "{report}")?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Wait for `child` and return its exit status together with the peak RSS
+/// of the largest process in its tree, which includes the processes that
+/// bubblewrap reaps inside the sandbox.
+pub fn wait_with_max_rss(child: Child) -> io::Result<(ExitStatus, Option<u64>)> {
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: rusage is plain data; wait4 only writes into the status and
+    // rusage we pass, for a child this process spawned and has not reaped.
+    let usage = unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        loop {
+            if libc::wait4(pid, &mut status, 0, &mut usage) >= 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        usage
+    };
+    // ru_maxrss is reported in KiB on Linux.
+    let max_rss = u64::try_from(usage.ru_maxrss).ok().map(|kib| kib * 1024);
+    Ok((ExitStatus::from_raw(status), max_rss))
+}
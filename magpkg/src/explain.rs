@@ -0,0 +1,292 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    fs,
+    rc::Rc,
+    time::Duration,
+};
+
+use reqwest::Url;
+
+use crate::{
+    buildtimes::BuildHistory,
+    diff::short_hash,
+    package::{BuilderShell, Package, collect_closure, package_base_name},
+    store::{PackageStore, format_bytes},
+};
+
+/// Render a Markdown review of what building `roots` would do: which
+/// packages get built or reused, where their sources come from, which
+/// builds relax the sandbox, and how much would be downloaded and added to
+/// the store.
+///
+/// ```text
+/// # Plan for `import "packages/core.jsonnet"`
+///
+/// 37 packages: 12 to build, 25 already in the store.
+///
+/// ## Packages
+///
+/// | Package | Hash | Status | Sources | Sandbox |
+/// | ------- | ---- | ------ | ------- | ------- |
+/// | zlib | 5f0c1a2b3c4d | build | 1 from zlib.net | |
+/// ...
+/// ```
+pub fn explain_plan(
+    expression: &str,
+    roots: &[Rc<Package>],
+    store: &PackageStore,
+    history: &BuildHistory,
+) -> String {
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for root in roots {
+        collect_closure(root.clone(), &mut visited, &mut order);
+    }
+
+    let to_build: Vec<&Rc<Package>> = order
+        .iter()
+        .filter(|package| !store.is_artifact_published(package))
+        .collect();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# Plan for `{}`\n", expression.trim());
+    let _ = writeln!(
+        out,
+        "{} packages: {} to build, {} already in the store.\n",
+        order.len(),
+        to_build.len(),
+        order.len() - to_build.len()
+    );
+
+    let _ = writeln!(out, "## Packages\n");
+    let _ = writeln!(out, "| Package | Hash | Status | Sources | Sandbox |");
+    let _ = writeln!(out, "| ------- | ---- | ------ | ------- | ------- |");
+    for package in &order {
+        let status = if store.is_artifact_published(package) {
+            "in store"
+        } else {
+            "build"
+        };
+        let mut domains: Vec<String> = package
+            .fetch
+            .iter()
+            .flat_map(|fetch| fetch.urls.iter().map(|url| source_domain(url)))
+            .collect();
+        domains.sort();
+        domains.dedup();
+        let sources = if package.fetch.is_empty() {
+            String::new()
+        } else {
+            format!("{} from {}", package.fetch.len(), domains.join(", "))
+        };
+        let _ = writeln!(
+            out,
+            "| {} | {} | {status} | {sources} | {} |",
+            escape_cell(package.name.as_deref().unwrap_or("(unnamed)")),
+            short_hash(&package.hash),
+            escape_cell(&sandbox_exceptions(package).join("; "))
+        );
+    }
+
+    let mut by_domain: BTreeMap<String, Vec<(&str, bool)>> = BTreeMap::new();
+    let mut seen_fetches = HashSet::new();
+    for package in &to_build {
+        for fetch in &package.fetch {
+            if !seen_fetches.insert(fetch.sha256.as_str()) {
+                continue;
+            }
+            let cached = store.fetch_root().join(&fetch.sha256).is_file();
+            let primary = fetch
+                .urls
+                .first()
+                .map(|url| source_domain(url))
+                .unwrap_or_else(|| "(no URLs)".into());
+            by_domain
+                .entry(primary)
+                .or_default()
+                .push((fetch.filename.as_str(), cached));
+        }
+    }
+    if !by_domain.is_empty() {
+        let _ = writeln!(out, "\n## Sources\n");
+        let _ = writeln!(
+            out,
+            "Sources of the packages to build, by the domain of their first URL:\n"
+        );
+        for (domain, files) in &by_domain {
+            let cached = files.iter().filter(|(_, cached)| *cached).count();
+            let names: Vec<&str> = files.iter().map(|(name, _)| *name).collect();
+            let _ = write!(out, "- {domain}: {}", names.join(", "));
+            if cached > 0 {
+                let _ = write!(out, " ({cached} cached)");
+            }
+            let _ = writeln!(out);
+        }
+    }
+
+    let relaxed: Vec<(&Rc<Package>, Vec<String>)> = to_build
+        .iter()
+        .map(|package| (*package, sandbox_exceptions(package)))
+        .filter(|(_, exceptions)| !exceptions.is_empty())
+        .collect();
+    let _ = writeln!(out, "\n## Sandbox\n");
+    let _ = writeln!(
+        out,
+        "Builds run without network access; only the fetches above download anything."
+    );
+    if relaxed.is_empty() {
+        let _ = writeln!(
+            out,
+            "No build that needs to run changes the default sandbox."
+        );
+    } else {
+        let _ = writeln!(out);
+        for (package, exceptions) in relaxed {
+            let _ = writeln!(
+                out,
+                "- {}: {}",
+                package_base_name(package),
+                exceptions.join("; ")
+            );
+        }
+    }
+
+    let mut download_count = 0;
+    let mut cached_bytes = 0;
+    let mut cached_count = 0;
+    seen_fetches.clear();
+    for package in &to_build {
+        for fetch in &package.fetch {
+            if !seen_fetches.insert(fetch.sha256.as_str()) {
+                continue;
+            }
+            match fs::metadata(store.fetch_root().join(&fetch.sha256)) {
+                Ok(meta) => {
+                    cached_count += 1;
+                    cached_bytes += meta.len();
+                }
+                Err(_) => download_count += 1,
+            }
+        }
+    }
+
+    let budgeted: u64 = to_build
+        .iter()
+        .filter_map(|package| package.budget.max_bytes)
+        .sum();
+    let unbudgeted = to_build
+        .iter()
+        .filter(|package| package.budget.max_bytes.is_none())
+        .count();
+
+    let known: Duration = to_build
+        .iter()
+        .filter_map(|package| history.get(package))
+        .sum();
+    let never_built = to_build
+        .iter()
+        .filter(|package| history.get(package).is_none())
+        .count();
+
+    let _ = writeln!(out, "\n## Totals\n");
+    let _ = writeln!(
+        out,
+        "- Downloads: {download_count} files not in the fetch cache (sizes are known only once fetched); {cached_count} cached ({})",
+        format_bytes(cached_bytes)
+    );
+    let _ = write!(out, "- Store growth: {} new artifacts", to_build.len());
+    if budgeted > 0 {
+        let _ = write!(
+            out,
+            ", at most {} declared by budgets",
+            format_bytes(budgeted)
+        );
+        if unbudgeted > 0 {
+            let _ = write!(out, " plus {unbudgeted} without a budget");
+        }
+    }
+    let _ = writeln!(out);
+    if known > Duration::ZERO {
+        let _ = write!(
+            out,
+            "- Build time: ~{} min from recorded build times",
+            known.as_secs().div_ceil(60)
+        );
+        if never_built > 0 {
+            let _ = write!(out, " (+{never_built} never built)");
+        }
+        let _ = writeln!(out);
+    } else if never_built > 0 {
+        let _ = writeln!(
+            out,
+            "- Build time: unknown, {never_built} packages were never built here"
+        );
+    }
+    out
+}
+
+/// Everything a package changes about the default build sandbox, phrased
+/// for a reviewer.
+fn sandbox_exceptions(package: &Package) -> Vec<String> {
+    let sandbox = &package.sandbox;
+    let mut exceptions = Vec::new();
+    if !sandbox.ro_binds.is_empty() {
+        exceptions.push(format!("host mounts {}", sandbox.ro_binds.join(", ")));
+    }
+    if sandbox.host_dev == Some(false) {
+        exceptions.push("minimal /dev".into());
+    }
+    if sandbox.fakeroot {
+        exceptions.push("fakeroot".into());
+    }
+    if sandbox.uid.is_some() || sandbox.gid.is_some() {
+        exceptions.push(format!(
+            "runs as {}:{}",
+            sandbox.uid.unwrap_or(0),
+            sandbox.gid.unwrap_or(0)
+        ));
+    }
+    if let Some(hostname) = &sandbox.hostname {
+        exceptions.push(format!("hostname {hostname}"));
+    }
+    if let Some(fake_time) = &sandbox.fake_time {
+        exceptions.push(format!("clock pinned to {fake_time}"));
+    }
+    if sandbox.random_seed.is_some() {
+        exceptions.push("seeded /dev/urandom".into());
+    }
+    match &package.builder_shell {
+        Some(BuilderShell::Path(path)) => exceptions.push(format!("shell {path}")),
+        Some(BuilderShell::Package(shell)) => {
+            exceptions.push(format!("shell from {}", package_base_name(shell)))
+        }
+        None => {}
+    }
+    if let Some(interpreter) = package.build_with.first() {
+        exceptions.push(format!("interpreter {interpreter}"));
+    }
+    if !package.env.is_empty() {
+        let names: Vec<&str> = package.env.keys().map(String::as_str).collect();
+        exceptions.push(format!("env {}", names.join(", ")));
+    }
+    exceptions
+}
+
+fn source_domain(url: &str) -> String {
+    if url.trim_start().starts_with("magnet:") {
+        return "BitTorrent".into();
+    }
+    match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "file" => "local files".into(),
+        Ok(parsed) => parsed
+            .host_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| parsed.scheme().to_string()),
+        Err(_) => url.to_string(),
+    }
+}
+
+fn escape_cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
@@ -0,0 +1,393 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use reqwest::{
+    Url,
+    blocking::{RequestBuilder, Response},
+    header::{AUTHORIZATION, HeaderName, HeaderValue, LOCATION},
+};
+
+use crate::{MagError, MagResult, mirrors::is_url_prefix};
+
+pub const FETCH_AUTH_CONFIG_FILE: &str = "fetch-auth.conf";
+pub const NETRC_ENV: &str = "NETRC";
+/// Prefix of a value that is read from the environment when a request is
+/// made, so secrets need not be written to the config file.
+const ENV_VALUE_PREFIX: &str = "env:";
+/// Redirects followed by [`FetchAuth::send`] before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+/// One header sent to URLs under a prefix.
+#[derive(Debug)]
+struct HeaderRule {
+    prefix: String,
+    name: HeaderName,
+    /// Text put before the value, such as `Bearer `.
+    scheme: &'static str,
+    /// The literal value, or `env:<VAR>`.
+    value: String,
+    /// `<file>:<line>` of the rule, for errors.
+    location: String,
+}
+
+/// Credentials for HTTP fetches from private mirrors, read from
+/// `<store>/fetch-auth.conf`:
+///
+/// ```text
+/// header <url-prefix> <name> <value>   (value is the rest of the line)
+/// bearer <url-prefix> <token>          (an Authorization: Bearer header)
+/// netrc  on|off                        (default on)
+/// ```
+///
+/// A value of `env:<VAR>` is read from that environment variable when the
+/// request is sent. For each header name the longest matching prefix wins.
+/// URLs without an `Authorization` rule fall back to the `machine` and
+/// `default` entries of `$NETRC` or `~/.netrc`. Manifests never carry
+/// credentials, so configuring them does not change any package hash.
+///
+/// Requests are sent with [`Self::send`], which follows redirects itself so
+/// that headers configured for one origin never reach another.
+#[derive(Debug, Default)]
+pub struct FetchAuth {
+    rules: Vec<HeaderRule>,
+    netrc: Option<Netrc>,
+}
+
+impl FetchAuth {
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        let mut auth = Self::default();
+        let mut use_netrc = true;
+        let path = base_root.join(FETCH_AUTH_CONFIG_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let location = format!("{}:{}", path.display(), index + 1);
+            let invalid = || MagError::Generic(format!("{location}: invalid fetch auth setting"));
+            let (key, rest) = split_field(line);
+            match key {
+                "header" => {
+                    let (prefix, rest) = split_field(rest);
+                    let (name, value) = split_field(rest);
+                    if !is_url_prefix(prefix) || value.is_empty() {
+                        return Err(invalid());
+                    }
+                    let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+                    auth.rules.push(HeaderRule {
+                        prefix: prefix.to_string(),
+                        name,
+                        scheme: "",
+                        value: value.to_string(),
+                        location,
+                    });
+                }
+                "bearer" => {
+                    let (prefix, token) = split_field(rest);
+                    if !is_url_prefix(prefix)
+                        || token.is_empty()
+                        || token.contains(char::is_whitespace)
+                    {
+                        return Err(invalid());
+                    }
+                    auth.rules.push(HeaderRule {
+                        prefix: prefix.to_string(),
+                        name: AUTHORIZATION,
+                        scheme: "Bearer ",
+                        value: token.to_string(),
+                        location,
+                    });
+                }
+                "netrc" => match rest {
+                    "on" => use_netrc = true,
+                    "off" => use_netrc = false,
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            }
+        }
+        // Longest prefix first, so the first rule for a header name is the
+        // most specific one.
+        auth.rules
+            .sort_by(|a, b| b.prefix.len().cmp(&a.prefix.len()));
+        if use_netrc {
+            auth.netrc = netrc_path()
+                .map(|path| Netrc::load(&path))
+                .transpose()?
+                .flatten();
+        }
+        Ok(auth)
+    }
+
+    /// Send the request `make_request` builds for a URL, starting at `url`,
+    /// with the credentials configured for it. `make_request` must use a
+    /// client that does not follow redirects. Redirects are followed here:
+    /// a hop that stays on the origin of `url` gets the credentials of
+    /// `url`, and a hop to another origin only those configured for its
+    /// own URL.
+    pub fn send(
+        &self,
+        url: &Url,
+        make_request: impl Fn(&str) -> RequestBuilder,
+    ) -> MagResult<Response> {
+        let mut current = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let credentials = if current.origin() == url.origin() {
+                url
+            } else {
+                &current
+            };
+            let response = self
+                .apply(credentials, make_request(current.as_str()))?
+                .send()?;
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+            let Some(location) = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+            else {
+                return Ok(response);
+            };
+            current = current.join(location).map_err(|err| {
+                MagError::Generic(format!("{current} redirected to an invalid URL: {err}"))
+            })?;
+            if !matches!(current.scheme(), "http" | "https") {
+                return Err(MagError::Generic(format!(
+                    "{url} redirected to unsupported URL {current}"
+                )));
+            }
+        }
+        Err(MagError::Generic(format!(
+            "{url} redirected more than {MAX_REDIRECTS} times"
+        )))
+    }
+
+    /// Add the configured credentials for `url` to `request`.
+    fn apply(&self, url: &Url, mut request: RequestBuilder) -> MagResult<RequestBuilder> {
+        let mut applied = HashSet::new();
+        for rule in &self.rules {
+            if !url.as_str().starts_with(rule.prefix.as_str()) || !applied.insert(&rule.name) {
+                continue;
+            }
+            let value = format!("{}{}", rule.scheme, rule.resolve_value()?);
+            let mut value = HeaderValue::from_str(&value).map_err(|_| {
+                MagError::Generic(format!(
+                    "{}: header value is not valid in HTTP",
+                    rule.location
+                ))
+            })?;
+            value.set_sensitive(true);
+            request = request.header(rule.name.clone(), value);
+        }
+        if applied.contains(&AUTHORIZATION) || !url.username().is_empty() {
+            return Ok(request);
+        }
+        if let (Some(netrc), Some(host)) = (&self.netrc, url.host_str()) {
+            if let Some(entry) = netrc.entry_for(host) {
+                request = request.basic_auth(&entry.login, Some(&entry.password));
+            }
+        }
+        Ok(request)
+    }
+}
+
+impl HeaderRule {
+    fn resolve_value(&self) -> MagResult<String> {
+        let Some(var) = self.value.strip_prefix(ENV_VALUE_PREFIX) else {
+            return Ok(self.value.clone());
+        };
+        match env::var(var) {
+            Ok(secret) if !secret.trim().is_empty() => Ok(secret.trim().to_string()),
+            _ => Err(MagError::Generic(format!(
+                "{}: environment variable {var} is not set",
+                self.location
+            ))),
+        }
+    }
+}
+
+fn split_field(value: &str) -> (&str, &str) {
+    match value.split_once(char::is_whitespace) {
+        Some((field, rest)) => (field, rest.trim_start()),
+        None => (value, ""),
+    }
+}
+
+fn netrc_path() -> Option<PathBuf> {
+    match env::var_os(NETRC_ENV) {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc")),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct NetrcEntry {
+    login: String,
+    password: String,
+}
+
+/// Logins from a `.netrc` file. `macdef` bodies are skipped and `account`
+/// is ignored.
+#[derive(Debug, Default)]
+struct Netrc {
+    machines: Vec<(String, NetrcEntry)>,
+    default: Option<NetrcEntry>,
+}
+
+impl Netrc {
+    fn load(path: &Path) -> MagResult<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                eprintln!("warning: ignoring {}: {err}", path.display());
+                return Ok(None);
+            }
+        };
+
+        let mut netrc = Netrc::default();
+        // The entry being read: its machine name (None for `default`).
+        let mut current: Option<(Option<String>, NetrcEntry)> = None;
+        let mut in_macdef = false;
+        for line in contents.lines() {
+            if in_macdef {
+                in_macdef = !line.trim().is_empty();
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" => {
+                        netrc.finish(current.take());
+                        let machine = match token {
+                            "machine" => {
+                                Some(tokens.next().unwrap_or_default().to_ascii_lowercase())
+                            }
+                            _ => None,
+                        };
+                        current = Some((machine, NetrcEntry::default()));
+                    }
+                    "login" | "password" | "account" => {
+                        let value = tokens.next().unwrap_or_default().to_string();
+                        if let Some((_, entry)) = &mut current {
+                            match token {
+                                "login" => entry.login = value,
+                                "password" => entry.password = value,
+                                _ => {}
+                            }
+                        }
+                    }
+                    "macdef" => {
+                        in_macdef = true;
+                        break;
+                    }
+                    _ if token.starts_with('#') => break,
+                    _ => {}
+                }
+            }
+        }
+        netrc.finish(current);
+        Ok(Some(netrc))
+    }
+
+    fn finish(&mut self, entry: Option<(Option<String>, NetrcEntry)>) {
+        match entry {
+            Some((Some(machine), entry)) => self.machines.push((machine, entry)),
+            Some((None, entry)) => self.default = Some(entry),
+            None => {}
+        }
+    }
+
+    /// The first entry for `host`, else the `default` entry.
+    fn entry_for(&self, host: &str) -> Option<&NetrcEntry> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.machines
+            .iter()
+            .find(|(machine, _)| *machine == host)
+            .map(|(_, entry)| entry)
+            .or(self.default.as_ref())
+            .filter(|entry| !entry.login.is_empty() || !entry.password.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(config: &str) -> MagResult<FetchAuth> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(FETCH_AUTH_CONFIG_FILE), config).unwrap();
+        FetchAuth::load(dir.path())
+    }
+
+    #[test]
+    fn parses_rules_longest_prefix_first() {
+        let auth = load(
+            "# mirrors\n\
+             netrc off\n\
+             bearer https://mirror.example.com/ env:MIRROR_TOKEN\n\
+             header https://mirror.example.com/private/ X-Api-Key two words\n",
+        )
+        .unwrap();
+        assert!(auth.netrc.is_none());
+        assert_eq!(auth.rules.len(), 2);
+        assert_eq!(auth.rules[0].prefix, "https://mirror.example.com/private/");
+        assert_eq!(auth.rules[0].name.as_str(), "x-api-key");
+        assert_eq!(auth.rules[0].value, "two words");
+        assert_eq!(auth.rules[1].name, AUTHORIZATION);
+        assert_eq!(auth.rules[1].scheme, "Bearer ");
+        assert_eq!(auth.rules[1].value, "env:MIRROR_TOKEN");
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for config in [
+            "netrc maybe\n",
+            "bearer https://mirror.example.com/\n",
+            "bearer https://mirror.example.com/ two tokens\n",
+            "header https://mirror.example.com/ X-Api-Key\n",
+            "header https://mirror.example.com/ Bad:Name value\n",
+            "header not-a-url X-Api-Key value\n",
+            "password hunter2\n",
+        ] {
+            assert!(
+                load(&format!("netrc off\n{config}")).is_err(),
+                "{config:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_netrc_machines_and_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("netrc");
+        fs::write(
+            &path,
+            "machine Mirror.Example.com login alice password secret\n\
+             macdef init\n\
+             echo machine ignored.example.com login mallory\n\
+             \n\
+             default login anonymous password guest\n",
+        )
+        .unwrap();
+        let netrc = Netrc::load(&path).unwrap().unwrap();
+        let entry = netrc.entry_for("mirror.example.com.").unwrap();
+        assert_eq!(
+            (entry.login.as_str(), entry.password.as_str()),
+            ("alice", "secret")
+        );
+        let entry = netrc.entry_for("ignored.example.com").unwrap();
+        assert_eq!(entry.login, "anonymous");
+    }
+}
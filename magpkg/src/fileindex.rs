@@ -0,0 +1,242 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Write},
+    path::{Component, Path},
+};
+
+use tar::EntryType;
+
+use crate::{MagError, MagResult, codec::open_artifact};
+
+pub const FILE_INDEX_SUFFIX: &str = ".files";
+
+/// First line of every index; indexes written in an older layout are
+/// rebuilt rather than parsed.
+const INDEX_VERSION: &str = "files\t2";
+
+/// The paths a package artifact provides, cached next to the artifact as
+/// `pkgs/<base>.files` so ownership queries never decompress tarballs twice.
+///
+/// ```text
+/// files     2
+/// artifact  <sha256 of the artifact>
+/// d         /usr/bin
+/// f         /usr/bin/foo          <offset>    <size>
+/// l         /usr/lib/libfoo.so    libfoo.so.1
+/// ```
+///
+/// The header ties the index to the artifact it was read from; a rebuilt or
+/// substituted artifact has a new digest and gets a fresh index. Regular
+/// files record where their bytes sit in the decompressed tar stream, so
+/// one file can be read back without unpacking the artifact. Hard links
+/// have no contents of their own and carry no range.
+pub struct FileIndex {
+    pub entries: Vec<IndexedPath>,
+}
+
+#[derive(Clone)]
+pub struct IndexedPath {
+    pub kind: PathKind,
+    /// Absolute path inside the package's root filesystem.
+    pub path: String,
+    pub link_target: Option<String>,
+    /// Offset and length of the file's data in the decompressed tar stream.
+    pub contents: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl PathKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PathKind::File => "file",
+            PathKind::Dir => "directory",
+            PathKind::Symlink => "symlink",
+            PathKind::Other => "other",
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            PathKind::File => "f",
+            PathKind::Dir => "d",
+            PathKind::Symlink => "l",
+            PathKind::Other => "o",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "f" => Some(PathKind::File),
+            "d" => Some(PathKind::Dir),
+            "l" => Some(PathKind::Symlink),
+            "o" => Some(PathKind::Other),
+            _ => None,
+        }
+    }
+}
+
+impl FileIndex {
+    /// Read the cached index for `artifact`, rebuilding it from the tarball
+    /// when it is missing or was made from a different artifact.
+    pub fn load(artifact: &Path, index_path: &Path, artifact_sha256: &str) -> MagResult<Self> {
+        match fs::read_to_string(index_path) {
+            Ok(contents) => {
+                if let Some(index) = parse_index(&contents, artifact_sha256) {
+                    return Ok(index);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let index = read_artifact(artifact)?;
+        index.write(index_path, artifact_sha256)?;
+        Ok(index)
+    }
+
+    pub fn get(&self, path: &str) -> Option<&IndexedPath> {
+        self.entries
+            .binary_search_by(|entry| entry.path.as_str().cmp(path))
+            .ok()
+            .map(|position| &self.entries[position])
+    }
+
+    fn write(&self, index_path: &Path, artifact_sha256: &str) -> MagResult<()> {
+        let tmp_path = index_path.with_extension(format!("files.{}.tmp", std::process::id()));
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(writer, "{INDEX_VERSION}")?;
+            writeln!(writer, "artifact\t{artifact_sha256}")?;
+            for entry in &self.entries {
+                write!(writer, "{}\t{}", entry.kind.code(), entry.path)?;
+                if let Some(target) = &entry.link_target {
+                    write!(writer, "\t{target}")?;
+                }
+                if let Some((offset, size)) = entry.contents {
+                    write!(writer, "\t{offset}\t{size}")?;
+                }
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, index_path)?;
+        Ok(())
+    }
+}
+
+fn parse_index(contents: &str, artifact_sha256: &str) -> Option<FileIndex> {
+    let mut lines = contents.lines();
+    if lines.next()? != INDEX_VERSION || lines.next()? != format!("artifact\t{artifact_sha256}") {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for line in lines {
+        let mut fields = line.split('\t');
+        let kind = PathKind::from_code(fields.next()?)?;
+        let path = fields.next()?.to_string();
+        let (link_target, contents) = match kind {
+            PathKind::Symlink => (fields.next().map(str::to_string), None),
+            PathKind::File => match (fields.next(), fields.next()) {
+                (Some(offset), Some(size)) => {
+                    (None, Some((offset.parse().ok()?, size.parse().ok()?)))
+                }
+                _ => (None, None),
+            },
+            _ => (None, None),
+        };
+        entries.push(IndexedPath {
+            kind,
+            path,
+            link_target,
+            contents,
+        });
+    }
+    Some(FileIndex { entries })
+}
+
+fn read_artifact(artifact: &Path) -> MagResult<FileIndex> {
+    let read_error = |err: std::io::Error| {
+        MagError::Generic(format!(
+            "failed to list files in {}: {err}",
+            artifact.display()
+        ))
+    };
+    let mut archive = tar::Archive::new(open_artifact(artifact)?);
+    let mut entries = Vec::new();
+    for entry in archive.entries().map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        let Some(path) = rooted_path(&entry.path().map_err(read_error)?) else {
+            continue;
+        };
+        let kind = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::Link => PathKind::File,
+            EntryType::Directory => PathKind::Dir,
+            EntryType::Symlink => PathKind::Symlink,
+            _ => PathKind::Other,
+        };
+        let link_target = match kind {
+            PathKind::Symlink => entry
+                .link_name()
+                .map_err(read_error)?
+                .map(|target| target.to_string_lossy().into_owned())
+                .filter(|target| !target.contains(['\t', '\n'])),
+            _ => None,
+        };
+        let contents = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                Some((entry.raw_file_position(), entry.size()))
+            }
+            _ => None,
+        };
+        entries.push(IndexedPath {
+            kind,
+            path,
+            link_target,
+            contents,
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries.dedup_by(|later, earlier| {
+        if later.path == earlier.path {
+            std::mem::swap(later, earlier);
+            true
+        } else {
+            false
+        }
+    });
+    Ok(FileIndex { entries })
+}
+
+/// `./usr/bin/foo` in an artifact becomes `/usr/bin/foo`; the root itself
+/// and paths that cannot be represented in the index are skipped.
+fn rooted_path(path: &Path) -> Option<String> {
+    let mut rooted = String::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part.to_str()?;
+                if part.contains(['\t', '\n']) {
+                    return None;
+                }
+                rooted.push('/');
+                rooted.push_str(part);
+            }
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!rooted.is_empty()).then_some(rooted)
+}
+
+/// Normalize a user-supplied path to the index's form: absolute, without
+/// `.` components or a trailing slash.
+pub fn normalize_query(path: &str) -> Option<String> {
+    rooted_path(Path::new(path))
+}
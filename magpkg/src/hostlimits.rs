@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::Path,
+    sync::{
+        Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+
+use crate::{MagError, MagResult};
+
+pub const FETCH_HOSTS_CONFIG_FILE: &str = "fetch-hosts.conf";
+const DEFAULT_HOST_CONNECTIONS: usize = 4;
+/// Longest `Retry-After` a host may impose on later requests.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Connection and request-rate limit for one host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostLimit {
+    pub connections: usize,
+    /// Minimum delay between the starts of two requests; zero is unlimited.
+    pub interval: Duration,
+}
+
+impl Default for HostLimit {
+    fn default() -> Self {
+        Self {
+            connections: DEFAULT_HOST_CONNECTIONS,
+            interval: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Default)]
+struct HostState {
+    active: usize,
+    next_start: Option<Instant>,
+}
+
+/// Per-host politeness limits for HTTP fetches, read from
+/// `<store>/fetch-hosts.conf`:
+///
+/// ```text
+/// default <connections> [<requests-per-minute>]
+/// host    <domain> <connections> [<requests-per-minute>]
+/// ```
+///
+/// A `host` line also covers subdomains of its domain; the longest match
+/// wins. Limits are shared by every download in this process, so racing
+/// sources and concurrent fetches wait their turn on a busy mirror.
+pub struct HostLimiter {
+    default: HostLimit,
+    hosts: Vec<(String, HostLimit)>,
+    state: Mutex<HashMap<String, HostState>>,
+    changed: Condvar,
+}
+
+/// One granted connection to a host, released when dropped.
+pub struct HostPermit<'a> {
+    limiter: &'a HostLimiter,
+    host: String,
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.lock_state();
+        if let Some(host) = state.get_mut(&self.host) {
+            host.active = host.active.saturating_sub(1);
+        }
+        self.limiter.changed.notify_all();
+    }
+}
+
+impl HostLimiter {
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        let mut limiter = Self {
+            default: HostLimit::default(),
+            hosts: Vec::new(),
+            state: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        };
+        let path = base_root.join(FETCH_HOSTS_CONFIG_FILE);
+        match fs::read_to_string(&path) {
+            Ok(contents) => limiter.parse_file(&path, &contents)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(limiter)
+    }
+
+    fn parse_file(&mut self, path: &Path, contents: &str) -> MagResult<()> {
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                MagError::Generic(format!(
+                    "{}:{}: invalid host limit {line:?}",
+                    path.display(),
+                    index + 1
+                ))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["default", limit @ ..] => {
+                    self.default = parse_limit(limit).ok_or_else(invalid)?;
+                }
+                ["host", domain, limit @ ..] => {
+                    let domain = domain
+                        .trim_start_matches('.')
+                        .trim_end_matches('.')
+                        .to_ascii_lowercase();
+                    if domain.is_empty() {
+                        return Err(invalid());
+                    }
+                    let limit = parse_limit(limit).ok_or_else(invalid)?;
+                    self.hosts.retain(|(existing, _)| *existing != domain);
+                    self.hosts.push((domain, limit));
+                }
+                [key, ..] => {
+                    return Err(MagError::Generic(format!(
+                        "{}:{}: unknown setting {key:?}",
+                        path.display(),
+                        index + 1
+                    )));
+                }
+                [] => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The limit that applies to `host`.
+    pub fn limit_for(&self, host: &str) -> HostLimit {
+        self.hosts
+            .iter()
+            .filter(|(domain, _)| {
+                host == domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, limit)| *limit)
+            .unwrap_or(self.default)
+    }
+
+    /// Wait until a request to `url`'s host is allowed, then hold one of its
+    /// connections until the permit is dropped. Returns an error if `cancel`
+    /// is set while waiting.
+    pub fn acquire(&self, url: &Url, cancel: &AtomicBool) -> MagResult<HostPermit<'_>> {
+        let host = host_key(url);
+        let limit = self.limit_for(&host);
+        let mut reported = false;
+        let mut state = self.lock_state();
+        loop {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(MagError::Generic(format!(
+                    "download from {host} was cancelled"
+                )));
+            }
+            let entry = state.entry(host.clone()).or_default();
+            let now = Instant::now();
+            let wait = match entry.next_start {
+                Some(next) if next > now => Some(next - now),
+                _ if entry.active >= limit.connections => None,
+                _ => {
+                    entry.active += 1;
+                    entry.next_start = Some(now + limit.interval);
+                    return Ok(HostPermit {
+                        limiter: self,
+                        host,
+                    });
+                }
+            };
+            if !reported {
+                eprintln!("waiting for a free connection to {host}");
+                reported = true;
+            }
+            let timeout = wait
+                .unwrap_or(CANCEL_POLL_INTERVAL)
+                .min(CANCEL_POLL_INTERVAL);
+            state = self
+                .changed
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+
+    /// Hold back further requests to `url`'s host after it answered with
+    /// HTTP 429 or 503 and a `Retry-After` of `delay`.
+    pub fn back_off(&self, url: &Url, delay: Duration) {
+        let delay = delay.min(MAX_RETRY_AFTER);
+        let host = host_key(url);
+        eprintln!(
+            "{host} asked to retry after {}s; holding further requests",
+            delay.as_secs()
+        );
+        let mut state = self.lock_state();
+        let entry = state.entry(host).or_default();
+        let until = Instant::now() + delay;
+        if entry.next_start.is_none_or(|next| next < until) {
+            entry.next_start = Some(until);
+        }
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, HashMap<String, HostState>> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Parse `<connections> [<requests-per-minute>]`.
+fn parse_limit(fields: &[&str]) -> Option<HostLimit> {
+    let (connections, rate) = match fields {
+        [connections] => (connections, None),
+        [connections, rate] => (connections, Some(rate)),
+        _ => return None,
+    };
+    let connections = connections.parse::<usize>().ok().filter(|n| *n > 0)?;
+    let interval = match rate {
+        None => Duration::ZERO,
+        Some(rate) => match rate.parse::<u32>().ok()? {
+            0 => Duration::ZERO,
+            per_minute => Duration::from_secs(60) / per_minute,
+        },
+    };
+    Some(HostLimit {
+        connections,
+        interval,
+    })
+}
+
+fn host_key(url: &Url) -> String {
+    url.host_str()
+        .unwrap_or_default()
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+/// Delay requested by a `Retry-After` header given in seconds. The HTTP-date
+/// form is not interpreted.
+pub fn retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
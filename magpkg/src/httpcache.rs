@@ -0,0 +1,503 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use ring::signature::{ED25519, Ed25519KeyPair, UnparsedPublicKey};
+
+use crate::{
+    MagError, MagResult,
+    codec::ArtifactCodec,
+    index::public_key_hex,
+    store::{artifact_is_published, published_sha256},
+};
+
+/// Base URLs of `magpkg serve` instances to substitute package artifacts and
+/// fetch payloads from, separated by commas or whitespace.
+pub const SUBSTITUTERS_ENV: &str = "MAGPKG_SUBSTITUTERS";
+
+const MAX_CONNECTIONS: usize = 64;
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+static SKIP_SIGNATURE_CHECK: OnceLock<bool> = OnceLock::new();
+
+/// Accept unsigned or untrusted publish records from substituters
+/// (`--no-check-signature`). Later calls are ignored.
+pub fn set_skip_signature_check(skip: bool) {
+    let _ = SKIP_SIGNATURE_CHECK.set(skip);
+}
+
+pub fn signature_check_skipped() -> bool {
+    SKIP_SIGNATURE_CHECK.get().copied().unwrap_or(false)
+}
+
+pub fn configured_substituters() -> Vec<String> {
+    env::var(SUBSTITUTERS_ENV)
+        .map(|value| {
+            value
+                .split([',', ' ', '\n', '\t'])
+                .map(|url| url.trim().trim_end_matches('/'))
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The store directories `magpkg serve` exposes.
+///
+/// ```text
+/// GET /index                                   listing, see below
+/// GET /pkgs/<base>.tar.zst                     published package artifact
+/// GET /pkgs/<base>.published                   its publish record, signed
+///                                              when serving with --sign-key
+/// GET /fetch/<sha256>                          cached fetch payload
+/// GET /torrent/<info-hash>/resource.torrent    torrent metadata
+/// ```
+///
+/// The index lists one entry per line:
+///
+/// ```text
+/// package  <base>       <sha256>  <size>
+/// fetch    <sha256>     <size>
+/// torrent  <info-hash>
+/// ```
+pub struct CacheServer {
+    pub package_root: PathBuf,
+    pub fetch_root: PathBuf,
+    pub torrent_root: PathBuf,
+    pub signing_key: Option<Ed25519KeyPair>,
+}
+
+enum Response {
+    File(PathBuf, &'static str),
+    Text(String),
+    Error(u16, &'static str),
+}
+
+impl CacheServer {
+    /// Accept connections until the process is stopped. Each connection is
+    /// served on its own thread and closed after one request.
+    pub fn serve(self, listen: &str) -> MagResult<()> {
+        let listener = TcpListener::bind(listen)?;
+        eprintln!("serving binary cache on http://{}", listener.local_addr()?);
+
+        let server = Arc::new(self);
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("warning: failed to accept connection: {err}");
+                    continue;
+                }
+            };
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                let _ = write_head(&mut stream, 503, "text/plain", 0);
+                continue;
+            }
+            let server = server.clone();
+            let active = active.clone();
+            thread::spawn(move || {
+                if let Err(err) = server.handle(stream) {
+                    if !matches!(
+                        err.kind(),
+                        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset
+                    ) {
+                        eprintln!("warning: request failed: {err}");
+                    }
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "?".into());
+
+        let request_line = match read_request_head(BufReader::new(stream.try_clone()?))? {
+            RequestHead::Line(line) => line,
+            RequestHead::TooLarge => {
+                return write_error(&mut stream, 431, "request header too large", false);
+            }
+            RequestHead::Malformed => {
+                return write_error(&mut stream, 400, "bad request", false);
+            }
+        };
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let path = target.split('?').next().unwrap_or("");
+        let response = match method {
+            "GET" | "HEAD" => self.route(path),
+            _ => Response::Error(405, "method not allowed"),
+        };
+        let head_only = method == "HEAD";
+
+        let status = match response {
+            Response::File(file_path, content_type) => match File::open(&file_path) {
+                Ok(mut file) => {
+                    let len = file.metadata()?.len();
+                    write_head(&mut stream, 200, content_type, len)?;
+                    if !head_only {
+                        io::copy(&mut file, &mut stream)?;
+                    }
+                    200
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    write_error(&mut stream, 404, "not found", head_only)?;
+                    404
+                }
+                Err(err) => return Err(err),
+            },
+            Response::Text(body) => {
+                write_head(
+                    &mut stream,
+                    200,
+                    "text/plain; charset=utf-8",
+                    body.len() as u64,
+                )?;
+                if !head_only {
+                    stream.write_all(body.as_bytes())?;
+                }
+                200
+            }
+            Response::Error(code, message) => {
+                write_error(&mut stream, code, message, head_only)?;
+                code
+            }
+        };
+        stream.flush()?;
+        eprintln!("{peer} {method} {path} {status}");
+        Ok(())
+    }
+
+    fn route(&self, path: &str) -> Response {
+        if path == "/" {
+            return self.route("/index");
+        }
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if !segments.iter().all(|segment| is_safe_segment(segment)) {
+            return Response::Error(404, "not found");
+        }
+        match segments.as_slice() {
+            ["index"] => match self.index() {
+                Ok(index) => Response::Text(index),
+                Err(_) => Response::Error(500, "failed to list the store"),
+            },
+            ["pkgs", name] => {
+                let artifact_name = name
+                    .strip_suffix(".published")
+                    .map(|base| format!("{base}.tar.zst"))
+                    .unwrap_or_else(|| name.to_string());
+                let artifact = self.package_root.join(&artifact_name);
+                if !artifact_name.ends_with(".tar.zst") || !artifact_is_published(&artifact) {
+                    return Response::Error(404, "not found");
+                }
+                if let Some(base) = name.strip_suffix(".published") {
+                    let Some(key) = &self.signing_key else {
+                        return Response::File(
+                            self.package_root.join(name),
+                            "text/plain; charset=utf-8",
+                        );
+                    };
+                    match fs::read_to_string(self.package_root.join(name)) {
+                        Ok(record) => Response::Text(sign_publish_record(base, &record, key)),
+                        Err(_) => Response::Error(404, "not found"),
+                    }
+                } else {
+                    let content_type = match File::open(&artifact)
+                        .and_then(|mut file| ArtifactCodec::detect(&mut file))
+                    {
+                        Ok(ArtifactCodec::Zstd) => "application/zstd",
+                        Ok(ArtifactCodec::Xz) => "application/x-xz",
+                        Ok(ArtifactCodec::None) => "application/x-tar",
+                        Err(_) => "application/octet-stream",
+                    };
+                    Response::File(artifact, content_type)
+                }
+            }
+            ["fetch", sha256] if is_sha256(sha256) => {
+                Response::File(self.fetch_root.join(sha256), "application/octet-stream")
+            }
+            ["torrent", info_hash, "resource.torrent"] => Response::File(
+                self.torrent_root.join(info_hash).join("resource.torrent"),
+                "application/x-bittorrent",
+            ),
+            _ => Response::Error(404, "not found"),
+        }
+    }
+
+    fn index(&self) -> io::Result<String> {
+        let mut lines = Vec::new();
+        for name in sorted_names(&self.package_root)? {
+            let Some(base) = name.strip_suffix(".tar.zst") else {
+                continue;
+            };
+            let artifact = self.package_root.join(&name);
+            if !artifact_is_published(&artifact) {
+                continue;
+            }
+            if let Some(sha256) = published_sha256(&artifact) {
+                let size = fs::metadata(&artifact)?.len();
+                lines.push(format!("package\t{base}\t{sha256}\t{size}"));
+            }
+        }
+        for name in sorted_names(&self.fetch_root)? {
+            if is_sha256(&name) {
+                let size = fs::metadata(self.fetch_root.join(&name))?.len();
+                lines.push(format!("fetch\t{name}\t{size}"));
+            }
+        }
+        for name in sorted_names(&self.torrent_root)? {
+            if self
+                .torrent_root
+                .join(&name)
+                .join("resource.torrent")
+                .is_file()
+            {
+                lines.push(format!("torrent\t{name}"));
+            }
+        }
+        let mut index = lines.join("\n");
+        index.push('\n');
+        Ok(index)
+    }
+}
+
+fn sorted_names(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn is_safe_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && !segment.starts_with('.')
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+}
+
+/// How reading the request line and headers of a request ended.
+#[derive(Debug, PartialEq, Eq)]
+enum RequestHead {
+    /// The request line, with its line ending.
+    Line(String),
+    /// The head is longer than `MAX_HEADER_BYTES`.
+    TooLarge,
+    /// The request line is unterminated or not UTF-8.
+    Malformed,
+}
+
+/// Read a request's line and headers. They are read through one limit, so
+/// a client that never sends a newline cannot grow the buffer past it.
+fn read_request_head(mut reader: impl BufRead) -> io::Result<RequestHead> {
+    let mut head = (&mut reader).take(MAX_HEADER_BYTES as u64 + 1);
+    let mut request_line = String::new();
+    let mut ended = false;
+    let read = (|| -> io::Result<()> {
+        head.read_line(&mut request_line)?;
+        loop {
+            let mut line = String::new();
+            if head.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if line == "\r\n" || line == "\n" {
+                ended = true;
+                return Ok(());
+            }
+        }
+    })();
+    match read {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(RequestHead::Malformed),
+        Err(err) => return Err(err),
+    }
+    if !ended && head.limit() == 0 {
+        return Ok(RequestHead::TooLarge);
+    }
+    if !request_line.ends_with('\n') {
+        return Ok(RequestHead::Malformed);
+    }
+    Ok(RequestHead::Line(request_line))
+}
+
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn write_head(stream: &mut TcpStream, status: u16, content_type: &str, len: u64) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {len}\r\nConnection: close\r\n\r\n"
+    )
+}
+
+fn write_error(
+    stream: &mut TcpStream,
+    status: u16,
+    message: &str,
+    head_only: bool,
+) -> io::Result<()> {
+    let body = format!("{message}\n");
+    write_head(
+        stream,
+        status,
+        "text/plain; charset=utf-8",
+        body.len() as u64,
+    )?;
+    if !head_only {
+        stream.write_all(body.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// A publish record as served to substituters by a signing cache. The
+/// `package` line binds the record to one artifact, and the `signature`
+/// line covers every byte before it:
+///
+/// ```text
+/// package    <base>
+/// sha256 <hex>
+/// size <bytes>
+/// codec <codec>
+/// key        <hex Ed25519 public key>
+/// signature  <hex signature>
+/// ```
+fn sign_publish_record(base: &str, record: &str, key: &Ed25519KeyPair) -> String {
+    let mut body = format!("package\t{base}\n");
+    for line in record.lines().filter(|line| !line.trim().is_empty()) {
+        body.push_str(line);
+        body.push('\n');
+    }
+    body.push_str(&format!("key\t{}\n", public_key_hex(key)));
+    let signature = key.sign(body.as_bytes());
+    body.push_str(&format!("signature\t{}\n", hex::encode(signature.as_ref())));
+    body
+}
+
+/// Check a publish record fetched from `origin` for the artifact `base` and
+/// return the artifact's sha256. Unless signature checks are skipped, the
+/// record must be signed by one of `trusted_keys` and name `base`.
+pub fn verify_publish_record(
+    record: &str,
+    base: &str,
+    trusted_keys: &[Vec<u8>],
+    origin: &str,
+) -> MagResult<String> {
+    let sha256 = |text: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix("sha256 "))
+            .map(|sha| sha.trim().to_string())
+            .ok_or_else(|| MagError::Generic(format!("{origin} sent a malformed record")))
+    };
+    if signature_check_skipped() {
+        return sha256(record);
+    }
+
+    let sig_start = record
+        .rfind("\nsignature\t")
+        .map(|pos| pos + 1)
+        .ok_or_else(|| MagError::Generic(format!("{origin} sent an unsigned record")))?;
+    let (signed, sig_line) = record.split_at(sig_start);
+    let signature = sig_line
+        .trim_end()
+        .strip_prefix("signature\t")
+        .and_then(|value| hex::decode(value).ok())
+        .ok_or_else(|| MagError::Generic(format!("{origin} sent a malformed signature")))?;
+    let key = signed
+        .lines()
+        .find_map(|line| line.strip_prefix("key\t"))
+        .and_then(|value| hex::decode(value.trim()).ok())
+        .ok_or_else(|| MagError::Generic(format!("{origin} sent a record without a key")))?;
+    if !trusted_keys.contains(&key) {
+        return Err(MagError::Generic(format!(
+            "{origin} signed its record with untrusted key {}",
+            hex::encode(&key)
+        )));
+    }
+    UnparsedPublicKey::new(&ED25519, &key)
+        .verify(signed.as_bytes(), &signature)
+        .map_err(|_| MagError::Generic(format!("{origin} record signature verification failed")))?;
+    if signed.lines().next() != Some(format!("package\t{base}").as_str()) {
+        return Err(MagError::Generic(format!(
+            "{origin} sent a signed record for another package"
+        )));
+    }
+    sha256(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn head(bytes: &[u8]) -> RequestHead {
+        read_request_head(Cursor::new(bytes.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn reads_the_request_line() {
+        assert_eq!(
+            head(b"GET /index HTTP/1.1\r\nHost: cache\r\n\r\nignored"),
+            RequestHead::Line("GET /index HTTP/1.1\r\n".into())
+        );
+        assert_eq!(
+            head(b"HEAD /pkgs/a.tar.zst HTTP/1.0\n"),
+            RequestHead::Line("HEAD /pkgs/a.tar.zst HTTP/1.0\n".into())
+        );
+    }
+
+    #[test]
+    fn stops_reading_at_the_limit() {
+        let endless = vec![b'a'; MAX_HEADER_BYTES * 4];
+        assert_eq!(head(&endless), RequestHead::TooLarge);
+
+        let mut long_header = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        long_header.extend(vec![b'a'; MAX_HEADER_BYTES]);
+        long_header.extend(b"\r\n\r\n");
+        assert_eq!(head(&long_header), RequestHead::TooLarge);
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert_eq!(head(b""), RequestHead::Malformed);
+        assert_eq!(head(b"GET / HTTP/1.1"), RequestHead::Malformed);
+        assert_eq!(head(b"GET /\xff HTTP/1.1\r\n\r\n"), RequestHead::Malformed);
+    }
+}
@@ -1,8 +1,12 @@
 use std::{
     any::Any,
-    fmt,
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt, fs,
     hash::{Hash, Hasher},
+    io::Write,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 use jrsonnet_evaluator::{
@@ -12,23 +16,115 @@ use jrsonnet_evaluator::{
 };
 use jrsonnet_gcmodule::{Trace, Tracer};
 use reqwest::Url;
-use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::blocking::ClientBuilder;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{MagResult, offline::is_offline, proxy::HttpClient, store::store_base_root};
 
 const USER_AGENT: &str = concat!("magpkg/", env!("CARGO_PKG_VERSION"));
+const PIN_FRAGMENT_PREFIX: &str = "sha256=";
+
+/// Remote imports loaded during evaluation, keyed by URL (without any pin
+/// fragment) and mapped to the sha256 of the bytes that were served.
+pub type ImportLog = Rc<RefCell<BTreeMap<String, String>>>;
 
 pub struct MagImportResolver {
     file: FileImportResolver,
-    client: Client,
+    client: HttpClient,
+    cache_root: PathBuf,
+    log: ImportLog,
 }
 
 impl MagImportResolver {
-    pub fn new(library_paths: Vec<PathBuf>) -> Self {
+    pub fn new(library_paths: Vec<PathBuf>) -> MagResult<Self> {
         let file = FileImportResolver::new(library_paths);
-        let client = ClientBuilder::new()
-            .user_agent(USER_AGENT)
-            .build()
-            .expect("failed to build http client");
-        Self { file, client }
+        let client = HttpClient::from_env(|| ClientBuilder::new().user_agent(USER_AGENT))?;
+        let cache_root = store_base_root()?.join("imports");
+        Ok(Self {
+            file,
+            client,
+            cache_root,
+            log: ImportLog::default(),
+        })
+    }
+
+    pub fn import_log(&self) -> ImportLog {
+        self.log.clone()
+    }
+
+    fn load_remote(&self, remote: &RemoteSource) -> JrResult<Vec<u8>> {
+        let (url, pin) = split_pin(remote.url())?;
+
+        let cache_path = pin.as_ref().map(|digest| self.cache_root.join(digest));
+        if let (Some(path), Some(digest)) = (&cache_path, &pin) {
+            if let Ok(bytes) = fs::read(path) {
+                if sha256_hex(&bytes) == *digest {
+                    self.record(url, digest.clone());
+                    return Ok(bytes);
+                }
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        if is_offline() {
+            let hint = match pin {
+                Some(_) => "evaluate it once while online to cache it",
+                None => "only imports pinned with #sha256=<digest> are cached",
+            };
+            return Err(ErrorKind::ImportIo(format!(
+                "--offline was given, but remote import {url} is not in the import cache; {hint}"
+            ))
+            .into());
+        }
+
+        let bytes = self.download(url)?;
+        let actual = sha256_hex(&bytes);
+
+        if let (Some(path), Some(digest)) = (&cache_path, &pin) {
+            if actual != *digest {
+                return Err(ErrorKind::ImportIo(format!(
+                    "sha256 mismatch for {url}: expected {digest}, got {actual}"
+                ))
+                .into());
+            }
+            write_cache_entry(&self.cache_root, path, &bytes).map_err(|err| {
+                ErrorKind::ImportIo(format!(
+                    "failed to cache import {url} at {}: {err}",
+                    path.display()
+                ))
+            })?;
+        }
+
+        self.record(url, actual);
+        Ok(bytes)
+    }
+
+    /// Log an import of `url` whose contents had `digest`.
+    pub fn record(&self, url: &str, digest: String) {
+        self.log.borrow_mut().insert(url.to_owned(), digest);
+    }
+
+    fn download(&self, url: &str) -> JrResult<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .map_err(|err| ErrorKind::ImportIo(err.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(ErrorKind::ImportIo(format!(
+                "HTTP {} fetching {}",
+                response.status(),
+                url
+            ))
+            .into());
+        }
+
+        let bytes = response
+            .bytes()
+            .map_err(|err| ErrorKind::ImportIo(err.to_string()))?;
+        Ok(bytes.to_vec())
     }
 }
 
@@ -63,25 +159,7 @@ impl ImportResolver for MagImportResolver {
 
     fn load_file_contents(&self, resolved: &SourcePath) -> JrResult<Vec<u8>> {
         if let Some(remote) = resolved.downcast_ref::<RemoteSource>() {
-            let response = self
-                .client
-                .get(remote.url())
-                .send()
-                .map_err(|err| ErrorKind::ImportIo(err.to_string()))?;
-
-            if !response.status().is_success() {
-                return Err(ErrorKind::ImportIo(format!(
-                    "HTTP {} fetching {}",
-                    response.status(),
-                    remote.url()
-                ))
-                .into());
-            }
-
-            let bytes = response
-                .bytes()
-                .map_err(|err| ErrorKind::ImportIo(err.to_string()))?;
-            return Ok(bytes.to_vec());
+            return self.load_remote(remote);
         }
 
         self.file.load_file_contents(resolved)
@@ -175,3 +253,37 @@ fn join_remote_url(base: &str, path: &str) -> JrResult<String> {
         .map_err(|err| ErrorKind::ImportIo(format!("failed to join {path} onto {base}: {err}")))?;
     Ok(joined.into())
 }
+
+/// Split a `#sha256=<hex>` pin off a remote import URL.
+fn split_pin(url: &str) -> JrResult<(&str, Option<String>)> {
+    let Some((base, fragment)) = url.split_once('#') else {
+        return Ok((url, None));
+    };
+
+    let Some(digest) = fragment.strip_prefix(PIN_FRAGMENT_PREFIX) else {
+        return Ok((base, None));
+    };
+
+    let digest = digest.to_ascii_lowercase();
+    if digest.len() != 64 || !digest.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return Err(ErrorKind::ImportIo(format!(
+            "invalid sha256 pin in import {url}: expected 64 hex characters"
+        ))
+        .into());
+    }
+
+    Ok((base, Some(digest)))
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn write_cache_entry(cache_root: &Path, dest: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(cache_root)?;
+    let mut tmp = NamedTempFile::new_in(cache_root)?;
+    tmp.write_all(bytes)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(dest).map_err(|err| err.error)?;
+    Ok(())
+}
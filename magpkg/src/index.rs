@@ -0,0 +1,370 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    net::SocketAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use reqwest::Url;
+use ring::{
+    rand::SystemRandom,
+    signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+
+use crate::{
+    MagError, MagResult,
+    profiles::{escape, unescape},
+    proxy::HttpClient,
+};
+
+pub const INDEXES_ENV: &str = "MAGPKG_INDEXES";
+pub const TRUSTED_KEYS_ENV: &str = "MAGPKG_TRUSTED_KEYS";
+pub const TRUSTED_KEYS_FILE: &str = "trusted-keys";
+const INDEX_HEADER: &str = "# magpkg torrent index";
+const INDEX_VERSION: &str = "1";
+
+/// Signed mapping from package hashes and fetch sha256s to magnet links for
+/// a whole release, plus the named packages it offers to `magpkg search`.
+/// Indexes are line oriented like lockfiles; the trailing `key` and
+/// `signature` records cover every byte that precedes the `signature` line:
+///
+/// ```text
+/// # magpkg torrent index
+/// version	1
+/// package	<hash>	<sha256>	<magnet>
+/// fetch	<sha256>	<magnet>
+/// entry	<hash>	<name>	<version>	<description>	<expression>
+/// key	<public key hex>
+/// signature	<signature hex>
+/// ```
+///
+/// An `entry` leaves version and description empty when the package has
+/// none; tabs, newlines, and backslashes in its fields are escaped.
+#[derive(Debug, Default)]
+pub struct TorrentIndex {
+    pub packages: BTreeMap<String, IndexedArtifact>,
+    pub fetches: BTreeMap<String, String>,
+    pub catalog: Vec<IndexedPackage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexedArtifact {
+    pub sha256: String,
+    pub magnet: String,
+}
+
+/// A named package an index offers.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexedPackage {
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    /// Jsonnet expression that evaluates to the package.
+    pub expression: String,
+    pub hash: String,
+}
+
+impl IndexedPackage {
+    /// Whether `query` occurs in the name or description, ignoring case.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.name.to_lowercase().contains(&query)
+            || self
+                .description
+                .as_ref()
+                .is_some_and(|description| description.to_lowercase().contains(&query))
+    }
+}
+
+impl TorrentIndex {
+    pub fn to_signed_bytes(&self, key: &Ed25519KeyPair) -> MagResult<Vec<u8>> {
+        let mut body = String::new();
+        writeln!(body, "{INDEX_HEADER}").unwrap();
+        writeln!(body, "version\t{INDEX_VERSION}").unwrap();
+        for (hash, artifact) in &self.packages {
+            writeln!(
+                body,
+                "package\t{hash}\t{}\t{}",
+                artifact.sha256, artifact.magnet
+            )
+            .unwrap();
+        }
+        for (sha, magnet) in &self.fetches {
+            writeln!(body, "fetch\t{sha}\t{magnet}").unwrap();
+        }
+        let mut catalog: Vec<&IndexedPackage> = self.catalog.iter().collect();
+        catalog.sort();
+        catalog.dedup();
+        for entry in catalog {
+            writeln!(
+                body,
+                "entry\t{}\t{}\t{}\t{}\t{}",
+                entry.hash,
+                escape(&entry.name),
+                escape(entry.version.as_deref().unwrap_or_default()),
+                escape(entry.description.as_deref().unwrap_or_default()),
+                escape(&entry.expression)
+            )
+            .unwrap();
+        }
+        writeln!(body, "key\t{}", public_key_hex(key)).unwrap();
+
+        let signature = key.sign(body.as_bytes());
+        writeln!(body, "signature\t{}", hex::encode(signature.as_ref())).unwrap();
+        Ok(body.into_bytes())
+    }
+
+    /// Parse an index and verify it was signed by one of `trusted_keys`.
+    pub fn parse_signed(bytes: &[u8], trusted_keys: &[Vec<u8>], origin: &str) -> MagResult<Self> {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|err| MagError::Generic(format!("index {origin} is not UTF-8: {err}")))?;
+
+        let sig_start = text
+            .rfind("\nsignature\t")
+            .map(|pos| pos + 1)
+            .ok_or_else(|| MagError::Generic(format!("index {origin} is not signed")))?;
+        let (signed, sig_line) = text.split_at(sig_start);
+        let signature = sig_line
+            .trim_end()
+            .strip_prefix("signature\t")
+            .and_then(|value| hex::decode(value).ok())
+            .ok_or_else(|| {
+                MagError::Generic(format!("index {origin} has a malformed signature"))
+            })?;
+
+        let mut index = TorrentIndex::default();
+        let mut key = None;
+        let mut version_seen = false;
+
+        for (line_no, line) in signed.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["version", version] if *version == INDEX_VERSION => version_seen = true,
+                ["package", hash, sha256, magnet] => {
+                    index.packages.insert(
+                        hash.to_string(),
+                        IndexedArtifact {
+                            sha256: sha256.to_string(),
+                            magnet: magnet.to_string(),
+                        },
+                    );
+                }
+                ["fetch", sha256, magnet] => {
+                    index.fetches.insert(sha256.to_string(), magnet.to_string());
+                }
+                ["entry", hash, name, version, description, expression] => {
+                    let optional = |value: &str| Some(unescape(value)).filter(|v| !v.is_empty());
+                    index.catalog.push(IndexedPackage {
+                        name: unescape(name),
+                        version: optional(version),
+                        description: optional(description),
+                        expression: unescape(expression),
+                        hash: hash.to_string(),
+                    });
+                }
+                ["key", public_key] => {
+                    key = Some(hex::decode(public_key).map_err(|err| {
+                        MagError::Generic(format!("index {origin} has a malformed key: {err}"))
+                    })?);
+                }
+                _ => {
+                    return Err(MagError::Generic(format!(
+                        "index {origin}:{}: malformed entry",
+                        line_no + 1
+                    )));
+                }
+            }
+        }
+
+        if !version_seen {
+            return Err(MagError::Generic(format!(
+                "index {origin} is missing a supported version header"
+            )));
+        }
+
+        let key = key.ok_or_else(|| MagError::Generic(format!("index {origin} names no key")))?;
+        if !trusted_keys.iter().any(|trusted| *trusted == key) {
+            return Err(MagError::Generic(format!(
+                "index {origin} is signed by untrusted key {}",
+                hex::encode(&key)
+            )));
+        }
+
+        UnparsedPublicKey::new(&ED25519, &key)
+            .verify(signed.as_bytes(), &signature)
+            .map_err(|_| {
+                MagError::Generic(format!("index {origin} signature verification failed"))
+            })?;
+
+        Ok(index)
+    }
+}
+
+/// Load an Ed25519 signing key stored as PKCS#8, optionally creating it.
+pub fn load_signing_key(path: &Path, generate: bool) -> MagResult<Ed25519KeyPair> {
+    if generate && !path.exists() {
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| MagError::Generic("failed to generate signing key".into()))?;
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(document.as_ref())?;
+        file.sync_all()?;
+        println!("Generated signing key at {}", path.display());
+    }
+
+    let bytes = fs::read(path).map_err(|err| {
+        MagError::Generic(format!(
+            "failed to read signing key {}: {err}",
+            path.display()
+        ))
+    })?;
+    Ed25519KeyPair::from_pkcs8(&bytes)
+        .map_err(|err| MagError::Generic(format!("invalid signing key {}: {err}", path.display())))
+}
+
+pub fn public_key_hex(key: &Ed25519KeyPair) -> String {
+    hex::encode(key.public_key().as_ref())
+}
+
+/// Public keys trusted to sign indexes, bundles, and binary cache records:
+/// those in `MAGPKG_TRUSTED_KEYS` plus those listed one per line in
+/// `<store>/trusted-keys`.
+pub fn trusted_keys(base_root: &Path) -> MagResult<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    if let Ok(value) = env::var(TRUSTED_KEYS_ENV) {
+        for key in split_list(&value) {
+            keys.push(hex::decode(key).map_err(|err| {
+                MagError::Generic(format!("invalid key {key} in {TRUSTED_KEYS_ENV}: {err}"))
+            })?);
+        }
+    }
+
+    let path = base_root.join(TRUSTED_KEYS_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(keys),
+        Err(err) => return Err(err.into()),
+    };
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = hex::decode(line).map_err(|err| {
+            MagError::Generic(format!(
+                "{}:{}: invalid key {line}: {err}",
+                path.display(),
+                index + 1
+            ))
+        })?;
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Load every index listed in `MAGPKG_INDEXES`, reading each location with
+/// `read`. Indexes that fail to load or verify are reported and skipped so
+/// that builds fall back to the manifest.
+pub fn load_configured_indexes(
+    base_root: &Path,
+    read: impl Fn(&str) -> MagResult<Vec<u8>>,
+) -> Vec<TorrentIndex> {
+    let Ok(value) = env::var(INDEXES_ENV) else {
+        return Vec::new();
+    };
+
+    let trusted = match trusted_keys(base_root) {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("warning: ignoring torrent indexes: {err}");
+            return Vec::new();
+        }
+    };
+    if trusted.is_empty() {
+        eprintln!(
+            "warning: ignoring {INDEXES_ENV}; list their signers in {TRUSTED_KEYS_ENV} or {TRUSTED_KEYS_FILE}"
+        );
+        return Vec::new();
+    }
+
+    let mut indexes = Vec::new();
+    for location in split_list(&value) {
+        let loaded =
+            read(location).and_then(|bytes| TorrentIndex::parse_signed(&bytes, &trusted, location));
+        match loaded {
+            Ok(index) => indexes.push(index),
+            Err(err) => eprintln!("warning: skipping torrent index {location}: {err}"),
+        }
+    }
+    indexes
+}
+
+pub fn magnet_for(info_hash: &str, name: &str) -> String {
+    magnet_with_sources(info_hash, name, &[], &[], &[])
+}
+
+/// Magnet link that also lists trackers (`tr`), webseeds (`ws`), and peer
+/// addresses (`x.pe`). Peers let clients without DHT, such as those on
+/// IPv6-only networks, connect to a known seeder directly.
+pub fn magnet_with_sources(
+    info_hash: &str,
+    name: &str,
+    trackers: &[String],
+    webseeds: &[String],
+    peers: &[SocketAddr],
+) -> String {
+    let mut url = Url::parse(&format!("magnet:?xt=urn:btih:{info_hash}")).expect("valid magnet");
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs.append_pair("dn", name);
+        for tracker in trackers {
+            pairs.append_pair("tr", tracker);
+        }
+        for webseed in webseeds {
+            pairs.append_pair("ws", webseed);
+        }
+        for peer in peers {
+            pairs.append_pair("x.pe", &peer.to_string());
+        }
+    }
+    url.into()
+}
+
+/// Read an index from an http(s) URL or a local path.
+pub fn read_index_bytes(client: &HttpClient, location: &str) -> MagResult<Vec<u8>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let response = client.get(location).send()?;
+        if !response.status().is_success() {
+            return Err(MagError::Generic(format!(
+                "HTTP {} fetching {location}",
+                response.status()
+            )));
+        }
+        return Ok(response.bytes()?.to_vec());
+    }
+    Ok(fs::read(location)?)
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split([',', ' ', '\n', '\t'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
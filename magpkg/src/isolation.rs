@@ -0,0 +1,199 @@
+use std::process::Command;
+
+use crate::{MagError, MagResult, capabilities::find_executable};
+
+/// Host namespaces a venv can leave with the manifest's `unshare` array.
+/// The mount and user namespaces are always private; the network namespace
+/// is shared so venvs keep host networking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Namespace {
+    /// Processes in the venv see only each other, and `/proc` lists only them.
+    Pid,
+    /// System V IPC objects and POSIX message queues.
+    Ipc,
+    /// The hostname, which programs in the venv can then change.
+    Uts,
+    /// The cgroup tree shown under `/proc/self/cgroup`.
+    Cgroup,
+}
+
+pub const ALL_NAMESPACES: [Namespace; 4] = [
+    Namespace::Pid,
+    Namespace::Ipc,
+    Namespace::Uts,
+    Namespace::Cgroup,
+];
+
+impl Namespace {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Namespace::Pid => "pid",
+            Namespace::Ipc => "ipc",
+            Namespace::Uts => "uts",
+            Namespace::Cgroup => "cgroup",
+        }
+    }
+
+    pub fn parse(value: &str) -> MagResult<Self> {
+        ALL_NAMESPACES
+            .into_iter()
+            .find(|namespace| namespace.as_str() == value)
+            .ok_or_else(|| {
+                MagError::Generic(format!(
+                    "unknown namespace {value:?} in 'unshare'; expected one of pid, ipc, uts, cgroup"
+                ))
+            })
+    }
+
+    fn bwrap_flag(self) -> &'static str {
+        match self {
+            Namespace::Pid => "--unshare-pid",
+            Namespace::Ipc => "--unshare-ipc",
+            Namespace::Uts => "--unshare-uts",
+            // Kernels without cgroup namespaces keep the host's.
+            Namespace::Cgroup => "--unshare-cgroup-try",
+        }
+    }
+}
+
+/// Resource limits for everything running in a venv, from the manifest's
+/// `limits` object. They are enforced by a transient systemd scope, so the
+/// venv's processes share one cgroup with these settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// Memory in bytes (`MemoryMax`); the venv's processes are reclaimed,
+    /// then OOM-killed, above it.
+    pub memory: Option<u64>,
+    /// Most processes and threads at once (`TasksMax`).
+    pub pids: Option<u64>,
+    /// CPU time as a number of CPUs, such as `1.5` (`CPUQuota`).
+    pub cpus: Option<f64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.pids.is_none() && self.cpus.is_none()
+    }
+
+    /// Take every limit `other` sets.
+    pub fn merge(&mut self, other: &ResourceLimits) {
+        if other.memory.is_some() {
+            self.memory = other.memory;
+        }
+        if other.pids.is_some() {
+            self.pids = other.pids;
+        }
+        if other.cpus.is_some() {
+            self.cpus = other.cpus;
+        }
+    }
+
+    fn systemd_properties(&self) -> Vec<String> {
+        let mut properties = Vec::new();
+        if let Some(memory) = self.memory {
+            properties.push(format!("MemoryMax={memory}"));
+            // Without this the kernel pushes the excess to swap instead of
+            // holding the venv to the limit.
+            properties.push("MemorySwapMax=0".to_string());
+        }
+        if let Some(pids) = self.pids {
+            properties.push(format!("TasksMax={pids}"));
+        }
+        if let Some(cpus) = self.cpus {
+            properties.push(format!("CPUQuota={}%", (cpus * 100.0).round() as u64));
+        }
+        properties
+    }
+}
+
+/// The uid and gid that processes in a venv run as, from the manifest's
+/// `uid`, `gid`, and `mapRoot` fields. The invoking user is mapped to them
+/// in a new user namespace, so `uid: 0` grants no privileges on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapping {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Login name that `/etc/passwd` and `/etc/group` give a mapped non-root id.
+const MAPPED_USER: &str = "user";
+const NOBODY_ID: u32 = 65534;
+
+impl IdMapping {
+    pub const ROOT: IdMapping = IdMapping { uid: 0, gid: 0 };
+
+    fn bwrap_args(&self) -> [String; 5] {
+        [
+            "--unshare-user".into(),
+            "--uid".into(),
+            self.uid.to_string(),
+            "--gid".into(),
+            self.gid.to_string(),
+        ]
+    }
+
+    /// `/etc/passwd` naming root, the mapped user, and nobody, so tools
+    /// that look up their own user name find one.
+    pub fn passwd(&self) -> String {
+        let mut passwd = String::from("root:x:0:0:root:/root:/bin/sh\n");
+        if self.uid != 0 && self.uid != NOBODY_ID {
+            passwd.push_str(&format!(
+                "{MAPPED_USER}:x:{}:{}::/home/{MAPPED_USER}:/bin/sh\n",
+                self.uid, self.gid
+            ));
+        }
+        passwd.push_str(&format!(
+            "nobody:x:{NOBODY_ID}:{NOBODY_ID}:nobody:/:/sbin/nologin\n"
+        ));
+        passwd
+    }
+
+    /// `/etc/group` to match [`IdMapping::passwd`].
+    pub fn group(&self) -> String {
+        let mut group = String::from("root:x:0:\n");
+        if self.gid != 0 && self.gid != NOBODY_ID {
+            group.push_str(&format!("{MAPPED_USER}:x:{}:\n", self.gid));
+        }
+        group.push_str(&format!("nogroup:x:{NOBODY_ID}:\n"));
+        group
+    }
+}
+
+/// The command that starts bwrap for a venv: bwrap itself, or bwrap inside
+/// a transient `systemd-run` scope when `limits` sets anything. Root gets a
+/// system scope; other users get one in their user manager, which needs the
+/// memory, pids, and cpu controllers delegated to it (the default on
+/// current systemd).
+pub fn venv_sandbox_command(
+    limits: &ResourceLimits,
+    unshare: &[Namespace],
+    id_mapping: Option<IdMapping>,
+) -> MagResult<Command> {
+    let mut cmd = if limits.is_empty() {
+        Command::new("bwrap")
+    } else {
+        let Some(systemd_run) = find_executable("systemd-run") else {
+            return Err(MagError::Generic(
+                "the venv sets 'limits', which are enforced with a systemd scope, but systemd-run is not on PATH".into(),
+            ));
+        };
+        let mut cmd = Command::new(systemd_run);
+        // SAFETY: geteuid has no preconditions.
+        if unsafe { libc::geteuid() } != 0 {
+            cmd.arg("--user");
+        }
+        cmd.args(["--scope", "--quiet", "--collect"]);
+        for property in limits.systemd_properties() {
+            cmd.arg("--property").arg(property);
+        }
+        cmd.arg("--").arg("bwrap");
+        cmd
+    };
+    for namespace in unshare {
+        cmd.arg(namespace.bwrap_flag());
+    }
+    if let Some(mapping) = id_mapping {
+        cmd.args(mapping.bwrap_args());
+    }
+    Ok(cmd)
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use fs2::FileExt;
+
+use crate::{
+    MagResult,
+    leases::{lease_host, unix_now},
+};
+
+pub const JOURNAL_DIR: &str = "journal";
+
+/// Intent records of one `magpkg` process under
+/// `<store>/journal/<host>@<pid>`.
+///
+/// Before a multi-step store operation starts, the process appends what it
+/// may leave behind if it dies halfway; once the operation has finished or
+/// cleaned up after itself, it appends `done`:
+///
+/// ```text
+/// begin  <id>  <operation>
+/// lock   <id>  <lock file guarding the operation>
+/// path   <id>  <path removed on rollback>     (repeatable)
+/// done   <id>
+/// ```
+///
+/// The owner keeps an exclusive lock on the file for its whole lifetime and
+/// removes it on exit, so a file whose lock can be taken was left by a
+/// process that crashed. [`recover`] rolls back its unfinished operations.
+/// Locks do not reach across machines sharing a store, so only the host
+/// named in a journal recovers it.
+pub struct Journal {
+    path: PathBuf,
+    state: Mutex<JournalState>,
+}
+
+struct JournalState {
+    file: File,
+    next_id: u64,
+}
+
+/// An operation recorded in the journal, marked done when dropped.
+pub struct JournalEntry<'a> {
+    journal: Option<&'a Journal>,
+    id: u64,
+}
+
+impl Drop for JournalEntry<'_> {
+    fn drop(&mut self) {
+        if let Some(journal) = self.journal {
+            journal.append(&format!("done\t{}\n", self.id));
+        }
+    }
+}
+
+impl Journal {
+    pub fn open(base_root: &Path) -> MagResult<Self> {
+        let dir = base_root.join(JOURNAL_DIR);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}@{}", lease_host(), std::process::id()));
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)?;
+        file.lock_exclusive()?;
+
+        // A journal under our name was left by a crashed process that had
+        // the same pid. Roll it back before starting over; operations that
+        // cannot be rolled back yet are kept aside for a later recovery.
+        let contents = fs::read_to_string(&path)?;
+        if !contents.is_empty() {
+            let mut stats = RecoveryStats::default();
+            if roll_back(&contents, &mut stats)? {
+                let aside = dir.join(format!(
+                    "{}@{}@{}",
+                    lease_host(),
+                    std::process::id(),
+                    unix_now()
+                ));
+                fs::write(&aside, &contents)?;
+            }
+            file.set_len(0)?;
+        }
+        Ok(Self {
+            path,
+            state: Mutex::new(JournalState { file, next_id: 0 }),
+        })
+    }
+
+    /// Record that `operation`, guarded by `lock`, is about to create
+    /// `paths`, which a rollback removes again.
+    pub fn begin(&self, operation: &str, lock: &Path, paths: &[PathBuf]) -> JournalEntry<'_> {
+        let id = {
+            let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+            state.next_id += 1;
+            state.next_id
+        };
+        let mut record = format!("begin\t{id}\t{operation}\nlock\t{id}\t{}\n", lock.display());
+        for path in paths {
+            record.push_str(&format!("path\t{id}\t{}\n", path.display()));
+        }
+        self.append(&record);
+        JournalEntry {
+            journal: Some(self),
+            id,
+        }
+    }
+
+    fn append(&self, record: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let result = state
+            .file
+            .write_all(record.as_bytes())
+            .and_then(|()| state.file.sync_data());
+        if let Err(err) = result {
+            eprintln!("warning: failed to write store journal: {err}");
+        }
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl JournalEntry<'_> {
+    /// An entry for a store without a journal; dropping it does nothing.
+    pub fn untracked() -> Self {
+        Self {
+            journal: None,
+            id: 0,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct RecoveryStats {
+    pub operations_rolled_back: usize,
+    pub paths_removed: usize,
+    /// Operations whose lock is held again; retried on a later recovery.
+    pub operations_deferred: usize,
+}
+
+#[derive(Default)]
+struct PendingOperation {
+    name: String,
+    lock: Option<PathBuf>,
+    paths: Vec<PathBuf>,
+}
+
+/// Roll back the unfinished operations of every journal this host left
+/// behind in a process that exited without finishing them. Journals are
+/// processed in name order and each operation's paths in reverse order of
+/// recording. An operation whose lock file is held again is left for a
+/// later pass, since another process may be redoing it with the same paths.
+pub fn recover(base_root: &Path) -> MagResult<RecoveryStats> {
+    let mut stats = RecoveryStats::default();
+    let dir = base_root.join(JOURNAL_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(stats),
+        Err(err) => return Err(err.into()),
+    };
+    let host = lease_host();
+    let own = format!("{host}@{}", std::process::id());
+    let mut journals: Vec<PathBuf> = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Journals written before they were keyed by host are named by
+        // pid alone.
+        let ours = match name.split_once('@') {
+            Some((journal_host, _)) => journal_host == host,
+            None => name.parse::<u32>().is_ok(),
+        };
+        if ours && name != own {
+            journals.push(entry.path());
+        }
+    }
+    journals.sort();
+
+    for path in journals {
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        if file.try_lock_exclusive().is_err() {
+            // The owner is still running.
+            continue;
+        }
+        let contents = fs::read_to_string(&path)?;
+        if !roll_back(&contents, &mut stats)? {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(stats)
+}
+
+/// Roll back the unfinished operations recorded in `contents`. Returns
+/// whether some were deferred because their lock is held again.
+fn roll_back(contents: &str, stats: &mut RecoveryStats) -> MagResult<bool> {
+    let mut pending: BTreeMap<u64, PendingOperation> = BTreeMap::new();
+    for line in contents.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(kind), Some(id)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(id) = id.parse::<u64>() else {
+            continue;
+        };
+        let value = fields.next().unwrap_or_default();
+        match kind {
+            "begin" => pending.entry(id).or_default().name = value.to_string(),
+            "lock" => pending.entry(id).or_default().lock = Some(PathBuf::from(value)),
+            "path" => pending
+                .entry(id)
+                .or_default()
+                .paths
+                .push(PathBuf::from(value)),
+            "done" => {
+                pending.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    let mut deferred = false;
+    for operation in pending.values() {
+        let guard = match &operation.lock {
+            Some(lock) => match File::open(lock) {
+                Ok(lock_file) => {
+                    if lock_file.try_lock_exclusive().is_err() {
+                        stats.operations_deferred += 1;
+                        deferred = true;
+                        continue;
+                    }
+                    Some(lock_file)
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            },
+            None => None,
+        };
+        eprintln!("rolling back interrupted {}", operation.name);
+        for target in operation.paths.iter().rev() {
+            let removed = match fs::symlink_metadata(target) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(target),
+                Ok(_) => fs::remove_file(target),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => Err(err),
+            };
+            match removed {
+                Ok(()) => stats.paths_removed += 1,
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        stats.operations_rolled_back += 1;
+        drop(guard);
+    }
+    Ok(deferred)
+}
@@ -0,0 +1,624 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{MagError, MagResult, bundle::local_hostname};
+
+pub const LEASES_DIR: &str = "leases";
+pub const LOCKS_CONFIG_FILE: &str = "locks.conf";
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(120);
+const LEASE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const LEASE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// `f_type` of NFS mounts in `statfs(2)`.
+const NFS_SUPER_MAGIC: u64 = 0x6969;
+
+/// When store locks are backed by leases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LeaseMode {
+    /// Only when the store is on NFS.
+    Auto,
+    On,
+    Off,
+}
+
+/// A lease as recorded under `<store>/leases/<label>`, with characters
+/// other than letters, digits, `-`, `_`, and `.` in the label replaced by
+/// `_`. Shared leases, which stand in for shared `flock` locks, are named
+/// `<label>.shared-<host>-<pid>-<n>` so that any number can coexist, and
+/// record `mode shared`:
+///
+/// ```text
+/// label     <lock label>
+/// host      <hostname>
+/// pid       <pid>
+/// acquired  <unix-seconds>
+/// renewed   <unix-seconds>
+/// mode      shared
+/// ```
+#[derive(Debug, Clone)]
+pub struct LeaseInfo {
+    pub label: String,
+    pub host: String,
+    pub pid: u32,
+    pub acquired: u64,
+    pub renewed: u64,
+    pub shared: bool,
+}
+
+impl LeaseInfo {
+    fn to_record(&self) -> String {
+        let mut record = format!(
+            "label\t{}\nhost\t{}\npid\t{}\nacquired\t{}\nrenewed\t{}\n",
+            self.label, self.host, self.pid, self.acquired, self.renewed
+        );
+        if self.shared {
+            record.push_str("mode\tshared\n");
+        }
+        record
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut info = LeaseInfo {
+            label: String::new(),
+            host: String::new(),
+            pid: 0,
+            acquired: 0,
+            renewed: 0,
+            shared: false,
+        };
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('\t') else {
+                continue;
+            };
+            match key {
+                "label" => info.label = value.to_string(),
+                "host" => info.host = value.to_string(),
+                "pid" => info.pid = value.parse().ok()?,
+                "acquired" => info.acquired = value.parse().ok()?,
+                "renewed" => info.renewed = value.parse().ok()?,
+                "mode" => info.shared = value == "shared",
+                _ => {}
+            }
+        }
+        (info.pid != 0 && !info.label.is_empty()).then_some(info)
+    }
+
+    fn is_ours(&self, host: &str) -> bool {
+        self.host == host && self.pid == std::process::id()
+    }
+
+    /// Why the lease may be taken over: it was not renewed within `ttl`, or
+    /// its holder ran on this host and has exited.
+    pub fn stale_reason(&self, ttl: Duration, local_host: &str) -> Option<String> {
+        let age = unix_now().saturating_sub(self.renewed);
+        if age > ttl.as_secs() {
+            return Some(format!("not renewed for {age}s"));
+        }
+        if self.host == local_host && !process_alive(self.pid) {
+            return Some(format!("pid {} has exited", self.pid));
+        }
+        None
+    }
+}
+
+/// Lease files that back the store's `flock` locks where those are not
+/// reliable, such as on NFS or after a machine holding them crashed.
+/// Configured by `<store>/locks.conf`:
+///
+/// ```text
+/// leases auto|on|off    (default auto: on when the store is on NFS)
+/// ttl    <seconds>      (default 120)
+/// ```
+///
+/// A lease is created exclusively next to the lock it guards and renewed
+/// by a background thread every quarter of the TTL. A lease that was not
+/// renewed within the TTL, or whose holder on this host has exited, is
+/// stale and taken over by the next process that wants the lock. Hosts
+/// sharing a store need clocks that agree to well within the TTL.
+///
+/// Shared leases exclude the exclusive lease for the same label and each
+/// other not at all: a shared holder backs off while the exclusive lease is
+/// live, and an exclusive holder waits until no live shared lease is left.
+pub struct LeaseManager {
+    dir: PathBuf,
+    ttl: Duration,
+    host: String,
+    /// Leases this process holds, by lease file name.
+    held: Arc<Mutex<HashMap<String, LeaseInfo>>>,
+    shared_seq: AtomicUsize,
+    stop: Arc<AtomicBool>,
+    renewer: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+/// A lease held until dropped.
+pub struct LeaseGuard<'a> {
+    manager: &'a LeaseManager,
+    name: String,
+}
+
+impl Drop for LeaseGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.release(&self.name);
+    }
+}
+
+impl LeaseManager {
+    /// The store's lease manager, or `None` when leases are off.
+    pub fn load(base_root: &Path) -> MagResult<Option<Self>> {
+        let (mode, ttl) = read_config(base_root)?;
+        let enabled = match mode {
+            LeaseMode::On => true,
+            LeaseMode::Off => false,
+            LeaseMode::Auto => is_nfs(base_root),
+        };
+        if !enabled {
+            return Ok(None);
+        }
+        let dir = base_root.join(LEASES_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Some(Self {
+            dir,
+            ttl,
+            host: lease_host(),
+            held: Arc::new(Mutex::new(HashMap::new())),
+            shared_seq: AtomicUsize::new(0),
+            stop: Arc::new(AtomicBool::new(false)),
+            renewer: Mutex::new(None),
+        }))
+    }
+
+    /// Take the lease for `label`, waiting while a live holder has it and
+    /// taking it over once it is stale, then waiting for live shared
+    /// holders to let go.
+    pub fn acquire(&self, label: &str) -> MagResult<LeaseGuard<'_>> {
+        let mut last_report: Option<Instant> = None;
+        let guard = loop {
+            if let Some(guard) = self.try_take(label)? {
+                break guard;
+            }
+            if let Some(holder) = read_lease(&self.dir.join(lease_file_name(label))) {
+                if last_report.is_none_or(|at| at.elapsed() >= LEASE_REPORT_INTERVAL) {
+                    eprintln!(
+                        "waiting for {label}: leased by pid {} on {}, renewed {}s ago",
+                        holder.pid,
+                        holder.host,
+                        unix_now().saturating_sub(holder.renewed)
+                    );
+                    last_report = Some(Instant::now());
+                }
+            }
+            thread::sleep(LEASE_POLL_INTERVAL);
+        };
+        while let Some(holder) = self.live_shared_holder(label)? {
+            if last_report.is_none_or(|at| at.elapsed() >= LEASE_REPORT_INTERVAL) {
+                eprintln!(
+                    "waiting for {label}: shared with pid {} on {}",
+                    holder.pid, holder.host
+                );
+                last_report = Some(Instant::now());
+            }
+            thread::sleep(LEASE_POLL_INTERVAL);
+        }
+        Ok(guard)
+    }
+
+    /// Take the lease for `label` if no live process holds it, exclusively
+    /// or shared. Reclaiming commands use this for the duration of a
+    /// removal, so that no other host can take the lock in between.
+    pub fn try_acquire(&self, label: &str) -> MagResult<Option<LeaseGuard<'_>>> {
+        for _ in 0..2 {
+            let Some(guard) = self.try_take(label)? else {
+                continue;
+            };
+            if self.live_shared_holder(label)?.is_some() {
+                return Ok(None);
+            }
+            return Ok(Some(guard));
+        }
+        Ok(None)
+    }
+
+    /// Take a shared lease for `label`, waiting while a live process holds
+    /// its exclusive lease.
+    pub fn acquire_shared(&self, label: &str) -> MagResult<LeaseGuard<'_>> {
+        let seq = self.shared_seq.fetch_add(1, Ordering::SeqCst);
+        let name = format!(
+            "{}.shared-{}-{}-{seq}",
+            lease_file_name(label),
+            lease_file_name(&self.host),
+            std::process::id()
+        );
+        let path = self.dir.join(&name);
+        let mut last_report: Option<Instant> = None;
+        loop {
+            let now = unix_now();
+            let info = LeaseInfo {
+                label: label.to_string(),
+                host: self.host.clone(),
+                pid: std::process::id(),
+                acquired: now,
+                renewed: now,
+                shared: true,
+            };
+            let mut file = File::create(&path)?;
+            file.write_all(info.to_record().as_bytes())?;
+            file.sync_all()?;
+            self.lock_held().insert(name.clone(), info);
+            self.start_renewer();
+            let guard = LeaseGuard {
+                manager: self,
+                name: name.clone(),
+            };
+
+            // The shared lease is visible before the exclusive one is
+            // checked, so an exclusive holder that checks for shared leases
+            // after taking its own sees this one, or this sees it.
+            let exclusive = read_lease(&self.dir.join(lease_file_name(label))).filter(|holder| {
+                !holder.is_ours(&self.host) && holder.stale_reason(self.ttl, &self.host).is_none()
+            });
+            let Some(holder) = exclusive else {
+                return Ok(guard);
+            };
+            drop(guard);
+            if last_report.is_none_or(|at| at.elapsed() >= LEASE_REPORT_INTERVAL) {
+                eprintln!(
+                    "waiting for {label}: leased by pid {} on {}, renewed {}s ago",
+                    holder.pid,
+                    holder.host,
+                    unix_now().saturating_sub(holder.renewed)
+                );
+                last_report = Some(Instant::now());
+            }
+            thread::sleep(LEASE_POLL_INTERVAL);
+        }
+    }
+
+    /// Create the exclusive lease for `label`, taking it over when it is
+    /// stale. `None` when a live process holds it.
+    fn try_take(&self, label: &str) -> MagResult<Option<LeaseGuard<'_>>> {
+        let name = lease_file_name(label);
+        let path = self.dir.join(&name);
+        let now = unix_now();
+        let info = LeaseInfo {
+            label: label.to_string(),
+            host: self.host.clone(),
+            pid: std::process::id(),
+            acquired: now,
+            renewed: now,
+            shared: false,
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(info.to_record().as_bytes())?;
+                file.sync_all()?;
+                self.lock_held().insert(name.clone(), info);
+                self.start_renewer();
+                return Ok(Some(LeaseGuard {
+                    manager: self,
+                    name,
+                }));
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        // A lease being written right now reads as empty; give it time,
+        // but not more than a TTL, after which its writer has crashed.
+        let Some(holder) = read_lease(&path) else {
+            let abandoned = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > self.ttl);
+            if abandoned {
+                let _ = fs::remove_file(&path);
+            }
+            return Ok(None);
+        };
+        if let Some(reason) = holder.stale_reason(self.ttl, &self.host) {
+            eprintln!(
+                "warning: taking over the lease for {label} from pid {} on {} ({reason})",
+                holder.pid, holder.host
+            );
+            steal_lease(&path, &holder)?;
+        }
+        Ok(None)
+    }
+
+    /// A live shared holder of `label` other than this process. Shared
+    /// leases left behind by dead holders are removed.
+    fn live_shared_holder(&self, label: &str) -> MagResult<Option<LeaseInfo>> {
+        let prefix = format!("{}.shared-", lease_file_name(label));
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                continue;
+            }
+            let Some(holder) = read_lease(&entry.path()) else {
+                continue;
+            };
+            if holder.is_ours(&self.host) {
+                continue;
+            }
+            if holder.stale_reason(self.ttl, &self.host).is_some() {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            return Ok(Some(holder));
+        }
+        Ok(None)
+    }
+
+    fn release(&self, name: &str) {
+        let Some(info) = self.lock_held().remove(name) else {
+            return;
+        };
+        let path = self.dir.join(name);
+        match read_lease(&path) {
+            Some(holder) if holder.is_ours(&self.host) => {
+                let _ = fs::remove_file(&path);
+            }
+            Some(holder) => eprintln!(
+                "warning: the lease for {} was taken over by pid {} on {} while held",
+                info.label, holder.pid, holder.host
+            ),
+            None => {}
+        }
+    }
+
+    fn lock_held(&self) -> std::sync::MutexGuard<'_, HashMap<String, LeaseInfo>> {
+        self.held.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn start_renewer(&self) {
+        let mut renewer = self.renewer.lock().unwrap_or_else(|err| err.into_inner());
+        if renewer.is_some() {
+            return;
+        }
+        let dir = self.dir.clone();
+        let host = self.host.clone();
+        let held = self.held.clone();
+        let stop = self.stop.clone();
+        let interval = self.ttl / 4;
+        let spawned = thread::Builder::new()
+            .name("lease-renewer".into())
+            .spawn(move || {
+                let mut last_renewal = Instant::now();
+                while !stop.load(Ordering::SeqCst) {
+                    thread::sleep(LEASE_POLL_INTERVAL);
+                    if last_renewal.elapsed() < interval {
+                        continue;
+                    }
+                    last_renewal = Instant::now();
+                    let mut held = held.lock().unwrap_or_else(|err| err.into_inner());
+                    for (name, info) in held.iter_mut() {
+                        renew_lease(&dir.join(name), &host, info);
+                    }
+                }
+            });
+        match spawned {
+            Ok(handle) => *renewer = Some(handle),
+            Err(err) => eprintln!("warning: failed to start lease renewal: {err}"),
+        }
+    }
+}
+
+impl Drop for LeaseManager {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let renewer = self
+            .renewer
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some(handle) = renewer.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Leases recorded in the store, whether or not this host uses them.
+pub fn list_leases(base_root: &Path) -> MagResult<Vec<LeaseInfo>> {
+    let entries = match fs::read_dir(base_root.join(LEASES_DIR)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut leases = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        // Leases being renewed or taken over are staged as dotfiles.
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if let Some(info) = read_lease(&entry.path()) {
+            leases.push(info);
+        }
+    }
+    leases.sort_by(|a, b| a.label.cmp(&b.label));
+    Ok(leases)
+}
+
+/// The lease TTL configured for the store, used to judge staleness when
+/// listing leases.
+pub fn lease_ttl(base_root: &Path) -> MagResult<Duration> {
+    Ok(read_config(base_root)?.1)
+}
+
+/// The name this host records in its leases.
+pub fn lease_host() -> String {
+    local_hostname().unwrap_or_else(|| "localhost".into())
+}
+
+/// Rewrite our lease at `path` with a fresh `renewed` time, unless another
+/// process took it over in the meantime.
+fn renew_lease(path: &Path, host: &str, info: &mut LeaseInfo) {
+    match read_lease(path) {
+        Some(current) if current.is_ours(host) => {}
+        _ => return,
+    }
+    info.renewed = unix_now();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{name}.renew-{}", std::process::id()));
+    let result = fs::write(&tmp, info.to_record()).and_then(|()| fs::rename(&tmp, path));
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp);
+        eprintln!(
+            "warning: failed to renew the lease for {}: {err}",
+            info.label
+        );
+    }
+}
+
+/// Move a stale lease aside. If another process replaced it with a fresh
+/// lease in the meantime, that lease is put back instead.
+fn steal_lease(path: &Path, stale: &LeaseInfo) -> MagResult<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let aside = path.with_file_name(format!(".{name}.stale-{}", std::process::id()));
+    match fs::rename(path, &aside) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+    let moved = read_lease(&aside);
+    if moved.is_some_and(|moved| moved.pid != stale.pid || moved.host != stale.host) {
+        match fs::hard_link(&aside, path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let _ = fs::remove_file(&aside);
+    Ok(())
+}
+
+fn read_lease(path: &Path) -> Option<LeaseInfo> {
+    LeaseInfo::parse(&fs::read_to_string(path).ok()?)
+}
+
+/// Lock labels such as `package zlib-1.3` as file names.
+fn lease_file_name(label: &str) -> String {
+    label.replace(
+        |c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')),
+        "_",
+    )
+}
+
+fn read_config(base_root: &Path) -> MagResult<(LeaseMode, Duration)> {
+    let mut mode = LeaseMode::Auto;
+    let mut ttl = DEFAULT_LEASE_TTL;
+    let path = base_root.join(LOCKS_CONFIG_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((mode, ttl)),
+        Err(err) => return Err(err.into()),
+    };
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || {
+            MagError::Generic(format!(
+                "{}:{}: invalid lock setting {line:?}",
+                path.display(),
+                index + 1
+            ))
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["leases", "auto"] => mode = LeaseMode::Auto,
+            ["leases", "on"] => mode = LeaseMode::On,
+            ["leases", "off"] => mode = LeaseMode::Off,
+            ["ttl", seconds] => {
+                let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+                if seconds < 4 {
+                    return Err(invalid());
+                }
+                ttl = Duration::from_secs(seconds);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok((mode, ttl))
+}
+
+fn is_nfs(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: statfs is plain data and all zeroes is a valid value.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is a
+    // writable statfs that outlives the call.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    stat.f_type as u64 == NFS_SUPER_MAGIC
+}
+
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(contents: &str) -> MagResult<(LeaseMode, Duration)> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(LOCKS_CONFIG_FILE), contents).unwrap();
+        read_config(dir.path())
+    }
+
+    #[test]
+    fn defaults_without_a_config() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            read_config(dir.path()).unwrap(),
+            (LeaseMode::Auto, DEFAULT_LEASE_TTL)
+        );
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!(
+            config("# shared store\nleases on\nttl 30\n").unwrap(),
+            (LeaseMode::On, Duration::from_secs(30))
+        );
+        assert_eq!(config("  leases   off  \n").unwrap().0, LeaseMode::Off);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for contents in [
+            "leases sometimes\n",
+            "ttl 3\n",
+            "ttl -1\n",
+            "ttl\n",
+            "leases on off\n",
+            "timeout 30\n",
+        ] {
+            assert!(config(contents).is_err(), "{contents:?} was accepted");
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{MagError, MagResult, package::FetchResource};
+
+/// Files under user-provided directories (`magpkg fetch --link-from`) that
+/// may already hold fetched sources, such as an old mirror dump or another
+/// machine's fetch cache.
+///
+/// Files are indexed by name only. A fetch looks at the files named like
+/// its `filename` or like its sha256, and only those candidates are hashed,
+/// so a large tree is walked once but never read in full.
+#[derive(Default)]
+pub struct LinkSources {
+    by_name: HashMap<OsString, Vec<PathBuf>>,
+    files: usize,
+}
+
+impl LinkSources {
+    /// Walk `dirs` and index every regular file. Symlinks, devices, and
+    /// other special files are skipped, and symlinked directories are not
+    /// followed.
+    pub fn scan(dirs: &[PathBuf]) -> MagResult<Self> {
+        let mut sources = Self::default();
+        for dir in dirs {
+            if !dir.is_dir() {
+                return Err(MagError::Generic(format!(
+                    "--link-from {} is not a directory",
+                    dir.display()
+                )));
+            }
+            let mut pending = vec![dir.clone()];
+            while let Some(current) = pending.pop() {
+                let entries = match fs::read_dir(&current) {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                        eprintln!("warning: skipping unreadable {}", current.display());
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                for entry in entries {
+                    let entry = entry?;
+                    let path = entry.path();
+                    let file_type = entry.file_type()?;
+                    if file_type.is_dir() {
+                        pending.push(path);
+                        continue;
+                    }
+                    if file_type.is_file() {
+                        sources
+                            .by_name
+                            .entry(entry.file_name())
+                            .or_default()
+                            .push(path);
+                        sources.files += 1;
+                    }
+                }
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Number of files indexed.
+    pub fn file_count(&self) -> usize {
+        self.files
+    }
+
+    /// Files that might hold `fetch`, named like its file name first and
+    /// then like its sha256.
+    pub fn candidates(&self, fetch: &FetchResource) -> Vec<&Path> {
+        let sha256 = fetch.sha256.trim().to_ascii_lowercase();
+        [fetch.filename.as_str(), sha256.as_str()]
+            .into_iter()
+            .filter_map(|name| self.by_name.get(&OsString::from(name)))
+            .flatten()
+            .map(PathBuf::as_path)
+            .collect()
+    }
+}
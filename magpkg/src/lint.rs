@@ -0,0 +1,458 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    os::unix::fs::FileExt,
+    path::{Component, Path, PathBuf},
+};
+
+use tar::EntryType;
+
+use crate::{MagError, MagResult, codec::open_artifact};
+
+/// Runtime mount points that never come from the package closure.
+const RUNTIME_MOUNTS: &[&str] = &["dev", "proc", "sys", "tmp", "run"];
+const LIBRARY_DIRS: &[&str] = &["lib", "lib64", "usr/lib", "usr/lib64", "usr/local/lib"];
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Files, directories, and symlinks visible in a merged root filesystem, keyed
+/// by path relative to `/`.
+#[derive(Default)]
+struct RootView {
+    paths: HashSet<PathBuf>,
+    links: HashMap<PathBuf, PathBuf>,
+}
+
+impl RootView {
+    fn insert(&mut self, path: PathBuf, link_target: Option<PathBuf>) {
+        match link_target {
+            Some(target) => {
+                self.links.insert(path.clone(), target);
+            }
+            None => {
+                self.links.remove(&path);
+            }
+        }
+        self.paths.insert(path);
+    }
+
+    /// Resolve `path` through every symlink in the view, including symlinked
+    /// parent directories. Returns `None` for loops.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let mut pending: Vec<PathBuf> = Vec::new();
+        push_components(&mut pending, path);
+        pending.reverse();
+
+        let mut current = PathBuf::new();
+        let mut hops = 0;
+        while let Some(component) = pending.pop() {
+            if component.as_os_str() == ".." {
+                current.pop();
+                continue;
+            }
+            let candidate = current.join(&component);
+            match self.links.get(&candidate) {
+                Some(target) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return None;
+                    }
+                    if target.is_absolute() {
+                        current = PathBuf::new();
+                    }
+                    let mut expanded = Vec::new();
+                    push_components(&mut expanded, target);
+                    pending.extend(expanded.into_iter().rev());
+                }
+                None => current = candidate,
+            }
+        }
+        Some(current)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.resolve(path).is_some_and(|resolved| {
+            resolved.as_os_str().is_empty() || self.paths.contains(&resolved)
+        })
+    }
+}
+
+fn push_components(out: &mut Vec<PathBuf>, path: &Path) {
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => out.push(PathBuf::from(part)),
+            Component::ParentDir => out.push(PathBuf::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+/// Problems found in a build output, one message each.
+#[derive(Debug, Default)]
+pub struct LintReport {
+    /// Symlinks whose targets do not exist.
+    pub symlinks: Vec<String>,
+    /// ELF libraries and interpreters that the runtime closure lacks.
+    pub runtime_deps: Vec<String>,
+}
+
+impl LintReport {
+    pub fn is_empty(&self) -> bool {
+        self.symlinks.is_empty() && self.runtime_deps.is_empty()
+    }
+}
+
+/// Check a finished output tree for symlinks that point at nothing in the
+/// package plus its runtime closure, and for ELF files whose `DT_NEEDED`
+/// libraries or `PT_INTERP` interpreter cannot be found there.
+pub fn lint_output(out_dir: &Path, closure_artifacts: &[PathBuf]) -> MagResult<LintReport> {
+    let mut own = RootView::default();
+    let mut elf_files = Vec::new();
+    scan_output(out_dir, Path::new(""), &mut own, &mut elf_files)?;
+
+    let mut report = LintReport::default();
+    if own.links.is_empty() && elf_files.is_empty() {
+        return Ok(report);
+    }
+
+    let mut view = RootView::default();
+    for artifact in closure_artifacts {
+        read_artifact_entries(artifact, &mut view)?;
+    }
+    for path in own.paths {
+        let target = own.links.get(&path).cloned();
+        view.insert(path, target);
+    }
+
+    let mut links: Vec<(&PathBuf, &PathBuf)> = own.links.iter().collect();
+    links.sort();
+    for (link, target) in links {
+        let Some(resolved) = view.resolve(link) else {
+            report.symlinks.push(format!(
+                "/{} -> {}: symlink loop",
+                link.display(),
+                target.display()
+            ));
+            continue;
+        };
+        if is_runtime_mount(&resolved) {
+            continue;
+        }
+        if !resolved.as_os_str().is_empty() && !view.paths.contains(&resolved) {
+            report.symlinks.push(format!(
+                "/{} -> {}: target is not provided by the package or its runtime dependencies",
+                link.display(),
+                target.display()
+            ));
+        }
+    }
+
+    elf_files.sort();
+    for rel_path in elf_files {
+        let Some(dynamic) = read_elf_dynamic(&out_dir.join(&rel_path))? else {
+            continue;
+        };
+        let origin = rel_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if let Some(interpreter) = &dynamic.interpreter {
+            if !view.exists(Path::new(interpreter)) {
+                report.runtime_deps.push(format!(
+                    "/{}: interpreter {interpreter} is not provided by the package or its runtime dependencies",
+                    rel_path.display()
+                ));
+            }
+        }
+
+        let mut search_dirs: Vec<PathBuf> = dynamic
+            .runpaths
+            .iter()
+            .flat_map(|entry| entry.split(':'))
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| {
+                let dir = dir.replace("${ORIGIN}", "$ORIGIN");
+                match dir.strip_prefix("$ORIGIN") {
+                    Some(rest) => origin.join(rest.trim_start_matches('/')),
+                    None => PathBuf::from(dir),
+                }
+            })
+            .collect();
+        search_dirs.extend(default_library_dirs());
+
+        for needed in &dynamic.needed {
+            let found = if needed.contains('/') {
+                view.exists(&origin.join(needed))
+            } else {
+                search_dirs.iter().any(|dir| view.exists(&dir.join(needed)))
+            };
+            if !found {
+                report.runtime_deps.push(format!(
+                    "/{}: needs {needed}, which no runtime dependency provides",
+                    rel_path.display()
+                ));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn default_library_dirs() -> Vec<PathBuf> {
+    let arch = env::consts::ARCH;
+    let mut dirs: Vec<PathBuf> = LIBRARY_DIRS.iter().map(PathBuf::from).collect();
+    dirs.push(PathBuf::from(format!("lib/{arch}-linux-gnu")));
+    dirs.push(PathBuf::from(format!("usr/lib/{arch}-linux-gnu")));
+    dirs
+}
+
+fn is_runtime_mount(path: &Path) -> bool {
+    path.components().next().is_some_and(|first| {
+        RUNTIME_MOUNTS
+            .iter()
+            .any(|mount| first.as_os_str() == *mount)
+    })
+}
+
+fn scan_output(
+    root: &Path,
+    rel: &Path,
+    view: &mut RootView,
+    elf_files: &mut Vec<PathBuf>,
+) -> MagResult<()> {
+    let dir = root.join(rel);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let rel_path = rel.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            view.insert(rel_path, Some(target));
+        } else if file_type.is_dir() {
+            view.insert(rel_path.clone(), None);
+            scan_output(root, &rel_path, view, elf_files)?;
+        } else {
+            if file_type.is_file() && has_elf_magic(&entry.path())? {
+                elf_files.push(rel_path.clone());
+            }
+            view.insert(rel_path, None);
+        }
+    }
+    Ok(())
+}
+
+fn read_artifact_entries(artifact: &Path, view: &mut RootView) -> MagResult<()> {
+    let mut archive = tar::Archive::new(open_artifact(artifact)?);
+    let entries = archive.entries().map_err(|err| {
+        MagError::Generic(format!(
+            "failed to read archive entries from {}: {err}",
+            artifact.display()
+        ))
+    })?;
+
+    for entry in entries {
+        let entry = entry.map_err(|err| {
+            MagError::Generic(format!(
+                "failed to process entry from {}: {err}",
+                artifact.display()
+            ))
+        })?;
+        let path: PathBuf = entry
+            .path()?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let target = if entry.header().entry_type() == EntryType::Symlink {
+            entry.link_name()?.map(|target| target.into_owned())
+        } else {
+            None
+        };
+        view.insert(path, target);
+    }
+    Ok(())
+}
+
+fn has_elf_magic(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == *b"\x7fELF"),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+struct ElfDynamic {
+    needed: Vec<String>,
+    runpaths: Vec<String>,
+    interpreter: Option<String>,
+}
+
+struct ElfReader {
+    file: File,
+    is_64: bool,
+    big_endian: bool,
+}
+
+impl ElfReader {
+    fn bytes(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    fn uint(&self, buf: &[u8], offset: usize, size: usize) -> u64 {
+        let bytes = &buf[offset..offset + size];
+        let fold = |value: u64, byte: &u8| (value << 8) | u64::from(*byte);
+        if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        }
+    }
+
+    fn word(&self, buf: &[u8], offset: usize) -> u64 {
+        self.uint(buf, offset, if self.is_64 { 8 } else { 4 })
+    }
+}
+
+/// Read `DT_NEEDED`, `DT_RPATH`/`DT_RUNPATH`, and the `PT_INTERP` program
+/// interpreter from a dynamically linked ELF file. Static binaries, relocatable objects, and truncated files
+/// yield `None`.
+fn read_elf_dynamic(path: &Path) -> MagResult<Option<ElfDynamic>> {
+    const PT_LOAD: u64 = 1;
+    const PT_DYNAMIC: u64 = 2;
+    const PT_INTERP: u64 = 3;
+    const DT_NEEDED: u64 = 1;
+    const DT_STRTAB: u64 = 5;
+    const DT_STRSZ: u64 = 10;
+    const DT_RPATH: u64 = 15;
+    const DT_RUNPATH: u64 = 29;
+
+    let file = File::open(path)?;
+    let mut ident = [0u8; 64];
+    if file.read_exact_at(&mut ident, 0).is_err() {
+        return Ok(None);
+    }
+    let reader = ElfReader {
+        file,
+        is_64: ident[4] == 2,
+        big_endian: ident[5] == 2,
+    };
+
+    let e_type = reader.uint(&ident, 16, 2);
+    if e_type != 2 && e_type != 3 {
+        return Ok(None);
+    }
+    let (ph_off, ph_entsize, ph_num) = if reader.is_64 {
+        (
+            reader.uint(&ident, 32, 8),
+            reader.uint(&ident, 54, 2),
+            reader.uint(&ident, 56, 2),
+        )
+    } else {
+        (
+            reader.uint(&ident, 28, 4),
+            reader.uint(&ident, 42, 2),
+            reader.uint(&ident, 44, 2),
+        )
+    };
+
+    // (type, offset, vaddr, filesz) for each program header.
+    let mut segments = Vec::new();
+    for index in 0..ph_num {
+        let Ok(header) = reader.bytes(ph_off + index * ph_entsize, ph_entsize as usize) else {
+            return Ok(None);
+        };
+        let segment = if reader.is_64 {
+            (
+                reader.uint(&header, 0, 4),
+                reader.uint(&header, 8, 8),
+                reader.uint(&header, 16, 8),
+                reader.uint(&header, 32, 8),
+            )
+        } else {
+            (
+                reader.uint(&header, 0, 4),
+                reader.uint(&header, 4, 4),
+                reader.uint(&header, 8, 4),
+                reader.uint(&header, 16, 4),
+            )
+        };
+        segments.push(segment);
+    }
+
+    let Some(&(_, dyn_offset, _, dyn_size)) = segments.iter().find(|seg| seg.0 == PT_DYNAMIC)
+    else {
+        return Ok(None);
+    };
+    let Ok(dynamic) = reader.bytes(dyn_offset, dyn_size.min(1 << 20) as usize) else {
+        return Ok(None);
+    };
+
+    let interpreter = segments
+        .iter()
+        .find(|seg| seg.0 == PT_INTERP)
+        .and_then(|&(_, offset, _, size)| reader.bytes(offset, size.min(4096) as usize).ok())
+        .map(|bytes| {
+            let end = bytes
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        })
+        .filter(|path| !path.is_empty());
+
+    let entry_size = if reader.is_64 { 16 } else { 8 };
+    let word_size = entry_size / 2;
+    let mut needed_offsets = Vec::new();
+    let mut runpath_offsets = Vec::new();
+    let mut strtab = None;
+    let mut strsz = 0;
+    for chunk_start in (0..dynamic.len().saturating_sub(entry_size - 1)).step_by(entry_size) {
+        let tag = reader.word(&dynamic, chunk_start);
+        let value = reader.word(&dynamic, chunk_start + word_size);
+        match tag {
+            0 => break,
+            DT_NEEDED => needed_offsets.push(value),
+            DT_RPATH | DT_RUNPATH => runpath_offsets.push(value),
+            DT_STRTAB => strtab = Some(value),
+            DT_STRSZ => strsz = value,
+            _ => {}
+        }
+    }
+
+    let Some(strtab_vaddr) = strtab else {
+        return Ok(None);
+    };
+    let Some(strtab_offset) = segments
+        .iter()
+        .filter(|seg| seg.0 == PT_LOAD)
+        .find(|seg| seg.2 <= strtab_vaddr && strtab_vaddr < seg.2 + seg.3)
+        .map(|seg| seg.1 + (strtab_vaddr - seg.2))
+    else {
+        return Ok(None);
+    };
+    let Ok(strings) = reader.bytes(strtab_offset, strsz.min(1 << 20) as usize) else {
+        return Ok(None);
+    };
+
+    let lookup = |offset: u64| -> Option<String> {
+        let tail = strings.get(offset as usize..)?;
+        let end = tail.iter().position(|byte| *byte == 0)?;
+        Some(String::from_utf8_lossy(&tail[..end]).into_owned())
+    };
+
+    Ok(Some(ElfDynamic {
+        needed: needed_offsets.into_iter().filter_map(lookup).collect(),
+        runpaths: runpath_offsets.into_iter().filter_map(lookup).collect(),
+        interpreter,
+    }))
+}
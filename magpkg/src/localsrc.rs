@@ -0,0 +1,166 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+};
+
+use sha2::{Digest, Sha256};
+use tar::Builder;
+
+use crate::{
+    MagError, MagResult, channels::CHANNELS_DIR, pack::append_tree_filtered, store::store_base_root,
+};
+
+/// Prefix of imports that evaluate to the absolute path of a directory,
+/// resolved against the directory of the importing file, for use as a
+/// local source: `src: import "local:./myapp"`.
+pub const LOCAL_IMPORT_PREFIX: &str = "local:";
+
+static SOURCE_ROOTS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// Only allow local sources beneath `roots`, the `--import-root`
+/// directories, or in a channel checkout. Without roots, any directory may
+/// be used.
+pub fn set_source_roots(roots: Vec<PathBuf>) {
+    let _ = SOURCE_ROOTS.set(roots);
+}
+
+/// A local directory used as a fetch source. It is archived as a
+/// deterministic, uncompressed tar whose sha256 stands in for the fetch's
+/// hash, so editing any file that is not ignored changes the package id.
+#[derive(Debug, Clone)]
+pub struct LocalSource {
+    pub path: PathBuf,
+    /// Patterns for entries left out of the archive; see [`is_ignored`].
+    pub ignore: Vec<String>,
+}
+
+impl LocalSource {
+    /// Check that `path`, which must be absolute, is a directory within the
+    /// allowed roots. Manifests get absolute paths for directories next to
+    /// them from [`LOCAL_IMPORT_PREFIX`] imports.
+    pub fn new(path: &str, ignore: Vec<String>) -> MagResult<Self> {
+        if !Path::new(path).is_absolute() {
+            return Err(MagError::Generic(format!(
+                "source directory {path} is relative; use `import \"{LOCAL_IMPORT_PREFIX}{path}\"` to resolve it against the manifest's directory"
+            )));
+        }
+        let path = fs::canonicalize(path)
+            .map_err(|err| MagError::Generic(format!("source directory {path}: {err}")))?;
+        if !path.is_dir() {
+            return Err(MagError::Generic(format!(
+                "source {} is not a directory",
+                path.display()
+            )));
+        }
+        check_source_roots(&path)?;
+        Ok(Self { path, ignore })
+    }
+
+    /// The sha256 of the directory's archive, computed without writing it.
+    pub fn sha256(&self) -> MagResult<String> {
+        self.archive_to(io::sink())
+    }
+
+    /// Write the directory's archive to `dest` and return its sha256.
+    pub fn write_archive(&self, dest: &Path) -> MagResult<String> {
+        let file = File::create(dest)?;
+        let sha256 = self.archive_to(&file)?;
+        file.sync_all()?;
+        Ok(sha256)
+    }
+
+    fn archive_to<W: Write>(&self, inner: W) -> MagResult<String> {
+        let mut builder = Builder::new(HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        });
+        append_tree_filtered(&mut builder, &self.path, &|relative| {
+            !is_ignored(&self.ignore, relative)
+        })?;
+        let writer = builder.into_inner()?;
+        Ok(format!("{:x}", writer.hasher.finalize()))
+    }
+}
+
+fn check_source_roots(path: &Path) -> MagResult<()> {
+    let Some(roots) = SOURCE_ROOTS.get().filter(|roots| !roots.is_empty()) else {
+        return Ok(());
+    };
+    let channels = store_base_root().ok().map(|base| base.join(CHANNELS_DIR));
+    let allowed = roots
+        .iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .chain(channels)
+        .any(|root| path.starts_with(root));
+    if !allowed {
+        return Err(MagError::Generic(format!(
+            "source {} is outside the allowed import roots",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether `relative` matches one of `patterns`. A pattern without `/`
+/// matches any entry with that name at any depth (`.git`, `*.o`); one with
+/// `/` matches the path from the source root (`build/out`). `*` matches any
+/// run of characters within a name. An ignored directory is skipped with
+/// everything beneath it.
+pub fn is_ignored(patterns: &[String], relative: &Path) -> bool {
+    let components: Vec<&str> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect();
+    let Some(name) = components.last() else {
+        return false;
+    };
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.trim_matches('/');
+        if pattern.contains('/') {
+            let parts: Vec<&str> = pattern.split('/').collect();
+            parts.len() == components.len()
+                && parts
+                    .iter()
+                    .zip(&components)
+                    .all(|(part, component)| glob_match(part, component))
+        } else {
+            glob_match(pattern, name)
+        }
+    })
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(remainder) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=remainder.len())
+                .filter(|index| remainder.is_char_boundary(*index))
+                .any(|index| glob_match(rest, &remainder[index..]))
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    MagError, MagResult,
+    package::{Package, collect_closure, package_base_name},
+};
+
+pub const DEFAULT_LOCKFILE: &str = "magpkg.lock";
+const LOCKFILE_HEADER: &str = "# magpkg lockfile";
+const LOCKFILE_VERSION: &str = "1";
+
+/// Snapshot of everything that feeds into an evaluated package graph.
+///
+/// The on-disk format is line oriented with tab-separated fields so that it
+/// diffs cleanly in version control:
+///
+/// ```text
+/// version  1
+/// import   <url>       <sha256>
+/// fetch    <sha256>    <filename>  <url>...
+/// package  <hash>      <base-name>
+/// ```
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    pub imports: BTreeMap<String, String>,
+    pub fetches: BTreeMap<String, LockedFetch>,
+    pub packages: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedFetch {
+    pub filename: String,
+    pub urls: Vec<String>,
+}
+
+impl Lockfile {
+    pub fn from_graph(roots: &[Rc<Package>], imports: &BTreeMap<String, String>) -> Self {
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for pkg in roots {
+            collect_closure(pkg.clone(), &mut visited, &mut order);
+        }
+
+        let mut lock = Lockfile {
+            imports: imports.clone(),
+            ..Default::default()
+        };
+
+        for package in order {
+            lock.packages
+                .insert(package.hash.clone(), package_base_name(&package));
+            for fetch in &package.fetch {
+                lock.fetches.insert(
+                    fetch.sha256.trim().to_ascii_lowercase(),
+                    LockedFetch {
+                        filename: fetch.filename.clone(),
+                        urls: fetch.urls.clone(),
+                    },
+                );
+            }
+        }
+
+        lock
+    }
+
+    pub fn read(path: &Path) -> MagResult<Self> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            MagError::Generic(format!("failed to read lockfile {}: {err}", path.display()))
+        })?;
+
+        let mut lock = Lockfile::default();
+        let mut version_seen = false;
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_no = index + 1;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            let malformed = || {
+                MagError::Generic(format!(
+                    "{}:{line_no}: malformed lockfile entry",
+                    path.display()
+                ))
+            };
+
+            match fields[0] {
+                "version" => {
+                    if fields.get(1) != Some(&LOCKFILE_VERSION) {
+                        return Err(MagError::Generic(format!(
+                            "{}:{line_no}: unsupported lockfile version",
+                            path.display()
+                        )));
+                    }
+                    version_seen = true;
+                }
+                "import" if fields.len() == 3 => {
+                    lock.imports
+                        .insert(fields[1].to_string(), fields[2].to_string());
+                }
+                "fetch" if fields.len() >= 3 => {
+                    lock.fetches.insert(
+                        fields[1].to_string(),
+                        LockedFetch {
+                            filename: fields[2].to_string(),
+                            urls: fields[3..].iter().map(|url| url.to_string()).collect(),
+                        },
+                    );
+                }
+                "package" if fields.len() == 3 => {
+                    lock.packages
+                        .insert(fields[1].to_string(), fields[2].to_string());
+                }
+                _ => return Err(malformed()),
+            }
+        }
+
+        if !version_seen {
+            return Err(MagError::Generic(format!(
+                "{}: missing lockfile version header",
+                path.display()
+            )));
+        }
+
+        Ok(lock)
+    }
+
+    pub fn write(&self, path: &Path) -> MagResult<()> {
+        for value in self
+            .imports
+            .iter()
+            .flat_map(|(url, sha)| [url, sha])
+            .chain(self.fetches.values().map(|fetch| &fetch.filename))
+            .chain(self.fetches.values().flat_map(|fetch| fetch.urls.iter()))
+        {
+            if value.contains(['\t', '\n', '\r']) {
+                return Err(MagError::Generic(format!(
+                    "cannot record {value:?} in lockfile: contains tab or newline"
+                )));
+            }
+        }
+
+        let tmp_path = path.with_extension("lock.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(writer, "{LOCKFILE_HEADER}")?;
+            writeln!(writer, "version\t{LOCKFILE_VERSION}")?;
+            for (url, sha) in &self.imports {
+                writeln!(writer, "import\t{url}\t{sha}")?;
+            }
+            for (sha, fetch) in &self.fetches {
+                write!(writer, "fetch\t{sha}\t{}", fetch.filename)?;
+                for url in &fetch.urls {
+                    write!(writer, "\t{url}")?;
+                }
+                writeln!(writer)?;
+            }
+            for (hash, base) in &self.packages {
+                writeln!(writer, "package\t{hash}\t{base}")?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Describe every way `actual` deviates from this (expected) lockfile.
+    pub fn deviations(&self, actual: &Lockfile) -> Vec<String> {
+        let mut out = Vec::new();
+
+        diff_maps(&self.imports, &actual.imports, "import", &mut out, |sha| {
+            sha.clone()
+        });
+        diff_maps(&self.fetches, &actual.fetches, "fetch", &mut out, |fetch| {
+            format!("{} [{}]", fetch.filename, fetch.urls.join(", "))
+        });
+        diff_maps(
+            &self.packages,
+            &actual.packages,
+            "package",
+            &mut out,
+            |base| base.clone(),
+        );
+
+        out
+    }
+}
+
+fn diff_maps<V: PartialEq>(
+    expected: &BTreeMap<String, V>,
+    actual: &BTreeMap<String, V>,
+    kind: &str,
+    out: &mut Vec<String>,
+    describe: impl Fn(&V) -> String,
+) {
+    for (key, value) in actual {
+        match expected.get(key) {
+            None => out.push(format!("{kind} {key} is not in the lockfile")),
+            Some(locked) if locked != value => out.push(format!(
+                "{kind} {key} changed: locked {}, evaluated {}",
+                describe(locked),
+                describe(value)
+            )),
+            Some(_) => {}
+        }
+    }
+    for key in expected.keys() {
+        if !actual.contains_key(key) {
+            out.push(format!("{kind} {key} is locked but no longer evaluated"));
+        }
+    }
+}
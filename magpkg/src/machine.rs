@@ -0,0 +1,182 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use crate::{MagError, MagResult, MountKind, VenvSpec};
+
+/// Directory machinectl and `systemd-nspawn --machine` read trusted settings
+/// files from. Settings next to an image in `/var/lib/machines` are
+/// untrusted, so nspawn ignores their bind mounts.
+pub const NSPAWN_SETTINGS_DIR: &str = "/etc/systemd/nspawn";
+
+/// Check that `name` is usable as a machine name: a valid hostname of at
+/// most 64 characters, as machinectl requires.
+pub fn validate_machine_name(name: &str) -> MagResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with(['-', '.'])
+        && !name.ends_with(['-', '.'])
+        && !name.contains("..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(MagError::Generic(format!(
+            "invalid machine name {name:?}; use up to 64 letters, digits, '-', '_', or '.'"
+        )))
+    }
+}
+
+/// Render a `.nspawn` settings file for a venv spec, and the warnings for
+/// the parts nspawn has no equivalent for:
+///
+/// ```text
+/// [Exec]
+/// Environment=PATH=/usr/bin:/bin:/usr/sbin:/sbin
+/// Environment=LD_LIBRARY_PATH=/usr/lib64:/usr/lib:/lib
+///
+/// [Files]
+/// BindReadOnly=/srv/data:/data
+/// TemporaryFileSystem=/scratch
+/// ```
+///
+/// The default venv mounts are left out, since nspawn sets up `/dev`,
+/// `/proc`, `/sys`, `/tmp`, and `/etc/resolv.conf` itself. Optional binds
+/// whose source is missing when exporting are skipped.
+pub fn nspawn_settings(spec: &VenvSpec, expression: &str) -> (String, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# Generated by magpkg export-machine from {expression}"
+    );
+    let _ = writeln!(out, "# Rootfs hash {}", spec.rootfs_hash);
+
+    out.push_str("\n[Exec]\n");
+    let mut variables = spec.env_set.clone();
+    variables
+        .entry("PATH".to_string())
+        .or_insert_with(|| "/usr/bin:/bin:/usr/sbin:/sbin".to_string());
+    variables
+        .entry("LD_LIBRARY_PATH".to_string())
+        .or_insert_with(|| "/usr/lib64:/usr/lib:/lib".to_string());
+    for (key, value) in &variables {
+        let _ = writeln!(out, "Environment={}", quote_assignment(key, value));
+    }
+    if !spec.env_keep.is_empty() {
+        warnings.push(format!(
+            "envKeep ({}) copies variables from the caller at launch; the machine does not get them",
+            spec.env_keep.join(", ")
+        ));
+    }
+    if !spec.passthrough.is_empty() {
+        warnings.push(
+            "passthrough entries are not exported; add the sockets and devices to the settings file by hand".into(),
+        );
+    }
+    if !spec.limits.is_empty() || !spec.unshare.is_empty() {
+        warnings.push(
+            "limits and unshare are not exported; nspawn machines get their own namespaces, and limits go in the machine's systemd unit".into(),
+        );
+    }
+    if spec.id_mapping.is_some() {
+        warnings.push(
+            "uid and gid are not exported; set User= in the settings file to run as another user"
+                .into(),
+        );
+    }
+
+    let mut files = String::new();
+    for mount in &spec.mounts {
+        let target = escape_path(&mount.target);
+        let source = mount.source.as_deref();
+        let setting = match mount.kind {
+            MountKind::Bind | MountKind::RoBind | MountKind::DevBind => {
+                let Some(source) = source else {
+                    continue;
+                };
+                if mount.optional && !source.exists() {
+                    warnings.push(format!(
+                        "skipping optional mount of {}, which does not exist",
+                        source.display()
+                    ));
+                    continue;
+                }
+                let key = match mount.kind {
+                    MountKind::RoBind => "BindReadOnly",
+                    _ => "Bind",
+                };
+                if matches!(mount.kind, MountKind::DevBind) {
+                    warnings.push(format!(
+                        "{} is exported as a plain bind; the machine also needs DeviceAllow= for it",
+                        source.display()
+                    ));
+                }
+                let source = escape_path(source);
+                if source == target {
+                    format!("{key}={source}")
+                } else {
+                    format!("{key}={source}:{target}")
+                }
+            }
+            MountKind::Tmpfs => format!("TemporaryFileSystem={target}"),
+            // nspawn always mounts /proc.
+            MountKind::Proc => continue,
+        };
+        let _ = writeln!(files, "{setting}");
+    }
+    if !files.is_empty() {
+        out.push_str("\n[Files]\n");
+        out.push_str(&files);
+    }
+
+    (out, warnings)
+}
+
+/// Escape `:` and `\` in a path for the colon-separated bind settings.
+fn escape_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace(':', "\\:")
+}
+
+/// `KEY=VALUE` for an `Environment=` line, quoted when the value holds
+/// whitespace, quotes, or backslashes, which systemd would otherwise split
+/// or unescape.
+fn quote_assignment(key: &str, value: &str) -> String {
+    let assignment = format!("{key}={value}");
+    if assignment.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\')) {
+        format!(
+            "\"{}\"",
+            assignment.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    } else {
+        assignment
+    }
+}
+
+/// Write a minimal `/etc/os-release` unless the closure provides one, since
+/// nspawn refuses to start a directory that does not look like an OS tree.
+pub fn ensure_os_release(rootfs: &Path, name: &str) -> MagResult<()> {
+    for candidate in ["etc/os-release", "usr/lib/os-release"] {
+        if fs::symlink_metadata(rootfs.join(candidate)).is_ok() {
+            return Ok(());
+        }
+    }
+    let etc = rootfs.join("etc");
+    match fs::symlink_metadata(&etc) {
+        Ok(meta) if !meta.is_dir() => {
+            return Err(MagError::Generic(format!(
+                "{} is not a directory; cannot add /etc/os-release",
+                etc.display()
+            )));
+        }
+        Ok(_) => {}
+        Err(_) => fs::create_dir_all(&etc)?,
+    }
+    fs::write(
+        etc.join("os-release"),
+        format!("ID=magpkg\nNAME=\"magpkg machine\"\nPRETTY_NAME=\"{name} (magpkg)\"\n"),
+    )?;
+    Ok(())
+}
@@ -1,56 +1,192 @@
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     env,
     ffi::OsString,
     fs::{self, File, OpenOptions},
-    io::{self, Write},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     os::unix::{ffi::OsStrExt, fs::PermissionsExt, fs::symlink, process::ExitStatusExt},
     path::{Path, PathBuf},
     process,
-    process::Command,
+    process::{Command, Stdio},
     rc::Rc,
-    time::Duration,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::{Shell, generate};
+use flate2::{Compression as GzCompression, write::GzEncoder};
 use fs2::FileExt;
 use jrsonnet_evaluator::error::Error as JrError;
-use jrsonnet_evaluator::{ObjValue, State, Val, trace::PathResolver};
-use jrsonnet_stdlib::ContextInitializer as StdlibContext;
+use jrsonnet_evaluator::{ObjValue, Val};
 use sha2::{Digest, Sha256};
 use thiserror::Error;
+use zstd::stream::write::Encoder as ZstdEncoder;
 
+mod activity;
+mod archive;
+mod btconfig;
 mod btfetcher;
 mod btseed;
+mod buildtimes;
+mod bundle;
+mod capabilities;
+mod channels;
+mod codec;
+mod copy;
+mod cpio;
+mod diff;
+mod discover;
+mod embed;
 mod errors;
+mod exitreport;
+mod explain;
+mod fetchauth;
+mod fileindex;
+mod hostlimits;
+mod httpcache;
 mod imports;
+mod index;
+mod isolation;
+mod journal;
+mod leases;
+mod linkfrom;
+mod lint;
+mod localsrc;
+mod lockfile;
+mod machine;
+mod metadb;
+mod mirrors;
+mod natives;
+mod offline;
+mod pack;
 mod package;
+mod passthrough;
+mod priority;
+mod probe;
+mod profiles;
+mod provenance;
+mod proxy;
+mod quiet;
+mod sandbox;
+mod sbom;
+mod seekable;
+mod shims;
+mod signals;
+mod stats;
 mod store;
+mod webhooks;
+mod why;
 
+use crate::btconfig::{
+    ListenAddr, TorrentConfig, TorrentOverrides, set_cli_overrides, verify_interval_from_hours,
+};
 use crate::btseed::TorrentSeeder;
+use crate::buildtimes::BuildHistory;
+use crate::bundle::BundleSigner;
+use crate::capabilities::{CapabilityStatus, probe_capabilities, require_sandbox};
+use crate::channels::Channels;
+use crate::codec::{ArtifactCodec, CompressionSettings, DEFAULT_COMPRESSION_LEVEL};
+use crate::copy::{CopyMode, SshRemote, is_published};
+use crate::cpio::CpioWriter;
+use crate::diff::{diff_graphs, short_hash, summarize_graph, summarize_lockfile};
+use crate::discover::{
+    find_manifests, manifest_snippets, manifests_expression, packages_from_manifests,
+};
+use crate::embed::Evaluator;
 use crate::errors::format_jr_error;
-use crate::imports::MagImportResolver;
-use crate::package::{Package, PackageGraphBuilder, collect_runtime_closure};
-use crate::store::{CleanupOptions, PackageStore};
+use crate::exitreport::{ExitReportTarget, wait_with_max_rss};
+use crate::explain::explain_plan;
+use crate::fileindex::PathKind;
+use crate::httpcache::{CacheServer, set_skip_signature_check};
+use crate::imports::ImportLog;
+use crate::index::{
+    INDEXES_ENV, IndexedPackage, load_signing_key, magnet_with_sources, public_key_hex,
+};
+use crate::isolation::{IdMapping, Namespace, ResourceLimits, venv_sandbox_command};
+use crate::leases::{lease_host, lease_ttl, list_leases, unix_now};
+use crate::linkfrom::LinkSources;
+use crate::localsrc::set_source_roots;
+use crate::lockfile::{DEFAULT_LOCKFILE, Lockfile};
+use crate::machine::{
+    NSPAWN_SETTINGS_DIR, ensure_os_release, nspawn_settings, validate_machine_name,
+};
+use crate::metadb::ArtifactRow;
+use crate::offline::{is_offline, set_offline};
+use crate::pack::append_tree;
+use crate::package::{
+    BuilderShell, MAX_COMPRESSION_LEVEL, ObjKey, Package, PackageGraphBuilder,
+    collect_runtime_closure, package_base_name,
+};
+use crate::passthrough::{ALL_PASSTHROUGH, Passthrough, apply_passthrough};
+use crate::priority::{CpuSet, IoClass, Scheduling, parse_nice};
+use crate::probe::{SourceStatus, probe_fetches};
+use crate::profiles::VenvProfile;
+use crate::quiet::SilencedStderr;
+use crate::sbom::spdx_document;
+use crate::shims::HostExports;
+use crate::stats::AreaStats;
+use crate::store::{
+    BuildSource, CleanupOptions, PackageStore, PackageTiming, TorrentSources, UsagePolicy,
+    VENV_LAYERS_FILE, format_bytes, read_torrent_sources, run_debug_shell, sha256_file,
+    store_base_root, touch_path, venv_lease_label,
+};
+use crate::webhooks::{BuildEvent, BuildStatus, Webhooks};
+use crate::why::{dependency_chains, format_chains};
 
 const DEFAULT_SEED_PORT: u16 = 6881;
 
 fn main() {
     if let Err(err) = try_main() {
         report_error(&err);
-        std::process::exit(1);
+        std::process::exit(err.exit_code());
     }
 }
 
 fn try_main() -> MagResult<()> {
     let cli = Cli::parse();
+    set_cli_overrides(cli.torrent.overrides());
+    set_skip_signature_check(cli.no_check_signature);
+    set_offline(cli.offline);
+    set_source_roots(cli.imports.roots.clone());
+    let _ = IMPORT_OPTIONS.set(cli.imports);
+    let _ = JSON_OUTPUT.set(cli.json);
     match cli.command {
         Commands::Build(args) => run_build(args),
         Commands::Fetch(args) => run_fetch(args),
         Commands::Cleanup(args) => run_cleanup(args),
+        Commands::Du(args) => run_du(args),
+        Commands::Remove(args) => run_remove(args),
+        Commands::Repair => run_repair(),
         Commands::Seed(args) => run_seed(args),
+        Commands::Serve(args) => run_serve(args),
         Commands::ExportTarball(args) => run_export_tarball(args),
         Commands::Venv(args) => run_venv(args),
+        Commands::Lock(args) => run_lock(args),
+        Commands::Diff(args) => run_diff(args),
+        Commands::Explain(args) => run_explain(args),
+        Commands::Why(args) => run_why(args),
+        Commands::Sbom(args) => run_sbom(args),
+        Commands::Provenance(args) => run_provenance(args),
+        Commands::Owns(args) => run_owns(args),
+        Commands::Cat(args) => run_cat(args),
+        Commands::PublishIndex(args) => run_publish_index(args),
+        Commands::Search(args) => run_search(args),
+        Commands::Copy(args) => run_copy(args),
+        Commands::CopyServer(args) => run_copy_server(args),
+        Commands::Channel(args) => run_channel(args),
+        Commands::ExportImage(args) => run_export_image(args),
+        Commands::ExportInitramfs(args) => run_export_initramfs(args),
+        Commands::ExportMachine(args) => run_export_machine(args),
+        Commands::Store(args) => run_store(args),
+        Commands::Locks(args) => run_locks(args),
+        Commands::DebugBuild(args) => run_debug_build(args),
+        Commands::Shell(args) => run_shell(args),
+        Commands::Torrents(args) => run_torrents(args),
+        Commands::Doctor => run_doctor(),
+        Commands::Completions(args) => run_completions(args),
+        Commands::Complete(args) => run_complete(args),
     }
 }
 
@@ -61,44 +197,692 @@ fn try_main() -> MagResult<()> {
     about = "Magnet Linux package manager tooling"
 )]
 struct Cli {
+    #[command(flatten)]
+    torrent: TorrentArgs,
+    /// Accept artifacts from binary caches whose publish records are unsigned
+    /// or signed by an untrusted key. Intended for local testing.
+    #[arg(long, global = true)]
+    no_check_signature: bool,
+    /// Machine-readable output: commands with a report print it as JSON,
+    /// and a failure is printed to stderr as a JSON object with its code.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(flatten)]
+    imports: ImportArgs,
+    /// Never use the network: remote imports and sources must already be
+    /// cached or reachable through file:// URLs, and binary caches, torrent
+    /// indexes, and webhooks are skipped.
+    #[arg(long, global = true)]
+    offline: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Restrictions on what manifests may import.
+#[derive(Args)]
+struct ImportArgs {
+    /// Only allow manifests to import files beneath DIR (repeatable).
+    #[arg(long = "import-root", value_name = "DIR", global = true)]
+    roots: Vec<PathBuf>,
+    /// Refuse `http://` and `https://` imports.
+    #[arg(long = "no-remote-imports", global = true)]
+    no_remote: bool,
+}
+
+static IMPORT_OPTIONS: OnceLock<ImportArgs> = OnceLock::new();
+static JSON_OUTPUT: OnceLock<bool> = OnceLock::new();
+
+/// Whether `--json` was given.
+fn json_output() -> bool {
+    JSON_OUTPUT.get().copied().unwrap_or(false)
+}
+
+/// Name under which a manifest read with `-f -` is imported.
+const STDIN_MANIFEST_NAME: &str = "<stdin>";
+static STDIN_MANIFEST: OnceLock<String> = OnceLock::new();
+
+/// Peer discovery overrides for `<store>/torrent.conf`.
+#[derive(Args)]
+struct TorrentArgs {
+    /// Disable the BitTorrent DHT.
+    #[arg(long, global = true, conflicts_with = "dht")]
+    no_dht: bool,
+    /// Enable the BitTorrent DHT even if torrent.conf disables it.
+    #[arg(long, global = true)]
+    dht: bool,
+    /// Do not persist DHT routing state under the store.
+    #[arg(long, global = true)]
+    no_dht_persistence: bool,
+    /// Announce to this tracker and add it to created torrents (repeatable).
+    #[arg(long = "tracker", value_name = "URL", global = true)]
+    trackers: Vec<String>,
+    /// Ignore trackers and discover peers through the DHT only.
+    #[arg(long, global = true, conflicts_with = "trackers")]
+    trackerless: bool,
+    /// Abandon a torrent download after this many seconds without progress
+    /// and fall back to HTTP sources (0 waits forever).
+    #[arg(long, value_name = "SECS", global = true)]
+    torrent_stall_timeout: Option<u64>,
+}
+
+impl TorrentArgs {
+    fn overrides(&self) -> TorrentOverrides {
+        TorrentOverrides {
+            dht: if self.no_dht {
+                Some(false)
+            } else if self.dht {
+                Some(true)
+            } else {
+                None
+            },
+            persist_dht: self.no_dht_persistence.then_some(false),
+            trackerless: self.trackerless.then_some(true),
+            trackers: self.trackers.clone(),
+            stall_timeout: self.torrent_stall_timeout,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Evaluate a Jsonnet manifest and build the package graph.
     Build(BuildArgs),
     /// Pre-fetch sources for a package graph without building.
     Fetch(FetchArgs),
-    /// Remove cached artifacts older than the expiry window.
+    /// Remove cached artifacts older than the expiry window, or least recently
+    /// used ones when free space runs low (--min-free).
+    #[command(alias = "gc")]
     Cleanup(CleanupArgs),
+    /// Report store disk usage by category and package, marking what
+    /// `cleanup` with the same flags would remove.
+    Du(DuArgs),
+    /// Remove specific package artifacts, optionally with everything that
+    /// depends on them.
+    Remove(RemoveArgs),
+    /// Roll back operations interrupted by a crash and remove the partial
+    /// files they left, without touching anything complete.
+    Repair,
     /// Seed cached torrents so peers can download sources from this machine.
     Seed(SeedArgs),
+    /// Serve built artifacts, fetched sources, and torrent metadata as an HTTP binary cache.
+    Serve(ServeArgs),
     /// Export the runtime closure of packages as a tarball.
     ExportTarball(ExportTarballArgs),
     /// Materialize a runtime environment under the store and launch a venv inside it.
     Venv(VenvArgs),
+    /// Record remote imports, fetches, and package hashes into a lockfile.
+    Lock(LockArgs),
+    /// Show which packages an expression adds, removes, or rebuilds compared to another.
+    Diff(DiffArgs),
+    /// Summarize what building an expression would do, as Markdown for reviewers.
+    Explain(ExplainArgs),
+    /// Show every dependency chain from an expression's roots to a package.
+    Why(WhyArgs),
+    /// Write an SPDX 2.3 JSON software bill of materials for an expression.
+    Sbom(SbomArgs),
+    /// Show how a published package was built: its inputs, builder, and timing.
+    Provenance(ProvenanceArgs),
+    /// Show which packages in an expression's runtime closure provide a path.
+    Owns(OwnsArgs),
+    /// Print a file from the runtime closure without unpacking the artifacts.
+    Cat(CatArgs),
+    /// Build a package set and write a signed torrent index for it.
+    PublishIndex(PublishIndexArgs),
+    /// Find packages offered by the configured indexes by name or description.
+    Search(SearchArgs),
+    /// Copy the artifacts of a runtime closure that another store is missing
+    /// to or from it over SSH.
+    Copy(CopyArgs),
+    /// The remote end of `magpkg copy`.
+    #[command(name = "__copy", hide = true)]
+    CopyServer(CopyServerArgs),
+    /// Manage channels, pinned manifest tarballs imported as `channel:<name>/<path>`.
+    Channel(ChannelArgs),
+    /// Export the runtime closure of packages as a compressed read-only filesystem image.
+    ExportImage(ExportImageArgs),
+    /// Export the runtime closure of packages as a newc cpio archive for use as an initramfs.
+    ExportInitramfs(ExportInitramfsArgs),
+    /// Export a venv's runtime closure and settings as a systemd-nspawn machine.
+    ExportMachine(ExportMachineArgs),
+    /// Inspect and manage the package store.
+    Store(StoreArgs),
+    /// Show which processes and hosts hold store locks.
+    Locks(LocksArgs),
+    /// Open a shell inside a build tree kept by --keep-failed.
+    DebugBuild(DebugBuildArgs),
+    /// Open a shell in a package's build sandbox without running its build
+    /// script, to try out build commands.
+    Shell(ShellArgs),
+    /// Inspect the torrents this store can seed.
+    Torrents(TorrentsArgs),
+    /// Check which host facilities are available and what is degraded without them.
+    Doctor,
+    /// Print a shell completion script.
+    Completions(CompletionsArgs),
+    /// Print store-aware completion candidates (used by `completions --dynamic`).
+    #[command(name = "__complete", hide = true)]
+    Complete(CompleteArgs),
 }
 
 #[derive(Args)]
-struct BuildArgs {
+struct CompletionsArgs {
+    /// Shell to generate completions for.
+    #[arg(value_enum)]
+    shell: Shell,
+    /// Also complete arguments such as package hashes from the local store.
+    #[arg(long)]
+    dynamic: bool,
+}
+
+#[derive(Args)]
+struct CompleteArgs {
+    /// What to list.
+    #[arg(value_enum)]
+    kind: CompletionKind,
+    /// Only print candidates starting with this prefix.
+    #[arg(default_value = "")]
+    prefix: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum CompletionKind {
+    /// Base names of published packages.
+    Packages,
+    /// Hashes of failed builds kept by --keep-failed.
+    Failed,
+    /// Names of registered venv profiles.
+    Profiles,
+}
+
+impl CompletionKind {
+    fn label(self) -> &'static str {
+        match self {
+            CompletionKind::Packages => "packages",
+            CompletionKind::Failed => "failed",
+            CompletionKind::Profiles => "profiles",
+        }
+    }
+}
+
+/// Subcommands whose first positional argument completes from the store.
+/// Nested subcommands are given with their parent and come first, so that
+/// `venv remove` is not completed like `remove`.
+const DYNAMIC_COMPLETIONS: &[(&str, CompletionKind)] = &[
+    ("venv enter", CompletionKind::Profiles),
+    ("venv update", CompletionKind::Profiles),
+    ("venv remove", CompletionKind::Profiles),
+    ("venv export", CompletionKind::Profiles),
+    ("venv unexport", CompletionKind::Profiles),
+    ("debug-build", CompletionKind::Failed),
+    ("remove", CompletionKind::Packages),
+    ("provenance", CompletionKind::Packages),
+];
+
+#[derive(Args)]
+struct ShellArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Package to open the sandbox of: a name, base name, or hash prefix of
+    /// any package in the graph. Optional when the manifest evaluates to a
+    /// single package.
+    #[arg(value_name = "PACKAGE")]
+    package: Option<String>,
+    /// Parallelism to pass to dependency builds and the shell via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+}
+
+#[derive(Args)]
+struct DebugBuildArgs {
+    /// Package hash (or unique prefix) of the failed build.
+    #[arg(value_name = "HASH")]
+    hash: String,
+}
+
+#[derive(Args)]
+struct StoreArgs {
+    #[command(subcommand)]
+    command: StoreCommands,
+}
+
+#[derive(Subcommand)]
+enum StoreCommands {
+    /// Report entry counts, sizes, ages, and cache/lock counters for the store.
+    Stats,
+    /// Show what other magpkg processes using this store are doing.
+    Activity,
+    /// Write one published artifact and its metadata to a portable bundle file.
+    Export(StoreExportArgs),
+    /// Verify a bundle written by `store export` and publish its artifact.
+    Import(StoreImportArgs),
+    /// List published artifacts with their sizes, last use, and build provenance.
+    Query(StoreQueryArgs),
+    /// Rebuild the store database from the published artifacts.
+    Reindex,
+}
+
+#[derive(Args)]
+struct StoreQueryArgs {
+    /// Only list artifacts whose base name contains this text.
+    #[arg(value_name = "PATTERN")]
+    pattern: Option<String>,
+    /// Only list artifacts that depend directly on this published artifact.
+    #[arg(long, value_name = "HASH")]
+    depends_on: Option<String>,
+    /// Only list artifacts built on this host.
+    #[arg(long, value_name = "HOST")]
+    builder: Option<String>,
+    /// Only list artifacts not used by a build for this many days.
+    #[arg(long, value_name = "DAYS")]
+    unused_days: Option<u64>,
+    /// Check the database against the published artifacts first, adding
+    /// and dropping rows for artifacts changed behind its back.
+    #[arg(long)]
+    verify: bool,
+}
+
+#[derive(Args)]
+struct ChannelArgs {
+    #[command(subcommand)]
+    command: ChannelCommands,
+}
+
+#[derive(Subcommand)]
+enum ChannelCommands {
+    /// Register a channel and pin it to the tarball its URL serves now.
+    Add(ChannelAddArgs),
+    /// Fetch channels again and repin those whose tarball changed.
+    Update(ChannelUpdateArgs),
+    /// Forget a channel and remove its checkout from the store.
+    Remove(ChannelRemoveArgs),
+    /// List channels with their URLs and pins.
+    List,
+}
+
+#[derive(Args)]
+struct ChannelAddArgs {
+    /// Name to import the channel's files under.
+    #[arg(value_name = "NAME")]
+    name: String,
+    /// URL or path of the channel tarball.
+    #[arg(value_name = "URL")]
+    url: String,
+}
+
+#[derive(Args)]
+struct ChannelUpdateArgs {
+    /// Channels to update; all of them when omitted.
+    #[arg(value_name = "NAME")]
+    names: Vec<String>,
+}
+
+#[derive(Args)]
+struct ChannelRemoveArgs {
+    #[arg(value_name = "NAME")]
+    name: String,
+}
+
+#[derive(Args)]
+struct LocksArgs {
+    #[command(subcommand)]
+    command: LocksCommands,
+}
+
+#[derive(Subcommand)]
+enum LocksCommands {
+    /// List store leases and the locks held by processes on this host.
+    List,
+}
+
+#[derive(Args)]
+struct StoreExportArgs {
+    /// Base name, package hash, or unique hash prefix of a published artifact.
+    #[arg(value_name = "HASH")]
+    hash: String,
+    /// Write the bundle to this path (default: <base>.magpkg-bundle).
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Sign the bundle with this Ed25519 key in PKCS#8 format.
+    #[arg(long, value_name = "PATH")]
+    key: Option<PathBuf>,
+    /// Create the signing key if it does not exist yet.
+    #[arg(long, requires = "key")]
+    generate_key: bool,
+}
+
+#[derive(Args)]
+struct StoreImportArgs {
+    /// Bundle files to import.
+    #[arg(value_name = "FILE", required = true)]
+    files: Vec<PathBuf>,
+    /// Accept bundles that are unsigned or signed by a key outside MAGPKG_TRUSTED_KEYS.
+    #[arg(long)]
+    allow_untrusted: bool,
+}
+
+/// The manifest a command evaluates, given inline with `-e` or as a file
+/// with `-f`.
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct ManifestArgs {
     /// Jsonnet expression to evaluate and convert into packages.
-    #[arg(short = 'e', long = "expression", value_name = "EXPR", required = true)]
-    expression: String,
+    #[arg(short = 'e', long = "expression", value_name = "EXPR")]
+    expression: Option<String>,
+    /// Path to a Jsonnet manifest file (shorthand for `-e 'import "PATH"'`),
+    /// or `-` to read it from stdin.
+    #[arg(short = 'f', long = "file", value_name = "PATH")]
+    file: Option<PathBuf>,
+}
+
+impl ManifestArgs {
+    fn expression(&self) -> MagResult<String> {
+        manifest_expression(self.expression.clone(), self.file.clone())?.ok_or_else(|| {
+            MagError::Generic("pass a manifest with -e/--expression or -f/--file".into())
+        })
+    }
+}
+
+/// The expression for a manifest given inline or as a file; a file becomes
+/// an `import` of its path.
+fn manifest_expression(
+    expression: Option<String>,
+    file: Option<PathBuf>,
+) -> MagResult<Option<String>> {
+    match (expression, file) {
+        (Some(expr), _) => Ok(Some(expr)),
+        (None, Some(path)) if path.as_os_str() == "-" => {
+            if STDIN_MANIFEST.get().is_none() {
+                let mut manifest = String::new();
+                io::stdin().read_to_string(&mut manifest)?;
+                let _ = STDIN_MANIFEST.set(manifest);
+            }
+            Ok(Some(format!(
+                "import {}",
+                quote_jsonnet_string(Path::new(STDIN_MANIFEST_NAME))?
+            )))
+        }
+        (None, Some(path)) => Ok(Some(format!("import {}", quote_jsonnet_string(&path)?))),
+        (None, None) => Ok(None),
+    }
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Build every .jsonnet file below DIR in one evaluation and one build.
+    #[arg(long, value_name = "DIR", group = "ManifestArgs")]
+    dir: Option<PathBuf>,
+    /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+    /// Keep the sandbox of a failed build for inspection with `magpkg debug-build`.
+    #[arg(long)]
+    keep_failed: bool,
+    /// After a package fails, keep building those that do not depend on it,
+    /// and report every failure at the end.
+    #[arg(long)]
+    keep_going: bool,
+    /// Wait for other queued builds on this store to finish instead of interleaving with them.
+    #[arg(long)]
+    queue: bool,
+    /// Skip the check phase of packages built from source; their hashes do not change.
+    #[arg(long)]
+    skip_checks: bool,
+    /// Only warn about missing runtime dependencies, even for packages that set checkRuntimeDeps.
+    #[arg(long)]
+    lint: bool,
+    /// Print a per-package timing table to stderr once the build finishes.
+    #[arg(long)]
+    timings: bool,
+    /// Write the per-package timings as JSON to PATH (`-` for stdout, replacing the artifact list).
+    #[arg(long, value_name = "PATH")]
+    timings_json: Option<PathBuf>,
+    /// Codec for new artifacts; packages may override it.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ArtifactCodec::Zstd)]
+    compression_format: ArtifactCodec,
+    /// Compression level for new artifacts, from 1 (fastest) to 22 (smallest; xz stops at 9); packages may override it.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value_t = DEFAULT_COMPRESSION_LEVEL,
+        value_parser = clap::value_parser!(i32).range(1..=MAX_COMPRESSION_LEVEL as i64)
+    )]
+    compression_level: i32,
+    /// Threads compressing each artifact (default: one per CPU, at most
+    /// twice that); packages may override it.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = default_parallelism() as u32,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    compression_threads: u32,
+    /// Run build sandboxes with this niceness (-20 to 19), e.g. 19 on a shared server.
+    #[arg(long, value_name = "N", value_parser = parse_nice_arg, allow_hyphen_values = true)]
+    nice: Option<i32>,
+    /// I/O scheduling class of build sandboxes: idle, best-effort[:0-7], or realtime[:0-7].
+    #[arg(long, value_name = "CLASS", value_parser = parse_ionice_arg)]
+    ionice: Option<IoClass>,
+    /// Pin build sandboxes to these CPUs, e.g. 0-3,8; BUILD_PARALLELISM is capped to match.
+    #[arg(long, value_name = "CPUS", value_parser = parse_cpuset_arg)]
+    cpuset: Option<CpuSet>,
+    #[command(flatten)]
+    frozen: FrozenArgs,
+}
+
+fn parse_nice_arg(value: &str) -> Result<i32, String> {
+    parse_nice(value).ok_or_else(|| format!("niceness must be between -20 and 19, got {value:?}"))
+}
+
+fn parse_ionice_arg(value: &str) -> Result<IoClass, String> {
+    IoClass::parse(value).ok_or_else(|| {
+        format!("invalid I/O class {value:?}; use idle, best-effort[:0-7], or realtime[:0-7]")
+    })
+}
+
+fn parse_cpuset_arg(value: &str) -> Result<CpuSet, String> {
+    CpuSet::parse(value).ok_or_else(|| format!("invalid CPU list {value:?}"))
+}
+
+#[derive(Args, Default)]
+struct FrozenArgs {
+    /// Refuse to proceed if the evaluated graph deviates from the lockfile.
+    #[arg(long)]
+    frozen: bool,
+    /// Lockfile consulted by --frozen.
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_LOCKFILE)]
+    lockfile: PathBuf,
+}
+
+#[derive(Args)]
+struct LockArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Write the lockfile to this path.
+    #[arg(short, long, value_name = "PATH", default_value = DEFAULT_LOCKFILE)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Jsonnet expression for the old package graph.
+    #[arg(long, value_name = "EXPR", required_unless_present = "old_lock")]
+    old: Option<String>,
+    /// Lockfile recording the old package graph, e.g. one checked in on the base branch.
+    #[arg(long, value_name = "PATH", conflicts_with = "old")]
+    old_lock: Option<PathBuf>,
+    #[command(flatten)]
+    manifest: ManifestArgs,
+}
+
+#[derive(Args)]
+struct ExplainArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+}
+
+#[derive(Args)]
+struct WhyArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Package to explain: a name, base name, or hash prefix of any package
+    /// in the graph.
+    #[arg(value_name = "PACKAGE")]
+    package: String,
+    /// Follow only runtime dependencies, showing how the package ends up in
+    /// the runtime closure.
+    #[arg(long)]
+    runtime: bool,
+    /// Most chains to print.
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    limit: u32,
+}
+
+#[derive(Args)]
+struct SbomArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Also list build-only dependencies, not just the runtime closure.
+    #[arg(long)]
+    include_build_deps: bool,
+    /// Write the document to this path instead of stdout.
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ProvenanceArgs {
+    /// Package base name, hash, or unique hash prefix.
+    #[arg(value_name = "PACKAGE")]
+    package: String,
+}
+
+#[derive(Args)]
+struct OwnsArgs {
+    /// Paths to look up, as they appear in an exported image (e.g. /usr/bin/foo).
+    #[arg(value_name = "PATH", required_unless_present = "collisions")]
+    paths: Vec<String>,
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// List every non-directory path provided by more than one package instead.
+    #[arg(long, conflicts_with = "paths")]
+    collisions: bool,
+}
+
+#[derive(Args)]
+struct CatArgs {
+    /// Path as it appears in an exported image (e.g. /etc/os-release).
+    #[arg(value_name = "PATH")]
+    path: String,
+    #[command(flatten)]
+    manifest: ManifestArgs,
+}
+
+#[derive(Args)]
+struct PublishIndexArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Publish every .jsonnet file below DIR; search entries import the
+    /// manifests by their path relative to DIR. Without it, the index has
+    /// no search entries.
+    #[arg(long, value_name = "DIR", group = "ManifestArgs")]
+    dir: Option<PathBuf>,
+    /// Prefix for the import paths of search entries, such as
+    /// `channel:magnet/`, naming where consumers find the manifests.
+    #[arg(long, value_name = "PREFIX", requires = "dir", default_value = "")]
+    import_base: String,
+    /// Ed25519 signing key in PKCS#8 format.
+    #[arg(long, value_name = "PATH")]
+    key: PathBuf,
+    /// Create the signing key if it does not exist yet.
+    #[arg(long)]
+    generate_key: bool,
+    /// Write the index to this path.
+    #[arg(short, long, value_name = "PATH")]
+    output: PathBuf,
     /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
     #[arg(long, default_value_t = default_parallelism())]
     parallelism: usize,
 }
 
+#[derive(Args)]
+struct SearchArgs {
+    /// Text to look for in package names and descriptions.
+    #[arg(value_name = "QUERY")]
+    query: String,
+}
+
+#[derive(Args)]
+struct CopyArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Store to copy the closure to, as ssh://[user@]host[:port].
+    #[arg(
+        long,
+        value_name = "URL",
+        required_unless_present = "from",
+        conflicts_with = "from"
+    )]
+    to: Option<String>,
+    /// Store to copy the closure from, as ssh://[user@]host[:port].
+    #[arg(long, value_name = "URL")]
+    from: Option<String>,
+    /// magpkg binary to run on the remote host.
+    #[arg(long, value_name = "PROGRAM", default_value = "magpkg")]
+    remote_program: String,
+}
+
+#[derive(Args)]
+struct CopyServerArgs {
+    #[arg(value_enum)]
+    mode: CopyMode,
+}
+
 #[derive(Args)]
 struct FetchArgs {
     /// Jsonnet expression to evaluate and convert into packages.
-    #[arg(short = 'e', long = "expression", value_name = "EXPR", required = true)]
-    expression: String,
+    #[arg(
+        short = 'e',
+        long = "expression",
+        value_name = "EXPR",
+        required_unless_present_any = ["refresh_torrents", "file"]
+    )]
+    expression: Option<String>,
+    /// Path to a Jsonnet manifest file (shorthand for `-e 'import "PATH"'`),
+    /// or `-` to read it from stdin.
+    #[arg(
+        short = 'f',
+        long = "file",
+        value_name = "PATH",
+        conflicts_with = "expression"
+    )]
+    file: Option<PathBuf>,
     /// Only fetch sources for packages whose artifacts are not yet built.
     #[arg(long)]
     missing_only: bool,
+    /// Regenerate torrent metadata for every cached fetch under the current
+    /// torrent policy instead of fetching. With -e, the manifest's fetches
+    /// identify cached files that have no torrent yet.
+    #[arg(long, conflicts_with = "missing_only")]
+    refresh_torrents: bool,
+    /// With --refresh-torrents, write nothing: generate each torrent twice
+    /// and fail if the two differ.
+    #[arg(long, requires = "refresh_torrents")]
+    check_deterministic: bool,
+    /// Before downloading, look for each missing source in this directory
+    /// (by file name or sha256 name) and hard-link or copy it into the
+    /// cache once its sha256 verifies. May be repeated.
+    #[arg(long, value_name = "DIR", conflicts_with = "refresh_torrents")]
+    link_from: Vec<PathBuf>,
+    /// Download nothing; list every fetch with the sources it would try,
+    /// in order.
+    #[arg(long, conflicts_with_all = ["refresh_torrents", "link_from"])]
+    print_urls: bool,
+    /// With --print-urls, probe each source (HTTP HEAD, DHT peers for magnet
+    /// links) and fail if a fetch has no live source.
+    #[arg(long, requires = "print_urls")]
+    check: bool,
 }
 
 #[derive(Args)]
@@ -121,6 +905,68 @@ struct CleanupArgs {
     /// Enable all cleanup categories (packages, fetched, torrents, venvs).
     #[arg(long)]
     all: bool,
+    /// Also remove your isolated venv home directories not entered for
+    /// --max-age-days. Homes hold user data, so --all leaves them alone.
+    #[arg(long, conflicts_with = "min_free")]
+    venv_homes: bool,
+    /// Only clean up when the store filesystem has less than SIZE available
+    /// (e.g. `20G`), removing least recently used entries regardless of age.
+    /// Without category flags, every category is eligible.
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, conflicts_with = "max_age_days")]
+    min_free: Option<u64>,
+    /// Keep removing entries until this much space is available (defaults to --min-free).
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, requires = "min_free")]
+    target_free: Option<u64>,
+}
+
+impl CleanupArgs {
+    /// Categories to clean. With `--min-free` and no category flags, every
+    /// category is eligible; age-based cleanup only removes the ones asked for.
+    fn options(&self) -> CleanupOptions {
+        let default = self.min_free.is_some()
+            && !(self.all || self.packages || self.fetched || self.torrents || self.venvs);
+        CleanupOptions {
+            packages: default || self.all || self.packages,
+            fetched: default || self.all || self.fetched,
+            torrents: default || self.all || self.torrents,
+            venvs: default || self.all || self.venvs,
+        }
+    }
+
+    fn expiry(&self) -> Duration {
+        Duration::from_secs(self.max_age_days.saturating_mul(24 * 60 * 60))
+    }
+
+    fn target_free(&self, min_free: u64) -> u64 {
+        self.target_free.unwrap_or(min_free).max(min_free)
+    }
+}
+
+#[derive(Args)]
+struct RemoveArgs {
+    /// Package base name, package name (every build of it), hash, or unique
+    /// hash prefix.
+    #[arg(required = true, value_name = "PACKAGE")]
+    packages: Vec<String>,
+    /// Also remove every published package that depends on a removed one,
+    /// at build time or at runtime.
+    #[arg(short, long)]
+    recursive: bool,
+    /// Also remove fetched sources that no remaining package was built from.
+    #[arg(long)]
+    fetches: bool,
+    /// Print what would be removed without removing anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct DuArgs {
+    #[command(flatten)]
+    policy: CleanupArgs,
+    /// Number of largest packages to list.
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    top: usize,
 }
 
 #[derive(Args)]
@@ -128,33 +974,183 @@ struct SeedArgs {
     /// Listen for inbound BitTorrent peers on the given TCP port (default 6881).
     #[arg(long, value_name = "PORT", conflicts_with = "no_listen")]
     listen_port: Option<u16>,
+    /// Listen on this address, with an optional port: 0.0.0.0 (default),
+    /// `[::]` for IPv4 and IPv6, or a specific address such as
+    /// `[2001:db8::1]:6881`. Overrides `listen-addr` in torrent.conf.
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr, conflicts_with = "no_listen")]
+    listen_addr: Option<ListenAddr>,
+    /// Public address peers can reach this seeder on, added to announced
+    /// magnet links (repeatable), e.g. `[2001:db8::1]:6881`.
+    #[arg(long = "announce-addr", value_name = "ADDR:PORT")]
+    announce_addrs: Vec<SocketAddr>,
     /// Run the seeder without opening an inbound TCP port.
-    #[arg(long, conflicts_with = "listen_port")]
+    #[arg(long, conflicts_with_all = ["listen_port", "listen_addr"])]
     no_listen: bool,
+    /// Additional directory to watch for torrents (repeatable), e.g. another store's `torrent/` tree.
+    #[arg(long = "torrent-dir", value_name = "PATH")]
+    torrent_dirs: Vec<PathBuf>,
+    /// Do not seed torrents from this store's own `torrent/` directory.
+    #[arg(long, requires = "torrent_dirs")]
+    no_store_torrents: bool,
+    /// Print a magnet link for every seeded resource before starting.
+    #[arg(long)]
+    announce: bool,
+    /// Cap the disk used by payload copies under the store's torrent/ tree
+    /// (K, M, G, or T suffix); the least recently requested are evicted.
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    max_storage: Option<u64>,
+    /// Seed payloads without first checking them against their torrent's
+    /// piece hashes.
+    #[arg(long)]
+    no_verify: bool,
+    /// Hash seeded payloads again every HOURS hours; overrides
+    /// `verify-interval` in torrent.conf (0 disables).
+    #[arg(long, value_name = "HOURS")]
+    verify_interval: Option<u64>,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address and port to listen on; use 0.0.0.0:PORT to serve the LAN.
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// Sign served publish records with this Ed25519 key in PKCS#8 format.
+    #[arg(long, value_name = "PATH")]
+    sign_key: Option<PathBuf>,
+    /// Create the signing key if it does not exist yet.
+    #[arg(long, requires = "sign_key")]
+    generate_key: bool,
+}
+
+#[derive(Args)]
+struct TorrentsArgs {
+    #[command(subcommand)]
+    command: TorrentsCommands,
+}
+
+#[derive(Subcommand)]
+enum TorrentsCommands {
+    /// Print magnet links, info hashes, and webseeds for every seedable resource.
+    List(TorrentsListArgs),
+}
+
+#[derive(Args)]
+struct TorrentsListArgs {
+    /// Additional directory of torrents to include (repeatable).
+    #[arg(long = "torrent-dir", value_name = "PATH")]
+    torrent_dirs: Vec<PathBuf>,
 }
 
 #[derive(Args)]
 struct ExportTarballArgs {
-    /// Jsonnet expression to evaluate into packages.
-    #[arg(short = 'e', long = "expression", value_name = "EXPR", required = true)]
-    expression: String,
+    #[command(flatten)]
+    manifest: ManifestArgs,
     /// Write the tarball to this path instead of stdout. Use '-' for stdout.
     #[arg(short, long, value_name = "PATH")]
     output: Option<PathBuf>,
     /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
     #[arg(long, default_value_t = default_parallelism())]
     parallelism: usize,
+    /// Write a zstd-compressed tarball by joining the artifacts' compressed frames
+    /// instead of unpacking and repacking the closure.
+    #[arg(long)]
+    concat: bool,
+    /// Export a second time and fail unless both copies are byte-identical.
+    #[arg(long, requires = "output")]
+    check_deterministic: bool,
+    /// Add the provenance records of the packages under
+    /// /usr/share/magpkg/provenance.
+    #[arg(long, conflicts_with = "concat")]
+    provenance: bool,
+}
+
+#[derive(Args)]
+struct ExportImageArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Filesystem image format to produce.
+    #[arg(long, value_enum, default_value_t = ImageFormat::Squashfs)]
+    format: ImageFormat,
+    /// Write the image to this path.
+    #[arg(short, long, value_name = "PATH")]
+    output: PathBuf,
+    /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+    /// Export a second time and fail unless both images are byte-identical.
+    #[arg(long)]
+    check_deterministic: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImageFormat {
+    Squashfs,
+    Erofs,
+}
+
+#[derive(Args)]
+struct ExportInitramfsArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Write the archive to this path. Use '-' for stdout.
+    #[arg(short, long, value_name = "PATH")]
+    output: PathBuf,
+    /// Compression to apply (defaults to the output extension: .gz, .zst, or none).
+    #[arg(long, value_enum)]
+    compression: Option<CpioCompression>,
+    /// Target of the /init symlink the kernel runs, unless the closure provides /init.
+    #[arg(long, value_name = "PATH", default_value = "/sbin/init")]
+    init: String,
+    /// Do not add an /init symlink.
+    #[arg(long, conflicts_with = "init")]
+    no_init: bool,
+    /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+    /// Export a second time and fail unless both archives are byte-identical
+    /// (not with `-o -`).
+    #[arg(long)]
+    check_deterministic: bool,
+}
+
+#[derive(Args)]
+struct ExportMachineArgs {
+    #[command(flatten)]
+    manifest: ManifestArgs,
+    /// Machine name, used for the rootfs directory and the .nspawn settings file.
+    #[arg(long)]
+    name: String,
+    /// Write the rootfs to DIR/NAME and the settings to DIR/NAME.nspawn.
+    #[arg(short, long, value_name = "DIR", required_unless_present = "import")]
+    output: Option<PathBuf>,
+    /// Pipe the rootfs into `machinectl import-tar` and install the settings
+    /// under /etc/systemd/nspawn.
+    #[arg(long, conflicts_with = "output")]
+    import: bool,
+    /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CpioCompression {
+    None,
+    Gzip,
+    Zstd,
 }
 
 #[derive(Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct VenvArgs {
+    #[command(subcommand)]
+    action: Option<VenvAction>,
     /// Jsonnet expression describing the virtual environment.
     #[arg(
         short = 'e',
         long = "expression",
         value_name = "EXPR",
-        conflicts_with = "file",
-        required_unless_present = "file"
+        conflicts_with_all = ["file", "name"],
+        required_unless_present_any = ["file", "name"]
     )]
     expression: Option<String>,
     /// Path to a Jsonnet file describing the virtual environment (shorthand for `import`).
@@ -162,27 +1158,181 @@ struct VenvArgs {
         short = 'f',
         long = "file",
         value_name = "PATH",
-        conflicts_with = "expression"
+        conflicts_with_all = ["expression", "name"]
     )]
     file: Option<PathBuf>,
+    /// Enter the venv registered under NAME with `magpkg venv add`.
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
     /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
     #[arg(long, default_value_t = default_parallelism())]
     parallelism: usize,
+    #[command(flatten)]
+    frozen: FrozenArgs,
+    /// Mount the packages' extracted trees as overlay layers instead of
+    /// copying them into a rootfs of the venv's own.
+    #[arg(long, conflicts_with_all = ["name", "print_env"])]
+    overlay: bool,
+    /// Print shell exports pointing into the venv rootfs instead of entering it.
+    #[arg(long, conflicts_with = "command")]
+    print_env: bool,
+    /// Build the packages and materialize the rootfs without launching anything,
+    /// then print the rootfs hash, its path, and whether it was created or reused.
+    #[arg(long, conflicts_with_all = ["name", "print_env", "json_exit_report", "command"])]
+    prepare: bool,
+    /// Write a JSON report of the command's exit status, wall time, and peak memory to file descriptor FD.
+    #[arg(long, value_name = "FD", conflicts_with = "print_env")]
+    json_exit_report: Option<i32>,
     /// Command to run inside the venv (defaults to /bin/sh when omitted).
     #[arg(trailing_var_arg = true, value_name = "COMMAND")]
     command: Vec<String>,
 }
 
-#[derive(Debug, Error)]
-enum MagError {
-    #[error("failed to evaluate expression: {message}")]
-    ExpressionEval {
-        message: String,
-        #[source]
-        source: JrError,
+#[derive(Subcommand)]
+enum VenvAction {
+    /// Register a venv manifest under a name and build its rootfs.
+    Add(VenvProfileArgs),
+    /// Re-evaluate a profile's manifest, or point the profile at a new one.
+    Update(VenvProfileArgs),
+    /// Enter a registered venv without evaluating its manifest.
+    Enter(VenvEnterArgs),
+    /// Run a command in a venv for use in scripts and pipelines.
+    Exec(VenvExecArgs),
+    /// Forget a registered venv. Its rootfs is left for `magpkg cleanup --venvs`.
+    Remove {
+        /// Profile name.
+        name: String,
     },
-    #[error("dependency cycle detected")]
-    DependencyCycle,
+    /// List registered venvs.
+    List,
+    /// Install PATH shims and desktop entries that launch a profile's apps on the host.
+    Export(VenvExportArgs),
+    /// Remove the shims and desktop entries generated for a profile.
+    Unexport {
+        /// Profile name.
+        name: String,
+    },
+}
+
+#[derive(Args)]
+struct VenvExportArgs {
+    /// Profile name.
+    name: String,
+    /// Command inside the venv to expose on the host PATH (repeatable).
+    #[arg(long = "bin", value_name = "COMMAND")]
+    bins: Vec<String>,
+    /// Do not export the venv's desktop entries.
+    #[arg(long)]
+    no_desktop: bool,
+    /// Directory for the PATH shims (default: ~/.local/bin).
+    #[arg(long, value_name = "DIR")]
+    bin_dir: Option<PathBuf>,
+    /// Data directory for desktop entries and icons (default: $XDG_DATA_HOME or ~/.local/share).
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct VenvProfileArgs {
+    /// Profile name.
+    name: String,
+    /// Jsonnet expression describing the virtual environment.
+    #[arg(
+        short = 'e',
+        long = "expression",
+        value_name = "EXPR",
+        conflicts_with = "file"
+    )]
+    expression: Option<String>,
+    /// Path to a Jsonnet file describing the virtual environment.
+    #[arg(short = 'f', long = "file", value_name = "PATH")]
+    file: Option<PathBuf>,
+    /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+    #[command(flatten)]
+    frozen: FrozenArgs,
+    /// Mount the packages' extracted trees as overlay layers instead of
+    /// copying them. `update` keeps the current layout unless this or
+    /// `--copy` is given.
+    #[arg(long, conflicts_with = "copy")]
+    overlay: bool,
+    /// Copy the packages into a rootfs of the venv's own (the default).
+    #[arg(long)]
+    copy: bool,
+}
+
+/// `magpkg venv exec`: stdin, stdout, and stderr go to the command untouched,
+/// so `cat data | magpkg venv exec -f env.jsonnet -- tool > out` works.
+#[derive(Args)]
+struct VenvExecArgs {
+    /// Jsonnet expression describing the virtual environment.
+    #[arg(
+        short = 'e',
+        long = "expression",
+        value_name = "EXPR",
+        conflicts_with_all = ["file", "name"],
+        required_unless_present_any = ["file", "name"]
+    )]
+    expression: Option<String>,
+    /// Path to a Jsonnet file describing the virtual environment. Stdin
+    /// (`-`) is not accepted; it belongs to the command.
+    #[arg(
+        short = 'f',
+        long = "file",
+        value_name = "PATH",
+        conflicts_with_all = ["expression", "name"]
+    )]
+    file: Option<PathBuf>,
+    /// Run in the venv registered under NAME with `magpkg venv add`.
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+    /// Parallelism to pass to package build scripts via BUILD_PARALLELISM.
+    #[arg(long, default_value_t = default_parallelism())]
+    parallelism: usize,
+    #[command(flatten)]
+    frozen: FrozenArgs,
+    /// Mount the packages' extracted trees as overlay layers instead of
+    /// copying them into a rootfs of the venv's own.
+    #[arg(long, conflicts_with = "name")]
+    overlay: bool,
+    /// Do not print build progress or other magpkg messages to stderr.
+    /// Errors are still reported.
+    #[arg(short, long)]
+    quiet: bool,
+    /// Write a JSON report of the command's exit status, wall time, and peak memory to file descriptor FD.
+    #[arg(long, value_name = "FD")]
+    json_exit_report: Option<i32>,
+    /// Command to run inside the venv.
+    #[arg(trailing_var_arg = true, required = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+#[derive(Args)]
+struct VenvEnterArgs {
+    /// Profile name.
+    name: String,
+    /// Print shell exports pointing into the venv rootfs instead of entering it.
+    #[arg(long, conflicts_with = "command")]
+    print_env: bool,
+    /// Write a JSON report of the command's exit status, wall time, and peak memory to file descriptor FD.
+    #[arg(long, value_name = "FD", conflicts_with = "print_env")]
+    json_exit_report: Option<i32>,
+    /// Command to run inside the venv (defaults to /bin/sh when omitted).
+    #[arg(trailing_var_arg = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+enum MagError {
+    #[error("failed to evaluate expression: {message}")]
+    ExpressionEval {
+        message: String,
+        #[source]
+        source: JrError,
+    },
+    #[error("dependency cycle detected")]
+    DependencyCycle,
     #[error("{context}: {message}")]
     Evaluation {
         context: String,
@@ -202,19 +1352,234 @@ enum MagError {
     },
     #[error("{context} failed with status {status}")]
     CommandFailure { context: String, status: i32 },
+    #[error("build script for {package} failed with status {status}")]
+    SandboxFailure { package: String, status: i32 },
+    #[error("sha256 mismatch for {filename}: expected {expected}, got {actual}")]
+    FetchHashMismatch {
+        filename: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("failed to fetch {filename}: {message}")]
+    FetchFailure { filename: String, message: String },
+    #[error(
+        "--offline was given, but these are not available without the network:\n  {}\n\
+         fetch them while online with `magpkg fetch`, or pass copies with --link-from",
+        .resources.join("\n  ")
+    )]
+    OfflineUnavailable { resources: Vec<String> },
+    #[error("missing artifact for {role} {package}")]
+    MissingArtifact {
+        /// How the package was needed, e.g. `dependency`.
+        role: &'static str,
+        package: String,
+    },
+    #[error("{0}")]
+    LockfileMismatch(String),
+    #[error("{}", format_build_failures(.failures, .skipped))]
+    BuildsFailed {
+        /// Base names of the packages that failed under `--keep-going`,
+        /// with their errors.
+        failures: Vec<(String, MagError)>,
+        /// Packages left unbuilt, with the failed dependency responsible.
+        skipped: Vec<(String, String)>,
+    },
+    #[error("interrupted by signal {0}")]
+    Interrupted(i32),
     #[error("{0}")]
     Generic(String),
 }
 
+/// Exit statuses by failure class. Clap uses 2 for usage errors, and
+/// interrupted runs exit with 128 plus the signal number.
+impl MagError {
+    /// Stable identifier of the failure class, for scripts and the JSON
+    /// error report.
+    fn code(&self) -> &'static str {
+        match self {
+            MagError::ExpressionEval { .. } | MagError::Evaluation { .. } => "eval-error",
+            MagError::DependencyCycle => "dependency-cycle",
+            MagError::Io { .. } => "io-error",
+            MagError::Network { .. } => "network-error",
+            MagError::CommandFailure { .. } => "command-failure",
+            MagError::SandboxFailure { .. } => "sandbox-failure",
+            MagError::FetchHashMismatch { .. } => "fetch-hash-mismatch",
+            MagError::FetchFailure { .. } => "fetch-failure",
+            MagError::OfflineUnavailable { .. } => "offline-unavailable",
+            MagError::MissingArtifact { .. } => "missing-dependency-artifact",
+            MagError::LockfileMismatch(_) => "lockfile-mismatch",
+            MagError::BuildsFailed { .. } => "builds-failed",
+            MagError::Interrupted(_) => "interrupted",
+            MagError::Generic(_) => "error",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            MagError::ExpressionEval { .. }
+            | MagError::Evaluation { .. }
+            | MagError::DependencyCycle => 3,
+            MagError::Network { .. }
+            | MagError::FetchHashMismatch { .. }
+            | MagError::FetchFailure { .. }
+            | MagError::OfflineUnavailable { .. } => 4,
+            MagError::MissingArtifact { .. } => 5,
+            MagError::SandboxFailure { .. } => 6,
+            MagError::LockfileMismatch(_) => 7,
+            // The status of the first failure, so that a run whose only
+            // failures are build scripts still exits with 6.
+            MagError::BuildsFailed { failures, .. } => failures
+                .first()
+                .map_or(1, |(_, failure)| failure.exit_code()),
+            MagError::Interrupted(signal) => 128 + signal,
+            MagError::Io { .. } | MagError::CommandFailure { .. } | MagError::Generic(_) => 1,
+        }
+    }
+
+    /// Variant-specific fields of the JSON error report.
+    fn details(&self) -> serde_json::Value {
+        match self {
+            MagError::CommandFailure { context, status } => {
+                serde_json::json!({ "context": context, "status": status })
+            }
+            MagError::SandboxFailure { package, status } => {
+                serde_json::json!({ "package": package, "status": status })
+            }
+            MagError::FetchHashMismatch {
+                filename,
+                expected,
+                actual,
+            } => serde_json::json!({
+                "filename": filename,
+                "expected_sha256": expected,
+                "actual_sha256": actual,
+            }),
+            MagError::FetchFailure { filename, .. } => serde_json::json!({ "filename": filename }),
+            MagError::OfflineUnavailable { resources } => {
+                serde_json::json!({ "resources": resources })
+            }
+            MagError::MissingArtifact { role, package } => {
+                serde_json::json!({ "role": role, "package": package })
+            }
+            MagError::BuildsFailed { failures, skipped } => serde_json::json!({
+                "failures": failures
+                    .iter()
+                    .map(|(package, failure)| serde_json::json!({
+                        "package": package,
+                        "code": failure.code(),
+                        "message": failure.to_string(),
+                        "details": failure.details(),
+                    }))
+                    .collect::<Vec<_>>(),
+                "skipped": skipped
+                    .iter()
+                    .map(|(package, dependency)| serde_json::json!({
+                        "package": package,
+                        "dependency": dependency,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            MagError::Interrupted(signal) => serde_json::json!({ "signal": signal }),
+            _ => serde_json::json!({}),
+        }
+    }
+}
+
+/// The report of a `--keep-going` build with failures:
+///
+/// ```text
+/// 1 package(s) failed and 2 were skipped:
+///   failed   zlib-3f1c…: build script for zlib-3f1c… failed with status 2
+///   skipped  libpng-9a0e… (needs zlib-3f1c…)
+/// ```
+fn format_build_failures(failures: &[(String, MagError)], skipped: &[(String, String)]) -> String {
+    let mut report = format!(
+        "{} package(s) failed and {} were skipped:",
+        failures.len(),
+        skipped.len()
+    );
+    for (package, failure) in failures {
+        report.push_str(&format!("\n  failed   {package}: {failure}"));
+    }
+    for (package, dependency) in skipped {
+        report.push_str(&format!("\n  skipped  {package} (needs {dependency})"));
+    }
+    report
+}
+
 type MagResult<T> = std::result::Result<T, MagError>;
 
 fn run_build(args: BuildArgs) -> MagResult<()> {
-    let manifest_value = evaluate_expression(&args.expression)?;
     let mut builder = PackageGraphBuilder::default();
-    let packages = builder.packages_from_value(manifest_value)?;
+    let (packages, imports) = match &args.dir {
+        Some(dir) => {
+            let manifests = find_manifests(dir)?;
+            let (value, imports) = evaluate_expression_logged(&manifests_expression(&manifests)?)?;
+            let packages = packages_from_manifests(value, &manifests, &mut builder)?;
+            eprintln!(
+                "Found {} manifests below {} with {} packages",
+                manifests.len(),
+                dir.display(),
+                packages
+                    .iter()
+                    .map(|package| &package.hash)
+                    .collect::<HashSet<_>>()
+                    .len()
+            );
+            (packages, imports)
+        }
+        None => {
+            let (value, imports) = evaluate_expression_logged(&args.manifest.expression()?)?;
+            (builder.packages_from_value(value)?, imports)
+        }
+    };
+    check_frozen(&args.frozen, &packages, &imports)?;
 
-    let store = PackageStore::new()?;
-    store.build_packages(&packages, args.parallelism)?;
+    let mut store = PackageStore::new()?;
+    store.set_expression(Some(match &args.dir {
+        Some(dir) => format!("--dir {}", dir.display()),
+        None => args.manifest.expression()?,
+    }));
+    store.set_keep_failed(args.keep_failed);
+    store.set_keep_going(args.keep_going);
+    store.set_skip_checks(args.skip_checks);
+    store.set_lint_only(args.lint);
+    store.set_compression(CompressionSettings {
+        codec: args.compression_format,
+        level: args.compression_level,
+        threads: args.compression_threads as usize,
+    });
+    store.set_build_scheduling(Scheduling {
+        nice: args.nice,
+        ionice: args.ionice,
+        cpuset: args.cpuset.clone(),
+    });
+    let _queue = if args.queue {
+        Some(store.enter_build_queue()?)
+    } else {
+        None
+    };
+    let webhooks = Webhooks::load(&store_base_root()?)?;
+    let started = Instant::now();
+    let result = store.build_packages(&packages, args.parallelism);
+    let elapsed = started.elapsed();
+    if !webhooks.is_empty() {
+        fire_build_webhooks(&webhooks, &store, &packages, elapsed, &result);
+    }
+    result?;
+
+    let timings = store.take_build_timings();
+    if args.timings {
+        print_build_timings(&timings, elapsed);
+    }
+    if let Some(path) = &args.timings_json {
+        let report = build_timings_json(&timings, elapsed);
+        if path == Path::new("-") {
+            println!("{report}");
+            return Ok(());
+        }
+        fs::write(path, format!("{report}\n"))?;
+    }
 
     let mut seen = HashSet::new();
     for package in packages {
@@ -227,155 +1592,2919 @@ fn run_build(args: BuildArgs) -> MagResult<()> {
     Ok(())
 }
 
-fn run_fetch(args: FetchArgs) -> MagResult<()> {
-    let manifest_value = evaluate_expression(&args.expression)?;
+/// Report each top-level target: failed when the build stopped before its
+/// artifact was published. Interrupted builds are not reported. After a
+/// `--keep-going` build, a failed target gets its own error.
+fn fire_build_webhooks(
+    webhooks: &Webhooks,
+    store: &PackageStore,
+    roots: &[Rc<Package>],
+    elapsed: Duration,
+    result: &MagResult<Vec<PathBuf>>,
+) {
+    if let Err(MagError::Interrupted(_)) = result {
+        return;
+    }
+    let error = result.as_ref().err().map(|err| err.to_string());
+    let mut seen = HashSet::new();
+    for root in roots {
+        if !seen.insert(root.hash.clone()) {
+            continue;
+        }
+        let base = package_base_name(root);
+        let published = store.is_artifact_published(root);
+        let own_error = match result {
+            Err(MagError::BuildsFailed { failures, skipped }) => failures
+                .iter()
+                .find(|(package, _)| *package == base)
+                .map(|(_, failure)| failure.to_string())
+                .or_else(|| {
+                    skipped
+                        .iter()
+                        .find(|(package, _)| *package == base)
+                        .map(|(_, dependency)| format!("not built: {dependency} failed"))
+                }),
+            _ => None,
+        };
+        webhooks.fire(&BuildEvent {
+            package: &base,
+            hash: &root.hash,
+            status: if published {
+                BuildStatus::Success
+            } else {
+                BuildStatus::Failure
+            },
+            duration: elapsed,
+            error: if published {
+                None
+            } else {
+                own_error.as_deref().or(error.as_deref())
+            },
+        });
+    }
+}
+
+/// Print the timings slowest first, so the packages worth looking at are at
+/// the top of a large graph's report.
+fn print_build_timings(timings: &[PackageTiming], elapsed: Duration) {
+    let mut sorted: Vec<&PackageTiming> = timings.iter().collect();
+    sorted.sort_by(|a, b| b.total.cmp(&a.total));
+    let width = sorted
+        .iter()
+        .map(|timing| timing.base.len())
+        .max()
+        .unwrap_or(0)
+        .max("PACKAGE".len());
+
+    let seconds = |duration: Duration| format!("{:.1}s", duration.as_secs_f64());
+    eprintln!(
+        "{:<width$}  {:<11}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>10}",
+        "PACKAGE", "SOURCE", "TOTAL", "FETCH", "EXTRACT", "SCRIPT", "PACK", "SIZE"
+    );
+    for timing in sorted {
+        eprintln!(
+            "{:<width$}  {:<11}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>10}",
+            timing.base,
+            timing.source.as_str(),
+            seconds(timing.total),
+            seconds(timing.fetch),
+            seconds(timing.extract),
+            seconds(timing.script),
+            seconds(timing.pack),
+            format_bytes(timing.artifact_bytes)
+        );
+    }
+    let built = timings
+        .iter()
+        .filter(|timing| timing.source == BuildSource::Built)
+        .count();
+    eprintln!(
+        "{} package(s), {built} built, {} total",
+        timings.len(),
+        seconds(elapsed)
+    );
+}
+
+fn build_timings_json(timings: &[PackageTiming], elapsed: Duration) -> serde_json::Value {
+    let packages: Vec<serde_json::Value> = timings
+        .iter()
+        .map(|timing| {
+            serde_json::json!({
+                "package": timing.base,
+                "source": timing.source.as_str(),
+                "totalSeconds": timing.total.as_secs_f64(),
+                "fetchSeconds": timing.fetch.as_secs_f64(),
+                "extractSeconds": timing.extract.as_secs_f64(),
+                "scriptSeconds": timing.script.as_secs_f64(),
+                "packSeconds": timing.pack.as_secs_f64(),
+                "artifactBytes": timing.artifact_bytes,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "totalSeconds": elapsed.as_secs_f64(),
+        "packages": packages,
+    })
+}
+
+/// `magpkg owns`: answer path ownership from the per-artifact file indexes.
+/// Packages are listed in export order, so for a file the last owner's copy
+/// is the one that ends up in an image.
+fn run_owns(args: OwnsArgs) -> MagResult<()> {
+    let mut builder = PackageGraphBuilder::default();
+    let packages =
+        builder.packages_from_value(evaluate_expression(&args.manifest.expression()?)?)?;
+    let closure = compute_runtime_closure(&packages);
+
+    let store = PackageStore::new()?;
+    let mut indexes = Vec::with_capacity(closure.len());
+    for package in &closure {
+        indexes.push((package_base_name(package), store.file_index(package)?));
+    }
+
+    if args.collisions {
+        let mut owners: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (base, index) in &indexes {
+            for entry in &index.entries {
+                if entry.kind != PathKind::Dir {
+                    owners.entry(&entry.path).or_default().push(base);
+                }
+            }
+        }
+        for (path, bases) in owners.iter().filter(|(_, bases)| bases.len() > 1) {
+            println!("{path}\t{}", bases.join(" "));
+        }
+        return Ok(());
+    }
+
+    let mut missing = 0;
+    for query in &args.paths {
+        let path = fileindex::normalize_query(query)
+            .ok_or_else(|| MagError::Generic(format!("invalid path {query:?}")))?;
+        let mut found = false;
+        for (base, index) in &indexes {
+            if let Some(entry) = index.get(&path) {
+                found = true;
+                match &entry.link_target {
+                    Some(target) => println!("{path}\t{base}\t{} -> {target}", entry.kind.as_str()),
+                    None => println!("{path}\t{base}\t{}", entry.kind.as_str()),
+                }
+            }
+        }
+        if found {
+            continue;
+        }
+
+        // A path under a symlinked directory (e.g. /lib -> usr/lib) is only
+        // reachable through the link, so point at it.
+        let via_link = path
+            .match_indices('/')
+            .rev()
+            .filter(|(position, _)| *position > 0)
+            .map(|(position, _)| &path[..position])
+            .find_map(|ancestor| {
+                indexes.iter().find_map(|(base, index)| {
+                    index
+                        .get(ancestor)
+                        .filter(|entry| entry.kind == PathKind::Symlink)
+                        .map(|entry| (base, entry))
+                })
+            });
+        match via_link {
+            Some((base, link)) => eprintln!(
+                "{path}: not provided directly; {} is a symlink to {} in {base}",
+                link.path,
+                link.link_target.as_deref().unwrap_or("?")
+            ),
+            None => eprintln!("{path}: not provided by any package"),
+        }
+        missing += 1;
+    }
+
+    if missing > 0 {
+        return Err(MagError::Generic(format!(
+            "{missing} path(s) not provided by any package in the closure"
+        )));
+    }
+    Ok(())
+}
+
+fn run_cat(args: CatArgs) -> MagResult<()> {
+    let mut builder = PackageGraphBuilder::default();
+    let packages =
+        builder.packages_from_value(evaluate_expression(&args.manifest.expression()?)?)?;
+    let closure = compute_runtime_closure(&packages);
+    let path = fileindex::normalize_query(&args.path)
+        .ok_or_else(|| MagError::Generic(format!("invalid path {:?}", args.path)))?;
+
+    // Later packages in the closure are unpacked over earlier ones, so the
+    // last owner provides the copy that ends up in an exported image.
+    let store = PackageStore::new()?;
+    let mut owner = None;
+    for package in &closure {
+        if let Some(entry) = store.file_index(package)?.get(&path) {
+            owner = Some((package, entry.clone()));
+        }
+    }
+    let Some((package, entry)) = owner else {
+        return Err(MagError::Generic(format!(
+            "{path}: not provided by any package in the closure"
+        )));
+    };
+    if let Some(target) = &entry.link_target {
+        return Err(MagError::Generic(format!(
+            "{path} is a symlink to {target} in {}",
+            package_base_name(package)
+        )));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    store.copy_artifact_file(package, &entry, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn run_lock(args: LockArgs) -> MagResult<()> {
+    let (manifest_value, imports) = evaluate_expression_logged(&args.manifest.expression()?)?;
     let mut builder = PackageGraphBuilder::default();
     let packages = builder.packages_from_value(manifest_value)?;
 
+    let lock = Lockfile::from_graph(&packages, &imports.borrow());
+    lock.write(&args.output)?;
+    println!(
+        "Wrote {} ({} import(s), {} fetch(es), {} package(s))",
+        args.output.display(),
+        lock.imports.len(),
+        lock.fetches.len(),
+        lock.packages.len()
+    );
+    Ok(())
+}
+
+fn run_diff(args: DiffArgs) -> MagResult<()> {
+    let old = match (&args.old, &args.old_lock) {
+        (Some(expression), _) => {
+            let mut builder = PackageGraphBuilder::default();
+            summarize_graph(&builder.packages_from_value(evaluate_expression(expression)?)?)
+        }
+        (None, Some(path)) => summarize_lockfile(&Lockfile::read(path)?),
+        (None, None) => unreachable!("clap requires --old or --old-lock"),
+    };
+    let mut builder = PackageGraphBuilder::default();
+    let new = summarize_graph(
+        &builder.packages_from_value(evaluate_expression(&args.manifest.expression()?)?)?,
+    );
+
+    let diff = diff_graphs(&old, &new);
+    if json_output() {
+        let changes: Vec<serde_json::Value> = diff
+            .changes
+            .iter()
+            .map(|change| {
+                serde_json::json!({
+                    "change": change.kind.as_str(),
+                    "name": change.name,
+                    "oldHash": change.old_hash,
+                    "newHash": change.new_hash,
+                    "reasons": change.reasons,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({ "changes": changes, "unchanged": diff.unchanged })
+        );
+        return Ok(());
+    }
+
+    for change in &diff.changes {
+        let hashes = match (&change.old_hash, &change.new_hash) {
+            (Some(old), Some(new)) => format!("{} -> {}", short_hash(old), short_hash(new)),
+            (Some(hash), None) | (None, Some(hash)) => short_hash(hash).to_string(),
+            (None, None) => String::new(),
+        };
+        if change.reasons.is_empty() {
+            println!("{:<8} {}\t{hashes}", change.kind.as_str(), change.label());
+        } else {
+            println!(
+                "{:<8} {}\t{hashes}\t{}",
+                change.kind.as_str(),
+                change.label(),
+                change.reasons.join("; ")
+            );
+        }
+    }
+    let count = |kind| {
+        diff.changes
+            .iter()
+            .filter(|change| change.kind == kind)
+            .count()
+    };
+    println!(
+        "{} added, {} removed, {} rebuilt, {} unchanged",
+        count(diff::ChangeKind::Added),
+        count(diff::ChangeKind::Removed),
+        count(diff::ChangeKind::Rebuilt),
+        diff.unchanged
+    );
+    Ok(())
+}
+
+/// `magpkg explain`: a review-friendly plan for an expression. Nothing is
+/// fetched or built; cache state comes from the local store.
+fn run_explain(args: ExplainArgs) -> MagResult<()> {
+    let mut builder = PackageGraphBuilder::default();
+    let expression = args.manifest.expression()?;
+    let packages = builder.packages_from_value(evaluate_expression(&expression)?)?;
     let store = PackageStore::new()?;
-    store.fetch_packages(&packages, args.missing_only)?;
+    let history = BuildHistory::load(&store_base_root()?);
+    print!("{}", explain_plan(&expression, &packages, &store, &history));
+    Ok(())
+}
+
+fn run_why(args: WhyArgs) -> MagResult<()> {
+    let mut builder = PackageGraphBuilder::default();
+    let roots = builder.packages_from_value(evaluate_expression(&args.manifest.expression()?)?)?;
+    let target = find_graph_package(&roots, &args.package)?;
+    let (chains, total) = dependency_chains(&roots, &target, args.runtime, args.limit as usize);
+    if chains.is_empty() {
+        let through = if args.runtime {
+            "runtime dependencies"
+        } else {
+            "any dependencies"
+        };
+        return Err(MagError::Generic(format!(
+            "{} is not reachable from the roots through {through}",
+            args.package
+        )));
+    }
+    print!("{}", format_chains(&target, &chains, total));
+    Ok(())
+}
 
+fn run_sbom(args: SbomArgs) -> MagResult<()> {
+    let mut builder = PackageGraphBuilder::default();
+    let expression = args.manifest.expression()?;
+    let packages = builder.packages_from_value(evaluate_expression(&expression)?)?;
+    let store = PackageStore::new()?;
+    let document = spdx_document(&expression, &packages, &store, args.include_build_deps);
+    match args.output {
+        Some(path) => fs::write(path, format!("{document:#}\n"))?,
+        None => println!("{document:#}"),
+    }
     Ok(())
 }
 
-fn run_cleanup(args: CleanupArgs) -> MagResult<()> {
+fn run_provenance(args: ProvenanceArgs) -> MagResult<()> {
     let store = PackageStore::new()?;
-    let seconds_per_day = 24 * 60 * 60;
-    let expiry = Duration::from_secs(args.max_age_days.saturating_mul(seconds_per_day));
-    let options = CleanupOptions {
-        packages: args.all || args.packages,
-        fetched: args.all || args.fetched,
-        torrents: args.all || args.torrents,
-        venvs: args.all || args.venvs,
+    let base = store.find_published_base(&args.package)?;
+    let Some(record) = store.package_provenance(&base)? else {
+        return Err(MagError::Generic(format!(
+            "{base} has no provenance record; it was substituted, imported, or built before provenance was recorded"
+        )));
+    };
+    if json_output() {
+        println!("{record:#}");
+        return Ok(());
+    }
+
+    let text = |value: &serde_json::Value| value.as_str().unwrap_or("-").to_string();
+    println!("package     {base}");
+    println!("sha256      {}", text(&record["sha256"]));
+    println!(
+        "built       {} on {} in {}s",
+        text(&record["startedAt"]),
+        text(&record["builder"]["host"]),
+        record["durationSeconds"]
+    );
+    println!("magpkg      {}", text(&record["builder"]["magpkgVersion"]));
+    println!("expression  {}", text(&record["expression"]));
+    for (label, kind) in [("run deps", "run"), ("build deps", "build")] {
+        for input in record["inputs"][kind].as_array().into_iter().flatten() {
+            println!(
+                "{label:<11} {} (sha256 {})",
+                text(&input["base"]),
+                text(&input["sha256"])
+            );
+        }
+    }
+    for fetch in record["inputs"]["fetch"].as_array().into_iter().flatten() {
+        println!(
+            "fetch       {} (sha256 {})",
+            text(&fetch["filename"]),
+            text(&fetch["sha256"])
+        );
+    }
+    Ok(())
+}
+
+fn run_publish_index(args: PublishIndexArgs) -> MagResult<()> {
+    let key = load_signing_key(&args.key, args.generate_key)?;
+
+    let mut builder = PackageGraphBuilder::default();
+    let snippets: Vec<(Rc<Package>, Option<String>)> = match &args.dir {
+        Some(dir) => {
+            let manifests = find_manifests(dir)?;
+            let value = evaluate_expression(&manifests_expression(&manifests)?)?;
+            manifest_snippets(value, &manifests, dir, &args.import_base, &mut builder)?
+                .into_iter()
+                .map(|(package, expression)| (package, Some(expression)))
+                .collect()
+        }
+        None => {
+            // An expression given here names paths on this machine, which
+            // consumers could not evaluate.
+            eprintln!("note: search entries are only published with --dir");
+            let value = evaluate_expression(&args.manifest.expression()?)?;
+            builder
+                .packages_from_value(value)?
+                .into_iter()
+                .map(|package| (package, None))
+                .collect()
+        }
+    };
+    let packages: Vec<Rc<Package>> = snippets
+        .iter()
+        .map(|(package, _)| package.clone())
+        .collect();
+
+    let store = PackageStore::new()?;
+    store.build_packages(&packages, args.parallelism)?;
+    let mut index = store.publish_index(&packages)?;
+    // Only named packages can be searched for.
+    for (package, expression) in snippets {
+        let Some(expression) = expression else {
+            continue;
+        };
+        if let Some(name) = &package.name {
+            index.catalog.push(IndexedPackage {
+                name: name.clone(),
+                version: package.metadata.version.clone(),
+                description: package.metadata.description.clone(),
+                expression,
+                hash: package.hash.clone(),
+            });
+        }
+    }
+
+    let bytes = index.to_signed_bytes(&key)?;
+    if let Some(parent) = args.output.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(&args.output, bytes)?;
+
+    println!(
+        "Wrote {} ({} package(s), {} fetch(es), {} searchable); signing key {}",
+        args.output.display(),
+        index.packages.len(),
+        index.fetches.len(),
+        index.catalog.len(),
+        public_key_hex(&key)
+    );
+    Ok(())
+}
+
+fn run_copy(args: CopyArgs) -> MagResult<()> {
+    let manifest_value = evaluate_expression(&args.manifest.expression()?)?;
+    let mut builder = PackageGraphBuilder::default();
+    let packages = builder.packages_from_value(manifest_value)?;
+    let bases: Vec<String> = compute_runtime_closure(&packages)
+        .iter()
+        .map(|pkg| package_base_name(pkg))
+        .collect();
+
+    let store = PackageStore::new()?;
+    if let Some(url) = &args.to {
+        let remote = SshRemote::parse(url, &args.remote_program)?;
+        let unbuilt: Vec<&str> = bases
+            .iter()
+            .filter(|base| !is_published(&store, base))
+            .map(String::as_str)
+            .collect();
+        if !unbuilt.is_empty() {
+            return Err(MagError::Generic(format!(
+                "not in the local store: {}; build the closure first",
+                unbuilt.join(", ")
+            )));
+        }
+        let missing = remote.missing(&bases)?;
+        let size: u64 = missing
+            .iter()
+            .filter_map(|base| {
+                fs::metadata(store.package_root().join(format!("{base}.tar.zst"))).ok()
+            })
+            .map(|meta| meta.len())
+            .sum();
+        if !missing.is_empty() {
+            remote.push(&store, &missing)?;
+        }
+        println!(
+            "copied {} of {} artifact(s) ({}) to {}",
+            missing.len(),
+            bases.len(),
+            format_bytes(size),
+            remote.url()
+        );
+    } else if let Some(url) = &args.from {
+        let remote = SshRemote::parse(url, &args.remote_program)?;
+        let missing: Vec<String> = bases
+            .iter()
+            .filter(|base| !is_published(&store, base))
+            .cloned()
+            .collect();
+        let received = if missing.is_empty() {
+            Vec::new()
+        } else {
+            remote.pull(&store, &missing)?
+        };
+        println!(
+            "copied {} of {} artifact(s) from {}",
+            received.len(),
+            bases.len(),
+            remote.url()
+        );
+    }
+    Ok(())
+}
+
+fn run_copy_server(args: CopyServerArgs) -> MagResult<()> {
+    copy::serve(&PackageStore::new()?, args.mode)
+}
+
+fn run_search(args: SearchArgs) -> MagResult<()> {
+    if is_offline() {
+        return Err(MagError::Generic(
+            "search reads the configured indexes, which --offline skips".into(),
+        ));
+    }
+    let store = PackageStore::new()?;
+    let indexes = store.indexes();
+    let mut found: Vec<(&IndexedPackage, bool)> = Vec::new();
+    for index in indexes {
+        for entry in index
+            .catalog
+            .iter()
+            .filter(|entry| entry.matches(&args.query))
+        {
+            if !found.iter().any(|(seen, _)| *seen == entry) {
+                let prebuilt = indexes
+                    .iter()
+                    .any(|index| index.packages.contains_key(&entry.hash));
+                found.push((entry, prebuilt));
+            }
+        }
+    }
+    // Exact name matches first, then name matches, then description matches.
+    let query = args.query.to_lowercase();
+    found.sort_by_key(|(entry, _)| {
+        let name = entry.name.to_lowercase();
+        (name != query, !name.contains(&query), *entry)
+    });
+
+    if json_output() {
+        let entries: Vec<serde_json::Value> = found
+            .iter()
+            .map(|(entry, prebuilt)| {
+                serde_json::json!({
+                    "name": entry.name,
+                    "version": entry.version,
+                    "description": entry.description,
+                    "expression": entry.expression,
+                    "hash": entry.hash,
+                    "prebuilt": prebuilt,
+                })
+            })
+            .collect();
+        println!("{:#}", serde_json::Value::Array(entries));
+        return Ok(());
+    }
+
+    if found.is_empty() {
+        if indexes.is_empty() {
+            eprintln!("No indexes are configured; list them in {INDEXES_ENV}");
+        } else {
+            eprintln!("No packages match {:?}", args.query);
+        }
+        return Ok(());
+    }
+    for (entry, prebuilt) in found {
+        let version = entry.version.as_deref().unwrap_or("-");
+        let prebuilt = if prebuilt { " (prebuilt)" } else { "" };
+        println!("{} {version}{prebuilt}", entry.name);
+        if let Some(description) = &entry.description {
+            println!("    {description}");
+        }
+        println!("    {}", entry.expression);
+    }
+    Ok(())
+}
+
+fn check_frozen(args: &FrozenArgs, packages: &[Rc<Package>], imports: &ImportLog) -> MagResult<()> {
+    if !args.frozen {
+        return Ok(());
+    }
+
+    let expected = Lockfile::read(&args.lockfile)?;
+    let actual = Lockfile::from_graph(packages, &imports.borrow());
+    let deviations = expected.deviations(&actual);
+    if deviations.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "evaluated graph deviates from {} (run `magpkg lock` to update it):",
+        args.lockfile.display()
+    );
+    for deviation in deviations {
+        message.push_str("\n  ");
+        message.push_str(&deviation);
+    }
+    Err(MagError::LockfileMismatch(message))
+}
+
+fn run_fetch(args: FetchArgs) -> MagResult<()> {
+    let expression = manifest_expression(args.expression, args.file)?;
+    let packages = match &expression {
+        Some(expression) => {
+            let manifest_value = evaluate_expression(expression)?;
+            let mut builder = PackageGraphBuilder::default();
+            builder.packages_from_value(manifest_value)?
+        }
+        None => Vec::new(),
+    };
+
+    let mut store = PackageStore::new()?;
+    if !args.link_from.is_empty() {
+        let sources = LinkSources::scan(&args.link_from)?;
+        eprintln!("indexed {} files for --link-from", sources.file_count());
+        store.set_link_sources(sources);
+    }
+    if args.refresh_torrents && args.check_deterministic {
+        let stats = store.refresh_torrents(&packages, true)?;
+        println!(
+            "Torrents checked: {} match the stored copy, {} differ, {} skipped.",
+            stats.unchanged, stats.regenerated, stats.skipped
+        );
+        if stats.nondeterministic > 0 {
+            return Err(MagError::Generic(format!(
+                "{} torrent(s) differ between two generations",
+                stats.nondeterministic
+            )));
+        }
+        return Ok(());
+    }
+    if args.refresh_torrents {
+        let stats = store.refresh_torrents(&packages, false)?;
+        println!(
+            "Torrents refreshed: {} regenerated, {} unchanged, {} skipped.",
+            stats.regenerated, stats.unchanged, stats.skipped
+        );
+        if stats.skipped > 0 && expression.is_none() {
+            println!("  Pass -e with the manifest to identify skipped fetches.");
+        }
+        return Ok(());
+    }
+    if args.print_urls {
+        return print_fetch_sources(&store, &packages, args.missing_only, args.check);
+    }
+    store.fetch_packages(&packages, args.missing_only)?;
+
+    Ok(())
+}
+
+/// `magpkg fetch --print-urls [--check]`.
+fn print_fetch_sources(
+    store: &PackageStore,
+    packages: &[Rc<Package>],
+    missing_only: bool,
+    check: bool,
+) -> MagResult<()> {
+    if check && is_offline() {
+        return Err(MagError::Generic(
+            "--check probes sources over the network and cannot run with --offline".into(),
+        ));
+    }
+    let planned = store.planned_fetches(packages, missing_only);
+    let statuses = if check {
+        probe_fetches(store, &planned)
+    } else {
+        planned.iter().map(|_| Vec::new()).collect()
+    };
+
+    if json_output() {
+        let report: Vec<serde_json::Value> = planned
+            .iter()
+            .zip(&statuses)
+            .map(|(fetch, statuses)| {
+                let sources: Vec<serde_json::Value> = fetch
+                    .sources
+                    .iter()
+                    .enumerate()
+                    .map(|(index, url)| {
+                        let mut source = serde_json::json!({ "url": url });
+                        if let Some(status) = statuses.get(index) {
+                            source["alive"] = status.is_alive().into();
+                            match status {
+                                SourceStatus::Alive { size } => source["bytes"] = (*size).into(),
+                                SourceStatus::Peers(peers) => source["peers"] = (*peers).into(),
+                                SourceStatus::Dead(reason) => {
+                                    source["error"] = reason.as_str().into()
+                                }
+                            }
+                        }
+                        source
+                    })
+                    .collect();
+                serde_json::json!({
+                    "filename": fetch.filename,
+                    "sha256": fetch.sha256,
+                    "cached": fetch.cached,
+                    "sources": sources,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(report));
+    } else {
+        for (fetch, statuses) in planned.iter().zip(&statuses) {
+            let cached = if fetch.cached { " (cached)" } else { "" };
+            println!("{} {}{cached}", fetch.filename, fetch.sha256);
+            for (index, url) in fetch.sources.iter().enumerate() {
+                match statuses.get(index) {
+                    None => println!("  {url}"),
+                    Some(SourceStatus::Alive { size }) => match size {
+                        Some(size) => println!("  ok    {url} ({})", format_bytes(*size)),
+                        None => println!("  ok    {url}"),
+                    },
+                    Some(SourceStatus::Peers(0)) => println!("  dead  {url}: no peers on the DHT"),
+                    Some(SourceStatus::Peers(peers)) => println!("  ok    {url} ({peers} peers)"),
+                    Some(SourceStatus::Dead(reason)) => println!("  dead  {url}: {reason}"),
+                }
+            }
+            if fetch.sources.is_empty() {
+                println!("  (no sources)");
+            }
+        }
+    }
+
+    if !check {
+        return Ok(());
+    }
+    let dead_sources: usize = statuses
+        .iter()
+        .flatten()
+        .filter(|status| !status.is_alive())
+        .count();
+    let unreachable: Vec<&str> = planned
+        .iter()
+        .zip(&statuses)
+        .filter(|(_, statuses)| !statuses.iter().any(SourceStatus::is_alive))
+        .map(|(fetch, _)| fetch.filename.as_str())
+        .collect();
+    eprintln!(
+        "{} fetch(es), {dead_sources} dead source(s), {} fetch(es) without a live source",
+        planned.len(),
+        unreachable.len()
+    );
+    if unreachable.is_empty() {
+        return Ok(());
+    }
+    Err(MagError::Generic(format!(
+        "no live source for {}",
+        unreachable.join(", ")
+    )))
+}
+
+fn run_cleanup(args: CleanupArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    if let Some(min_free) = args.min_free {
+        return run_reclaim(&store, &args, min_free);
+    }
+    let stats = store.cleanup(args.expiry(), args.options())?;
+
+    println!("Cleanup completed (max age: {} day(s)).", args.max_age_days);
+
+    if stats.package_artifacts_removed
+        + stats.package_build_dirs_removed
+        + stats.package_lock_files_removed
+        > 0
+    {
+        println!(
+            "  Package artifacts removed: {}, build dirs: {}, lock files: {}",
+            stats.package_artifacts_removed,
+            stats.package_build_dirs_removed,
+            stats.package_lock_files_removed
+        );
+    }
+
+    if stats.fetch_files_removed + stats.fetch_partials_removed + stats.fetch_lock_files_removed > 0
+    {
+        println!(
+            "  Fetch files removed: {}, partials: {}, lock files: {}",
+            stats.fetch_files_removed, stats.fetch_partials_removed, stats.fetch_lock_files_removed
+        );
+    }
+
+    if stats.venv_rootfs_removed > 0 {
+        println!("  Venv rootfs removed: {}", stats.venv_rootfs_removed);
+    }
+
+    if stats.unpacked_dirs_removed > 0 {
+        println!(
+            "  Unpacked artifacts removed: {}",
+            stats.unpacked_dirs_removed
+        );
+    }
+
+    if args.venv_homes {
+        let removed = store.cleanup_venv_homes(args.expiry())?;
+        if removed > 0 {
+            println!("  Venv homes removed: {removed}");
+        }
+    }
+
+    Ok(())
+}
+
+/// `magpkg remove`: delete the selected artifacts under their locks. Edges
+/// come from the store database, or the `.deps` records it indexes, which
+/// are written at build time, so packages built before those existed are
+/// neither found as dependents nor protect fetches.
+fn run_remove(args: RemoveArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let mut targets = BTreeSet::new();
+    for query in &args.packages {
+        targets.extend(store.match_published_bases(query)?);
+    }
+
+    let (records, unrecorded) = store.dependency_records()?;
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (base, record) in &records {
+        for dep in record.run.iter().chain(&record.build) {
+            dependents.entry(dep).or_default().push(base);
+        }
+    }
+
+    if args.recursive {
+        let mut queue: Vec<String> = targets.iter().cloned().collect();
+        while let Some(base) = queue.pop() {
+            for dependent in dependents.get(base.as_str()).into_iter().flatten() {
+                if targets.insert(dependent.to_string()) {
+                    queue.push(dependent.to_string());
+                }
+            }
+        }
+        if unrecorded > 0 {
+            eprintln!(
+                "note: {unrecorded} artifact(s) were built before dependencies were recorded and are not checked for dependents"
+            );
+        }
+    } else {
+        for base in &targets {
+            let kept: Vec<&str> = dependents
+                .get(base.as_str())
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|dependent| !targets.contains(*dependent))
+                .collect();
+            if let Some(first) = kept.first() {
+                eprintln!(
+                    "note: {} published package(s) depend on {base}, e.g. {first}; they are kept (use --recursive to remove them too)",
+                    kept.len()
+                );
+            }
+        }
+    }
+
+    let mut removed = BTreeSet::new();
+    let mut in_use = 0usize;
+    for base in &targets {
+        if args.dry_run {
+            println!("would remove {base}");
+            removed.insert(base.as_str());
+            continue;
+        }
+        let hash = records.get(base).map(|record| record.hash.as_str());
+        if store.remove_package(base, hash)? {
+            println!("removed {base}");
+            removed.insert(base.as_str());
+        } else {
+            eprintln!("skipping {base}; another process is using it");
+            in_use += 1;
+        }
+    }
+
+    if args.fetches {
+        let kept: HashSet<&str> = records
+            .iter()
+            .filter(|(base, _)| !removed.contains(base.as_str()))
+            .flat_map(|(_, record)| record.fetches.iter().map(String::as_str))
+            .collect();
+        let orphaned: BTreeSet<&str> = removed
+            .iter()
+            .filter_map(|base| records.get(*base))
+            .flat_map(|record| record.fetches.iter().map(String::as_str))
+            .filter(|sha| !kept.contains(sha) && store.fetch_root().join(sha).is_file())
+            .collect();
+        for sha in orphaned {
+            if args.dry_run {
+                println!("would remove fetch {sha}");
+            } else if store.remove_fetch(sha)? {
+                println!("removed fetch {sha}");
+            } else {
+                eprintln!("skipping fetch {sha}; another process is using it");
+            }
+        }
+    }
+
+    if in_use > 0 {
+        return Err(MagError::Generic(format!(
+            "{in_use} package(s) were in use and not removed"
+        )));
+    }
+    Ok(())
+}
+
+fn run_repair() -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let stats = store.repair()?;
+
+    println!("Repair completed.");
+    let journal = &stats.journal;
+    if journal.operations_rolled_back + journal.operations_deferred > 0 {
+        println!(
+            "  Interrupted operations rolled back: {}, paths removed: {}, still locked: {}",
+            journal.operations_rolled_back, journal.paths_removed, journal.operations_deferred
+        );
+    }
+    if stats.fetch_partials_removed > 0 {
+        println!("  Fetch partials removed: {}", stats.fetch_partials_removed);
+    }
+    if stats.package_partials_removed
+        + stats.package_build_dirs_removed
+        + stats.package_sidecars_removed
+        > 0
+    {
+        println!(
+            "  Package partials removed: {}, build dirs: {}, orphaned sidecars: {}",
+            stats.package_partials_removed,
+            stats.package_build_dirs_removed,
+            stats.package_sidecars_removed
+        );
+    }
+    if stats.venv_staging_dirs_removed > 0 {
+        println!(
+            "  Partial venv rootfs removed: {}",
+            stats.venv_staging_dirs_removed
+        );
+    }
+    if stats.torrents_skipped {
+        println!("  Torrent directories skipped: other magpkg processes are using the store");
+    } else if stats.torrent_partials_removed + stats.torrent_dirs_removed > 0 {
+        println!(
+            "  Torrent partials removed: {}, incomplete torrent dirs: {}",
+            stats.torrent_partials_removed, stats.torrent_dirs_removed
+        );
+    }
+
+    Ok(())
+}
+
+/// `magpkg cleanup --min-free`: a no-op while enough space is available, so
+/// it can run from a frequent timer.
+fn run_reclaim(store: &PackageStore, args: &CleanupArgs, min_free: u64) -> MagResult<()> {
+    let target_free = args.target_free(min_free);
+    let outcome = store.reclaim_space(min_free, target_free, args.options(), &pinned_venvs()?)?;
+    if outcome.available_before >= min_free {
+        println!(
+            "{} available, at least {} required; nothing to clean up.",
+            format_bytes(outcome.available_before),
+            format_bytes(min_free)
+        );
+        return Ok(());
+    }
+
+    let stats = &outcome.stats;
+    println!(
+        "Reclaimed {} ({} -> {} available, target {}).",
+        format_bytes(
+            outcome
+                .available_after
+                .saturating_sub(outcome.available_before)
+        ),
+        format_bytes(outcome.available_before),
+        format_bytes(outcome.available_after),
+        format_bytes(target_free)
+    );
+    println!(
+        "  Package artifacts: {}, unpacked artifacts: {}, fetch files: {}, venvs: {}, torrents: {}",
+        stats.package_artifacts_removed,
+        stats.unpacked_dirs_removed,
+        stats.fetch_files_removed,
+        stats.venv_rootfs_removed,
+        stats.torrent_dirs_removed
+    );
+    if outcome.available_after < target_free {
+        println!("  Target not reached; remaining entries are in use or pinned.");
+    }
+    Ok(())
+}
+
+/// Root filesystem hashes of venvs registered as profiles, which
+/// `cleanup --min-free` never removes. A profile that fails to load is
+/// reported, and its venv stays pinned when its `rootfs` entry is readable.
+fn pinned_venvs() -> MagResult<HashSet<String>> {
+    let base_root = store_base_root()?;
+    let mut pinned = HashSet::new();
+    for name in profiles::list_profiles(&base_root)? {
+        match VenvProfile::load(&base_root, &name) {
+            Ok(profile) => {
+                pinned.insert(profile.spec.rootfs_hash);
+            }
+            Err(err) => {
+                eprintln!("warning: skipping venv profile {name}: {err}");
+                match profiles::profile_rootfs_hash(&base_root, &name) {
+                    Some(hash) => {
+                        pinned.insert(hash);
+                    }
+                    None => eprintln!(
+                        "warning: venv profile {name} names no rootfs; its venv is not pinned"
+                    ),
+                }
+            }
+        }
+    }
+    Ok(pinned)
+}
+
+fn run_du(args: DuArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let cleanup = &args.policy;
+    let policy = match cleanup.min_free {
+        Some(min_free) => UsagePolicy::MinFree {
+            min_free,
+            target_free: cleanup.target_free(min_free),
+        },
+        None => UsagePolicy::MaxAge(cleanup.expiry()),
+    };
+    let usage = store.disk_usage(policy, cleanup.options(), &pinned_venvs()?)?;
+
+    let categories = ["packages", "unpacked", "fetched", "torrents", "venvs"];
+    let mut totals: BTreeMap<&str, (u64, u64, u64)> = BTreeMap::new();
+    for entry in &usage.entries {
+        let total = totals.entry(entry.category).or_default();
+        total.0 += 1;
+        total.1 += entry.bytes;
+        if entry.reclaimable {
+            total.2 += entry.bytes;
+        }
+    }
+
+    // A package's size includes its extracted copy under unpacked/.
+    let hashes = store.published_hashes()?;
+    let unpacked: HashMap<&str, u64> = usage
+        .entries
+        .iter()
+        .filter(|entry| entry.category == "unpacked")
+        .map(|entry| (entry.name.as_str(), entry.bytes))
+        .collect();
+    let mut packages: Vec<(&str, u64, u64, bool)> = usage
+        .entries
+        .iter()
+        .filter(|entry| entry.category == "packages")
+        .map(|entry| {
+            let extracted = hashes
+                .get(&entry.name)
+                .and_then(|hash| unpacked.get(hash.as_str()))
+                .copied()
+                .unwrap_or(0);
+            (
+                entry.name.as_str(),
+                entry.bytes,
+                extracted,
+                entry.reclaimable,
+            )
+        })
+        .collect();
+    packages.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(b.0)));
+
+    let total_bytes: u64 = totals.values().map(|total| total.1).sum();
+    let total_reclaimable: u64 = totals.values().map(|total| total.2).sum();
+
+    if json_output() {
+        let mut report = serde_json::Map::new();
+        for category in categories {
+            let (entries, bytes, reclaimable) = totals.get(category).copied().unwrap_or_default();
+            report.insert(
+                category.to_string(),
+                serde_json::json!({
+                    "entries": entries,
+                    "bytes": bytes,
+                    "reclaimable_bytes": reclaimable,
+                }),
+            );
+        }
+        report.insert(
+            "total".to_string(),
+            serde_json::json!({
+                "bytes": total_bytes,
+                "reclaimable_bytes": total_reclaimable,
+                "available_bytes": usage.available,
+            }),
+        );
+        let rows: Vec<serde_json::Value> = packages
+            .iter()
+            .map(|(name, artifact, extracted, reclaimable)| {
+                serde_json::json!({
+                    "name": name,
+                    "artifact_bytes": artifact,
+                    "unpacked_bytes": extracted,
+                    "reclaimable": reclaimable,
+                })
+            })
+            .collect();
+        report.insert("packages_by_size".to_string(), rows.into());
+        println!("{}", serde_json::Value::Object(report));
+        return Ok(());
+    }
+
+    println!(
+        "{:<10} {:>8} {:>12} {:>12}",
+        "CATEGORY", "ENTRIES", "SIZE", "RECLAIMABLE"
+    );
+    for category in categories {
+        let (entries, bytes, reclaimable) = totals.get(category).copied().unwrap_or_default();
+        println!(
+            "{category:<10} {entries:>8} {:>12} {:>12}",
+            format_bytes(bytes),
+            format_bytes(reclaimable)
+        );
+    }
+    println!(
+        "{:<10} {:>8} {:>12} {:>12}",
+        "total",
+        usage.entries.len(),
+        format_bytes(total_bytes),
+        format_bytes(total_reclaimable)
+    );
+    println!(
+        "{} available on the store filesystem.",
+        format_bytes(usage.available)
+    );
+
+    if !packages.is_empty() && args.top > 0 {
+        println!();
+        println!("Largest packages (* would be removed by cleanup with these flags):");
+        for (name, artifact, extracted, reclaimable) in packages.iter().take(args.top) {
+            let marker = if *reclaimable { '*' } else { ' ' };
+            if *extracted > 0 {
+                println!(
+                    "{marker} {:>10}  {name} ({} unpacked)",
+                    format_bytes(artifact + extracted),
+                    format_bytes(*extracted)
+                );
+            } else {
+                println!("{marker} {:>10}  {name}", format_bytes(*artifact));
+            }
+        }
+        if packages.len() > args.top {
+            println!("  ... {} more", packages.len() - args.top);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a byte count with an optional binary suffix: `512M`, `20G`, `1T`.
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits_end);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {value:?}"))?;
+    let shift = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => {
+            return Err(format!(
+                "invalid size suffix in {value:?}; use K, M, G, or T"
+            ));
+        }
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {value:?} is too large"))
+}
+
+fn run_serve(args: ServeArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let signing_key = match &args.sign_key {
+        Some(path) => {
+            let key = load_signing_key(path, args.generate_key)?;
+            eprintln!("signing publish records with key {}", public_key_hex(&key));
+            Some(key)
+        }
+        None => None,
+    };
+    CacheServer {
+        package_root: store.package_root().to_path_buf(),
+        fetch_root: store.fetch_root().to_path_buf(),
+        torrent_root: store.torrent_root().to_path_buf(),
+        signing_key,
+    }
+    .serve(&args.listen)
+}
+
+fn run_seed(args: SeedArgs) -> MagResult<()> {
+    if is_offline() {
+        return Err(MagError::Generic(
+            "magpkg seed shares files with peers over the network; it cannot run with --offline"
+                .into(),
+        ));
+    }
+    let store = PackageStore::new()?;
+    let mut seed_config = store.torrent_config().clone();
+    if let Some(hours) = args.verify_interval {
+        seed_config.verify_interval = verify_interval_from_hours(hours);
+    }
+    let mut seeder = TorrentSeeder::new(store.torrent_root().to_path_buf(), seed_config)?;
+    seeder.set_store_root_enabled(!args.no_store_torrents);
+    seeder.set_max_storage(args.max_storage);
+    seeder.set_verify(!args.no_verify);
+    for dir in args.torrent_dirs {
+        seeder.add_watch_root(dir)?;
+    }
+
+    let config = store.torrent_config();
+    let listen = if args.no_listen {
+        None
+    } else {
+        let ip = args
+            .listen_addr
+            .or(config.listen_addr)
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip);
+        let port = args
+            .listen_addr
+            .and_then(|addr| addr.port)
+            .or(args.listen_port)
+            .or(config.listen_addr.and_then(|addr| addr.port))
+            .unwrap_or(DEFAULT_SEED_PORT);
+        Some(SocketAddr::new(ip, port))
+    };
+
+    if args.announce {
+        let mut peers = args.announce_addrs.clone();
+        // A concrete public address is where peers reach us; a wildcard or
+        // loopback address says nothing about that.
+        if let Some(addr) = listen {
+            if addr.port() != 0 && is_announceable(addr.ip()) {
+                peers.push(addr);
+            }
+        }
+        print_announcements(&store, &seeder, false, &peers)?;
+    }
+
+    seeder.run(listen)
+}
+
+fn parse_listen_addr(value: &str) -> Result<ListenAddr, String> {
+    ListenAddr::parse(value).ok_or_else(|| format!("invalid listen address {value:?}"))
+}
+
+fn is_announceable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_unspecified() || ip.is_loopback() || ip.is_link_local()),
+        // Link-local IPv6 addresses need a zone, which magnet links cannot carry.
+        IpAddr::V6(ip) => !(ip.is_unspecified() || ip.is_loopback() || ip.is_unicast_link_local()),
+    }
+}
+
+fn run_torrents(args: TorrentsArgs) -> MagResult<()> {
+    match args.command {
+        TorrentsCommands::List(args) => {
+            let store = PackageStore::new()?;
+            let mut seeder = TorrentSeeder::new(
+                store.torrent_root().to_path_buf(),
+                store.torrent_config().clone(),
+            )?;
+            for dir in args.torrent_dirs {
+                seeder.add_watch_root(dir)?;
+            }
+            print_announcements(&store, &seeder, json_output(), &[])
+        }
+    }
+}
+
+fn run_doctor() -> MagResult<()> {
+    let base_root = store_base_root()?;
+    let capabilities = probe_capabilities(&TorrentConfig::load(&base_root)?);
+
+    if json_output() {
+        let array: Vec<serde_json::Value> = capabilities
+            .iter()
+            .map(|capability| {
+                serde_json::json!({
+                    "name": capability.name,
+                    "status": capability.status.as_str(),
+                    "detail": capability.detail,
+                    "affects": capability.affects,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(array));
+    } else {
+        for capability in &capabilities {
+            println!(
+                "{:<16} {:<12} {}",
+                capability.name,
+                capability.status.as_str(),
+                capability.detail
+            );
+            if capability.status != CapabilityStatus::Available {
+                println!("{:<16} {:<12} affects {}", "", "", capability.affects);
+            }
+        }
+    }
+
+    let unavailable: Vec<&str> = capabilities
+        .iter()
+        .filter(|capability| capability.status == CapabilityStatus::Unavailable)
+        .map(|capability| capability.name)
+        .collect();
+    if !unavailable.is_empty() {
+        return Err(MagError::Generic(format!(
+            "unavailable on this host: {}",
+            unavailable.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Print a magnet link for each torrent the seeder would serve, including
+/// configured trackers, the webseeds recorded when the source was fetched, and
+/// the store's `announce-addr` peers followed by `extra_peers`.
+fn print_announcements(
+    store: &PackageStore,
+    seeder: &TorrentSeeder,
+    json: bool,
+    extra_peers: &[SocketAddr],
+) -> MagResult<()> {
+    let config = store.torrent_config();
+    let trackers = config.announce_trackers();
+    let mut peers = config.announce_addrs.clone();
+    for peer in extra_peers {
+        if !peers.contains(peer) {
+            peers.push(*peer);
+        }
+    }
+    let mut entries = Vec::new();
+    for torrent in seeder.seedable() {
+        let sources = if torrent
+            .torrent_dir
+            .file_name()
+            .is_some_and(|name| name == torrent.info_hash.as_str())
+        {
+            read_torrent_sources(&torrent.torrent_dir)
+        } else {
+            TorrentSources::default()
+        };
+        let magnet = magnet_with_sources(
+            &torrent.info_hash,
+            &torrent.display_name,
+            trackers,
+            &sources.webseeds,
+            &peers,
+        );
+        entries.push((torrent, sources, magnet));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        let array: Vec<serde_json::Value> = entries
+            .iter()
+            .map(|(torrent, sources, magnet)| {
+                serde_json::json!({
+                    "info_hash": torrent.info_hash,
+                    "name": torrent.display_name,
+                    "sha256": sources.sha256,
+                    "magnet": magnet,
+                    "webseeds": sources.webseeds,
+                })
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::Value::Array(array))?;
+        return Ok(());
+    }
+
+    for (torrent, sources, magnet) in &entries {
+        writeln!(out, "{} {}", torrent.info_hash, torrent.display_name)?;
+        if let Some(sha) = &sources.sha256 {
+            writeln!(out, "  sha256:  {sha}")?;
+        }
+        writeln!(out, "  magnet:  {magnet}")?;
+        for webseed in &sources.webseeds {
+            writeln!(out, "  webseed: {webseed}")?;
+        }
+    }
+    Ok(())
+}
+
+fn run_export_tarball(args: ExportTarballArgs) -> MagResult<()> {
+    if args.check_deterministic {
+        if let Some(output) = &args.output {
+            check_deterministic_output(output)?;
+        }
+    }
+    let manifest_value = evaluate_expression(&args.manifest.expression()?)?;
+    let mut builder = PackageGraphBuilder::default();
+    let packages = builder.packages_from_value(manifest_value)?;
+
+    let store = PackageStore::new()?;
+    store.build_packages(&packages, args.parallelism)?;
+
+    let export = |path: Option<&Path>| -> MagResult<()> {
+        let mut writer: Box<dyn Write> = match path {
+            Some(path) if path != Path::new("-") => {
+                if let Some(parent) = path.parent() {
+                    if !parent.as_os_str().is_empty() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                }
+                Box::new(io::BufWriter::new(File::create(path)?))
+            }
+            _ => Box::new(io::stdout().lock()),
+        };
+        if args.concat {
+            store
+                .export_runtime_closure_concat(&packages, &mut writer)?
+                .flush()?;
+        } else {
+            store.export_runtime_closure_tarball(&packages, &mut writer, args.provenance)?;
+        }
+        Ok(())
+    };
+    export(args.output.as_deref())?;
+    if args.check_deterministic {
+        if let Some(output) = &args.output {
+            check_deterministic(output, |path| export(Some(path)))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject `--check-deterministic` with `-o -` before anything is built:
+/// stdout cannot be read back for the comparison.
+fn check_deterministic_output(output: &Path) -> MagResult<()> {
+    if output == Path::new("-") {
+        return Err(MagError::Generic(
+            "--check-deterministic needs an output file, not stdout".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// `--check-deterministic`: export once more into a temporary file next to
+/// `output` and fail unless it is byte-identical to `output`.
+fn check_deterministic(
+    output: &Path,
+    export: impl FnOnce(&Path) -> MagResult<()>,
+) -> MagResult<()> {
+    check_deterministic_output(output)?;
+    let dir = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let second = tempfile::Builder::new()
+        .prefix(".magpkg-check-")
+        .tempfile_in(dir)?
+        .into_temp_path();
+    export(&second)?;
+
+    let expected = sha256_file(output)?;
+    let actual = sha256_file(&second)?;
+    if expected != actual {
+        return Err(MagError::Generic(format!(
+            "{} is not reproducible: a second export hashes to {actual} instead of {expected}",
+            output.display()
+        )));
+    }
+    eprintln!("{} is reproducible (sha256 {expected})", output.display());
+    Ok(())
+}
+
+fn run_export_image(args: ExportImageArgs) -> MagResult<()> {
+    let manifest_value = evaluate_expression(&args.manifest.expression()?)?;
+    let mut builder = PackageGraphBuilder::default();
+    let packages = builder.packages_from_value(manifest_value)?;
+
+    let store = PackageStore::new()?;
+    store.build_packages(&packages, args.parallelism)?;
+
+    if let Some(parent) = args.output.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    write_image(&store, &packages, args.format, &args.output)?;
+    if args.check_deterministic {
+        check_deterministic(&args.output, |path| {
+            write_image(&store, &packages, args.format, path)
+        })?;
+    }
+    println!(
+        "Wrote {} image to {}",
+        args.format.label(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Stage the runtime closure of `packages` and pack it into an image at
+/// `output`. Timestamps are pinned to the epoch and, for erofs, the volume
+/// UUID is derived from the package hashes, so the same closure always
+/// yields the same image.
+fn write_image(
+    store: &PackageStore,
+    packages: &[Rc<Package>],
+    format: ImageFormat,
+    output: &Path,
+) -> MagResult<()> {
+    let staging = tempfile::Builder::new().prefix("magpkg-image-").tempdir()?;
+    let rootfs = staging.path().join("rootfs");
+    store.export_runtime_closure_rootfs(packages, &rootfs)?;
+
+    let tmp_output = output.with_extension("img.tmp");
+    if tmp_output.exists() {
+        fs::remove_file(&tmp_output)?;
+    }
+
+    let (program, mut cmd) = match format {
+        ImageFormat::Squashfs => {
+            let mut cmd = Command::new("mksquashfs");
+            cmd.arg(&rootfs).arg(&tmp_output).args([
+                "-noappend",
+                "-comp",
+                "zstd",
+                "-all-root",
+                "-all-time",
+                "0",
+                "-mkfs-time",
+                "0",
+                // Build hosts label files differently (SELinux, capabilities),
+                // so extended attributes would make images differ.
+                "-no-xattrs",
+                "-quiet",
+            ]);
+            ("mksquashfs", cmd)
+        }
+        ImageFormat::Erofs => {
+            let mut hasher = Sha256::new();
+            for package in packages {
+                hasher.update(package.hash.as_bytes());
+            }
+            let digest = hex::encode(&hasher.finalize()[..16]);
+            let uuid = format!(
+                "{}-{}-{}-{}-{}",
+                &digest[..8],
+                &digest[8..12],
+                &digest[12..16],
+                &digest[16..20],
+                &digest[20..]
+            );
+            let mut cmd = Command::new("mkfs.erofs");
+            cmd.args(["-zlz4hc", "--all-root", "-T0", "-U"])
+                .arg(uuid)
+                .arg(&tmp_output)
+                .arg(&rootfs);
+            ("mkfs.erofs", cmd)
+        }
+    };
+
+    let status = match cmd.status() {
+        Ok(status) => status,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(MagError::Generic(format!(
+                "{program} not found on PATH; install it to export {} images",
+                format.label()
+            )));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if !status.success() {
+        let _ = fs::remove_file(&tmp_output);
+        return Err(MagError::CommandFailure {
+            context: program.to_string(),
+            status: status.code().unwrap_or(-1),
+        });
+    }
+
+    fs::rename(&tmp_output, output)?;
+    Ok(())
+}
+
+fn run_export_machine(args: ExportMachineArgs) -> MagResult<()> {
+    validate_machine_name(&args.name)?;
+    // Importing writes under /var/lib/machines and /etc, so fail before the
+    // build rather than after it.
+    // SAFETY: geteuid has no preconditions.
+    if args.import && unsafe { libc::geteuid() } != 0 {
+        return Err(MagError::Generic(
+            "export-machine --import must run as root; use --output to write the machine elsewhere"
+                .into(),
+        ));
+    }
+    let expression = args.manifest.expression()?;
+    let manifest_value = evaluate_expression(&expression)?;
+    let mut builder = PackageGraphBuilder::default();
+    let spec = VenvSpec::from_value(manifest_value, &mut builder)?;
+
+    let store = PackageStore::new()?;
+    store.build_packages(&spec.packages, args.parallelism)?;
+
+    let (settings, warnings) = nspawn_settings(&spec, &expression);
+    for warning in &warnings {
+        eprintln!("warning: {warning}");
+    }
+    let settings_name = format!("{}.nspawn", args.name);
+
+    // Materialize the closure, its fsEntries, and an os-release into `rootfs`.
+    let materialize = |rootfs: &Path| -> MagResult<()> {
+        store.export_runtime_closure_rootfs(&spec.packages, rootfs)?;
+        apply_fs_entries(rootfs, &spec.fs_entries)?;
+        ensure_os_release(rootfs, &args.name)
+    };
+
+    if let Some(output) = &args.output {
+        let rootfs = output.join(&args.name);
+        let settings_path = output.join(&settings_name);
+        if fs::symlink_metadata(&rootfs).is_ok() {
+            return Err(MagError::Generic(format!(
+                "{} already exists; remove it or pick another --name",
+                rootfs.display()
+            )));
+        }
+        fs::create_dir_all(output)?;
+        let staging = output.join(format!(".{}.tmp", args.name));
+        // Left behind by an interrupted export.
+        match fs::remove_dir_all(&staging) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        if let Err(err) = materialize(&staging) {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(err);
+        }
+        fs::rename(&staging, &rootfs)?;
+        fs::write(&settings_path, settings)?;
+        println!(
+            "Wrote machine {} to {} with settings {}",
+            args.name,
+            rootfs.display(),
+            settings_path.display()
+        );
+        println!(
+            "Copy {} to {NSPAWN_SETTINGS_DIR}/ for its bind mounts to apply.",
+            settings_path.display()
+        );
+        return Ok(());
+    }
+
+    let staging = tempfile::Builder::new()
+        .prefix("magpkg-machine-")
+        .tempdir()?;
+    let rootfs = staging.path().join("rootfs");
+    materialize(&rootfs)?;
+
+    let mut child = match Command::new("machinectl")
+        .args(["import-tar", "-"])
+        .arg(&args.name)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(MagError::Generic(
+                "machinectl not found on PATH; install systemd-container or use --output".into(),
+            ));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let streamed = (|| -> MagResult<()> {
+        let stdin = child.stdin.take().expect("machinectl stdin is piped");
+        let mut tar = tar::Builder::new(io::BufWriter::new(stdin));
+        append_tree(&mut tar, &rootfs, None)?;
+        tar.into_inner()?.flush()?;
+        Ok(())
+    })();
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(MagError::CommandFailure {
+            context: "machinectl import-tar".to_string(),
+            status: status.code().unwrap_or(-1),
+        });
+    }
+    streamed?;
+
+    let settings_path = Path::new(NSPAWN_SETTINGS_DIR).join(&settings_name);
+    fs::create_dir_all(NSPAWN_SETTINGS_DIR)?;
+    fs::write(&settings_path, settings)?;
+    println!(
+        "Imported machine {} with settings {}; start it with `machinectl start {}`",
+        args.name,
+        settings_path.display(),
+        args.name
+    );
+    Ok(())
+}
+
+impl ImageFormat {
+    fn label(self) -> &'static str {
+        match self {
+            ImageFormat::Squashfs => "squashfs",
+            ImageFormat::Erofs => "erofs",
+        }
+    }
+}
+
+fn run_export_initramfs(args: ExportInitramfsArgs) -> MagResult<()> {
+    if args.check_deterministic {
+        check_deterministic_output(&args.output)?;
+    }
+    let manifest_value = evaluate_expression(&args.manifest.expression()?)?;
+    let mut builder = PackageGraphBuilder::default();
+    let packages = builder.packages_from_value(manifest_value)?;
+
+    let store = PackageStore::new()?;
+    store.build_packages(&packages, args.parallelism)?;
+
+    let compression = args.compression.unwrap_or_else(|| {
+        match args.output.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => CpioCompression::Gzip,
+            Some("zst" | "zstd") => CpioCompression::Zstd,
+            _ => CpioCompression::None,
+        }
+    });
+
+    // Stage the closure and write the archive to `output`; `recheck` marks
+    // the second export of --check-deterministic, which stays quiet.
+    let export = |output: &Path, recheck: bool| -> MagResult<()> {
+        let staging = tempfile::Builder::new()
+            .prefix("magpkg-initramfs-")
+            .tempdir()?;
+        let rootfs = staging.path().join("rootfs");
+        store.export_runtime_closure_rootfs(&packages, &rootfs)?;
+
+        let init = if args.no_init || fs::symlink_metadata(rootfs.join("init")).is_ok() {
+            None
+        } else {
+            if !recheck
+                && fs::symlink_metadata(rootfs.join(args.init.trim_start_matches('/'))).is_err()
+            {
+                eprintln!(
+                    "warning: /init points at {}, which no package in the closure provides",
+                    args.init
+                );
+            }
+            Some(args.init.as_str())
+        };
+
+        let to_stdout = output == Path::new("-");
+        let tmp_output = output.with_extension("cpio.tmp");
+        let sink: Box<dyn Write> = if to_stdout {
+            Box::new(io::stdout().lock())
+        } else {
+            if let Some(parent) = output.parent() {
+                if !parent.as_os_str().is_empty() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            Box::new(io::BufWriter::new(File::create(&tmp_output)?))
+        };
+
+        let write_archive = |sink: Box<dyn Write>| -> MagResult<()> {
+            let mut sink = match compression {
+                CpioCompression::None => write_initramfs(sink, &rootfs, init)?,
+                CpioCompression::Gzip => {
+                    let encoder = GzEncoder::new(sink, GzCompression::default());
+                    write_initramfs(encoder, &rootfs, init)?.finish()?
+                }
+                CpioCompression::Zstd => {
+                    let encoder = ZstdEncoder::new(sink, 0)?;
+                    write_initramfs(encoder, &rootfs, init)?.finish()?
+                }
+            };
+            sink.flush()?;
+            Ok(())
+        };
+        if let Err(err) = write_archive(sink) {
+            if !to_stdout {
+                let _ = fs::remove_file(&tmp_output);
+            }
+            return Err(err);
+        }
+
+        if !to_stdout {
+            fs::rename(&tmp_output, output)?;
+        }
+        Ok(())
+    };
+
+    export(&args.output, false)?;
+    if args.check_deterministic {
+        check_deterministic(&args.output, |path| export(path, true))?;
+    }
+    if args.output != Path::new("-") {
+        eprintln!("Wrote initramfs to {}", args.output.display());
+    }
+    Ok(())
+}
+
+fn write_initramfs<W: Write>(writer: W, rootfs: &Path, init: Option<&str>) -> MagResult<W> {
+    let mut cpio = CpioWriter::new(writer);
+    if let Some(target) = init {
+        cpio.append_symlink("init", target)?;
+    }
+    cpio.append_tree(rootfs)?;
+    cpio.finish()
+}
+
+fn run_debug_build(args: DebugBuildArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let failed_root = store.find_failed_build(&args.hash)?;
+    println!("entering {}", failed_root.display());
+    run_debug_shell(&failed_root)
+}
+
+/// `magpkg shell`: build the package's dependencies, then open its build
+/// sandbox with an interactive shell in place of the build script.
+fn run_shell(args: ShellArgs) -> MagResult<()> {
+    let mut builder = PackageGraphBuilder::default();
+    let roots = builder.packages_from_value(evaluate_expression(&args.manifest.expression()?)?)?;
+    let package = match &args.package {
+        Some(query) => find_graph_package(&roots, query)?,
+        None => match roots.as_slice() {
+            [package] => package.clone(),
+            _ => {
+                return Err(MagError::Generic(format!(
+                    "the manifest evaluates to {} packages; name the one to open",
+                    roots.len()
+                )));
+            }
+        },
+    };
+
+    let mut deps: Vec<Rc<Package>> = package
+        .run_deps
+        .iter()
+        .chain(&package.build_deps)
+        .cloned()
+        .collect();
+    if let Some(BuilderShell::Package(shell)) = &package.builder_shell {
+        deps.push(shell.clone());
+    }
+    let store = PackageStore::new()?;
+    store.build_packages(&deps, args.parallelism)?;
+    store.open_build_shell(&package, args.parallelism)
+}
+
+/// The package in the graph under `roots` whose name, base name, or hash
+/// prefix is `query`.
+fn find_graph_package(roots: &[Rc<Package>], query: &str) -> MagResult<Rc<Package>> {
+    let mut queue: Vec<Rc<Package>> = roots.to_vec();
+    let mut seen = HashSet::new();
+    let mut matches: Vec<Rc<Package>> = Vec::new();
+    while let Some(package) = queue.pop() {
+        if !seen.insert(package.hash.clone()) {
+            continue;
+        }
+        if package.name.as_deref() == Some(query)
+            || package_base_name(&package) == query
+            || package.hash.starts_with(query)
+        {
+            matches.push(package.clone());
+        }
+        queue.extend(package.run_deps.iter().cloned());
+        queue.extend(package.build_deps.iter().cloned());
+        if let Some(BuilderShell::Package(shell)) = &package.builder_shell {
+            queue.push(shell.clone());
+        }
+    }
+    match matches.as_slice() {
+        [package] => Ok(package.clone()),
+        [] => Err(MagError::Generic(format!(
+            "no package in the graph matches {query}"
+        ))),
+        _ => {
+            let mut bases: Vec<String> = matches
+                .iter()
+                .map(|package| package_base_name(package))
+                .collect();
+            bases.sort();
+            Err(MagError::Generic(format!(
+                "{query} matches {} packages: {}",
+                bases.len(),
+                bases.join(", ")
+            )))
+        }
+    }
+}
+
+fn run_completions(args: CompletionsArgs) -> MagResult<()> {
+    let mut command = Cli::command();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    generate(args.shell, &mut command, "magpkg", &mut out);
+    if !args.dynamic {
+        return Ok(());
+    }
+
+    match args.shell {
+        Shell::Bash => {
+            writeln!(out, "_magpkg_dynamic() {{")?;
+            writeln!(out, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"")?;
+            writeln!(
+                out,
+                "    local prev=\"${{COMP_WORDS[COMP_CWORD-2]}} ${{COMP_WORDS[COMP_CWORD-1]}}\""
+            )?;
+            writeln!(out, "    local kind=\"\"")?;
+            writeln!(out, "    case \"$prev\" in")?;
+            for (subcommand, kind) in DYNAMIC_COMPLETIONS {
+                writeln!(
+                    out,
+                    "        {}) kind={} ;;",
+                    case_pattern(subcommand),
+                    kind.label()
+                )?;
+            }
+            writeln!(out, "    esac")?;
+            writeln!(out, "    if [[ -n \"$kind\" ]]; then")?;
+            writeln!(
+                out,
+                "        COMPREPLY=($(compgen -W \"$(magpkg __complete \"$kind\" \"$cur\" 2>/dev/null)\" -- \"$cur\"))"
+            )?;
+            writeln!(out, "        return 0")?;
+            writeln!(out, "    fi")?;
+            writeln!(out, "    _magpkg \"$@\"")?;
+            writeln!(out, "}}")?;
+            writeln!(
+                out,
+                "complete -F _magpkg_dynamic -o bashdefault -o default magpkg"
+            )?;
+        }
+        Shell::Zsh => {
+            writeln!(out, "_magpkg_dynamic() {{")?;
+            writeln!(out, "    local kind=\"\"")?;
+            writeln!(
+                out,
+                "    case \"${{words[CURRENT-2]}} ${{words[CURRENT-1]}}\" in"
+            )?;
+            for (subcommand, kind) in DYNAMIC_COMPLETIONS {
+                writeln!(
+                    out,
+                    "        {}) kind={} ;;",
+                    case_pattern(subcommand),
+                    kind.label()
+                )?;
+            }
+            writeln!(out, "    esac")?;
+            writeln!(out, "    if [[ -n \"$kind\" ]]; then")?;
+            writeln!(out, "        local -a candidates")?;
+            writeln!(
+                out,
+                "        candidates=(${{(f)\"$(magpkg __complete \"$kind\" \"${{words[CURRENT]}}\" 2>/dev/null)\"}})"
+            )?;
+            writeln!(out, "        compadd -a candidates")?;
+            writeln!(out, "        return")?;
+            writeln!(out, "    fi")?;
+            writeln!(out, "    _magpkg \"$@\"")?;
+            writeln!(out, "}}")?;
+            writeln!(out, "compdef _magpkg_dynamic magpkg")?;
+        }
+        Shell::Fish => {
+            for (subcommand, kind) in DYNAMIC_COMPLETIONS {
+                let condition = match subcommand.split_once(' ') {
+                    Some((parent, nested)) => format!(
+                        "__fish_seen_subcommand_from {parent}; and __fish_seen_subcommand_from {nested}"
+                    ),
+                    None => format!(
+                        "not __fish_seen_subcommand_from venv; and __fish_seen_subcommand_from {subcommand}"
+                    ),
+                };
+                writeln!(
+                    out,
+                    "complete -c magpkg -n \"{condition}\" -f -a \"(magpkg __complete {} (commandline -ct) 2>/dev/null)\"",
+                    kind.label()
+                )?;
+            }
+        }
+        other => {
+            return Err(MagError::Generic(format!(
+                "--dynamic completions are not supported for {other}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The `case` pattern matching the last two words before the cursor when
+/// they end in `subcommand`.
+fn case_pattern(subcommand: &str) -> String {
+    if subcommand.contains(' ') {
+        format!("\"{subcommand}\"")
+    } else {
+        format!("*\" {subcommand}\"")
+    }
+}
+
+fn run_complete(args: CompleteArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let candidates = match args.kind {
+        CompletionKind::Packages => store.published_package_bases()?,
+        CompletionKind::Failed => store.failed_build_hashes()?,
+        CompletionKind::Profiles => profiles::list_profiles(&store_base_root()?)?,
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for candidate in candidates {
+        if candidate.starts_with(&args.prefix) {
+            writeln!(out, "{candidate}")?;
+        }
+    }
+    Ok(())
+}
+
+fn run_store(args: StoreArgs) -> MagResult<()> {
+    match args.command {
+        StoreCommands::Stats => run_store_stats(),
+        StoreCommands::Activity => run_store_activity(),
+        StoreCommands::Export(args) => run_store_export(args),
+        StoreCommands::Import(args) => run_store_import(args),
+        StoreCommands::Query(args) => run_store_query(args),
+        StoreCommands::Reindex => run_store_reindex(),
+    }
+}
+
+fn run_store_query(args: StoreQueryArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let db = if args.verify {
+        store.sync_metadb()?
+    } else {
+        store.indexed_metadb()
+    };
+    let Some(db) = db else {
+        return Err(MagError::Generic(
+            "the store database cannot be opened; run `magpkg store reindex` to recreate it".into(),
+        ));
+    };
+    let dependents = match &args.depends_on {
+        Some(query) => Some(
+            db.dependents(&store.find_published_base(query)?)?
+                .into_iter()
+                .collect::<HashSet<_>>(),
+        ),
+        None => None,
+    };
+    let now = unix_now();
+    let rows: Vec<ArtifactRow> = db
+        .artifacts()?
+        .into_iter()
+        .filter(|row| {
+            args.pattern
+                .as_ref()
+                .is_none_or(|pattern| row.base.contains(pattern.as_str()))
+        })
+        .filter(|row| {
+            dependents
+                .as_ref()
+                .is_none_or(|dependents| dependents.contains(&row.base))
+        })
+        .filter(|row| {
+            args.builder
+                .as_ref()
+                .is_none_or(|host| row.builder.as_ref() == Some(host))
+        })
+        .filter(|row| {
+            args.unused_days
+                .is_none_or(|days| now.saturating_sub(row.last_access) >= days * 86_400)
+        })
+        .collect();
+
+    if json_output() {
+        let mut report = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let metadata = store.package_metadata(&row.base)?.unwrap_or_default();
+            report.push(serde_json::json!({
+                "base": row.base,
+                "hash": row.hash,
+                "bytes": row.size,
+                "last_access": row.last_access,
+                "built_at": row.built_at,
+                "builder": row.builder,
+                "expression": row.expression,
+                "version": metadata.version,
+                "license": metadata.license,
+                "homepage": metadata.homepage,
+                "description": metadata.description,
+            }));
+        }
+        println!("{}", serde_json::Value::Array(report));
+        return Ok(());
+    }
+
+    for row in &rows {
+        let days = now.saturating_sub(row.last_access) / 86_400;
+        let provenance = match (&row.builder, row.built_at) {
+            (Some(builder), Some(built_at)) => format!(
+                "built on {builder} {} day(s) ago",
+                now.saturating_sub(built_at) / 86_400
+            ),
+            _ => "not built here".to_string(),
+        };
+        println!(
+            "{}\t{}\tused {days} day(s) ago\t{provenance}",
+            row.base,
+            format_bytes(row.size)
+        );
+    }
+    Ok(())
+}
+
+fn run_store_reindex() -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let count = store.reindex()?;
+    println!("Indexed {count} published artifact(s).");
+    Ok(())
+}
+
+fn run_store_export(args: StoreExportArgs) -> MagResult<()> {
+    let key = args
+        .key
+        .as_ref()
+        .map(|path| load_signing_key(path, args.generate_key))
+        .transpose()?;
+    let store = PackageStore::new()?;
+    let base = store.find_published_base(&args.hash)?;
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("{base}.magpkg-bundle")));
+    let metadata = store.export_bundle(&base, &output, key.as_ref())?;
+    match &key {
+        Some(key) => println!(
+            "exported {base} ({}) to {}, signed by {}",
+            format_bytes(metadata.size),
+            output.display(),
+            public_key_hex(key)
+        ),
+        None => println!(
+            "exported {base} ({}) to {} unsigned",
+            format_bytes(metadata.size),
+            output.display()
+        ),
+    }
+    Ok(())
+}
+
+fn run_store_import(args: StoreImportArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    for file in &args.files {
+        let (metadata, signer, added) = store.import_bundle(file, args.allow_untrusted)?;
+        let signer = match signer {
+            BundleSigner::Trusted(key) => format!("signed by trusted key {key}"),
+            BundleSigner::Untrusted(key) => format!("signed by untrusted key {key}"),
+            BundleSigner::Unsigned => "unsigned".to_string(),
+        };
+        let origin = metadata
+            .origin
+            .as_deref()
+            .map(|origin| format!(" from {origin}"))
+            .unwrap_or_default();
+        if added {
+            println!("imported {}{origin}, {signer}", metadata.base);
+        } else {
+            println!("{} is already in the store", metadata.base);
+        }
+    }
+    Ok(())
+}
+
+fn run_channel(args: ChannelArgs) -> MagResult<()> {
+    let base_root = store_base_root()?;
+    let mut channels = match args.command {
+        ChannelCommands::List => Channels::load(&base_root)?,
+        _ => Channels::load_for_update(&base_root)?,
+    };
+    match args.command {
+        ChannelCommands::Add(args) => {
+            let channel = channels.add(&args.name, &args.url)?;
+            println!("added channel {} at {}", channel.name, channel.sha256);
+        }
+        ChannelCommands::Update(args) => {
+            let names = if args.names.is_empty() {
+                channels
+                    .iter()
+                    .map(|channel| channel.name.clone())
+                    .collect()
+            } else {
+                args.names
+            };
+            for name in names {
+                match channels.update(&name)? {
+                    Some(previous) => println!(
+                        "{name}: {previous} -> {}",
+                        channels.get(&name).map_or("", |channel| &channel.sha256)
+                    ),
+                    None => println!("{name}: unchanged"),
+                }
+            }
+        }
+        ChannelCommands::Remove(args) => {
+            channels.remove(&args.name)?;
+            println!("removed channel {}", args.name);
+        }
+        ChannelCommands::List => {
+            let now = unix_now();
+            for channel in channels.iter() {
+                println!(
+                    "{}\t{}\t{}\tpinned {} day(s) ago",
+                    channel.name,
+                    channel.url,
+                    channel.sha256,
+                    now.saturating_sub(channel.updated) / 86_400
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_locks(args: LocksArgs) -> MagResult<()> {
+    match args.command {
+        LocksCommands::List => run_locks_list(),
+    }
+}
+
+/// `magpkg locks list`: leases from every host sharing the store, then the
+/// `flock` locks that processes on this host advertise without a lease.
+fn run_locks_list() -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let base_root = store_base_root()?;
+    let leases = list_leases(&base_root)?;
+    let ttl = lease_ttl(&base_root)?;
+    let host = lease_host();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut listed = HashSet::new();
+    for lease in &leases {
+        let state = match lease.stale_reason(ttl, &host) {
+            Some(reason) => format!("stale: {reason}"),
+            None => format!("renewed {}s ago", now.saturating_sub(lease.renewed)),
+        };
+        let mode = if lease.shared { " (shared)" } else { "" };
+        println!(
+            "{}{mode}\tpid {} on {}\theld {}s\t{state}",
+            lease.label,
+            lease.pid,
+            lease.host,
+            now.saturating_sub(lease.acquired)
+        );
+        listed.insert(lease.label.clone());
+    }
+    for info in store.activities()? {
+        for label in info.holding.iter().filter(|label| !listed.contains(*label)) {
+            println!(
+                "{label}\tpid {} on {host}\theld {}s\tno lease ({})",
+                info.pid,
+                now.saturating_sub(info.started),
+                info.command
+            );
+            listed.insert(label.clone());
+        }
+    }
+    if listed.is_empty() {
+        eprintln!("no store locks are held");
+    }
+    Ok(())
+}
+
+fn run_store_activity() -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let activities = store.activities()?;
+    if activities.is_empty() {
+        println!("no other magpkg processes are using this store");
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    for info in activities {
+        println!(
+            "pid {} (running {}s): {}",
+            info.pid,
+            now.saturating_sub(info.started),
+            info.step
+        );
+        println!("  command: {}", info.command);
+        for label in &info.holding {
+            println!("  holding: {label}");
+        }
+    }
+    Ok(())
+}
+
+fn run_store_stats() -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let store_stats = store.collect_stats()?;
+
+    let areas = [
+        ("packages", &store_stats.packages),
+        ("fetched", &store_stats.fetched),
+        ("torrents", &store_stats.torrents),
+        ("venvs", &store_stats.venvs),
+    ];
+    let counter = |name: &str| store_stats.counters.get(name).copied().unwrap_or(0);
+    let hit_rate = |hits: u64, misses: u64| {
+        if hits + misses == 0 {
+            None
+        } else {
+            Some(hits as f64 / (hits + misses) as f64)
+        }
+    };
+    let fetch_hit_rate = hit_rate(
+        counter(stats::FETCH_CACHE_HITS),
+        counter(stats::FETCH_CACHE_MISSES),
+    );
+    let package_hit_rate = hit_rate(
+        counter(stats::PACKAGE_CACHE_HITS),
+        counter(stats::PACKAGE_BUILDS),
+    );
+
+    if json_output() {
+        let mut report = serde_json::Map::new();
+        for (name, area) in areas {
+            report.insert(name.to_string(), area_json(area));
+        }
+        report.insert(
+            "counters".to_string(),
+            serde_json::to_value(&store_stats.counters).expect("counters serialize"),
+        );
+        report.insert(
+            "hit_rates".to_string(),
+            serde_json::json!({
+                "fetch": fetch_hit_rate,
+                "package": package_hit_rate,
+            }),
+        );
+        println!("{}", serde_json::Value::Object(report));
+        return Ok(());
+    }
+
+    for (name, area) in areas {
+        println!(
+            "{name}: {} entries, {} files, {} bytes",
+            area.entries, area.files, area.bytes
+        );
+    }
+    for (name, value) in &store_stats.counters {
+        println!("{name}: {value}");
+    }
+    if let Some(rate) = fetch_hit_rate {
+        println!("fetch hit rate: {:.1}%", rate * 100.0);
+    }
+    if let Some(rate) = package_hit_rate {
+        println!("package hit rate: {:.1}%", rate * 100.0);
+    }
+    Ok(())
+}
+
+fn area_json(area: &AreaStats) -> serde_json::Value {
+    let unix = |time: Option<SystemTime>| {
+        time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|age| age.as_secs())
+    };
+    serde_json::json!({
+        "entries": area.entries,
+        "files": area.files,
+        "bytes": area.bytes,
+        "oldest_mtime": unix(area.oldest),
+        "newest_mtime": unix(area.newest),
+    })
+}
+
+fn run_venv(args: VenvArgs) -> MagResult<()> {
+    let VenvArgs {
+        action,
+        expression,
+        file,
+        name,
+        parallelism,
+        frozen,
+        overlay,
+        print_env,
+        prepare,
+        json_exit_report,
+        command,
+    } = args;
+
+    match action {
+        Some(VenvAction::Add(args)) => return run_venv_add(args, false),
+        Some(VenvAction::Update(args)) => return run_venv_add(args, true),
+        Some(VenvAction::Enter(args)) => {
+            let exit_report = args
+                .json_exit_report
+                .map(ExitReportTarget::open)
+                .transpose()?;
+            return enter_venv_profile(&args.name, args.print_env, exit_report, args.command);
+        }
+        Some(VenvAction::Exec(args)) => return run_venv_exec(args),
+        Some(VenvAction::Remove { name }) => {
+            let base_root = store_base_root()?;
+            if !profiles::remove_profile(&base_root, &name)? {
+                return Err(MagError::Generic(format!("no venv profile named {name}")));
+            }
+            if let Some(exports) = HostExports::load(&base_root, &name)? {
+                exports.remove(&base_root)?;
+            }
+            eprintln!("Removed venv profile {name}");
+            return Ok(());
+        }
+        Some(VenvAction::List) => {
+            let base_root = store_base_root()?;
+            for name in profiles::list_profiles(&base_root)? {
+                let profile = VenvProfile::load(&base_root, &name)?;
+                println!("{name}\t{}\t{}", profile.spec.rootfs_hash, profile.manifest);
+            }
+            return Ok(());
+        }
+        Some(VenvAction::Export(args)) => return run_venv_export(args),
+        Some(VenvAction::Unexport { name }) => {
+            let base_root = store_base_root()?;
+            let Some(exports) = HostExports::load(&base_root, &name)? else {
+                return Err(MagError::Generic(format!(
+                    "venv profile {name} has no host exports"
+                )));
+            };
+            let count = exports.files().len();
+            exports.remove(&base_root)?;
+            eprintln!("Removed {count} generated file(s) for venv profile {name}");
+            return Ok(());
+        }
+        None => {}
+    }
+    let exit_report = json_exit_report.map(ExitReportTarget::open).transpose()?;
+    if let Some(name) = name {
+        return enter_venv_profile(&name, print_env, exit_report, command);
+    }
+
+    let manifest_expr = manifest_expression(expression, file)?
+        .expect("clap enforces presence of expression or file");
+
+    let store = PackageStore::new()?;
+    let (spec, created) = prepare_venv(&store, &manifest_expr, &frozen, parallelism, overlay)?;
+    if prepare {
+        println!(
+            "{}\t{}\t{}",
+            spec.rootfs_hash,
+            store
+                .venv_rootfs_dir(&spec.rootfs_hash)
+                .join("rootfs")
+                .display(),
+            if created { "created" } else { "reused" }
+        );
+        return Ok(());
+    }
+    enter_venv(
+        &store,
+        &store.venv_rootfs_dir(&spec.rootfs_hash),
+        &spec,
+        print_env,
+        exit_report,
+        command,
+    )
+}
+
+/// Evaluate a venv manifest, build its packages, and materialize the cached
+/// rootfs if it is not already present. Also reports whether the rootfs was
+/// created by this call. With `overlay`, a new rootfs only records the
+/// packages to mount; an existing copied rootfs is reused either way.
+fn prepare_venv(
+    store: &PackageStore,
+    manifest_expr: &str,
+    frozen: &FrozenArgs,
+    parallelism: usize,
+    overlay: bool,
+) -> MagResult<(VenvSpec, bool)> {
+    let (manifest_value, imports) = evaluate_expression_logged(manifest_expr)?;
+    let mut builder = PackageGraphBuilder::default();
+    let spec = VenvSpec::from_value(manifest_value, &mut builder)?;
+    check_frozen(frozen, &spec.packages, &imports)?;
+
+    store.build_packages(&spec.packages, parallelism)?;
+
+    let rootfs_dir = store.venv_rootfs_dir(&spec.rootfs_hash);
+    let rootfs_path = rootfs_dir.join("rootfs");
+    let _lock = store.lock_venv_prepare(&spec.rootfs_hash)?;
+    if !overlay && rootfs_dir.join(VENV_LAYERS_FILE).exists() {
+        discard_layered_venv(&rootfs_dir)?;
+    }
+
+    let created = !rootfs_path.exists();
+    if created {
+        // The rootfs is assembled aside and renamed into place, so that a
+        // crash never leaves a venv that looks complete.
+        let staging = store.venv_staging_dir(&spec.rootfs_hash);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+        let staged_rootfs = staging.join("rootfs");
+        let exported = (|| -> MagResult<()> {
+            let layered =
+                overlay && store.export_runtime_closure_layers(&spec.packages, &staging)?;
+            if !layered {
+                if overlay {
+                    eprintln!(
+                        "note: the venv has too many packages to mount as overlay layers; copying them instead"
+                    );
+                }
+                store.export_runtime_closure_rootfs(&spec.packages, &staged_rootfs)?;
+            }
+            apply_fs_entries(&staged_rootfs, &spec.fs_entries)?;
+            if rootfs_dir.exists() {
+                fs::remove_dir_all(&rootfs_dir)?;
+            }
+            fs::rename(&staging, &rootfs_dir)?;
+            Ok(())
+        })();
+        if let Err(err) = exported {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(err);
+        }
+        eprintln!(
+            "Venv rootfs hash {} stored at {}",
+            spec.rootfs_hash,
+            rootfs_dir.display()
+        );
+    } else {
+        touch_path(&rootfs_dir)?;
+    }
+
+    Ok((spec, created))
+}
+
+/// Remove a venv prepared with `--overlay` so that a copied rootfs can take
+/// its place. Fails while the venv is running.
+fn discard_layered_venv(rootfs_dir: &Path) -> MagResult<()> {
+    let lock_file = File::create(rootfs_dir.join("rootfs").join(".lock"))?;
+    match lock_file.try_lock_exclusive() {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            return Err(MagError::Generic(format!(
+                "venv {} is running with its packages mounted as overlay layers; exit it before copying them",
+                rootfs_dir.display()
+            )));
+        }
+        Err(err) => return Err(err.into()),
+    }
+    eprintln!(
+        "Replacing the overlay layers of venv {} with a copied rootfs",
+        rootfs_dir.display()
+    );
+    fs::remove_dir_all(rootfs_dir)?;
+    Ok(())
+}
+
+/// Fail for a venv whose packages are mounted as overlay layers, which has
+/// no merged tree on the host for `what` to use.
+fn require_copied_rootfs(rootfs_dir: &Path, what: &str) -> MagResult<()> {
+    if rootfs_dir.join(VENV_LAYERS_FILE).exists() {
+        return Err(MagError::Generic(format!(
+            "{what} needs a copied rootfs, but venv {} mounts its packages as overlay layers; prepare it again without --overlay",
+            rootfs_dir.display()
+        )));
+    }
+    Ok(())
+}
+
+fn enter_venv(
+    store: &PackageStore,
+    rootfs_dir: &Path,
+    spec: &VenvSpec,
+    print_env: bool,
+    exit_report: Option<ExitReportTarget>,
+    command: Vec<String>,
+) -> MagResult<()> {
+    if print_env {
+        require_copied_rootfs(rootfs_dir, "--print-env")?;
+        return print_venv_env(&rootfs_dir.join("rootfs"), spec);
+    }
+
+    let command = if command.is_empty() {
+        vec![OsString::from("/bin/sh")]
+    } else {
+        command.iter().map(OsString::from).collect()
+    };
+
+    launch_venv(
+        store,
+        rootfs_dir,
+        spec,
+        LaunchMode::Interactive,
+        exit_report,
+        command,
+    )
+}
+
+/// `magpkg venv exec`: prepare the venv, optionally with magpkg's own
+/// messages silenced, then run the command in exec mode.
+fn run_venv_exec(args: VenvExecArgs) -> MagResult<()> {
+    if args.file.as_deref() == Some(Path::new("-")) {
+        return Err(MagError::Generic(
+            "venv exec keeps stdin for the command; pass the manifest as a file or with -e".into(),
+        ));
+    }
+    let exit_report = args
+        .json_exit_report
+        .map(ExitReportTarget::open)
+        .transpose()?;
+    let silenced = if args.quiet {
+        Some(SilencedStderr::new()?)
+    } else {
+        None
+    };
+
+    let store = PackageStore::new()?;
+    let (rootfs_dir, spec) = match &args.name {
+        Some(name) => profile_rootfs(&store, name)?,
+        None => {
+            let manifest_expr = manifest_expression(args.expression, args.file)?
+                .expect("clap enforces presence of expression or file");
+            let (spec, _) = prepare_venv(
+                &store,
+                &manifest_expr,
+                &args.frozen,
+                args.parallelism,
+                args.overlay,
+            )?;
+            (store.venv_rootfs_dir(&spec.rootfs_hash), spec)
+        }
+    };
+    drop(silenced);
+
+    let command = args.command.iter().map(OsString::from).collect();
+    launch_venv(
+        &store,
+        &rootfs_dir,
+        &spec,
+        LaunchMode::Exec,
+        exit_report,
+        command,
+    )
+}
+
+/// `magpkg venv add` and `magpkg venv update`: evaluate the manifest, build
+/// the rootfs, and record the result under the profile name. `update` reuses
+/// the recorded manifest unless a new one is given.
+fn run_venv_add(args: VenvProfileArgs, update: bool) -> MagResult<()> {
+    let VenvProfileArgs {
+        name,
+        expression,
+        file,
+        parallelism,
+        frozen,
+        overlay,
+        copy,
+    } = args;
+    profiles::validate_profile_name(&name)?;
+    let store = PackageStore::new()?;
+    let base_root = &store_base_root()?;
+
+    let existing = profiles::list_profiles(base_root)?.contains(&name);
+    if update && !existing {
+        return Err(MagError::Generic(format!(
+            "no venv profile named {name}; use `magpkg venv add` to register it"
+        )));
+    }
+    if !update && existing {
+        return Err(MagError::Generic(format!(
+            "venv profile {name} already exists; use `magpkg venv update` to change it"
+        )));
+    }
+
+    let manifest = match (expression, file) {
+        (Some(expr), _) => expr,
+        // Profiles are entered from any directory, so pin the manifest path.
+        (None, Some(path)) => format!(
+            "import {}",
+            quote_jsonnet_string(&fs::canonicalize(&path)?)?
+        ),
+        (None, None) if update => VenvProfile::load(base_root, &name)?.manifest,
+        (None, None) => {
+            return Err(MagError::Generic(
+                "venv add requires a manifest via -e/--expression or -f/--file".into(),
+            ));
+        }
     };
-    let stats = store.cleanup(expiry, options)?;
 
-    println!("Cleanup completed (max age: {} day(s)).", args.max_age_days);
+    let overlay = match (overlay, copy) {
+        (true, _) => true,
+        (_, true) => false,
+        _ if update => VenvProfile::load(base_root, &name)?.overlay,
+        _ => false,
+    };
 
-    if stats.package_artifacts_removed
-        + stats.package_build_dirs_removed
-        + stats.package_lock_files_removed
-        > 0
-    {
-        println!(
-            "  Package artifacts removed: {}, build dirs: {}, lock files: {}",
-            stats.package_artifacts_removed,
-            stats.package_build_dirs_removed,
-            stats.package_lock_files_removed
-        );
+    let (spec, _) = prepare_venv(&store, &manifest, &frozen, parallelism, overlay)?;
+    let rootfs_hash = spec.rootfs_hash.clone();
+    VenvProfile {
+        name: name.clone(),
+        manifest,
+        spec,
+        overlay,
     }
+    .save(base_root)?;
+    eprintln!("Venv profile {name} points at rootfs {rootfs_hash}");
+    refresh_host_exports(&store, base_root, &name, &rootfs_hash)
+}
 
-    if stats.fetch_files_removed + stats.fetch_partials_removed + stats.fetch_lock_files_removed > 0
-    {
-        println!(
-            "  Fetch files removed: {}, partials: {}, lock files: {}",
-            stats.fetch_files_removed, stats.fetch_partials_removed, stats.fetch_lock_files_removed
-        );
+/// `magpkg venv export`: generate shims for `--bin` commands and desktop
+/// entries for the profile's rootfs. Re-running replaces the previous set.
+fn run_venv_export(args: VenvExportArgs) -> MagResult<()> {
+    let store = PackageStore::new()?;
+    let base_root = &store_base_root()?;
+    let profile = VenvProfile::load(base_root, &args.name)?;
+    let rootfs_dir = store.venv_rootfs_dir(&profile.spec.rootfs_hash);
+    if !rootfs_dir.join("rootfs").exists() {
+        return Err(MagError::Generic(format!(
+            "venv rootfs for profile {} is gone; run `magpkg venv update {}` first",
+            args.name, args.name
+        )));
     }
+    require_copied_rootfs(&rootfs_dir, "venv export")?;
 
-    if stats.venv_rootfs_removed > 0 {
-        println!("  Venv rootfs removed: {}", stats.venv_rootfs_removed);
+    let mut exports = HostExports::new(
+        &args.name,
+        args.bin_dir,
+        args.data_dir,
+        !args.no_desktop,
+        args.bins,
+    )?;
+    if let Some(previous) = HostExports::load(base_root, &args.name)? {
+        previous.remove(base_root)?;
+    }
+    exports.generate(base_root, &env::current_exe()?, &rootfs_dir.join("rootfs"))?;
+    for file in exports.files() {
+        println!("{}", file.display());
+    }
+    if !exports.bins.is_empty() {
+        let on_path = env::var_os("PATH")
+            .is_some_and(|path| env::split_paths(&path).any(|dir| dir == exports.bin_dir));
+        if !on_path {
+            eprintln!(
+                "note: {} is not on PATH; add it to run the shims by name",
+                exports.bin_dir.display()
+            );
+        }
     }
+    Ok(())
+}
 
+/// Regenerate a profile's host exports after its rootfs changed, so desktop
+/// entries and icons do not point at a removed tree.
+fn refresh_host_exports(
+    store: &PackageStore,
+    base_root: &Path,
+    name: &str,
+    rootfs_hash: &str,
+) -> MagResult<()> {
+    let Some(mut exports) = HostExports::load(base_root, name)? else {
+        return Ok(());
+    };
+    let rootfs_dir = store.venv_rootfs_dir(rootfs_hash);
+    if rootfs_dir.join(VENV_LAYERS_FILE).exists() {
+        eprintln!(
+            "note: host exports of venv profile {name} were not refreshed; run `magpkg venv update {name} --copy` to regenerate them"
+        );
+        return Ok(());
+    }
+    let rootfs = rootfs_dir.join("rootfs");
+    exports.generate(base_root, &env::current_exe()?, &rootfs)?;
+    eprintln!("Refreshed host exports of venv profile {name}");
     Ok(())
 }
 
-fn run_seed(args: SeedArgs) -> MagResult<()> {
+/// Enter a registered venv. The manifest is only re-evaluated when the cached
+/// rootfs has been removed, e.g. by `magpkg cleanup --venvs`.
+fn enter_venv_profile(
+    name: &str,
+    print_env: bool,
+    exit_report: Option<ExitReportTarget>,
+    command: Vec<String>,
+) -> MagResult<()> {
     let store = PackageStore::new()?;
-    let seeder = TorrentSeeder::new(store.torrent_root().to_path_buf())?;
+    let (rootfs_dir, spec) = profile_rootfs(&store, name)?;
+    enter_venv(&store, &rootfs_dir, &spec, print_env, exit_report, command)
+}
 
-    let listen_port = if args.no_listen {
-        None
+/// The rootfs directory and launch settings of a registered venv,
+/// re-evaluating its manifest if cleanup has removed the rootfs.
+fn profile_rootfs(store: &PackageStore, name: &str) -> MagResult<(PathBuf, VenvSpec)> {
+    let base_root = &store_base_root()?;
+    let mut profile = VenvProfile::load(base_root, name)?;
+
+    let mut rootfs_dir = store.venv_rootfs_dir(&profile.spec.rootfs_hash);
+    if rootfs_dir.join("rootfs").exists() {
+        touch_path(&rootfs_dir)?;
     } else {
-        Some(args.listen_port.unwrap_or(DEFAULT_SEED_PORT))
-    };
+        eprintln!("Venv rootfs for profile {name} is gone; re-evaluating its manifest");
+        profile.spec = prepare_venv(
+            store,
+            &profile.manifest,
+            &FrozenArgs::default(),
+            default_parallelism(),
+            profile.overlay,
+        )?
+        .0;
+        profile.save(base_root)?;
+        rootfs_dir = store.venv_rootfs_dir(&profile.spec.rootfs_hash);
+        refresh_host_exports(store, base_root, name, &profile.spec.rootfs_hash)?;
+    }
 
-    seeder.run(listen_port)
+    Ok((rootfs_dir, profile.spec))
 }
 
-fn run_export_tarball(args: ExportTarballArgs) -> MagResult<()> {
-    let manifest_value = evaluate_expression(&args.expression)?;
-    let mut builder = PackageGraphBuilder::default();
-    let packages = builder.packages_from_value(manifest_value)?;
-
-    let store = PackageStore::new()?;
-    store.build_packages(&packages, args.parallelism)?;
+/// Print POSIX shell exports that make the venv's tools usable from the host
+/// without bubblewrap, e.g. `eval "$(magpkg venv -f env.jsonnet --print-env)"`.
+fn print_venv_env(rootfs: &Path, spec: &VenvSpec) -> MagResult<()> {
+    let rootfs = fs::canonicalize(rootfs)?;
+    let mut exports: Vec<(String, String)> = Vec::new();
 
-    match args.output {
-        Some(ref path) if path == Path::new("-") => {
-            let stdout = io::stdout();
-            let mut handle = stdout.lock();
-            store.export_runtime_closure_tarball(&packages, &mut handle)?;
+    let search_paths = [
+        ("PATH", &["usr/bin", "bin", "usr/sbin", "sbin"][..]),
+        (
+            "LD_LIBRARY_PATH",
+            &["usr/lib64", "usr/lib", "lib64", "lib"][..],
+        ),
+        (
+            "PKG_CONFIG_PATH",
+            &[
+                "usr/lib64/pkgconfig",
+                "usr/lib/pkgconfig",
+                "usr/share/pkgconfig",
+            ][..],
+        ),
+    ];
+    for (name, dirs) in search_paths {
+        let mut entries: Vec<String> = dirs
+            .iter()
+            .map(|dir| rootfs.join(dir))
+            .filter(|dir| dir.is_dir())
+            .map(|dir| dir.display().to_string())
+            .collect();
+        if entries.is_empty() {
+            continue;
         }
-        Some(path) => {
-            if let Some(parent) = path.parent() {
-                if !parent.as_os_str().is_empty() {
-                    std::fs::create_dir_all(parent)?;
-                }
+        if let Ok(existing) = env::var(name) {
+            if !existing.is_empty() {
+                entries.push(existing);
             }
-            let file = File::create(&path)?;
-            let mut writer = io::BufWriter::new(file);
-            store.export_runtime_closure_tarball(&packages, &mut writer)?;
-        }
-        None => {
-            let stdout = io::stdout();
-            let mut handle = stdout.lock();
-            store.export_runtime_closure_tarball(&packages, &mut handle)?;
         }
+        exports.push((name.to_string(), entries.join(":")));
     }
 
-    Ok(())
-}
-
-fn run_venv(args: VenvArgs) -> MagResult<()> {
-    let VenvArgs {
-        expression,
-        file,
-        parallelism,
-        command,
-    } = args;
-
-    let manifest_expr = match (expression, file) {
-        (Some(expr), None) => expr,
-        (None, Some(path)) => format!("import {}", quote_jsonnet_string(&path)?),
-        (Some(_), Some(_)) => unreachable!("clap enforces mutual exclusivity"),
-        (None, None) => unreachable!("clap enforces presence of expression or file"),
-    };
-
-    let manifest_value = evaluate_expression(&manifest_expr)?;
-    let mut builder = PackageGraphBuilder::default();
-    let spec = VenvSpec::from_value(manifest_value, &mut builder)?;
-
-    let store = PackageStore::new()?;
-    store.build_packages(&spec.packages, parallelism)?;
-
-    let rootfs_dir = store.venv_rootfs_dir(&spec.rootfs_hash);
-    let rootfs_path = rootfs_dir.join("rootfs");
-
-    if !rootfs_path.exists() {
-        fs::create_dir_all(&rootfs_dir)?;
-        if let Err(err) = store.export_runtime_closure_rootfs(&spec.packages, &rootfs_path) {
-            let _ = fs::remove_dir_all(&rootfs_dir);
-            return Err(err);
-        }
-        if let Err(err) = apply_fs_entries(&rootfs_path, &spec.fs_entries) {
-            let _ = fs::remove_dir_all(&rootfs_dir);
-            return Err(err);
+    for (key, value) in &spec.env_set {
+        if !exports.iter().any(|(name, _)| name == key) {
+            exports.push((key.clone(), value.clone()));
         }
-        println!(
-            "Venv rootfs hash {} stored at {}",
-            spec.rootfs_hash,
-            rootfs_dir.display()
-        );
     }
+    exports.push(("MAGPKG_VENV_ROOT".to_string(), rootfs.display().to_string()));
 
-    let command = if command.is_empty() {
-        vec![OsString::from("/bin/sh")]
-    } else {
-        command.iter().map(OsString::from).collect()
-    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (key, value) in exports {
+        writeln!(out, "export {key}={}", shell_quote(&value))?;
+    }
+    Ok(())
+}
 
-    launch_venv(&rootfs_path, &spec, command)
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
 }
 
 fn quote_jsonnet_string(path: &Path) -> MagResult<String> {
@@ -407,7 +4536,27 @@ fn quote_jsonnet_string(path: &Path) -> MagResult<String> {
     Ok(out)
 }
 
-fn launch_venv(rootfs: &Path, spec: &VenvSpec, command: Vec<OsString>) -> MagResult<()> {
+/// How a venv command is launched.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LaunchMode {
+    /// A shell or program used at the terminal: the host's `/dev`, `/sys`,
+    /// and `/tmp` plus any display and audio passthrough.
+    Interactive,
+    /// A scripted command: a minimal `/dev`, a private `/tmp`, no
+    /// passthrough, and the sandbox dies with `magpkg`.
+    Exec,
+}
+
+fn launch_venv(
+    store: &PackageStore,
+    rootfs_dir: &Path,
+    spec: &VenvSpec,
+    mode: LaunchMode,
+    exit_report: Option<ExitReportTarget>,
+    command: Vec<OsString>,
+) -> MagResult<()> {
+    require_sandbox("launching a venv")?;
+    let rootfs = &rootfs_dir.join("rootfs");
     if !rootfs.exists() {
         return Err(MagError::Generic(format!(
             "venv rootfs missing at {}",
@@ -418,6 +4567,9 @@ fn launch_venv(rootfs: &Path, spec: &VenvSpec, command: Vec<OsString>) -> MagRes
     let lock_path = rootfs.join(".lock");
     let lock_file = File::create(&lock_path)?;
     FileExt::lock_shared(&lock_file)?;
+    let venv_hash = rootfs_dir.file_name().unwrap_or_default().to_string_lossy();
+    let _lease = store.lease_shared(&venv_lease_label(&venv_hash))?;
+    let layers = store.venv_layers(rootfs_dir)?;
 
     let host_cwd = env::current_dir()?;
     let mut target_dir = host_cwd.clone();
@@ -455,18 +4607,61 @@ fn launch_venv(rootfs: &Path, spec: &VenvSpec, command: Vec<OsString>) -> MagRes
         .entry("HOME".to_string())
         .or_insert_with(|| env::var("HOME").unwrap_or_else(|_| "/root".into()));
 
-    let mut cmd = Command::new("bwrap");
-    cmd.arg("--ro-bind").arg(rootfs).arg("/");
+    let mut cmd = venv_sandbox_command(&spec.limits, &spec.unshare, spec.id_mapping)?;
+    match &layers {
+        // The first --overlay-src is the lowest layer; the venv's own tree,
+        // with its fsEntries and mount points, goes on top.
+        Some(layers) => {
+            for dir in &layers.dirs {
+                cmd.arg("--overlay-src").arg(dir);
+            }
+            cmd.arg("--overlay-src").arg(rootfs);
+            cmd.arg("--ro-overlay").arg("/");
+        }
+        None => {
+            cmd.arg("--ro-bind").arg(rootfs).arg("/");
+        }
+    }
 
     let mut mounts = Vec::new();
     if spec.use_default_mounts {
-        mounts.extend(default_mounts());
+        match mode {
+            LaunchMode::Interactive => mounts.extend(default_mounts()),
+            LaunchMode::Exec => {
+                fs::create_dir_all(rootfs.join("dev"))?;
+                cmd.arg("--dev").arg("/dev");
+                mounts.extend(exec_mounts());
+            }
+        }
     }
     mounts.extend(spec.mounts.clone());
 
     if !mounts.iter().any(|m| m.target == Path::new("/tmp")) {
         mounts.push(mount_spec(MountKind::Tmpfs, None, "/tmp", false));
     }
+    match mode {
+        LaunchMode::Interactive => {
+            apply_passthrough(&spec.passthrough, &mut mounts, &mut variables)
+        }
+        LaunchMode::Exec => {
+            cmd.arg("--die-with-parent");
+        }
+    }
+
+    let home = PathBuf::from(&variables["HOME"]);
+    add_home_mount(store, spec, &home, &mut mounts)?;
+    // An isolated home hides the host's, so a working directory below it
+    // only exists when a mount provides it.
+    if spec.home == HomeDir::Isolated
+        && target_dir.starts_with(&home)
+        && !mounts.iter().any(|mount| {
+            mount.target != home
+                && mount.target.starts_with(&home)
+                && target_dir.starts_with(&mount.target)
+        })
+    {
+        target_dir = home;
+    }
 
     for mount in &mounts {
         match mount.kind {
@@ -550,13 +4745,29 @@ fn launch_venv(rootfs: &Path, spec: &VenvSpec, command: Vec<OsString>) -> MagRes
         cmd.arg("--setenv").arg(&key).arg(&value);
     }
 
-    cmd.args(command);
-
-    let status = cmd.status();
-
-    drop(lock_file);
+    cmd.args(&command);
 
-    let status = status?;
+    let status = match exit_report {
+        Some(report) => {
+            let started = Instant::now();
+            let waited = cmd.spawn().and_then(wait_with_max_rss);
+            drop(lock_file);
+            let (status, max_rss) = waited?;
+            report.write(
+                status,
+                max_rss,
+                started.elapsed(),
+                &spec.rootfs_hash,
+                &command,
+            )?;
+            status
+        }
+        None => {
+            let status = cmd.status();
+            drop(lock_file);
+            status?
+        }
+    };
 
     if let Some(code) = status.code() {
         if code == 0 {
@@ -579,10 +4790,44 @@ struct VenvSpec {
     env_set: BTreeMap<String, String>,
     use_default_mounts: bool,
     mounts: Vec<MountSpec>,
+    passthrough: Vec<Passthrough>,
+    limits: ResourceLimits,
+    unshare: Vec<Namespace>,
+    id_mapping: Option<IdMapping>,
+    home: HomeDir,
     fs_entries: Vec<FsEntry>,
     rootfs_hash: String,
 }
 
+/// What a venv sees at `$HOME`, from the manifest's `homeDir` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum HomeDir {
+    /// A persistent directory of its own under `<store>/venv-homes/<hash>`.
+    #[default]
+    Isolated,
+    /// The invoking user's home directory, read-write.
+    Host,
+}
+
+impl HomeDir {
+    fn parse(value: &str) -> MagResult<Self> {
+        match value {
+            "isolated" => Ok(HomeDir::Isolated),
+            "host" => Ok(HomeDir::Host),
+            other => Err(MagError::Generic(format!(
+                "unknown homeDir {other:?}; expected \"isolated\" or \"host\""
+            ))),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            HomeDir::Isolated => "isolated",
+            HomeDir::Host => "host",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct MountSpec {
     kind: MountKind,
@@ -727,6 +4972,56 @@ fn default_mounts() -> Vec<MountSpec> {
     ]
 }
 
+/// Default mounts for `venv exec`. `/dev` is a minimal device tree set up
+/// separately, and `/tmp` is left to the private tmpfs.
+fn exec_mounts() -> Vec<MountSpec> {
+    vec![
+        mount_spec(MountKind::Proc, None, "/proc", false),
+        mount_spec(
+            MountKind::RoBind,
+            Some("/etc/resolv.conf"),
+            "/etc/resolv.conf",
+            true,
+        ),
+        mount_spec(MountKind::RoBind, Some("/etc/hosts"), "/etc/hosts", true),
+    ]
+}
+
+/// Bind the venv's home directory at `home`, the `HOME` it runs with,
+/// unless a mount already targets that path. It goes before the mounts
+/// below `home`, so they stay visible on top of it.
+fn add_home_mount(
+    store: &PackageStore,
+    spec: &VenvSpec,
+    home: &Path,
+    mounts: &mut Vec<MountSpec>,
+) -> MagResult<()> {
+    if !home.is_absolute() || home == Path::new("/") || mounts.iter().any(|m| m.target == home) {
+        return Ok(());
+    }
+    let source = match spec.home {
+        HomeDir::Isolated => store.venv_home_dir(&spec.rootfs_hash)?,
+        HomeDir::Host => match env::var_os("HOME") {
+            Some(host_home) if !host_home.is_empty() => PathBuf::from(host_home),
+            _ => return Ok(()),
+        },
+    };
+    let position = mounts
+        .iter()
+        .position(|mount| mount.target.starts_with(home))
+        .unwrap_or(mounts.len());
+    mounts.insert(
+        position,
+        MountSpec {
+            kind: MountKind::Bind,
+            source: Some(source),
+            target: home.to_path_buf(),
+            optional: false,
+        },
+    );
+    Ok(())
+}
+
 fn mount_spec(kind: MountKind, source: Option<&str>, target: &str, optional: bool) -> MountSpec {
     MountSpec {
         kind,
@@ -737,28 +5032,117 @@ fn mount_spec(kind: MountKind, source: Option<&str>, target: &str, optional: boo
 }
 
 impl VenvSpec {
+    /// Evaluate a venv manifest together with the bases it `extends`. Layers
+    /// are merged base-first, so the manifest itself has the final say:
+    /// packages and `envKeep` accumulate, `envSet` keys and `mountDefaults`
+    /// take the last value, and a mount or `fsEntries` path replaces any
+    /// earlier one with the same target. `uid`, `gid`, `mapRoot`, and
+    /// `homeDir` also take the last value.
     fn from_value(value: Val, builder: &mut PackageGraphBuilder) -> MagResult<Self> {
         let obj = value
             .as_obj()
             .ok_or_else(|| MagError::Generic("venv manifest must evaluate to an object".into()))?;
 
-        let packages_value = get_manifest_field(&obj, "packages")?.ok_or_else(|| {
-            MagError::Generic("venv manifest must define a 'packages' field".into())
-        })?;
-        let packages = builder.packages_from_value(packages_value)?;
+        let mut layers = Vec::new();
+        collect_venv_layers(obj, &mut HashSet::new(), &mut HashSet::new(), &mut layers)?;
+
+        let mut packages: Vec<Rc<Package>> = Vec::new();
+        let mut env_keep: Vec<String> = Vec::new();
+        let mut env_set = BTreeMap::new();
+        let mut use_default_mounts = true;
+        let mut mounts: Vec<MountSpec> = Vec::new();
+        let mut passthrough: Vec<Passthrough> = Vec::new();
+        let mut limits = ResourceLimits::default();
+        let mut unshare: Vec<Namespace> = Vec::new();
+        let mut uid = None;
+        let mut gid = None;
+        let mut map_root = None;
+        let mut home = HomeDir::default();
+        let mut fs_entries: Vec<FsEntry> = Vec::new();
+        for layer in &layers {
+            if let Some(value) = get_manifest_field(layer, "packages")? {
+                for pkg in builder.packages_from_value(value)? {
+                    if !packages.iter().any(|existing| existing.hash == pkg.hash) {
+                        packages.push(pkg);
+                    }
+                }
+            }
+            for name in read_string_array(layer, "envKeep")? {
+                if !env_keep.contains(&name) {
+                    env_keep.push(name);
+                }
+            }
+            env_set.extend(read_string_map(layer, "envSet")?);
+            if let Some(flag) = read_optional_bool_field(layer, "mountDefaults", "venv")? {
+                use_default_mounts = flag;
+            }
+            for mount in read_mounts(layer)? {
+                mounts.retain(|existing| existing.target != mount.target);
+                mounts.push(mount);
+            }
+            let mut presets = Vec::new();
+            if read_optional_bool_field(layer, "desktop", "venv")? == Some(true) {
+                presets.extend(ALL_PASSTHROUGH);
+            }
+            for name in read_string_array(layer, "passthrough")? {
+                presets.push(Passthrough::parse(&name)?);
+            }
+            for preset in presets {
+                if !passthrough.contains(&preset) {
+                    passthrough.push(preset);
+                }
+            }
+            limits.merge(&read_limits(layer)?);
+            for name in read_string_array(layer, "unshare")? {
+                let namespace = Namespace::parse(&name)?;
+                if !unshare.contains(&namespace) {
+                    unshare.push(namespace);
+                }
+            }
+            if let Some(id) = read_id_field(layer, "uid")? {
+                uid = Some(id);
+            }
+            if let Some(id) = read_id_field(layer, "gid")? {
+                gid = Some(id);
+            }
+            if let Some(flag) = read_optional_bool_field(layer, "mapRoot", "venv")? {
+                map_root = Some(flag);
+            }
+            if let Some(value) = read_optional_string_field(layer, "homeDir", "venv")? {
+                home = HomeDir::parse(&value)?;
+            }
+            for entry in read_filesystem_entries(layer)? {
+                fs_entries.retain(|existing| existing.path != entry.path);
+                fs_entries.push(entry);
+            }
+        }
+
+        let id_mapping = match (map_root, uid, gid) {
+            (Some(true), Some(1..), _) | (Some(true), _, Some(1..)) => {
+                return Err(MagError::Generic(
+                    "'mapRoot' runs the venv as uid and gid 0 and conflicts with a non-zero 'uid' or 'gid'".into(),
+                ));
+            }
+            (Some(true), _, _) => Some(IdMapping::ROOT),
+            // A missing id takes the value of the other, so `uid: 1000`
+            // alone runs as 1000:1000.
+            (_, Some(uid), gid) => Some(IdMapping {
+                uid,
+                gid: gid.unwrap_or(uid),
+            }),
+            (_, None, Some(gid)) => Some(IdMapping { uid: gid, gid }),
+            (_, None, None) => None,
+        };
+        if let Some(mapping) = id_mapping {
+            add_identity_entries(&mut fs_entries, mapping);
+        }
+
         if packages.is_empty() {
             return Err(MagError::Generic(
-                "venv manifest field 'packages' must not be empty".into(),
+                "venv manifest and its bases must list at least one package in 'packages'".into(),
             ));
         }
 
-        let env_keep = read_string_array(&obj, "envKeep")?;
-        let env_set = read_string_map(&obj, "envSet")?;
-        let use_default_mounts =
-            read_optional_bool_field(&obj, "mountDefaults", "venv")?.unwrap_or(true);
-        let mounts = read_mounts(&obj)?;
-        let fs_entries = read_filesystem_entries(&obj)?;
-
         let closure = compute_runtime_closure(&packages);
         let rootfs_hash = compute_rootfs_hash(&closure, &fs_entries);
 
@@ -768,12 +5152,115 @@ impl VenvSpec {
             env_set,
             use_default_mounts,
             mounts,
+            passthrough,
+            limits,
+            unshare,
+            id_mapping,
+            home,
             fs_entries,
             rootfs_hash,
         })
     }
 }
 
+/// Give a venv with an id mapping `/etc/passwd` and `/etc/group` entries
+/// for it, and `/root` when it runs as root, unless `fsEntries` already
+/// provide those paths. They are part of the rootfs, so the rootfs hash
+/// covers them.
+fn add_identity_entries(fs_entries: &mut Vec<FsEntry>, mapping: IdMapping) {
+    let mut defaults = vec![
+        (
+            "/etc/passwd",
+            FsEntryKind::File,
+            0o644,
+            Some(mapping.passwd()),
+        ),
+        (
+            "/etc/group",
+            FsEntryKind::File,
+            0o644,
+            Some(mapping.group()),
+        ),
+    ];
+    if mapping.uid == 0 {
+        defaults.push(("/root", FsEntryKind::Dir, 0o700, None));
+    }
+    for (path, kind, mode, contents) in defaults {
+        if fs_entries.iter().any(|entry| entry.path == Path::new(path)) {
+            continue;
+        }
+        fs_entries.push(FsEntry {
+            kind,
+            path: PathBuf::from(path),
+            mode: Some(mode),
+            contents: contents.map(String::into_bytes),
+            target: None,
+        });
+    }
+}
+
+/// Flatten a venv spec and its `extends` bases (a spec or an array of specs)
+/// into merge order: each base's own bases first, then the base, then the
+/// spec itself. A base reached twice is applied once, at its first position.
+fn collect_venv_layers(
+    spec: ObjValue,
+    visiting: &mut HashSet<ObjKey>,
+    seen: &mut HashSet<ObjKey>,
+    layers: &mut Vec<ObjValue>,
+) -> MagResult<()> {
+    let key = ObjKey::new(spec.clone());
+    if seen.contains(&key) {
+        return Ok(());
+    }
+    if !visiting.insert(key.clone()) {
+        return Err(MagError::Generic(
+            "venv manifest 'extends' forms a cycle".into(),
+        ));
+    }
+
+    let bases = match get_manifest_field(&spec, "extends")? {
+        None | Some(Val::Null) => Vec::new(),
+        Some(Val::Obj(base)) => vec![base],
+        Some(Val::Arr(arr)) => {
+            let mut bases = Vec::with_capacity(arr.len());
+            for (index, item) in arr.iter().enumerate() {
+                let value = item.map_err(|err| {
+                    let message = format_jr_error(&err);
+                    MagError::Evaluation {
+                        context: format!("failed to evaluate extends[{index}]"),
+                        message,
+                        source: err,
+                    }
+                })?;
+                match value {
+                    Val::Obj(base) => bases.push(base),
+                    other => {
+                        return Err(MagError::Generic(format!(
+                            "extends[{index}]: expected a venv spec object, got {:?}",
+                            other.value_type()
+                        )));
+                    }
+                }
+            }
+            bases
+        }
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "field 'extends' must be a venv spec or an array of them, got {:?}",
+                other.value_type()
+            )));
+        }
+    };
+    for base in bases {
+        collect_venv_layers(base, visiting, seen, layers)?;
+    }
+
+    visiting.remove(&key);
+    seen.insert(key);
+    layers.push(spec);
+    Ok(())
+}
+
 fn get_manifest_field(obj: &ObjValue, field: &str) -> MagResult<Option<Val>> {
     obj.get(field.into()).map_err(|err| {
         let message = format_jr_error(&err);
@@ -878,6 +5365,23 @@ fn read_required_string_field(obj: &ObjValue, field: &str, context: &str) -> Mag
     }
 }
 
+fn read_optional_string_field(
+    obj: &ObjValue,
+    field: &str,
+    context: &str,
+) -> MagResult<Option<String>> {
+    let value = get_manifest_field(obj, field)?;
+
+    match value {
+        None | Some(Val::Null) => Ok(None),
+        Some(Val::Str(s)) => Ok(Some(s.to_string())),
+        Some(other) => Err(MagError::Generic(format!(
+            "{context}: expected field '{field}' to be a string, got {:?}",
+            other.value_type()
+        ))),
+    }
+}
+
 fn read_optional_bool_field(obj: &ObjValue, field: &str, context: &str) -> MagResult<Option<bool>> {
     let value = get_manifest_field(obj, field)?;
 
@@ -977,6 +5481,93 @@ fn read_mounts(obj: &ObjValue) -> MagResult<Vec<MountSpec>> {
     }
 }
 
+/// A venv's `uid` or `gid` field: an integer from 0 to 2^32 - 2.
+fn read_id_field(obj: &ObjValue, field: &str) -> MagResult<Option<u32>> {
+    match get_manifest_field(obj, field)? {
+        None | Some(Val::Null) => Ok(None),
+        Some(Val::Num(id)) => {
+            let id = f64::from(id);
+            if id < 0.0 || id >= f64::from(u32::MAX) || id.fract() != 0.0 {
+                return Err(MagError::Generic(format!(
+                    "field '{field}' must be an id from 0 to {}",
+                    u32::MAX - 1
+                )));
+            }
+            Ok(Some(id as u32))
+        }
+        Some(other) => Err(MagError::Generic(format!(
+            "field '{field}' must be a number, got {:?}",
+            other.value_type()
+        ))),
+    }
+}
+
+/// Read the `limits` object: `memory` as a size such as `"4G"` or a byte
+/// count, `pids` as a count, and `cpus` as a number of CPUs.
+fn read_limits(obj: &ObjValue) -> MagResult<ResourceLimits> {
+    let Some(value) = get_manifest_field(obj, "limits")? else {
+        return Ok(ResourceLimits::default());
+    };
+    let limits_obj = match value {
+        Val::Null => return Ok(ResourceLimits::default()),
+        Val::Obj(limits_obj) => limits_obj,
+        other => {
+            return Err(MagError::Generic(format!(
+                "field 'limits' must be an object, got {:?}",
+                other.value_type()
+            )));
+        }
+    };
+
+    let mut limits = ResourceLimits::default();
+    for key in limits_obj.fields() {
+        let name = key.to_string();
+        let value = get_manifest_field(&limits_obj, &name)?.expect("field exists");
+        let invalid =
+            |expected: &str| MagError::Generic(format!("limits.{name} must be {expected}"));
+        match (name.as_str(), value) {
+            ("memory", Val::Str(size)) => {
+                let bytes = parse_byte_size(&size.to_string())
+                    .map_err(|err| MagError::Generic(format!("limits.memory: {err}")))?;
+                if bytes == 0 {
+                    return Err(invalid("a positive size"));
+                }
+                limits.memory = Some(bytes);
+            }
+            ("memory", Val::Num(bytes)) => {
+                let bytes = f64::from(bytes);
+                if bytes < 1.0 || bytes.fract() != 0.0 {
+                    return Err(invalid("a positive size"));
+                }
+                limits.memory = Some(bytes as u64);
+            }
+            ("memory", _) => return Err(invalid("a size such as \"4G\" or a byte count")),
+            ("pids", Val::Num(count)) => {
+                let count = f64::from(count);
+                if count < 1.0 || count.fract() != 0.0 {
+                    return Err(invalid("a positive integer"));
+                }
+                limits.pids = Some(count as u64);
+            }
+            ("pids", _) => return Err(invalid("a positive integer")),
+            ("cpus", Val::Num(cpus)) => {
+                let cpus = f64::from(cpus);
+                if cpus < 0.01 {
+                    return Err(invalid("a number of CPUs of at least 0.01"));
+                }
+                limits.cpus = Some(cpus);
+            }
+            ("cpus", _) => return Err(invalid("a number of CPUs")),
+            _ => {
+                return Err(MagError::Generic(format!(
+                    "unknown field limits.{name}; expected memory, pids, or cpus"
+                )));
+            }
+        }
+    }
+    Ok(limits)
+}
+
 fn read_filesystem_entries(obj: &ObjValue) -> MagResult<Vec<FsEntry>> {
     let Some(value) = get_manifest_field(obj, "fsEntries")? else {
         return Ok(Vec::new());
@@ -1160,22 +5751,39 @@ fn compute_rootfs_hash(packages: &[Rc<Package>], fs_entries: &[FsEntry]) -> Stri
 }
 
 fn report_error(err: &MagError) {
-    eprintln!("Error: {}", err);
+    if json_output() {
+        let report = serde_json::json!({
+            "error": {
+                "code": err.code(),
+                "message": err.to_string(),
+                "exit_code": err.exit_code(),
+                "details": err.details(),
+            }
+        });
+        eprintln!("{report}");
+    } else {
+        eprintln!("Error: {}", err);
+    }
 }
 
 fn evaluate_expression(expression: &str) -> MagResult<Val> {
-    let mut builder = State::builder();
-    builder.import_resolver(MagImportResolver::new(Vec::new()));
-    builder.context_initializer(StdlibContext::new(PathResolver::new_cwd_fallback()));
-    let state = builder.build();
+    evaluate_expression_logged(expression).map(|(value, _)| value)
+}
 
-    state.evaluate_snippet("<cli>", expression).map_err(|err| {
-        let message = format_jr_error(&err);
-        MagError::ExpressionEval {
-            message,
-            source: err,
+/// Evaluate an expression and return a handle to the log of remote imports.
+/// Values are lazy, so the log is only complete once the graph has been built.
+fn evaluate_expression_logged(expression: &str) -> MagResult<(Val, ImportLog)> {
+    let mut evaluator = Evaluator::new()?;
+    if let Some(options) = IMPORT_OPTIONS.get() {
+        for root in &options.roots {
+            evaluator = evaluator.import_root(root)?;
         }
-    })
+        evaluator = evaluator.remote_imports(!options.no_remote);
+    }
+    if let Some(manifest) = STDIN_MANIFEST.get() {
+        evaluator = evaluator.virtual_file(STDIN_MANIFEST_NAME, manifest.as_bytes());
+    }
+    evaluator.evaluate("<cli>", expression)
 }
 
 fn default_parallelism() -> usize {
@@ -0,0 +1,312 @@
+use std::{collections::BTreeMap, path::Path, sync::Mutex, time::Duration};
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{MagError, MagResult, store::DependencyRecord};
+
+pub const METADB_FILE: &str = "store.db";
+
+/// Bumped whenever the schema changes; an index with another version is
+/// dropped and rebuilt from the filesystem.
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS artifacts (
+    base        TEXT PRIMARY KEY,
+    hash        TEXT NOT NULL,
+    size        INTEGER NOT NULL,
+    last_access INTEGER NOT NULL,
+    has_deps    INTEGER NOT NULL,
+    built_at    INTEGER,
+    builder     TEXT,
+    expression  TEXT
+);
+CREATE TABLE IF NOT EXISTS edges (
+    base TEXT NOT NULL REFERENCES artifacts(base) ON DELETE CASCADE,
+    dep  TEXT NOT NULL,
+    kind TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS edges_by_dep ON edges(dep);
+CREATE TABLE IF NOT EXISTS fetches (
+    base   TEXT NOT NULL REFERENCES artifacts(base) ON DELETE CASCADE,
+    sha256 TEXT NOT NULL
+);
+";
+
+/// An index of the published artifacts in `<store>/store.db`, so that
+/// queries over the whole store do not have to open one sidecar file per
+/// package.
+///
+/// The filesystem stays authoritative: rows are written whenever an
+/// artifact is published or reused and removed when the artifact is, and
+/// [`crate::store::PackageStore::sync_metadb`], run on request, drops or
+/// adds rows for artifacts that changed behind its back. Build provenance
+/// (when, where, and from which expression an artifact was built) is read
+/// from the artifact's `.provenance.json` record.
+pub struct MetaDb {
+    conn: Mutex<Connection>,
+    /// Whether the tables were created by this open, because the database
+    /// is new or had another schema version.
+    created: bool,
+}
+
+/// One published artifact.
+#[derive(Debug, Clone, Default)]
+pub struct ArtifactRow {
+    pub base: String,
+    pub hash: String,
+    /// Artifact size in bytes.
+    pub size: u64,
+    /// When a build last used the artifact, in seconds since the epoch.
+    pub last_access: u64,
+    /// When the artifact was built, in seconds since the epoch; `None` for
+    /// artifacts without a provenance record, such as substituted ones.
+    pub built_at: Option<u64>,
+    /// Host that built the artifact.
+    pub builder: Option<String>,
+    /// Expression given to the build that produced it.
+    pub expression: Option<String>,
+}
+
+fn db_error(err: rusqlite::Error) -> MagError {
+    MagError::Generic(format!("store database: {err}"))
+}
+
+impl MetaDb {
+    /// Open the database of the store at `base_root`. A `shared` store is
+    /// used from several machines, where the shared memory index of WAL
+    /// mode does not work, so it keeps a rollback journal instead.
+    pub fn open(base_root: &Path, shared: bool) -> MagResult<Self> {
+        let conn = Connection::open(base_root.join(METADB_FILE)).map_err(db_error)?;
+        conn.busy_timeout(Duration::from_secs(30))
+            .map_err(db_error)?;
+        let journal_mode = if shared { "DELETE" } else { "WAL" };
+        conn.pragma_update(None, "journal_mode", journal_mode)
+            .map_err(db_error)?;
+        conn.pragma_update(None, "foreign_keys", true)
+            .map_err(db_error)?;
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(db_error)?;
+        let created = version != SCHEMA_VERSION;
+        if created {
+            conn.execute_batch(
+                "DROP TABLE IF EXISTS fetches; DROP TABLE IF EXISTS edges; DROP TABLE IF EXISTS artifacts;",
+            )
+            .map_err(db_error)?;
+        }
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+            .map_err(db_error)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            created,
+        })
+    }
+
+    /// Whether the tables were created empty by [`Self::open`] and still
+    /// need to be filled from the filesystem.
+    pub fn was_created(&self) -> bool {
+        self.created
+    }
+
+    /// Insert or update `row` and replace its dependency edges. Provenance
+    /// columns that `row` leaves empty keep their stored values.
+    pub fn record_artifact(
+        &self,
+        row: &ArtifactRow,
+        deps: Option<&DependencyRecord>,
+    ) -> MagResult<()> {
+        let mut conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        let tx = conn.transaction().map_err(db_error)?;
+        insert_artifact(&tx, row, deps)?;
+        tx.commit().map_err(db_error)
+    }
+
+    /// Replace the whole index with `rows`, keeping the provenance of
+    /// artifacts that are still present when `rows` has none for them.
+    pub fn replace_all(&self, rows: &[(ArtifactRow, Option<DependencyRecord>)]) -> MagResult<()> {
+        let mut conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        let tx = conn.transaction().map_err(db_error)?;
+        let provenance = read_provenance(&tx)?;
+        tx.execute("DELETE FROM artifacts", []).map_err(db_error)?;
+        for (row, deps) in rows {
+            let mut row = row.clone();
+            if let Some((built_at, builder, expression)) = provenance.get(&row.base) {
+                row.built_at = row.built_at.or(*built_at);
+                row.builder = row.builder.or_else(|| builder.clone());
+                row.expression = row.expression.or_else(|| expression.clone());
+            }
+            insert_artifact(&tx, &row, deps.as_ref())?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    pub fn remove(&self, base: &str) -> MagResult<()> {
+        let conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        conn.execute("DELETE FROM artifacts WHERE base = ?1", params![base])
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Every indexed artifact, sorted by base name.
+    pub fn artifacts(&self) -> MagResult<Vec<ArtifactRow>> {
+        let conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        let mut stmt = conn
+            .prepare(
+                "SELECT base, hash, size, last_access, built_at, builder, expression
+                 FROM artifacts ORDER BY base",
+            )
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(ArtifactRow {
+                    base: row.get(0)?,
+                    hash: row.get(1)?,
+                    size: row.get::<_, i64>(2)? as u64,
+                    last_access: row.get::<_, i64>(3)? as u64,
+                    built_at: row.get::<_, Option<i64>>(4)?.map(|time| time as u64),
+                    builder: row.get(5)?,
+                    expression: row.get(6)?,
+                })
+            })
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    /// Dependency records of the indexed artifacts that have one, by base.
+    pub fn dependency_records(&self) -> MagResult<BTreeMap<String, DependencyRecord>> {
+        let conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        let mut records: BTreeMap<String, DependencyRecord> = BTreeMap::new();
+        let mut stmt = conn
+            .prepare("SELECT base, hash FROM artifacts WHERE has_deps")
+            .map_err(db_error)?;
+        let mut rows = stmt.query([]).map_err(db_error)?;
+        while let Some(row) = rows.next().map_err(db_error)? {
+            let base: String = row.get(0).map_err(db_error)?;
+            let hash: String = row.get(1).map_err(db_error)?;
+            records.insert(
+                base,
+                DependencyRecord {
+                    hash,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT base, dep, kind FROM edges ORDER BY rowid")
+            .map_err(db_error)?;
+        let mut rows = stmt.query([]).map_err(db_error)?;
+        while let Some(row) = rows.next().map_err(db_error)? {
+            let base: String = row.get(0).map_err(db_error)?;
+            let dep: String = row.get(1).map_err(db_error)?;
+            let kind: String = row.get(2).map_err(db_error)?;
+            if let Some(record) = records.get_mut(&base) {
+                match kind.as_str() {
+                    "run" => record.run.push(dep),
+                    _ => record.build.push(dep),
+                }
+            }
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT base, sha256 FROM fetches ORDER BY rowid")
+            .map_err(db_error)?;
+        let mut rows = stmt.query([]).map_err(db_error)?;
+        while let Some(row) = rows.next().map_err(db_error)? {
+            let base: String = row.get(0).map_err(db_error)?;
+            let sha: String = row.get(1).map_err(db_error)?;
+            if let Some(record) = records.get_mut(&base) {
+                record.fetches.push(sha);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Base names of the artifacts that depend directly on `dep`.
+    pub fn dependents(&self, dep: &str) -> MagResult<Vec<String>> {
+        let conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT base FROM edges WHERE dep = ?1 ORDER BY base")
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map(params![dep], |row| row.get(0))
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+}
+
+fn insert_artifact(
+    conn: &Connection,
+    row: &ArtifactRow,
+    deps: Option<&DependencyRecord>,
+) -> MagResult<()> {
+    let existing: Option<(Option<i64>, Option<String>, Option<String>)> = conn
+        .query_row(
+            "SELECT built_at, builder, expression FROM artifacts WHERE base = ?1",
+            params![row.base],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+    let (old_built_at, old_builder, old_expression) = existing.unwrap_or_default();
+    conn.execute("DELETE FROM artifacts WHERE base = ?1", params![row.base])
+        .map_err(db_error)?;
+    conn.execute(
+        "INSERT INTO artifacts (base, hash, size, last_access, has_deps, built_at, builder, expression)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            row.base,
+            row.hash,
+            row.size as i64,
+            row.last_access as i64,
+            deps.is_some(),
+            row.built_at.map(|time| time as i64).or(old_built_at),
+            row.builder.clone().or(old_builder),
+            row.expression.clone().or(old_expression),
+        ],
+    )
+    .map_err(db_error)?;
+    let Some(deps) = deps else {
+        return Ok(());
+    };
+    for (kind, list) in [("run", &deps.run), ("build", &deps.build)] {
+        for dep in list {
+            conn.execute(
+                "INSERT INTO edges (base, dep, kind) VALUES (?1, ?2, ?3)",
+                params![row.base, dep, kind],
+            )
+            .map_err(db_error)?;
+        }
+    }
+    for sha in &deps.fetches {
+        conn.execute(
+            "INSERT INTO fetches (base, sha256) VALUES (?1, ?2)",
+            params![row.base, sha],
+        )
+        .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+type Provenance = (Option<u64>, Option<String>, Option<String>);
+
+fn read_provenance(conn: &Connection) -> MagResult<BTreeMap<String, Provenance>> {
+    let mut stmt = conn
+        .prepare("SELECT base, built_at, builder, expression FROM artifacts")
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                (
+                    row.get::<_, Option<i64>>(1)?.map(|time| time as u64),
+                    row.get(2)?,
+                    row.get(3)?,
+                ),
+            ))
+        })
+        .map_err(db_error)?;
+    rows.collect::<Result<_, _>>().map_err(db_error)
+}
@@ -0,0 +1,89 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use crate::{MagError, MagResult};
+
+pub const FETCH_MIRRORS_CONFIG_FILE: &str = "fetch-mirrors.conf";
+
+/// URL rewrites that send fetches to internal mirrors, read from
+/// `<store>/fetch-mirrors.conf`:
+///
+/// ```text
+/// mirror   <upstream-prefix> <mirror-prefix>   (repeatable)
+/// fallback on|off                              (default on)
+/// ```
+///
+/// A fetch URL starting with an upstream prefix is tried with that prefix
+/// replaced before any other source; the longest matching prefix wins.
+/// With `fallback on` the upstream URL stays in the list after it, so a
+/// file missing from the mirror is still downloaded. Manifests keep their
+/// canonical URLs, and since fetches are verified by sha256, the package
+/// hashes do not change.
+#[derive(Debug, Default)]
+pub struct MirrorMap {
+    rules: Vec<(String, String)>,
+    no_fallback: bool,
+}
+
+impl MirrorMap {
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        let mut mirrors = Self::default();
+        let path = base_root.join(FETCH_MIRRORS_CONFIG_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(mirrors),
+            Err(err) => return Err(err.into()),
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                MagError::Generic(format!(
+                    "{}:{}: invalid mirror setting {line:?}",
+                    path.display(),
+                    index + 1
+                ))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["mirror", upstream, mirror]
+                    if is_url_prefix(upstream) && is_url_prefix(mirror) =>
+                {
+                    mirrors
+                        .rules
+                        .push((upstream.to_string(), mirror.to_string()));
+                }
+                ["fallback", "on"] => mirrors.no_fallback = false,
+                ["fallback", "off"] => mirrors.no_fallback = true,
+                _ => return Err(invalid()),
+            }
+        }
+        // Longest prefix first, so the first match is the most specific.
+        mirrors
+            .rules
+            .sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Ok(mirrors)
+    }
+
+    /// The mirror URL for `url`, if a rule matches.
+    pub fn rewrite(&self, url: &str) -> Option<String> {
+        self.rules.iter().find_map(|(upstream, mirror)| {
+            url.strip_prefix(upstream.as_str())
+                .map(|rest| format!("{mirror}{rest}"))
+        })
+    }
+
+    /// Whether upstream URLs are still tried after their mirror.
+    pub fn fallback(&self) -> bool {
+        !self.no_fallback
+    }
+}
+
+/// Whether `value` is an `http://` or `https://` URL prefix with more
+/// than the scheme, as config files accept them.
+pub fn is_url_prefix(value: &str) -> bool {
+    ["http://", "https://"]
+        .iter()
+        .any(|scheme| value.len() > scheme.len() && value.starts_with(scheme))
+}
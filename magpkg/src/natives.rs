@@ -0,0 +1,381 @@
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    env, fs,
+};
+
+use jrsonnet_evaluator::{
+    ContextBuilder, ContextInitializer, ObjValue, ObjValueBuilder, Thunk, Val,
+    error::{ErrorKind, Result as JrResult},
+    function::{FuncVal, builtin},
+    parser::Source,
+};
+use jrsonnet_gcmodule::{Trace, Tracer};
+use jrsonnet_stdlib::ContextInitializer as StdlibContext;
+use reqwest::Url;
+
+use crate::package::{DEFAULT_OUTPUT, ObjKey};
+
+/// Evaluator context that layers a `magpkg` object of helper functions on top
+/// of the Jsonnet standard library. Without `host_access`, the helpers that
+/// read the host (`envVar` and `readFile`) fail instead.
+pub struct MagContext {
+    std: StdlibContext,
+    host_access: bool,
+}
+
+impl MagContext {
+    pub fn new(std: StdlibContext, host_access: bool) -> Self {
+        Self { std, host_access }
+    }
+}
+
+impl Trace for MagContext {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        self.std.trace(tracer);
+    }
+
+    fn is_type_tracked() -> bool
+    where
+        Self: Sized,
+    {
+        true
+    }
+}
+
+impl ContextInitializer for MagContext {
+    fn reserve_vars(&self) -> usize {
+        self.std.reserve_vars() + 1
+    }
+
+    fn populate(&self, for_file: Source, builder: &mut ContextBuilder) {
+        self.std.populate(for_file, builder);
+        builder.bind(
+            "magpkg",
+            Thunk::evaluated(Val::Obj(magpkg_library(self.host_access))),
+        );
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn magpkg_library(host_access: bool) -> ObjValue {
+    let mut builder = ObjValueBuilder::new();
+    builder.method("fetchurl", builtin_fetchurl::INST);
+    builder.method("arch", builtin_arch::INST);
+    if host_access {
+        builder.method("envVar", builtin_env_var::INST);
+        builder.method("readFile", builtin_read_file::INST);
+    } else {
+        builder.method("envVar", builtin_env_var_disabled::INST);
+        builder.method("readFile", builtin_read_file_disabled::INST);
+    }
+    builder.method("passthru", builtin_passthru::INST);
+    builder.method("collectPassthru", builtin_collect_passthru::INST);
+    builder.method("withPassthru", builtin_with_passthru::INST);
+    builder.method("override", builtin_override::INST);
+    builder.method("overlay", builtin_overlay::INST);
+    builder.method("composeVenv", builtin_compose_venv::INST);
+    builder.method("output", builtin_output::INST);
+    builder.method("outputs", builtin_outputs::INST);
+    builder.build()
+}
+
+/// `magpkg.fetchurl(url, sha256)` builds a fetch entry whose filename is the
+/// last path segment of the URL.
+#[builtin]
+fn builtin_fetchurl(url: String, sha256: String) -> JrResult<ObjValue> {
+    let parsed = Url::parse(&url)
+        .map_err(|err| ErrorKind::RuntimeError(format!("invalid url {url}: {err}").into()))?;
+    let filename = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| {
+            ErrorKind::RuntimeError(format!("cannot derive a filename from {url}").into())
+        })?
+        .to_string();
+
+    let mut builder = ObjValueBuilder::new();
+    builder.field("filename").value(Val::string(filename));
+    builder.field("sha256").value(Val::string(sha256));
+    builder
+        .field("urls")
+        .value(Val::Arr(vec![Val::string(url)].into()));
+    Ok(builder.build())
+}
+
+/// `magpkg.arch()` returns the host CPU architecture, e.g. `x86_64`.
+#[builtin]
+fn builtin_arch() -> String {
+    env::consts::ARCH.to_string()
+}
+
+/// `magpkg.envVar(name)` returns the variable's value or `null` when unset.
+#[builtin]
+fn builtin_env_var(name: String) -> Option<String> {
+    env::var(name).ok()
+}
+
+/// `magpkg.envVar` in an evaluator without host access.
+#[builtin]
+fn builtin_env_var_disabled(name: String) -> JrResult<Option<String>> {
+    Err(ErrorKind::RuntimeError(
+        format!("magpkg.envVar({name:?}) is not available; filesystem access is disabled").into(),
+    )
+    .into())
+}
+
+/// `magpkg.passthru(pkg, key, default=null)` returns `pkg.passthru[key]`, or
+/// `default` when the package does not expose it.
+#[builtin]
+fn builtin_passthru(pkg: ObjValue, key: String, default: Option<Val>) -> JrResult<Val> {
+    Ok(passthru_value(&pkg, &key)?.unwrap_or_else(|| default.unwrap_or(Val::Null)))
+}
+
+/// `magpkg.collectPassthru(pkgs, key)` returns the `key` passthru values of
+/// the packages in `pkgs` that expose it, in order.
+#[builtin]
+fn builtin_collect_passthru(pkgs: Vec<ObjValue>, key: String) -> JrResult<Vec<Val>> {
+    let mut values = Vec::new();
+    for pkg in &pkgs {
+        if let Some(value) = passthru_value(pkg, &key)? {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+/// `magpkg.withPassthru(pkg, attrs)` returns `pkg` with `attrs` merged into
+/// its passthru object. The package hash is unchanged.
+#[builtin]
+fn builtin_with_passthru(pkg: ObjValue, attrs: ObjValue) -> JrResult<ObjValue> {
+    let merged = match pkg.get("passthru".into())? {
+        Some(Val::Obj(existing)) => attrs.extend_from(existing),
+        None | Some(Val::Null) => attrs,
+        Some(other) => return Err(passthru_type_error(&other).into()),
+    };
+    let mut builder = ObjValueBuilder::new();
+    builder.field("passthru").value(Val::Obj(merged));
+    Ok(builder.build().extend_from(pkg))
+}
+
+/// `magpkg.override(pkg, attrs)` returns `pkg` with `attrs` layered on top,
+/// exactly like `pkg + attrs`; `super` inside `attrs` refers to `pkg`.
+#[builtin]
+fn builtin_override(pkg: ObjValue, attrs: ObjValue) -> ObjValue {
+    attrs.extend_from(pkg)
+}
+
+/// `magpkg.overlay(pkgs, overrides)` rewrites a package or array of packages
+/// so that every package named in `overrides`, however deep in the graph, has
+/// its attributes layered on top. Dependents of an overridden package are
+/// rebuilt to point at the new value; untouched subgraphs are shared.
+#[builtin]
+fn builtin_overlay(pkgs: Val, overrides: ObjValue) -> JrResult<Val> {
+    let mut overlay = Overlay {
+        overrides,
+        done: HashMap::new(),
+        visiting: HashSet::new(),
+    };
+    match pkgs {
+        Val::Obj(pkg) => Ok(Val::Obj(overlay.rewrite(pkg)?)),
+        Val::Arr(arr) => {
+            let mut rewritten = Vec::with_capacity(arr.len());
+            for item in arr.iter() {
+                rewritten.push(Val::Obj(overlay.rewrite(expect_package(item?)?)?));
+            }
+            Ok(Val::Arr(rewritten.into()))
+        }
+        other => Err(ErrorKind::RuntimeError(
+            format!(
+                "overlay expects a package or an array of packages, got {:?}",
+                other.value_type()
+            )
+            .into(),
+        )
+        .into()),
+    }
+}
+
+struct Overlay {
+    overrides: ObjValue,
+    done: HashMap<ObjKey, ObjValue>,
+    visiting: HashSet<ObjKey>,
+}
+
+impl Overlay {
+    /// Rewrite a dependency, which is either a package or a
+    /// `magpkg.output` reference to one.
+    fn rewrite_dep(&mut self, dep: ObjValue) -> JrResult<ObjValue> {
+        let owner = match dep.get("outputOf".into())? {
+            Some(owner) => expect_package(owner)?,
+            None => return self.rewrite(dep),
+        };
+        let new_owner = self.rewrite(owner.clone())?;
+        if ObjValue::ptr_eq(&owner, &new_owner) {
+            return Ok(dep);
+        }
+        let name = match dep.get("output".into())? {
+            Some(Val::Str(name)) => name.to_string(),
+            _ => {
+                return Err(ErrorKind::RuntimeError(
+                    "overlay: output reference without an output name".into(),
+                )
+                .into());
+            }
+        };
+        Ok(output_reference(new_owner, name))
+    }
+
+    fn rewrite(&mut self, pkg: ObjValue) -> JrResult<ObjValue> {
+        let key = ObjKey::new(pkg.clone());
+        if let Some(done) = self.done.get(&key) {
+            return Ok(done.clone());
+        }
+        if !self.visiting.insert(key.clone()) {
+            return Err(ErrorKind::RuntimeError("overlay: dependency cycle".into()).into());
+        }
+
+        let mut builder = ObjValueBuilder::new();
+        let mut changed = false;
+        for field in ["runDeps", "buildDeps"] {
+            let Some(Val::Arr(deps)) = pkg.get(field.into())? else {
+                continue;
+            };
+            let mut rewritten = Vec::with_capacity(deps.len());
+            let mut field_changed = false;
+            for dep in deps.iter() {
+                let dep = expect_package(dep?)?;
+                let new_dep = self.rewrite_dep(dep.clone())?;
+                field_changed |= !ObjValue::ptr_eq(&dep, &new_dep);
+                rewritten.push(Val::Obj(new_dep));
+            }
+            if field_changed {
+                builder.field(field).value(Val::Arr(rewritten.into()));
+                changed = true;
+            }
+        }
+
+        let attrs = match pkg.get("name".into())? {
+            Some(Val::Str(name)) => match self.overrides.get(name.to_string().as_str().into())? {
+                Some(Val::Obj(attrs)) => Some(attrs),
+                None | Some(Val::Null) => None,
+                Some(other) => {
+                    return Err(ErrorKind::RuntimeError(
+                        format!(
+                            "overlay: override for {name} must be an object, got {:?}",
+                            other.value_type()
+                        )
+                        .into(),
+                    )
+                    .into());
+                }
+            },
+            _ => None,
+        };
+
+        let mut result = if changed {
+            builder.build().extend_from(pkg.clone())
+        } else {
+            pkg.clone()
+        };
+        if let Some(attrs) = attrs {
+            result = attrs.extend_from(result);
+        }
+
+        self.visiting.remove(&key);
+        self.done.insert(key, result.clone());
+        Ok(result)
+    }
+}
+
+/// `magpkg.output(pkg, name)` refers to one output of a multi-output
+/// package. The reference can be used wherever a package is expected in
+/// `runDeps` and `buildDeps`; `"out"` refers to the package itself.
+#[builtin]
+fn builtin_output(pkg: ObjValue, name: String) -> ObjValue {
+    output_reference(pkg, name)
+}
+
+/// `magpkg.outputs(self, names)` declares a package's outputs as an object
+/// with one reference per output, so dependents can write `pkg.outputs.dev`.
+/// Its `out` field is the package itself.
+#[builtin]
+fn builtin_outputs(pkg: ObjValue, names: Vec<String>) -> ObjValue {
+    let mut builder = ObjValueBuilder::new();
+    builder.field(DEFAULT_OUTPUT).value(Val::Obj(pkg.clone()));
+    for name in names {
+        if name != DEFAULT_OUTPUT {
+            let reference = output_reference(pkg.clone(), name.clone());
+            builder.field(name).value(Val::Obj(reference));
+        }
+    }
+    builder.build()
+}
+
+fn output_reference(pkg: ObjValue, name: String) -> ObjValue {
+    let mut builder = ObjValueBuilder::new();
+    builder.field("outputOf").value(Val::Obj(pkg));
+    builder.field("output").value(Val::string(name));
+    builder.build()
+}
+
+/// `magpkg.composeVenv(specs)` returns a venv spec that extends each of
+/// `specs` in order; `magpkg venv` merges them, later specs taking precedence.
+/// Extra fields can be layered on with `+`.
+#[builtin]
+fn builtin_compose_venv(specs: Vec<ObjValue>) -> ObjValue {
+    let mut builder = ObjValueBuilder::new();
+    builder.field("extends").value(Val::Arr(
+        specs.into_iter().map(Val::Obj).collect::<Vec<_>>().into(),
+    ));
+    builder.build()
+}
+
+fn expect_package(value: Val) -> JrResult<ObjValue> {
+    match value {
+        Val::Obj(obj) => Ok(obj),
+        other => Err(ErrorKind::RuntimeError(
+            format!("expected a package object, got {:?}", other.value_type()).into(),
+        )
+        .into()),
+    }
+}
+
+fn passthru_value(pkg: &ObjValue, key: &str) -> JrResult<Option<Val>> {
+    match pkg.get("passthru".into())? {
+        Some(Val::Obj(passthru)) => Ok(passthru.get(key.into())?),
+        None | Some(Val::Null) => Ok(None),
+        Some(other) => Err(passthru_type_error(&other).into()),
+    }
+}
+
+fn passthru_type_error(value: &Val) -> ErrorKind {
+    ErrorKind::RuntimeError(
+        format!(
+            "expected field 'passthru' to be an object, got {:?}",
+            value.value_type()
+        )
+        .into(),
+    )
+}
+
+/// `magpkg.readFile(path)` returns a file's contents as a string.
+#[builtin]
+fn builtin_read_file(path: String) -> JrResult<String> {
+    fs::read_to_string(&path).map_err(|err| {
+        ErrorKind::RuntimeError(format!("failed to read {path}: {err}").into()).into()
+    })
+}
+
+/// `magpkg.readFile` in an evaluator without host access.
+#[builtin]
+fn builtin_read_file_disabled(path: String) -> JrResult<String> {
+    Err(ErrorKind::RuntimeError(
+        format!("magpkg.readFile({path:?}) is not available; filesystem access is disabled").into(),
+    )
+    .into())
+}
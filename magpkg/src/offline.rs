@@ -0,0 +1,24 @@
+use std::sync::OnceLock;
+
+static OFFLINE: OnceLock<bool> = OnceLock::new();
+
+/// Forbid network access for the rest of the run (`--offline`). Later calls
+/// are ignored.
+///
+/// Offline, remote imports must already be in the import cache, fetches are
+/// served from the fetch cache, `--link-from` files, local sources, and
+/// `file://` URLs only, and binary caches, torrent indexes, and webhooks are
+/// not contacted. Builds check every source they need before starting, so
+/// one error lists everything that is missing.
+pub fn set_offline(offline: bool) {
+    let _ = OFFLINE.set(offline);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.get().copied().unwrap_or(false)
+}
+
+/// Whether fetching `url` stays on this machine.
+pub fn is_local_url(url: &str) -> bool {
+    url.starts_with("file://")
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use tar::{Builder, EntryType, Header};
+
+use crate::{MagError, MagResult};
+
+/// Ownership, modes, and device numbers that a `fakeroot -s` session
+/// recorded for files it faked, keyed by the files' device and inode.
+///
+/// ```text
+/// dev=fd03,ino=1834,mode=104755,uid=0,gid=0,nlink=1,rdev=0
+/// dev=fd03,ino=1840,mode=20666,uid=0,gid=0,nlink=1,rdev=259
+/// ```
+///
+/// `dev` is hexadecimal and `mode` octal. A device node created under
+/// fakeroot is an empty regular file on disk; its recorded mode carries the
+/// real type.
+#[derive(Default)]
+pub struct FakerootState {
+    entries: HashMap<(u64, u64), FakedStat>,
+}
+
+#[derive(Clone, Copy)]
+struct FakedStat {
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    rdev: u64,
+}
+
+impl FakerootState {
+    /// Read a saved fakeroot database. A missing file means the session
+    /// faked nothing.
+    pub fn load(path: &Path) -> MagResult<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut entries = HashMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid = || {
+                MagError::Generic(format!(
+                    "unrecognized fakeroot state line in {}: {line}",
+                    path.display()
+                ))
+            };
+            let mut fields = HashMap::new();
+            for field in line.split(',') {
+                let (key, value) = field.split_once('=').ok_or_else(invalid)?;
+                fields.insert(key, value);
+            }
+            let number = |key: &str, radix: u32| {
+                fields
+                    .get(key)
+                    .and_then(|value| u64::from_str_radix(value, radix).ok())
+                    .ok_or_else(invalid)
+            };
+            let stat = FakedStat {
+                mode: number("mode", 8)? as u32,
+                uid: number("uid", 10)?,
+                gid: number("gid", 10)?,
+                rdev: number("rdev", 10)?,
+            };
+            entries.insert((number("dev", 16)?, number("ino", 10)?), stat);
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Append the tree at `root` to `builder` as `./...` entries in sorted
+/// order. Owners are normalized to 0:0, mtimes to 0, and user and group
+/// names are left empty, so the archive depends only on the tree's names,
+/// contents, and modes. With a fakeroot state, files it recorded take their
+/// owner, mode, and device type from the state instead.
+pub fn append_tree<W: Write>(
+    builder: &mut Builder<W>,
+    root: &Path,
+    fakeroot: Option<&FakerootState>,
+) -> MagResult<()> {
+    let name = Path::new(".");
+    append_entry(builder, root, name, fakeroot, false)?;
+    append_dir_contents(builder, root, name, fakeroot, false, &|_| true)
+}
+
+/// Like [`append_tree`] without a fakeroot state, skipping every entry
+/// (and everything beneath it) for which `keep` returns false. `keep` sees
+/// the entry's path relative to `root`. Modes are normalized as well: 0755
+/// for directories and files with any execute bit, 0644 for other files,
+/// so the archive does not depend on the umask of the checkout.
+pub fn append_tree_filtered<W: Write>(
+    builder: &mut Builder<W>,
+    root: &Path,
+    keep: &dyn Fn(&Path) -> bool,
+) -> MagResult<()> {
+    let name = Path::new(".");
+    append_entry(builder, root, name, None, true)?;
+    append_dir_contents(builder, root, name, None, true, keep)
+}
+
+fn append_dir_contents<W: Write>(
+    builder: &mut Builder<W>,
+    dir: &Path,
+    prefix: &Path,
+    fakeroot: Option<&FakerootState>,
+    normalize_modes: bool,
+    keep: &dyn Fn(&Path) -> bool,
+) -> MagResult<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        if !keep(name.strip_prefix(".").unwrap_or(&name)) {
+            continue;
+        }
+        append_entry(builder, &path, &name, fakeroot, normalize_modes)?;
+        if entry.file_type()?.is_dir() {
+            append_dir_contents(builder, &path, &name, fakeroot, normalize_modes, keep)?;
+        }
+    }
+    Ok(())
+}
+
+fn append_entry<W: Write>(
+    builder: &mut Builder<W>,
+    path: &Path,
+    name: &Path,
+    fakeroot: Option<&FakerootState>,
+    normalize_modes: bool,
+) -> MagResult<()> {
+    let meta = fs::symlink_metadata(path)?;
+    let faked = fakeroot.and_then(|state| state.entries.get(&(meta.dev(), meta.ino())));
+
+    let disk_type = meta.mode() & libc::S_IFMT;
+    let file_type = match faked {
+        Some(faked) if disk_type == libc::S_IFREG => faked.mode & libc::S_IFMT,
+        _ => disk_type,
+    };
+    let (mode, uid, gid, rdev) = match faked {
+        Some(faked) => (faked.mode, faked.uid, faked.gid, faked.rdev),
+        None => (meta.mode(), 0, 0, meta.rdev()),
+    };
+    let mode = match file_type {
+        _ if !normalize_modes => mode,
+        libc::S_IFDIR | libc::S_IFLNK => 0o755,
+        _ if mode & 0o111 != 0 => 0o755,
+        _ => 0o644,
+    };
+
+    let mut header = Header::new_gnu();
+    header.set_mode(mode & 0o7777);
+    header.set_uid(uid);
+    header.set_gid(gid);
+    header.set_mtime(0);
+    header.set_size(0);
+
+    match file_type {
+        libc::S_IFDIR => {
+            header.set_entry_type(EntryType::Directory);
+            builder.append_data(&mut header, name, io::empty())?;
+        }
+        libc::S_IFLNK => {
+            header.set_entry_type(EntryType::Symlink);
+            builder.append_link(&mut header, name, fs::read_link(path)?)?;
+        }
+        libc::S_IFREG => {
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(meta.len());
+            builder.append_data(&mut header, name, File::open(path)?)?;
+        }
+        libc::S_IFCHR | libc::S_IFBLK | libc::S_IFIFO => {
+            header.set_entry_type(match file_type {
+                libc::S_IFCHR => EntryType::Char,
+                libc::S_IFBLK => EntryType::Block,
+                _ => EntryType::Fifo,
+            });
+            header.set_device_major(libc::major(rdev))?;
+            header.set_device_minor(libc::minor(rdev))?;
+            builder.append_data(&mut header, name, io::empty())?;
+        }
+        // Sockets cannot be represented in a tar archive.
+        _ => {}
+    }
+    Ok(())
+}
@@ -1,21 +1,236 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::{Component, Path},
     rc::Rc,
 };
 
+use base64::{Engine as _, prelude::BASE64_STANDARD};
 use jrsonnet_evaluator::{ObjValue, Val};
 use sha2::{Digest, Sha256};
 
-use crate::{MagError, MagResult, errors::format_jr_error};
+use crate::{
+    MagError, MagResult, codec::ArtifactCodec, errors::format_jr_error, localsrc::LocalSource,
+};
 
 #[derive(Debug)]
 pub struct Package {
     pub name: Option<String>,
     pub build: String,
+    /// Scripts run around `build` in the same sandbox (the `preBuild`,
+    /// `check`, and `postBuild` fields).
+    pub hooks: BuildHooks,
     pub hash: String,
     pub run_deps: Vec<Rc<Package>>,
     pub build_deps: Vec<Rc<Package>>,
     pub fetch: Vec<FetchResource>,
+    pub sandbox: SandboxOptions,
+    pub budget: OutputBudget,
+    pub compression: CompressionOverride,
+    /// Whether to lint the output for dangling symlinks and missing ELF
+    /// libraries after the build (the `lint` field, default `true`).
+    pub lint: bool,
+    /// Whether ELF libraries and interpreters missing from the runtime
+    /// closure fail the build (the `checkRuntimeDeps` field, default `false`).
+    pub check_runtime_deps: bool,
+    /// Shell that runs the build script (the `builderShell` field). `None`
+    /// uses `/bin/sh` from the dependency rootfs.
+    pub builder_shell: Option<BuilderShell>,
+    /// Interpreter and leading arguments that run the build script instead
+    /// of the shell (the `buildWith` field); empty for the shell.
+    pub build_with: Vec<String>,
+    /// Extra environment variables for the build script (the `env` field),
+    /// as written; see [`resolve_build_env`].
+    pub env: BTreeMap<String, String>,
+    /// Named outputs the build script produces (the `outputs` field). The
+    /// first is always `out`; the others are packed into artifacts of their
+    /// own and referenced through [`OutputOf`] packages.
+    pub outputs: Vec<String>,
+    /// Set on the package that stands for a non-default output of another
+    /// package, created for `magpkg.output(pkg, name)` references.
+    pub output_of: Option<OutputOf>,
+    pub metadata: PackageMetadata,
+}
+
+/// Descriptive fields of a package (`version`, `license`, `homepage`, and
+/// `description`), reported by `magpkg sbom` and recorded next to the
+/// artifact. They only contribute to the package hash with `hashMetadata:
+/// true`; otherwise packages that differ only in metadata are the same
+/// package and the first one wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageMetadata {
+    pub version: Option<String>,
+    /// SPDX license expression, e.g. `GPL-2.0-only OR MIT`.
+    pub license: Option<String>,
+    pub homepage: Option<String>,
+    pub description: Option<String>,
+}
+
+impl PackageMetadata {
+    fn fields(&self) -> impl Iterator<Item = (&'static str, Option<&str>)> {
+        [
+            ("version", self.version.as_deref()),
+            ("license", self.license.as_deref()),
+            ("homepage", self.homepage.as_deref()),
+            ("description", self.description.as_deref()),
+        ]
+        .into_iter()
+    }
+
+    /// The `<base>.meta` record, one `field<TAB>value` line per set field.
+    /// Line breaks and tabs in values are written as spaces.
+    ///
+    /// ```text
+    /// version      1.3.1
+    /// license      Zlib
+    /// homepage     https://zlib.net
+    /// description  compression library
+    /// ```
+    pub fn to_record(&self) -> String {
+        let mut record = String::new();
+        for (field, value) in self.fields() {
+            if let Some(value) = value {
+                let value = value.replace(['\n', '\t', '\r'], " ");
+                record.push_str(&format!("{field}\t{value}\n"));
+            }
+        }
+        record
+    }
+
+    /// Parse a record written by [`PackageMetadata::to_record`]. Unknown
+    /// fields are ignored.
+    pub fn from_record(record: &str) -> Self {
+        let mut metadata = Self::default();
+        for line in record.lines() {
+            let Some((field, value)) = line.split_once('\t') else {
+                continue;
+            };
+            let slot = match field {
+                "version" => &mut metadata.version,
+                "license" => &mut metadata.license,
+                "homepage" => &mut metadata.homepage,
+                "description" => &mut metadata.description,
+                _ => continue,
+            };
+            *slot = Some(value.to_string());
+        }
+        metadata
+    }
+}
+
+/// A secondary output such as `dev` or `doc`. The package standing for it
+/// has no build of its own: it depends at runtime on its owner's `out`, and
+/// its artifact is packed when the owner is built.
+#[derive(Debug, Clone)]
+pub struct OutputOf {
+    pub owner: Rc<Package>,
+    pub output: String,
+}
+
+pub const DEFAULT_OUTPUT: &str = "out";
+
+/// Highest zstd level accepted for artifacts.
+pub const MAX_COMPRESSION_LEVEL: i32 = 22;
+
+pub const DEFAULT_BUILDER_SHELL: &str = "/bin/sh";
+
+/// Build phases besides the main `build` script, each run by the builder
+/// shell. Empty strings are phases the package does not have.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildHooks {
+    pub pre_build: String,
+    pub check: String,
+    pub post_build: String,
+}
+
+impl BuildHooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_build.is_empty() && self.check.is_empty() && self.post_build.is_empty()
+    }
+
+    /// The manifest field name and script of each phase, in running order
+    /// around `build`: `preBuild` before it, `check` and `postBuild` after.
+    pub fn phases(&self) -> [(&'static str, &str); 3] {
+        [
+            ("preBuild", &self.pre_build),
+            ("check", &self.check),
+            ("postBuild", &self.post_build),
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BuilderShell {
+    /// Absolute path of a shell provided by the dependency rootfs.
+    Path(String),
+    /// Package whose `bin/sh` runs the build; it is mounted read-only and
+    /// should be statically linked.
+    Package(Rc<Package>),
+}
+
+/// Per-package adjustments to the build sandbox, read from the `sandbox`
+/// field. Only options that are set contribute to the package hash.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxOptions {
+    /// Fixed wall-clock time presented to the build through libfaketime.
+    pub fake_time: Option<String>,
+    /// Seed for the deterministic stream served as /dev/urandom and /dev/random.
+    pub random_seed: Option<String>,
+    /// Host paths bound read-only at the same location inside the sandbox.
+    pub ro_binds: Vec<String>,
+    /// Whether the host's /dev is shared (the default) or replaced by a
+    /// minimal device tree.
+    pub host_dev: Option<bool>,
+    /// User and group ids the build runs as inside a user namespace.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Hostname reported inside a private UTS namespace.
+    pub hostname: Option<String>,
+    /// Run the build under `fakeroot` and pack the owners, modes, and device
+    /// nodes it recorded.
+    pub fakeroot: bool,
+}
+
+/// Sandbox directories that magpkg populates itself; binds may not shadow them.
+const RESERVED_SANDBOX_PATHS: &[&str] = &[
+    "/", "/build", "/fetch", "/out", "/outputs", "/store", "/proc", "/tmp",
+];
+
+/// Variables magpkg sets for every build, or for the sandbox options that
+/// rely on them; the `env` field may not override them.
+const RESERVED_BUILD_ENV: &[&str] = &[
+    "SHELL",
+    "CONFIG_SHELL",
+    "BUILD_PARALLELISM",
+    "LD_PRELOAD",
+    "FAKETIME",
+    "FAKETIME_DONT_RESET",
+];
+
+/// Expected size of a package's output, read from the `budget` field. The
+/// budget only guards the build and does not contribute to the package hash.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBudget {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    pub policy: BudgetPolicy,
+}
+
+/// Per-package artifact codec and compression settings, read from the
+/// `compression` field.
+/// Unset values fall back to the `magpkg build` options. Like budgets, they
+/// do not contribute to the package hash.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionOverride {
+    pub codec: Option<ArtifactCodec>,
+    pub level: Option<i32>,
+    pub threads: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    Warn,
+    #[default]
+    Fail,
 }
 
 #[derive(Debug, Clone)]
@@ -23,6 +238,12 @@ pub struct FetchResource {
     pub filename: String,
     pub sha256: String,
     pub urls: Vec<String>,
+    /// Set for `src` entries, which archive a local directory instead of
+    /// downloading and appear in `/fetch/` as a directory.
+    pub source: Option<LocalSource>,
+    /// Set for `type: "inline"` entries, whose contents are given in the
+    /// manifest.
+    pub inline: Option<Vec<u8>>,
 }
 
 #[derive(Default)]
@@ -78,13 +299,60 @@ impl PackageGraphBuilder {
         }
 
         let result = (|| -> MagResult<Rc<Package>> {
+            match get_field(&obj, "outputOf")? {
+                None | Some(Val::Null) => {}
+                Some(owner @ Val::Obj(_)) => {
+                    let output = read_required_string(&obj, "output", "output reference")?;
+                    let owner = self.build_from_val(owner, visiting)?;
+                    let package = self.output_package(owner, &output)?;
+                    self.by_obj.insert(key.clone(), package.clone());
+                    return Ok(package);
+                }
+                Some(other) => {
+                    return Err(MagError::Generic(format!(
+                        "expected field 'outputOf' to be a package, got {:?}",
+                        other.value_type()
+                    )));
+                }
+            }
+
             let name = read_package_name(&obj)?;
             let run_deps = self.collect_dependencies(&obj, "runDeps", visiting)?;
             let build_deps = self.collect_dependencies(&obj, "buildDeps", visiting)?;
             let build_script = read_build_script(&obj)?;
+            let hooks = read_build_hooks(&obj)?;
             let fetch = read_fetch_list(&obj)?;
+            let sandbox = read_sandbox_options(&obj)?;
+            let builder_shell = self.read_builder_shell(&obj, visiting)?;
+            let build_with = read_build_with(&obj)?;
+            let env = read_build_env(&obj)?;
+            expand_store_refs(&env, &build_deps)?;
+            resolve_build_script(&build_script, "build", &build_deps)?;
+            for (phase, script) in hooks.phases() {
+                resolve_build_script(script, phase, &build_deps)?;
+            }
+            let outputs = read_outputs(&obj)?;
+            let budget = read_output_budget(&obj)?;
+            let compression = read_compression(&obj)?;
+            let lint = read_optional_bool(&obj, "lint", "package")?.unwrap_or(true);
+            let check_runtime_deps =
+                read_optional_bool(&obj, "checkRuntimeDeps", "package")?.unwrap_or(false);
+            validate_passthru(&obj)?;
+            let metadata = read_package_metadata(&obj)?;
+            let hash_metadata =
+                read_optional_bool(&obj, "hashMetadata", "package")?.unwrap_or(false);
 
             let build_is_empty = build_script.trim().is_empty();
+            if !hooks.is_empty() && (build_is_empty || build_script == "untar") {
+                return Err(MagError::Generic(
+                    "preBuild, check, and postBuild need a build script to run around".into(),
+                ));
+            }
+            if outputs.len() > 1 && (build_is_empty || build_script == "untar") {
+                return Err(MagError::Generic(
+                    "packages with several outputs need a build script that fills them".into(),
+                ));
+            }
             if build_is_empty && fetch.is_empty() && run_deps.is_empty() && build_deps.is_empty() {
                 return Err(MagError::Generic(
                     "package definition must declare a build script, fetch entry, or dependencies"
@@ -92,7 +360,19 @@ impl PackageGraphBuilder {
                 ));
             }
 
-            let hash = compute_hash(&build_script, &fetch, &run_deps, &build_deps);
+            let hash = compute_hash(
+                &build_script,
+                &hooks,
+                &fetch,
+                &run_deps,
+                &build_deps,
+                &sandbox,
+                builder_shell.as_ref(),
+                &build_with,
+                &env,
+                &outputs,
+                hash_metadata.then_some(&metadata),
+            );
 
             if let Some(existing) = self.by_hash.get(&hash) {
                 self.by_obj.insert(key.clone(), existing.clone());
@@ -102,10 +382,22 @@ impl PackageGraphBuilder {
             let package = Rc::new(Package {
                 name,
                 build: build_script,
+                hooks,
                 hash: hash.clone(),
                 run_deps,
                 build_deps,
                 fetch,
+                sandbox,
+                budget,
+                compression,
+                lint,
+                check_runtime_deps,
+                builder_shell,
+                build_with,
+                env,
+                outputs,
+                output_of: None,
+                metadata,
             });
 
             self.by_obj.insert(key.clone(), package.clone());
@@ -117,6 +409,78 @@ impl PackageGraphBuilder {
         result
     }
 
+    /// The package standing for output `output` of `owner`; `out` is the
+    /// owner itself.
+    fn output_package(&mut self, owner: Rc<Package>, output: &str) -> MagResult<Rc<Package>> {
+        if output == DEFAULT_OUTPUT {
+            return Ok(owner);
+        }
+        if !owner.outputs.iter().any(|name| name == output) {
+            return Err(MagError::Generic(format!(
+                "{} has no output {output:?}; it declares {}",
+                package_base_name(&owner),
+                owner.outputs.join(", ")
+            )));
+        }
+
+        let hash = output_hash(&owner.hash, output);
+        if let Some(existing) = self.by_hash.get(&hash) {
+            return Ok(existing.clone());
+        }
+
+        let package = Rc::new(Package {
+            name: owner.name.clone(),
+            build: String::new(),
+            hooks: BuildHooks::default(),
+            hash: hash.clone(),
+            run_deps: vec![owner.clone()],
+            build_deps: Vec::new(),
+            fetch: Vec::new(),
+            sandbox: SandboxOptions::default(),
+            budget: OutputBudget::default(),
+            compression: CompressionOverride::default(),
+            lint: false,
+            check_runtime_deps: false,
+            builder_shell: None,
+            build_with: Vec::new(),
+            env: BTreeMap::new(),
+            outputs: Vec::new(),
+            metadata: owner.metadata.clone(),
+            output_of: Some(OutputOf {
+                owner,
+                output: output.to_string(),
+            }),
+        });
+        self.by_hash.insert(hash, package.clone());
+        Ok(package)
+    }
+
+    fn read_builder_shell(
+        &mut self,
+        obj: &ObjValue,
+        visiting: &mut HashSet<ObjKey>,
+    ) -> MagResult<Option<BuilderShell>> {
+        match get_field(obj, "builderShell")? {
+            None | Some(Val::Null) => Ok(None),
+            Some(Val::Str(path)) => {
+                let path = path.to_string();
+                if !path.starts_with('/') || path.contains('\0') {
+                    return Err(MagError::Generic(format!(
+                        "builderShell path {path:?} must be absolute"
+                    )));
+                }
+                Ok(Some(BuilderShell::Path(path)))
+            }
+            Some(value @ Val::Obj(_)) => Ok(Some(BuilderShell::Package(
+                self.build_from_val(value, visiting)?,
+            ))),
+            Some(other) => Err(MagError::Generic(format!(
+                "expected field 'builderShell' to be a path or a package, got {:?}",
+                other.value_type()
+            ))),
+        }
+    }
+
     fn collect_dependencies(
         &mut self,
         obj: &ObjValue,
@@ -157,11 +521,12 @@ impl PackageGraphBuilder {
     }
 }
 
+/// Identity of a Jsonnet object, for memoizing work per package value.
 #[derive(Clone)]
-struct ObjKey(ObjValue);
+pub(crate) struct ObjKey(ObjValue);
 
 impl ObjKey {
-    fn new(obj: ObjValue) -> Self {
+    pub(crate) fn new(obj: ObjValue) -> Self {
         Self(obj)
     }
 }
@@ -227,6 +592,226 @@ fn validate_package_name(name: &str) -> MagResult<()> {
     Ok(())
 }
 
+/// `passthru` carries metadata for other Jsonnet code and is never hashed;
+/// only its shape is checked here.
+fn validate_passthru(obj: &ObjValue) -> MagResult<()> {
+    match get_field(obj, "passthru")? {
+        None | Some(Val::Null) | Some(Val::Obj(_)) => Ok(()),
+        Some(other) => Err(MagError::Generic(format!(
+            "expected field 'passthru' to be an object, got {:?}",
+            other.value_type()
+        ))),
+    }
+}
+
+fn read_package_metadata(obj: &ObjValue) -> MagResult<PackageMetadata> {
+    Ok(PackageMetadata {
+        version: read_optional_string(obj, "version", "package")?,
+        license: read_optional_string(obj, "license", "package")?,
+        homepage: read_optional_string(obj, "homepage", "package")?,
+        description: read_optional_string(obj, "description", "package")?,
+    })
+}
+
+/// `buildWith` is an interpreter path, or an array of the interpreter and
+/// its leading arguments, e.g. `["/usr/bin/perl", "-w"]`.
+fn read_build_with(obj: &ObjValue) -> MagResult<Vec<String>> {
+    let build_with = match get_field(obj, "buildWith")? {
+        Some(Val::Str(path)) => vec![path.to_string()],
+        _ => read_string_array(obj, "buildWith", "package")?,
+    };
+    if let Some(interpreter) = build_with.first() {
+        if !interpreter.starts_with('/') {
+            return Err(MagError::Generic(format!(
+                "buildWith interpreter {interpreter:?} must be an absolute path"
+            )));
+        }
+    }
+    Ok(build_with)
+}
+
+/// `outputs` lists output names, e.g. `["out", "dev", "doc"]`, or is the
+/// object `magpkg.outputs` builds, whose field names are the outputs. `out`
+/// is always the first output, whether or not it is listed.
+fn read_outputs(obj: &ObjValue) -> MagResult<Vec<String>> {
+    let declared = match get_field(obj, "outputs")? {
+        Some(Val::Obj(outputs)) => outputs
+            .fields()
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        _ => read_string_array(obj, "outputs", "package")?,
+    };
+    if declared.is_empty() {
+        return Ok(vec![DEFAULT_OUTPUT.to_string()]);
+    }
+    let mut outputs = vec![DEFAULT_OUTPUT.to_string()];
+    for name in declared {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '_');
+        if !valid {
+            return Err(MagError::Generic(format!(
+                "outputs: {name:?} must be lowercase letters, digits, or '_'"
+            )));
+        }
+        if name != DEFAULT_OUTPUT && outputs.contains(&name) {
+            return Err(MagError::Generic(format!(
+                "outputs: {name} is listed twice"
+            )));
+        }
+        if name != DEFAULT_OUTPUT {
+            outputs.push(name);
+        }
+    }
+    Ok(outputs)
+}
+
+/// `env` maps variable names to values, e.g.
+/// `{ CC: "${store:gcc}/bin/gcc", LANG: "C.UTF-8" }`.
+fn read_build_env(obj: &ObjValue) -> MagResult<BTreeMap<String, String>> {
+    let env_obj = match get_field(obj, "env")? {
+        None | Some(Val::Null) => return Ok(BTreeMap::new()),
+        Some(Val::Obj(env_obj)) => env_obj,
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "field 'env' must be an object mapping names to strings, got {:?}",
+                other.value_type()
+            )));
+        }
+    };
+
+    let mut env = BTreeMap::new();
+    for key in env_obj.fields() {
+        let name = key.to_string();
+        let valid = name
+            .chars()
+            .next()
+            .is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+            && name
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+        if !valid {
+            return Err(MagError::Generic(format!(
+                "env: {name:?} is not a valid variable name"
+            )));
+        }
+        if RESERVED_BUILD_ENV.contains(&name.as_str()) {
+            return Err(MagError::Generic(format!(
+                "env: {name} is set by magpkg and cannot be overridden"
+            )));
+        }
+        match get_field(&env_obj, &name)? {
+            Some(Val::Str(value)) => {
+                let value = value.to_string();
+                if value.contains('\0') {
+                    return Err(MagError::Generic(format!(
+                        "env: value of {name} must not contain NUL bytes"
+                    )));
+                }
+                env.insert(name, value);
+            }
+            Some(other) => {
+                return Err(MagError::Generic(format!(
+                    "env: expected {name} to be a string, got {:?}",
+                    other.value_type()
+                )));
+            }
+            None => {}
+        }
+    }
+    Ok(env)
+}
+
+/// The build environment of `package` with every `${store:NAME}` replaced
+/// by `/store/<base name>` of the package called NAME among the build
+/// dependencies that magpkg installs under `/store`. Other text, including
+/// other `${...}` forms, is passed through for the script to expand.
+pub fn resolve_build_env(package: &Package) -> MagResult<Vec<(String, String)>> {
+    expand_store_refs(&package.env, &package.build_deps)
+}
+
+fn expand_store_refs(
+    env: &BTreeMap<String, String>,
+    build_deps: &[Rc<Package>],
+) -> MagResult<Vec<(String, String)>> {
+    env.iter()
+        .map(|(name, value)| {
+            let context = format!("env: {name}");
+            let expanded = expand_store_paths(value, "${store:", '}', build_deps, &context)?;
+            Ok((name.clone(), expanded))
+        })
+        .collect()
+}
+
+/// A build or phase script with every `@dep:NAME@` replaced by
+/// `/store/<base name>` of the build dependency called NAME, as in
+/// [`resolve_build_env`]. The package hash covers the script as written.
+pub fn resolve_build_script(
+    script: &str,
+    phase: &str,
+    build_deps: &[Rc<Package>],
+) -> MagResult<String> {
+    expand_store_paths(script, "@dep:", '@', build_deps, phase)
+}
+
+fn expand_store_paths(
+    text: &str,
+    open: &str,
+    close: char,
+    build_deps: &[Rc<Package>],
+    context: &str,
+) -> MagResult<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(open) {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + open.len()..];
+        let end = after.find(close).ok_or_else(|| {
+            MagError::Generic(format!("{context}: unterminated {open}...{close}"))
+        })?;
+        let name = &after[..end];
+        let dep = find_build_store_package(build_deps, name).map_err(|detail| {
+            MagError::Generic(format!(
+                "{context} references {open}{name}{close}, {detail}"
+            ))
+        })?;
+        expanded.push_str("/store/");
+        expanded.push_str(&package_base_name(&dep));
+        rest = &after[end + close.len_utf8()..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Look up a package by name in the closure that is installed under
+/// `/store` for a build: the build dependencies and everything they depend
+/// on.
+fn find_build_store_package(build_deps: &[Rc<Package>], name: &str) -> Result<Rc<Package>, String> {
+    let mut queue: VecDeque<Rc<Package>> = build_deps.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    let mut found: Option<Rc<Package>> = None;
+    while let Some(dep) = queue.pop_front() {
+        if !seen.insert(dep.hash.clone()) {
+            continue;
+        }
+        if dep.name.as_deref() == Some(name) && dep.output_of.is_none() {
+            if let Some(other) = &found {
+                return Err(format!(
+                    "which names both {} and {}",
+                    package_base_name(other),
+                    package_base_name(&dep)
+                ));
+            }
+            found = Some(dep.clone());
+        }
+        queue.extend(dep.run_deps.iter().cloned());
+        queue.extend(dep.build_deps.iter().cloned());
+    }
+    found.ok_or_else(|| format!("but no build dependency is named {name:?}"))
+}
+
 fn read_build_script(obj: &ObjValue) -> MagResult<String> {
     let value = get_field(obj, "build")?;
 
@@ -240,6 +825,17 @@ fn read_build_script(obj: &ObjValue) -> MagResult<String> {
     }
 }
 
+fn read_build_hooks(obj: &ObjValue) -> MagResult<BuildHooks> {
+    let read = |field: &str| -> MagResult<String> {
+        Ok(read_optional_string(obj, field, "package")?.unwrap_or_default())
+    };
+    Ok(BuildHooks {
+        pre_build: read("preBuild")?,
+        check: read("check")?,
+        post_build: read("postBuild")?,
+    })
+}
+
 fn read_fetch_list(obj: &ObjValue) -> MagResult<Vec<FetchResource>> {
     let value = get_field(obj, "fetch")?;
 
@@ -268,6 +864,23 @@ fn read_fetch_list(obj: &ObjValue) -> MagResult<Vec<FetchResource>> {
                     ))
                 })?;
 
+                if let Some(src) = read_optional_string(&fetch_obj, "src", &context)? {
+                    out.push(read_local_source(&fetch_obj, &src, &context)?);
+                    continue;
+                }
+                match read_optional_string(&fetch_obj, "type", &context)?.as_deref() {
+                    None | Some("url") => {}
+                    Some("inline") => {
+                        out.push(read_inline_fetch(&fetch_obj, &context)?);
+                        continue;
+                    }
+                    Some(other) => {
+                        return Err(MagError::Generic(format!(
+                            "{context}: unknown fetch type {other:?}; expected \"url\" or \"inline\""
+                        )));
+                    }
+                }
+
                 let filename = read_required_string(&fetch_obj, "filename", &context)?;
                 let sha256 = read_required_string(&fetch_obj, "sha256", &context)?;
                 let urls = read_string_array(&fetch_obj, "urls", &context)?;
@@ -276,6 +889,8 @@ fn read_fetch_list(obj: &ObjValue) -> MagResult<Vec<FetchResource>> {
                     filename,
                     sha256,
                     urls,
+                    source: None,
+                    inline: None,
                 });
             }
             Ok(out)
@@ -287,6 +902,273 @@ fn read_fetch_list(obj: &ObjValue) -> MagResult<Vec<FetchResource>> {
     }
 }
 
+/// A `{src: DIR, filename?, ignore?, sha256?}` fetch entry. The directory
+/// is hashed at evaluation time; a given `sha256` pins its contents.
+fn read_local_source(obj: &ObjValue, src: &str, context: &str) -> MagResult<FetchResource> {
+    let ignore = read_string_array(obj, "ignore", context)?;
+    let source = LocalSource::new(src, ignore)?;
+    let filename = match read_optional_string(obj, "filename", context)? {
+        Some(filename) => filename,
+        None => source
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                MagError::Generic(format!("{context}: set a filename for source {src}"))
+            })?,
+    };
+    let sha256 = source.sha256()?;
+    if let Some(pinned) = read_optional_string(obj, "sha256", context)? {
+        if !pinned.eq_ignore_ascii_case(&sha256) {
+            return Err(MagError::Generic(format!(
+                "{context}: source {} hashes to {sha256}, expected {pinned}",
+                source.path.display()
+            )));
+        }
+    }
+    Ok(FetchResource {
+        filename,
+        sha256,
+        urls: Vec::new(),
+        source: Some(source),
+        inline: None,
+    })
+}
+
+/// A `{type: "inline", filename, contents, encoding?, sha256?}` fetch
+/// entry. `contents` is text, or base64 with `encoding: "base64"`; a given
+/// `sha256` pins the decoded bytes.
+fn read_inline_fetch(obj: &ObjValue, context: &str) -> MagResult<FetchResource> {
+    let filename = read_required_string(obj, "filename", context)?;
+    if filename.is_empty() || filename.starts_with('.') || filename.contains('/') {
+        return Err(MagError::Generic(format!(
+            "{context}: inline filename {filename:?} must be a plain file name"
+        )));
+    }
+    let contents = read_required_string(obj, "contents", context)?;
+    let contents = match read_optional_string(obj, "encoding", context)?.as_deref() {
+        None | Some("utf8") => contents.into_bytes(),
+        Some("base64") => BASE64_STANDARD
+            .decode(contents.split_whitespace().collect::<String>())
+            .map_err(|err| {
+                MagError::Generic(format!("{context}: contents are not valid base64: {err}"))
+            })?,
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "{context}: unknown encoding {other:?}; expected \"utf8\" or \"base64\""
+            )));
+        }
+    };
+    let sha256 = hex::encode(Sha256::digest(&contents));
+    if let Some(pinned) = read_optional_string(obj, "sha256", context)? {
+        if !pinned.eq_ignore_ascii_case(&sha256) {
+            return Err(MagError::Generic(format!(
+                "{context}: inline {filename} hashes to {sha256}, expected {pinned}"
+            )));
+        }
+    }
+    Ok(FetchResource {
+        filename,
+        sha256,
+        urls: Vec::new(),
+        source: None,
+        inline: Some(contents),
+    })
+}
+
+fn read_sandbox_options(obj: &ObjValue) -> MagResult<SandboxOptions> {
+    let value = get_field(obj, "sandbox")?;
+
+    let sandbox_obj = match value {
+        None | Some(Val::Null) => return Ok(SandboxOptions::default()),
+        Some(Val::Obj(sandbox_obj)) => sandbox_obj,
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "field 'sandbox' must be an object, got {:?}",
+                other.value_type()
+            )));
+        }
+    };
+
+    let ro_binds = read_string_array(&sandbox_obj, "roBinds", "sandbox")?;
+    for bind in &ro_binds {
+        let path = Path::new(bind);
+        let reserved = RESERVED_SANDBOX_PATHS.iter().any(|reserved| {
+            path == Path::new(reserved) || (*reserved != "/" && path.starts_with(reserved))
+        });
+        if !path.is_absolute()
+            || path
+                .components()
+                .any(|component| matches!(component, Component::ParentDir | Component::CurDir))
+            || reserved
+        {
+            return Err(MagError::Generic(format!(
+                "sandbox: roBinds entry {bind:?} must be an absolute, normalized path outside {}",
+                RESERVED_SANDBOX_PATHS[1..].join(", ")
+            )));
+        }
+    }
+
+    let hostname = read_optional_string(&sandbox_obj, "hostname", "sandbox")?;
+    if let Some(hostname) = &hostname {
+        let valid = !hostname.is_empty()
+            && hostname.len() <= 64
+            && hostname
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '.');
+        if !valid {
+            return Err(MagError::Generic(format!(
+                "sandbox: hostname {hostname:?} must be 1-64 letters, digits, '-' or '.'"
+            )));
+        }
+    }
+
+    Ok(SandboxOptions {
+        fake_time: read_optional_string(&sandbox_obj, "fakeTime", "sandbox")?,
+        random_seed: read_optional_string(&sandbox_obj, "randomSeed", "sandbox")?,
+        ro_binds,
+        host_dev: read_optional_bool(&sandbox_obj, "hostDev", "sandbox")?,
+        uid: read_optional_id(&sandbox_obj, "uid")?,
+        gid: read_optional_id(&sandbox_obj, "gid")?,
+        hostname,
+        fakeroot: read_optional_bool(&sandbox_obj, "fakeroot", "sandbox")?.unwrap_or(false),
+    })
+}
+
+fn read_optional_id(obj: &ObjValue, field: &str) -> MagResult<Option<u32>> {
+    read_optional_count(obj, field, "sandbox")?
+        .map(|id| {
+            u32::try_from(id)
+                .map_err(|_| MagError::Generic(format!("sandbox: {field} {id} is out of range")))
+        })
+        .transpose()
+}
+
+fn read_output_budget(obj: &ObjValue) -> MagResult<OutputBudget> {
+    let value = get_field(obj, "budget")?;
+
+    let budget_obj = match value {
+        None | Some(Val::Null) => return Ok(OutputBudget::default()),
+        Some(Val::Obj(budget_obj)) => budget_obj,
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "field 'budget' must be an object, got {:?}",
+                other.value_type()
+            )));
+        }
+    };
+
+    let policy = match read_optional_string(&budget_obj, "onExceed", "budget")?.as_deref() {
+        None | Some("fail") => BudgetPolicy::Fail,
+        Some("warn") => BudgetPolicy::Warn,
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "budget: onExceed must be \"warn\" or \"fail\", got {other:?}"
+            )));
+        }
+    };
+
+    Ok(OutputBudget {
+        max_bytes: read_optional_count(&budget_obj, "maxBytes", "budget")?,
+        max_files: read_optional_count(&budget_obj, "maxFiles", "budget")?,
+        policy,
+    })
+}
+
+fn read_compression(obj: &ObjValue) -> MagResult<CompressionOverride> {
+    let compression_obj = match get_field(obj, "compression")? {
+        None | Some(Val::Null) => return Ok(CompressionOverride::default()),
+        Some(Val::Obj(compression_obj)) => compression_obj,
+        Some(other) => {
+            return Err(MagError::Generic(format!(
+                "field 'compression' must be an object, got {:?}",
+                other.value_type()
+            )));
+        }
+    };
+
+    let codec = read_optional_string(&compression_obj, "format", "compression")?
+        .map(|format| {
+            ArtifactCodec::parse(&format).ok_or_else(|| {
+                MagError::Generic(format!(
+                    "compression: format must be \"zstd\", \"xz\", or \"none\", got {format:?}"
+                ))
+            })
+        })
+        .transpose()?;
+    let level = read_optional_count(&compression_obj, "level", "compression")?
+        .map(|level| {
+            if (1..=MAX_COMPRESSION_LEVEL as u64).contains(&level) {
+                Ok(level as i32)
+            } else {
+                Err(MagError::Generic(format!(
+                    "compression: level must be between 1 and {MAX_COMPRESSION_LEVEL}, got {level}"
+                )))
+            }
+        })
+        .transpose()?;
+    let threads = match read_optional_count(&compression_obj, "threads", "compression")? {
+        Some(0) => {
+            return Err(MagError::Generic(
+                "compression: threads must be at least 1".into(),
+            ));
+        }
+        threads => threads.map(|threads| threads as usize),
+    };
+    Ok(CompressionOverride {
+        codec,
+        level,
+        threads,
+    })
+}
+
+fn read_optional_count(obj: &ObjValue, field: &str, context: &str) -> MagResult<Option<u64>> {
+    let value = get_field(obj, field)?;
+
+    match value {
+        None | Some(Val::Null) => Ok(None),
+        Some(Val::Num(n)) => {
+            let n = f64::from(n);
+            if n < 0.0 || n.fract() != 0.0 {
+                return Err(MagError::Generic(format!(
+                    "{context}: field '{field}' must be a non-negative integer, got {n}"
+                )));
+            }
+            Ok(Some(n as u64))
+        }
+        Some(other) => Err(MagError::Generic(format!(
+            "{context}: expected field '{field}' to be a number, got {:?}",
+            other.value_type()
+        ))),
+    }
+}
+
+fn read_optional_bool(obj: &ObjValue, field: &str, context: &str) -> MagResult<Option<bool>> {
+    let value = get_field(obj, field)?;
+
+    match value {
+        None | Some(Val::Null) => Ok(None),
+        Some(Val::Bool(b)) => Ok(Some(b)),
+        Some(other) => Err(MagError::Generic(format!(
+            "{context}: expected field '{field}' to be a boolean, got {:?}",
+            other.value_type()
+        ))),
+    }
+}
+
+fn read_optional_string(obj: &ObjValue, field: &str, context: &str) -> MagResult<Option<String>> {
+    let value = get_field(obj, field)?;
+
+    match value {
+        None | Some(Val::Null) => Ok(None),
+        Some(Val::Str(s)) => Ok(Some(s.to_string())),
+        Some(other) => Err(MagError::Generic(format!(
+            "{context}: expected field '{field}' to be a string, got {:?}",
+            other.value_type()
+        ))),
+    }
+}
+
 fn read_required_string(obj: &ObjValue, field: &str, context: &str) -> MagResult<String> {
     let value = get_field(obj, field)?;
 
@@ -317,7 +1199,7 @@ fn read_string_array(obj: &ObjValue, field: &str, context: &str) -> MagResult<Ve
                 let val = item.map_err(|err| {
                     let message = format_jr_error(&err);
                     MagError::Evaluation {
-                        context: format!("{context}: failed to evaluate urls[{index}]"),
+                        context: format!("{context}: failed to evaluate {field}[{index}]"),
                         message,
                         source: err,
                     }
@@ -326,7 +1208,7 @@ fn read_string_array(obj: &ObjValue, field: &str, context: &str) -> MagResult<Ve
                     Val::Str(s) => out.push(s.to_string()),
                     other => {
                         return Err(MagError::Generic(format!(
-                            "{context}: expected urls[{index}] to be a string, got {:?}",
+                            "{context}: expected {field}[{index}] to be a string, got {:?}",
                             other.value_type()
                         )));
                     }
@@ -343,19 +1225,37 @@ fn read_string_array(obj: &ObjValue, field: &str, context: &str) -> MagResult<Ve
 
 fn compute_hash(
     build: &str,
+    hooks: &BuildHooks,
     fetch: &[FetchResource],
     run_deps: &[Rc<Package>],
     build_deps: &[Rc<Package>],
+    sandbox: &SandboxOptions,
+    builder_shell: Option<&BuilderShell>,
+    build_with: &[String],
+    env: &BTreeMap<String, String>,
+    outputs: &[String],
+    metadata: Option<&PackageMetadata>,
 ) -> String {
     let mut hasher = Sha256::new();
     hasher.update(b"build:");
     hasher.update(build.as_bytes());
+    for (phase, script) in hooks.phases() {
+        if !script.is_empty() {
+            hasher.update(b"\0");
+            hasher.update(phase.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(script.as_bytes());
+        }
+    }
     hasher.update(b"\0fetch\0");
     for item in fetch {
         hasher.update(item.filename.as_bytes());
         hasher.update(b"\0");
         hasher.update(item.sha256.as_bytes());
         hasher.update(b"\0");
+        if item.source.is_some() {
+            hasher.update(b"src\0");
+        }
     }
     hasher.update(b"\0run\0");
     for dep in run_deps {
@@ -365,6 +1265,85 @@ fn compute_hash(
     for dep in build_deps {
         hasher.update(dep.hash.as_bytes());
     }
+    if let Some(fake_time) = &sandbox.fake_time {
+        hasher.update(b"\0faketime\0");
+        hasher.update(fake_time.as_bytes());
+    }
+    if let Some(seed) = &sandbox.random_seed {
+        hasher.update(b"\0randomseed\0");
+        hasher.update(seed.as_bytes());
+    }
+    if !sandbox.ro_binds.is_empty() {
+        hasher.update(b"\0robinds\0");
+        for bind in &sandbox.ro_binds {
+            hasher.update(bind.as_bytes());
+            hasher.update(b"\0");
+        }
+    }
+    if let Some(host_dev) = sandbox.host_dev {
+        hasher.update(b"\0hostdev\0");
+        hasher.update([u8::from(host_dev)]);
+    }
+    if let Some(uid) = sandbox.uid {
+        hasher.update(b"\0uid\0");
+        hasher.update(uid.to_string().as_bytes());
+    }
+    if let Some(gid) = sandbox.gid {
+        hasher.update(b"\0gid\0");
+        hasher.update(gid.to_string().as_bytes());
+    }
+    if let Some(hostname) = &sandbox.hostname {
+        hasher.update(b"\0hostname\0");
+        hasher.update(hostname.as_bytes());
+    }
+    if sandbox.fakeroot {
+        hasher.update(b"\0fakeroot\0");
+    }
+    match builder_shell {
+        Some(BuilderShell::Path(path)) => {
+            hasher.update(b"\0shell\0");
+            hasher.update(path.as_bytes());
+        }
+        Some(BuilderShell::Package(shell)) => {
+            hasher.update(b"\0shellpkg\0");
+            hasher.update(shell.hash.as_bytes());
+        }
+        None => {}
+    }
+    if !build_with.is_empty() {
+        hasher.update(b"\0buildwith\0");
+        for arg in build_with {
+            hasher.update(arg.as_bytes());
+            hasher.update(b"\0");
+        }
+    }
+    if !env.is_empty() {
+        hasher.update(b"\0env\0");
+        for (name, value) in env {
+            hasher.update(name.as_bytes());
+            hasher.update(b"=");
+            hasher.update(value.as_bytes());
+            hasher.update(b"\0");
+        }
+    }
+    if outputs.len() > 1 {
+        hasher.update(b"\0outputs\0");
+        for output in outputs {
+            hasher.update(output.as_bytes());
+            hasher.update(b"\0");
+        }
+    }
+    if let Some(metadata) = metadata {
+        hasher.update(b"\0metadata\0");
+        for (field, value) in metadata.fields() {
+            if let Some(value) = value {
+                hasher.update(field.as_bytes());
+                hasher.update(b"=");
+                hasher.update(value.as_bytes());
+                hasher.update(b"\0");
+            }
+        }
+    }
     let digest = hasher.finalize();
     format!("{:x}", digest)
 }
@@ -400,11 +1379,43 @@ pub fn collect_closure(
     for dep in &pkg.build_deps {
         collect_closure(dep.clone(), visited, order);
     }
+    if let Some(BuilderShell::Package(shell)) = &pkg.builder_shell {
+        collect_closure(shell.clone(), visited, order);
+    }
 
     order.push(pkg);
 }
 
+/// The packages that must be built before `pkg`: its run and build
+/// dependencies, its builder shell package, and for an extra output the
+/// package that produces it.
+pub fn direct_dependencies(pkg: &Package) -> impl Iterator<Item = &Rc<Package>> {
+    let shell = match &pkg.builder_shell {
+        Some(BuilderShell::Package(shell)) => Some(shell),
+        _ => None,
+    };
+    pkg.run_deps
+        .iter()
+        .chain(&pkg.build_deps)
+        .chain(shell)
+        .chain(pkg.output_of.as_ref().map(|output_of| &output_of.owner))
+}
+
+/// Hash of the package standing for the extra output `output` of the
+/// package hashed `owner_hash`.
+pub fn output_hash(owner_hash: &str, output: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"output\0");
+    hasher.update(owner_hash.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(output.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
 pub fn package_base_name(package: &Package) -> String {
+    if let Some(OutputOf { owner, output }) = &package.output_of {
+        return format!("{}-{output}", package_base_name(owner));
+    }
     match package.name.as_deref() {
         Some(name) if !name.is_empty() => format!("{name}-{}", package.hash),
         _ => format!("pkg-{}", package.hash),
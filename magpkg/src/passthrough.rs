@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::{MagError, MagResult, MountKind, MountSpec};
+
+/// Host desktop services a venv can reach, selected with the manifest's
+/// `passthrough` array (or all of them with `desktop: true`).
+///
+/// Presets are resolved when the venv launches, from the host's current
+/// session: the sockets they name are bound at the same paths inside the
+/// venv and the variables that point at them are kept. A service that is
+/// not running on the host is skipped with a warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Passthrough {
+    /// `/tmp/.X11-unix`, `DISPLAY`, and the `XAUTHORITY` cookie file.
+    X11,
+    /// The compositor socket named by `WAYLAND_DISPLAY`.
+    Wayland,
+    /// The session bus from `DBUS_SESSION_BUS_ADDRESS` and the system bus.
+    Dbus,
+    /// The PulseAudio socket, which PipeWire also provides.
+    Pulseaudio,
+}
+
+pub const ALL_PASSTHROUGH: [Passthrough; 4] = [
+    Passthrough::X11,
+    Passthrough::Wayland,
+    Passthrough::Dbus,
+    Passthrough::Pulseaudio,
+];
+
+const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+
+impl Passthrough {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Passthrough::X11 => "x11",
+            Passthrough::Wayland => "wayland",
+            Passthrough::Dbus => "dbus",
+            Passthrough::Pulseaudio => "pulseaudio",
+        }
+    }
+
+    pub fn parse(value: &str) -> MagResult<Self> {
+        ALL_PASSTHROUGH
+            .into_iter()
+            .find(|preset| preset.as_str() == value)
+            .ok_or_else(|| {
+                MagError::Generic(format!(
+                    "unknown passthrough preset {value:?}; expected one of x11, wayland, dbus, pulseaudio"
+                ))
+            })
+    }
+}
+
+/// Add the mounts and variables for `presets` to a venv launch. Mounts are
+/// appended after the manifest's own, so `/tmp` or runtime-directory
+/// tmpfs mounts do not hide the sockets. Variables already present are
+/// left alone, so `envSet` still wins.
+pub fn apply_passthrough(
+    presets: &[Passthrough],
+    mounts: &mut Vec<MountSpec>,
+    variables: &mut BTreeMap<String, String>,
+) {
+    if presets.is_empty() {
+        return;
+    }
+
+    let mut binds: Vec<PathBuf> = Vec::new();
+
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute());
+    let needs_runtime_dir = presets.iter().any(|preset| *preset != Passthrough::X11);
+    if needs_runtime_dir && runtime_dir.is_none() {
+        eprintln!(
+            "warning: XDG_RUNTIME_DIR is not set; wayland, dbus, and pulseaudio passthrough may not find their sockets"
+        );
+    }
+
+    for preset in presets {
+        match preset {
+            Passthrough::X11 => {
+                if env::var_os("DISPLAY").is_none() {
+                    eprintln!("warning: x11 passthrough requested but DISPLAY is not set");
+                    continue;
+                }
+                keep_variable("DISPLAY", variables);
+                binds.push(PathBuf::from("/tmp/.X11-unix"));
+                if let Some(xauthority) = env::var_os("XAUTHORITY") {
+                    keep_variable("XAUTHORITY", variables);
+                    binds.push(PathBuf::from(xauthority));
+                }
+            }
+            Passthrough::Wayland => {
+                let display = env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-0".into());
+                let socket = if Path::new(&display).is_absolute() {
+                    PathBuf::from(&display)
+                } else if let Some(dir) = &runtime_dir {
+                    dir.join(&display)
+                } else {
+                    continue;
+                };
+                if !socket.exists() {
+                    eprintln!(
+                        "warning: wayland passthrough requested but {} does not exist",
+                        socket.display()
+                    );
+                    continue;
+                }
+                variables.entry("WAYLAND_DISPLAY".into()).or_insert(display);
+                keep_variable("XDG_SESSION_TYPE", variables);
+                binds.push(socket);
+            }
+            Passthrough::Dbus => {
+                keep_variable("DBUS_SESSION_BUS_ADDRESS", variables);
+                match env::var("DBUS_SESSION_BUS_ADDRESS") {
+                    Ok(address) => binds.extend(dbus_socket_paths(&address)),
+                    Err(_) => {
+                        if let Some(dir) = &runtime_dir {
+                            let bus = dir.join("bus");
+                            if bus.exists() {
+                                variables
+                                    .entry("DBUS_SESSION_BUS_ADDRESS".into())
+                                    .or_insert(format!("unix:path={}", bus.display()));
+                                binds.push(bus);
+                            }
+                        }
+                    }
+                }
+                if Path::new(SYSTEM_BUS_SOCKET).exists() {
+                    binds.push(PathBuf::from(SYSTEM_BUS_SOCKET));
+                }
+            }
+            Passthrough::Pulseaudio => {
+                let socket = match env::var("PULSE_SERVER") {
+                    Ok(server) => match server.strip_prefix("unix:") {
+                        Some(path) => PathBuf::from(path),
+                        // Network servers need nothing mounted.
+                        None => {
+                            keep_variable("PULSE_SERVER", variables);
+                            continue;
+                        }
+                    },
+                    Err(_) => match &runtime_dir {
+                        Some(dir) => dir.join("pulse/native"),
+                        None => continue,
+                    },
+                };
+                if !socket.exists() {
+                    eprintln!(
+                        "warning: pulseaudio passthrough requested but {} does not exist",
+                        socket.display()
+                    );
+                    continue;
+                }
+                variables
+                    .entry("PULSE_SERVER".into())
+                    .or_insert(format!("unix:{}", socket.display()));
+                binds.push(socket);
+            }
+        }
+    }
+
+    if let Some(dir) = &runtime_dir {
+        if needs_runtime_dir {
+            if !mounts.iter().any(|mount| &mount.target == dir) {
+                mounts.push(MountSpec {
+                    kind: MountKind::Tmpfs,
+                    source: None,
+                    target: dir.clone(),
+                    optional: false,
+                });
+            }
+            variables
+                .entry("XDG_RUNTIME_DIR".into())
+                .or_insert(dir.to_string_lossy().into_owned());
+        }
+    }
+
+    binds.sort();
+    binds.dedup();
+    for path in binds {
+        mounts.push(MountSpec {
+            kind: MountKind::Bind,
+            source: Some(path.clone()),
+            target: path,
+            optional: true,
+        });
+    }
+}
+
+fn keep_variable(name: &str, variables: &mut BTreeMap<String, String>) {
+    if let Ok(value) = env::var(name) {
+        variables.entry(name.to_string()).or_insert(value);
+    }
+}
+
+/// Filesystem sockets in a D-Bus address list such as
+/// `unix:path=/run/user/1000/bus;unix:abstract=/tmp/dbus-x`. Abstract
+/// sockets need no mount because venvs share the host's network namespace.
+fn dbus_socket_paths(address: &str) -> Vec<PathBuf> {
+    address
+        .split(';')
+        .filter_map(|entry| entry.strip_prefix("unix:"))
+        .flat_map(|params| params.split(','))
+        .filter_map(|param| param.strip_prefix("path="))
+        .map(PathBuf::from)
+        .collect()
+}
@@ -0,0 +1,246 @@
+use std::{fs, io, io::ErrorKind, os::unix::process::CommandExt, path::Path, process::Command};
+
+use crate::{MagError, MagResult};
+
+pub const BUILD_PRIORITY_CONFIG_FILE: &str = "build-priority.conf";
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Block I/O scheduling class, as set by `ionice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoClass {
+    /// Parse `idle`, `best-effort[:<0-7>]`, or `realtime[:<0-7>]`; a
+    /// missing level means 4, the kernel's default.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (class, level) = match value.split_once(':') {
+            Some((class, level)) => (class, Some(level.parse::<u8>().ok().filter(|l| *l <= 7)?)),
+            None => (value, None),
+        };
+        match class {
+            "idle" if level.is_none() => Some(IoClass::Idle),
+            "best-effort" => Some(IoClass::BestEffort(level.unwrap_or(4))),
+            "realtime" => Some(IoClass::Realtime(level.unwrap_or(4))),
+            _ => None,
+        }
+    }
+
+    fn ioprio(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoClass::Realtime(level) => (1, level),
+            IoClass::BestEffort(level) => (2, level),
+            IoClass::Idle => (3, 0),
+        };
+        (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level)
+    }
+}
+
+/// How a build sandbox is scheduled. Unset fields leave the inherited
+/// value alone.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduling {
+    /// Niceness from -20 to 19; raising priority needs `CAP_SYS_NICE`.
+    pub nice: Option<i32>,
+    pub ionice: Option<IoClass>,
+    pub cpuset: Option<CpuSet>,
+}
+
+impl Scheduling {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.ionice.is_none() && self.cpuset.is_none()
+    }
+
+    /// Take every field `other` sets.
+    fn merge(&mut self, other: &Scheduling) {
+        if other.nice.is_some() {
+            self.nice = other.nice;
+        }
+        if other.ionice.is_some() {
+            self.ionice = other.ionice;
+        }
+        if other.cpuset.is_some() {
+            self.cpuset = other.cpuset.clone();
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Option<()> {
+        match key {
+            "nice" => self.nice = Some(parse_nice(value)?),
+            "ionice" => self.ionice = Some(IoClass::parse(value)?),
+            "cpuset" => self.cpuset = Some(CpuSet::parse(value)?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Build parallelism to advertise, capped at the size of the cpuset.
+    pub fn parallelism(&self, parallelism: usize) -> usize {
+        match &self.cpuset {
+            Some(cpuset) => parallelism.min(cpuset.cpus.len()).max(1),
+            None => parallelism,
+        }
+    }
+
+    /// Apply the settings to `cmd`'s process before it executes; children
+    /// inherit them, so they cover the whole sandbox.
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+        let nice = self.nice;
+        let ioprio = self.ionice.map(IoClass::ioprio);
+        let cpuset = self.cpuset.as_ref().map(|cpuset| {
+            // SAFETY: cpu_set_t is plain data and all zeroes is an empty set.
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for &cpu in &cpuset.cpus {
+                // SAFETY: CPU_SET only writes within `set`; CpuSet::parse
+                // rejects CPUs beyond CPU_SETSIZE.
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            set
+        });
+        // SAFETY: the hook runs between fork and exec and only makes
+        // syscalls, which are async-signal-safe; it allocates nothing.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(ioprio) = ioprio {
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(set) = &cpuset {
+                    if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) != 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Scheduling of build sandboxes on shared machines, read from
+/// `<store>/build-priority.conf` and overridden by `magpkg build` flags:
+///
+/// ```text
+/// nice    <-20..19>
+/// ionice  idle|best-effort[:<0-7>]|realtime[:<0-7>]
+/// cpuset  <cpu-list>                                  (e.g. 0-3,8)
+/// package <name> nice|ionice|cpuset <value>           (one package only)
+/// ```
+///
+/// For a package, the command-line flags win, then its `package` lines,
+/// then the store-wide lines.
+#[derive(Debug, Clone, Default)]
+pub struct BuildPriority {
+    default: Scheduling,
+    packages: Vec<(String, Scheduling)>,
+    overrides: Scheduling,
+}
+
+impl BuildPriority {
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        let mut priority = Self::default();
+        let path = base_root.join(BUILD_PRIORITY_CONFIG_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(priority),
+            Err(err) => return Err(err.into()),
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                MagError::Generic(format!(
+                    "{}:{}: invalid build priority setting {line:?}",
+                    path.display(),
+                    index + 1
+                ))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["package", name, key, value] => {
+                    let position = match priority.packages.iter().position(|(n, _)| n == name) {
+                        Some(position) => position,
+                        None => {
+                            priority
+                                .packages
+                                .push((name.to_string(), Scheduling::default()));
+                            priority.packages.len() - 1
+                        }
+                    };
+                    priority.packages[position]
+                        .1
+                        .set(key, value)
+                        .ok_or_else(invalid)?;
+                }
+                [key, value] => priority.default.set(key, value).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(priority)
+    }
+
+    /// Settings from the command line, which take precedence over the file.
+    pub fn set_overrides(&mut self, overrides: Scheduling) {
+        self.overrides = overrides;
+    }
+
+    /// The scheduling for a package named `name`.
+    pub fn for_package(&self, name: Option<&str>) -> Scheduling {
+        let mut scheduling = self.default.clone();
+        if let Some(name) = name {
+            if let Some((_, package)) = self.packages.iter().find(|(n, _)| n == name) {
+                scheduling.merge(package);
+            }
+        }
+        scheduling.merge(&self.overrides);
+        scheduling
+    }
+}
+
+pub fn parse_nice(value: &str) -> Option<i32> {
+    value.parse().ok().filter(|nice| (-20..=19).contains(nice))
+}
+
+/// CPUs a build sandbox may run on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet {
+    cpus: Vec<usize>,
+}
+
+impl CpuSet {
+    /// Parse a CPU list such as `0-3,8,10-11`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut cpus = Vec::new();
+        for part in value.split(',') {
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?),
+                None => {
+                    let cpu = part.parse::<usize>().ok()?;
+                    (cpu, cpu)
+                }
+            };
+            if start > end || end >= libc::CPU_SETSIZE as usize {
+                return None;
+            }
+            cpus.extend(start..=end);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Some(Self { cpus })
+    }
+}
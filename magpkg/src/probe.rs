@@ -0,0 +1,95 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::store::PackageStore;
+
+/// How long an HTTP source may take to answer a probe.
+pub const HTTP_PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long the DHT is asked for peers of a magnet link.
+pub const DHT_PROBE_WAIT: Duration = Duration::from_secs(15);
+/// Sources probed at once.
+const PROBE_WIDTH: usize = 8;
+
+/// A fetch as `magpkg fetch` would resolve it, without downloading.
+pub struct PlannedFetch {
+    pub filename: String,
+    pub sha256: String,
+    /// Whether the file is already in the fetch cache.
+    pub cached: bool,
+    /// Every source, in the order downloads try them.
+    pub sources: Vec<String>,
+}
+
+/// What probing one source found.
+#[derive(Debug, Clone)]
+pub enum SourceStatus {
+    /// The source answered; with its size when the server reported one.
+    Alive { size: Option<u64> },
+    /// Distinct peers the DHT returned for a magnet link.
+    Peers(usize),
+    /// The source cannot deliver the file.
+    Dead(String),
+}
+
+impl SourceStatus {
+    pub fn is_alive(&self) -> bool {
+        match self {
+            SourceStatus::Alive { .. } => true,
+            SourceStatus::Peers(peers) => *peers > 0,
+            SourceStatus::Dead(_) => false,
+        }
+    }
+}
+
+/// Probe every source of `fetches`, several at a time. The result holds
+/// one status per source, in the same order.
+pub fn probe_fetches(store: &PackageStore, fetches: &[PlannedFetch]) -> Vec<Vec<SourceStatus>> {
+    let jobs: Vec<(usize, usize, &str)> = fetches
+        .iter()
+        .enumerate()
+        .flat_map(|(fetch, planned)| {
+            planned
+                .sources
+                .iter()
+                .enumerate()
+                .map(move |(source, url)| (fetch, source, url.as_str()))
+        })
+        .collect();
+    let results: Mutex<Vec<Vec<Option<SourceStatus>>>> = Mutex::new(
+        fetches
+            .iter()
+            .map(|planned| vec![None; planned.sources.len()])
+            .collect(),
+    );
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..PROBE_WIDTH.min(jobs.len()) {
+            scope.spawn(|| {
+                while let Some(&(fetch, source, url)) =
+                    jobs.get(next.fetch_add(1, Ordering::Relaxed))
+                {
+                    let status = store.probe_source(url);
+                    results.lock().unwrap_or_else(|err| err.into_inner())[fetch][source] =
+                        Some(status);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|err| err.into_inner())
+        .into_iter()
+        .map(|statuses| {
+            statuses
+                .into_iter()
+                .map(|status| status.unwrap_or_else(|| SourceStatus::Dead("not probed".into())))
+                .collect()
+        })
+        .collect()
+}
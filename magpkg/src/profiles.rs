@@ -0,0 +1,335 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    HomeDir, MagError, MagResult, MountKind, MountSpec, VenvSpec,
+    isolation::{IdMapping, Namespace, ResourceLimits},
+    passthrough::Passthrough,
+};
+
+pub const PROFILES_DIR: &str = "venv-profiles";
+
+/// A venv manifest registered under a name with `magpkg venv add`.
+///
+/// Profiles live in `<store>/venv-profiles/<name>` and record everything
+/// needed to enter the venv again without evaluating the manifest:
+///
+/// ```text
+/// manifest       <jsonnet expression>
+/// rootfs         <rootfs hash>
+/// mountDefaults  on|off
+/// envKeep        <name>                                  (repeatable)
+/// envSet         <name>    <value>                       (repeatable)
+/// mount          <type>    <source|->  <target>  optional|required
+/// passthrough    x11|wayland|dbus|pulseaudio             (repeatable)
+/// limit          memory|pids|cpus  <value>               (repeatable)
+/// unshare        pid|ipc|uts|cgroup                      (repeatable)
+/// idmap          <uid>     <gid>
+/// home           isolated|host                           (default isolated)
+/// layout         copy|overlay                            (default copy)
+/// ```
+///
+/// Backslashes, tabs, and newlines in values are written as `\\`, `\t`, and
+/// `\n`, so inline expressions survive the round trip.
+pub struct VenvProfile {
+    pub name: String,
+    pub manifest: String,
+    /// The resolved spec. Packages and `fsEntries` are already baked into
+    /// the cached rootfs, so only the launch settings are recorded.
+    pub spec: VenvSpec,
+    /// Whether the packages are mounted as overlay layers rather than
+    /// copied into the rootfs.
+    pub overlay: bool,
+}
+
+pub fn profiles_dir(base_root: &Path) -> PathBuf {
+    base_root.join(PROFILES_DIR)
+}
+
+pub fn validate_profile_name(name: &str) -> MagResult<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(MagError::Generic(format!(
+            "invalid venv profile name {name:?}: use letters, digits, '-', '_', and '.'"
+        )))
+    }
+}
+
+/// Names of every registered profile, sorted.
+pub fn list_profiles(base_root: &Path) -> MagResult<Vec<String>> {
+    let entries = match fs::read_dir(profiles_dir(base_root)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if validate_profile_name(&name).is_ok() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+impl VenvProfile {
+    pub fn load(base_root: &Path, name: &str) -> MagResult<Self> {
+        validate_profile_name(name)?;
+        let path = profiles_dir(base_root).join(name);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(MagError::Generic(format!(
+                    "no venv profile named {name}; register one with `magpkg venv add {name} -f <manifest>`"
+                )));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut manifest = None;
+        let mut overlay = false;
+        let mut spec = VenvSpec {
+            packages: Vec::new(),
+            env_keep: Vec::new(),
+            env_set: BTreeMap::new(),
+            use_default_mounts: true,
+            mounts: Vec::new(),
+            passthrough: Vec::new(),
+            limits: ResourceLimits::default(),
+            unshare: Vec::new(),
+            id_mapping: None,
+            home: HomeDir::default(),
+            fs_entries: Vec::new(),
+            rootfs_hash: String::new(),
+        };
+        for (index, line) in contents.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            let invalid = || {
+                MagError::Generic(format!(
+                    "{}:{}: invalid profile entry",
+                    path.display(),
+                    index + 1
+                ))
+            };
+            match fields.as_slice() {
+                [key, value] if key == "manifest" => manifest = Some(value.clone()),
+                [key, value] if key == "rootfs" => spec.rootfs_hash = value.clone(),
+                [key, value] if key == "mountDefaults" => {
+                    spec.use_default_mounts = match value.as_str() {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(invalid()),
+                    };
+                }
+                [key, value] if key == "envKeep" => spec.env_keep.push(value.clone()),
+                [key, name, value] if key == "envSet" => {
+                    spec.env_set.insert(name.clone(), value.clone());
+                }
+                [key, kind, source, target, optional] if key == "mount" => {
+                    spec.mounts.push(MountSpec {
+                        kind: mount_kind_from_str(kind).ok_or_else(invalid)?,
+                        source: (source != "-").then(|| PathBuf::from(source)),
+                        target: PathBuf::from(target),
+                        optional: match optional.as_str() {
+                            "optional" => true,
+                            "required" => false,
+                            _ => return Err(invalid()),
+                        },
+                    });
+                }
+                [key, preset] if key == "passthrough" => {
+                    spec.passthrough.push(Passthrough::parse(preset)?);
+                }
+                [key, name, value] if key == "limit" => match name.as_str() {
+                    "memory" => spec.limits.memory = Some(value.parse().map_err(|_| invalid())?),
+                    "pids" => spec.limits.pids = Some(value.parse().map_err(|_| invalid())?),
+                    "cpus" => spec.limits.cpus = Some(value.parse().map_err(|_| invalid())?),
+                    _ => return Err(invalid()),
+                },
+                [key, namespace] if key == "unshare" => {
+                    spec.unshare.push(Namespace::parse(namespace)?);
+                }
+                [key, uid, gid] if key == "idmap" => {
+                    spec.id_mapping = Some(IdMapping {
+                        uid: uid.parse().map_err(|_| invalid())?,
+                        gid: gid.parse().map_err(|_| invalid())?,
+                    });
+                }
+                [key, value] if key == "home" => spec.home = HomeDir::parse(value)?,
+                [key, value] if key == "layout" => {
+                    overlay = match value.as_str() {
+                        "copy" => false,
+                        "overlay" => true,
+                        _ => return Err(invalid()),
+                    };
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let manifest = manifest.ok_or_else(|| {
+            MagError::Generic(format!("{}: missing manifest entry", path.display()))
+        })?;
+        if spec.rootfs_hash.is_empty() {
+            return Err(MagError::Generic(format!(
+                "{}: missing rootfs entry",
+                path.display()
+            )));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            manifest,
+            spec,
+            overlay,
+        })
+    }
+
+    pub fn save(&self, base_root: &Path) -> MagResult<()> {
+        validate_profile_name(&self.name)?;
+        let dir = profiles_dir(base_root);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(&self.name);
+        let tmp_path = dir.join(format!(".{}.tmp", self.name));
+        {
+            let spec = &self.spec;
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(writer, "manifest\t{}", escape(&self.manifest))?;
+            writeln!(writer, "rootfs\t{}", spec.rootfs_hash)?;
+            writeln!(
+                writer,
+                "mountDefaults\t{}",
+                if spec.use_default_mounts { "on" } else { "off" }
+            )?;
+            for name in &spec.env_keep {
+                writeln!(writer, "envKeep\t{}", escape(name))?;
+            }
+            for (name, value) in &spec.env_set {
+                writeln!(writer, "envSet\t{}\t{}", escape(name), escape(value))?;
+            }
+            for mount in &spec.mounts {
+                let source = mount
+                    .source
+                    .as_ref()
+                    .map(|source| escape(&source.to_string_lossy()))
+                    .unwrap_or_else(|| "-".into());
+                writeln!(
+                    writer,
+                    "mount\t{}\t{source}\t{}\t{}",
+                    mount_kind_str(mount.kind),
+                    escape(&mount.target.to_string_lossy()),
+                    if mount.optional {
+                        "optional"
+                    } else {
+                        "required"
+                    }
+                )?;
+            }
+            for preset in &spec.passthrough {
+                writeln!(writer, "passthrough\t{}", preset.as_str())?;
+            }
+            if let Some(memory) = spec.limits.memory {
+                writeln!(writer, "limit\tmemory\t{memory}")?;
+            }
+            if let Some(pids) = spec.limits.pids {
+                writeln!(writer, "limit\tpids\t{pids}")?;
+            }
+            if let Some(cpus) = spec.limits.cpus {
+                writeln!(writer, "limit\tcpus\t{cpus}")?;
+            }
+            for namespace in &spec.unshare {
+                writeln!(writer, "unshare\t{}", namespace.as_str())?;
+            }
+            if let Some(mapping) = spec.id_mapping {
+                writeln!(writer, "idmap\t{}\t{}", mapping.uid, mapping.gid)?;
+            }
+            writeln!(writer, "home\t{}", spec.home.as_str())?;
+            if self.overlay {
+                writeln!(writer, "layout\toverlay")?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// The `rootfs` entry of a profile, read without checking the rest of the
+/// file, so the venv of a profile that no longer loads can still be found.
+pub fn profile_rootfs_hash(base_root: &Path, name: &str) -> Option<String> {
+    let contents = fs::read_to_string(profiles_dir(base_root).join(name)).ok()?;
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("rootfs\t"))
+        .map(unescape)
+        .filter(|hash| !hash.is_empty())
+}
+
+/// Forget a profile. Returns whether it existed.
+pub fn remove_profile(base_root: &Path, name: &str) -> MagResult<bool> {
+    validate_profile_name(name)?;
+    match fs::remove_file(profiles_dir(base_root).join(name)) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn mount_kind_str(kind: MountKind) -> &'static str {
+    match kind {
+        MountKind::Bind => "bind",
+        MountKind::RoBind => "ro-bind",
+        MountKind::DevBind => "dev-bind",
+        MountKind::Proc => "proc",
+        MountKind::Tmpfs => "tmpfs",
+    }
+}
+
+fn mount_kind_from_str(value: &str) -> Option<MountKind> {
+    match value {
+        "bind" => Some(MountKind::Bind),
+        "ro-bind" => Some(MountKind::RoBind),
+        "dev-bind" => Some(MountKind::DevBind),
+        "proc" => Some(MountKind::Proc),
+        "tmpfs" => Some(MountKind::Tmpfs),
+        _ => None,
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
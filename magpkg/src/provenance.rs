@@ -0,0 +1,220 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value, json};
+
+use crate::{
+    leases::lease_host,
+    package::{BuilderShell, Package, package_base_name},
+    sbom::utc_timestamp,
+    store::{PackageStore, PackageTiming, published_sha256},
+};
+
+/// Sidecar next to an artifact built by this store, `<base>.provenance.json`.
+pub const PROVENANCE_SUFFIX: &str = ".provenance.json";
+
+/// Directory of an exported tarball that `--provenance` puts the records in,
+/// one `<base>.json` per package.
+pub const EXPORT_PROVENANCE_DIR: &str = "usr/share/magpkg/provenance";
+
+/// The provenance record of a package this process just built:
+///
+/// ```text
+/// {
+///   "base": "hello-2.12-3f1c…",
+///   "hash": "3f1c…",
+///   "sha256": "<artifact sha256>",
+///   "package": { "name": "hello", "build": "…", "env": {…}, … },
+///   "inputs": {
+///     "run": [{ "base": "glibc-…", "hash": "…", "sha256": "…" }],
+///     "build": [ … ],
+///     "fetch": [{ "filename": "hello-2.12.tar.gz", "sha256": "…" }]
+///   },
+///   "builder": { "host": "buildbox", "magpkgVersion": "0.1.0" },
+///   "expression": "pkgs/hello.jsonnet",
+///   "startedAt": "2024-05-01T12:00:00Z",
+///   "finishedAt": "2024-05-01T12:01:30Z",
+///   "durationSeconds": 90.2,
+///   "phases": { "fetch": 1.5, "extract": 3.1, "script": 80.0, "pack": 5.6 }
+/// }
+/// ```
+///
+/// `package` holds the evaluated fields that feed the package hash and its
+/// metadata. Input `sha256` values are those of the dependency artifacts
+/// the build used, so the record still tells them apart if a dependency is
+/// later rebuilt differently.
+pub fn provenance_record(
+    package: &Package,
+    store: &PackageStore,
+    expression: Option<&str>,
+    started: SystemTime,
+    timing: &PackageTiming,
+) -> Value {
+    let finished = SystemTime::now();
+    let duration = finished.duration_since(started).unwrap_or_default();
+    let mut build_inputs: Vec<Value> = package
+        .build_deps
+        .iter()
+        .map(|dep| input(dep, store))
+        .collect();
+    if let Some(BuilderShell::Package(shell)) = &package.builder_shell {
+        build_inputs.push(input(shell, store));
+    }
+    let fetches: Vec<Value> = package
+        .fetch
+        .iter()
+        .map(|fetch| json!({ "filename": fetch.filename, "sha256": fetch.sha256 }))
+        .collect();
+
+    json!({
+        "base": package_base_name(package),
+        "hash": package.hash,
+        "sha256": published_sha256(&store.package_artifact_path(package)),
+        "package": definition(package),
+        "inputs": {
+            "run": package.run_deps.iter().map(|dep| input(dep, store)).collect::<Vec<_>>(),
+            "build": build_inputs,
+            "fetch": fetches,
+        },
+        "builder": {
+            "host": lease_host(),
+            "magpkgVersion": env!("CARGO_PKG_VERSION"),
+        },
+        "expression": expression,
+        "startedAt": utc_timestamp(epoch_seconds(started)),
+        "finishedAt": utc_timestamp(epoch_seconds(finished)),
+        "durationSeconds": seconds(duration),
+        "phases": {
+            "fetch": seconds(timing.fetch),
+            "extract": seconds(timing.extract),
+            "script": seconds(timing.script),
+            "pack": seconds(timing.pack),
+        },
+    })
+}
+
+fn input(dep: &Package, store: &PackageStore) -> Value {
+    json!({
+        "base": package_base_name(dep),
+        "hash": dep.hash,
+        "sha256": published_sha256(&store.package_artifact_path(dep)),
+    })
+}
+
+/// The evaluated manifest fields of `package`, leaving out those it does
+/// not set.
+fn definition(package: &Package) -> Value {
+    let mut fields = Map::new();
+    if let Some(name) = &package.name {
+        fields.insert("name".into(), json!(name));
+    }
+    fields.insert("build".into(), json!(package.build));
+    for (field, script) in package.hooks.phases() {
+        if !script.is_empty() {
+            fields.insert(field.into(), json!(script));
+        }
+    }
+    if !package.build_with.is_empty() {
+        fields.insert("buildWith".into(), json!(package.build_with));
+    }
+    match &package.builder_shell {
+        Some(BuilderShell::Path(path)) => {
+            fields.insert("builderShell".into(), json!(path));
+        }
+        Some(BuilderShell::Package(shell)) => {
+            fields.insert("builderShell".into(), json!(package_base_name(shell)));
+        }
+        None => {}
+    }
+    if !package.env.is_empty() {
+        fields.insert("env".into(), json!(package.env));
+    }
+    if package.outputs.len() > 1 {
+        fields.insert("outputs".into(), json!(package.outputs));
+    }
+    if let Some(output_of) = &package.output_of {
+        fields.insert(
+            "outputOf".into(),
+            json!({
+                "package": package_base_name(&output_of.owner),
+                "output": output_of.output,
+            }),
+        );
+    }
+    let fetch: Vec<Value> = package
+        .fetch
+        .iter()
+        .map(|fetch| match &fetch.source {
+            Some(source) => json!({
+                "filename": fetch.filename,
+                "sha256": fetch.sha256,
+                "src": source.path,
+            }),
+            None if fetch.inline.is_some() => json!({
+                "filename": fetch.filename,
+                "sha256": fetch.sha256,
+                "type": "inline",
+            }),
+            None => json!({
+                "filename": fetch.filename,
+                "sha256": fetch.sha256,
+                "urls": fetch.urls,
+            }),
+        })
+        .collect();
+    if !fetch.is_empty() {
+        fields.insert("fetch".into(), json!(fetch));
+    }
+
+    let sandbox = &package.sandbox;
+    let mut sandbox_fields = Map::new();
+    if let Some(time) = &sandbox.fake_time {
+        sandbox_fields.insert("fakeTime".into(), json!(time));
+    }
+    if let Some(seed) = &sandbox.random_seed {
+        sandbox_fields.insert("randomSeed".into(), json!(seed));
+    }
+    if !sandbox.ro_binds.is_empty() {
+        sandbox_fields.insert("roBinds".into(), json!(sandbox.ro_binds));
+    }
+    if let Some(host_dev) = sandbox.host_dev {
+        sandbox_fields.insert("hostDev".into(), json!(host_dev));
+    }
+    if let Some(uid) = sandbox.uid {
+        sandbox_fields.insert("uid".into(), json!(uid));
+    }
+    if let Some(gid) = sandbox.gid {
+        sandbox_fields.insert("gid".into(), json!(gid));
+    }
+    if let Some(hostname) = &sandbox.hostname {
+        sandbox_fields.insert("hostname".into(), json!(hostname));
+    }
+    if sandbox.fakeroot {
+        sandbox_fields.insert("fakeroot".into(), json!(true));
+    }
+    if !sandbox_fields.is_empty() {
+        fields.insert("sandbox".into(), Value::Object(sandbox_fields));
+    }
+
+    let metadata = &package.metadata;
+    for (field, value) in [
+        ("version", &metadata.version),
+        ("license", &metadata.license),
+        ("homepage", &metadata.homepage),
+        ("description", &metadata.description),
+    ] {
+        if let Some(value) = value {
+            fields.insert(field.into(), json!(value));
+        }
+    }
+    Value::Object(fields)
+}
+
+fn epoch_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Seconds rounded to tenths, which is all a build duration needs.
+fn seconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10.0).round() / 10.0
+}
@@ -0,0 +1,206 @@
+use std::{env, fs};
+
+use reqwest::{
+    Certificate, NoProxy, Proxy, Url,
+    blocking::{Client, ClientBuilder, RequestBuilder},
+};
+
+use crate::{MagError, MagResult};
+
+pub const SOCKS_PROXY_ENV: &str = "MAGPKG_SOCKS_PROXY";
+pub const SOCKS_DOMAINS_ENV: &str = "MAGPKG_SOCKS_DOMAINS";
+pub const CA_BUNDLE_ENV: &str = "MAGPKG_CA_BUNDLE";
+pub const INSECURE_HOSTS_ENV: &str = "MAGPKG_INSECURE_TLS_HOSTS";
+
+/// SOCKS5 egress applied to HTTP fetches and remote Jsonnet imports.
+///
+/// When `domains` is empty every request is routed through the proxy;
+/// otherwise only hosts equal to, or subdomains of, a listed domain are.
+#[derive(Debug, Clone)]
+pub struct SocksProxyConfig {
+    proxy_url: Url,
+    domains: Vec<String>,
+}
+
+impl SocksProxyConfig {
+    pub fn from_env() -> MagResult<Option<Self>> {
+        let Some(raw) = env::var_os(SOCKS_PROXY_ENV) else {
+            return Ok(None);
+        };
+        let raw = raw.to_string_lossy().trim().to_string();
+        if raw.is_empty() {
+            return Ok(None);
+        }
+
+        let domains = env::var(SOCKS_DOMAINS_ENV)
+            .map(|value| parse_domain_list(&value))
+            .unwrap_or_default();
+
+        Self::new(&raw, domains).map(Some)
+    }
+
+    pub fn new(proxy: &str, domains: Vec<String>) -> MagResult<Self> {
+        let proxy_url = Url::parse(proxy).map_err(|err| {
+            MagError::Generic(format!("invalid {SOCKS_PROXY_ENV} value {proxy}: {err}"))
+        })?;
+
+        match proxy_url.scheme() {
+            "socks5" | "socks5h" => {}
+            other => {
+                return Err(MagError::Generic(format!(
+                    "{SOCKS_PROXY_ENV} must use the socks5:// or socks5h:// scheme, got {other}://"
+                )));
+            }
+        }
+
+        if proxy_url.host_str().is_none() {
+            return Err(MagError::Generic(format!(
+                "{SOCKS_PROXY_ENV} is missing a proxy host: {proxy}"
+            )));
+        }
+
+        Ok(Self { proxy_url, domains })
+    }
+
+    pub fn routes(&self, url: &Url) -> bool {
+        self.domains.is_empty() || host_in_domains(url, &self.domains)
+    }
+
+    fn to_proxy(&self) -> Proxy {
+        let config = self.clone();
+        Proxy::custom(move |url| {
+            if config.routes(url) {
+                Some(config.proxy_url.clone())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// HTTP client for fetches, imports, and indexes. Hosts listed in
+/// `MAGPKG_INSECURE_TLS_HOSTS` get a second client that skips certificate
+/// verification; every other request uses the verifying client.
+pub struct HttpClient {
+    client: Client,
+    insecure: Option<(Client, Vec<String>)>,
+}
+
+impl HttpClient {
+    /// Build clients from `make_builder` with the store-wide egress settings:
+    /// the SOCKS5 proxy, `http_proxy`/`https_proxy`/`no_proxy`, and extra CA
+    /// certificates from `MAGPKG_CA_BUNDLE`.
+    pub fn from_env(make_builder: impl Fn() -> ClientBuilder) -> MagResult<Self> {
+        let client = configure(make_builder())?.build()?;
+        let insecure_hosts = env::var(INSECURE_HOSTS_ENV)
+            .map(|value| parse_domain_list(&value))
+            .unwrap_or_default();
+        let insecure = if insecure_hosts.is_empty() {
+            None
+        } else {
+            let insecure_client = configure(make_builder())?
+                .danger_accept_invalid_certs(true)
+                .build()?;
+            Some((insecure_client, insecure_hosts))
+        };
+        Ok(Self { client, insecure })
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client_for(url).get(url)
+    }
+
+    pub fn head(&self, url: &str) -> RequestBuilder {
+        self.client_for(url).head(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client_for(url).post(url)
+    }
+
+    fn client_for(&self, url: &str) -> &Client {
+        if let (Some((insecure, hosts)), Ok(parsed)) = (&self.insecure, Url::parse(url)) {
+            if parsed.scheme() == "https" && host_in_domains(&parsed, hosts) {
+                return insecure;
+            }
+        }
+        &self.client
+    }
+}
+
+fn configure(mut builder: ClientBuilder) -> MagResult<ClientBuilder> {
+    // Proxies are consulted in order: SOCKS-routed hosts first, then the
+    // conventional environment proxies. Adding any proxy disables reqwest's
+    // own environment lookup, so it is replicated here.
+    if let Some(config) = SocksProxyConfig::from_env()? {
+        builder = builder.proxy(config.to_proxy());
+    }
+    for (scheme, names) in [
+        ("http", ["http_proxy", "HTTP_PROXY"]),
+        ("https", ["https_proxy", "HTTPS_PROXY"]),
+    ] {
+        let Some(proxy_url) = names
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .map(|value| value.trim().to_string())
+            .find(|value| !value.is_empty())
+        else {
+            continue;
+        };
+        let proxy = match scheme {
+            "http" => Proxy::http(&proxy_url),
+            _ => Proxy::https(&proxy_url),
+        }
+        .map_err(|err| MagError::Generic(format!("invalid {scheme} proxy {proxy_url}: {err}")))?;
+        builder = builder.proxy(proxy.no_proxy(NoProxy::from_env()));
+    }
+
+    if let Some(bundles) = env::var_os(CA_BUNDLE_ENV) {
+        for path in env::split_paths(&bundles) {
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            let pem = fs::read(&path).map_err(|err| {
+                MagError::Generic(format!(
+                    "failed to read {CA_BUNDLE_ENV} file {}: {err}",
+                    path.display()
+                ))
+            })?;
+            let certificates = Certificate::from_pem_bundle(&pem).map_err(|err| {
+                MagError::Generic(format!(
+                    "invalid certificate in {CA_BUNDLE_ENV} file {}: {err}",
+                    path.display()
+                ))
+            })?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+    }
+
+    Ok(builder)
+}
+
+/// Whether the URL's host equals, or is a subdomain of, a listed domain.
+fn host_in_domains(url: &Url, domains: &[String]) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+    domains.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+fn parse_domain_list(value: &str) -> Vec<String> {
+    value
+        .split([',', ' ', '\n', '\t'])
+        .map(|item| item.trim().trim_start_matches('.').trim_end_matches('.'))
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_ascii_lowercase())
+        .collect()
+}
@@ -0,0 +1,41 @@
+use std::{fs::OpenOptions, io, os::fd::AsRawFd};
+
+use crate::MagResult;
+
+/// Points this process's stderr at `/dev/null` until dropped, for commands
+/// run with `--quiet`. Child processes started afterwards get the original
+/// stderr back, and errors are still reported because `main` prints them
+/// after the guard is gone.
+pub struct SilencedStderr {
+    saved: libc::c_int,
+}
+
+impl SilencedStderr {
+    pub fn new() -> MagResult<Self> {
+        let null = OpenOptions::new().write(true).open("/dev/null")?;
+        // SAFETY: dup only duplicates a descriptor this process owns.
+        let saved = unsafe { libc::dup(libc::STDERR_FILENO) };
+        if saved < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: both descriptors are open; dup2 atomically replaces fd 2.
+        if unsafe { libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+            let err = io::Error::last_os_error();
+            // SAFETY: `saved` was returned by dup above and is not used again.
+            unsafe { libc::close(saved) };
+            return Err(err.into());
+        }
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for SilencedStderr {
+    fn drop(&mut self) {
+        // SAFETY: `saved` is the original stderr duplicated in `new`, owned
+        // by this guard and closed exactly once.
+        unsafe {
+            libc::dup2(self.saved, libc::STDERR_FILENO);
+            libc::close(self.saved);
+        }
+    }
+}
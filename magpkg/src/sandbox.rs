@@ -0,0 +1,202 @@
+use std::{
+    env,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{MagError, MagResult};
+
+pub const LIBFAKETIME_ENV: &str = "MAGPKG_LIBFAKETIME";
+const LIBFAKETIME_CANDIDATES: &[&str] = &[
+    "/usr/lib/faketime/libfaketime.so.1",
+    "/usr/lib/x86_64-linux-gnu/faketime/libfaketime.so.1",
+    "/usr/lib/aarch64-linux-gnu/faketime/libfaketime.so.1",
+    "/usr/lib64/faketime/libfaketime.so.1",
+    "/usr/local/lib/faketime/libfaketime.so.1",
+];
+
+/// Locate libfaketime on the host so it can be bound into the sandbox.
+pub fn find_libfaketime() -> MagResult<PathBuf> {
+    if let Some(custom) = env::var_os(LIBFAKETIME_ENV) {
+        let path = PathBuf::from(custom);
+        if path.is_file() {
+            return Ok(path);
+        }
+        return Err(MagError::Generic(format!(
+            "{LIBFAKETIME_ENV} points at {}, which is not a file",
+            path.display()
+        )));
+    }
+
+    LIBFAKETIME_CANDIDATES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            MagError::Generic(format!(
+                "sandbox.fakeTime requires libfaketime; install it or set {LIBFAKETIME_ENV}"
+            ))
+        })
+}
+
+/// Format a `sandbox.fakeTime` value for the FAKETIME variable. Absolute
+/// timestamps are pinned with `@` so the clock starts at the given instant.
+pub fn faketime_spec(fake_time: &str) -> String {
+    let trimmed = fake_time.trim();
+    if trimmed.starts_with(['@', '+', '-']) {
+        trimmed.to_string()
+    } else {
+        format!("@{trimmed}")
+    }
+}
+
+/// How long the random stream writer waits for a reader or for room in the
+/// pipe before it checks whether it should stop.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long dropping a [`DeterministicRandom`] waits for its writer.
+const STREAM_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Named pipe that serves an endless, seed-determined byte stream. The
+/// writer closes its end as soon as the last reader has gone, so each
+/// reader that opens the pipe after the previous one closed it sees the
+/// stream from the beginning, and a tool reading /dev/urandom gets the
+/// same bytes on every build. Readers that hold the pipe open at the same
+/// time share one stream.
+pub struct DeterministicRandom {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl DeterministicRandom {
+    pub fn start(path: PathBuf, seed: &str) -> MagResult<Self> {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| MagError::Generic(format!("invalid fifo path {}", path.display())))?;
+        // SAFETY: c_path is a valid NUL-terminated path for the duration of the call.
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+        let worker_path = path.clone();
+        let seed = seed.to_string();
+        let worker = thread::Builder::new()
+            .name("deterministic-random".into())
+            .spawn(move || serve_stream(&worker_path, &seed, &worker_stop))
+            .map_err(|err| {
+                MagError::Generic(format!("failed to spawn random stream writer: {err}"))
+            })?;
+
+        Ok(Self {
+            path,
+            stop,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DeterministicRandom {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // The writer never blocks for longer than STREAM_POLL_INTERVAL, but
+        // a stuck thread must not hang the build; it is left behind then.
+        if let Some(worker) = self.worker.take() {
+            let deadline = Instant::now() + STREAM_STOP_TIMEOUT;
+            while !worker.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if worker.is_finished() {
+                let _ = worker.join();
+            } else {
+                eprintln!(
+                    "warning: the random stream writer for {} did not stop",
+                    self.path.display()
+                );
+            }
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_stream(path: &Path, seed: &str, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        // Without a reader, a non-blocking open fails with ENXIO instead of
+        // waiting, so the stop flag is checked between attempts.
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+        {
+            Ok(pipe) => serve_reader(pipe, seed, stop),
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {
+                thread::sleep(STREAM_POLL_INTERVAL)
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Write the stream from its start until every reader has closed the pipe.
+/// Dropping `pipe` then frees the pipe and whatever it still buffers, so
+/// the next reader starts over.
+fn serve_reader(mut pipe: File, seed: &str, stop: &AtomicBool) {
+    let mut counter: u64 = 0;
+    let mut block = [0u8; 32];
+    let mut written = block.len();
+    while !stop.load(Ordering::SeqCst) {
+        if written == block.len() {
+            let mut hasher = Sha256::new();
+            hasher.update(seed.as_bytes());
+            hasher.update(counter.to_le_bytes());
+            counter = counter.wrapping_add(1);
+            block.copy_from_slice(&hasher.finalize());
+            written = 0;
+        }
+        match pipe.write(&block[written..]) {
+            Ok(count) => written += count,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                let mut poll_fd = libc::pollfd {
+                    fd: pipe.as_raw_fd(),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                };
+                // SAFETY: poll_fd is a valid pollfd for an open descriptor and
+                // outlives the call; the count of 1 matches.
+                let ready =
+                    unsafe { libc::poll(&mut poll_fd, 1, STREAM_POLL_INTERVAL.as_millis() as i32) };
+                // POLLERR means the last reader closed the pipe.
+                if ready > 0 && poll_fd.revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                    return;
+                }
+            }
+            // EPIPE: the last reader closed the pipe.
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::HashSet,
+    env,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    package::{Package, collect_closure, collect_runtime_closure, package_base_name},
+    store::{PackageStore, published_sha256},
+};
+
+/// Render an SPDX 2.3 JSON document for the packages reachable from
+/// `roots`: their runtime closure, or every package including build-only
+/// dependencies when `include_build_deps` is set.
+///
+/// Each package reports its `version`, `license`, `homepage`, and
+/// `description` metadata, its first HTTP(S) source as the download
+/// location, and the sha256 of its artifact when it has been built. Run
+/// dependencies become `DEPENDS_ON` relationships and build dependencies
+/// `BUILD_DEPENDENCY_OF`. The creation time honors `SOURCE_DATE_EPOCH`, so
+/// the document is reproducible.
+pub fn spdx_document(
+    expression: &str,
+    roots: &[Rc<Package>],
+    store: &PackageStore,
+    include_build_deps: bool,
+) -> Value {
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for root in roots {
+        if include_build_deps {
+            collect_closure(root.clone(), &mut visited, &mut order);
+        } else {
+            collect_runtime_closure(root.clone(), &mut visited, &mut order);
+        }
+    }
+    let included: HashSet<&str> = order.iter().map(|package| package.hash.as_str()).collect();
+
+    let mut namespace = Sha256::new();
+    for package in &order {
+        namespace.update(package.hash.as_bytes());
+        namespace.update(b"\n");
+    }
+
+    let packages: Vec<Value> = order
+        .iter()
+        .map(|package| spdx_package(package, store))
+        .collect();
+
+    let mut relationships = Vec::new();
+    for root in roots {
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": spdx_id(root),
+        }));
+    }
+    for package in &order {
+        for dep in &package.run_deps {
+            relationships.push(json!({
+                "spdxElementId": spdx_id(package),
+                "relationshipType": "DEPENDS_ON",
+                "relatedSpdxElement": spdx_id(dep),
+            }));
+        }
+        for dep in &package.build_deps {
+            if included.contains(dep.hash.as_str()) {
+                relationships.push(json!({
+                    "spdxElementId": spdx_id(dep),
+                    "relationshipType": "BUILD_DEPENDENCY_OF",
+                    "relatedSpdxElement": spdx_id(package),
+                }));
+            }
+        }
+    }
+
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": expression.trim(),
+        "documentNamespace": format!(
+            "https://spdx.magnet-linux.invalid/{:x}",
+            namespace.finalize()
+        ),
+        "creationInfo": {
+            "created": creation_time(),
+            "creators": [format!("Tool: magpkg-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+fn spdx_package(package: &Package, store: &PackageStore) -> Value {
+    let metadata = &package.metadata;
+    let download = package
+        .fetch
+        .iter()
+        .flat_map(|fetch| &fetch.urls)
+        .find(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .map_or("NOASSERTION", String::as_str);
+
+    let mut entry = json!({
+        "SPDXID": spdx_id(package),
+        "name": package_base_name(package),
+        "downloadLocation": download,
+        "filesAnalyzed": false,
+        "licenseConcluded": "NOASSERTION",
+        "licenseDeclared": metadata.license.as_deref().unwrap_or("NOASSERTION"),
+        "copyrightText": "NOASSERTION",
+    });
+    let object = entry.as_object_mut().expect("package entry is an object");
+    if let Some(version) = &metadata.version {
+        object.insert("versionInfo".into(), json!(version));
+    }
+    if let Some(homepage) = &metadata.homepage {
+        object.insert("homepage".into(), json!(homepage));
+    }
+    if let Some(description) = &metadata.description {
+        object.insert("description".into(), json!(description));
+    }
+    if store.is_artifact_published(package) {
+        if let Some(sha256) = published_sha256(&store.package_artifact_path(package)) {
+            object.insert(
+                "checksums".into(),
+                json!([{ "algorithm": "SHA256", "checksumValue": sha256 }]),
+            );
+        }
+    }
+    entry
+}
+
+fn spdx_id(package: &Package) -> String {
+    format!("SPDXRef-Package-{}", package.hash)
+}
+
+/// `SOURCE_DATE_EPOCH`, or the current time, as an SPDX UTC timestamp.
+fn creation_time() -> String {
+    let seconds = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+    utc_timestamp(seconds)
+}
+
+/// Seconds since the epoch as an RFC 3339 UTC timestamp, e.g.
+/// `2024-05-01T12:00:00Z`.
+pub fn utc_timestamp(seconds: u64) -> String {
+    let days = (seconds / 86_400) as i64;
+    let time = seconds % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// The seconds since the epoch of a timestamp written by
+/// [`utc_timestamp`]; `None` for anything else.
+pub fn parse_utc_timestamp(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    // Days since 1970-01-01 from a civil date, the inverse of the above.
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = u64::try_from(era * 146_097 + doe - 719_468).ok()?;
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
@@ -0,0 +1,492 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+};
+
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::codec::{ArtifactCodec, CompressionSettings};
+
+/// Uncompressed bytes per frame. Reading one file from an artifact
+/// decompresses at most the frames its contents span, plus one.
+pub const FRAME_SIZE: usize = 1 << 20;
+
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const FOOTER_LEN: u64 = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+
+/// The most threads compressing or decompressing one artifact: twice the
+/// CPU count. Each one holds a [`FRAME_SIZE`] frame in memory, so larger
+/// counts only cost memory.
+pub fn max_threads() -> usize {
+    num_cpus::get().max(1) * 2
+}
+
+/// Writes the zstd seekable format: the input is cut into independent
+/// [`FRAME_SIZE`] frames and a seek table listing their sizes is appended as
+/// a skippable frame. Ordinary zstd decoders read the result as one stream
+/// and skip the table, so artifacts stay readable by `zstd -d` and older
+/// `magpkg` versions.
+///
+/// Frames do not depend on each other, so up to `threads` of them, at most
+/// [`max_threads`], are compressed at once. The output is the same for any
+/// thread count.
+pub struct SeekableWriter<W: Write> {
+    out: W,
+    level: i32,
+    threads: usize,
+    buffer: Vec<u8>,
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> SeekableWriter<W> {
+    pub fn new(out: W, settings: CompressionSettings) -> Self {
+        let threads = settings.threads.clamp(1, max_threads());
+        Self {
+            out,
+            level: settings.level,
+            threads,
+            buffer: Vec::with_capacity(FRAME_SIZE * threads),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Compress the last partial frames, append the seek table, and return
+    /// the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        let (mut out, frames) = self.finish_frames()?;
+        write_seek_table(&mut out, &frames)?;
+        out.flush()?;
+        Ok(out)
+    }
+
+    /// Compress the last partial frames and return the underlying writer
+    /// with the `(compressed, decompressed)` sizes of every frame written,
+    /// leaving the seek table to the caller.
+    pub fn finish_frames(mut self) -> io::Result<(W, Vec<(u32, u32)>)> {
+        self.flush_frames()?;
+        Ok((self.out, self.frames))
+    }
+
+    /// Compress the buffered frames, in parallel when there are several,
+    /// and write them out in order.
+    fn flush_frames(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let level = self.level;
+        let compressed: Vec<io::Result<Vec<u8>>> = if self.buffer.len() <= FRAME_SIZE {
+            vec![zstd::bulk::compress(&self.buffer, level)]
+        } else {
+            thread::scope(|scope| {
+                let workers: Vec<_> = self
+                    .buffer
+                    .chunks(FRAME_SIZE)
+                    .map(|chunk| scope.spawn(move || zstd::bulk::compress(chunk, level)))
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| {
+                        worker.join().unwrap_or_else(|_| {
+                            Err(io::Error::other("compression thread panicked"))
+                        })
+                    })
+                    .collect()
+            })
+        };
+        for (chunk, frame) in self.buffer.chunks(FRAME_SIZE).zip(compressed) {
+            let frame = frame?;
+            self.out.write_all(&frame)?;
+            self.frames.push((frame.len() as u32, chunk.len() as u32));
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+fn write_seek_table<W: Write>(out: &mut W, frames: &[(u32, u32)]) -> io::Result<()> {
+    let mut table = Vec::with_capacity(frames.len() * 8 + FOOTER_LEN as usize + 8);
+    table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    let content_len = frames.len() as u64 * 8 + FOOTER_LEN;
+    table.extend_from_slice(&(content_len as u32).to_le_bytes());
+    for (compressed, decompressed) in frames {
+        table.extend_from_slice(&compressed.to_le_bytes());
+        table.extend_from_slice(&decompressed.to_le_bytes());
+    }
+    table.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    table.push(0);
+    table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    out.write_all(&table)
+}
+
+impl<W: Write> Write for SeekableWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let batch = FRAME_SIZE * self.threads;
+        let take = data.len().min(batch - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..take]);
+        if self.buffer.len() == batch {
+            self.flush_frames()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct FrameEntry {
+    compressed_offset: u64,
+    decompressed_offset: u64,
+    compressed_size: u32,
+    decompressed_size: u32,
+}
+
+/// Frame layout of a seekable artifact, read from its trailing seek table.
+pub struct SeekTable {
+    frames: Vec<FrameEntry>,
+}
+
+impl SeekTable {
+    /// Read the seek table at the end of `file`. Returns `None` for plain
+    /// single-frame zstd files, such as artifacts written before the
+    /// seekable format was adopted.
+    pub fn read(file: &mut File) -> io::Result<Option<Self>> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        if file_len < FOOTER_LEN + 8 {
+            return Ok(None);
+        }
+
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        file.read_exact(&mut footer)?;
+        let frame_count = u32::from_le_bytes(footer[0..4].try_into().expect("4 bytes")) as u64;
+        let descriptor = footer[4];
+        if u32::from_le_bytes(footer[5..9].try_into().expect("4 bytes")) != SEEKABLE_MAGIC {
+            return Ok(None);
+        }
+
+        let entry_len = if descriptor & CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+        let content_len = frame_count * entry_len + FOOTER_LEN;
+        if file_len < content_len + 8 {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::End(-((content_len + 8) as i64)))?;
+        let mut table = vec![0u8; (content_len + 8) as usize];
+        file.read_exact(&mut table)?;
+        let word = |at: usize| u32::from_le_bytes(table[at..at + 4].try_into().expect("4 bytes"));
+        if word(0) != SKIPPABLE_MAGIC || u64::from(word(4)) != content_len {
+            return Ok(None);
+        }
+
+        let mut frames = Vec::with_capacity(frame_count as usize);
+        let (mut compressed_offset, mut decompressed_offset) = (0u64, 0u64);
+        for index in 0..frame_count as usize {
+            let at = 8 + index * entry_len as usize;
+            let entry = FrameEntry {
+                compressed_offset,
+                decompressed_offset,
+                compressed_size: word(at),
+                decompressed_size: word(at + 4),
+            };
+            compressed_offset += u64::from(entry.compressed_size);
+            decompressed_offset += u64::from(entry.decompressed_size);
+            frames.push(entry);
+        }
+        Ok(Some(Self { frames }))
+    }
+
+    /// Decompress `len` bytes starting at `offset` of the decompressed
+    /// stream into `out`, touching only the frames that hold them.
+    pub fn copy_range<W: Write + ?Sized>(
+        &self,
+        file: &mut File,
+        offset: u64,
+        len: u64,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let end = offset + len;
+        let mut copied = 0u64;
+        let first = self.frames.partition_point(|frame| {
+            frame.decompressed_offset + u64::from(frame.decompressed_size) <= offset
+        });
+        for frame in &self.frames[first..] {
+            if frame.decompressed_offset >= end {
+                break;
+            }
+            let mut compressed = vec![0u8; frame.compressed_size as usize];
+            file.seek(SeekFrom::Start(frame.compressed_offset))?;
+            file.read_exact(&mut compressed)?;
+            let data = zstd::bulk::decompress(&compressed, frame.decompressed_size as usize)?;
+
+            let start = offset.saturating_sub(frame.decompressed_offset) as usize;
+            let stop = (end - frame.decompressed_offset).min(data.len() as u64) as usize;
+            out.write_all(&data[start..stop])?;
+            copied += (stop - start) as u64;
+        }
+        if copied != len {
+            return Err(range_past_end());
+        }
+        Ok(())
+    }
+
+    /// Read the frames in order from `reader`, which must be positioned at
+    /// the first frame, decompressing up to `threads` of them, at most
+    /// [`max_threads`], at once.
+    pub fn parallel_reader<R: Read>(self, reader: R, threads: usize) -> ParallelFrameReader<R> {
+        ParallelFrameReader {
+            reader,
+            frames: self.frames,
+            next_frame: 0,
+            threads: threads.clamp(1, max_threads()),
+            decoded: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+/// Decompressed stream of a seekable artifact, for extracting it whole.
+/// Compressed frames are still read front to back, so a hashing reader
+/// underneath sees the file in order, but each batch is decompressed on
+/// several threads.
+pub struct ParallelFrameReader<R: Read> {
+    reader: R,
+    frames: Vec<FrameEntry>,
+    next_frame: usize,
+    threads: usize,
+    decoded: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> ParallelFrameReader<R> {
+    fn decode_batch(&mut self) -> io::Result<()> {
+        let batch =
+            &self.frames[self.next_frame..(self.next_frame + self.threads).min(self.frames.len())];
+        let mut compressed = Vec::with_capacity(batch.len());
+        for frame in batch {
+            let mut data = vec![0u8; frame.compressed_size as usize];
+            self.reader.read_exact(&mut data)?;
+            compressed.push((data, frame.decompressed_size as usize));
+        }
+        let decompress = |(data, size): &(Vec<u8>, usize)| -> io::Result<Vec<u8>> {
+            let decoded = zstd::bulk::decompress(data, *size)?;
+            if decoded.len() != *size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame size does not match the seek table",
+                ));
+            }
+            Ok(decoded)
+        };
+        let decoded: Vec<io::Result<Vec<u8>>> = if compressed.len() == 1 {
+            vec![decompress(&compressed[0])]
+        } else {
+            thread::scope(|scope| {
+                let workers: Vec<_> = compressed
+                    .iter()
+                    .map(|frame| scope.spawn(move || decompress(frame)))
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| {
+                        worker.join().unwrap_or_else(|_| {
+                            Err(io::Error::other("decompression thread panicked"))
+                        })
+                    })
+                    .collect()
+            })
+        };
+
+        self.next_frame += batch.len();
+        self.decoded.clear();
+        self.position = 0;
+        for frame in decoded {
+            self.decoded.extend_from_slice(&frame?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ParallelFrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.decoded.len() {
+            if self.next_frame == self.frames.len() {
+                return Ok(0);
+            }
+            self.decode_batch()?;
+        }
+        let len = buf.len().min(self.decoded.len() - self.position);
+        buf[..len].copy_from_slice(&self.decoded[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Copy `len` bytes at `offset` of an artifact's decompressed tar stream.
+/// Seekable artifacts only decompress the frames involved and uncompressed
+/// ones are read in place; older single-frame zstd artifacts and xz
+/// artifacts are decompressed up to the end of the range.
+pub fn copy_artifact_range<W: Write + ?Sized>(
+    artifact: &Path,
+    offset: u64,
+    len: u64,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut file = File::open(artifact)?;
+    let mut decoder: Box<dyn Read> = match ArtifactCodec::detect(&mut file)? {
+        ArtifactCodec::Zstd => {
+            if let Some(table) = SeekTable::read(&mut file)? {
+                return table.copy_range(&mut file, offset, len, out);
+            }
+            file.seek(SeekFrom::Start(0))?;
+            Box::new(ZstdDecoder::new(file)?)
+        }
+        ArtifactCodec::Xz => Box::new(XzDecoder::new(file)),
+        ArtifactCodec::None => {
+            file.seek(SeekFrom::Start(offset))?;
+            return copy_exact(&mut file.take(len), len, out);
+        }
+    };
+    let skipped = io::copy(&mut (&mut decoder).take(offset), &mut io::sink())?;
+    if skipped != offset {
+        return Err(range_past_end());
+    }
+    copy_exact(&mut decoder.take(len), len, out)
+}
+
+fn copy_exact<R: Read, W: Write + ?Sized>(reader: &mut R, len: u64, out: &mut W) -> io::Result<()> {
+    if io::copy(reader, out)? != len {
+        return Err(range_past_end());
+    }
+    Ok(())
+}
+
+fn range_past_end() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "range extends past the end of the artifact",
+    )
+}
+
+/// Length of the end-of-archive marker that closes every artifact's tar
+/// stream: two zeroed 512-byte blocks.
+const TAR_TRAILER_LEN: usize = 1024;
+
+/// Joins the tar streams of several artifacts into one seekable zstd tar
+/// without unpacking them. Frames of seekable artifacts are copied as they
+/// are; only the frames holding each artifact's end-of-archive marker are
+/// decompressed, trimmed, and compressed again. Other artifacts (xz,
+/// uncompressed, or zstd without a seek table) are recompressed whole.
+pub struct TarConcatenator<W: Write> {
+    out: W,
+    settings: CompressionSettings,
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> TarConcatenator<W> {
+    pub fn new(out: W, settings: CompressionSettings) -> Self {
+        Self {
+            out,
+            settings,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Append every entry of `artifact`, in archive order.
+    pub fn append_artifact(&mut self, artifact: &Path) -> io::Result<()> {
+        let mut file = File::open(artifact)?;
+        match ArtifactCodec::detect(&mut file)? {
+            ArtifactCodec::Zstd => {
+                if let Some(table) = SeekTable::read(&mut file)? {
+                    return self.append_frames(&mut file, &table);
+                }
+                file.seek(SeekFrom::Start(0))?;
+                self.append_stream(ZstdDecoder::new(file)?)
+            }
+            ArtifactCodec::Xz => self.append_stream(XzDecoder::new(file)),
+            ArtifactCodec::None => self.append_stream(file),
+        }
+    }
+
+    fn append_frames(&mut self, file: &mut File, table: &SeekTable) -> io::Result<()> {
+        let mut tail_start = table.frames.len();
+        let mut tail_len = 0usize;
+        while tail_start > 0 && tail_len < TAR_TRAILER_LEN {
+            tail_start -= 1;
+            tail_len += table.frames[tail_start].decompressed_size as usize;
+        }
+
+        for frame in &table.frames[..tail_start] {
+            file.seek(SeekFrom::Start(frame.compressed_offset))?;
+            let copied = io::copy(
+                &mut (&mut *file).take(u64::from(frame.compressed_size)),
+                &mut self.out,
+            )?;
+            if copied != u64::from(frame.compressed_size) {
+                return Err(range_past_end());
+            }
+            self.frames
+                .push((frame.compressed_size, frame.decompressed_size));
+        }
+
+        let mut tail = Vec::with_capacity(tail_len);
+        for frame in &table.frames[tail_start..] {
+            let mut compressed = vec![0u8; frame.compressed_size as usize];
+            file.seek(SeekFrom::Start(frame.compressed_offset))?;
+            file.read_exact(&mut compressed)?;
+            tail.extend(zstd::bulk::decompress(
+                &compressed,
+                frame.decompressed_size as usize,
+            )?);
+        }
+        self.append_stream(tail.as_slice())
+    }
+
+    /// Compress a decompressed tar stream, minus its end-of-archive marker.
+    fn append_stream<R: Read>(&mut self, mut reader: R) -> io::Result<()> {
+        let mut writer = SeekableWriter::new(&mut self.out, self.settings);
+        let mut held = Vec::with_capacity(FRAME_SIZE + TAR_TRAILER_LEN);
+        let mut buffer = vec![0u8; FRAME_SIZE];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            held.extend_from_slice(&buffer[..read]);
+            if held.len() > TAR_TRAILER_LEN {
+                let ready = held.len() - TAR_TRAILER_LEN;
+                writer.write_all(&held[..ready])?;
+                held.drain(..ready);
+            }
+        }
+        if held.len() != TAR_TRAILER_LEN || held.iter().any(|byte| *byte != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "artifact does not end with a tar end-of-archive marker",
+            ));
+        }
+        let (_, frames) = writer.finish_frames()?;
+        self.frames.extend(frames);
+        Ok(())
+    }
+
+    /// Close the combined archive with a single end-of-archive marker and
+    /// a seek table covering every frame.
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut writer = SeekableWriter::new(&mut self.out, self.settings);
+        writer.write_all(&[0u8; TAR_TRAILER_LEN])?;
+        let (_, frames) = writer.finish_frames()?;
+        self.frames.extend(frames);
+        write_seek_table(&mut self.out, &self.frames)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
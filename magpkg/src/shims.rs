@@ -0,0 +1,373 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    MagError, MagResult,
+    profiles::{profiles_dir, validate_profile_name},
+};
+
+/// Exports live next to the profiles; the leading dot keeps them out of
+/// `list_profiles`.
+const EXPORTS_DIR: &str = ".exports";
+const SHIM_MARKER: &str = "# generated by magpkg venv export";
+const DESKTOP_MARKER_KEY: &str = "X-Magpkg-Profile";
+/// Directories searched inside the rootfs for `--bin` commands.
+const BIN_DIRS: &[&str] = &["usr/bin", "bin", "usr/sbin", "sbin"];
+/// Theme icon locations searched inside the rootfs, best first.
+const ICON_DIRS: &[&str] = &[
+    "usr/share/icons/hicolor/scalable/apps",
+    "usr/share/icons/hicolor/512x512/apps",
+    "usr/share/icons/hicolor/256x256/apps",
+    "usr/share/icons/hicolor/128x128/apps",
+    "usr/share/icons/hicolor/64x64/apps",
+    "usr/share/icons/hicolor/48x48/apps",
+    "usr/share/pixmaps",
+];
+const ICON_EXTENSIONS: &[&str] = &["svg", "png", "xpm"];
+
+/// What `magpkg venv export` generated for a profile, recorded in
+/// `<store>/venv-profiles/.exports/<name>` so the files can be refreshed when
+/// the profile's rootfs changes and removed by `venv unexport`.
+///
+/// ```text
+/// binDir   /home/me/.local/bin
+/// dataDir  /home/me/.local/share
+/// desktop  on|off
+/// bin      <command>                 (repeatable)
+/// file     <generated path>          (repeatable)
+/// ```
+pub struct HostExports {
+    pub profile: String,
+    pub bin_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub desktop: bool,
+    pub bins: Vec<String>,
+    files: Vec<PathBuf>,
+}
+
+impl HostExports {
+    /// Export settings with the XDG defaults for any directory not given.
+    pub fn new(
+        profile: &str,
+        bin_dir: Option<PathBuf>,
+        data_dir: Option<PathBuf>,
+        desktop: bool,
+        bins: Vec<String>,
+    ) -> MagResult<Self> {
+        let home = || {
+            env::var_os("HOME").map(PathBuf::from).ok_or_else(|| {
+                MagError::Generic("HOME is not set; pass --bin-dir and --data-dir".into())
+            })
+        };
+        let bin_dir = match bin_dir {
+            Some(dir) => dir,
+            None => home()?.join(".local/bin"),
+        };
+        let data_dir = match (data_dir, env::var_os("XDG_DATA_HOME")) {
+            (Some(dir), _) => dir,
+            (None, Some(dir)) if !dir.is_empty() => PathBuf::from(dir),
+            (None, _) => home()?.join(".local/share"),
+        };
+        Ok(Self {
+            profile: profile.to_string(),
+            bin_dir,
+            data_dir,
+            desktop,
+            bins,
+            files: Vec::new(),
+        })
+    }
+
+    /// The recorded exports of `profile`, if it has any.
+    pub fn load(base_root: &Path, profile: &str) -> MagResult<Option<Self>> {
+        validate_profile_name(profile)?;
+        let path = exports_path(base_root, profile);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut exports = Self {
+            profile: profile.to_string(),
+            bin_dir: PathBuf::new(),
+            data_dir: PathBuf::new(),
+            desktop: false,
+            bins: Vec::new(),
+            files: Vec::new(),
+        };
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('\t') else {
+                continue;
+            };
+            match key {
+                "binDir" => exports.bin_dir = PathBuf::from(value),
+                "dataDir" => exports.data_dir = PathBuf::from(value),
+                "desktop" => exports.desktop = value == "on",
+                "bin" => exports.bins.push(value.to_string()),
+                "file" => exports.files.push(PathBuf::from(value)),
+                _ => {}
+            }
+        }
+        Ok(Some(exports))
+    }
+
+    /// Replace previously generated files with shims and desktop entries for
+    /// the rootfs at `rootfs`, launched through `magpkg`, then record them.
+    pub fn generate(&mut self, base_root: &Path, magpkg: &Path, rootfs: &Path) -> MagResult<()> {
+        self.remove_files()?;
+
+        let launcher = [
+            magpkg.to_string_lossy().into_owned(),
+            "venv".into(),
+            "enter".into(),
+            self.profile.clone(),
+            "--".into(),
+        ];
+
+        for command in &self.bins.clone() {
+            let inner_path = find_command(rootfs, command)?;
+            let shim = self.bin_dir.join(command);
+            let mut script = format!("#!/bin/sh\n{SHIM_MARKER} {}\nexec", self.profile);
+            for arg in launcher.iter().chain([&inner_path]) {
+                script.push(' ');
+                script.push_str(&shell_quote(arg));
+            }
+            script.push_str(" \"$@\"\n");
+            self.write_generated(&shim, script.as_bytes(), 0o755)?;
+        }
+
+        if self.desktop {
+            self.generate_desktop_entries(rootfs, &launcher)?;
+        }
+        self.save(base_root)
+    }
+
+    /// Remove every file this export generated, plus the record itself.
+    pub fn remove(mut self, base_root: &Path) -> MagResult<()> {
+        self.remove_files()?;
+        match fs::remove_file(exports_path(base_root, &self.profile)) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    fn generate_desktop_entries(&mut self, rootfs: &Path, launcher: &[String]) -> MagResult<()> {
+        let source_dir = rootfs.join("usr/share/applications");
+        let entries = match fs::read_dir(&source_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut sources = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Symlinks may point at absolute paths that only resolve inside
+            // the venv, so only regular files are exported.
+            if name.ends_with(".desktop") && entry.file_type()?.is_file() {
+                sources.push((name, entry.path()));
+            }
+        }
+        sources.sort();
+
+        let exec_prefix = launcher
+            .iter()
+            .map(|arg| desktop_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let applications_dir = self.data_dir.join("applications");
+        for (name, source) in sources {
+            let contents = fs::read_to_string(&source)?;
+            let mut rewritten = String::with_capacity(contents.len() + 256);
+            for line in contents.lines() {
+                if let Some(exec) = line.strip_prefix("Exec=") {
+                    rewritten.push_str(&format!("Exec={exec_prefix} {exec}\n"));
+                } else if line.starts_with("TryExec=") {
+                    // The program only exists inside the venv.
+                } else if let Some(icon) = line.strip_prefix("Icon=") {
+                    let icon = self
+                        .export_icon(rootfs, icon)?
+                        .unwrap_or_else(|| icon.into());
+                    rewritten.push_str(&format!("Icon={icon}\n"));
+                } else {
+                    rewritten.push_str(line);
+                    rewritten.push('\n');
+                    if line.trim() == "[Desktop Entry]" {
+                        rewritten.push_str(&format!("{DESKTOP_MARKER_KEY}={}\n", self.profile));
+                    }
+                }
+            }
+            let target = applications_dir.join(format!("magpkg-{}-{name}", self.profile));
+            self.write_generated(&target, rewritten.as_bytes(), 0o644)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the icon an entry names out of the rootfs, which moves whenever
+    /// the profile is updated. Returns the host path to use instead.
+    fn export_icon(&mut self, rootfs: &Path, icon: &str) -> MagResult<Option<String>> {
+        let icon = icon.trim();
+        let found = if let Some(inner) = icon.strip_prefix('/') {
+            let path = rootfs.join(inner);
+            path.is_file().then_some(path)
+        } else if icon.is_empty() || icon.contains('/') {
+            None
+        } else {
+            ICON_DIRS.iter().find_map(|dir| {
+                ICON_EXTENSIONS
+                    .iter()
+                    .map(|ext| rootfs.join(dir).join(format!("{icon}.{ext}")))
+                    .find(|path| path.is_file())
+            })
+        };
+        let Some(source) = found else {
+            return Ok(None);
+        };
+        let file_name = source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let target = self
+            .data_dir
+            .join("icons/magpkg")
+            .join(format!("{}-{file_name}", self.profile));
+        self.write_generated(&target, &fs::read(&source)?, 0o644)?;
+        Ok(Some(target.to_string_lossy().into_owned()))
+    }
+
+    /// Write a generated file, refusing to replace one the user owns.
+    fn write_generated(&mut self, path: &Path, contents: &[u8], mode: u32) -> MagResult<()> {
+        if fs::symlink_metadata(path).is_ok() && !self.files.iter().any(|file| file == path) {
+            let ours = fs::read(path).is_ok_and(|existing| is_generated(&existing));
+            if !ours {
+                return Err(MagError::Generic(format!(
+                    "{} already exists and was not generated by magpkg; remove it or export under a different name",
+                    path.display()
+                )));
+            }
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        if !self.files.iter().any(|file| file == path) {
+            self.files.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_files(&mut self) -> MagResult<()> {
+        for path in self.files.drain(..) {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, base_root: &Path) -> MagResult<()> {
+        let path = exports_path(base_root, &self.profile);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(writer, "binDir\t{}", self.bin_dir.display())?;
+            writeln!(writer, "dataDir\t{}", self.data_dir.display())?;
+            writeln!(
+                writer,
+                "desktop\t{}",
+                if self.desktop { "on" } else { "off" }
+            )?;
+            for command in &self.bins {
+                writeln!(writer, "bin\t{command}")?;
+            }
+            for file in &self.files {
+                writeln!(writer, "file\t{}", file.display())?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+fn exports_path(base_root: &Path, profile: &str) -> PathBuf {
+    profiles_dir(base_root).join(EXPORTS_DIR).join(profile)
+}
+
+/// Generated shims carry a marker comment and desktop entries a marker key;
+/// copied icons are only ever replaced when already recorded.
+fn is_generated(contents: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(contents);
+    text.lines()
+        .any(|line| line.starts_with(SHIM_MARKER) || line.starts_with(DESKTOP_MARKER_KEY))
+}
+
+/// Absolute path of `command` inside the venv.
+fn find_command(rootfs: &Path, command: &str) -> MagResult<String> {
+    if command.is_empty() || command.contains('/') || command.starts_with('.') {
+        return Err(MagError::Generic(format!(
+            "--bin takes a command name, got {command:?}"
+        )));
+    }
+    BIN_DIRS
+        .iter()
+        .find(|dir| {
+            fs::metadata(rootfs.join(dir).join(command))
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+        .map(|dir| format!("/{dir}/{command}"))
+        .ok_or_else(|| {
+            MagError::Generic(format!(
+                "{command} is not an executable in the venv's {}",
+                BIN_DIRS.join(", ")
+            ))
+        })
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Quote an argument for a desktop entry `Exec` key: reserved characters
+/// require double quotes, and backslashes are escaped once for the quoting
+/// rule and once more for the string value.
+fn desktop_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_' | '-' | '+' | ':'));
+    let arg = arg.replace('%', "%%");
+    if safe {
+        return arg;
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '`' | '$' => {
+                quoted.push_str("\\\\");
+                quoted.push(c);
+            }
+            '\\' => quoted.push_str("\\\\\\\\"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,99 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus},
+    ptr,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use crate::{MagError, MagResult};
+
+/// Signal that interrupted the current operation, or 0.
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+/// Process group of the running sandbox, or 0 when none is running.
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
+
+const HANDLED_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Catches SIGINT and SIGTERM while alive. The first signal is forwarded to
+/// the sandbox process group and recorded, so the build unwinds through its
+/// normal cleanup; a second one kills the sandbox and terminates immediately.
+/// Dropping the guard restores the default dispositions.
+pub struct InterruptGuard {
+    _private: (),
+}
+
+pub fn handle_interrupts() -> InterruptGuard {
+    INTERRUPTED.store(0, Ordering::SeqCst);
+    for signal in HANDLED_SIGNALS {
+        set_disposition(signal, on_signal as usize);
+    }
+    InterruptGuard { _private: () }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        for signal in HANDLED_SIGNALS {
+            set_disposition(signal, libc::SIG_DFL);
+        }
+    }
+}
+
+/// Fail with [`MagError::Interrupted`] once a signal has been received.
+pub fn check_interrupted() -> MagResult<()> {
+    match INTERRUPTED.load(Ordering::SeqCst) {
+        0 => Ok(()),
+        signal => Err(MagError::Interrupted(signal)),
+    }
+}
+
+/// Run `cmd` in its own process group so that signals reach it only through
+/// [`InterruptGuard`], which forwards them and then waits for it to exit.
+pub fn run_in_process_group(cmd: &mut Command) -> io::Result<ExitStatus> {
+    cmd.process_group(0);
+    let mut child = cmd.spawn()?;
+    let pgid = child.id() as i32;
+    CHILD_PGID.store(pgid, Ordering::SeqCst);
+
+    // A signal that arrived before the group was registered was not forwarded.
+    let pending = INTERRUPTED.load(Ordering::SeqCst);
+    if pending != 0 {
+        // SAFETY: kill has no memory-safety preconditions.
+        unsafe { libc::kill(-pgid, pending) };
+    }
+
+    let status = child.wait();
+    CHILD_PGID.store(0, Ordering::SeqCst);
+    status
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let pgid = CHILD_PGID.load(Ordering::SeqCst);
+    if INTERRUPTED.swap(signal, Ordering::SeqCst) != 0 {
+        // SAFETY: kill, signal, and raise are async-signal-safe.
+        unsafe {
+            if pgid > 0 {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+        return;
+    }
+    if pgid > 0 {
+        // SAFETY: kill is async-signal-safe.
+        unsafe { libc::kill(-pgid, signal) };
+    }
+}
+
+fn set_disposition(signal: libc::c_int, handler: libc::sighandler_t) {
+    // SAFETY: the sigaction struct is fully initialized before use and the
+    // handler only touches atomics and async-signal-safe functions.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, ptr::null_mut());
+    }
+}
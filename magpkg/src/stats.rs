@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use fs2::FileExt;
+
+use crate::MagResult;
+
+pub const STATS_FILE: &str = "stats.db";
+const STATS_LOCK_FILE: &str = "stats.lock";
+
+pub const FETCH_CACHE_HITS: &str = "fetch_cache_hits";
+pub const FETCH_CACHE_MISSES: &str = "fetch_cache_misses";
+pub const PACKAGE_CACHE_HITS: &str = "package_cache_hits";
+pub const PACKAGE_BUILDS: &str = "package_builds";
+pub const LOCK_CONTENTION: &str = "lock_contention";
+pub const ARTIFACT_UNPACKS: &str = "artifact_unpacks";
+
+/// Accumulates counters in memory and merges them into the store's stats
+/// database on flush (or drop), so hot paths never touch the file.
+pub struct StatsRecorder {
+    store_root: PathBuf,
+    pending: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl StatsRecorder {
+    pub fn new(store_root: impl Into<PathBuf>) -> Self {
+        Self {
+            store_root: store_root.into(),
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn incr(&self, counter: &'static str) {
+        if let Ok(mut pending) = self.pending.lock() {
+            *pending.entry(counter).or_insert(0) += 1;
+        }
+    }
+
+    pub fn flush(&self) -> MagResult<()> {
+        let pending = match self.pending.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => return Ok(()),
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let lock_file = File::create(self.store_root.join(STATS_LOCK_FILE))?;
+        lock_file.lock_exclusive()?;
+
+        let path = self.store_root.join(STATS_FILE);
+        let mut counters = read_counters(&self.store_root)?;
+        for (name, value) in pending {
+            *counters.entry(name.to_string()).or_insert(0) += value;
+        }
+
+        let tmp_path = path.with_extension("db.tmp");
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            for (name, value) in &counters {
+                writeln!(file, "{name} {value}")?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+impl Drop for StatsRecorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+pub fn read_counters(store_root: &Path) -> MagResult<BTreeMap<String, u64>> {
+    let contents = match fs::read_to_string(store_root.join(STATS_FILE)) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut counters = BTreeMap::new();
+    for line in contents.lines() {
+        if let Some((name, value)) = line.split_once(' ') {
+            if let Ok(value) = value.trim().parse::<u64>() {
+                counters.insert(name.to_string(), value);
+            }
+        }
+    }
+    Ok(counters)
+}
+
+/// Size and age summary for one area of the store.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct AreaStats {
+    pub entries: u64,
+    pub files: u64,
+    pub bytes: u64,
+    pub oldest: Option<SystemTime>,
+    pub newest: Option<SystemTime>,
+}
+
+impl AreaStats {
+    pub fn observe_entry(&mut self, modified: Option<SystemTime>) {
+        self.entries += 1;
+        if let Some(modified) = modified {
+            self.oldest = Some(self.oldest.map_or(modified, |old| old.min(modified)));
+            self.newest = Some(self.newest.map_or(modified, |new| new.max(modified)));
+        }
+    }
+}
+
+/// Add the files and bytes beneath `path` (not following symlinks) to `stats`.
+pub fn accumulate_usage(path: &Path, stats: &mut AreaStats) -> MagResult<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            accumulate_usage(&entry?.path(), stats)?;
+        }
+    } else {
+        stats.files += 1;
+        stats.bytes += metadata.len();
+    }
+    Ok(())
+}
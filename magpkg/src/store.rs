@@ -1,49 +1,175 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     env,
     fs::{self, File, OpenOptions},
-    io::{self, ErrorKind, Read, Write},
-    os::unix::fs::PermissionsExt,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{MetadataExt, PermissionsExt},
+    },
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
     rc::Rc,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant, SystemTime},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc as std_mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use filetime::{FileTime, set_file_times};
-use flate2::read::GzDecoder;
 use fs2::FileExt;
 use reqwest::{Url, blocking::Client};
+use ring::signature::Ed25519KeyPair;
 use sha2::{Digest, Sha256};
 use tar::{Builder, EntryType};
 use tempfile::Builder as TempDirBuilder;
 use tokio::runtime::Builder as TokioRuntimeBuilder;
-use zstd::stream::{read::Decoder as ZstdDecoder, write::Encoder as ZstdEncoder};
 
 use crate::{
     MagError, MagResult,
+    activity::{Activity, ActivityInfo, find_holder, list_activities},
+    archive,
+    btconfig::{
+        TorrentConfig, inject_trackers, inject_webseeds, strip_volatile_keys, torrent_webseeds,
+    },
     btfetcher::{
-        TORRENT_FETCHER_LOCK, TORRENT_SESSION_PREFIX, TORRENT_WORK_MARKER, TorrentDownloadRequest,
-        TorrentFetcher,
+        TORRENT_FETCHER_LOCK, TORRENT_SESSION_PREFIX, TORRENT_SESSION_STATE_DIR,
+        TORRENT_WORK_MARKER, TorrentDownloadRequest, TorrentFetcher,
     },
     btseed::{self, TorrentSeedInfo, load_torrent_seed_info, seed_lock_path},
+    buildtimes::{BuildHistory, BuildProgress},
+    bundle::{BundleMetadata, BundleSigner, local_hostname, read_bundle, write_bundle},
+    capabilities::{probe_capabilities, report_degraded_once, require_sandbox},
+    codec::{ArtifactCodec, ArtifactWriter, CompressionSettings, decode_artifact},
+    fetchauth::FetchAuth,
+    fileindex::{FILE_INDEX_SUFFIX, FileIndex, IndexedPath, PathKind},
+    hostlimits::{HostLimiter, retry_after},
+    httpcache::{configured_substituters, signature_check_skipped, verify_publish_record},
+    index::{
+        IndexedArtifact, TorrentIndex, load_configured_indexes, magnet_for, read_index_bytes,
+        trusted_keys,
+    },
+    journal::{self, Journal, JournalEntry, RecoveryStats},
+    leases::{LeaseGuard, LeaseManager, lease_host, unix_now},
+    linkfrom::LinkSources,
+    lint::lint_output,
+    metadb::{ArtifactRow, MetaDb},
+    mirrors::MirrorMap,
+    offline::{is_local_url, is_offline},
+    pack::{FakerootState, append_tree},
     package::{
-        FetchResource, Package, collect_closure, collect_runtime_closure, package_base_name,
+        BudgetPolicy, BuilderShell, DEFAULT_BUILDER_SHELL, FetchResource, OutputOf, Package,
+        PackageMetadata, collect_closure, collect_runtime_closure, direct_dependencies,
+        output_hash, package_base_name, resolve_build_env, resolve_build_script,
     },
+    priority::{BuildPriority, Scheduling},
+    probe::{DHT_PROBE_WAIT, HTTP_PROBE_TIMEOUT, PlannedFetch, SourceStatus},
+    provenance::{EXPORT_PROVENANCE_DIR, PROVENANCE_SUFFIX, provenance_record},
+    proxy::HttpClient,
+    sandbox::{DeterministicRandom, faketime_spec, find_libfaketime},
+    sbom::parse_utc_timestamp,
+    seekable::{SeekTable, TarConcatenator, copy_artifact_range},
+    signals::{check_interrupted, handle_interrupts, run_in_process_group},
+    stats::{self, AreaStats, StatsRecorder, accumulate_usage, read_counters},
 };
 
 use librqbit::dht::Id20;
 use librqbit::{CreateTorrentOptions, Magnet, create_torrent};
 
 const FETCH_LOCK_SUFFIX: &str = ".lock";
+/// Sandbox directory holding one subdirectory per secondary output.
+const OUTPUTS_DIR: &str = "outputs";
+const BUILD_QUEUE_LOCK: &str = "build-queue.lock";
+const TORRENT_SOURCES_FILE: &str = "sources";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const LOCK_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const PUBLISHED_SUFFIX: &str = ".published";
+const FAILED_SUFFIX: &str = ".failed";
+const METADATA_SUFFIX: &str = ".meta";
+const DEPS_SUFFIX: &str = ".deps";
+const UNPACKED_DIR: &str = "unpacked";
+const VENV_HOMES_DIR: &str = "venv-homes";
+/// Lists the package layers of a venv mounted as an overlay, lowest first.
+pub const VENV_LAYERS_FILE: &str = "layers";
+/// mount(2) passes overlayfs options in a single page, and bubblewrap
+/// prefixes every lower directory with `/oldroot`.
+const OVERLAY_OPTIONS_MAX: usize = 4000;
+const OVERLAY_SOURCE_PREFIX: &str = "/oldroot";
+/// Marks the per-source temp file of a fetch raced across several sources.
+const RACE_SOURCE_MARKER: &str = ".src";
+pub const FETCH_RACE_ENV: &str = "MAGPKG_FETCH_RACE";
+const DEFAULT_FETCH_RACE: usize = 3;
+/// Fetches cached, downloaded, or verified at once.
+const FETCH_WIDTH: usize = 4;
+/// Block size for hashing files; the next block is read while one is hashed.
+const HASH_BLOCK_SIZE: usize = 4 << 20;
 pub struct PackageStore {
-    client: Client,
+    client: HttpClient,
+    /// Client for source fetches, which follow redirects through
+    /// [`FetchAuth::send`] instead.
+    fetch_client: HttpClient,
+    host_limits: HostLimiter,
+    mirrors: MirrorMap,
+    fetch_auth: FetchAuth,
+    base_root: PathBuf,
     store_root: PathBuf,
     fetch_root: PathBuf,
     torrent_root: PathBuf,
     venv_root: PathBuf,
+    unpacked_root: PathBuf,
     torrent_fetcher: Mutex<Option<Arc<TorrentFetcher>>>,
+    indexes: OnceLock<Vec<TorrentIndex>>,
+    stats: StatsRecorder,
+    keep_failed: bool,
+    keep_going: bool,
+    skip_checks: bool,
+    lint_only: bool,
+    link_sources: LinkSources,
+    compression: CompressionSettings,
+    priority: BuildPriority,
+    build_timings: Mutex<Vec<PackageTiming>>,
+    torrent_config: TorrentConfig,
+    activity: OnceLock<Option<Activity>>,
+    journal: OnceLock<Option<Journal>>,
+    metadb: OnceLock<Option<MetaDb>>,
+    expression: Option<String>,
+    leases: Option<LeaseManager>,
+}
+
+/// Releases a lock label from the activity record, and its lease on shared
+/// stores, when dropped. The lock itself is released by dropping the locked
+/// file.
+struct LockHold<'a> {
+    store: &'a PackageStore,
+    label: String,
+    waited: bool,
+    _lease: Option<LeaseGuard<'a>>,
+}
+
+impl Drop for LockHold<'_> {
+    fn drop(&mut self) {
+        if let Some(activity) = self.store.activity() {
+            activity.release(&self.label);
+        }
+    }
+}
+
+/// Place at the head of the build queue; see [`PackageStore::enter_build_queue`].
+pub struct BuildQueueGuard<'a> {
+    _hold: LockHold<'a>,
+    _file: File,
+}
+
+#[derive(Debug)]
+pub struct StoreStats {
+    pub packages: AreaStats,
+    pub fetched: AreaStats,
+    pub torrents: AreaStats,
+    pub venvs: AreaStats,
+    pub counters: BTreeMap<String, u64>,
 }
 
 #[derive(Default, Debug)]
@@ -58,6 +184,48 @@ pub struct CleanupStats {
     pub torrent_work_dirs_removed: usize,
     pub torrent_session_dirs_removed: usize,
     pub venv_rootfs_removed: usize,
+    pub unpacked_dirs_removed: usize,
+}
+
+#[derive(Default, Debug)]
+pub struct RepairStats {
+    pub journal: RecoveryStats,
+    pub fetch_partials_removed: usize,
+    pub package_partials_removed: usize,
+    pub package_build_dirs_removed: usize,
+    pub package_sidecars_removed: usize,
+    pub venv_staging_dirs_removed: usize,
+    pub torrent_partials_removed: usize,
+    pub torrent_dirs_removed: usize,
+    /// Set when other processes were using the store, so torrent
+    /// directories, which have no lock of their own, were left alone.
+    pub torrents_skipped: bool,
+}
+
+/// Which entries a disk usage report counts as reclaimable: those an
+/// age-based `cleanup` would remove, or those `cleanup --min-free` would
+/// remove to reach `target_free`.
+#[derive(Clone, Copy)]
+pub enum UsagePolicy {
+    MaxAge(Duration),
+    MinFree { min_free: u64, target_free: u64 },
+}
+
+/// One store entry measured by [`PackageStore::disk_usage`].
+#[derive(Debug)]
+pub struct UsageEntry {
+    pub category: &'static str,
+    /// Package base name, extracted package hash, fetch sha256, venv hash,
+    /// or torrent info hash.
+    pub name: String,
+    pub bytes: u64,
+    pub reclaimable: bool,
+}
+
+#[derive(Debug)]
+pub struct DiskUsage {
+    pub available: u64,
+    pub entries: Vec<UsageEntry>,
 }
 
 #[derive(Default, Clone, Copy)]
@@ -68,6 +236,81 @@ pub struct CleanupOptions {
     pub venvs: bool,
 }
 
+/// How a package's artifact was obtained during `build_packages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildSource {
+    Cached,
+    Substituted,
+    Built,
+}
+
+impl BuildSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuildSource::Cached => "cached",
+            BuildSource::Substituted => "substituted",
+            BuildSource::Built => "built",
+        }
+    }
+}
+
+/// Where the time went for one package of a `build_packages` run. `total`
+/// includes waiting for another process that held the package lock.
+#[derive(Debug, Clone)]
+pub struct PackageTiming {
+    pub base: String,
+    pub source: BuildSource,
+    pub total: Duration,
+    /// Fetching sources, or downloading a substitute.
+    pub fetch: Duration,
+    /// Installing dependencies into the sandbox, or unpacking `untar` sources.
+    pub extract: Duration,
+    /// Running the build script.
+    pub script: Duration,
+    /// Linting and compressing the output.
+    pub pack: Duration,
+    pub artifact_bytes: u64,
+}
+
+/// Result of [`PackageStore::reclaim_space`]. `available_*` are the bytes
+/// available to unprivileged users on the store filesystem.
+#[derive(Default, Debug)]
+pub struct ReclaimOutcome {
+    pub available_before: u64,
+    pub available_after: u64,
+    pub stats: CleanupStats,
+}
+
+enum ReclaimItem {
+    Package(String),
+    Unpacked(String),
+    Fetch(String),
+    Venv,
+    Torrent,
+}
+
+#[derive(Default, Debug)]
+pub struct TorrentRefreshStats {
+    pub regenerated: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub nondeterministic: usize,
+}
+
+/// Shell that runs a build script: a path inside the sandbox, plus the host
+/// directory of a builderShell package mounted at [`SHELL_MOUNT`].
+struct SandboxShell {
+    path: String,
+    bind: Option<PathBuf>,
+}
+
+const SHELL_MOUNT: &str = "/.magpkg-shell";
+/// Where the build script is placed inside the sandbox.
+const BUILD_SCRIPT_PATH: &str = "/tmp/.magpkg-build-script";
+/// Script that runs `preBuild`, the build script, `check`, and `postBuild`
+/// in order, for packages with build hooks.
+const BUILD_PHASES_PATH: &str = "/tmp/.magpkg-build-phases";
+
 struct TorrentInfo {
     info_hash: String,
     relative_path: PathBuf,
@@ -81,39 +324,130 @@ struct DownloadOutcome {
 
 impl PackageStore {
     pub fn new() -> MagResult<Self> {
-        let base_root = if let Some(custom) = env::var_os("MAGPKG_STORE") {
-            PathBuf::from(custom)
-        } else {
-            let home = env::var_os("HOME")
-                .ok_or_else(|| MagError::Generic("HOME environment variable is not set".into()))?;
-            PathBuf::from(home).join(".magpkg")
-        };
+        let base_root = store_base_root()?;
         let fetch_root = base_root.join("fetch");
         let store_root = base_root.join("pkgs");
         let torrent_root = base_root.join("torrent");
         let venv_root = base_root.join("venv");
+        let unpacked_root = base_root.join(UNPACKED_DIR);
         fs::create_dir_all(&fetch_root)?;
         fs::create_dir_all(&store_root)?;
         fs::create_dir_all(&torrent_root)?;
         fs::create_dir_all(&venv_root)?;
+        fs::create_dir_all(&unpacked_root)?;
 
         let user_agent = format!("magpkg/{}", env!("CARGO_PKG_VERSION"));
 
-        let client = Client::builder()
-            .timeout(Duration::from_secs(12 * 60 * 60))
-            .user_agent(&user_agent)
-            .build()?;
+        let client = HttpClient::from_env(|| {
+            Client::builder()
+                .timeout(Duration::from_secs(12 * 60 * 60))
+                .user_agent(&user_agent)
+        })?;
+        let fetch_client = HttpClient::from_env(|| {
+            Client::builder()
+                .timeout(Duration::from_secs(12 * 60 * 60))
+                .user_agent(&user_agent)
+                .redirect(reqwest::redirect::Policy::none())
+        })?;
+
+        let torrent_config = TorrentConfig::load(&base_root)?;
+        report_degraded_once(&base_root, &probe_capabilities(&torrent_config));
+
+        // Roll back what crashed processes left half done before anything
+        // can mistake it for finished work.
+        if let Err(err) = journal::recover(&base_root) {
+            eprintln!("warning: store journal recovery failed: {err}");
+        }
 
         Ok(Self {
             client,
+            fetch_client,
+            host_limits: HostLimiter::load(&base_root)?,
+            mirrors: MirrorMap::load(&base_root)?,
+            fetch_auth: FetchAuth::load(&base_root)?,
+            torrent_config,
+            stats: StatsRecorder::new(base_root.clone()),
+            base_root,
             store_root,
             fetch_root,
             torrent_root,
             venv_root,
+            unpacked_root,
             torrent_fetcher: Mutex::new(None),
+            indexes: OnceLock::new(),
+            keep_failed: false,
+            keep_going: false,
+            skip_checks: false,
+            lint_only: false,
+            link_sources: LinkSources::default(),
+            compression: CompressionSettings::default(),
+            priority: BuildPriority::load(&base_root)?,
+            build_timings: Mutex::new(Vec::new()),
+            activity: OnceLock::new(),
+            journal: OnceLock::new(),
+            metadb: OnceLock::new(),
+            expression: None,
+            leases: LeaseManager::load(&base_root)?,
         })
     }
 
+    /// Preserve the sandbox of a failed build instead of leaving it to be
+    /// wiped by the next attempt.
+    pub fn set_keep_failed(&mut self, keep_failed: bool) {
+        self.keep_failed = keep_failed;
+    }
+
+    /// Keep building the packages whose dependencies succeeded after a build
+    /// fails, and report every failure at the end.
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+
+    /// Leave out the `check` phase of packages built by this store.
+    pub fn set_skip_checks(&mut self, skip_checks: bool) {
+        self.skip_checks = skip_checks;
+    }
+
+    /// Compression used for artifacts built by this store, unless a package
+    /// overrides it.
+    pub fn set_compression(&mut self, compression: CompressionSettings) {
+        self.compression = compression;
+    }
+
+    /// Compression for `package`'s artifacts.
+    fn compression_for(&self, package: &Package) -> CompressionSettings {
+        CompressionSettings {
+            codec: package.compression.codec.unwrap_or(self.compression.codec),
+            level: package.compression.level.unwrap_or(self.compression.level),
+            threads: package
+                .compression
+                .threads
+                .unwrap_or(self.compression.threads),
+        }
+    }
+
+    /// Scheduling for build sandboxes that overrides `build-priority.conf`.
+    pub fn set_build_scheduling(&mut self, scheduling: Scheduling) {
+        self.priority.set_overrides(scheduling);
+    }
+
+    /// Look for missing fetches among these files before downloading them.
+    pub fn set_link_sources(&mut self, sources: LinkSources) {
+        self.link_sources = sources;
+    }
+
+    /// Report missing runtime dependencies as warnings even for packages
+    /// that set `checkRuntimeDeps`.
+    pub fn set_lint_only(&mut self, lint_only: bool) {
+        self.lint_only = lint_only;
+    }
+
+    /// Record `expression` as the provenance of the artifacts built from
+    /// now on.
+    pub fn set_expression(&mut self, expression: Option<String>) {
+        self.expression = expression;
+    }
+
     pub fn build_packages(
         &self,
         roots: &[Rc<Package>],
@@ -126,19 +460,77 @@ impl PackageStore {
             collect_closure(pkg.clone(), &mut visited, &mut order);
         }
 
+        if is_offline() {
+            self.check_offline_fetches(
+                order
+                    .iter()
+                    .filter(|package| !self.is_artifact_published(package.as_ref())),
+            )?;
+        }
+
+        let mut history = BuildHistory::load(&self.base_root);
+        let to_build = order
+            .iter()
+            .filter(|package| !self.is_artifact_published(package.as_ref()))
+            .map(|package| (package_base_name(package.as_ref()), history.get(package)))
+            .collect();
+        let mut progress = BuildProgress::new(order.len(), to_build);
+
+        let _interrupts = handle_interrupts();
         let mut artifacts = Vec::with_capacity(order.len());
+        let mut failures = Vec::new();
+        let mut skipped = Vec::new();
+        // Packages that failed or were skipped, by hash, with the failed
+        // package responsible.
+        let mut broken: HashMap<String, String> = HashMap::new();
         for package in order {
-            let path = self.build_single(&package, parallelism)?;
-            artifacts.push(path);
+            check_interrupted()?;
+            let base = package_base_name(package.as_ref());
+            let failed_dep = direct_dependencies(&package)
+                .find_map(|dep| broken.get(&dep.hash))
+                .cloned();
+            if let Some(cause) = failed_dep {
+                eprintln!("skipping {base}: its dependency {cause} failed");
+                broken.insert(package.hash.clone(), cause.clone());
+                skipped.push((base.clone(), cause));
+                progress.finish(&base);
+                continue;
+            }
+            if progress.is_pending(&base) {
+                eprintln!("[{}]", progress.summary());
+            }
+            match self.build_single(&package, parallelism, &mut history) {
+                Ok(path) => artifacts.push(path),
+                Err(err) if self.keep_going && !matches!(err, MagError::Interrupted(_)) => {
+                    eprintln!("error: {base} failed: {err}");
+                    broken.insert(package.hash.clone(), base.clone());
+                    failures.push((base.clone(), err));
+                }
+                Err(err) => return Err(err),
+            }
+            progress.finish(&base);
         }
         self.shutdown_torrent_fetcher()?;
+        if !failures.is_empty() {
+            return Err(MagError::BuildsFailed { failures, skipped });
+        }
         Ok(artifacts)
     }
 
+    /// Per-package timings recorded by `build_packages` since the last call,
+    /// in build order.
+    pub fn take_build_timings(&self) -> Vec<PackageTiming> {
+        self.build_timings
+            .lock()
+            .map(|mut timings| std::mem::take(&mut *timings))
+            .unwrap_or_default()
+    }
+
     pub fn cleanup(&self, expiry: Duration, options: CleanupOptions) -> MagResult<CleanupStats> {
         let now = SystemTime::now();
         let mut stats = CleanupStats::default();
         self.cleanup_packages(now, expiry, &mut stats, options.packages)?;
+        self.cleanup_unpacked(now, expiry, &mut stats)?;
         self.cleanup_fetches(now, expiry, &mut stats, options.fetched)?;
         if options.venvs {
             self.cleanup_venvs(now, expiry, &mut stats)?;
@@ -157,9 +549,297 @@ impl PackageStore {
         Ok(stats)
     }
 
+    /// Free space on the store filesystem when it has dropped below
+    /// `min_free`: remove the least recently used entries of the enabled
+    /// categories (extracted artifacts always qualify) until `target_free`
+    /// bytes are available. Entries that another process holds a lock on and
+    /// venvs in `pinned_venvs` are left alone. Below the threshold nothing is
+    /// touched.
+    pub fn reclaim_space(
+        &self,
+        min_free: u64,
+        target_free: u64,
+        options: CleanupOptions,
+        pinned_venvs: &HashSet<String>,
+    ) -> MagResult<ReclaimOutcome> {
+        let available_before = fs2::available_space(&self.base_root)?;
+        let mut outcome = ReclaimOutcome {
+            available_before,
+            available_after: available_before,
+            stats: CleanupStats::default(),
+        };
+        if available_before >= min_free {
+            return Ok(outcome);
+        }
+
+        let seed_lock = if options.torrents {
+            let lock = btseed::try_acquire_seed_lock(&seed_lock_path(self.torrent_root()))?;
+            if lock.is_none() {
+                eprintln!("Skipping torrents; seeder appears to be running.");
+            }
+            lock
+        } else {
+            None
+        };
+
+        let mut candidates = self.reclaim_candidates(options, seed_lock.is_some(), pinned_venvs)?;
+        candidates.sort_by_key(|(modified, _, _)| *modified);
+
+        for (_, path, item) in candidates {
+            if outcome.available_after >= target_free {
+                break;
+            }
+            check_interrupted()?;
+            if self.reclaim_item(&path, &item)? {
+                let stats = &mut outcome.stats;
+                match item {
+                    ReclaimItem::Package(_) => stats.package_artifacts_removed += 1,
+                    ReclaimItem::Unpacked(_) => stats.unpacked_dirs_removed += 1,
+                    ReclaimItem::Fetch(_) => stats.fetch_files_removed += 1,
+                    ReclaimItem::Venv => stats.venv_rootfs_removed += 1,
+                    ReclaimItem::Torrent => stats.torrent_dirs_removed += 1,
+                }
+                outcome.available_after = fs2::available_space(&self.base_root)?;
+            }
+        }
+        drop(seed_lock);
+        Ok(outcome)
+    }
+
+    fn reclaim_candidates(
+        &self,
+        options: CleanupOptions,
+        torrents: bool,
+        pinned_venvs: &HashSet<String>,
+    ) -> MagResult<Vec<(SystemTime, PathBuf, ReclaimItem)>> {
+        fn modified(path: &Path) -> SystemTime {
+            fs::symlink_metadata(path)
+                .and_then(|meta| meta.modified())
+                .unwrap_or(UNIX_EPOCH)
+        }
+
+        let mut candidates = Vec::new();
+        for entry in fs::read_dir(&self.unpacked_root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && !name.starts_with('.') {
+                let path = entry.path();
+                candidates.push((modified(&path), path, ReclaimItem::Unpacked(name)));
+            }
+        }
+
+        if options.packages {
+            for row in self.indexed_artifacts()? {
+                let path = self.store_root.join(format!("{}.tar.zst", row.base));
+                candidates.push((modified(&path), path, ReclaimItem::Package(row.base)));
+            }
+        }
+
+        if options.fetched {
+            for entry in fs::read_dir(&self.fetch_root)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !entry.file_type()?.is_file()
+                    || name.ends_with(FETCH_LOCK_SUFFIX)
+                    || name.ends_with(".tmp")
+                    || name == TORRENT_FETCHER_LOCK
+                {
+                    continue;
+                }
+                let path = entry.path();
+                candidates.push((modified(&path), path, ReclaimItem::Fetch(name)));
+            }
+        }
+
+        for (enabled, root, venvs) in [
+            (options.venvs, &self.venv_root, true),
+            (torrents, &self.torrent_root, false),
+        ] {
+            if !enabled {
+                continue;
+            }
+            for entry in fs::read_dir(root)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !entry.file_type()?.is_dir()
+                    || name.starts_with('.')
+                    || (venvs && pinned_venvs.contains(&name))
+                {
+                    continue;
+                }
+                let path = entry.path();
+                let item = if venvs {
+                    ReclaimItem::Venv
+                } else {
+                    ReclaimItem::Torrent
+                };
+                candidates.push((modified(&path), path, item));
+            }
+        }
+
+        Ok(candidates)
+    }
+
+    /// Measure every entry cleanup can remove and mark the ones `policy`
+    /// would reclaim with `options`, without removing anything. Entries
+    /// that are locked when cleanup runs are skipped then, so the report is
+    /// an upper bound.
+    pub fn disk_usage(
+        &self,
+        policy: UsagePolicy,
+        options: CleanupOptions,
+        pinned_venvs: &HashSet<String>,
+    ) -> MagResult<DiskUsage> {
+        let all = CleanupOptions {
+            packages: true,
+            fetched: true,
+            torrents: true,
+            venvs: true,
+        };
+        let mut candidates = self.reclaim_candidates(all, true, &HashSet::new())?;
+        candidates.sort_by_key(|(modified, _, _)| *modified);
+
+        let available = fs2::available_space(&self.base_root)?;
+        let now = SystemTime::now();
+        let mut freed = 0u64;
+        let mut entries = Vec::with_capacity(candidates.len());
+        for (_, path, item) in candidates {
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let (category, enabled, name) = match item {
+                ReclaimItem::Package(base) => ("packages", options.packages, base),
+                ReclaimItem::Unpacked(hash) => ("unpacked", true, hash),
+                ReclaimItem::Fetch(sha) => ("fetched", options.fetched, sha),
+                ReclaimItem::Venv => ("venvs", options.venvs, file_name),
+                ReclaimItem::Torrent => ("torrents", options.torrents, file_name),
+            };
+            let mut usage = AreaStats::default();
+            accumulate_usage(&path, &mut usage)?;
+            let reclaimable = enabled
+                && match policy {
+                    UsagePolicy::MaxAge(expiry) => is_path_expired(&path, now, expiry)?,
+                    UsagePolicy::MinFree {
+                        min_free,
+                        target_free,
+                    } => {
+                        available < min_free
+                            && !(category == "venvs" && pinned_venvs.contains(&name))
+                            && available.saturating_add(freed) < target_free
+                    }
+                };
+            if reclaimable {
+                freed += usage.bytes;
+            }
+            entries.push(UsageEntry {
+                category,
+                name,
+                bytes: usage.bytes,
+                reclaimable,
+            });
+        }
+        Ok(DiskUsage { available, entries })
+    }
+
+    /// Remove one reclaim candidate under the same lock its users take.
+    /// Returns false when the entry is in use or already gone.
+    fn reclaim_item(&self, path: &Path, item: &ReclaimItem) -> MagResult<bool> {
+        let lock_path = match item {
+            ReclaimItem::Package(base) => Some(self.store_root.join(format!("{base}.lock"))),
+            ReclaimItem::Unpacked(hash) => Some(
+                self.unpacked_root
+                    .join(format!("{hash}{FETCH_LOCK_SUFFIX}")),
+            ),
+            ReclaimItem::Fetch(sha) => {
+                Some(self.fetch_root.join(format!("{sha}{FETCH_LOCK_SUFFIX}")))
+            }
+            ReclaimItem::Venv => Some(path.join("rootfs").join(".lock")).filter(|p| p.exists()),
+            ReclaimItem::Torrent => None,
+        };
+        let label = match item {
+            ReclaimItem::Package(base) => Some(format!("package {base}")),
+            ReclaimItem::Unpacked(hash) => Some(format!("unpack {hash}")),
+            ReclaimItem::Fetch(sha) => Some(format!("fetch {sha}")),
+            ReclaimItem::Venv => path
+                .file_name()
+                .map(|hash| venv_lease_label(&hash.to_string_lossy())),
+            ReclaimItem::Torrent => None,
+        };
+        let _lease = match (&self.leases, label) {
+            (Some(leases), Some(label)) => match leases.try_acquire(&label)? {
+                Some(lease) => Some(lease),
+                None => return Ok(false),
+            },
+            _ => None,
+        };
+        let _lock = match lock_path {
+            Some(lock_path) => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(&lock_path)?;
+                match file.try_lock_exclusive() {
+                    Ok(()) => Some(file),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            None => None,
+        };
+
+        let result = match item {
+            ReclaimItem::Package(base) => fs::remove_file(path).and_then(|()| {
+                let index_path = self.store_root.join(format!("{base}{FILE_INDEX_SUFFIX}"));
+                let metadata_path = self.store_root.join(format!("{base}{METADATA_SUFFIX}"));
+                let deps_path = self.store_root.join(format!("{base}{DEPS_SUFFIX}"));
+                let provenance_path = self.store_root.join(format!("{base}{PROVENANCE_SUFFIX}"));
+                for derived in [
+                    published_marker_path(path),
+                    index_path,
+                    metadata_path,
+                    deps_path,
+                    provenance_path,
+                ] {
+                    match fs::remove_file(derived) {
+                        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }),
+            ReclaimItem::Unpacked(_) => remove_read_only_tree(path),
+            ReclaimItem::Fetch(_) => fs::remove_file(path),
+            ReclaimItem::Venv | ReclaimItem::Torrent => fs::remove_dir_all(path),
+        };
+        if let (Ok(()), ReclaimItem::Package(base)) = (&result, item) {
+            self.unindex(base);
+        }
+        match result {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub fn fetch_packages(&self, roots: &[Rc<Package>], missing_only: bool) -> MagResult<()> {
+        if is_offline() {
+            let mut visited = HashSet::new();
+            let mut closure = Vec::new();
+            for pkg in roots {
+                collect_closure(pkg.clone(), &mut visited, &mut closure);
+            }
+            self.check_offline_fetches(
+                closure
+                    .iter()
+                    .filter(|pkg| !missing_only || !self.is_artifact_published(pkg.as_ref())),
+            )?;
+        }
+
         let mut queue = VecDeque::new();
         let mut visited = HashSet::new();
+        let mut fetches: Vec<FetchResource> = Vec::new();
 
         for pkg in roots {
             queue.push_back(pkg.clone());
@@ -173,133 +853,2192 @@ impl PackageStore {
             for dep in pkg.run_deps.iter().chain(pkg.build_deps.iter()) {
                 queue.push_back(dep.clone());
             }
+            if let Some(BuilderShell::Package(shell)) = &pkg.builder_shell {
+                queue.push_back(shell.clone());
+            }
 
             if missing_only {
-                let artifact = self.package_artifact_path(pkg.as_ref());
-                if artifact.exists() {
+                if self.is_artifact_published(pkg.as_ref()) {
+                    continue;
+                }
+            }
+
+            for fetch in &pkg.fetch {
+                if !fetches.iter().any(|seen| seen.sha256 == fetch.sha256) {
+                    fetches.push(fetch.clone());
+                }
+            }
+        }
+        if !fetches.is_empty() {
+            eprintln!("fetching {} source(s)...", fetches.len());
+        }
+        self.cache_fetches(&fetches)?;
+
+        self.shutdown_torrent_fetcher()?;
+        Ok(())
+    }
+
+    /// The fetches `fetch_packages` would make for `roots`, with the
+    /// sources each would try, without contacting any of them. Local `src`
+    /// entries have no sources and are left out.
+    pub fn planned_fetches(&self, roots: &[Rc<Package>], missing_only: bool) -> Vec<PlannedFetch> {
+        let mut visited = HashSet::new();
+        let mut closure = Vec::new();
+        for pkg in roots {
+            collect_closure(pkg.clone(), &mut visited, &mut closure);
+        }
+        let mut seen = HashSet::new();
+        let mut planned = Vec::new();
+        for pkg in closure {
+            if missing_only && self.is_artifact_published(pkg.as_ref()) {
+                continue;
+            }
+            for fetch in &pkg.fetch {
+                if fetch.source.is_some()
+                    || fetch.inline.is_some()
+                    || !seen.insert(fetch.sha256.clone())
+                {
                     continue;
                 }
+                planned.push(PlannedFetch {
+                    filename: fetch.filename.clone(),
+                    sha256: fetch.sha256.clone(),
+                    cached: self.fetch_root.join(&fetch.sha256).is_file(),
+                    sources: self.fetch_sources(fetch),
+                });
+            }
+        }
+        planned
+    }
+
+    /// Check whether `url` can deliver a file without downloading it: a
+    /// HEAD request (or a one-byte range when HEAD is refused) for HTTP, a
+    /// DHT peer lookup for magnet links, and an existence check for
+    /// `file://` URLs.
+    pub fn probe_source(&self, url: &str) -> SourceStatus {
+        let probe = || -> MagResult<SourceStatus> {
+            if url.trim_start().starts_with("magnet:") {
+                let magnet = Magnet::parse(url.trim())
+                    .map_err(|err| MagError::Generic(format!("invalid magnet link: {err:#}")))?;
+                let Some(info_hash) = magnet.as_id20() else {
+                    return Ok(SourceStatus::Dead(
+                        "no v1 info hash to look up on the DHT".into(),
+                    ));
+                };
+                let peers = self
+                    .torrent_fetcher()?
+                    .count_peers(info_hash, DHT_PROBE_WAIT)?;
+                return Ok(SourceStatus::Peers(peers));
+            }
+            let parsed =
+                Url::parse(url).map_err(|err| MagError::Generic(format!("invalid URL: {err}")))?;
+            match parsed.scheme() {
+                "file" => {
+                    let path = file_url_to_path(&parsed)?;
+                    Ok(match fs::metadata(&path) {
+                        Ok(meta) if meta.is_file() => SourceStatus::Alive {
+                            size: Some(meta.len()),
+                        },
+                        _ => SourceStatus::Dead(format!("{} does not exist", path.display())),
+                    })
+                }
+                "http" | "https" => {
+                    let _permit = self.host_limits.acquire(&parsed, &AtomicBool::new(false))?;
+                    let mut response = self.fetch_auth.send(&parsed, |url| {
+                        self.fetch_client.head(url).timeout(HTTP_PROBE_TIMEOUT)
+                    })?;
+                    if matches!(
+                        response.status(),
+                        reqwest::StatusCode::METHOD_NOT_ALLOWED
+                            | reqwest::StatusCode::NOT_IMPLEMENTED
+                    ) {
+                        response = self.fetch_auth.send(&parsed, |url| {
+                            self.fetch_client
+                                .get(url)
+                                .header(reqwest::header::RANGE, "bytes=0-0")
+                                .timeout(HTTP_PROBE_TIMEOUT)
+                        })?;
+                    }
+                    let status = response.status();
+                    if !status.is_success() {
+                        return Ok(SourceStatus::Dead(format!("HTTP {status}")));
+                    }
+                    let headers = response.headers();
+                    let header = |name: reqwest::header::HeaderName| {
+                        headers.get(name).and_then(|value| value.to_str().ok())
+                    };
+                    // A range response reports the full size after the slash.
+                    let size = match header(reqwest::header::CONTENT_RANGE) {
+                        Some(range) => range
+                            .rsplit('/')
+                            .next()
+                            .and_then(|total| total.parse().ok()),
+                        None => header(reqwest::header::CONTENT_LENGTH)
+                            .and_then(|length| length.parse().ok()),
+                    };
+                    Ok(SourceStatus::Alive { size })
+                }
+                scheme => Ok(SourceStatus::Dead(format!("unsupported scheme {scheme}"))),
+            }
+        };
+        probe().unwrap_or_else(|err| SourceStatus::Dead(err.to_string()))
+    }
+
+    /// Regenerate the torrent metadata of every cached fetch so it matches
+    /// the current torrent policy (piece length, trackers, webseeds). Payloads
+    /// are rehashed from the fetch cache, never downloaded again.
+    ///
+    /// A cached file is identified by the fetches reachable from `roots` or,
+    /// failing that, by the `sources` record of a torrent created for it
+    /// earlier. Files matching neither are skipped.
+    ///
+    /// With `check`, nothing is written: each torrent is generated twice,
+    /// and pairs that differ count as nondeterministic, while torrents that
+    /// differ from the stored copy count as regenerated.
+    pub fn refresh_torrents(
+        &self,
+        roots: &[Rc<Package>],
+        check: bool,
+    ) -> MagResult<TorrentRefreshStats> {
+        let mut known = self.recorded_fetches()?;
+        let mut visited = HashSet::new();
+        let mut closure = Vec::new();
+        for pkg in roots {
+            collect_closure(pkg.clone(), &mut visited, &mut closure);
+        }
+        for pkg in &closure {
+            for fetch in &pkg.fetch {
+                known.insert(fetch.sha256.trim().to_ascii_lowercase(), fetch.clone());
+            }
+        }
+
+        let mut cached = Vec::new();
+        for entry in fs::read_dir(&self.fetch_root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.len() == 64
+                && name.bytes().all(|b| b.is_ascii_hexdigit())
+                && entry.file_type()?.is_file()
+            {
+                cached.push(name);
+            }
+        }
+        cached.sort();
+
+        let mut stats = TorrentRefreshStats::default();
+        let total = cached.len();
+        for (index, sha) in cached.iter().enumerate() {
+            let percent = (index + 1) as f64 / total as f64 * 100.0;
+            let Some(fetch) = known.get(sha) else {
+                eprintln!(
+                    "refreshing torrents: {}/{total} ({percent:.0}%) {sha}: no known source, skipped",
+                    index + 1
+                );
+                stats.skipped += 1;
+                continue;
+            };
+            eprintln!(
+                "refreshing torrents: {}/{total} ({percent:.0}%) {}",
+                index + 1,
+                fetch.filename
+            );
+            self.set_activity_step(format!("refreshing torrent for {}", fetch.filename));
+
+            let lock_path = self.fetch_root.join(format!("{sha}{FETCH_LOCK_SUFFIX}"));
+            let lock_file = File::create(&lock_path)?;
+            let hold = self.lock_tracked(&lock_file, format!("fetch {sha}"))?;
+            let path = self.fetch_root.join(sha);
+            let result = if check {
+                self.check_fetch_torrent(fetch, &path, &mut stats)
+            } else {
+                self.refresh_fetch_torrent(fetch, &path)
+            };
+            drop(hold);
+
+            if result? {
+                stats.regenerated += 1;
+            } else {
+                stats.unchanged += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Fetches recorded in `torrent/<info-hash>/sources`, keyed by sha256.
+    fn recorded_fetches(&self) -> MagResult<HashMap<String, FetchResource>> {
+        let mut fetches = HashMap::new();
+        let entries = match fs::read_dir(&self.torrent_root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(fetches),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let dir = entry?.path();
+            let sources = read_torrent_sources(&dir);
+            let Some(sha256) = sources.sha256 else {
+                continue;
+            };
+            let Ok(TorrentSeedInfo { relative_path, .. }) =
+                load_torrent_seed_info(&dir.join("resource.torrent"))
+            else {
+                continue;
+            };
+            fetches.entry(sha256.clone()).or_insert(FetchResource {
+                filename: relative_path.to_string_lossy().into_owned(),
+                sha256,
+                urls: sources.webseeds,
+                source: None,
+                inline: None,
+            });
+        }
+        Ok(fetches)
+    }
+
+    /// Rebuild the torrent for one cached fetch, returning whether anything
+    /// was written. Torrents published upstream (magnet or .torrent URLs)
+    /// have a fixed info hash and are only re-linked.
+    fn refresh_fetch_torrent(&self, fetch: &FetchResource, path: &Path) -> MagResult<bool> {
+        for url in &fetch.urls {
+            if let Some(info_hash) = info_hash_from_url(url)? {
+                let dir = self.torrent_root.join(&info_hash);
+                if self.touch_torrent_dir_path(&dir, path)? {
+                    write_torrent_sources(&dir, fetch)?;
+                    return Ok(false);
+                }
+            }
+        }
+
+        let info = self.create_torrent_for_file(fetch, path)?;
+        let torrent_dir = self.torrent_root.join(&info.info_hash);
+        let current = fs::read(torrent_dir.join("resource.torrent")).ok();
+        if current.as_deref() == Some(info.torrent_bytes.as_slice())
+            && torrent_dir.join(&info.relative_path).exists()
+        {
+            write_torrent_sources(&torrent_dir, fetch)?;
+            return Ok(false);
+        }
+
+        self.write_fetch_torrent(fetch, path, &info)?;
+        Ok(true)
+    }
+
+    /// Generate the torrent for one cached fetch twice without writing it,
+    /// returning whether it differs from the stored copy. Torrents published
+    /// upstream are not generated locally and are never reported.
+    fn check_fetch_torrent(
+        &self,
+        fetch: &FetchResource,
+        path: &Path,
+        stats: &mut TorrentRefreshStats,
+    ) -> MagResult<bool> {
+        for url in &fetch.urls {
+            if info_hash_from_url(url)?.is_some() {
+                return Ok(false);
+            }
+        }
+
+        let first = self.create_torrent_for_file(fetch, path)?;
+        let second = self.create_torrent_for_file(fetch, path)?;
+        if first.torrent_bytes != second.torrent_bytes {
+            eprintln!(
+                "nondeterministic torrent for {}: info hashes {} and {}",
+                fetch.filename, first.info_hash, second.info_hash
+            );
+            stats.nondeterministic += 1;
+        }
+        let stored = fs::read(
+            self.torrent_root
+                .join(&first.info_hash)
+                .join("resource.torrent"),
+        )
+        .ok();
+        Ok(stored.as_deref() != Some(first.torrent_bytes.as_slice()))
+    }
+
+    fn torrent_fetcher(&self) -> MagResult<Arc<TorrentFetcher>> {
+        let mut guard = self
+            .torrent_fetcher
+            .lock()
+            .map_err(|_| MagError::Generic("torrent fetcher mutex poisoned".into()))?;
+
+        if let Some(fetcher) = guard.as_ref() {
+            return Ok(fetcher.clone());
+        }
+
+        let fetcher = Arc::new(TorrentFetcher::new(
+            self.fetch_root.clone(),
+            self.torrent_config.clone(),
+        )?);
+        *guard = Some(fetcher.clone());
+        Ok(fetcher)
+    }
+
+    fn shutdown_torrent_fetcher(&self) -> MagResult<()> {
+        let mut guard = self
+            .torrent_fetcher
+            .lock()
+            .map_err(|_| MagError::Generic("torrent fetcher mutex poisoned".into()))?;
+        guard.take();
+        Ok(())
+    }
+
+    /// Lock `file` exclusively and advertise the lock as `label` in this
+    /// process's activity record. When another process holds it, the
+    /// acquisition counts as contended, and what that process is doing is
+    /// reported every few seconds until the lock is free.
+    fn lock_tracked(&self, file: &File, label: String) -> MagResult<LockHold<'_>> {
+        let waited = match file.try_lock_exclusive() {
+            Ok(()) => false,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                self.stats.incr(stats::LOCK_CONTENTION);
+                self.wait_for_lock(file, &label)?;
+                true
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let lease = match &self.leases {
+            Some(leases) => Some(leases.acquire(&label)?),
+            None => None,
+        };
+        if let Some(activity) = self.activity() {
+            activity.hold(&label);
+        }
+        Ok(LockHold {
+            store: self,
+            label,
+            waited,
+            _lease: lease,
+        })
+    }
+
+    fn wait_for_lock(&self, file: &File, label: &str) -> MagResult<()> {
+        let mut last_report: Option<Instant> = None;
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            if last_report.is_none_or(|at| at.elapsed() >= LOCK_REPORT_INTERVAL) {
+                match find_holder(&self.base_root, label) {
+                    Some(holder) => eprintln!(
+                        "waiting for {label}: held by pid {} ({}), currently {}",
+                        holder.pid, holder.command, holder.step
+                    ),
+                    None => eprintln!("waiting for {label}: held by another process"),
+                }
+                last_report = Some(Instant::now());
+            }
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+    }
+
+    fn activity(&self) -> Option<&Activity> {
+        self.activity
+            .get_or_init(|| match Activity::register(&self.base_root) {
+                Ok(activity) => Some(activity),
+                Err(err) => {
+                    eprintln!("warning: failed to record store activity: {err}");
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// The store database, filled from the published artifacts when it was
+    /// just created. `None` when it cannot be opened.
+    fn metadb(&self) -> Option<&MetaDb> {
+        self.metadb
+            .get_or_init(|| {
+                let opened = MetaDb::open(&self.base_root, self.leases.is_some()).and_then(|db| {
+                    if db.was_created() {
+                        db.replace_all(&self.artifact_rows_from_files()?)?;
+                    }
+                    Ok(db)
+                });
+                match opened {
+                    Ok(db) => Some(db),
+                    Err(err) => {
+                        eprintln!("warning: failed to open the store database: {err}");
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    /// Record a multi-step operation in this process's store journal, so
+    /// that `paths` are removed if the process dies before the returned
+    /// entry is dropped. `lock` must be the lock file the caller holds for
+    /// the operation.
+    fn begin_journaled(&self, operation: &str, lock: &Path, paths: &[PathBuf]) -> JournalEntry<'_> {
+        let journal = self
+            .journal
+            .get_or_init(|| match Journal::open(&self.base_root) {
+                Ok(journal) => Some(journal),
+                Err(err) => {
+                    eprintln!("warning: failed to open store journal: {err}");
+                    None
+                }
+            })
+            .as_ref();
+        match journal {
+            Some(journal) => journal.begin(operation, lock, paths),
+            None => JournalEntry::untracked(),
+        }
+    }
+
+    fn set_activity_step(&self, step: impl Into<String>) {
+        if let Some(activity) = self.activity() {
+            activity.set_step(step);
+        }
+    }
+
+    /// Serialize whole builds against other `magpkg build --queue` runs
+    /// instead of interleaving with them package by package. The returned
+    /// guard keeps the place until it is dropped.
+    pub fn enter_build_queue(&self) -> MagResult<BuildQueueGuard<'_>> {
+        let file = File::create(self.base_root.join(BUILD_QUEUE_LOCK))?;
+        let hold = self.lock_tracked(&file, "build queue".to_string())?;
+        Ok(BuildQueueGuard {
+            _hold: hold,
+            _file: file,
+        })
+    }
+
+    /// Take a shared lease for `label` alongside a shared `flock`, so that
+    /// hosts reclaiming a shared store see the hold. `None` when the store
+    /// does not use leases.
+    pub fn lease_shared(&self, label: &str) -> MagResult<Option<LeaseGuard<'_>>> {
+        match &self.leases {
+            Some(leases) => Ok(Some(leases.acquire_shared(label)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lock `lock_path` and take the lease for `label` without waiting, for
+    /// removing what they guard. `None` when either is held elsewhere.
+    fn try_lock_free(
+        &self,
+        lock_path: &Path,
+        label: &str,
+    ) -> MagResult<Option<(File, Option<LeaseGuard<'_>>)>> {
+        let file = File::create(lock_path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let lease = match &self.leases {
+            Some(leases) => match leases.try_acquire(label)? {
+                Some(lease) => Some(lease),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        Ok(Some((file, lease)))
+    }
+
+    pub fn activities(&self) -> MagResult<Vec<ActivityInfo>> {
+        list_activities(&self.base_root)
+    }
+
+    /// Undo the effects of interrupted operations: roll back journaled
+    /// operations of crashed processes, then remove partial files, build
+    /// directories, and sidecars of missing artifacts whose lock is free,
+    /// regardless of age. Unlike `cleanup`, nothing complete is removed.
+    pub fn repair(&self) -> MagResult<RepairStats> {
+        let mut stats = RepairStats {
+            journal: journal::recover(&self.base_root)?,
+            ..RepairStats::default()
+        };
+
+        for entry in fs::read_dir(&self.fetch_root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_file() || name.ends_with(FETCH_LOCK_SUFFIX) {
+                continue;
+            }
+            let sha = match name.split_once(RACE_SOURCE_MARKER) {
+                Some((sha, _)) => sha,
+                None => match name.strip_suffix(".tmp") {
+                    Some(sha) => sha,
+                    None => continue,
+                },
+            };
+            if sha.len() != 64 || !sha.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                continue;
+            }
+            let lock_path = self.fetch_root.join(format!("{sha}{FETCH_LOCK_SUFFIX}"));
+            if let Some(_lock) = self.try_lock_free(&lock_path, &format!("fetch {sha}"))? {
+                fs::remove_file(entry.path())?;
+                stats.fetch_partials_removed += 1;
+            }
+        }
+
+        for entry in fs::read_dir(&self.store_root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            let (base, kind) = if let Some(base) = name.strip_suffix(".build") {
+                (base, PackageLeftover::BuildDir)
+            } else if let Some(base) = name.strip_suffix(".tar.tmp").or_else(|| {
+                name.strip_suffix(".tmp")
+                    .and_then(package_base_from_sidecar)
+            }) {
+                (base, PackageLeftover::Partial)
+            } else if let Some(base) = package_base_from_sidecar(&name) {
+                (base, PackageLeftover::Sidecar)
+            } else {
+                continue;
+            };
+            if kind == PackageLeftover::Sidecar
+                && self.store_root.join(format!("{base}.tar.zst")).exists()
+            {
+                continue;
+            }
+            let lock_path = self.store_root.join(format!("{base}.lock"));
+            let Some(_lock) = self.try_lock_free(&lock_path, &format!("package {base}"))? else {
+                continue;
+            };
+            match kind {
+                PackageLeftover::BuildDir => {
+                    fs::remove_dir_all(&path)?;
+                    stats.package_build_dirs_removed += 1;
+                }
+                PackageLeftover::Partial => {
+                    fs::remove_file(&path)?;
+                    stats.package_partials_removed += 1;
+                }
+                PackageLeftover::Sidecar => {
+                    fs::remove_file(&path)?;
+                    stats.package_sidecars_removed += 1;
+                }
+            }
+        }
+
+        for entry in fs::read_dir(&self.venv_root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(hash) = name
+                .strip_prefix('.')
+                .and_then(|name| name.strip_suffix(".tmp"))
+            else {
+                continue;
+            };
+            let lock_path = self.venv_root.join(format!("{hash}.lock"));
+            if let Some(_lock) = self.try_lock_free(&lock_path, &format!("prepare venv {hash}"))? {
+                fs::remove_dir_all(entry.path())?;
+                stats.venv_staging_dirs_removed += 1;
+            }
+        }
+
+        if !self.activities()?.is_empty() {
+            stats.torrents_skipped = true;
+            return Ok(stats);
+        }
+        for entry in fs::read_dir(&self.torrent_root)? {
+            let entry = entry?;
+            let dir = entry.path();
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if !dir.join("resource.torrent").exists() {
+                fs::remove_dir_all(&dir)?;
+                stats.torrent_dirs_removed += 1;
+                continue;
+            }
+            for file in fs::read_dir(&dir)? {
+                let file = file?;
+                if file.file_name().to_string_lossy().ends_with(".tmp") {
+                    fs::remove_file(file.path())?;
+                    stats.torrent_partials_removed += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn collect_stats(&self) -> MagResult<StoreStats> {
+        fn modified(path: &Path) -> Option<SystemTime> {
+            fs::metadata(path).and_then(|meta| meta.modified()).ok()
+        }
+
+        let mut packages = AreaStats::default();
+        for entry in fs::read_dir(&self.store_root)? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(".tar.zst") {
+                packages.observe_entry(modified(&path));
+                accumulate_usage(&path, &mut packages)?;
+            }
+        }
+
+        let mut fetched = AreaStats::default();
+        for entry in fs::read_dir(&self.fetch_root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !entry.file_type()?.is_file()
+                || name.ends_with(FETCH_LOCK_SUFFIX)
+                || name.ends_with(".tmp")
+                || name == TORRENT_FETCHER_LOCK
+            {
+                continue;
+            }
+            let path = entry.path();
+            fetched.observe_entry(modified(&path));
+            accumulate_usage(&path, &mut fetched)?;
+        }
+
+        let mut torrents = AreaStats::default();
+        let mut venvs = AreaStats::default();
+        for (root, area) in [
+            (&self.torrent_root, &mut torrents),
+            (&self.venv_root, &mut venvs),
+        ] {
+            for entry in fs::read_dir(root)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let path = entry.path();
+                area.observe_entry(modified(&path));
+                accumulate_usage(&path, area)?;
+            }
+        }
+
+        self.stats.flush()?;
+        Ok(StoreStats {
+            packages,
+            fetched,
+            torrents,
+            venvs,
+            counters: read_counters(&self.base_root)?,
+        })
+    }
+
+    /// The verified indexes listed in `MAGPKG_INDEXES`, loaded on first
+    /// use; none when offline.
+    pub fn indexes(&self) -> &[TorrentIndex] {
+        if is_offline() {
+            return &[];
+        }
+        self.indexes.get_or_init(|| {
+            load_configured_indexes(&self.base_root, |location| self.read_index(location))
+        })
+    }
+
+    /// Read the index at `location`, a magnet link fetched through the
+    /// torrent fetcher, an http(s) URL, or a local path.
+    fn read_index(&self, location: &str) -> MagResult<Vec<u8>> {
+        if !location.starts_with("magnet:") {
+            return read_index_bytes(&self.client, location);
+        }
+        let info_hash = info_hash_from_url(location)?
+            .ok_or_else(|| MagError::Generic(format!("{location} names no torrent info hash")))?;
+        let key = format!("index-{info_hash}");
+        let dest = temp_path_for(&self.fetch_root.join(&key));
+        let request = TorrentDownloadRequest {
+            url: location.to_string(),
+            sha256: key.clone(),
+            filename: key,
+            dest: dest.clone(),
+            cancel: None,
+        };
+        let downloaded = self
+            .torrent_fetcher()
+            .and_then(|fetcher| fetcher.download(request))
+            .and_then(|_| Ok(fs::read(&dest)?));
+        let _ = fs::remove_file(&dest);
+        downloaded
+    }
+
+    fn index_magnet_for_fetch(&self, sha256: &str) -> Option<String> {
+        let sha256 = sha256.trim().to_ascii_lowercase();
+        self.indexes()
+            .iter()
+            .find_map(|index| index.fetches.get(&sha256).cloned())
+    }
+
+    /// Try to download a prebuilt artifact advertised by a torrent index.
+    /// Failures are reported and the caller falls back to building.
+    fn try_substitute(&self, package: &Package, artifact_path: &Path) -> MagResult<bool> {
+        if is_offline() {
+            return Ok(false);
+        }
+        if self.try_substitute_from_index(package, artifact_path)? {
+            return Ok(true);
+        }
+        self.try_substitute_from_caches(package, artifact_path)
+    }
+
+    fn try_substitute_from_index(
+        &self,
+        package: &Package,
+        artifact_path: &Path,
+    ) -> MagResult<bool> {
+        let Some(entry) = self
+            .indexes()
+            .iter()
+            .find_map(|index| index.packages.get(&package.hash).cloned())
+        else {
+            return Ok(false);
+        };
+
+        let base = package_base_name(package);
+        eprintln!("substituting {base} from torrent index...");
+
+        let tmp_path = temp_path_for(artifact_path);
+        let request = TorrentDownloadRequest {
+            url: entry.magnet.clone(),
+            sha256: entry.sha256.clone(),
+            filename: format!("{base}.tar.zst"),
+            dest: tmp_path.clone(),
+            cancel: None,
+        };
+        let downloaded = self
+            .torrent_fetcher()
+            .and_then(|fetcher| fetcher.download(request));
+        if let Err(err) = downloaded {
+            eprintln!("warning: substitution of {base} failed: {err}");
+            let _ = fs::remove_file(&tmp_path);
+            return Ok(false);
+        }
+
+        let installed = verify_sha256(&tmp_path, &entry.sha256).and_then(|verified| {
+            if !verified {
+                eprintln!("warning: substituted artifact for {base} failed sha256 verification");
+                return Ok(false);
+            }
+            install_substitute(&tmp_path, artifact_path)?;
+            Ok(true)
+        });
+        match installed {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                let _ = fs::remove_file(&tmp_path);
+                Ok(false)
+            }
+            // The build can still produce the artifact itself.
+            Err(err) => {
+                eprintln!("warning: substitution of {base} failed: {err}");
+                let _ = fs::remove_file(&tmp_path);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Download the artifact from the first `magpkg serve` instance in
+    /// `MAGPKG_SUBSTITUTERS` that has published it, checked against the
+    /// sha256 in that cache's publish record. The record must be signed by
+    /// a trusted key unless `--no-check-signature` was given.
+    fn try_substitute_from_caches(
+        &self,
+        package: &Package,
+        artifact_path: &Path,
+    ) -> MagResult<bool> {
+        let caches = configured_substituters();
+        if caches.is_empty() {
+            return Ok(false);
+        }
+        let trusted = trusted_keys(&self.base_root)?;
+        if trusted.is_empty() && !signature_check_skipped() {
+            eprintln!(
+                "warning: ignoring binary caches; list their signers in MAGPKG_TRUSTED_KEYS or trusted-keys"
+            );
+            return Ok(false);
+        }
+
+        let base = package_base_name(package);
+        for cache in caches {
+            let record_url = format!("{cache}/pkgs/{base}{PUBLISHED_SUFFIX}");
+            let parsed = match Url::parse(&record_url) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    eprintln!("warning: binary cache {cache} has an invalid URL: {err}");
+                    continue;
+                }
+            };
+            // The record counts against the host's limit like the artifact.
+            let record = {
+                let _permit = self.host_limits.acquire(&parsed, &AtomicBool::new(false))?;
+                self.client
+                    .get(parsed)
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .and_then(|response| response.text())
+            };
+            let record = match record {
+                Ok(record) => record,
+                Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => continue,
+                Err(err) => {
+                    eprintln!("warning: binary cache {cache} is unavailable: {err}");
+                    continue;
+                }
+            };
+            let sha256 = match verify_publish_record(&record, &base, &trusted, &cache) {
+                Ok(sha256) => sha256,
+                Err(err) => {
+                    eprintln!("warning: refusing {base} from binary cache: {err}");
+                    continue;
+                }
+            };
+
+            eprintln!("substituting {base} from {cache}...");
+            let resource = FetchResource {
+                filename: format!("{base}.tar.zst"),
+                sha256,
+                urls: Vec::new(),
+                source: None,
+                inline: None,
+            };
+            let url = format!("{cache}/pkgs/{base}.tar.zst");
+            let tmp_path = temp_path_for(artifact_path);
+            let cancel = Arc::new(AtomicBool::new(false));
+            let download = match self.fetch_source(&resource, &url, &tmp_path, &cancel) {
+                Ok(download) => download,
+                Err(err) => {
+                    eprintln!("warning: substitution of {base} from {cache} failed: {err}");
+                    let _ = fs::remove_file(&tmp_path);
+                    continue;
+                }
+            };
+
+            if let Err(err) = install_substitute(&download.path, artifact_path) {
+                eprintln!("warning: substitution of {base} from {cache} failed: {err}");
+                let _ = fs::remove_file(&download.path);
+                continue;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Build index entries for the whole closure of `roots`, registering a
+    /// torrent for every package artifact and fetch payload so that `magpkg
+    /// seed` shares them.
+    pub fn publish_index(&self, roots: &[Rc<Package>]) -> MagResult<TorrentIndex> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        for pkg in roots {
+            collect_closure(pkg.clone(), &mut visited, &mut order);
+        }
+
+        let mut index = TorrentIndex::default();
+        for package in order {
+            let artifact = self.package_artifact_path(package.as_ref());
+            if !self.is_artifact_published(package.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "package",
+                    package: package.hash.clone(),
+                });
+            }
+            let name = format!("{}.tar.zst", package_base_name(package.as_ref()));
+            let info = self.create_torrent_named(&name, &artifact)?;
+            self.write_torrent_artifacts(&artifact, &info)?;
+            index.packages.insert(
+                package.hash.clone(),
+                IndexedArtifact {
+                    sha256: sha256_file(&artifact)?,
+                    magnet: magnet_for(&info.info_hash, &name),
+                },
+            );
+
+            for fetch in &package.fetch {
+                let cached = self.cache_fetch(fetch)?;
+                let info = self.create_torrent_for_file(fetch, &cached)?;
+                self.write_fetch_torrent(fetch, &cached, &info)?;
+                index.fetches.insert(
+                    fetch.sha256.trim().to_ascii_lowercase(),
+                    magnet_for(&info.info_hash, &fetch.filename),
+                );
+            }
+        }
+
+        self.shutdown_torrent_fetcher()?;
+        Ok(index)
+    }
+
+    pub fn venv_rootfs_dir(&self, hash: &str) -> PathBuf {
+        self.venv_root.join(hash)
+    }
+
+    /// Where the rootfs of venv `hash` is assembled before it is renamed
+    /// into place, so that a venv directory is never seen half-built.
+    pub fn venv_staging_dir(&self, hash: &str) -> PathBuf {
+        self.venv_root.join(format!(".{hash}.tmp"))
+    }
+
+    /// Lock venv `hash` for preparing its rootfs, waiting while another
+    /// process prepares it.
+    pub fn lock_venv_prepare(&self, hash: &str) -> MagResult<VenvPrepareLock<'_>> {
+        let file = File::create(self.venv_root.join(format!("{hash}.lock")))?;
+        let hold = self.lock_tracked(&file, format!("prepare venv {hash}"))?;
+        Ok(VenvPrepareLock {
+            _hold: hold,
+            _file: file,
+        })
+    }
+
+    /// The invoking user's isolated home directory for the venv with rootfs
+    /// `hash`, `venv-homes/<uid>/<hash>`, created private to the user and
+    /// touched so that `cleanup --venv-homes` sees it as used. A home from
+    /// before homes were kept per user, `venv-homes/<hash>`, is moved there
+    /// when the user owns it.
+    pub fn venv_home_dir(&self, hash: &str) -> MagResult<PathBuf> {
+        let user_root = self.venv_homes_root();
+        let dir = user_root.join(hash);
+        if !dir.exists() {
+            fs::create_dir_all(&user_root)?;
+            fs::set_permissions(&user_root, fs::Permissions::from_mode(0o700))?;
+            let legacy = self.base_root.join(VENV_HOMES_DIR).join(hash);
+            let owned = fs::symlink_metadata(&legacy)
+                .is_ok_and(|meta| meta.is_dir() && meta.uid() == current_uid());
+            if owned {
+                fs::rename(&legacy, &dir)?;
+            } else {
+                fs::create_dir(&dir)?;
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+            }
+        }
+        touch_path(&dir)?;
+        Ok(dir)
+    }
+
+    fn venv_homes_root(&self) -> PathBuf {
+        self.base_root
+            .join(VENV_HOMES_DIR)
+            .join(current_uid().to_string())
+    }
+
+    /// Remove the invoking user's venv homes that were not entered within
+    /// `expiry`, except those of venvs running now. Homes hold user data, so
+    /// this only runs when asked for explicitly. Returns how many were
+    /// removed.
+    pub fn cleanup_venv_homes(&self, expiry: Duration) -> MagResult<usize> {
+        let entries = match fs::read_dir(self.venv_homes_root()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || !is_path_expired(&entry.path(), now, expiry)? {
+                continue;
+            }
+            let lock_path = self
+                .venv_rootfs_dir(&entry.file_name().to_string_lossy())
+                .join("rootfs")
+                .join(".lock");
+            let _lock = match File::open(&lock_path) {
+                Ok(file) => match file.try_lock_exclusive() {
+                    Ok(()) => Some(file),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err.into()),
+                },
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn torrent_root(&self) -> &Path {
+        &self.torrent_root
+    }
+
+    /// `pkgs/`, where package artifacts and their publish records live.
+    pub fn package_root(&self) -> &Path {
+        &self.store_root
+    }
+
+    pub fn fetch_root(&self) -> &Path {
+        &self.fetch_root
+    }
+
+    pub fn torrent_config(&self) -> &TorrentConfig {
+        &self.torrent_config
+    }
+
+    fn build_single(
+        &self,
+        package: &Rc<Package>,
+        parallelism: usize,
+        history: &mut BuildHistory,
+    ) -> MagResult<PathBuf> {
+        let started = Instant::now();
+        let started_at = SystemTime::now();
+        let base = package_base_name(package.as_ref());
+        let mut timing = PackageTiming {
+            base: base.clone(),
+            source: BuildSource::Cached,
+            total: Duration::ZERO,
+            fetch: Duration::ZERO,
+            extract: Duration::ZERO,
+            script: Duration::ZERO,
+            pack: Duration::ZERO,
+            artifact_bytes: 0,
+        };
+        let artifact_path = self.store_root.join(format!("{base}.tar.zst"));
+        let path = self.build_single_timed(package, parallelism, &artifact_path, &mut timing)?;
+        if let Err(err) = self.write_package_metadata(package) {
+            eprintln!("warning: failed to record metadata of {base}: {err}");
+        }
+        if let Err(err) = self.write_package_deps(package) {
+            eprintln!("warning: failed to record dependencies of {base}: {err}");
+        }
+        if let Err(err) = self.index_artifact(package, &artifact_path, timing.source) {
+            eprintln!("warning: failed to index {base} in the store database: {err}");
+        }
+        if let Err(err) = self.index_extra_outputs(package, timing.source) {
+            eprintln!("warning: failed to index the outputs of {base}: {err}");
+        }
+        if timing.source == BuildSource::Built {
+            if let Err(err) = self.write_provenance(package, started_at, &timing) {
+                eprintln!("warning: failed to record provenance of {base}: {err}");
+            }
+        }
+
+        timing.total = started.elapsed();
+        timing.artifact_bytes = fs::metadata(&artifact_path).map_or(0, |meta| meta.len());
+        if timing.source == BuildSource::Built {
+            let work = timing.fetch + timing.extract + timing.script + timing.pack;
+            if let Err(err) = history.record(package, work) {
+                eprintln!("warning: failed to record build time of {base}: {err}");
+            }
+        }
+        if let Ok(mut timings) = self.build_timings.lock() {
+            timings.push(timing);
+        }
+        Ok(path)
+    }
+
+    /// The metadata fields recorded in `<base>.meta`, or `None` when the
+    /// package has none.
+    pub fn package_metadata(&self, base: &str) -> MagResult<Option<PackageMetadata>> {
+        let path = self.store_root.join(format!("{base}{METADATA_SUFFIX}"));
+        match fs::read_to_string(&path) {
+            Ok(record) => Ok(Some(PackageMetadata::from_record(&record))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Keep `<base>.meta` in line with the manifest's metadata fields, so
+    /// tools that only see the store can report them.
+    fn write_package_metadata(&self, package: &Package) -> MagResult<()> {
+        let path = self
+            .store_root
+            .join(format!("{}{METADATA_SUFFIX}", package_base_name(package)));
+        let record = package.metadata.to_record();
+        if record.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+        if fs::read_to_string(&path).is_ok_and(|existing| existing == record) {
+            return Ok(());
+        }
+        let tmp = temp_path_for(&path);
+        fs::write(&tmp, &record)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Record what the package was built from in `<base>.deps`, so that
+    /// `magpkg remove` can find dependents without a manifest:
+    ///
+    /// ```text
+    /// hash   <package hash>
+    /// name   <package name>       (absent for unnamed packages)
+    /// run    <dependency base>    (repeatable)
+    /// build  <dependency base>    (repeatable; includes a builderShell package)
+    /// fetch  <sha256>             (repeatable)
+    /// ```
+    fn write_package_deps(&self, package: &Package) -> MagResult<()> {
+        let owner = match &package.output_of {
+            Some(OutputOf { owner, .. }) => owner.as_ref(),
+            None => package,
+        };
+        let record = DependencyRecord {
+            hash: package.hash.clone(),
+            name: owner.name.clone().unwrap_or_default(),
+            run: package
+                .run_deps
+                .iter()
+                .map(|dep| package_base_name(dep))
+                .collect(),
+            build: package
+                .build_deps
+                .iter()
+                .chain(match &package.builder_shell {
+                    Some(BuilderShell::Package(shell)) => Some(shell),
+                    _ => None,
+                })
+                .map(|dep| package_base_name(dep))
+                .collect(),
+            fetches: package
+                .fetch
+                .iter()
+                .map(|fetch| fetch.sha256.clone())
+                .collect(),
+        };
+        self.write_deps_record(&package_base_name(package), &record)
+    }
+
+    fn write_deps_record(&self, base: &str, record: &DependencyRecord) -> MagResult<()> {
+        let path = self.store_root.join(format!("{base}{DEPS_SUFFIX}"));
+        let record = record.to_text();
+        if fs::read_to_string(&path).is_ok_and(|existing| existing == record) {
+            return Ok(());
+        }
+        let tmp = temp_path_for(&path);
+        fs::write(&tmp, &record)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The package hash of the published artifact `base`, from its `.deps`
+    /// record. Extra outputs are named `<name>-<hash>-<output>`, so the name
+    /// is only consulted for artifacts published without a record.
+    pub fn package_hash(&self, base: &str) -> MagResult<Option<String>> {
+        if let Some(record) = self.package_deps(base)? {
+            return Ok(Some(record.hash));
+        }
+        Ok(legacy_base_hash(base).map(str::to_string))
+    }
+
+    /// Package hashes of the published artifacts whose hash is known, by
+    /// base name.
+    pub fn published_hashes(&self) -> MagResult<BTreeMap<String, String>> {
+        if let Some(db) = self.metadb() {
+            return Ok(db
+                .artifacts()?
+                .into_iter()
+                .filter(|row| !row.hash.is_empty())
+                .map(|row| (row.base, row.hash))
+                .collect());
+        }
+        let mut hashes = BTreeMap::new();
+        for base in self.published_package_bases()? {
+            if let Some(hash) = self.package_hash(&base)? {
+                hashes.insert(base, hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// The `<base>.deps` record of a published package, or `None` for one
+    /// built before dependencies were recorded.
+    pub fn package_deps(&self, base: &str) -> MagResult<Option<DependencyRecord>> {
+        let path = self.store_root.join(format!("{base}{DEPS_SUFFIX}"));
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut record = DependencyRecord::default();
+        for line in contents.lines() {
+            match line.split_once('\t') {
+                Some(("hash", hash)) => record.hash = hash.to_string(),
+                Some(("name", name)) => record.name = name.to_string(),
+                Some(("run", dep)) => record.run.push(dep.to_string()),
+                Some(("build", dep)) => record.build.push(dep.to_string()),
+                Some(("fetch", sha)) => record.fetches.push(sha.to_string()),
+                _ => {
+                    return Err(MagError::Generic(format!(
+                        "{}: invalid dependency record {line:?}",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        Ok(Some(record))
+    }
+
+    /// Write `<base>.provenance.json` for a package this process built; see
+    /// [`provenance_record`]. Substituted and reused artifacts keep the
+    /// record, if any, of the build that produced them.
+    fn write_provenance(
+        &self,
+        package: &Package,
+        started: SystemTime,
+        timing: &PackageTiming,
+    ) -> MagResult<()> {
+        let path = self
+            .store_root
+            .join(format!("{}{PROVENANCE_SUFFIX}", package_base_name(package)));
+        let record = provenance_record(package, self, self.expression.as_deref(), started, timing);
+        let tmp = temp_path_for(&path);
+        fs::write(&tmp, format!("{record:#}\n"))?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The provenance record of a published package, or `None` when it was
+    /// substituted, imported, or built before provenance was recorded.
+    pub fn package_provenance(&self, base: &str) -> MagResult<Option<serde_json::Value>> {
+        let path = self.store_root.join(format!("{base}{PROVENANCE_SUFFIX}"));
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|err| MagError::Generic(format!("{}: {err}", path.display())))
+    }
+
+    /// Upsert the artifact's row in the store database, with provenance
+    /// when this process built it.
+    fn index_artifact(
+        &self,
+        package: &Package,
+        artifact_path: &Path,
+        source: BuildSource,
+    ) -> MagResult<()> {
+        let Some(db) = self.metadb() else {
+            return Ok(());
+        };
+        let base = package_base_name(package);
+        let built = source == BuildSource::Built;
+        let row = ArtifactRow {
+            base: base.clone(),
+            hash: package.hash.clone(),
+            size: fs::metadata(artifact_path)?.len(),
+            last_access: unix_now(),
+            built_at: built.then(unix_now),
+            builder: built.then(lease_host),
+            expression: self.expression.clone().filter(|_| built),
+        };
+        let deps = self.package_deps(&base)?;
+        db.record_artifact(&row, deps.as_ref())
+    }
+
+    /// Record the extra outputs packed with `package` like artifacts of
+    /// their own. They only become packages when a dependent refers to them,
+    /// and cleanup would otherwise never see the ones nothing refers to.
+    fn index_extra_outputs(&self, package: &Package, source: BuildSource) -> MagResult<()> {
+        if package.output_of.is_some() {
+            return Ok(());
+        }
+        let base = package_base_name(package);
+        let built = source == BuildSource::Built;
+        for output in package.outputs.iter().skip(1) {
+            let output_base = format!("{base}-{output}");
+            let artifact_path = self.store_root.join(format!("{output_base}.tar.zst"));
+            if !artifact_is_published(&artifact_path) {
+                continue;
+            }
+            // The same record the output package writes when it is built.
+            let record = DependencyRecord {
+                hash: output_hash(&package.hash, output),
+                name: package.name.clone().unwrap_or_default(),
+                run: vec![base.clone()],
+                build: Vec::new(),
+                fetches: Vec::new(),
+            };
+            self.write_deps_record(&output_base, &record)?;
+            let Some(db) = self.metadb() else {
+                continue;
+            };
+            let row = ArtifactRow {
+                base: output_base,
+                hash: record.hash.clone(),
+                size: fs::metadata(&artifact_path)?.len(),
+                last_access: unix_now(),
+                built_at: built.then(unix_now),
+                builder: built.then(lease_host),
+                expression: self.expression.clone().filter(|_| built),
+            };
+            db.record_artifact(&row, Some(&record))?;
+        }
+        Ok(())
+    }
+
+    /// The store database, read without looking at `pkgs/`. Every publish
+    /// and removal keeps it current. `None` when it cannot be opened.
+    pub fn indexed_metadb(&self) -> Option<&MetaDb> {
+        self.metadb()
+    }
+
+    /// The published artifacts, from the store database or, when it cannot
+    /// be opened, from `pkgs/`.
+    pub fn indexed_artifacts(&self) -> MagResult<Vec<ArtifactRow>> {
+        match self.metadb() {
+            Some(db) => db.artifacts(),
+            None => Ok(self
+                .artifact_rows_from_files()?
+                .into_iter()
+                .map(|(row, _)| row)
+                .collect()),
+        }
+    }
+
+    /// The store database after cross-checking it against `pkgs/`: rows of
+    /// removed artifacts are dropped and artifacts published without it,
+    /// for example by an older magpkg or by hand, are added. This lists
+    /// the whole package directory, so it only runs on request. `None`
+    /// when the database cannot be opened.
+    pub fn sync_metadb(&self) -> MagResult<Option<&MetaDb>> {
+        let Some(db) = self.metadb() else {
+            return Ok(None);
+        };
+        let published: BTreeSet<String> = self.published_package_bases()?.into_iter().collect();
+        let indexed: BTreeSet<String> = db.artifacts()?.into_iter().map(|row| row.base).collect();
+        for stale in indexed.difference(&published) {
+            db.remove(stale)?;
+        }
+        for base in published.difference(&indexed) {
+            let (row, deps) = self.artifact_row_from_files(base)?;
+            db.record_artifact(&row, deps.as_ref())?;
+        }
+        Ok(Some(db))
+    }
+
+    /// Rebuild the store database from the published artifacts and their
+    /// sidecars, including the `.provenance.json` records. Provenance
+    /// already indexed for an artifact without a record is kept. Returns
+    /// the number of indexed artifacts.
+    pub fn reindex(&self) -> MagResult<usize> {
+        let db = self
+            .metadb()
+            .ok_or_else(|| MagError::Generic("the store database cannot be opened".into()))?;
+        let rows = self.artifact_rows_from_files()?;
+        db.replace_all(&rows)?;
+        Ok(rows.len())
+    }
+
+    fn artifact_rows_from_files(&self) -> MagResult<Vec<(ArtifactRow, Option<DependencyRecord>)>> {
+        let mut rows = Vec::new();
+        for base in self.published_package_bases()? {
+            rows.push(self.artifact_row_from_files(&base)?);
+        }
+        Ok(rows)
+    }
+
+    /// Add an artifact published outside a build, such as an imported or
+    /// received one, to the store database.
+    fn index_published(&self, base: &str) {
+        let Some(db) = self.metadb() else {
+            return;
+        };
+        let indexed = self
+            .artifact_row_from_files(base)
+            .and_then(|(row, deps)| db.record_artifact(&row, deps.as_ref()));
+        if let Err(err) = indexed {
+            eprintln!("warning: failed to index {base} in the store database: {err}");
+        }
+    }
+
+    /// Drop a removed artifact from the store database.
+    fn unindex(&self, base: &str) {
+        if let Some(db) = self.metadb() {
+            if let Err(err) = db.remove(base) {
+                eprintln!("warning: failed to drop {base} from the store database: {err}");
+            }
+        }
+    }
+
+    fn artifact_row_from_files(
+        &self,
+        base: &str,
+    ) -> MagResult<(ArtifactRow, Option<DependencyRecord>)> {
+        let meta = fs::metadata(self.store_root.join(format!("{base}.tar.zst")))?;
+        let last_access = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |age| age.as_secs());
+        let deps = self.package_deps(base)?;
+        let hash = match &deps {
+            Some(record) => record.hash.clone(),
+            None => legacy_base_hash(base).unwrap_or_default().to_string(),
+        };
+        let mut row = ArtifactRow {
+            base: base.to_string(),
+            hash,
+            size: meta.len(),
+            last_access,
+            ..Default::default()
+        };
+        // Provenance comes from the build's record, which travels with the
+        // artifact; an unreadable record only costs the provenance columns.
+        match self.package_provenance(base) {
+            Ok(Some(record)) => {
+                row.built_at = record["finishedAt"].as_str().and_then(parse_utc_timestamp);
+                row.builder = record["builder"]["host"].as_str().map(str::to_string);
+                row.expression = record["expression"].as_str().map(str::to_string);
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("warning: ignoring the provenance of {base}: {err}"),
+        }
+        Ok((row, deps))
+    }
+
+    /// The dependency records of all published artifacts, and how many were
+    /// published without one. They come from the store database when it can
+    /// be opened, and from the `.deps` sidecars otherwise.
+    pub fn dependency_records(&self) -> MagResult<(BTreeMap<String, DependencyRecord>, usize)> {
+        if let Some(db) = self.metadb() {
+            let records = db.dependency_records()?;
+            let unrecorded = db.artifacts()?.len() - records.len();
+            return Ok((records, unrecorded));
+        }
+        let mut records = BTreeMap::new();
+        let mut unrecorded = 0usize;
+        for base in self.published_package_bases()? {
+            match self.package_deps(&base)? {
+                Some(record) => {
+                    records.insert(base, record);
+                }
+                None => unrecorded += 1,
+            }
+        }
+        Ok((records, unrecorded))
+    }
+
+    fn build_single_timed(
+        &self,
+        package: &Rc<Package>,
+        parallelism: usize,
+        artifact_path: &Path,
+        timing: &mut PackageTiming,
+    ) -> MagResult<PathBuf> {
+        let base = timing.base.clone();
+        let lock_path = self.store_root.join(format!("{base}.lock"));
+        let lock_file = File::create(&lock_path)?;
+        let hold = self.lock_tracked(&lock_file, format!("package {base}"))?;
+
+        if self.is_artifact_published(package.as_ref()) {
+            if hold.waited {
+                eprintln!("reusing {base} built by another magpkg process");
+            }
+            self.stats.incr(stats::PACKAGE_CACHE_HITS);
+            touch_path(artifact_path)?;
+            touch_path(&lock_path)?;
+            return Ok(artifact_path.to_path_buf());
+        }
+
+        let substitute_started = Instant::now();
+        if self.try_substitute(package.as_ref(), artifact_path)? {
+            timing.source = BuildSource::Substituted;
+            timing.fetch = substitute_started.elapsed();
+            touch_path(artifact_path)?;
+            touch_path(&lock_path)?;
+            return Ok(artifact_path.to_path_buf());
+        }
+
+        // A secondary output is only produced by building its owner, which
+        // packs every output again.
+        let (build_package, build_base, build_artifact, _owner_hold) = match &package.output_of {
+            Some(OutputOf { owner, .. }) => {
+                let owner_base = package_base_name(owner.as_ref());
+                let owner_lock = File::create(self.store_root.join(format!("{owner_base}.lock")))?;
+                let hold = self.lock_tracked(&owner_lock, format!("package {owner_base}"))?;
+                eprintln!("{base} is missing; rebuilding {owner_base}");
+                (
+                    owner.clone(),
+                    owner_base.clone(),
+                    self.store_root.join(format!("{owner_base}.tar.zst")),
+                    Some((owner_lock, hold)),
+                )
+            }
+            None => (
+                package.clone(),
+                base.clone(),
+                artifact_path.to_path_buf(),
+                None,
+            ),
+        };
+
+        eprintln!("building {build_base}...");
+        self.set_activity_step(format!("building {build_base}"));
+        self.stats.incr(stats::PACKAGE_BUILDS);
+        timing.source = BuildSource::Built;
+
+        let build_root = self.store_root.join(format!("{build_base}.build"));
+        let _journaled = self.begin_journaled(
+            &format!("build {build_base}"),
+            &self.store_root.join(format!("{build_base}.lock")),
+            &[build_root.clone(), build_artifact.with_extension("tmp")],
+        );
+        if build_root.exists() {
+            fs::remove_dir_all(&build_root)?;
+        }
+        fs::create_dir_all(&build_root)?;
+
+        if let Err(err) = self.run_build_steps(
+            &build_package,
+            &build_root,
+            &build_artifact,
+            parallelism,
+            timing,
+        ) {
+            if matches!(err, MagError::Interrupted(_)) {
+                eprintln!("interrupted; removing {}", build_root.display());
+                let _ = fs::remove_dir_all(&build_root);
+            } else if self.keep_failed {
+                self.preserve_failed_build(&build_base, &build_root);
+            }
+            return Err(err);
+        }
+
+        touch_path(artifact_path)?;
+        touch_path(&lock_path)?;
+        fs::remove_dir_all(&build_root)?;
+
+        Ok(artifact_path.to_path_buf())
+    }
+
+    /// Fill `rootfs` the way a build of `package` sees it: dependencies
+    /// installed, `/store` populated, sources in `/fetch`, and empty `/out`,
+    /// `/build`, and output directories. Returns the time spent installing
+    /// dependencies and preparing the sources.
+    fn prepare_sandbox_root(
+        &self,
+        package: &Rc<Package>,
+        rootfs: &Path,
+    ) -> MagResult<(Duration, Duration)> {
+        fs::create_dir_all(rootfs)?;
+
+        let step = Instant::now();
+        self.install_dependencies_into_root(package.as_ref(), rootfs)?;
+
+        for dir in ["dev", "proc", "sys", "tmp"] {
+            let path = rootfs.join(dir);
+            if fs::symlink_metadata(&path).is_err() {
+                fs::create_dir_all(path)?;
+            }
+        }
+
+        let out_dir = rootfs.join("out");
+        let fetch_dir = rootfs.join("fetch");
+        let store_dir = rootfs.join("store");
+        let build_dir = rootfs.join("build");
+        let outputs_dir = rootfs.join(OUTPUTS_DIR);
+
+        clear_directory(&out_dir)?;
+        clear_directory(&fetch_dir)?;
+        clear_directory(&store_dir)?;
+        clear_directory(&build_dir)?;
+        if package.outputs.len() > 1 {
+            clear_directory(&outputs_dir)?;
+            for output in &package.outputs[1..] {
+                fs::create_dir_all(outputs_dir.join(output))?;
+            }
+        }
+
+        self.populate_build_store(package, &store_dir)?;
+        let extract = step.elapsed();
+        let step = Instant::now();
+        self.prepare_fetches(&package.fetch, &fetch_dir)?;
+        Ok((extract, step.elapsed()))
+    }
+
+    /// Set up the sandbox `package` would be built in and open an
+    /// interactive shell there instead of running the build script. The
+    /// dependencies must already be built. The tree lives next to the
+    /// package's artifact, is journaled so a crash does not leak it, and is
+    /// removed when the shell exits.
+    pub fn open_build_shell(&self, package: &Rc<Package>, parallelism: usize) -> MagResult<()> {
+        let base = package_base_name(package.as_ref());
+        if package.build == "untar" {
+            return Err(MagError::Generic(format!(
+                "{base} is an untar package, which is unpacked without a build sandbox"
+            )));
+        }
+        if let Some(OutputOf { owner, .. }) = &package.output_of {
+            return Err(MagError::Generic(format!(
+                "{base} is built by {}; open a shell for that package instead",
+                package_base_name(owner)
+            )));
+        }
+
+        let shell_name = format!("{base}.shell-{}", std::process::id());
+        let shell_root = self.store_root.join(&shell_name);
+        let lock_path = self.store_root.join(format!("{shell_name}.lock"));
+        let lock_file = File::create(&lock_path)?;
+        lock_file.lock_exclusive()?;
+        let _journaled = self.begin_journaled(
+            &format!("build shell {base}"),
+            &lock_path,
+            &[lock_path.clone(), shell_root.clone()],
+        );
+
+        let result = (|| -> MagResult<()> {
+            let rootfs = shell_root.join("rootfs");
+            self.prepare_sandbox_root(package, &rootfs)?;
+            let shell = self.sandbox_shell(package.as_ref(), &rootfs)?;
+            let scheduling = self.priority.for_package(package.name.as_deref());
+            eprintln!(
+                "entering the build sandbox of {base}; the build script is at {BUILD_SCRIPT_PATH}"
+            );
+            if !package.hooks.is_empty() {
+                eprintln!(
+                    "run `{} {BUILD_PHASES_PATH}` to run it with its hooks",
+                    shell.path
+                );
+            }
+            run_bwrap_build(
+                package.as_ref(),
+                &rootfs,
+                &shell,
+                parallelism,
+                &scheduling,
+                SandboxEntry::Shell,
+            )
+        })();
+
+        match fs::remove_dir_all(&shell_root) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => eprintln!("warning: failed to remove {}: {err}", shell_root.display()),
+        }
+        drop(lock_file);
+        let _ = fs::remove_file(&lock_path);
+        result
+    }
+
+    fn run_build_steps(
+        &self,
+        package: &Rc<Package>,
+        build_root: &Path,
+        artifact_path: &Path,
+        parallelism: usize,
+        timing: &mut PackageTiming,
+    ) -> MagResult<()> {
+        if package.build == "untar" {
+            let fetch_dir = build_root.join("fetch");
+            let out_dir = build_root.join("untar-out");
+
+            clear_directory(&fetch_dir)?;
+            clear_directory(&out_dir)?;
+
+            let step = Instant::now();
+            let fetch_files = self.prepare_fetches(&package.fetch, &fetch_dir)?;
+            timing.fetch = step.elapsed();
+            let step = Instant::now();
+            build_via_untar(&fetch_files, &out_dir)?;
+            timing.extract = step.elapsed();
+
+            let step = Instant::now();
+            check_output_budget(package.as_ref(), &out_dir)?;
+            pack_output(&out_dir, artifact_path, None, self.compression_for(package))?;
+            timing.pack = step.elapsed();
+            return publish_artifact(artifact_path);
+        }
+
+        let rootfs = build_root.join("rootfs");
+        (timing.extract, timing.fetch) = self.prepare_sandbox_root(package, &rootfs)?;
+        let out_dir = rootfs.join("out");
+        let outputs_dir = rootfs.join(OUTPUTS_DIR);
+
+        if !package.build.is_empty() {
+            if let Some(interpreter) = package.build_with.first() {
+                check_executable_in_root(&rootfs, interpreter).map_err(|detail| {
+                    MagError::Generic(format!(
+                        "buildWith interpreter {interpreter} of {} is not usable: {detail}; add the package providing it to buildDeps",
+                        package_base_name(package.as_ref())
+                    ))
+                })?;
+            }
+            let shell = self.sandbox_shell(package.as_ref(), &rootfs)?;
+            let step = Instant::now();
+            let scheduling = self.priority.for_package(package.name.as_deref());
+            run_bwrap_build(
+                package.as_ref(),
+                &rootfs,
+                &shell,
+                parallelism,
+                &scheduling,
+                SandboxEntry::Script {
+                    skip_check: self.skip_checks,
+                },
+            )?;
+            timing.script = step.elapsed();
+        }
+
+        let step = Instant::now();
+        check_output_budget(package.as_ref(), &out_dir)?;
+        if package.lint || package.check_runtime_deps {
+            self.lint_build_output(package, &out_dir)?;
+        }
+
+        let fakeroot = if package.sandbox.fakeroot {
+            Some(FakerootState::load(&rootfs.join(FAKEROOT_STATE_PATH))?)
+        } else {
+            None
+        };
+        // Secondary outputs are published first, so a published `out`
+        // implies that every output of the build is in the store.
+        let base = package_base_name(package.as_ref());
+        let compression = self.compression_for(package);
+        for output in package.outputs.iter().skip(1) {
+            let output_artifact = self.store_root.join(format!("{base}-{output}.tar.zst"));
+            pack_output(
+                &outputs_dir.join(output),
+                &output_artifact,
+                fakeroot.as_ref(),
+                compression,
+            )?;
+            publish_artifact(&output_artifact)?;
+        }
+        pack_output(&out_dir, artifact_path, fakeroot.as_ref(), compression)?;
+        timing.pack = step.elapsed();
+        publish_artifact(artifact_path)
+    }
+
+    /// Report output symlinks and ELF dependencies that the package's runtime
+    /// closure does not satisfy. Findings are warnings, except that missing
+    /// runtime dependencies fail packages with `checkRuntimeDeps` unless the
+    /// build runs with `--lint`.
+    fn lint_build_output(&self, package: &Rc<Package>, out_dir: &Path) -> MagResult<()> {
+        let mut visited = HashSet::new();
+        let mut closure = Vec::new();
+        for dep in &package.run_deps {
+            collect_runtime_closure(dep.clone(), &mut visited, &mut closure);
+        }
+        let artifacts: Vec<PathBuf> = closure
+            .iter()
+            .map(|dep| self.package_artifact_path(dep.as_ref()))
+            .collect();
+
+        let report = lint_output(out_dir, &artifacts)?;
+        if report.is_empty() {
+            return Ok(());
+        }
+
+        let base = package_base_name(package.as_ref());
+        let strict = package.check_runtime_deps && !self.lint_only;
+        if strict && !report.runtime_deps.is_empty() {
+            let mut message = format!(
+                "{base}: {} runtime dependency problem(s) found in build output (add the providing packages to runDeps, or build with --lint to only warn):",
+                report.runtime_deps.len()
+            );
+            for finding in report.runtime_deps.iter().chain(&report.symlinks) {
+                message.push_str(&format!("\n  {finding}"));
+            }
+            return Err(MagError::Generic(message));
+        }
+
+        let findings: Vec<&String> = if package.lint {
+            report.runtime_deps.iter().chain(&report.symlinks).collect()
+        } else {
+            report.runtime_deps.iter().collect()
+        };
+        if findings.is_empty() {
+            return Ok(());
+        }
+        eprintln!(
+            "warning: {base}: {} problem(s) found in build output (set lint: false to skip):",
+            findings.len()
+        );
+        for finding in findings {
+            eprintln!("  {finding}");
+        }
+        Ok(())
+    }
+
+    /// Move a failed build root aside to `<base>.failed` so that a retry does
+    /// not wipe it, and tell the user how to get back into it.
+    fn preserve_failed_build(&self, base: &str, build_root: &Path) {
+        let failed_root = self.store_root.join(format!("{base}{FAILED_SUFFIX}"));
+        if failed_root.exists() {
+            if let Err(err) = fs::remove_dir_all(&failed_root) {
+                eprintln!(
+                    "warning: failed to replace {}: {err}",
+                    failed_root.display()
+                );
+                return;
+            }
+        }
+        match fs::rename(build_root, &failed_root) {
+            Ok(()) => {
+                eprintln!("kept failed build tree at {}", failed_root.display());
+                if let Some(hash) = base.rsplit('-').next() {
+                    eprintln!("re-enter it with: magpkg debug-build {hash}");
+                }
+            }
+            Err(err) => eprintln!(
+                "warning: failed to keep build tree {}: {err}",
+                build_root.display()
+            ),
+        }
+    }
+
+    /// Base names (`<name>-<hash>`) of packages with a published artifact.
+    pub fn published_package_bases(&self) -> MagResult<Vec<String>> {
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(&self.store_root)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(base) = name.strip_suffix(".tar.zst") {
+                if artifact_is_published(&self.store_root.join(name.as_ref())) {
+                    bases.push(base.to_string());
+                }
+            }
+        }
+        bases.sort();
+        Ok(bases)
+    }
+
+    /// Package hashes whose failed build tree was kept by `--keep-failed`.
+    pub fn failed_build_hashes(&self) -> MagResult<Vec<String>> {
+        let mut hashes = Vec::new();
+        for entry in fs::read_dir(&self.store_root)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(base) = name.strip_suffix(FAILED_SUFFIX) {
+                hashes.push(base.rsplit('-').next().unwrap_or(base).to_string());
             }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
 
-            if pkg.fetch.is_empty() {
+    /// Locate the preserved build tree for a package hash (or a unique prefix).
+    pub fn find_failed_build(&self, hash: &str) -> MagResult<PathBuf> {
+        let mut matches = Vec::new();
+        for entry in fs::read_dir(&self.store_root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let Some(base) = name.strip_suffix(FAILED_SUFFIX) else {
                 continue;
-            }
-
-            let base = package_base_name(pkg.as_ref());
-            eprintln!("fetching sources for {base}...");
-            for fetch in &pkg.fetch {
-                self.cache_fetch(fetch)?;
+            };
+            let entry_hash = base.rsplit('-').next().unwrap_or(base);
+            if entry_hash.starts_with(hash) && entry.file_type()?.is_dir() {
+                matches.push(entry.path());
             }
         }
 
-        self.shutdown_torrent_fetcher()?;
-        Ok(())
+        match matches.len() {
+            1 => Ok(matches.remove(0)),
+            0 => Err(MagError::Generic(format!(
+                "no kept build tree for {hash}; rebuild with --keep-failed first"
+            ))),
+            count => Err(MagError::Generic(format!(
+                "{count} kept build trees match {hash}; use a longer hash prefix"
+            ))),
+        }
     }
 
-    fn torrent_fetcher(&self) -> MagResult<Arc<TorrentFetcher>> {
-        let mut guard = self
-            .torrent_fetcher
-            .lock()
-            .map_err(|_| MagError::Generic("torrent fetcher mutex poisoned".into()))?;
-
-        if let Some(fetcher) = guard.as_ref() {
-            return Ok(fetcher.clone());
+    /// Published artifacts selected by `query`: a base name, every build of
+    /// a package name, or the artifact whose hash starts with `query`.
+    pub fn match_published_bases(&self, query: &str) -> MagResult<Vec<String>> {
+        let bases = self.published_package_bases()?;
+        if bases.iter().any(|base| base == query) {
+            return Ok(vec![query.to_string()]);
         }
-
-        let fetcher = Arc::new(TorrentFetcher::new(self.fetch_root.clone())?);
-        *guard = Some(fetcher.clone());
-        Ok(fetcher)
+        // Names may contain dashes and extra outputs append their output
+        // name, so the name comes from the `.deps` record; only artifacts
+        // published without one are matched by splitting off the hash.
+        let prefix = format!("{query}-");
+        let mut named = Vec::new();
+        for base in bases.iter().filter(|base| base.starts_with(&prefix)) {
+            let matches = match self.package_deps(base)? {
+                Some(record) if !record.name.is_empty() => record.name == query,
+                _ => base
+                    .rsplit_once('-')
+                    .is_some_and(|(name, hash)| name == query && !hash.is_empty()),
+            };
+            if matches {
+                named.push(base.clone());
+            }
+        }
+        if !named.is_empty() {
+            return Ok(named);
+        }
+        Ok(vec![self.find_published_base(query)?])
     }
 
-    fn shutdown_torrent_fetcher(&self) -> MagResult<()> {
-        let mut guard = self
-            .torrent_fetcher
-            .lock()
-            .map_err(|_| MagError::Generic("torrent fetcher mutex poisoned".into()))?;
-        guard.take();
-        Ok(())
+    /// Remove a published artifact, its sidecars, and its extracted copy
+    /// under the locks their users take. Returns false, having removed
+    /// nothing, when the package is in use.
+    pub fn remove_package(&self, base: &str, hash: Option<&str>) -> MagResult<bool> {
+        let artifact = self.store_root.join(format!("{base}.tar.zst"));
+        if !self.reclaim_item(&artifact, &ReclaimItem::Package(base.to_string()))? {
+            return Ok(false);
+        }
+        if let Some(hash) = hash {
+            let unpacked = self.unpacked_root.join(hash);
+            if unpacked.exists() {
+                self.reclaim_item(&unpacked, &ReclaimItem::Unpacked(hash.to_string()))?;
+            }
+        }
+        Ok(true)
     }
 
-    pub fn venv_rootfs_dir(&self, hash: &str) -> PathBuf {
-        self.venv_root.join(hash)
+    /// Remove a cached fetch unless another process holds its lock.
+    pub fn remove_fetch(&self, sha256: &str) -> MagResult<bool> {
+        let path = self.fetch_root.join(sha256);
+        self.reclaim_item(&path, &ReclaimItem::Fetch(sha256.to_string()))
     }
 
-    pub fn torrent_root(&self) -> &Path {
-        &self.torrent_root
+    /// Locate a published artifact by base name, package hash, or unique
+    /// hash prefix.
+    pub fn find_published_base(&self, query: &str) -> MagResult<String> {
+        let bases = self.published_package_bases()?;
+        if bases.iter().any(|base| base == query) {
+            return Ok(query.to_string());
+        }
+        let mut matches: Vec<String> = self
+            .published_hashes()?
+            .into_iter()
+            .filter(|(_, hash)| hash.starts_with(query))
+            .map(|(base, _)| base)
+            .collect();
+        match matches.len() {
+            1 => Ok(matches.remove(0)),
+            0 => Err(MagError::Generic(format!(
+                "no published artifact matches {query}"
+            ))),
+            count => Err(MagError::Generic(format!(
+                "{count} published artifacts match {query}; use a longer hash prefix"
+            ))),
+        }
     }
 
-    fn build_single(&self, package: &Rc<Package>, parallelism: usize) -> MagResult<PathBuf> {
-        let base = package_base_name(package.as_ref());
-        let artifact_path = self.store_root.join(format!("{base}.tar.zst"));
-        let lock_path = self.store_root.join(format!("{base}.lock"));
-        let lock_file = File::create(&lock_path)?;
-        lock_file.lock_exclusive()?;
+    /// Write a bundle of the published artifact `base` to `out`.
+    pub fn export_bundle(
+        &self,
+        base: &str,
+        out: &Path,
+        key: Option<&Ed25519KeyPair>,
+    ) -> MagResult<BundleMetadata> {
+        let artifact = self.store_root.join(format!("{base}.tar.zst"));
+        let sha256 = published_sha256(&artifact)
+            .filter(|_| artifact_is_published(&artifact))
+            .ok_or_else(|| MagError::Generic(format!("{base} is not published")))?;
+        let metadata = BundleMetadata {
+            base: base.to_string(),
+            sha256,
+            size: fs::metadata(&artifact)?.len(),
+            exported: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            origin: local_hostname(),
+            deps: self.package_deps(base)?,
+        };
 
-        if artifact_path.exists() {
-            touch_path(&artifact_path)?;
-            touch_path(&lock_path)?;
-            return Ok(artifact_path);
+        let tmp_path = temp_path_for(out);
+        let written = File::create(&tmp_path)
+            .map_err(MagError::from)
+            .and_then(|file| write_bundle(file, &metadata, &artifact, key))
+            .and_then(|file| Ok(file.sync_all()?));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
         }
+        fs::rename(&tmp_path, out)?;
+        touch_path(&artifact)?;
+        Ok(metadata)
+    }
 
-        eprintln!("building {base}...");
+    /// Verify a bundle and publish its artifact. Bundles that are unsigned
+    /// or signed by an untrusted key (see [`trusted_keys`]) are refused unless
+    /// `allow_untrusted` is set. Returns whether the artifact was new.
+    pub fn import_bundle(
+        &self,
+        path: &Path,
+        allow_untrusted: bool,
+    ) -> MagResult<(BundleMetadata, BundleSigner, bool)> {
+        let trusted = trusted_keys(&self.base_root)?;
+        let (metadata, signer, tmp_path) = read_bundle(path, &trusted, |metadata, signer| {
+            let refusal = match signer {
+                BundleSigner::Trusted(_) => None,
+                _ if allow_untrusted => None,
+                BundleSigner::Unsigned => Some("is unsigned".to_string()),
+                BundleSigner::Untrusted(key) => Some(format!("is signed by untrusted key {key}")),
+            };
+            if let Some(refusal) = refusal {
+                return Err(MagError::Generic(format!(
+                    "bundle {} {refusal}; add the signer to MAGPKG_TRUSTED_KEYS or trusted-keys, or pass --allow-untrusted",
+                    path.display()
+                )));
+            }
+            let artifact = self.store_root.join(format!("{}.tar.zst", metadata.base));
+            Ok(temp_path_for(&artifact))
+        })?;
 
-        let build_root = self.store_root.join(format!("{base}.build"));
-        if build_root.exists() {
-            fs::remove_dir_all(&build_root)?;
+        let base = metadata.base.clone();
+        let artifact = self.store_root.join(format!("{base}.tar.zst"));
+        let lock_path = self.store_root.join(format!("{base}.lock"));
+        let lock_file = File::create(&lock_path)?;
+        let _hold = self.lock_tracked(&lock_file, format!("package {base}"))?;
+
+        if artifact_is_published(&artifact) {
+            fs::remove_file(&tmp_path)?;
+            if published_sha256(&artifact).as_deref() != Some(metadata.sha256.as_str()) {
+                eprintln!(
+                    "warning: {base} is already published with different contents; keeping the local artifact"
+                );
+            }
+            touch_path(&artifact)?;
+            return Ok((metadata, signer, false));
         }
-        fs::create_dir_all(&build_root)?;
-
-        if package.build == "untar" {
-            let fetch_dir = build_root.join("fetch");
-            let out_dir = build_root.join("untar-out");
-
-            clear_directory(&fetch_dir)?;
-            clear_directory(&out_dir)?;
-
-            let fetch_files = self.prepare_fetches(&package.fetch, &fetch_dir)?;
-            build_via_untar(&fetch_files, &out_dir)?;
-
-            pack_output(&out_dir, &artifact_path)?;
-            touch_path(&artifact_path)?;
-            touch_path(&lock_path)?;
-            fs::remove_dir_all(&build_root)?;
-
-            return Ok(artifact_path);
+        fs::rename(&tmp_path, &artifact)?;
+        sync_parent_dir(&artifact)?;
+        publish_artifact(&artifact)?;
+        if let Some(deps) = &metadata.deps {
+            self.write_deps_record(&base, deps)?;
         }
+        self.index_published(&base);
+        touch_path(&lock_path)?;
+        Ok((metadata, signer, true))
+    }
 
-        let rootfs = build_root.join("rootfs");
-        fs::create_dir_all(&rootfs)?;
-
-        self.install_dependencies_into_root(package.as_ref(), &rootfs)?;
-
-        for dir in ["dev", "proc", "sys", "tmp"] {
-            let path = rootfs.join(dir);
-            if fs::symlink_metadata(&path).is_err() {
-                fs::create_dir_all(path)?;
+    /// Write the published artifacts `bases` to `out` as an uncompressed tar
+    /// stream for [`receive_artifacts`](Self::receive_artifacts). Each
+    /// artifact is preceded by its publish record and followed by whichever
+    /// of its metadata, dependency, and provenance sidecars exist.
+    pub fn send_artifacts(&self, bases: &[String], out: impl Write) -> MagResult<()> {
+        let mut builder = Builder::new(out);
+        for base in bases {
+            let artifact = self.store_root.join(format!("{base}.tar.zst"));
+            if !artifact_is_published(&artifact) {
+                return Err(MagError::Generic(format!("{base} is not published")));
+            }
+            builder.append_file(
+                format!("{base}{PUBLISHED_SUFFIX}"),
+                &mut File::open(published_marker_path(&artifact))?,
+            )?;
+            builder.append_file(format!("{base}.tar.zst"), &mut File::open(&artifact)?)?;
+            for suffix in [METADATA_SUFFIX, DEPS_SUFFIX, PROVENANCE_SUFFIX] {
+                let name = format!("{base}{suffix}");
+                match File::open(self.store_root.join(&name)) {
+                    Ok(mut file) => builder.append_file(&name, &mut file)?,
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
             }
+            touch_path(&artifact)?;
         }
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
 
-        let out_dir = rootfs.join("out");
-        let fetch_dir = rootfs.join("fetch");
-        let store_dir = rootfs.join("store");
-        let build_dir = rootfs.join("build");
-
-        clear_directory(&out_dir)?;
-        clear_directory(&fetch_dir)?;
-        clear_directory(&store_dir)?;
-        clear_directory(&build_dir)?;
+    /// Publish the artifacts of a stream written by
+    /// [`send_artifacts`](Self::send_artifacts) under their original base
+    /// names. Every artifact must match the sha256 in the sender's publish
+    /// record. Artifacts that are already published here are kept along
+    /// with their sidecars. With `wanted`, the stream is refused as soon as
+    /// it carries an artifact that was not asked for, so that a peer cannot
+    /// publish arbitrary bases. Returns the bases that were new.
+    pub fn receive_artifacts(
+        &self,
+        input: impl Read,
+        wanted: Option<&HashSet<String>>,
+    ) -> MagResult<Vec<String>> {
+        let mut archive = tar::Archive::new(input);
+        let mut expected: HashMap<String, String> = HashMap::new();
+        let mut received: Vec<String> = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            if name.is_empty() || name.starts_with('.') || name.contains('/') {
+                return Err(MagError::Generic(format!(
+                    "refusing artifact stream member {name:?}"
+                )));
+            }
+            if let Some(base) = name.strip_suffix(PUBLISHED_SUFFIX) {
+                if wanted.is_some_and(|wanted| !wanted.contains(base)) {
+                    return Err(MagError::Generic(format!(
+                        "refusing {base}, which was not requested"
+                    )));
+                }
+                let mut record = String::new();
+                entry.read_to_string(&mut record)?;
+                let sha256 = record
+                    .lines()
+                    .find_map(|line| line.strip_prefix("sha256 "))
+                    .map(|sha| sha.trim().to_string())
+                    .ok_or_else(|| {
+                        MagError::Generic(format!("publish record of {base} has no sha256"))
+                    })?;
+                expected.insert(base.to_string(), sha256);
+            } else if let Some(base) = name.strip_suffix(".tar.zst") {
+                let sha256 = expected.remove(base).ok_or_else(|| {
+                    MagError::Generic(format!("{base} arrived without its publish record"))
+                })?;
+                if self.receive_artifact(base, &sha256, &mut entry)? {
+                    received.push(base.to_string());
+                }
+            } else if let Some(base) = package_base_from_sidecar(&name) {
+                // Sidecars follow their artifact; those of an artifact that
+                // was already here stay as they are.
+                if received.last().is_some_and(|last| last == base) {
+                    let path = self.store_root.join(&name);
+                    let tmp_path = temp_path_for(&path);
+                    io::copy(&mut entry, &mut File::create(&tmp_path)?)?;
+                    fs::rename(&tmp_path, &path)?;
+                }
+            } else {
+                return Err(MagError::Generic(format!(
+                    "unexpected artifact stream member {name}"
+                )));
+            }
+        }
+        for base in &received {
+            self.index_published(base);
+        }
+        Ok(received)
+    }
 
-        self.populate_build_store(package, &store_dir)?;
-        self.prepare_fetches(&package.fetch, &fetch_dir)?;
+    /// Copy one artifact of a `receive_artifacts` stream into the store and
+    /// publish it, unless it is already published. Returns whether it was new.
+    fn receive_artifact(&self, base: &str, sha256: &str, contents: impl Read) -> MagResult<bool> {
+        let artifact = self.store_root.join(format!("{base}.tar.zst"));
+        let lock_path = self.store_root.join(format!("{base}.lock"));
+        let lock_file = File::create(&lock_path)?;
+        let _hold = self.lock_tracked(&lock_file, format!("package {base}"))?;
 
-        run_bwrap_build(package.as_ref(), &rootfs, parallelism)?;
+        if artifact_is_published(&artifact) {
+            if published_sha256(&artifact).as_deref() != Some(sha256) {
+                eprintln!(
+                    "warning: {base} is already published with different contents; keeping the local artifact"
+                );
+            }
+            touch_path(&artifact)?;
+            return Ok(false);
+        }
 
-        pack_output(&out_dir, &artifact_path)?;
-        touch_path(&artifact_path)?;
+        let tmp_path = temp_path_for(&artifact);
+        let mut hasher = Sha256::new();
+        let written = File::create(&tmp_path)
+            .map_err(MagError::from)
+            .and_then(|mut file| {
+                let mut reader = HashingReader {
+                    inner: contents,
+                    hasher: &mut hasher,
+                };
+                io::copy(&mut reader, &mut file)?;
+                Ok(file.sync_all()?)
+            });
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        let actual = hex::encode(hasher.finalize());
+        if actual != sha256 {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(MagError::Generic(format!(
+                "{base} arrived with sha256 {actual}, but its publish record says {sha256}"
+            )));
+        }
+        fs::rename(&tmp_path, &artifact)?;
+        sync_parent_dir(&artifact)?;
+        publish_artifact(&artifact)?;
         touch_path(&lock_path)?;
-        fs::remove_dir_all(&build_root)?;
-
-        Ok(artifact_path)
+        Ok(true)
     }
 
     fn cleanup_packages(
@@ -309,7 +3048,14 @@ impl PackageStore {
         stats: &mut CleanupStats,
         remove_artifacts: bool,
     ) -> MagResult<()> {
-        let mut bases = HashSet::new();
+        // Published artifacts come from the store database; the directory
+        // is listed for what interrupted builds and removals leave behind.
+        let indexed: HashSet<String> = self
+            .indexed_artifacts()?
+            .into_iter()
+            .map(|row| row.base)
+            .collect();
+        let mut bases = indexed.clone();
         for entry in fs::read_dir(&self.store_root)? {
             let entry = entry?;
             let name = entry.file_name();
@@ -336,11 +3082,34 @@ impl PackageStore {
             }
 
             let artifact_path = self.store_root.join(format!("{base}.tar.zst"));
-            if remove_artifacts {
+            let marker_path = published_marker_path(&artifact_path);
+            if remove_artifacts
+                && (indexed.contains(&base) || !artifact_is_published(&artifact_path))
+            {
                 if remove_path_if_expired(&artifact_path, now, expiry)? {
+                    self.unindex(&base);
                     stats.package_artifacts_removed += 1;
                 }
             }
+            if !artifact_path.exists() {
+                let index_path = self.store_root.join(format!("{base}{FILE_INDEX_SUFFIX}"));
+                let metadata_path = self.store_root.join(format!("{base}{METADATA_SUFFIX}"));
+                let deps_path = self.store_root.join(format!("{base}{DEPS_SUFFIX}"));
+                let provenance_path = self.store_root.join(format!("{base}{PROVENANCE_SUFFIX}"));
+                for path in [
+                    &marker_path,
+                    &index_path,
+                    &metadata_path,
+                    &deps_path,
+                    &provenance_path,
+                ] {
+                    match fs::remove_file(path) {
+                        Ok(()) => {}
+                        Err(err) if err.kind() == ErrorKind::NotFound => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            }
 
             let build_path = self.store_root.join(format!("{base}.build"));
             if build_path.exists() {
@@ -348,6 +3117,11 @@ impl PackageStore {
                 stats.package_build_dirs_removed += 1;
             }
 
+            let failed_path = self.store_root.join(format!("{base}{FAILED_SUFFIX}"));
+            if remove_path_if_expired(&failed_path, now, expiry)? {
+                stats.package_build_dirs_removed += 1;
+            }
+
             let mut remove_lock = false;
             if !artifact_path.exists() && !build_path.exists() {
                 if is_path_expired(&lock_path, now, expiry)? {
@@ -387,17 +3161,174 @@ impl PackageStore {
         }
 
         for dep in order {
-            let artifact = self.package_artifact_path(dep.as_ref());
-            if !artifact.exists() {
-                return Err(MagError::Generic(format!(
-                    "missing artifact for dependency {}",
-                    dep.hash
-                )));
+            if !self.is_artifact_published(dep.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "dependency",
+                    package: dep.hash.clone(),
+                });
             }
 
-            extract_tar_zst(&artifact, rootfs)?;
+            self.install_artifact(dep.as_ref(), rootfs)?;
+        }
+
+        Ok(())
+    }
+
+    /// Work out which shell runs the build script and check that it exists,
+    /// so that a dependency set without a shell fails with an explanation
+    /// instead of an opaque bwrap error.
+    fn sandbox_shell(&self, package: &Package, rootfs: &Path) -> MagResult<SandboxShell> {
+        let base = package_base_name(package);
+        match &package.builder_shell {
+            Some(BuilderShell::Package(shell_pkg)) => {
+                let shell_base = package_base_name(shell_pkg);
+                if !self.is_artifact_published(shell_pkg) {
+                    return Err(MagError::MissingArtifact {
+                        role: "builderShell package",
+                        package: shell_pkg.hash.clone(),
+                    });
+                }
+                let unpacked = self.unpacked_artifact(shell_pkg)?;
+                check_executable_in_root(&unpacked, DEFAULT_BUILDER_SHELL).map_err(|detail| {
+                    MagError::Generic(format!(
+                        "builderShell package {shell_base} of {base} must provide bin/sh, but {detail}"
+                    ))
+                })?;
+                Ok(SandboxShell {
+                    path: format!("{SHELL_MOUNT}{DEFAULT_BUILDER_SHELL}"),
+                    bind: Some(unpacked),
+                })
+            }
+            Some(BuilderShell::Path(path)) => {
+                check_executable_in_root(rootfs, path).map_err(|detail| {
+                    MagError::Generic(format!(
+                        "builderShell {path} of {base} is not usable: {detail}; add the package providing it to buildDeps"
+                    ))
+                })?;
+                Ok(SandboxShell {
+                    path: path.clone(),
+                    bind: None,
+                })
+            }
+            // A script run by buildWith does not need the shell itself.
+            None if !package.build_with.is_empty() => Ok(SandboxShell {
+                path: DEFAULT_BUILDER_SHELL.into(),
+                bind: None,
+            }),
+            None => {
+                check_executable_in_root(rootfs, DEFAULT_BUILDER_SHELL).map_err(|detail| {
+                    MagError::Generic(format!(
+                        "build script of {base} runs under {DEFAULT_BUILDER_SHELL}, but {detail}. \
+                         Add a package providing a shell to buildDeps, or set builderShell to a \
+                         shell path or a static shell package"
+                    ))
+                })?;
+                Ok(SandboxShell {
+                    path: DEFAULT_BUILDER_SHELL.into(),
+                    bind: None,
+                })
+            }
+        }
+    }
+
+    /// Place the contents of a published artifact under `dest`, merging with
+    /// whatever is already there. Files are copied, so writing to `dest`
+    /// never reaches the shared extraction cache.
+    fn install_artifact(&self, package: &Package, dest: &Path) -> MagResult<()> {
+        let unpacked = self.unpacked_artifact(package)?;
+        merge_tree(&unpacked, dest, false)?;
+        Ok(())
+    }
+
+    /// [`Self::install_artifact`] for a tree that is only read and then
+    /// discarded: files are hard-linked from the extraction cache.
+    fn link_artifact(&self, package: &Package, dest: &Path) -> MagResult<()> {
+        let unpacked = self.unpacked_artifact(package)?;
+        merge_tree(&unpacked, dest, true)?;
+        Ok(())
+    }
+
+    /// Return `unpacked/<hash>`, an immutable extracted copy of the package's
+    /// artifact, decompressing it on first use.
+    fn unpacked_artifact(&self, package: &Package) -> MagResult<PathBuf> {
+        self.unpacked_tree(&package.hash, &self.package_artifact_path(package))
+    }
+
+    fn unpacked_tree(&self, hash: &str, artifact: &Path) -> MagResult<PathBuf> {
+        let dir = self.unpacked_root.join(hash);
+        if dir.is_dir() {
+            touch_path(&dir)?;
+            return Ok(dir);
+        }
+
+        let lock_path = self
+            .unpacked_root
+            .join(format!("{hash}{FETCH_LOCK_SUFFIX}"));
+        let lock_file = File::create(&lock_path)?;
+        let hold = self.lock_tracked(&lock_file, format!("unpack {hash}"))?;
+
+        if !dir.is_dir() {
+            let tmp_dir = self
+                .unpacked_root
+                .join(format!(".{hash}.{}.tmp", std::process::id()));
+            if fs::symlink_metadata(&tmp_dir).is_ok() {
+                remove_read_only_tree(&tmp_dir)?;
+            }
+            fs::create_dir_all(&tmp_dir)?;
+            let unpacked = (|| -> MagResult<()> {
+                extract_artifact(artifact, &tmp_dir)?;
+                set_tree_read_only(&tmp_dir)?;
+                fs::rename(&tmp_dir, &dir)?;
+                Ok(())
+            })();
+            if let Err(err) = unpacked {
+                let _ = remove_read_only_tree(&tmp_dir);
+                return Err(err);
+            }
+            self.stats.incr(stats::ARTIFACT_UNPACKS);
         }
 
+        drop(hold);
+        Ok(dir)
+    }
+
+    /// Remove extracted artifacts that have not been used within the expiry
+    /// window, along with extractions abandoned by interrupted processes.
+    fn cleanup_unpacked(
+        &self,
+        now: SystemTime,
+        expiry: Duration,
+        stats: &mut CleanupStats,
+    ) -> MagResult<()> {
+        for entry in fs::read_dir(&self.unpacked_root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            if name.ends_with(FETCH_LOCK_SUFFIX) {
+                continue;
+            }
+
+            let hash = name
+                .strip_prefix('.')
+                .and_then(|rest| rest.split('.').next())
+                .unwrap_or(&name);
+            let lock_path = self
+                .unpacked_root
+                .join(format!("{hash}{FETCH_LOCK_SUFFIX}"));
+            let lock_file = File::create(&lock_path)?;
+            match lock_file.try_lock_exclusive() {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err.into()),
+            }
+
+            // The lock file stays: an extractor may already have it open,
+            // and one recreated at the path would not exclude that one.
+            if is_path_expired(&path, now, expiry)? {
+                remove_read_only_tree(&path)?;
+                stats.unpacked_dirs_removed += 1;
+            }
+        }
         Ok(())
     }
 
@@ -489,6 +3420,9 @@ impl PackageStore {
             }
 
             if let Some(base) = name_str.strip_suffix(".tmp") {
+                let base = base
+                    .split_once(RACE_SOURCE_MARKER)
+                    .map_or(base, |(sha, _)| sha);
                 groups
                     .entry(base.to_string())
                     .or_default()
@@ -592,6 +3526,7 @@ impl PackageStore {
             }
 
             let downloads_dir = path.join("downloads");
+            let mut removed_downloads = false;
             if downloads_dir.exists() {
                 for entry in fs::read_dir(&downloads_dir)? {
                     let entry = entry?;
@@ -601,11 +3536,18 @@ impl PackageStore {
                     let entry_path = entry.path();
                     let removed = remove_path_if_expired(&entry_path, now, expiry)?;
                     if removed {
+                        removed_downloads = true;
                         stats.fetch_partials_removed += 1;
                         stats.torrent_work_dirs_removed += 1;
                     }
                 }
             }
+            // Saved fastresume state would claim pieces of the removed
+            // downloads; without it, the rest are verified on their next run.
+            let state_dir = path.join(TORRENT_SESSION_STATE_DIR);
+            if removed_downloads && state_dir.exists() {
+                fs::remove_dir_all(&state_dir)?;
+            }
 
             drop(lock.take());
 
@@ -649,7 +3591,10 @@ impl PackageStore {
     ) -> MagResult<()> {
         for entry in fs::read_dir(&self.venv_root)? {
             let entry = entry?;
-            if !entry.file_type()?.is_dir() {
+            // Staging directories are removed by the next preparation of
+            // their venv, or by repair.
+            if !entry.file_type()?.is_dir() || entry.file_name().to_string_lossy().starts_with('.')
+            {
                 continue;
             }
 
@@ -697,12 +3642,11 @@ impl PackageStore {
             }
 
             // Ensure the dependency artifact exists.
-            let artifact = self.package_artifact_path(dep.as_ref());
-            if !artifact.exists() {
-                return Err(MagError::Generic(format!(
-                    "missing artifact for dependency {}",
-                    dep.hash
-                )));
+            if !self.is_artifact_published(dep.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "dependency",
+                    package: dep.hash.clone(),
+                });
             }
 
             let dest = store_dir.join(package_base_name(dep.as_ref()));
@@ -710,7 +3654,7 @@ impl PackageStore {
                 fs::remove_dir_all(&dest)?;
             }
             fs::create_dir_all(&dest)?;
-            extract_tar_zst(&artifact, &dest)?;
+            self.install_artifact(dep.as_ref(), &dest)?;
 
             for run_dep in &dep.run_deps {
                 queue.push_back(run_dep.clone());
@@ -728,36 +3672,126 @@ impl PackageStore {
         fetches: &[FetchResource],
         fetch_dir: &Path,
     ) -> MagResult<Vec<PathBuf>> {
+        let cached = self.cache_fetches(fetches)?;
         let mut result = Vec::with_capacity(fetches.len());
-        for fetch in fetches {
-            let cached = self.cache_fetch(fetch)?;
+        for (fetch, cached) in fetches.iter().zip(cached) {
             let dest = fetch_dir.join(&fetch.filename);
-            fs::copy(&cached, &dest)?;
+            if fetch.source.is_some() {
+                fs::create_dir_all(&dest)?;
+                tar::Archive::new(File::open(&cached)?).unpack(&dest)?;
+            } else {
+                fs::copy(&cached, &dest)?;
+            }
             result.push(dest);
         }
         Ok(result)
     }
 
+    /// [`Self::cache_fetch`] for up to [`FETCH_WIDTH`] fetches at once, so
+    /// that verifying large cached files and downloading overlap. Returns
+    /// the cached paths in the order of `fetches`; after the first failure
+    /// no further fetches are started.
+    fn cache_fetches(&self, fetches: &[FetchResource]) -> MagResult<Vec<PathBuf>> {
+        if fetches.len() <= 1 {
+            return fetches
+                .iter()
+                .map(|fetch| self.cache_fetch(fetch))
+                .collect();
+        }
+        let results: Mutex<Vec<Option<MagResult<PathBuf>>>> =
+            Mutex::new(fetches.iter().map(|_| None).collect());
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..FETCH_WIDTH.min(fetches.len()) {
+                scope.spawn(|| {
+                    while !failed.load(Ordering::Relaxed) {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(fetch) = fetches.get(index) else {
+                            break;
+                        };
+                        let result = self.cache_fetch(fetch);
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        results.lock().unwrap_or_else(|err| err.into_inner())[index] = Some(result);
+                    }
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
     fn cache_fetch(&self, fetch: &FetchResource) -> MagResult<PathBuf> {
         let dest = self.fetch_root.join(&fetch.sha256);
         let lock_path = self
             .fetch_root
             .join(format!("{}{}", fetch.sha256, FETCH_LOCK_SUFFIX));
         let lock_file = File::create(&lock_path)?;
-        lock_file.lock_exclusive()?;
+        let hold = self.lock_tracked(&lock_file, format!("fetch {}", fetch.sha256))?;
 
         let result = self.cache_fetch_locked(fetch, &dest);
 
         touch_path(&lock_path)?;
+        drop(hold);
         drop(lock_file);
 
         result
     }
 
+    /// Fail with every fetch of `packages` that cannot be satisfied without
+    /// the network, so an offline build stops before starting any work.
+    fn check_offline_fetches<'a>(
+        &self,
+        packages: impl Iterator<Item = &'a Rc<Package>>,
+    ) -> MagResult<()> {
+        let mut seen = HashSet::new();
+        let mut missing = Vec::new();
+        for package in packages {
+            for fetch in &package.fetch {
+                if seen.insert(fetch.sha256.clone()) && !self.is_fetch_available_offline(fetch) {
+                    missing.push(format!(
+                        "{} (for {})",
+                        offline_fetch_label(fetch),
+                        package_base_name(package)
+                    ));
+                }
+            }
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(MagError::OfflineUnavailable { resources: missing })
+        }
+    }
+
+    /// Whether `fetch` is cached or can be produced from this machine: a
+    /// local source, inline contents, a `--link-from` candidate, or a
+    /// `file://` URL, possibly after mirror rewriting.
+    fn is_fetch_available_offline(&self, fetch: &FetchResource) -> bool {
+        self.fetch_root.join(&fetch.sha256).exists()
+            || fetch.source.is_some()
+            || fetch.inline.is_some()
+            || !self.link_sources.candidates(fetch).is_empty()
+            || fetch.urls.iter().any(|url| {
+                is_local_url(url)
+                    || self
+                        .mirrors
+                        .rewrite(url)
+                        .is_some_and(|mirror| is_local_url(&mirror))
+            })
+    }
+
     fn cache_fetch_locked(&self, fetch: &FetchResource, dest: &Path) -> MagResult<PathBuf> {
         if dest.exists() {
             if verify_sha256(dest, &fetch.sha256)? {
                 eprintln!("fetch cache hit: {} ({})", fetch.filename, fetch.sha256);
+                self.stats.incr(stats::FETCH_CACHE_HITS);
                 touch_path(dest)?;
                 self.refresh_torrent_artifacts(fetch, dest)?;
                 return Ok(dest.to_path_buf());
@@ -765,71 +3799,358 @@ impl PackageStore {
             fs::remove_file(dest)?;
         }
 
+        self.stats.incr(stats::FETCH_CACHE_MISSES);
+        self.set_activity_step(format!("fetching {}", fetch.filename));
+
+        if let Some(source) = &fetch.source {
+            let tmp = temp_path_for(dest);
+            let sha256 = source.write_archive(&tmp)?;
+            if !sha256.eq_ignore_ascii_case(&fetch.sha256) {
+                fs::remove_file(&tmp)?;
+                return Err(MagError::FetchHashMismatch {
+                    filename: source.path.display().to_string(),
+                    expected: fetch.sha256.clone(),
+                    actual: sha256,
+                });
+            }
+            fs::rename(&tmp, dest)?;
+            eprintln!(
+                "fetch archived: {} ({}) from {}",
+                fetch.filename,
+                fetch.sha256,
+                source.path.display()
+            );
+            touch_path(dest)?;
+            return Ok(dest.to_path_buf());
+        }
+
+        if let Some(contents) = &fetch.inline {
+            let tmp = temp_path_for(dest);
+            fs::write(&tmp, contents)?;
+            fs::rename(&tmp, dest)?;
+            eprintln!(
+                "fetch written: {} ({}) inline",
+                fetch.filename, fetch.sha256
+            );
+            return Ok(dest.to_path_buf());
+        }
+
+        if let Some(source) = self.link_from_sources(fetch, dest)? {
+            eprintln!(
+                "fetch linked: {} ({}) from {}",
+                fetch.filename,
+                fetch.sha256,
+                source.display()
+            );
+            touch_path(dest)?;
+            let torrent_info = self.create_torrent_for_file(fetch, dest)?;
+            self.write_fetch_torrent(fetch, dest, &torrent_info)?;
+            return Ok(dest.to_path_buf());
+        }
+
         if fetch.urls.is_empty() {
-            return Err(MagError::Generic(format!(
-                "no URLs provided for fetch {}",
-                fetch.filename
-            )));
+            return Err(MagError::FetchFailure {
+                filename: fetch.filename.clone(),
+                message: "no URLs provided".into(),
+            });
+        }
+
+        let mut sources = self.fetch_sources(fetch);
+        if !is_offline() {
+            // Finding these downloads the `.torrent` files, which an offline
+            // fetch cannot do.
+            for webseed in self.torrent_file_webseeds(fetch) {
+                if !sources.contains(&webseed) {
+                    sources.push(webseed);
+                }
+            }
+        }
+        let mut prioritized_urls: Vec<&str> = sources.iter().map(String::as_str).collect();
+        if is_offline() {
+            prioritized_urls.retain(|url| is_local_url(url));
+            if prioritized_urls.is_empty() {
+                return Err(MagError::OfflineUnavailable {
+                    resources: vec![offline_fetch_label(fetch)],
+                });
+            }
+        }
+
+        let mut partials = vec![temp_path_for(dest)];
+        for index in 0..prioritized_urls.len() {
+            let source_dest =
+                dest.with_file_name(format!("{}{RACE_SOURCE_MARKER}{index}", fetch.sha256));
+            partials.push(temp_path_for(&source_dest));
+            partials.push(source_dest);
+        }
+        let _journaled = self.begin_journaled(
+            &format!("fetch {}", fetch.filename),
+            &self
+                .fetch_root
+                .join(format!("{}{FETCH_LOCK_SUFFIX}", fetch.sha256)),
+            &partials,
+        );
+        let (url, mut download) =
+            self.race_sources(fetch, &prioritized_urls, dest, fetch_race_width())?;
+
+        if dest.exists() {
+            fs::remove_file(dest)?;
+        }
+        fs::rename(&download.path, dest)?;
+        File::open(dest)?.sync_all()?;
+        let final_path = dest.to_path_buf();
+        eprintln!(
+            "fetch complete: {} ({}) from {url}",
+            fetch.filename, fetch.sha256
+        );
+        touch_path(&final_path)?;
+
+        let torrent_info = match download.torrent.take() {
+            Some(info) => info,
+            None => self.create_torrent_for_file(fetch, &final_path)?,
+        };
+        self.write_fetch_torrent(fetch, &final_path, &torrent_info)?;
+        Ok(final_path)
+    }
+
+    /// Place a copy of `fetch` found among the `--link-from` files at
+    /// `dest`, returning the file it came from. Candidates are hashed before
+    /// use; the copy is hard-linked when the file lives on the same
+    /// filesystem as the store and copied (and hashed again) otherwise.
+    fn link_from_sources(&self, fetch: &FetchResource, dest: &Path) -> MagResult<Option<PathBuf>> {
+        for candidate in self.link_sources.candidates(fetch) {
+            match verify_sha256(candidate, &fetch.sha256) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    eprintln!("warning: cannot read {}: {err}", candidate.display());
+                    continue;
+                }
+            }
+            // Only a file the store's owner owns is hard-linked; anyone else
+            // could edit a linked file after it was verified.
+            let shareable = match (fs::symlink_metadata(candidate), dest.parent()) {
+                (Ok(meta), Some(parent)) => {
+                    meta.is_file() && fs::metadata(parent)?.uid() == meta.uid()
+                }
+                _ => false,
+            };
+            let tmp = temp_path_for(dest);
+            let _ = fs::remove_file(&tmp);
+            if !shareable || fs::hard_link(candidate, &tmp).is_err() {
+                fs::copy(candidate, &tmp)?;
+                File::open(&tmp)?.sync_all()?;
+                if !verify_sha256(&tmp, &fetch.sha256)? {
+                    fs::remove_file(&tmp)?;
+                    eprintln!(
+                        "warning: {} changed while it was copied",
+                        candidate.display()
+                    );
+                    continue;
+                }
+            }
+            fs::rename(&tmp, dest)?;
+            return Ok(Some(candidate.to_path_buf()));
         }
+        Ok(None)
+    }
 
-        let mut prioritized_urls: Vec<&str> = Vec::with_capacity(fetch.urls.len());
+    /// Every source of `fetch` in the order downloads try them: internal
+    /// mirrors, the release index's magnet, binary caches, torrents, the
+    /// upstream URLs, and the webseeds of its magnet links. Webseeds listed
+    /// inside `.torrent` files take a download to find, so they are added
+    /// only when fetching and `--print-urls` does not show them.
+    fn fetch_sources(&self, fetch: &FetchResource) -> Vec<String> {
+        let index_magnet = self.index_magnet_for_fetch(&fetch.sha256);
+        let cache_urls: Vec<String> = configured_substituters()
+            .iter()
+            .map(|cache| format!("{cache}/fetch/{}", fetch.sha256.trim().to_ascii_lowercase()))
+            .collect();
+        let mirrored: Vec<(&str, String)> = fetch
+            .urls
+            .iter()
+            .filter_map(|url| Some((url.as_str(), self.mirrors.rewrite(url)?)))
+            .collect();
+        let mut prioritized_urls: Vec<&str> =
+            Vec::with_capacity(fetch.urls.len() + mirrored.len() + cache_urls.len() + 1);
+        // Internal mirrors come first; their upstream URLs stay as fallbacks
+        // unless the mirror config forbids it.
+        prioritized_urls.extend(mirrored.iter().map(|(_, mirror)| mirror.as_str()));
+        let skip_upstream = |url: &str| {
+            !self.mirrors.fallback() && mirrored.iter().any(|(upstream, _)| *upstream == url)
+        };
+        if let Some(magnet) = index_magnet.as_deref() {
+            prioritized_urls.push(magnet);
+        }
+        prioritized_urls.extend(cache_urls.iter().map(String::as_str));
         for url in &fetch.urls {
             if is_torrent_url(url) {
                 prioritized_urls.push(url.as_str());
             }
         }
         for url in &fetch.urls {
-            if !is_torrent_url(url) {
+            if !is_torrent_url(url) && !skip_upstream(url) {
                 prioritized_urls.push(url.as_str());
             }
         }
+        let mut webseeds: Vec<String> = Vec::new();
+        for magnet in index_magnet.iter().chain(&fetch.urls) {
+            for webseed in magnet_webseeds(magnet, &fetch.filename) {
+                if !fetch.urls.contains(&webseed) && !webseeds.contains(&webseed) {
+                    webseeds.push(webseed);
+                }
+            }
+        }
+        prioritized_urls.extend(webseeds.iter().map(String::as_str));
+        prioritized_urls.into_iter().map(str::to_string).collect()
+    }
 
-        let mut last_err: Option<MagError> = None;
+    /// HTTP(S) webseeds in the `url-list` of the `.torrent` files among
+    /// `fetch`'s URLs. The torrent client downloads each file again, and one
+    /// that cannot be read only contributes no webseeds.
+    fn torrent_file_webseeds(&self, fetch: &FetchResource) -> Vec<String> {
+        let mut webseeds = Vec::new();
+        for url in &fetch.urls {
+            let Ok(parsed) = Url::parse(url.trim()) else {
+                continue;
+            };
+            if !parsed.path().to_ascii_lowercase().ends_with(".torrent") {
+                continue;
+            }
+            let bytes = match parsed.scheme() {
+                "file" => file_url_to_path(&parsed).and_then(|path| Ok(fs::read(path)?)),
+                "http" | "https" => self
+                    .host_limits
+                    .acquire(&parsed, &AtomicBool::new(false))
+                    .and_then(|_permit| {
+                        let response = self
+                            .fetch_auth
+                            .send(&parsed, |url| self.fetch_client.get(url))?
+                            .error_for_status()
+                            .map_err(|err| MagError::Generic(err.to_string()))?;
+                        let bytes = response
+                            .bytes()
+                            .map_err(|err| MagError::Generic(err.to_string()))?;
+                        Ok(bytes.to_vec())
+                    }),
+                _ => continue,
+            };
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    eprintln!("warning: could not read webseeds from {url}: {err}");
+                    continue;
+                }
+            };
+            let (listed, name) = torrent_webseeds(&bytes);
+            let name = name.unwrap_or_else(|| fetch.filename.clone());
+            for webseed in http_webseeds(listed, &name) {
+                if !fetch.urls.contains(&webseed) && !webseeds.contains(&webseed) {
+                    webseeds.push(webseed);
+                }
+            }
+        }
+        webseeds
+    }
 
-        for url in prioritized_urls {
-            eprintln!("fetching {} from {}", fetch.filename, url);
-            let outcome = self.fetch_url(fetch, url, dest);
+    /// Download from up to `width` sources at once and keep the first copy
+    /// whose sha256 verifies; the others are cancelled. Sources further down
+    /// the list start as earlier ones fail, so `width == 1` tries them in
+    /// order.
+    fn race_sources<'a>(
+        &self,
+        fetch: &FetchResource,
+        urls: &[&'a str],
+        dest: &Path,
+        width: usize,
+    ) -> MagResult<(&'a str, DownloadOutcome)> {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let width = width.clamp(1, urls.len().max(1));
+
+        thread::scope(|scope| {
+            let (tx, rx) = std_mpsc::channel();
+            let launch = |index: usize| {
+                let url = urls[index];
+                let tx = tx.clone();
+                let cancel = cancel.clone();
+                let source_dest =
+                    dest.with_file_name(format!("{}{RACE_SOURCE_MARKER}{index}", fetch.sha256));
+                eprintln!("fetching {} from {url}", fetch.filename);
+                scope.spawn(move || {
+                    let result = self.fetch_source(fetch, url, &source_dest, &cancel);
+                    let _ = tx.send((url, result));
+                });
+            };
 
-            match outcome {
-                Ok(mut download) => {
-                    let tmp_path = download.path.clone();
-                    let hash_ok = verify_sha256(&tmp_path, &fetch.sha256)?;
-                    if !hash_ok {
-                        last_err = Some(MagError::Generic(format!(
-                            "SHA mismatch for {}",
-                            fetch.filename
-                        )));
-                        let _ = fs::remove_file(&tmp_path);
-                        if let Some(_info) = download.torrent.take() {
-                            // nothing to persist when hash fails; drop bytes
-                        }
-                        continue;
-                    }
+            let mut next = 0;
+            let mut running = 0;
+            while next < urls.len() && running < width {
+                launch(next);
+                next += 1;
+                running += 1;
+            }
 
-                    if dest.exists() {
-                        fs::remove_file(dest)?;
+            let mut winner = None;
+            let mut last_err = None;
+            while running > 0 {
+                let Ok((url, result)) = rx.recv() else {
+                    break;
+                };
+                running -= 1;
+                match result {
+                    Ok(outcome) if winner.is_none() => {
+                        cancel.store(true, Ordering::SeqCst);
+                        winner = Some((url, outcome));
+                    }
+                    Ok(outcome) => {
+                        let _ = fs::remove_file(&outcome.path);
+                    }
+                    Err(_) if winner.is_some() => {}
+                    Err(err) => {
+                        eprintln!("fetch of {} from {url} failed: {err}", fetch.filename);
+                        last_err = Some(err);
+                        if next < urls.len() {
+                            launch(next);
+                            next += 1;
+                            running += 1;
+                        }
                     }
-                    fs::rename(&tmp_path, dest)?;
-                    File::open(dest)?.sync_all()?;
-                    let final_path = dest.to_path_buf();
-                    eprintln!("fetch complete: {} ({})", fetch.filename, fetch.sha256);
-                    touch_path(&final_path)?;
-
-                    let torrent_info = match download.torrent.take() {
-                        Some(info) => info,
-                        None => self.create_torrent_for_file(fetch, &final_path)?,
-                    };
-                    self.write_torrent_artifacts(fetch, &final_path, &torrent_info)?;
-                    return Ok(final_path);
-                }
-                Err(err) => {
-                    last_err = Some(err);
                 }
             }
-        }
 
-        Err(last_err
-            .unwrap_or_else(|| MagError::Generic(format!("failed to fetch {}", fetch.filename))))
+            // Every source failed: report the last failure as a fetch
+            // failure, so that it is classified like any other.
+            winner.ok_or_else(|| match last_err {
+                Some(err @ (MagError::Interrupted(_) | MagError::FetchFailure { .. })) => err,
+                Some(err) => MagError::FetchFailure {
+                    filename: fetch.filename.clone(),
+                    message: err.to_string(),
+                },
+                None => MagError::FetchFailure {
+                    filename: fetch.filename.clone(),
+                    message: "no source succeeded".into(),
+                },
+            })
+        })
+    }
+
+    fn fetch_source(
+        &self,
+        fetch: &FetchResource,
+        url: &str,
+        dest: &Path,
+        cancel: &Arc<AtomicBool>,
+    ) -> MagResult<DownloadOutcome> {
+        let download = self.fetch_url(fetch, url, dest, cancel)?;
+        let actual = sha256_file(&download.path)?;
+        if !actual.eq_ignore_ascii_case(fetch.sha256.trim()) {
+            let _ = fs::remove_file(&download.path);
+            return Err(MagError::FetchHashMismatch {
+                filename: fetch.filename.clone(),
+                expected: fetch.sha256.clone(),
+                actual,
+            });
+        }
+        Ok(download)
     }
 
     fn refresh_torrent_artifacts(&self, fetch: &FetchResource, dest: &Path) -> MagResult<()> {
@@ -837,7 +4158,7 @@ impl PackageStore {
             if let Some(info_hash) = info_hash_from_url(url)? {
                 let dir = self.torrent_root.join(&info_hash);
                 if self.touch_torrent_dir_path(&dir, dest)? {
-                    return Ok(());
+                    return write_torrent_sources(&dir, fetch);
                 }
             }
         }
@@ -847,7 +4168,19 @@ impl PackageStore {
         }
 
         let torrent_info = self.create_torrent_for_file(fetch, dest)?;
-        self.write_torrent_artifacts(fetch, dest, &torrent_info)
+        self.write_fetch_torrent(fetch, dest, &torrent_info)
+    }
+
+    /// Register a fetched source for seeding and remember where else it can
+    /// be downloaded from, so announced magnet links can carry webseeds.
+    fn write_fetch_torrent(
+        &self,
+        fetch: &FetchResource,
+        data_path: &Path,
+        info: &TorrentInfo,
+    ) -> MagResult<()> {
+        self.write_torrent_artifacts(data_path, info)?;
+        write_torrent_sources(&self.torrent_root.join(&info.info_hash), fetch)
     }
 
     fn touch_torrent_dir_path(&self, dir: &Path, source_path: &Path) -> MagResult<bool> {
@@ -873,6 +4206,7 @@ impl PackageStore {
         let data_path = dir.join(&relative_path);
         if !data_path.exists() {
             copy_file_atomically(source_path, &data_path)?;
+            remove_evicted_marker(dir)?;
         } else {
             touch_path(&data_path)?;
         }
@@ -886,6 +4220,7 @@ impl PackageStore {
         fetch: &FetchResource,
         url: &str,
         dest: &Path,
+        cancel: &Arc<AtomicBool>,
     ) -> MagResult<DownloadOutcome> {
         if is_torrent_url(url) {
             let fetcher = self.torrent_fetcher()?;
@@ -902,6 +4237,7 @@ impl PackageStore {
                 sha256: fetch.sha256.clone(),
                 filename: fetch.filename.clone(),
                 dest: tmp_dest.clone(),
+                cancel: Some(cancel.clone()),
             };
 
             let download = fetcher.download(request)?;
@@ -920,18 +4256,46 @@ impl PackageStore {
                 match parsed.scheme() {
                     "file" => {
                         let path = file_url_to_path(&parsed)?;
-                        write_stream_with_feedback(File::open(path)?, temp_file, None, None)
+                        write_stream_with_feedback(
+                            File::open(path)?,
+                            temp_file,
+                            None,
+                            None,
+                            Some(cancel),
+                        )
                     }
                     "http" | "https" => {
-                        let mut response = self.client.get(parsed.clone()).send()?;
-                        if !response.status().is_success() {
+                        let _permit = self.host_limits.acquire(&parsed, cancel)?;
+                        let mut response = self
+                            .fetch_auth
+                            .send(&parsed, |url| self.fetch_client.get(url))?;
+                        let status = response.status();
+                        if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                            || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+                        {
+                            if let Some(delay) = response
+                                .headers()
+                                .get(reqwest::header::RETRY_AFTER)
+                                .and_then(|value| value.to_str().ok())
+                                .and_then(retry_after)
+                            {
+                                self.host_limits.back_off(&parsed, delay);
+                            }
+                        }
+                        if !status.is_success() {
                             return Err(MagError::Generic(format!(
                                 "failed to download {url}: HTTP {}",
                                 response.status()
                             )));
                         }
                         let total = response.content_length();
-                        write_stream_with_feedback(&mut response, temp_file, Some(url), total)
+                        write_stream_with_feedback(
+                            &mut response,
+                            temp_file,
+                            Some(url),
+                            total,
+                            Some(cancel),
+                        )
                     }
                     other => Err(MagError::Generic(format!(
                         "unsupported fetch URL scheme: {other}"
@@ -942,7 +4306,7 @@ impl PackageStore {
                 if !path.exists() {
                     return Err(MagError::Generic(format!("fetch source not found: {url}")));
                 }
-                write_stream_with_feedback(File::open(path)?, temp_file, None, None)
+                write_stream_with_feedback(File::open(path)?, temp_file, None, None, Some(cancel))
             };
 
             match result {
@@ -962,6 +4326,25 @@ impl PackageStore {
         &self,
         fetch: &FetchResource,
         path: &Path,
+    ) -> MagResult<TorrentInfo> {
+        let webseeds: Vec<String> = fetch
+            .urls
+            .iter()
+            .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+            .cloned()
+            .collect();
+        self.create_torrent_with_webseeds(&fetch.filename, path, &webseeds)
+    }
+
+    fn create_torrent_named(&self, name: &str, path: &Path) -> MagResult<TorrentInfo> {
+        self.create_torrent_with_webseeds(name, path, &[])
+    }
+
+    fn create_torrent_with_webseeds(
+        &self,
+        name: &str,
+        path: &Path,
+        webseeds: &[String],
     ) -> MagResult<TorrentInfo> {
         let runtime = TokioRuntimeBuilder::new_current_thread()
             .enable_all()
@@ -972,43 +4355,32 @@ impl PackageStore {
             .block_on(create_torrent(
                 path,
                 CreateTorrentOptions {
-                    name: Some(&fetch.filename),
-                    piece_length: Some(4 * 1024 * 1024),
+                    name: Some(name),
+                    piece_length: Some(self.torrent_config.piece_length),
                 },
             ))
             .map_err(|err| {
-                MagError::Generic(format!(
-                    "failed to create torrent for {}: {err:#}",
-                    fetch.filename
-                ))
+                MagError::Generic(format!("failed to create torrent for {name}: {err:#}"))
             })?;
 
         drop(runtime);
 
-        let bytes = result
-            .as_bytes()
-            .map_err(|err| {
-                MagError::Generic(format!(
-                    "failed to serialize torrent for {}: {err:#}",
-                    fetch.filename
-                ))
-            })?
-            .to_vec();
+        let bytes = result.as_bytes().map_err(|err| {
+            MagError::Generic(format!("failed to serialize torrent for {name}: {err:#}"))
+        })?;
+        let bytes = strip_volatile_keys(&bytes);
+        let bytes = inject_trackers(&bytes, self.torrent_config.announce_trackers());
+        let bytes = inject_webseeds(&bytes, webseeds);
         let info_hash = info_hash_to_hex(result.info_hash());
 
         Ok(TorrentInfo {
             info_hash,
-            relative_path: PathBuf::from(&fetch.filename),
+            relative_path: PathBuf::from(name),
             torrent_bytes: bytes,
         })
     }
 
-    fn write_torrent_artifacts(
-        &self,
-        _fetch: &FetchResource,
-        data_path: &Path,
-        info: &TorrentInfo,
-    ) -> MagResult<()> {
+    fn write_torrent_artifacts(&self, data_path: &Path, info: &TorrentInfo) -> MagResult<()> {
         let torrent_dir = self.torrent_root.join(&info.info_hash);
         fs::create_dir_all(&torrent_dir)?;
 
@@ -1024,6 +4396,7 @@ impl PackageStore {
 
         let copy_path = torrent_dir.join(&info.relative_path);
         copy_file_atomically(data_path, &copy_path)?;
+        remove_evicted_marker(&torrent_dir)?;
         touch_path(&torrent_dir)?;
         Ok(())
     }
@@ -1033,10 +4406,70 @@ impl PackageStore {
             .join(format!("{}.tar.zst", package_base_name(package)))
     }
 
+    /// Whether the package artifact completed the publish protocol. Readers
+    /// must rely on this rather than on the artifact file merely existing.
+    pub fn is_artifact_published(&self, package: &Package) -> bool {
+        artifact_is_published(&self.package_artifact_path(package))
+    }
+
+    /// The paths a built package provides, from `pkgs/<base>.files`. The
+    /// index is created from the artifact on first use.
+    pub fn file_index(&self, package: &Package) -> MagResult<FileIndex> {
+        let artifact = self.package_artifact_path(package);
+        let sha256 = published_sha256(&artifact)
+            .filter(|_| artifact_is_published(&artifact))
+            .ok_or_else(|| {
+                MagError::Generic(format!(
+                    "{} has not been built; run `magpkg build` first",
+                    package_base_name(package)
+                ))
+            })?;
+        let index_path = self
+            .store_root
+            .join(format!("{}{FILE_INDEX_SUFFIX}", package_base_name(package)));
+        FileIndex::load(&artifact, &index_path, &sha256)
+    }
+
+    /// Write the contents of one regular file from a built package's
+    /// artifact to `out`. Only the zstd frames holding the file are
+    /// decompressed.
+    pub fn copy_artifact_file<W: Write + ?Sized>(
+        &self,
+        package: &Package,
+        entry: &IndexedPath,
+        out: &mut W,
+    ) -> MagResult<()> {
+        let Some((offset, size)) = entry.contents else {
+            let kind = match entry.kind {
+                PathKind::File => "a hard link",
+                PathKind::Dir => "a directory",
+                PathKind::Symlink => "a symlink",
+                PathKind::Other => "a special file",
+            };
+            return Err(MagError::Generic(format!(
+                "{} in {} is {kind}, not a regular file",
+                entry.path,
+                package_base_name(package)
+            )));
+        };
+        let artifact = self.package_artifact_path(package);
+        copy_artifact_range(&artifact, offset, size, out).map_err(|err| {
+            MagError::Generic(format!(
+                "failed to read {} from {}: {err}",
+                entry.path,
+                artifact.display()
+            ))
+        })
+    }
+
+    /// Write the merged runtime closure of `packages` as an uncompressed tar.
+    /// With `provenance`, the record of each package built by this store is
+    /// added under `usr/share/magpkg/provenance/<base>.json`.
     pub fn export_runtime_closure_tarball<W: Write>(
         &self,
         packages: &[Rc<Package>],
         writer: &mut W,
+        provenance: bool,
     ) -> MagResult<()> {
         let mut visited = HashSet::new();
         let mut order = Vec::new();
@@ -1046,27 +4479,77 @@ impl PackageStore {
 
         let temp_dir = TempDirBuilder::new().prefix("magpkg-export-").tempdir()?;
 
-        for package in order {
-            let artifact = self.package_artifact_path(package.as_ref());
-            if !artifact.exists() {
-                return Err(MagError::Generic(format!(
-                    "missing artifact for package {}",
-                    package.hash
-                )));
+        for package in &order {
+            if !self.is_artifact_published(package.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "package",
+                    package: package.hash.clone(),
+                });
+            }
+            self.link_artifact(package.as_ref(), temp_dir.path())?;
+        }
+
+        if provenance {
+            let dir = temp_dir.path().join(EXPORT_PROVENANCE_DIR);
+            fs::create_dir_all(&dir)?;
+            let mut missing = 0usize;
+            for package in &order {
+                let base = package_base_name(package.as_ref());
+                match self.package_provenance(&base)? {
+                    Some(record) => {
+                        fs::write(dir.join(format!("{base}.json")), format!("{record:#}\n"))?
+                    }
+                    None => missing += 1,
+                }
+            }
+            if missing > 0 {
+                eprintln!(
+                    "note: {missing} package(s) were not built by this store and have no provenance record"
+                );
             }
-            extract_tar_zst(&artifact, temp_dir.path())?;
         }
 
         {
             let mut builder = Builder::new(&mut *writer);
-            builder.follow_symlinks(false);
-            builder.append_dir_all(".", temp_dir.path())?;
+            append_tree(&mut builder, temp_dir.path(), None)?;
             builder.finish()?;
         }
         writer.flush()?;
         Ok(())
     }
 
+    /// Write the runtime closure of `packages` as one zstd-compressed tar
+    /// by joining the artifacts' compressed frames in closure order, without
+    /// unpacking them. Entries of later packages follow those of earlier
+    /// ones, so extracting the result overwrites files the same way
+    /// [`Self::export_runtime_closure_tarball`] merges them.
+    pub fn export_runtime_closure_concat<W: Write>(
+        &self,
+        packages: &[Rc<Package>],
+        writer: W,
+    ) -> MagResult<W> {
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for pkg in packages {
+            collect_runtime_closure(pkg.clone(), &mut visited, &mut order);
+        }
+
+        let mut concat = TarConcatenator::new(writer, self.compression);
+        for package in order {
+            if !self.is_artifact_published(package.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "package",
+                    package: package.hash.clone(),
+                });
+            }
+            let artifact = self.package_artifact_path(package.as_ref());
+            concat.append_artifact(&artifact).map_err(|err| {
+                MagError::Generic(format!("failed to copy {}: {err}", artifact.display()))
+            })?;
+        }
+        Ok(concat.finish()?)
+    }
+
     pub fn export_runtime_closure_rootfs(
         &self,
         packages: &[Rc<Package>],
@@ -1081,14 +4564,13 @@ impl PackageStore {
         clear_directory(dest)?;
 
         for package in order {
-            let artifact = self.package_artifact_path(package.as_ref());
-            if !artifact.exists() {
-                return Err(MagError::Generic(format!(
-                    "missing artifact for package {}",
-                    package.hash
-                )));
+            if !self.is_artifact_published(package.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "package",
+                    package: package.hash.clone(),
+                });
             }
-            extract_tar_zst(&artifact, dest)?;
+            self.install_artifact(package.as_ref(), dest)?;
         }
 
         for dir in ["home", "tmp", "proc", "dev"] {
@@ -1100,6 +4582,183 @@ impl PackageStore {
 
         Ok(())
     }
+
+    /// Prepare `venv_dir` for a venv whose packages are mounted rather than
+    /// copied: the runtime closure is extracted into `unpacked/`, recorded
+    /// in `layers` in install order, and `rootfs/` is left as a top layer
+    /// holding only the base directories. Returns false, with nothing
+    /// written, when the closure has too many packages for one overlay
+    /// mount.
+    pub fn export_runtime_closure_layers(
+        &self,
+        packages: &[Rc<Package>],
+        venv_dir: &Path,
+    ) -> MagResult<bool> {
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for pkg in packages {
+            collect_runtime_closure(pkg.clone(), &mut visited, &mut order);
+        }
+
+        let top = venv_dir.join("rootfs");
+        let mut options_len = OVERLAY_SOURCE_PREFIX.len() + top.as_os_str().len();
+        for package in &order {
+            options_len += OVERLAY_SOURCE_PREFIX.len()
+                + self.unpacked_root.join(&package.hash).as_os_str().len()
+                + 1;
+        }
+        if options_len > OVERLAY_OPTIONS_MAX {
+            return Ok(false);
+        }
+
+        let mut layers = String::new();
+        for package in &order {
+            if !self.is_artifact_published(package.as_ref()) {
+                return Err(MagError::MissingArtifact {
+                    role: "package",
+                    package: package.hash.clone(),
+                });
+            }
+            self.unpacked_artifact(package.as_ref())?;
+            layers.push_str(&format!(
+                "{}\t{}\n",
+                package.hash,
+                package_base_name(package.as_ref())
+            ));
+        }
+
+        for dir in ["home", "tmp", "proc", "dev"] {
+            fs::create_dir_all(top.join(dir))?;
+        }
+        let tmp_path = venv_dir.join(format!(".{VENV_LAYERS_FILE}.tmp"));
+        fs::write(&tmp_path, layers)?;
+        fs::rename(&tmp_path, venv_dir.join(VENV_LAYERS_FILE))?;
+        Ok(true)
+    }
+
+    /// The extracted package trees of a venv prepared by
+    /// [`Self::export_runtime_closure_layers`], lowest layer first, or `None`
+    /// for a venv with a copied rootfs. Trees removed by cleanup since are
+    /// extracted again, and each is held with a shared lock so that cleanup
+    /// leaves it alone while the venv runs.
+    pub fn venv_layers(&self, venv_dir: &Path) -> MagResult<Option<VenvLayers<'_>>> {
+        let path = venv_dir.join(VENV_LAYERS_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut layers = VenvLayers {
+            dirs: Vec::new(),
+            _locks: Vec::new(),
+            _leases: Vec::new(),
+        };
+        for line in contents.lines() {
+            let Some((hash, base)) = line.split_once('\t') else {
+                return Err(MagError::Generic(format!(
+                    "{}: invalid layer entry {line:?}",
+                    path.display()
+                )));
+            };
+            let artifact = self.store_root.join(format!("{base}.tar.zst"));
+            if !artifact_is_published(&artifact) {
+                return Err(MagError::MissingArtifact {
+                    role: "venv layer",
+                    package: hash.to_string(),
+                });
+            }
+            let dir = self.unpacked_tree(hash, &artifact)?;
+            let lock_file = File::create(
+                self.unpacked_root
+                    .join(format!("{hash}{FETCH_LOCK_SUFFIX}")),
+            )?;
+            FileExt::lock_shared(&lock_file)?;
+            if let Some(lease) = self.lease_shared(&format!("unpack {hash}"))? {
+                layers._leases.push(lease);
+            }
+            layers.dirs.push(dir);
+            layers._locks.push(lock_file);
+        }
+        Ok(Some(layers))
+    }
+}
+
+/// What a published package was built from, read from `<base>.deps`.
+#[derive(Debug, Clone, Default)]
+pub struct DependencyRecord {
+    pub hash: String,
+    /// The package name, which for an extra output is its owner's; empty
+    /// for unnamed packages.
+    pub name: String,
+    /// Base names of runtime dependencies.
+    pub run: Vec<String>,
+    /// Base names of build-only dependencies.
+    pub build: Vec<String>,
+    /// Sha256 digests of the fetched sources.
+    pub fetches: Vec<String>,
+}
+
+impl DependencyRecord {
+    /// The record as written to `<base>.deps`.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("hash\t{}\n", self.hash);
+        if !self.name.is_empty() {
+            text.push_str(&format!("name\t{}\n", self.name));
+        }
+        for dep in &self.run {
+            text.push_str(&format!("run\t{dep}\n"));
+        }
+        for dep in &self.build {
+            text.push_str(&format!("build\t{dep}\n"));
+        }
+        for sha in &self.fetches {
+            text.push_str(&format!("fetch\t{sha}\n"));
+        }
+        text
+    }
+}
+
+/// Package trees mounted as the lower layers of a venv's root overlay.
+pub struct VenvLayers<'a> {
+    /// `unpacked/<hash>` directories, lowest layer first.
+    pub dirs: Vec<PathBuf>,
+    _locks: Vec<File>,
+    _leases: Vec<LeaseGuard<'a>>,
+}
+
+/// Held while a venv's rootfs is prepared.
+pub struct VenvPrepareLock<'a> {
+    _hold: LockHold<'a>,
+    _file: File,
+}
+
+/// The lease label for the venv `<hash>`, taken shared while it runs and
+/// exclusively while reclaim removes it.
+pub fn venv_lease_label(hash: &str) -> String {
+    format!("venv {hash}")
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and cannot fail.
+    unsafe { libc::getuid() }
+}
+
+pub fn store_base_root() -> MagResult<PathBuf> {
+    if let Some(custom) = env::var_os("MAGPKG_STORE") {
+        return Ok(PathBuf::from(custom));
+    }
+    let home = env::var_os("HOME")
+        .ok_or_else(|| MagError::Generic("HOME environment variable is not set".into()))?;
+    Ok(PathBuf::from(home).join(".magpkg"))
+}
+
+/// Forget that `seed --max-storage` evicted a torrent's payload once the
+/// payload is back.
+fn remove_evicted_marker(torrent_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(torrent_dir.join(btseed::EVICTED_MARKER)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
 }
 
 fn copy_file_atomically(src: &Path, dest: &Path) -> MagResult<()> {
@@ -1163,117 +4822,425 @@ fn info_hash_from_url(url: &str) -> MagResult<Option<String>> {
     Ok(None)
 }
 
-fn file_url_to_path(url: &Url) -> MagResult<PathBuf> {
-    if url.scheme() != "file" {
-        return Err(MagError::Generic(format!("expected file URL, got {}", url)));
-    }
+/// HTTP(S) webseeds (`ws` parameters, BEP 19) of a magnet link, usable as
+/// plain download URLs. A webseed ending in `/` names a directory, so the
+/// magnet's display name, or `filename`, is appended.
+fn magnet_webseeds(url: &str, filename: &str) -> Vec<String> {
+    let Ok(parsed) = Url::parse(url.trim()) else {
+        return Vec::new();
+    };
+    if parsed.scheme() != "magnet" {
+        return Vec::new();
+    }
+    let name = parsed
+        .query_pairs()
+        .find(|(key, _)| key == "dn")
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_else(|| filename.to_string());
+    let listed = parsed
+        .query_pairs()
+        .filter(|(key, _)| key == "ws")
+        .map(|(_, value)| value.into_owned());
+    http_webseeds(listed, &name)
+}
+
+/// The HTTP(S) URLs among `webseeds`, with `name` appended to those ending
+/// in `/`.
+fn http_webseeds(webseeds: impl IntoIterator<Item = String>, name: &str) -> Vec<String> {
+    webseeds
+        .into_iter()
+        .filter_map(|webseed| Url::parse(&webseed).ok())
+        .filter(|webseed| matches!(webseed.scheme(), "http" | "https"))
+        .filter_map(|webseed| {
+            if webseed.path().ends_with('/') {
+                webseed.join(name).ok()
+            } else {
+                Some(webseed)
+            }
+        })
+        .map(String::from)
+        .collect()
+}
+
+fn file_url_to_path(url: &Url) -> MagResult<PathBuf> {
+    if url.scheme() != "file" {
+        return Err(MagError::Generic(format!("expected file URL, got {}", url)));
+    }
+
+    if let Some(host) = url.host_str() {
+        if !host.is_empty() && host != "localhost" {
+            return Err(MagError::Generic(format!(
+                "unsupported file URL host: {host}"
+            )));
+        }
+    }
+
+    let path = url
+        .to_file_path()
+        .map_err(|_| MagError::Generic(format!("invalid file URL: {url}")))?;
+
+    if !path.is_absolute() {
+        return Err(MagError::Generic(
+            "file URLs must reference absolute paths".into(),
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Compare a finished output tree against the package's declared budget.
+fn check_output_budget(package: &Package, out_dir: &Path) -> MagResult<()> {
+    let budget = &package.budget;
+    if budget.max_bytes.is_none() && budget.max_files.is_none() {
+        return Ok(());
+    }
+
+    let mut usage = AreaStats::default();
+    accumulate_usage(out_dir, &mut usage)?;
+
+    let mut exceeded = Vec::new();
+    if let Some(max_bytes) = budget.max_bytes.filter(|max| usage.bytes > *max) {
+        exceeded.push(format!("{} bytes (budget {max_bytes})", usage.bytes));
+    }
+    if let Some(max_files) = budget.max_files.filter(|max| usage.files > *max) {
+        exceeded.push(format!("{} files (budget {max_files})", usage.files));
+    }
+    if exceeded.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "output of {} exceeds its budget: {}",
+        package_base_name(package),
+        exceeded.join(", ")
+    );
+    match budget.policy {
+        BudgetPolicy::Warn => {
+            eprintln!("warning: {message}");
+            Ok(())
+        }
+        BudgetPolicy::Fail => Err(MagError::Generic(message)),
+    }
+}
+
+/// Open an interactive shell inside a preserved build tree using the same
+/// sandbox layout as the build itself.
+pub fn run_debug_shell(failed_root: &Path) -> MagResult<()> {
+    let rootfs = failed_root.join("rootfs");
+    if !rootfs.is_dir() {
+        return Err(MagError::Generic(format!(
+            "{} has no sandbox rootfs to enter (untar builds run outside the sandbox)",
+            failed_root.display()
+        )));
+    }
+    check_executable_in_root(&rootfs, DEFAULT_BUILDER_SHELL).map_err(|detail| {
+        MagError::Generic(format!(
+            "cannot open a debug shell in {}: {detail}",
+            rootfs.display()
+        ))
+    })?;
+    require_sandbox("debug-build")?;
+
+    let mut cmd = Command::new("bwrap");
+    cmd.arg("--unshare-net")
+        .arg("--bind")
+        .arg(&rootfs)
+        .arg("/")
+        .arg("--dev-bind")
+        .arg("/dev")
+        .arg("/dev")
+        .arg("--proc")
+        .arg("/proc")
+        .arg("--clearenv");
+    cmd.arg("--setenv").arg("PATH").arg(SANDBOX_PATH.join(":"));
+    cmd.arg("--setenv").arg("SHELL").arg("/bin/sh");
+    cmd.arg("--setenv").arg("HOME").arg("/build");
+    if let Ok(term) = std::env::var("TERM") {
+        cmd.arg("--setenv").arg("TERM").arg(term);
+    }
+    cmd.arg("--chdir").arg("/build");
+    cmd.arg("/bin/sh");
+
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(MagError::CommandFailure {
+            context: "debug shell".into(),
+            status: status.code().unwrap_or(-1),
+        });
+    }
+    Ok(())
+}
+
+/// Check that `path` names an executable file inside `root`, following
+/// symlinks the way the sandbox would see them. On failure, describe what is
+/// missing, including the symlinks that were followed.
+fn check_executable_in_root(root: &Path, path: &str) -> Result<(), String> {
+    let mut pending: Vec<PathBuf> = Vec::new();
+    let push_reversed = |pending: &mut Vec<PathBuf>, path: &Path| {
+        let parts: Vec<PathBuf> = path
+            .components()
+            .filter_map(|component| match component {
+                std::path::Component::Normal(part) => Some(PathBuf::from(part)),
+                std::path::Component::ParentDir => Some(PathBuf::from("..")),
+                _ => None,
+            })
+            .collect();
+        pending.extend(parts.into_iter().rev());
+    };
+    push_reversed(&mut pending, Path::new(path));
 
-    if let Some(host) = url.host_str() {
-        if !host.is_empty() && host != "localhost" {
-            return Err(MagError::Generic(format!(
-                "unsupported file URL host: {host}"
-            )));
+    let mut current = PathBuf::new();
+    let mut followed = Vec::new();
+    let via = |followed: &[String]| {
+        if followed.is_empty() {
+            String::new()
+        } else {
+            format!(" (following {})", followed.join(", "))
+        }
+    };
+    while let Some(part) = pending.pop() {
+        if part.as_os_str() == ".." {
+            current.pop();
+            continue;
+        }
+        let candidate = current.join(&part);
+        match fs::symlink_metadata(root.join(&candidate)) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                if followed.len() >= 40 {
+                    return Err(format!("{path} is a symlink loop"));
+                }
+                let target = fs::read_link(root.join(&candidate)).map_err(|err| err.to_string())?;
+                followed.push(format!("/{} -> {}", candidate.display(), target.display()));
+                if target.is_absolute() {
+                    current = PathBuf::new();
+                }
+                push_reversed(&mut pending, &target);
+            }
+            Ok(_) => current = candidate,
+            Err(_) => {
+                return Err(format!(
+                    "/{} does not exist{}",
+                    candidate.display(),
+                    via(&followed)
+                ));
+            }
         }
     }
 
-    let path = url
-        .to_file_path()
-        .map_err(|_| MagError::Generic(format!("invalid file URL: {url}")))?;
-
-    if !path.is_absolute() {
-        return Err(MagError::Generic(
-            "file URLs must reference absolute paths".into(),
-        ));
+    match fs::metadata(root.join(&current)) {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => Ok(()),
+        _ => Err(format!(
+            "/{} is not an executable file{}",
+            current.display(),
+            via(&followed)
+        )),
     }
+}
 
-    Ok(path)
+/// Where a `sandbox.fakeroot` build saves its fakeroot database, relative
+/// to the build root.
+const FAKEROOT_STATE_PATH: &str = "tmp/.magpkg-fakeroot-state";
+
+const SANDBOX_PATH: [&str; 6] = [
+    "/usr/bin",
+    "/bin",
+    "/store/bin",
+    "/store/sbin",
+    "/usr/sbin",
+    "/sbin",
+];
+
+/// What runs inside a build sandbox.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SandboxEntry {
+    /// The package's build script and hooks, with stdin closed.
+    Script { skip_check: bool },
+    /// An interactive shell in `/build` on the terminal, for `magpkg shell`.
+    /// The build script and hooks are still placed at their usual paths.
+    Shell,
 }
 
-fn run_bwrap_build(package: &Package, rootfs: &Path, parallelism: usize) -> MagResult<()> {
+fn run_bwrap_build(
+    package: &Package,
+    rootfs: &Path,
+    shell: &SandboxShell,
+    parallelism: usize,
+    scheduling: &Scheduling,
+    entry: SandboxEntry,
+) -> MagResult<()> {
     let script = package.build.as_str();
-    if script.is_empty() {
+    let is_script = matches!(entry, SandboxEntry::Script { .. });
+    if script.is_empty() && is_script {
         return Ok(());
     }
+    require_sandbox("building a package")?;
+
+    let fakeroot = if package.sandbox.fakeroot {
+        match fs::remove_file(rootfs.join(FAKEROOT_STATE_PATH)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let fakeroot = SANDBOX_PATH
+            .iter()
+            .map(|dir| format!("{dir}/fakeroot"))
+            .find(|path| check_executable_in_root(rootfs, path).is_ok())
+            .ok_or_else(|| {
+                MagError::Generic(format!(
+                    "sandbox.fakeroot of {} needs fakeroot in the build root; add the package providing it to buildDeps",
+                    package_base_name(package)
+                ))
+            })?;
+        Some(fakeroot)
+    } else {
+        None
+    };
+
+    let build_env = resolve_build_env(package)?;
 
     let build_root = rootfs.parent().ok_or_else(|| {
         MagError::Generic("rootfs directory missing parent for build script staging".into())
     })?;
-    let script_host_path = build_root.join(format!(
-        ".magpkg-build-script-{}-{}",
-        package.hash,
-        std::process::id()
-    ));
-
-    {
-        let mut file = File::create(&script_host_path)?;
-        file.write_all(script.as_bytes())?;
-        if !script.ends_with('\n') {
-            file.write_all(b"\n")?;
+    let mut staged = StagedScripts::new(build_root, package);
+    staged.stage(
+        BUILD_SCRIPT_PATH,
+        &resolve_build_script(script, "build", &package.build_deps)?,
+    )?;
+    if !package.hooks.is_empty() {
+        for (phase, hook) in package.hooks.phases() {
+            if !hook.is_empty() {
+                staged.stage(
+                    &hook_script_path(phase),
+                    &resolve_build_script(hook, phase, &package.build_deps)?,
+                )?;
+            }
         }
-        file.sync_all()?;
+        let skip_check = matches!(entry, SandboxEntry::Script { skip_check: true });
+        staged.stage(
+            BUILD_PHASES_PATH,
+            &build_phases_script(package, shell, skip_check),
+        )?;
     }
-    let mut perms = fs::metadata(&script_host_path)?.permissions();
-    perms.set_mode(0o700);
-    fs::set_permissions(&script_host_path, perms)?;
-
-    let script_container_path = "/tmp/.magpkg-build-script";
 
+    let sandbox = &package.sandbox;
     let mut cmd = Command::new("bwrap");
-    cmd.arg("--unshare-net")
+    if is_script {
+        cmd.stdin(Stdio::null());
+    }
+    cmd.arg("--die-with-parent")
+        .arg("--unshare-net")
         .arg("--bind")
         .arg(rootfs)
-        .arg("/")
-        .arg("--dev-bind")
-        .arg("/dev")
-        .arg("/dev")
-        .arg("--proc")
-        .arg("/proc")
-        .arg("--clearenv")
-        .arg("--ro-bind")
-        .arg(&script_host_path)
-        .arg(script_container_path);
-
-    let path_segments = [
-        "/usr/bin",
-        "/bin",
-        "/store/bin",
-        "/store/sbin",
-        "/usr/sbin",
-        "/sbin",
-    ];
-    let path_value = path_segments.join(":");
+        .arg("/");
+    if sandbox.host_dev.unwrap_or(true) {
+        cmd.arg("--dev-bind").arg("/dev").arg("/dev");
+    } else {
+        cmd.arg("--dev").arg("/dev");
+    }
+    cmd.arg("--proc").arg("/proc").arg("--clearenv");
+    for (host_path, container_path) in &staged.files {
+        cmd.arg("--ro-bind").arg(host_path).arg(container_path);
+    }
+
+    for bind in &sandbox.ro_binds {
+        if !Path::new(bind).exists() {
+            return Err(MagError::Generic(format!(
+                "sandbox.roBinds path {bind} does not exist on this host"
+            )));
+        }
+        cmd.arg("--ro-bind").arg(bind).arg(bind);
+    }
+    if sandbox.uid.is_some() || sandbox.gid.is_some() {
+        cmd.arg("--unshare-user");
+        if let Some(uid) = sandbox.uid {
+            cmd.arg("--uid").arg(uid.to_string());
+        }
+        if let Some(gid) = sandbox.gid {
+            cmd.arg("--gid").arg(gid.to_string());
+        }
+    }
+    if let Some(hostname) = &sandbox.hostname {
+        cmd.arg("--unshare-uts").arg("--hostname").arg(hostname);
+    }
+    if let Some(shell_root) = &shell.bind {
+        cmd.arg("--ro-bind").arg(shell_root).arg(SHELL_MOUNT);
+    }
+
+    let path_value = SANDBOX_PATH.join(":");
     cmd.arg("--setenv").arg("PATH").arg(&path_value);
-    cmd.arg("--setenv").arg("SHELL").arg("/bin/sh");
-    cmd.arg("--setenv").arg("CONFIG_SHELL").arg("/bin/sh");
+    cmd.arg("--setenv").arg("SHELL").arg(&shell.path);
+    cmd.arg("--setenv").arg("CONFIG_SHELL").arg(&shell.path);
     cmd.arg("--setenv")
         .arg("BUILD_PARALLELISM")
-        .arg(parallelism.to_string());
+        .arg(scheduling.parallelism(parallelism).to_string());
     cmd.arg("--setenv").arg("HOME").arg("/build");
     if let Ok(term) = std::env::var("TERM") {
         cmd.arg("--setenv").arg("TERM").arg(term);
     }
+    for (name, value) in build_env {
+        cmd.arg("--setenv").arg(name).arg(value);
+    }
 
-    cmd.arg("--chdir").arg("/build");
-    cmd.arg("/bin/sh");
-    cmd.arg(script_container_path);
+    if let Some(fake_time) = &package.sandbox.fake_time {
+        let libfaketime = find_libfaketime()?;
+        let container_path = "/tmp/.magpkg-libfaketime.so.1";
+        cmd.arg("--ro-bind").arg(&libfaketime).arg(container_path);
+        cmd.arg("--setenv").arg("LD_PRELOAD").arg(container_path);
+        cmd.arg("--setenv")
+            .arg("FAKETIME")
+            .arg(faketime_spec(fake_time));
+        cmd.arg("--setenv").arg("FAKETIME_DONT_RESET").arg("1");
+    }
 
-    let status = match cmd.status() {
-        Ok(status) => status,
-        Err(err) => {
-            let _ = fs::remove_file(&script_host_path);
-            return Err(err.into());
+    // Kept alive until the build exits; dropping it stops the stream.
+    let mut random_stream = None;
+    if let Some(seed) = &package.sandbox.random_seed {
+        let fifo_path = build_root.join(format!(".magpkg-urandom-{}", std::process::id()));
+        let stream = DeterministicRandom::start(fifo_path, seed)?;
+        cmd.arg("--ro-bind").arg(stream.path()).arg("/dev/urandom");
+        cmd.arg("--ro-bind").arg(stream.path()).arg("/dev/random");
+        random_stream = Some(stream);
+    }
+
+    cmd.arg("--chdir").arg("/build");
+    if let Some(fakeroot) = &fakeroot {
+        cmd.arg(fakeroot)
+            .arg("-s")
+            .arg(format!("/{FAKEROOT_STATE_PATH}"))
+            .arg("--");
+    }
+    match entry {
+        SandboxEntry::Script { .. } if !package.hooks.is_empty() => {
+            cmd.arg(&shell.path).arg(BUILD_PHASES_PATH);
+        }
+        SandboxEntry::Script { .. } => {
+            if package.build_with.is_empty() {
+                cmd.arg(&shell.path);
+            } else {
+                cmd.args(&package.build_with);
+            }
+            cmd.arg(BUILD_SCRIPT_PATH);
+        }
+        SandboxEntry::Shell => {
+            cmd.arg(&shell.path);
         }
-    };
-    match fs::remove_file(&script_host_path) {
-        Ok(_) => {}
-        Err(err) if err.kind() == ErrorKind::NotFound => {}
-        Err(err) => return Err(err.into()),
     }
 
-    if !status.success() {
+    scheduling.apply(&mut cmd);
+    // The shell stays in the terminal's foreground process group, so that
+    // it can read from the terminal and ^C reaches the command it runs.
+    let status = match entry {
+        SandboxEntry::Script { .. } => run_in_process_group(&mut cmd)?,
+        SandboxEntry::Shell => cmd.status()?,
+    };
+    drop(random_stream);
+    drop(staged);
+    check_interrupted()?;
+
+    if !status.success() && is_script {
         let code = status.code().unwrap_or(-1);
-        return Err(MagError::CommandFailure {
-            context: format!("build script for {}", package_base_name(package)),
+        return Err(MagError::SandboxFailure {
+            package: package_base_name(package),
             status: code,
         });
     }
@@ -1281,6 +5248,110 @@ fn run_bwrap_build(package: &Package, rootfs: &Path, parallelism: usize) -> MagR
     Ok(())
 }
 
+/// Where a build hook is placed inside the sandbox, e.g.
+/// `/tmp/.magpkg-preBuild`.
+fn hook_script_path(phase: &str) -> String {
+    format!("/tmp/.magpkg-{phase}")
+}
+
+/// The script at [`BUILD_PHASES_PATH`]: each phase in order, stopping at
+/// the first that fails. Phases are separate processes, so only files carry
+/// over from one to the next.
+fn build_phases_script(package: &Package, shell: &SandboxShell, skip_check: bool) -> String {
+    let build_command: Vec<String> = if package.build_with.is_empty() {
+        vec![shell_quote(&shell.path)]
+    } else {
+        package
+            .build_with
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect()
+    };
+    let hook_command =
+        |phase: &str| format!("{} {}", shell_quote(&shell.path), hook_script_path(phase));
+
+    let mut steps = Vec::new();
+    let hooks = &package.hooks;
+    if !hooks.pre_build.is_empty() {
+        steps.push(("preBuild", hook_command("preBuild")));
+    }
+    steps.push((
+        "build",
+        format!("{} {BUILD_SCRIPT_PATH}", build_command.join(" ")),
+    ));
+    if !hooks.check.is_empty() && !skip_check {
+        steps.push(("check", hook_command("check")));
+    }
+    if !hooks.post_build.is_empty() {
+        steps.push(("postBuild", hook_command("postBuild")));
+    }
+
+    let mut script = String::new();
+    for (phase, command) in steps {
+        script.push_str(&format!(
+            "echo 'magpkg: running {phase}' >&2\n{command} || exit\n"
+        ));
+    }
+    script
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Scripts written next to the build rootfs and bound read-only into the
+/// sandbox, removed when dropped.
+struct StagedScripts<'a> {
+    build_root: &'a Path,
+    package: &'a Package,
+    /// Host path and sandbox path of each script.
+    files: Vec<(PathBuf, String)>,
+}
+
+impl<'a> StagedScripts<'a> {
+    fn new(build_root: &'a Path, package: &'a Package) -> Self {
+        Self {
+            build_root,
+            package,
+            files: Vec::new(),
+        }
+    }
+
+    fn stage(&mut self, container_path: &str, script: &str) -> MagResult<()> {
+        let name = container_path.rsplit('/').next().unwrap_or(container_path);
+        let host_path = self.build_root.join(format!(
+            "{name}-{}-{}",
+            self.package.hash,
+            std::process::id()
+        ));
+        // Keep the path for cleanup before anything can fail.
+        self.files
+            .push((host_path.clone(), container_path.to_string()));
+        let mut file = File::create(&host_path)?;
+        file.write_all(script.as_bytes())?;
+        if !script.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        let mut perms = file.metadata()?.permissions();
+        perms.set_mode(0o700);
+        fs::set_permissions(&host_path, perms)?;
+        Ok(())
+    }
+}
+
+impl Drop for StagedScripts<'_> {
+    fn drop(&mut self) {
+        for (host_path, _) in &self.files {
+            match fs::remove_file(host_path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => eprintln!("warning: failed to remove {}: {err}", host_path.display()),
+            }
+        }
+    }
+}
+
 fn build_via_untar(fetches: &[PathBuf], out_dir: &Path) -> MagResult<()> {
     if fetches.is_empty() {
         return Err(MagError::Generic(
@@ -1290,12 +5361,26 @@ fn build_via_untar(fetches: &[PathBuf], out_dir: &Path) -> MagResult<()> {
 
     clear_directory(out_dir)?;
     for fetch in fetches {
-        unpack_fetch_archive(fetch, out_dir)?;
+        if fetch.is_dir() {
+            // A `src` directory, already unpacked into the scratch fetch
+            // directory on the same filesystem.
+            for entry in fs::read_dir(fetch)? {
+                let entry = entry?;
+                fs::rename(entry.path(), out_dir.join(entry.file_name()))?;
+            }
+            continue;
+        }
+        archive::unpack_source(fetch, out_dir)?;
     }
     Ok(())
 }
 
-fn pack_output(src: &Path, dest: &Path) -> MagResult<()> {
+fn pack_output(
+    src: &Path,
+    dest: &Path,
+    fakeroot: Option<&FakerootState>,
+    compression: CompressionSettings,
+) -> MagResult<()> {
     if !src.exists() {
         fs::create_dir_all(src)?;
     }
@@ -1308,52 +5393,171 @@ fn pack_output(src: &Path, dest: &Path) -> MagResult<()> {
     }
 
     let file = File::create(&tmp_tar)?;
-    let encoder = ZstdEncoder::new(file, 0)?;
-    {
-        let mut builder = Builder::new(encoder.auto_finish());
-        builder.follow_symlinks(false);
-        builder.append_dir_all(".", src)?;
-        builder.finish()?;
-    }
+    let encoder = ArtifactWriter::new(file, compression);
+    let mut builder = Builder::new(encoder);
+    append_tree(&mut builder, src, fakeroot)?;
+    let file = builder.into_inner()?.finish()?;
+    file.sync_all()?;
+    drop(file);
 
+    let marker = published_marker_path(dest);
+    match fs::remove_file(&marker) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
     if dest.exists() {
         fs::remove_file(dest)?;
     }
     fs::rename(&tmp_tar, dest)?;
+    sync_parent_dir(dest)?;
     Ok(())
 }
 
-fn unpack_fetch_archive(archive_path: &Path, dest: &Path) -> MagResult<()> {
-    let file = File::open(archive_path)?;
-    match archive_path.extension().and_then(|ext| ext.to_str()) {
-        Some("zst") => {
-            let decoder = ZstdDecoder::new(file)?;
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(dest)?;
-        }
-        Some("gz") => {
-            let decoder = GzDecoder::new(file);
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(dest)?;
-        }
-        Some("tar") => {
-            let mut archive = tar::Archive::new(file);
-            archive.unpack(dest)?;
+/// Move a downloaded substitute from `tmp_path` into place and publish it.
+fn install_substitute(tmp_path: &Path, artifact_path: &Path) -> MagResult<()> {
+    File::open(tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, artifact_path)?;
+    sync_parent_dir(artifact_path)?;
+    publish_artifact(artifact_path)
+}
+
+/// Final step of the publish protocol (write, fsync, rename, fsync dir,
+/// record): write a marker next to the artifact recording its size and
+/// sha256. Readers on other machines sharing the store only trust artifacts
+/// whose marker exists and whose size matches, so a partially synced
+/// artifact is never consumed.
+fn publish_artifact(artifact: &Path) -> MagResult<()> {
+    let size = fs::metadata(artifact)?.len();
+    let sha256 = sha256_file(artifact)?;
+    let codec = ArtifactCodec::detect(&mut File::open(artifact)?)?;
+
+    let marker = published_marker_path(artifact);
+    let tmp_marker = marker.with_extension("published.tmp");
+    {
+        let mut file = File::create(&tmp_marker)?;
+        writeln!(file, "sha256 {sha256}")?;
+        writeln!(file, "size {size}")?;
+        writeln!(file, "codec {}", codec.as_str())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_marker, &marker)?;
+    sync_parent_dir(&marker)?;
+    Ok(())
+}
+
+pub fn artifact_is_published(artifact: &Path) -> bool {
+    let Ok(record) = fs::read_to_string(published_marker_path(artifact)) else {
+        return false;
+    };
+    let Some(size) = record
+        .lines()
+        .find_map(|line| line.strip_prefix("size "))
+        .and_then(|size| size.trim().parse::<u64>().ok())
+    else {
+        return false;
+    };
+    fs::metadata(artifact).is_ok_and(|meta| meta.is_file() && meta.len() == size)
+}
+
+pub fn published_sha256(artifact: &Path) -> Option<String> {
+    fs::read_to_string(published_marker_path(artifact))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("sha256 "))
+        .map(|sha| sha.trim().to_string())
+}
+
+fn published_marker_path(artifact: &Path) -> PathBuf {
+    let name = artifact
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let base = name.strip_suffix(".tar.zst").unwrap_or(&name);
+    artifact.with_file_name(format!("{base}{PUBLISHED_SUFFIX}"))
+}
+
+/// How an unavailable fetch is listed in offline errors.
+fn offline_fetch_label(fetch: &FetchResource) -> String {
+    format!("source {} (sha256 {})", fetch.filename, fetch.sha256)
+}
+
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Extract an artifact into `dest`, hashing the compressed bytes as they
+/// are read. Frames of seekable artifacts are decompressed on all cores.
+/// When the artifact has a publish record, the sha256 must match it once
+/// the whole file has been read; a corrupt artifact is unpublished so the
+/// next build replaces it, and the caller discards `dest`.
+fn extract_artifact(archive_path: &Path, dest: &Path) -> MagResult<()> {
+    let mut file = File::open(archive_path)?;
+    let codec = ArtifactCodec::detect(&mut file)?;
+    let seek_table = match codec {
+        ArtifactCodec::Zstd => {
+            let table = SeekTable::read(&mut file)?;
+            file.seek(SeekFrom::Start(0))?;
+            table
         }
-        _ => {
-            return Err(MagError::Generic(format!(
-                "unsupported archive format for {}",
-                archive_path.display()
-            )));
+        _ => None,
+    };
+    let mut hasher = Sha256::new();
+    let compressed = HashingReader {
+        inner: &mut file,
+        hasher: &mut hasher,
+    };
+    let decoded: Box<dyn Read> = match seek_table {
+        Some(table) => {
+            let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+            Box::new(table.parallel_reader(compressed, threads))
         }
+        None => decode_artifact(codec, compressed)?,
+    };
+    unpack_artifact_entries(decoded, archive_path, dest)?;
+    // Hash whatever follows the tar stream, such as the seek table.
+    io::copy(
+        &mut HashingReader {
+            inner: &mut file,
+            hasher: &mut hasher,
+        },
+        &mut io::sink(),
+    )?;
+
+    let Some(expected) = published_sha256(archive_path) else {
+        return Ok(());
+    };
+    let actual = hex::encode(hasher.finalize());
+    if actual != expected.to_ascii_lowercase() {
+        let _ = fs::remove_file(published_marker_path(archive_path));
+        return Err(MagError::Generic(format!(
+            "artifact {} is corrupt: sha256 is {actual}, its publish record says {expected}; \
+             it was unpublished and will be rebuilt or substituted on the next build",
+            archive_path.display()
+        )));
     }
     Ok(())
 }
 
-fn extract_tar_zst(archive_path: &Path, dest: &Path) -> MagResult<()> {
-    let file = File::open(archive_path)?;
-    let decoder = ZstdDecoder::new(file)?;
-    let mut archive = tar::Archive::new(decoder);
+/// Passes reads through while feeding every byte to a sha256 hasher.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn unpack_artifact_entries(reader: impl Read, archive_path: &Path, dest: &Path) -> MagResult<()> {
+    let mut archive = tar::Archive::new(reader);
 
     let entries = archive.entries().map_err(|err| {
         MagError::Generic(format!(
@@ -1386,17 +5590,117 @@ fn extract_tar_zst(archive_path: &Path, dest: &Path) -> MagResult<()> {
     Ok(())
 }
 
+/// `ioctl` request that makes a file share another file's blocks
+/// (`FICLONE` from `linux/fs.h`).
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// Merge the tree at `src` into `dest` the way extracting its archive would:
+/// entries of a different type are replaced, directories are merged. Files
+/// are hard-linked when `share_files` is set, which is only safe for a
+/// `dest` nothing writes to, since root ignores the cache's read-only
+/// modes. Otherwise they are copied; copies and directories get their owner
+/// write bit back.
+fn merge_tree(src: &Path, dest: &Path, share_files: bool) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let source = entry.path();
+        let target = dest.join(entry.file_name());
+        let entry_type = if file_type.is_dir() {
+            EntryType::Directory
+        } else if file_type.is_symlink() {
+            EntryType::Symlink
+        } else {
+            EntryType::Regular
+        };
+        prepare_entry_target(dest, Path::new(&entry.file_name()), entry_type)?;
+
+        if file_type.is_dir() {
+            let mode = entry.metadata()?.permissions().mode() | 0o700;
+            fs::create_dir_all(&target)?;
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode))?;
+            merge_tree(&source, &target, share_files)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &target)?;
+        } else if !(share_files && fs::hard_link(&source, &target).is_ok()) {
+            clone_or_copy_file(&source, &target)?;
+            let mode = fs::metadata(&target)?.permissions().mode() | 0o200;
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy `source` to `target` as a reflink where the filesystem supports
+/// one, so copies out of the extraction cache cost no space on btrfs or
+/// xfs, and byte by byte elsewhere.
+fn clone_or_copy_file(source: &Path, target: &Path) -> io::Result<()> {
+    let mut src = File::open(source)?;
+    let mut dst = File::create(target)?;
+    // SAFETY: both descriptors stay open for the duration of the call, and
+    // FICLONE takes the source descriptor by value.
+    let cloned = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } == 0;
+    if !cloned {
+        io::copy(&mut src, &mut dst)?;
+    }
+    dst.set_permissions(src.metadata()?.permissions())?;
+    Ok(())
+}
+
+/// Clear the write bits of everything under `root`, including `root`.
+fn set_tree_read_only(root: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            set_tree_read_only(&entry.path())?;
+        } else if file_type.is_file() {
+            let mode = entry.metadata()?.permissions().mode() & !0o222;
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))?;
+        }
+    }
+    let mode = fs::metadata(root)?.permissions().mode() & !0o222;
+    fs::set_permissions(root, fs::Permissions::from_mode(mode))
+}
+
+/// Remove a tree created by [`set_tree_read_only`].
+fn remove_read_only_tree(root: &Path) -> io::Result<()> {
+    fn make_writable(dir: &Path) -> io::Result<()> {
+        let mode = fs::metadata(dir)?.permissions().mode() | 0o700;
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                make_writable(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    if fs::symlink_metadata(root)?.is_dir() {
+        make_writable(root)?;
+        fs::remove_dir_all(root)
+    } else {
+        fs::remove_file(root)
+    }
+}
+
 fn write_stream_with_feedback<R: Read>(
     mut reader: R,
     mut file: File,
     label: Option<&str>,
     total: Option<u64>,
+    cancel: Option<&AtomicBool>,
 ) -> MagResult<()> {
     let mut buffer = [0u8; 8192];
     let mut transferred: u64 = 0;
     let mut last_report = label.map(|_| Instant::now());
 
     loop {
+        if cancel.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            return Err(MagError::Generic("download cancelled".into()));
+        }
+        check_interrupted()?;
         let read = reader.read(&mut buffer)?;
         if read == 0 {
             break;
@@ -1478,6 +5782,64 @@ fn info_hash_to_hex(id: Id20) -> String {
     hex::encode(id.0)
 }
 
+/// Write `<info-hash>/sources`: the sha256 of the payload and the fetch's
+/// HTTP(S) URLs, which serve as webseeds for the torrent.
+fn write_torrent_sources(torrent_dir: &Path, fetch: &FetchResource) -> MagResult<()> {
+    let mut body = format!("sha256\t{}\n", fetch.sha256.trim().to_ascii_lowercase());
+    for url in &fetch.urls {
+        if (url.starts_with("http://") || url.starts_with("https://"))
+            && !url.contains(['\t', '\n'])
+        {
+            body.push_str(&format!("webseed\t{url}\n"));
+        }
+    }
+
+    let path = torrent_dir.join(TORRENT_SOURCES_FILE);
+    if fs::read_to_string(&path).is_ok_and(|existing| existing == body) {
+        return Ok(());
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, body)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Sources recorded next to a torrent by [`write_torrent_sources`].
+#[derive(Debug, Default, Clone)]
+pub struct TorrentSources {
+    pub sha256: Option<String>,
+    pub webseeds: Vec<String>,
+}
+
+pub fn read_torrent_sources(torrent_dir: &Path) -> TorrentSources {
+    let mut sources = TorrentSources::default();
+    let Ok(contents) = fs::read_to_string(torrent_dir.join(TORRENT_SOURCES_FILE)) else {
+        return sources;
+    };
+    for line in contents.lines() {
+        match line.split_once('\t') {
+            Some(("sha256", sha)) => sources.sha256 = Some(sha.to_string()),
+            Some(("webseed", url)) => sources.webseeds.push(url.to_string()),
+            _ => {}
+        }
+    }
+    sources
+}
+
+/// Number of sources a fetch may download from at once (`MAGPKG_FETCH_RACE`).
+fn fetch_race_width() -> usize {
+    match env::var(FETCH_RACE_ENV) {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(width) if width > 0 => width,
+            _ => {
+                eprintln!("warning: ignoring invalid {FETCH_RACE_ENV}={value}");
+                DEFAULT_FETCH_RACE
+            }
+        },
+        Err(_) => DEFAULT_FETCH_RACE,
+    }
+}
+
 fn temp_path_for(dest: &Path) -> PathBuf {
     match dest.file_name().and_then(|name| name.to_str()) {
         Some(name) if !name.is_empty() => dest.with_file_name(format!("{name}.tmp")),
@@ -1509,18 +5871,49 @@ fn create_temp_file(dest: &Path) -> io::Result<(PathBuf, File)> {
 }
 
 fn verify_sha256(path: &Path, expected: &str) -> MagResult<bool> {
+    let actual = sha256_file(path)?;
+    Ok(actual == expected.trim().to_ascii_lowercase())
+}
+
+/// sha256 of the file at `path`. Files larger than one block are read on
+/// a second thread, so that reading a large source from disk overlaps with
+/// hashing it.
+pub fn sha256_file(path: &Path) -> MagResult<String> {
     let mut file = File::open(path)?;
     let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 8192];
-    loop {
-        let read = file.read(&mut buffer)?;
-        if read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..read]);
+    if file.metadata()?.len() <= HASH_BLOCK_SIZE as u64 {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        hasher.update(&contents);
+        return Ok(format!("{:x}", hasher.finalize()));
     }
-    let actual = format!("{:x}", hasher.finalize());
-    Ok(actual == expected.trim().to_ascii_lowercase())
+
+    let (blocks_tx, blocks_rx) = std_mpsc::sync_channel::<Vec<u8>>(1);
+    let (spare_tx, spare_rx) = std_mpsc::channel::<Vec<u8>>();
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || -> io::Result<()> {
+            loop {
+                let mut block = spare_rx
+                    .try_recv()
+                    .unwrap_or_else(|_| Vec::with_capacity(HASH_BLOCK_SIZE));
+                block.clear();
+                (&mut file)
+                    .take(HASH_BLOCK_SIZE as u64)
+                    .read_to_end(&mut block)?;
+                if block.is_empty() || blocks_tx.send(block).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+        for block in blocks_rx {
+            hasher.update(&block);
+            let _ = spare_tx.send(block);
+        }
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("file reader thread panicked")))
+    })?;
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
 fn clear_directory(path: &Path) -> io::Result<()> {
@@ -1542,8 +5935,48 @@ fn clear_directory(path: &Path) -> io::Result<()> {
     Ok(())
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PackageLeftover {
+    BuildDir,
+    Partial,
+    Sidecar,
+}
+
+/// The package base of a file recording something about an artifact, which
+/// is meaningless once the artifact is gone.
+fn package_base_from_sidecar(name: &str) -> Option<&str> {
+    [
+        PUBLISHED_SUFFIX,
+        FILE_INDEX_SUFFIX,
+        METADATA_SUFFIX,
+        DEPS_SUFFIX,
+        PROVENANCE_SUFFIX,
+    ]
+    .into_iter()
+    .find_map(|suffix| name.strip_suffix(suffix))
+}
+
+/// The hash at the end of a `<name>-<hash>` base name, for artifacts that
+/// have no `.deps` record to read it from. Names of extra outputs do not end
+/// in a hash and give `None`.
+fn legacy_base_hash(base: &str) -> Option<&str> {
+    base.rsplit_once('-')
+        .map(|(_, hash)| hash)
+        .filter(|hash| hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
 fn package_base_from_entry(name: &str) -> Option<String> {
-    for suffix in [".tar.zst", ".build", ".lock"] {
+    for suffix in [
+        ".tar.zst",
+        ".build",
+        ".lock",
+        PUBLISHED_SUFFIX,
+        FILE_INDEX_SUFFIX,
+        FAILED_SUFFIX,
+        METADATA_SUFFIX,
+        DEPS_SUFFIX,
+        PROVENANCE_SUFFIX,
+    ] {
         if name.ends_with(suffix) {
             return Some(name.trim_end_matches(suffix).to_string());
         }
@@ -1589,7 +6022,7 @@ fn is_metadata_expired(metadata: &fs::Metadata, now: SystemTime, expiry: Duratio
     }
 }
 
-fn touch_path(path: &Path) -> io::Result<()> {
+pub fn touch_path(path: &Path) -> io::Result<()> {
     if !path.exists() {
         return Ok(());
     }
@@ -1631,7 +6064,7 @@ fn print_download_complete(label: &str, transferred: u64, total: Option<u64>) {
     }
 }
 
-fn format_bytes(bytes: u64) -> String {
+pub fn format_bytes(bytes: u64) -> String {
     const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
     let mut value = bytes as f64;
     let mut unit_index = 0;
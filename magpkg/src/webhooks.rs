@@ -0,0 +1,217 @@
+use std::{env, fs, io::ErrorKind, path::Path, time::Duration};
+
+use reqwest::blocking::ClientBuilder;
+use serde_json::json;
+
+use crate::{MagError, MagResult, offline::is_offline, proxy::HttpClient};
+
+pub const WEBHOOKS_CONFIG_FILE: &str = "webhooks.conf";
+/// Overrides the `log-url` setting, typically with the CI job's URL.
+pub const BUILD_LOG_URL_ENV: &str = "MAGPKG_BUILD_LOG_URL";
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStatus {
+    Success,
+    Failure,
+}
+
+impl BuildStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuildStatus::Success => "success",
+            BuildStatus::Failure => "failure",
+        }
+    }
+}
+
+/// The outcome of building one top-level target.
+pub struct BuildEvent<'a> {
+    pub package: &'a str,
+    pub hash: &'a str,
+    pub status: BuildStatus,
+    pub duration: Duration,
+    /// The build error, for failures.
+    pub error: Option<&'a str>,
+}
+
+struct Hook {
+    on: Option<BuildStatus>,
+    url: String,
+    template: Option<String>,
+}
+
+/// Webhooks fired when `magpkg build` finishes, read from
+/// `<store>/webhooks.conf`:
+///
+/// ```text
+/// hook    <success|failure|always> <url> [<template-file>]
+/// log-url <url-template>
+/// ```
+///
+/// Each hook receives one POST per top-level target. Without a template the
+/// body is a JSON object with the event's fields; a template file (relative
+/// paths resolve against the store) is sent instead with `{package}`,
+/// `{hash}`, `{status}`, `{duration}`, `{log}`, and `{error}` replaced by
+/// JSON-escaped values, so it can shape the payload for a chat service.
+/// `{log}` is `log-url` with the same placeholders filled in, or
+/// `MAGPKG_BUILD_LOG_URL` when set.
+pub struct Webhooks {
+    hooks: Vec<Hook>,
+    log_url: Option<String>,
+}
+
+impl Webhooks {
+    /// Read `webhooks.conf`. With `--offline`, no hooks are loaded.
+    pub fn load(base_root: &Path) -> MagResult<Self> {
+        let mut webhooks = Self {
+            hooks: Vec::new(),
+            log_url: None,
+        };
+        if is_offline() {
+            return Ok(webhooks);
+        }
+        let path = base_root.join(WEBHOOKS_CONFIG_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(webhooks),
+            Err(err) => return Err(err.into()),
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                MagError::Generic(format!(
+                    "{}:{}: invalid webhook setting {line:?}",
+                    path.display(),
+                    index + 1
+                ))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["hook", on, url, template @ ..] if template.len() <= 1 => {
+                    let on = match *on {
+                        "success" => Some(BuildStatus::Success),
+                        "failure" => Some(BuildStatus::Failure),
+                        "always" => None,
+                        _ => return Err(invalid()),
+                    };
+                    let template = match template.first() {
+                        Some(file) => {
+                            let file = base_root.join(file);
+                            Some(fs::read_to_string(&file).map_err(|err| {
+                                MagError::Generic(format!(
+                                    "webhook template {}: {err}",
+                                    file.display()
+                                ))
+                            })?)
+                        }
+                        None => None,
+                    };
+                    webhooks.hooks.push(Hook {
+                        on,
+                        url: url.to_string(),
+                        template,
+                    });
+                }
+                ["log-url", url] => webhooks.log_url = Some(url.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(webhooks)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Deliver `event` to every matching hook. Delivery failures are
+    /// reported and otherwise ignored, so a broken hook never fails a build.
+    pub fn fire(&self, event: &BuildEvent) {
+        let hooks: Vec<&Hook> = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.on.is_none_or(|on| on == event.status))
+            .collect();
+        if hooks.is_empty() {
+            return;
+        }
+        let client = match HttpClient::from_env(|| ClientBuilder::new().timeout(WEBHOOK_TIMEOUT)) {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("warning: cannot send build webhooks: {err}");
+                return;
+            }
+        };
+
+        let log = env::var(BUILD_LOG_URL_ENV)
+            .ok()
+            .filter(|url| !url.trim().is_empty())
+            .or_else(|| {
+                self.log_url
+                    .as_deref()
+                    .map(|template| render(template, event, "", false))
+            })
+            .unwrap_or_default();
+
+        for hook in hooks {
+            let (body, content_type) = match &hook.template {
+                Some(template) => {
+                    let body = render(template, event, &log, true);
+                    let content_type = if body.trim_start().starts_with(['{', '[']) {
+                        "application/json"
+                    } else {
+                        "text/plain"
+                    };
+                    (body, content_type)
+                }
+                None => (
+                    json!({
+                        "package": event.package,
+                        "hash": event.hash,
+                        "status": event.status.as_str(),
+                        "durationSeconds": event.duration.as_secs_f64(),
+                        "log": (!log.is_empty()).then_some(&log),
+                        "error": event.error,
+                    })
+                    .to_string(),
+                    "application/json",
+                ),
+            };
+            let result = client
+                .post(&hook.url)
+                .header("Content-Type", content_type)
+                .body(body)
+                .send()
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = result {
+                eprintln!("warning: build webhook {} failed: {err}", hook.url);
+            }
+        }
+    }
+}
+
+/// Fill a template's placeholders, JSON-escaping the values when `escape`
+/// is set.
+fn render(template: &str, event: &BuildEvent, log: &str, escape: bool) -> String {
+    let value = |value: &str| {
+        if escape {
+            let quoted = serde_json::Value::from(value).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        } else {
+            value.to_string()
+        }
+    };
+    template
+        .replace("{package}", &value(event.package))
+        .replace("{hash}", &value(event.hash))
+        .replace("{status}", event.status.as_str())
+        .replace(
+            "{duration}",
+            &format!("{:.0}", event.duration.as_secs_f64()),
+        )
+        .replace("{log}", &value(log))
+        .replace("{error}", &value(event.error.unwrap_or_default()))
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    rc::Rc,
+};
+
+use crate::{
+    diff::short_hash,
+    package::{BuilderShell, OutputOf, Package},
+};
+
+/// How a package depends on the next one in a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Run,
+    Build,
+    /// The `builderShell` package that runs the build script.
+    Shell,
+}
+
+impl EdgeKind {
+    fn label(self) -> &'static str {
+        match self {
+            EdgeKind::Run => "run",
+            EdgeKind::Build => "build",
+            EdgeKind::Shell => "shell",
+        }
+    }
+}
+
+/// One path from a root to the target: the root, then each edge and the
+/// package it leads to.
+pub struct Chain {
+    pub root: Rc<Package>,
+    pub steps: Vec<(EdgeKind, Rc<Package>)>,
+}
+
+impl Chain {
+    /// Whether every edge is a runtime dependency, so the target is in the
+    /// root's runtime closure through this chain.
+    pub fn is_runtime(&self) -> bool {
+        self.steps.iter().all(|(kind, _)| *kind == EdgeKind::Run)
+    }
+}
+
+/// Every chain of dependencies from `roots` to `target`, at most `limit` of
+/// them, and how many there are in total. With `runtime_only`, only
+/// runtime edges are followed.
+pub fn dependency_chains(
+    roots: &[Rc<Package>],
+    target: &Package,
+    runtime_only: bool,
+    limit: usize,
+) -> (Vec<Chain>, usize) {
+    let mut reaches = HashMap::new();
+    let mut counts = HashMap::new();
+    let mut chains = Vec::new();
+    let mut total = 0;
+    let mut seen_roots = HashSet::new();
+    for root in roots {
+        if !seen_roots.insert(root.hash.clone()) {
+            continue;
+        }
+        total += count_paths(root, target, runtime_only, &mut counts);
+        let mut steps = Vec::new();
+        collect_chains(
+            root,
+            root,
+            target,
+            runtime_only,
+            limit,
+            &mut reaches,
+            &mut steps,
+            &mut chains,
+        );
+    }
+    (chains, total)
+}
+
+fn edges(package: &Package, runtime_only: bool) -> Vec<(EdgeKind, Rc<Package>)> {
+    let mut edges: Vec<(EdgeKind, Rc<Package>)> = package
+        .run_deps
+        .iter()
+        .map(|dep| (EdgeKind::Run, dep.clone()))
+        .collect();
+    if !runtime_only {
+        edges.extend(
+            package
+                .build_deps
+                .iter()
+                .map(|dep| (EdgeKind::Build, dep.clone())),
+        );
+        if let Some(BuilderShell::Package(shell)) = &package.builder_shell {
+            edges.push((EdgeKind::Shell, shell.clone()));
+        }
+    }
+    edges
+}
+
+/// Number of distinct chains from `package` to `target`, memoized by hash.
+fn count_paths(
+    package: &Rc<Package>,
+    target: &Package,
+    runtime_only: bool,
+    counts: &mut HashMap<String, usize>,
+) -> usize {
+    if package.hash == target.hash {
+        return 1;
+    }
+    if let Some(count) = counts.get(&package.hash) {
+        return *count;
+    }
+    let count = edges(package, runtime_only)
+        .iter()
+        .map(|(_, dep)| count_paths(dep, target, runtime_only, counts))
+        .fold(0usize, usize::saturating_add);
+    counts.insert(package.hash.clone(), count);
+    count
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collect_chains(
+    root: &Rc<Package>,
+    package: &Rc<Package>,
+    target: &Package,
+    runtime_only: bool,
+    limit: usize,
+    reaches: &mut HashMap<String, bool>,
+    steps: &mut Vec<(EdgeKind, Rc<Package>)>,
+    chains: &mut Vec<Chain>,
+) {
+    if chains.len() >= limit {
+        return;
+    }
+    if package.hash == target.hash {
+        chains.push(Chain {
+            root: root.clone(),
+            steps: steps.clone(),
+        });
+        return;
+    }
+    for (kind, dep) in edges(package, runtime_only) {
+        if !reaches_target(&dep, target, runtime_only, reaches) {
+            continue;
+        }
+        steps.push((kind, dep.clone()));
+        collect_chains(
+            root,
+            &dep,
+            target,
+            runtime_only,
+            limit,
+            reaches,
+            steps,
+            chains,
+        );
+        steps.pop();
+    }
+}
+
+fn reaches_target(
+    package: &Rc<Package>,
+    target: &Package,
+    runtime_only: bool,
+    reaches: &mut HashMap<String, bool>,
+) -> bool {
+    if package.hash == target.hash {
+        return true;
+    }
+    if let Some(reached) = reaches.get(&package.hash) {
+        return *reached;
+    }
+    let reached = edges(package, runtime_only)
+        .iter()
+        .any(|(_, dep)| reaches_target(dep, target, runtime_only, reaches));
+    reaches.insert(package.hash.clone(), reached);
+    reached
+}
+
+/// Render the chains, one per line, with each edge labelled:
+///
+/// ```text
+/// hello -run-> libidn2 -run-> libunistring
+/// hello -build-> gettext -run-> libunistring
+///
+/// libunistring is in the runtime closure of hello.
+/// ```
+pub fn format_chains(target: &Package, chains: &[Chain], total: usize) -> String {
+    let mut names: HashMap<String, HashSet<String>> = HashMap::new();
+    for chain in chains {
+        let packages = std::iter::once(&chain.root).chain(chain.steps.iter().map(|(_, p)| p));
+        for package in packages {
+            names
+                .entry(plain_label(package))
+                .or_default()
+                .insert(package.hash.clone());
+        }
+    }
+    let label = |package: &Package| {
+        let plain = plain_label(package);
+        if names.get(&plain).is_some_and(|hashes| hashes.len() > 1) {
+            format!("{plain}@{}", short_hash(&package.hash))
+        } else {
+            plain
+        }
+    };
+
+    let mut out = String::new();
+    for chain in chains {
+        let mut line = label(&chain.root);
+        for (kind, package) in &chain.steps {
+            let _ = write!(line, " -{}-> {}", kind.label(), label(package));
+        }
+        let _ = writeln!(out, "{line}");
+    }
+    if total > chains.len() {
+        let _ = writeln!(out, "... and {} more chains", total - chains.len());
+    }
+
+    let target_label = label(target);
+    let mut runtime_roots: Vec<String> = chains
+        .iter()
+        .filter(|chain| chain.is_runtime() && !chain.steps.is_empty())
+        .map(|chain| label(&chain.root))
+        .collect();
+    runtime_roots.sort();
+    runtime_roots.dedup();
+    out.push('\n');
+    if chains.iter().any(|chain| chain.steps.is_empty()) {
+        let _ = writeln!(out, "{target_label} is one of the roots.");
+    }
+    if runtime_roots.is_empty() {
+        let _ = writeln!(
+            out,
+            "{target_label} is only needed to build the roots, not at runtime."
+        );
+    } else {
+        let _ = writeln!(
+            out,
+            "{target_label} is in the runtime closure of {}.",
+            runtime_roots.join(", ")
+        );
+    }
+    out
+}
+
+fn plain_label(package: &Package) -> String {
+    if let Some(OutputOf { owner, output }) = &package.output_of {
+        return format!("{}:{output}", plain_label(owner));
+    }
+    match package.name.as_deref() {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => format!("pkg-{}", short_hash(&package.hash)),
+    }
+}